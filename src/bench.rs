@@ -0,0 +1,255 @@
+use crate::nix_interface::nar_info::NarInfo;
+use anyhow::{Result, anyhow, bail};
+use clap::ValueEnum;
+use reqwest::StatusCode;
+use reqwest::blocking::Client;
+use std::fmt::Display;
+use std::io;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::debug;
+use url::Url;
+
+const NIX_BASE32_ALPHABET: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+/// Number of stored packages the workloads are spread over.
+const SAMPLED_PACKAGES: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Workload {
+    /// Narinfo lookups, half of them for packages which are not in the cache, like
+    /// Nix querying a substituter
+    Query,
+    /// Downloads of a narinfo followed by its NAR
+    Download,
+    /// Alternates between queries and downloads
+    Mixed,
+}
+
+pub struct BenchOptions {
+    pub url: Url,
+    pub workload: Workload,
+    pub requests: usize,
+    pub concurrency: usize,
+}
+
+struct Sample {
+    latency: Duration,
+    bytes: u64,
+    failed: bool,
+}
+
+/// Throughput and latency of a benchmark run. A download counts as a single
+/// operation, its latency covers both the narinfo and the NAR.
+pub struct BenchReport {
+    pub operations: usize,
+    pub failed: usize,
+    pub bytes: u64,
+    pub elapsed: Duration,
+    /// Latencies of the successful operations, sorted
+    latencies: Vec<Duration>,
+}
+
+impl BenchReport {
+    fn new(samples: Vec<Sample>, elapsed: Duration) -> Self {
+        let mut latencies: Vec<Duration> = samples
+            .iter()
+            .filter(|s| !s.failed)
+            .map(|s| s.latency)
+            .collect();
+        latencies.sort();
+        Self {
+            operations: samples.len(),
+            failed: samples.iter().filter(|s| s.failed).count(),
+            bytes: samples.iter().map(|s| s.bytes).sum(),
+            elapsed,
+            latencies,
+        }
+    }
+
+    /// Latency below which `percent` of the successful operations completed.
+    pub fn percentile(&self, percent: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = (percent / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[rank.clamp(1, self.latencies.len()) - 1]
+    }
+}
+
+impl Display for BenchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let seconds = self.elapsed.as_secs_f64().max(f64::EPSILON);
+        writeln!(
+            f,
+            "Operations: {} ({} failed) in {:.2}s",
+            self.operations, self.failed, seconds
+        )?;
+        writeln!(
+            f,
+            "Throughput: {:.1} operations/s, {:.2} MiB/s",
+            self.operations as f64 / seconds,
+            self.bytes as f64 / seconds / (1024.0 * 1024.0)
+        )?;
+        writeln!(
+            f,
+            "Latency: p50 {:.1?}, p90 {:.1?}, p99 {:.1?}, max {:.1?}",
+            self.percentile(50.0),
+            self.percentile(90.0),
+            self.percentile(99.0),
+            self.percentile(100.0)
+        )
+    }
+}
+
+/// Replays a synthetic workload against the server at `options.url` using
+/// `options.concurrency` parallel clients.
+pub fn run(options: &BenchOptions) -> Result<BenchReport> {
+    let mut base = options.url.clone();
+    if !base.path().ends_with('/') {
+        base.set_path(&format!("{}/", base.path()));
+    }
+    let client = Client::new();
+    let hashes = stored_hashes(&client, &base)?;
+    if hashes.is_empty() && options.workload != Workload::Query {
+        bail!("The cache at {} holds no packages to download", base);
+    }
+
+    let next = AtomicUsize::new(0);
+    let samples = Mutex::new(Vec::with_capacity(options.requests));
+    let started = Instant::now();
+    thread::scope(|scope| {
+        for _ in 0..options.concurrency.max(1) {
+            scope.spawn(|| {
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    if i >= options.requests {
+                        break;
+                    }
+                    let sample = operation(&client, &base, options.workload, &hashes, i);
+                    samples.lock().unwrap().push(sample);
+                }
+            });
+        }
+    });
+    Ok(BenchReport::new(
+        samples.into_inner().unwrap(),
+        started.elapsed(),
+    ))
+}
+
+fn stored_hashes(client: &Client, base: &Url) -> Result<Vec<String>> {
+    let mut url = base.join("api/entries")?;
+    url.set_query(Some(&format!("limit={SAMPLED_PACKAGES}")));
+    let response = client.get(url).send()?.error_for_status()?;
+    let entries: Vec<serde_json::Value> = serde_json::from_str(&response.text()?)?;
+    Ok(entries
+        .iter()
+        .filter_map(|entry| entry["hash"].as_str().map(str::to_string))
+        .collect())
+}
+
+fn operation(
+    client: &Client,
+    base: &Url,
+    workload: Workload,
+    hashes: &[String],
+    i: usize,
+) -> Sample {
+    let started = Instant::now();
+    let result = match workload {
+        Workload::Query => query(client, base, hashes, i),
+        Workload::Mixed if i.is_multiple_of(2) => query(client, base, hashes, i),
+        Workload::Download | Workload::Mixed => download(client, base, hashes, i),
+    };
+    match result {
+        Ok(bytes) => Sample {
+            latency: started.elapsed(),
+            bytes,
+            failed: false,
+        },
+        Err(e) => {
+            debug!("Operation {} failed: {}", i, e);
+            Sample {
+                latency: started.elapsed(),
+                bytes: 0,
+                failed: true,
+            }
+        }
+    }
+}
+
+/// Looks up a narinfo, every other lookup is for a package which is not stored.
+fn query(client: &Client, base: &Url, hashes: &[String], i: usize) -> Result<u64> {
+    let (hash, expected) = if hashes.is_empty() || i % 4 >= 2 {
+        (missing_hash(i), StatusCode::NOT_FOUND)
+    } else {
+        (hashes[i % hashes.len()].clone(), StatusCode::OK)
+    };
+    let response = client.get(base.join(&format!("{hash}.narinfo"))?).send()?;
+    let status = response.status();
+    let bytes = response.bytes()?.len() as u64;
+    if status != expected {
+        bail!("Expected {} for {}, got {}", expected, hash, status);
+    }
+    Ok(bytes)
+}
+
+fn download(client: &Client, base: &Url, hashes: &[String], i: usize) -> Result<u64> {
+    let hash = &hashes[i % hashes.len()];
+    let narinfo = client
+        .get(base.join(&format!("{hash}.narinfo"))?)
+        .send()?
+        .error_for_status()?
+        .text()?;
+    let nar_url =
+        NarInfo::field(&narinfo, "URL").ok_or_else(|| anyhow!("Narinfo of {} has no URL", hash))?;
+    // Also resolves URLs pointing at a mirror
+    let mut response = client.get(base.join(nar_url)?).send()?.error_for_status()?;
+    let nar_bytes = response.copy_to(&mut io::sink())?;
+    Ok(narinfo.len() as u64 + nar_bytes)
+}
+
+/// A valid hash which is not in the cache, distinct for every operation.
+fn missing_hash(i: usize) -> String {
+    let mut hash = vec![b'0'; 32];
+    let mut n = i;
+    for digit in hash.iter_mut().rev() {
+        *digit = NIX_BASE32_ALPHABET[n % 32];
+        n /= 32;
+    }
+    // Real hashes practically never start with twenty z's
+    hash[..20].fill(b'z');
+    String::from_utf8(hash).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentiles() {
+        let samples = (1..=100)
+            .map(|ms| Sample {
+                latency: Duration::from_millis(ms),
+                bytes: 10,
+                failed: ms.is_multiple_of(50),
+            })
+            .collect();
+        let report = BenchReport::new(samples, Duration::from_secs(2));
+        assert_eq!((report.operations, report.failed), (100, 2));
+        assert_eq!(report.bytes, 1000);
+        assert_eq!(report.percentile(50.0), Duration::from_millis(49));
+        assert_eq!(report.percentile(100.0), Duration::from_millis(99));
+        assert_eq!(report.percentile(0.0), Duration::from_millis(1));
+    }
+
+    #[test]
+    fn test_missing_hash() {
+        assert_eq!(missing_hash(0), "z".repeat(20) + &"0".repeat(12));
+        assert_eq!(missing_hash(33), "z".repeat(20) + "0000000000" + "11");
+        assert_ne!(missing_hash(1), missing_hash(2));
+    }
+}
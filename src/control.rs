@@ -0,0 +1,255 @@
+use crate::git_store::audit::{AuditRecord, Outcome};
+use crate::git_store::history;
+use crate::git_store::store::Store;
+use crate::logging;
+use crate::nix_interface::path::NixPath;
+use crate::settings;
+use anyhow::{Context, Result, anyhow, bail};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Instant;
+use tokio::runtime::Runtime;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// A local socket through which the CLI controls a running server, so that
+/// operations run inside the server instead of in a second process competing for
+/// the repository.
+///
+/// Every connection sends a single command line. The first line of the response is
+/// `ok` or `error`, followed by the output of the command or the error message.
+pub struct ControlSocket {
+    path: PathBuf,
+    cancel: CancellationToken,
+    listener: thread::JoinHandle<()>,
+}
+
+#[derive(Clone)]
+struct Controller {
+    store: Store,
+    config_file: String,
+    address: String,
+    started: Instant,
+    // Commands which are currently running
+    active: Arc<AtomicUsize>,
+    cancel: CancellationToken,
+}
+
+impl ControlSocket {
+    /// Listens on `path` until `stop` is called or `cancel` is triggered. Commands
+    /// started over the socket are cancelled along with it.
+    pub fn spawn(
+        path: &Path,
+        store: Store,
+        server_settings: &settings::Server,
+        config_file: &str,
+        cancel: CancellationToken,
+    ) -> Result<Self> {
+        if path.exists() {
+            if UnixStream::connect(path).is_ok() {
+                bail!("Another server is listening on {}", path.display());
+            }
+            // Left behind by a server which did not shut down cleanly
+            fs::remove_file(path)?;
+        }
+        let listener = bind_private(path)
+            .with_context(|| format!("Could not bind the control socket {}", path.display()))?;
+        info!("Listening for control commands on {}", path.display());
+
+        let controller = Controller {
+            store,
+            config_file: config_file.to_string(),
+            address: format!("{}:{}", server_settings.host, server_settings.port),
+            started: Instant::now(),
+            active: Arc::new(AtomicUsize::new(0)),
+            cancel: cancel.clone(),
+        };
+        let token = cancel.clone();
+        let listener = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if token.is_cancelled() {
+                    break;
+                }
+                match stream {
+                    Ok(stream) => {
+                        let controller = controller.clone();
+                        thread::spawn(move || controller.serve(stream));
+                    }
+                    Err(e) => warn!("Could not accept a control connection: {}", e),
+                }
+            }
+        });
+        Ok(Self {
+            path: path.to_path_buf(),
+            cancel,
+            listener,
+        })
+    }
+
+    pub fn stop(self) {
+        self.cancel.cancel();
+        // Wakes up the listener, which is blocked until the next connection
+        let _ = UnixStream::connect(&self.path);
+        let _ = self.listener.join();
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Binds the socket in a directory only this user may enter and moves it to `path`
+/// once its permissions are restricted, so that other users can't connect while
+/// the socket still has the permissions of the umask.
+fn bind_private(path: &Path) -> Result<UnixListener> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} is no file name", path.display()))?;
+    let private = path.with_file_name(format!(
+        ".{}.{}",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    // Left behind by a server with the same pid which did not shut down cleanly
+    let _ = fs::remove_dir_all(&private);
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let bound = (|| {
+        let socket = private.join("socket");
+        let listener = UnixListener::bind(&socket)?;
+        fs::set_permissions(&socket, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&socket, path)?;
+        anyhow::Ok(listener)
+    })();
+    let _ = fs::remove_dir_all(&private);
+    bound
+}
+
+impl Controller {
+    fn serve(&self, stream: UnixStream) {
+        let mut command = String::new();
+        if let Err(e) = BufReader::new(&stream).read_line(&mut command) {
+            warn!("Could not read a control command: {}", e);
+            return;
+        }
+        let command = command.trim();
+        info!("Running control command: {}", command);
+        self.active.fetch_add(1, Ordering::Relaxed);
+        let result = self.run(command);
+        self.active.fetch_sub(1, Ordering::Relaxed);
+        // Only the user running the server can connect to the socket
+        if let Some((action, target)) = audited(command) {
+            self.store.audit(&AuditRecord {
+                principal: history::identity().1,
+                action: action.to_string(),
+                target: target.to_string(),
+                outcome: Outcome::of(&result),
+            });
+        }
+        let response = match result {
+            Ok(output) => format!("ok\n{output}"),
+            Err(e) => {
+                warn!("Control command {} failed: {}", command, e);
+                format!("error\n{e}\n")
+            }
+        };
+        let _ = (&stream).write_all(response.as_bytes());
+    }
+
+    fn run(&self, command: &str) -> Result<String> {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        match words.as_slice() {
+            ["stats"] => self.stats(),
+            ["gc"] => {
+                let policy = self.store.gc_policy(None)?;
+                Ok(self
+                    .store
+                    .collect_garbage(&policy, &self.cancel)?
+                    .to_string())
+            }
+            ["pack-refs"] => {
+                self.store.pack_refs()?;
+                Ok("Packed references\n".to_string())
+            }
+            ["add", path] => {
+                let path = NixPath::new(*path)?;
+                let report = Runtime::new()?.block_on(async {
+                    self.store.peer_health_check().await;
+                    self.store.add_closure(&path, &self.cancel).await
+                })?;
+                Ok(format!("Added {}\n{}", path, report))
+            }
+            ["flush"] => {
+                self.store.flush_caches();
+                Ok("Flushed caches\n".to_string())
+            }
+            ["reload"] => {
+                let settings = settings::load_config(&self.config_file)?;
+                logging::set_level(&settings.log_level)?;
+                Ok(format!(
+                    "Log level set to {}, other settings take effect after a restart\n",
+                    settings.log_level
+                ))
+            }
+            ["maintenance", "on"] => {
+                self.store.set_maintenance(true);
+                Ok("Maintenance mode on\n".to_string())
+            }
+            ["maintenance", "off"] => {
+                self.store.set_maintenance(false);
+                Ok("Maintenance mode off\n".to_string())
+            }
+            _ => bail!("Unknown command: {}", command),
+        }
+    }
+
+    fn stats(&self) -> Result<String> {
+        let maintenance = if self.store.in_maintenance() {
+            "on"
+        } else {
+            "off"
+        };
+        // The stats command itself is not counted
+        let queued = self.active.load(Ordering::Relaxed).saturating_sub(1);
+        Ok(format!(
+            "Uptime: {}s\nListening: {}\nQueued commands: {}\n\
+             {}Added since start: {}\nMaintenance: {}\n",
+            self.started.elapsed().as_secs(),
+            self.address,
+            queued,
+            self.store.stats()?,
+            self.store.packages_added(),
+            maintenance
+        ))
+    }
+}
+
+/// The action and target recorded in the audit trail, for the commands which change
+/// the store.
+fn audited(command: &str) -> Option<(&str, &str)> {
+    match command.split_whitespace().collect::<Vec<_>>().as_slice() {
+        ["gc"] => Some(("gc", "")),
+        ["add", path] => Some(("add", *path)),
+        ["maintenance", mode] => Some(("maintenance", *mode)),
+        _ => None,
+    }
+}
+
+/// Sends a command to the control socket of a running server and returns its
+/// output.
+pub fn send(path: &Path, command: &str) -> Result<String> {
+    let mut stream = UnixStream::connect(path)
+        .with_context(|| format!("No server is listening on {}", path.display()))?;
+    writeln!(stream, "{command}")?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    match response.split_once('\n') {
+        Some(("ok", output)) => Ok(output.to_string()),
+        Some(("error", error)) => bail!("{}", error.trim_end()),
+        _ => bail!("Unexpected response on the control socket"),
+    }
+}
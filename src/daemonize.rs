@@ -0,0 +1,116 @@
+use anyhow::{Context, Result, bail};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Detaches the process from the terminal and continues in the background. Must be
+/// called before any threads are spawned, only the calling thread survives a fork.
+///
+/// The working directory is kept, so that relative paths in the configuration keep
+/// working. Standard input and output go to `/dev/null`, logs only survive with a
+/// `log_file`.
+pub fn daemonize() -> Result<()> {
+    fork_and_exit_parent()?;
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error()).context("Could not start a new session");
+    }
+    // The session leader exits as well, so the daemon can never acquire a terminal
+    fork_and_exit_parent()?;
+
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+            return Err(io::Error::last_os_error()).context("Could not redirect stdio");
+        }
+    }
+    Ok(())
+}
+
+fn fork_and_exit_parent() -> Result<()> {
+    match unsafe { libc::fork() } {
+        pid if pid < 0 => Err(io::Error::last_os_error()).context("Could not fork"),
+        0 => Ok(()),
+        _ => std::process::exit(0),
+    }
+}
+
+/// A file holding the pid of the running server, removed when it is dropped.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create(path: &Path) -> Result<Self> {
+        if let Some(pid) = running_pid(path)? {
+            bail!("A server is already running with pid {}", pid);
+        }
+        let mut file = fs::File::create(path)
+            .with_context(|| format!("Could not create the pid file {}", path.display()))?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Returns the pid in the pid file if that process is still alive. Pid files left
+/// behind by a crashed server are ignored.
+pub fn running_pid(path: &Path) -> Result<Option<i32>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let pid: i32 = content
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid pid file {}", path.display()))?;
+    // Signal 0 only checks whether the process exists
+    let alive = unsafe { libc::kill(pid, 0) } == 0
+        || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+    Ok(alive.then_some(pid))
+}
+
+/// Time since the pid file was written, i.e. since the server started.
+pub fn uptime(path: &Path) -> Result<Duration> {
+    let started = fs::metadata(path)?.modified()?;
+    Ok(SystemTime::now()
+        .duration_since(started)
+        .unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_pid_file() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("gachix.pid");
+        assert_eq!(running_pid(&path)?, None);
+
+        let pid_file = PidFile::create(&path)?;
+        assert_eq!(running_pid(&path)?, Some(std::process::id() as i32));
+        assert!(PidFile::create(&path).is_err());
+        drop(pid_file);
+        assert!(!path.exists());
+
+        // No process has the largest possible pid
+        fs::write(&path, format!("{}\n", i32::MAX))?;
+        assert_eq!(running_pid(&path)?, None);
+        PidFile::create(&path)?;
+        Ok(())
+    }
+}
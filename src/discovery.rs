@@ -0,0 +1,373 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, UdpSocket};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Result, anyhow, bail};
+use tracing::{debug, info, warn};
+use url::Url;
+
+use crate::settings;
+
+/// Name below the discovery domain which holds the peer records
+const SERVICE: &str = "_gachix._tcp";
+const TIMEOUT: Duration = Duration::from_secs(5);
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_OPT: u16 = 41;
+const CLASS_IN: u16 = 1;
+
+/// A gachix peer announced with an SRV record.
+#[derive(Debug, Clone, PartialEq)]
+struct SrvRecord {
+    priority: u16,
+    weight: u16,
+    port: u16,
+    target: String,
+}
+
+/// Adds the peers and builders announced in DNS for the discovery domain to the
+/// configured ones.
+///
+/// SRV records of `_gachix._tcp.<domain>` list git peers, which are reached over SSH
+/// at the repository path given by a `path=<path>` TXT record of the same name. TXT
+/// records of the form `remote=<url>` and `builder=<url>` add peers and Nix daemons
+/// by URL.
+///
+/// Anyone able to spoof the answers could otherwise announce their own peers, so
+/// they are only used if the name server validated them with DNSSEC. Without it,
+/// only builders whose URL pins their host key with `base64-ssh-public-host-key`
+/// are added.
+pub fn discover(store: &mut settings::Store) -> Result<()> {
+    let Some(domain) = &store.discovery_domain else {
+        return Ok(());
+    };
+    let name = format!("{SERVICE}.{}", domain.trim_end_matches('.'));
+    let server = nameserver()?;
+    let (txt, txt_authenticated) = query(server, &name, TYPE_TXT)?;
+    let (srv, srv_authenticated) = query(server, &name, TYPE_SRV)?;
+
+    let mut path = String::new();
+    let mut remotes = Vec::new();
+    let mut builders = Vec::new();
+    for entry in txt.iter().filter_map(|data| parse_txt(data).ok()) {
+        match entry.split_once('=') {
+            Some(("path", value)) => path = value.trim_start_matches('/').to_string(),
+            Some(("remote", value)) => remotes.push(Url::parse(value)?),
+            Some(("builder", value)) => builders.push(Url::parse(value)?),
+            _ => debug!("Ignoring TXT record {entry} of {name}"),
+        }
+    }
+    let mut peers = srv
+        .iter()
+        .map(|data| parse_srv(data))
+        .collect::<Result<Vec<_>>>()?;
+    // Lower priorities are preferred, and within a priority the heavier peers
+    peers.sort_by_key(|p| (p.priority, u16::MAX - p.weight));
+    for peer in peers {
+        remotes.push(Url::parse(&format!(
+            "ssh://{}:{}/{path}",
+            peer.target, peer.port
+        ))?);
+    }
+    if !(txt_authenticated && srv_authenticated) {
+        warn!(
+            "The records of {} are not validated with DNSSEC, only using builders with a pinned host key",
+            name
+        );
+        remotes.clear();
+        builders.retain(has_pinned_host_key);
+    }
+
+    info!(
+        "Discovered {} peers and {} builders at {}",
+        remotes.len(),
+        builders.len(),
+        name
+    );
+    for url in remotes {
+        if !store.remotes.contains(&url) {
+            store.remotes.push(url);
+        }
+    }
+    for url in builders {
+        if !store.builders.contains(&url) {
+            store.builders.push(url);
+        }
+    }
+    Ok(())
+}
+
+fn has_pinned_host_key(url: &Url) -> bool {
+    url.query_pairs()
+        .any(|(name, _)| name == "base64-ssh-public-host-key")
+}
+
+/// The first name server of the system resolver configuration.
+fn nameserver() -> Result<SocketAddr> {
+    let config = fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+    let address = config
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .find_map(|address| address.trim().parse::<IpAddr>().ok())
+        .unwrap_or(IpAddr::from([127, 0, 0, 1]));
+    Ok(SocketAddr::new(address, 53))
+}
+
+/// Asks the name server for the records of `name` and returns their data, and
+/// whether the name server validated them with DNSSEC. Truncated answers are
+/// repeated over TCP.
+fn query(server: SocketAddr, name: &str, record_type: u16) -> Result<(Vec<Vec<u8>>, bool)> {
+    let id = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos() as u16);
+    let request = request_dnssec(encode_query(id, name, record_type)?);
+
+    let bind = if server.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(bind)?;
+    socket.set_read_timeout(Some(TIMEOUT))?;
+    socket.send_to(&request, server)?;
+    let mut buffer = [0; 4096];
+    let size = socket.recv(&mut buffer)?;
+    let mut response = buffer[..size].to_vec();
+
+    if response.len() > 2 && response[2] & 0x02 != 0 {
+        let mut stream = TcpStream::connect_timeout(&server, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.write_all(&(request.len() as u16).to_be_bytes())?;
+        stream.write_all(&request)?;
+        let mut length = [0; 2];
+        stream.read_exact(&mut length)?;
+        response = vec![0; u16::from_be_bytes(length) as usize];
+        stream.read_exact(&mut response)?;
+    }
+    let records = parse_response(&response, id, record_type)?;
+    Ok((records, is_authenticated(&response)))
+}
+
+/// Asks the name server to validate the answers with DNSSEC and to tell whether
+/// they are authentic, by setting the AD flag and the DO flag of an EDNS record.
+fn request_dnssec(mut query: Vec<u8>) -> Vec<u8> {
+    query[3] |= 0x20;
+    // One additional record
+    query[11] = 1;
+    query.push(0);
+    query.extend(TYPE_OPT.to_be_bytes());
+    // UDP payload size, extended response code and version, DO flag, no data
+    query.extend(4096u16.to_be_bytes());
+    query.extend([0, 0, 0x80, 0, 0, 0]);
+    query
+}
+
+/// Whether the AD flag of a response is set, i.e. the name server validated the
+/// answers with DNSSEC. Only a resolver on a trusted path can vouch for that, like
+/// the local one.
+fn is_authenticated(message: &[u8]) -> bool {
+    message.get(3).is_some_and(|flags| flags & 0x20 != 0)
+}
+
+fn encode_query(id: u16, name: &str, record_type: u16) -> Result<Vec<u8>> {
+    let mut message = Vec::new();
+    message.extend(id.to_be_bytes());
+    // Recursion desired, one question
+    message.extend([0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            bail!("Invalid domain name: {name}");
+        }
+        message.push(label.len() as u8);
+        message.extend(label.as_bytes());
+    }
+    message.push(0);
+    message.extend(record_type.to_be_bytes());
+    message.extend(CLASS_IN.to_be_bytes());
+    Ok(message)
+}
+
+fn read_u16(message: &[u8], offset: usize) -> Result<u16> {
+    message
+        .get(offset..offset + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(|| anyhow!("DNS message is truncated"))
+}
+
+/// Reads a possibly compressed domain name, returning it and the offset after it.
+fn read_name(message: &[u8], mut offset: usize) -> Result<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Every pointer has to go back, which rules out loops
+    let mut limit = offset;
+    loop {
+        let length = *message
+            .get(offset)
+            .ok_or_else(|| anyhow!("DNS message is truncated"))? as usize;
+        if length & 0xc0 == 0xc0 {
+            let pointer = (read_u16(message, offset)? & 0x3fff) as usize;
+            if pointer >= limit {
+                bail!("Invalid name compression in DNS message");
+            }
+            end.get_or_insert(offset + 2);
+            limit = pointer;
+            offset = pointer;
+        } else if length == 0 {
+            return Ok((labels.join("."), end.unwrap_or(offset + 1)));
+        } else {
+            let label = message
+                .get(offset + 1..offset + 1 + length)
+                .ok_or_else(|| anyhow!("DNS message is truncated"))?;
+            labels.push(String::from_utf8_lossy(label).to_string());
+            offset += 1 + length;
+        }
+    }
+}
+
+/// Returns the data of the answers of type `record_type`. SRV data is rewritten with
+/// an uncompressed target, so that it can be parsed without the message.
+fn parse_response(message: &[u8], id: u16, record_type: u16) -> Result<Vec<Vec<u8>>> {
+    if message.len() < 12 {
+        bail!("DNS message is truncated");
+    }
+    if read_u16(message, 0)? != id {
+        bail!("DNS response does not answer the query");
+    }
+    match message[3] & 0x0f {
+        0 => {}
+        // The name does not exist
+        3 => return Ok(Vec::new()),
+        code => bail!("DNS query failed with response code {code}"),
+    }
+    let questions = read_u16(message, 4)?;
+    let answers = read_u16(message, 6)?;
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = read_name(message, offset)?.1 + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        offset = read_name(message, offset)?.1;
+        let answer_type = read_u16(message, offset)?;
+        let length = read_u16(message, offset + 8)? as usize;
+        let start = offset + 10;
+        let data = message
+            .get(start..start + length)
+            .ok_or_else(|| anyhow!("DNS message is truncated"))?;
+        if answer_type == record_type {
+            if record_type == TYPE_SRV {
+                let (target, _) = read_name(message, start + 6)?;
+                let mut srv = data.get(..6).unwrap_or_default().to_vec();
+                srv.extend(target.as_bytes());
+                records.push(srv);
+            } else {
+                records.push(data.to_vec());
+            }
+        }
+        offset = start + length;
+    }
+    Ok(records)
+}
+
+fn parse_srv(data: &[u8]) -> Result<SrvRecord> {
+    if data.len() < 6 {
+        bail!("SRV record is truncated");
+    }
+    Ok(SrvRecord {
+        priority: read_u16(data, 0)?,
+        weight: read_u16(data, 2)?,
+        port: read_u16(data, 4)?,
+        target: String::from_utf8(data[6..].to_vec())?,
+    })
+}
+
+/// Joins the character strings of a TXT record.
+fn parse_txt(data: &[u8]) -> Result<String> {
+    let mut text = Vec::new();
+    let mut offset = 0;
+    while let Some(&length) = data.get(offset) {
+        let end = offset + 1 + length as usize;
+        text.extend(
+            data.get(offset + 1..end)
+                .ok_or_else(|| anyhow!("TXT record is truncated"))?,
+        );
+        offset = end;
+    }
+    Ok(String::from_utf8(text)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(id: u16, record_type: u16, answers: &[&[u8]]) -> Result<Vec<u8>> {
+        let mut message = encode_query(id, "_gachix._tcp.example.com", record_type)?;
+        message[2] = 0x81;
+        message[3] = 0x80;
+        message[7] = answers.len() as u8;
+        for data in answers {
+            // Name pointing at the question
+            message.extend([0xc0, 12]);
+            message.extend(record_type.to_be_bytes());
+            message.extend(CLASS_IN.to_be_bytes());
+            message.extend(300u32.to_be_bytes());
+            message.extend((data.len() as u16).to_be_bytes());
+            message.extend(*data);
+        }
+        Ok(message)
+    }
+
+    #[test]
+    fn test_parse_srv_response() -> Result<()> {
+        // Priority 10, weight 5, port 2222, target peer.example.com compressed
+        // against the question
+        let mut data = vec![0, 10, 0, 5, 0x08, 0xae, 4];
+        data.extend(b"peer");
+        data.extend([0xc0, 25]);
+        let message = response(7, TYPE_SRV, &[&data])?;
+        let records = parse_response(&message, 7, TYPE_SRV)?;
+        assert_eq!(
+            parse_srv(&records[0])?,
+            SrvRecord {
+                priority: 10,
+                weight: 5,
+                port: 2222,
+                target: "peer.example.com".to_string()
+            }
+        );
+        assert!(parse_response(&message, 8, TYPE_SRV).is_err());
+        assert!(!is_authenticated(&message));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_txt_response() -> Result<()> {
+        let data = b"\x0bremote=ssh:\x11//peer/srv/gachix";
+        let message = response(1, TYPE_TXT, &[data])?;
+        let records = parse_response(&message, 1, TYPE_TXT)?;
+        assert_eq!(parse_txt(&records[0])?, "remote=ssh://peer/srv/gachix");
+        assert!(parse_txt(b"\x05abc").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_request_dnssec() -> Result<()> {
+        let query = request_dnssec(encode_query(3, "_gachix._tcp.example.com", TYPE_TXT)?);
+        assert_eq!(read_u16(&query, 2)?, 0x0120);
+        assert_eq!(read_u16(&query, 10)?, 1);
+        assert_eq!(
+            query[query.len() - 11..],
+            [0, 0, 41, 16, 0, 0, 0, 0x80, 0, 0, 0]
+        );
+
+        let mut message = response(3, TYPE_TXT, &[])?;
+        message[3] |= 0x20;
+        assert!(is_authenticated(&message));
+        assert!(has_pinned_host_key(&Url::parse(
+            "ssh://builder?base64-ssh-public-host-key=c3NoLWVkMjU1MTk="
+        )?));
+        assert!(!has_pinned_host_key(&Url::parse("ssh://builder")?));
+        Ok(())
+    }
+}
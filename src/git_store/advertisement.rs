@@ -0,0 +1,95 @@
+use git2::Oid;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PackageRef {
+    pub hash: String,
+    pub commit: String,
+}
+
+/// The packages a store holds with their result commits, so that peers and
+/// monitoring can learn the contents of a store without enumerating its references
+/// over git.
+///
+/// `head` identifies the advertised set. Passed back as `since`, only the packages
+/// added or changed since then are listed in `packages`, and the removed ones in
+/// `removed`. If `since` is unknown, e.g. because garbage collection pruned it, the
+/// full set is advertised and `full` is set.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RefAdvertisement {
+    /// Not set for read-only stores, which cannot record the advertised set
+    pub head: Option<String>,
+    pub full: bool,
+    pub packages: Vec<PackageRef>,
+    pub removed: Vec<String>,
+}
+
+impl RefAdvertisement {
+    pub fn full(head: Option<Oid>, current: &BTreeMap<String, Oid>) -> Self {
+        Self {
+            head: head.map(|oid| oid.to_string()),
+            full: true,
+            packages: current.iter().map(package_ref).collect(),
+            removed: Vec::new(),
+        }
+    }
+
+    pub fn since(
+        head: Oid,
+        previous: &BTreeMap<String, Oid>,
+        current: &BTreeMap<String, Oid>,
+    ) -> Self {
+        Self {
+            head: Some(head.to_string()),
+            full: false,
+            packages: current
+                .iter()
+                .filter(|(hash, oid)| previous.get(*hash) != Some(*oid))
+                .map(package_ref)
+                .collect(),
+            removed: previous
+                .keys()
+                .filter(|hash| !current.contains_key(*hash))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+fn package_ref((hash, oid): (&String, &Oid)) -> PackageRef {
+    PackageRef {
+        hash: hash.clone(),
+        commit: oid.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_since() {
+        let oid = |n: u8| Oid::from_bytes(&[n; 20]).unwrap();
+        let previous = BTreeMap::from([
+            ("a".to_string(), oid(1)),
+            ("b".to_string(), oid(2)),
+            ("c".to_string(), oid(3)),
+        ]);
+        let current = BTreeMap::from([
+            ("a".to_string(), oid(1)),
+            ("b".to_string(), oid(4)),
+            ("d".to_string(), oid(5)),
+        ]);
+        let advertisement = RefAdvertisement::since(oid(9), &previous, &current);
+        assert!(!advertisement.full);
+        let hashes: Vec<&str> = advertisement
+            .packages
+            .iter()
+            .map(|p| p.hash.as_str())
+            .collect();
+        assert_eq!(hashes, ["b", "d"]);
+        assert_eq!(advertisement.packages[0].commit, oid(4).to_string());
+        assert_eq!(advertisement.removed, ["c"]);
+    }
+}
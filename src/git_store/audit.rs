@@ -0,0 +1,210 @@
+use super::history::format_time;
+use anyhow::{Result, anyhow};
+use git2::Oid;
+use std::fmt::Display;
+
+/// Every operation which changes the store, whether requested on the command line,
+/// the control socket or the API, is recorded as a commit on this reference, along
+/// with who requested it and whether it succeeded. Unlike the history, which only
+/// holds the changes of packages, failed and refused requests are recorded too.
+pub const AUDIT_REF: &str = "refs/gachix/audit";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Succeeded,
+    Failed(String),
+}
+
+impl Outcome {
+    pub fn of<T>(result: &Result<T>) -> Self {
+        match result {
+            Ok(_) => Outcome::Succeeded,
+            Err(e) => Outcome::Failed(e.to_string()),
+        }
+    }
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Succeeded => f.write_str("ok"),
+            Outcome::Failed(reason) => write!(f, "failed: {reason}"),
+        }
+    }
+}
+
+/// An operation as recorded in the audit trail.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditRecord {
+    /// Who requested the operation, `user@host` for local ones and e.g.
+    /// `admin@<address>` for requests to the API
+    pub principal: String,
+    /// The command, or the method of an API request
+    pub action: String,
+    /// What the operation was applied to, empty if it applies to the whole store
+    pub target: String,
+    pub outcome: Outcome,
+}
+
+impl AuditRecord {
+    /// Renders the message of an audit commit, one field per line so that neither
+    /// the target nor the reason of a failure can be confused with another field.
+    pub fn to_message(&self) -> String {
+        let summary = format!("{} {}", self.action, self.target);
+        // Messages of errors can span several lines
+        let outcome = self.outcome.to_string().replace('\n', " ");
+        format!(
+            "{}\n\nPrincipal: {}\nAction: {}\nTarget: {}\nOutcome: {}\n",
+            summary.trim_end(),
+            self.principal,
+            self.action,
+            self.target,
+            outcome
+        )
+    }
+
+    pub fn parse(message: &str) -> Result<Self> {
+        let field = |name: &str| {
+            message
+                .lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(": "))
+                .map(str::to_string)
+                .ok_or_else(|| anyhow!("Audit record without {}: {}", name, message))
+        };
+        let outcome = match field("Outcome")?.as_str() {
+            "ok" => Outcome::Succeeded,
+            outcome => Outcome::Failed(
+                outcome
+                    .strip_prefix("failed: ")
+                    .unwrap_or(outcome)
+                    .to_string(),
+            ),
+        };
+        Ok(Self {
+            principal: field("Principal")?,
+            action: field("Action")?,
+            target: field("Target").unwrap_or_default(),
+            outcome,
+        })
+    }
+}
+
+/// A recorded operation, as shown by `gachix audit`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    /// The audit commit which recorded the operation
+    pub id: Oid,
+    /// Seconds since the Unix epoch
+    pub time: i64,
+    pub record: AuditRecord,
+}
+
+impl Display for AuditEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let id = self.id.to_string();
+        write!(
+            f,
+            "{} {} {} {}",
+            format_time(self.time),
+            &id[..10],
+            self.record.principal,
+            self.record.action
+        )?;
+        if !self.record.target.is_empty() {
+            write!(f, " {}", self.record.target)?;
+        }
+        write!(f, " {}", self.record.outcome)
+    }
+}
+
+/// Selects the operations shown by `gachix audit`.
+#[derive(Debug, Clone, Default)]
+pub struct AuditFilter {
+    /// Only operations requested by principals containing this string
+    pub principal: Option<String>,
+    /// Only operations with this action
+    pub action: Option<String>,
+    /// Only operations at or after this time, in seconds since the Unix epoch
+    pub since: Option<i64>,
+    /// Only operations at or before this time, in seconds since the Unix epoch
+    pub until: Option<i64>,
+    /// Only operations which failed or were refused
+    pub failed: bool,
+}
+
+impl AuditFilter {
+    pub fn matches(&self, time: i64, record: &AuditRecord) -> bool {
+        self.since.is_none_or(|since| time >= since)
+            && self.until.is_none_or(|until| time <= until)
+            && self
+                .principal
+                .as_ref()
+                .is_none_or(|principal| record.principal.contains(principal.as_str()))
+            && self
+                .action
+                .as_ref()
+                .is_none_or(|action| record.action == *action)
+            && (!self.failed || record.outcome != Outcome::Succeeded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audit_records() -> Result<()> {
+        let record = AuditRecord {
+            principal: "admin@10.0.0.1".to_string(),
+            action: "put".to_string(),
+            target: "/cas/abc".to_string(),
+            outcome: Outcome::Failed("401 Unauthorized\nretry".to_string()),
+        };
+        let message = record.to_message();
+        assert!(message.starts_with("put /cas/abc\n\n"));
+        let parsed = AuditRecord::parse(&message)?;
+        assert_eq!(parsed.principal, record.principal);
+        assert_eq!(parsed.target, record.target);
+        assert_eq!(
+            parsed.outcome,
+            Outcome::Failed("401 Unauthorized retry".to_string())
+        );
+
+        let gc = AuditRecord {
+            principal: "alice@builder".to_string(),
+            action: "gc".to_string(),
+            target: String::new(),
+            outcome: Outcome::of(&Ok(())),
+        };
+        assert!(gc.to_message().starts_with("gc\n\n"));
+        assert_eq!(AuditRecord::parse(&gc.to_message())?, gc);
+        assert!(AuditRecord::parse("gc").is_err());
+
+        let entry = AuditEntry {
+            id: Oid::from_bytes(&[0xab; 20])?,
+            time: 1714564800,
+            record: gc.clone(),
+        };
+        assert_eq!(
+            entry.to_string(),
+            "2024-05-01T12:00:00Z ababababab alice@builder gc ok"
+        );
+
+        let filter = AuditFilter {
+            principal: Some("admin".to_string()),
+            failed: true,
+            ..Default::default()
+        };
+        assert!(filter.matches(0, &record));
+        assert!(!filter.matches(0, &gc));
+        let since = AuditFilter {
+            since: Some(100),
+            action: Some("gc".to_string()),
+            ..Default::default()
+        };
+        assert!(since.matches(100, &gc));
+        assert!(!since.matches(99, &gc));
+        assert!(!since.matches(100, &record));
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+use anyhow::{Result, bail};
+use std::fmt::Display;
+
+use super::history::format_time;
+use super::tags::validate_name;
+
+/// Channels are logs `<name>.channel` below this namespace with a commit for every
+/// published version, whose tree lists the commits of its packages by the file
+/// name of their store path, see `GitRepo::write_commit_index`. Like those of
+/// tags, the names end in `.channel`, see `snapshot::package_ref`.
+pub const CHANNELS_NAMESPACE: &str = "refs/channels";
+
+pub fn channel_ref(name: &str) -> String {
+    format!("{CHANNELS_NAMESPACE}/{name}.channel")
+}
+
+/// Returns the name of the channel a reference holds.
+pub fn parse_channel_ref(reference: &str) -> Option<&str> {
+    reference
+        .strip_prefix(CHANNELS_NAMESPACE)?
+        .strip_prefix('/')?
+        .strip_suffix(".channel")
+        .filter(|name| !name.contains('/'))
+}
+
+/// Channel names follow the rules of tag names, but can't end in a version, so
+/// that `team-tools-v42` always means version 42 of `team-tools`.
+pub fn validate_channel_name(name: &str) -> Result<()> {
+    validate_name("channel", name)?;
+    if parse_version(name).is_some() {
+        bail!("Channel name {} ends in a version", name);
+    }
+    Ok(())
+}
+
+/// Names a version of a channel, e.g. `team-tools-v42`. The commit publishing
+/// the version has it as its message.
+pub fn version_name(name: &str, version: u64) -> String {
+    format!("{name}-v{version}")
+}
+
+/// Splits the name of a version into the name of the channel and the version.
+pub fn parse_version(spec: &str) -> Option<(&str, u64)> {
+    let (name, version) = spec.rsplit_once("-v")?;
+    if name.is_empty() || !version.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((name, version.parse().ok()?))
+}
+
+/// A published version of a channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelVersion {
+    pub name: String,
+    pub version: u64,
+    /// Seconds since the Unix epoch
+    pub published_at: i64,
+    pub publisher: String,
+    pub store_paths: Vec<String>,
+}
+
+impl Display for ChannelVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} packages",
+            version_name(&self.name, self.version),
+            format_time(self.published_at),
+            self.publisher,
+            self.store_paths.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_names() {
+        let reference = channel_ref("team-tools");
+        assert_eq!(reference, "refs/channels/team-tools.channel");
+        assert_eq!(parse_channel_ref(&reference), Some("team-tools"));
+        assert_eq!(parse_channel_ref("refs/tags/team-tools.tag"), None);
+
+        assert_eq!(version_name("team-tools", 42), "team-tools-v42");
+        assert_eq!(parse_version("team-tools-v42"), Some(("team-tools", 42)));
+        assert_eq!(parse_version("team-tools"), None);
+        assert_eq!(parse_version("team-tools-v"), None);
+        assert_eq!(parse_version("team-tools-v+4"), None);
+        assert_eq!(parse_version("-v4"), None);
+
+        assert!(validate_channel_name("team-tools").is_ok());
+        assert!(validate_channel_name("team-tools-v2").is_err());
+        assert!(validate_channel_name("team/tools").is_err());
+
+        let version = ChannelVersion {
+            name: "team-tools".to_string(),
+            version: 3,
+            published_at: 1714564800,
+            publisher: "alice@builder".to_string(),
+            store_paths: vec![],
+        };
+        assert_eq!(
+            version.to_string(),
+            "team-tools-v3 2024-05-01T12:00:00Z alice@builder 0 packages"
+        );
+    }
+}
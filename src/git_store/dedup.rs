@@ -0,0 +1,87 @@
+use crate::nar::decode::Dedup;
+use std::fmt::Display;
+
+/// How much of the contents of the packages added with a closure was new to the
+/// repository. Packages which were stored already or fetched from a git peer are
+/// not listed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DedupReport {
+    pub packages: Vec<(String, Dedup)>,
+    pub total: Dedup,
+    /// Packages of the closure stored without some of their references, which the
+    /// ingest filters skipped, along with the names of those references
+    pub incomplete: Vec<(String, Vec<String>)>,
+}
+
+impl DedupReport {
+    pub fn add(&mut self, name: &str, dedup: Dedup) {
+        self.packages.push((name.to_string(), dedup));
+        self.total.add(dedup);
+    }
+}
+
+impl Display for DedupReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (name, dedup) in &self.packages {
+            writeln!(
+                f,
+                "{}: {} bytes new, {} bytes shared ({:.1}%)",
+                name,
+                dedup.new_bytes,
+                dedup.shared_bytes,
+                dedup.shared_percent()
+            )?;
+        }
+        writeln!(
+            f,
+            "Total: {} bytes new, {} bytes shared ({:.1}%)",
+            self.total.new_bytes,
+            self.total.shared_bytes,
+            self.total.shared_percent()
+        )?;
+        for (name, missing) in &self.incomplete {
+            writeln!(f, "Incomplete: {} lacks {}", name, missing.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let mut report = DedupReport::default();
+        report.add(
+            "hello-2.12",
+            Dedup {
+                new_bytes: 300,
+                shared_bytes: 100,
+            },
+        );
+        report.add(
+            "glibc-2.40",
+            Dedup {
+                new_bytes: 0,
+                shared_bytes: 600,
+            },
+        );
+        assert_eq!(report.total.new_bytes, 300);
+        assert_eq!(report.total.shared_percent(), 70.0);
+        assert_eq!(
+            report.to_string(),
+            "hello-2.12: 300 bytes new, 100 bytes shared (25.0%)\n\
+             glibc-2.40: 0 bytes new, 600 bytes shared (100.0%)\n\
+             Total: 300 bytes new, 700 bytes shared (70.0%)\n"
+        );
+
+        report.packages.clear();
+        report.incomplete = vec![("hello-2.12".to_string(), vec!["hello-2.12-doc".to_string()])];
+        assert!(
+            report
+                .to_string()
+                .ends_with("Incomplete: hello-2.12 lacks hello-2.12-doc\n")
+        );
+    }
+}
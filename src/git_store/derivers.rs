@@ -0,0 +1,70 @@
+use crate::nix_interface::path::NixPath;
+use anyhow::{Result, bail};
+
+/// Index from the derivation which built a package to the package, so that whether
+/// the outputs of a derivation are stored can be told without knowing their paths.
+/// `<deriver hash>/<hash>` points at the result commit of every stored output.
+pub const DERIVERS_NAMESPACE: &str = "refs/deriver";
+
+pub fn deriver_ref(deriver_hash: &str, hash: &str) -> String {
+    format!("{DERIVERS_NAMESPACE}/{deriver_hash}/{hash}")
+}
+
+/// Build logs uploaded to the server are blobs referenced by
+/// `<deriver hash>.log` below this namespace. For the name, see
+/// `snapshot::package_ref`.
+pub const LOGS_NAMESPACE: &str = "refs/logs";
+
+pub fn log_ref(deriver_hash: &str) -> String {
+    format!("{LOGS_NAMESPACE}/{deriver_hash}.log")
+}
+
+/// Splits an index reference into the hash of the derivation and of the output.
+pub fn parse_deriver_ref(reference: &str) -> Option<(&str, &str)> {
+    reference
+        .strip_prefix(DERIVERS_NAMESPACE)?
+        .strip_prefix('/')?
+        .split_once('/')
+}
+
+/// Takes the hash of a derivation given as a store path, a file name like
+/// `<hash>-hello-2.12.drv`, or just its hash.
+pub fn deriver_hash(deriver: &str) -> Result<String> {
+    if deriver.len() == 32 && deriver.bytes().all(|c| c.is_ascii_alphanumeric()) {
+        return Ok(deriver.to_string());
+    }
+    let path = NixPath::new(deriver)?;
+    if !path.get_name().ends_with(".drv") {
+        bail!("{} is not a derivation", deriver);
+    }
+    Ok(path.get_base_32_hash().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deriver_refs() -> Result<()> {
+        let drv = "d".repeat(32);
+        let out = "o".repeat(32);
+        let reference = deriver_ref(&drv, &out);
+        assert_eq!(reference, format!("refs/deriver/{drv}/{out}"));
+        assert_eq!(
+            parse_deriver_ref(&reference),
+            Some((drv.as_str(), out.as_str()))
+        );
+        assert_eq!(parse_deriver_ref(&format!("refs/{out}/result")), None);
+        assert_eq!(log_ref(&drv), format!("refs/logs/{drv}.log"));
+
+        assert_eq!(deriver_hash(&drv)?, drv);
+        assert_eq!(deriver_hash(&format!("{drv}-hello-2.12.drv"))?, drv);
+        assert_eq!(
+            deriver_hash(&format!("/nix/store/{drv}-hello-2.12.drv"))?,
+            drv
+        );
+        assert!(deriver_hash(&format!("/nix/store/{out}-hello-2.12")).is_err());
+        assert!(deriver_hash("hello").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,46 @@
+use git2::Oid;
+use std::fmt::Display;
+
+/// Progress of store operations, published to the subscribers of a store.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    PackageStarted {
+        path: String,
+    },
+    BytesTransferred {
+        path: String,
+        bytes: u64,
+    },
+    Committed {
+        path: String,
+        commit: Oid,
+    },
+    Skipped {
+        path: String,
+        reason: String,
+    },
+    Failed {
+        path: String,
+        error: String,
+    },
+    /// A log line or activity of a daemon building the derivation at `path`
+    BuildOutput {
+        path: String,
+        line: String,
+    },
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Event::PackageStarted { path } => write!(f, "fetching {path}"),
+            Event::BytesTransferred { path, bytes } => {
+                write!(f, "transferred {bytes} bytes of {path}")
+            }
+            Event::Committed { path, commit } => write!(f, "added {path} ({commit})"),
+            Event::Skipped { path, reason } => write!(f, "skipped {path}: {reason}"),
+            Event::Failed { path, error } => write!(f, "failed {path}: {error}"),
+            Event::BuildOutput { line, .. } => write!(f, "{line}"),
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use anyhow::{Context, Result, bail};
+use git2::{FileMode, ObjectType, Oid, Repository};
+use std::ffi::{CString, OsStr};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+/// Nix sets the modification time of every file in the store to one second after the
+/// epoch, zero is reserved for files which are being written.
+const CANONICAL_TIME: libc::time_t = 1;
+
+/// Extended attributes which can't be removed and are kept by Nix as well.
+const KEPT_XATTRS: &[&[u8]] = &[b"security.selinux", b"system.nfs4_acl", b"security.csm"];
+
+/// Writes a stored entry to `dest`, which must not exist yet, with the metadata of a
+/// Nix store path: read-only permissions, the canonical modification time and no
+/// extended attributes.
+pub fn extract(repo: &Repository, oid: Oid, dest: &Path) -> Result<()> {
+    if dest.symlink_metadata().is_ok() {
+        bail!("{} already exists", dest.display());
+    }
+    let filemode = match repo.find_object(oid, None)?.kind() {
+        Some(ObjectType::Blob) => FileMode::Blob,
+        Some(ObjectType::Tree) => FileMode::Tree,
+        _ => bail!("Object must either be a tree or a blob"),
+    };
+    write_entry(repo, oid, filemode, dest)
+        .with_context(|| format!("Failed to extract {} to {}", oid, dest.display()))
+}
+
+fn write_entry(repo: &Repository, oid: Oid, filemode: FileMode, path: &Path) -> Result<()> {
+    let mode = match filemode {
+        FileMode::Blob | FileMode::BlobGroupWritable => {
+            fs::write(path, repo.find_blob(oid)?.content())?;
+            Some(0o444)
+        }
+        FileMode::BlobExecutable => {
+            fs::write(path, repo.find_blob(oid)?.content())?;
+            Some(0o555)
+        }
+        FileMode::Link => {
+            let blob = repo.find_blob(oid)?;
+            std::os::unix::fs::symlink(OsStr::from_bytes(blob.content()), path)?;
+            // Permissions of symlinks can't be changed on Linux
+            None
+        }
+        FileMode::Tree => {
+            fs::create_dir(path)?;
+            for entry in repo.find_tree(oid)?.iter() {
+                let filemode = match entry.filemode() {
+                    0o100644 => FileMode::Blob,
+                    0o100664 => FileMode::BlobGroupWritable,
+                    0o100755 => FileMode::BlobExecutable,
+                    0o120000 => FileMode::Link,
+                    0o040000 => FileMode::Tree,
+                    other => bail!("Unsupported file mode {:o} in tree {}", other, oid),
+                };
+                let name = entry_name(entry.name_bytes())?;
+                write_entry(repo, entry.id(), filemode, &path.join(name))?;
+            }
+            Some(0o555)
+        }
+        _ => bail!("Unsupported file mode of {}", oid),
+    };
+    canonicalise(path, mode)
+}
+
+/// The name of a tree entry as a single path component. Trees come from peers too,
+/// so a name like `..` or one with a `/` must not write outside of the destination.
+fn entry_name(name: &[u8]) -> Result<&OsStr> {
+    if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') {
+        bail!(
+            "Invalid name {:?} of a tree entry",
+            String::from_utf8_lossy(name)
+        );
+    }
+    Ok(OsStr::from_bytes(name))
+}
+
+/// Applies the metadata of a store path to a file after it was written. Directories
+/// have to be canonicalised after their contents, since adding to them changes their
+/// modification time.
+fn canonicalise(path: &Path, mode: Option<u32>) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    remove_xattrs(&c_path)?;
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    let time = libc::timespec {
+        tv_sec: CANONICAL_TIME,
+        tv_nsec: 0,
+    };
+    let times = [time, time];
+    // SAFETY: the path is a valid C string and `times` holds two timespecs
+    let result = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Failed to set the modification time of {}", path.display()));
+    }
+    Ok(())
+}
+
+fn remove_xattrs(c_path: &CString) -> Result<()> {
+    // SAFETY: a null buffer of size zero only queries the size of the list
+    let size = unsafe { libc::llistxattr(c_path.as_ptr(), std::ptr::null_mut(), 0) };
+    if size <= 0 {
+        // Not supported by the file system, or there are none
+        return Ok(());
+    }
+    let mut names = vec![0u8; size as usize];
+    // SAFETY: the buffer is valid for its whole length
+    let size = unsafe { libc::llistxattr(c_path.as_ptr(), names.as_mut_ptr().cast(), names.len()) };
+    if size < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    for name in names[..size as usize].split(|&b| b == 0) {
+        if name.is_empty() || KEPT_XATTRS.contains(&name) {
+            continue;
+        }
+        let c_name = CString::new(name)?;
+        // SAFETY: both are valid C strings
+        if unsafe { libc::lremovexattr(c_path.as_ptr(), c_name.as_ptr()) } != 0 {
+            return Err(io::Error::last_os_error()).with_context(|| {
+                format!(
+                    "Failed to remove extended attribute {}",
+                    String::from_utf8_lossy(name)
+                )
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_extract() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = Repository::init(temp_dir.path().join("repo"))?;
+        let mut bin = repo.treebuilder(None)?;
+        bin.insert(
+            "hello",
+            repo.blob(b"#!/bin/sh")?,
+            FileMode::BlobExecutable.into(),
+        )?;
+        let bin = bin.write()?;
+        let mut root = repo.treebuilder(None)?;
+        root.insert("bin", bin, FileMode::Tree.into())?;
+        root.insert("README", repo.blob(b"hello")?, FileMode::Blob.into())?;
+        root.insert("run", repo.blob(b"bin/hello")?, FileMode::Link.into())?;
+        let root = root.write()?;
+
+        let dest = temp_dir.path().join("out");
+        extract(&repo, root, &dest)?;
+        assert!(extract(&repo, root, &dest).is_err());
+
+        assert_eq!(fs::read(dest.join("README"))?, b"hello");
+        assert_eq!(fs::read_link(dest.join("run"))?, Path::new("bin/hello"));
+        let modes = [
+            ("", 0o555),
+            ("README", 0o444),
+            ("bin", 0o555),
+            ("bin/hello", 0o555),
+        ];
+        for (path, mode) in modes {
+            let metadata = dest.join(path).symlink_metadata()?;
+            assert_eq!(metadata.mode() & 0o777, mode, "{path}");
+            assert_eq!(metadata.mtime(), CANONICAL_TIME, "{path}");
+        }
+        assert_eq!(dest.join("run").symlink_metadata()?.mtime(), CANONICAL_TIME);
+
+        // Let the temporary directory be removed
+        fs::set_permissions(dest.join("bin"), fs::Permissions::from_mode(0o755))?;
+        fs::set_permissions(&dest, fs::Permissions::from_mode(0o755))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry_name() {
+        assert_eq!(entry_name(b"hello").unwrap(), "hello");
+        assert_eq!(entry_name(b"..hidden").unwrap(), "..hidden");
+        for name in [&b""[..], b".", b"..", b"/etc", b"bin/../..", b"a/"] {
+            assert!(entry_name(name).is_err(), "{name:?}");
+        }
+    }
+}
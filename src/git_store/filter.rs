@@ -0,0 +1,109 @@
+use anyhow::Result;
+use regex::Regex;
+
+use crate::settings;
+
+/// The compiled ingest filters of a store.
+#[derive(Debug, Clone, Default)]
+pub struct IngestFilter {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+    max_nar_size: Option<u64>,
+    systems: Vec<String>,
+}
+
+/// Translates a glob with `*` and `?` wildcards into an anchored regex.
+pub fn glob_to_regex(glob: &str) -> Result<Regex> {
+    let pattern = regex::escape(glob).replace(r"\*", ".*").replace(r"\?", ".");
+    Ok(Regex::new(&format!("^{pattern}$"))?)
+}
+
+impl IngestFilter {
+    pub fn new(filters: &settings::IngestFilters) -> Result<Self> {
+        let compile = |globs: &[String]| -> Result<Vec<Regex>> {
+            globs.iter().map(|g| glob_to_regex(g)).collect()
+        };
+        Ok(Self {
+            include: compile(&filters.include)?,
+            exclude: compile(&filters.exclude)?,
+            max_nar_size: filters.max_nar_size,
+            systems: filters.systems.clone(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty()
+            && self.exclude.is_empty()
+            && self.max_nar_size.is_none()
+            && self.systems.is_empty()
+    }
+
+    pub fn filters_systems(&self) -> bool {
+        !self.systems.is_empty()
+    }
+
+    /// Returns why a package is skipped, or `None` if it may be added. Packages whose
+    /// system is unknown are not skipped for their system.
+    pub fn rejects(&self, name: &str, nar_size: u64, system: Option<&str>) -> Option<String> {
+        if !self.include.is_empty() && !self.include.iter().any(|r| r.is_match(name)) {
+            return Some("not included".to_string());
+        }
+        if let Some(glob) = self.exclude.iter().find(|r| r.is_match(name)) {
+            return Some(format!("excluded by {}", glob.as_str()));
+        }
+        if let Some(max_nar_size) = self.max_nar_size
+            && nar_size > max_nar_size
+        {
+            return Some(format!("NAR size {nar_size} exceeds {max_nar_size}"));
+        }
+        if let Some(system) = system
+            && !self.systems.is_empty()
+            && !self.systems.iter().any(|s| s == system)
+        {
+            return Some(format!("built for {system}"));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejects() -> Result<()> {
+        let filter = IngestFilter::new(&settings::IngestFilters {
+            include: vec![],
+            exclude: vec!["*-source".to_string(), "*-debug".to_string()],
+            max_nar_size: Some(1000),
+            systems: vec!["x86_64-linux".to_string()],
+        })?;
+
+        assert_eq!(
+            filter.rejects("hello-2.12.1", 10, Some("x86_64-linux")),
+            None
+        );
+        assert_eq!(filter.rejects("hello-2.12.1", 10, None), None);
+        assert!(filter.rejects("hello-2.12.1-source", 10, None).is_some());
+        assert!(filter.rejects("hello-2.12.1", 1001, None).is_some());
+        assert!(
+            filter
+                .rejects("hello-2.12.1", 10, Some("aarch64-darwin"))
+                .is_some()
+        );
+        assert_eq!(filter.rejects("source.tar.gz", 10, None), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_include() -> Result<()> {
+        let filter = IngestFilter::new(&settings::IngestFilters {
+            include: vec!["glibc-?.*".to_string()],
+            ..Default::default()
+        })?;
+        assert_eq!(filter.rejects("glibc-2.40", 0, None), None);
+        assert!(filter.rejects("glibc-2.40-dev", 0, None).is_none());
+        assert!(filter.rejects("zlib-1.3", 0, None).is_some());
+        Ok(())
+    }
+}
@@ -0,0 +1,100 @@
+use std::fmt::Display;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RefKind {
+    Result,
+    Narinfo,
+}
+
+impl Display for RefKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RefKind::Result => f.write_str("result"),
+            RefKind::Narinfo => f.write_str("narinfo"),
+        }
+    }
+}
+
+/// An entry of which only one of the `result` and `narinfo` references exists.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DanglingEntry {
+    pub hash: String,
+    pub missing: RefKind,
+}
+
+/// A divergence between the narinfo of an entry and the closure stored for it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Inconsistency {
+    InvalidNarinfo {
+        hash: String,
+        error: String,
+    },
+    /// A path listed in `References` is not stored
+    MissingReference {
+        hash: String,
+        reference: String,
+    },
+    /// The parents of the result commit differ from the result commits of the references
+    ParentMismatch {
+        hash: String,
+        missing: Vec<String>,
+        unexpected: Vec<String>,
+    },
+}
+
+impl Display for Inconsistency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Inconsistency::InvalidNarinfo { hash, error } => {
+                write!(f, "entry {hash}: invalid narinfo: {error}")
+            }
+            Inconsistency::MissingReference { hash, reference } => {
+                write!(f, "entry {hash}: referenced path {reference} is not stored")
+            }
+            Inconsistency::ParentMismatch {
+                hash,
+                missing,
+                unexpected,
+            } => write!(
+                f,
+                "entry {hash}: commit parents diverge from references (missing: [{}], unexpected: [{}])",
+                missing.join(", "),
+                unexpected.join(", ")
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    pub checked: usize,
+    pub dangling: Vec<DanglingEntry>,
+    pub inconsistent: Vec<Inconsistency>,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.dangling.is_empty() && self.inconsistent.is_empty()
+    }
+}
+
+impl Display for FsckReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for entry in &self.dangling {
+            writeln!(
+                f,
+                "dangling entry {}: missing {} reference",
+                entry.hash, entry.missing
+            )?;
+        }
+        for inconsistency in &self.inconsistent {
+            writeln!(f, "{inconsistency}")?;
+        }
+        writeln!(
+            f,
+            "Checked {} entries, found {} problems",
+            self.checked,
+            self.dangling.len() + self.inconsistent.len()
+        )
+    }
+}
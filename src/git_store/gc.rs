@@ -0,0 +1,692 @@
+use crate::settings;
+use anyhow::{Result, bail};
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::fmt::Display;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy)]
+pub struct DiskUsage {
+    pub total: u64,
+    pub available: u64,
+}
+
+impl DiskUsage {
+    /// Measures the filesystem containing `path`.
+    pub fn of(path: &Path) -> Result<Self> {
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            bail!("statvfs failed: {}", std::io::Error::last_os_error());
+        }
+        Ok(Self {
+            total: stat.f_blocks as u64 * stat.f_frsize as u64,
+            available: stat.f_bavail as u64 * stat.f_frsize as u64,
+        })
+    }
+
+    pub fn used(&self) -> u64 {
+        self.total - self.available
+    }
+
+    pub fn used_percent(&self) -> f64 {
+        if self.total == 0 {
+            return 0.0;
+        }
+        self.used() as f64 * 100.0 / self.total as f64
+    }
+
+    /// Bytes which have to be freed to bring the usage down to `watermark` percent.
+    pub fn bytes_above(&self, watermark: f64) -> u64 {
+        let target_used = self.total as f64 * watermark / 100.0;
+        (self.used() as f64 - target_used).max(0.0) as u64
+    }
+
+    /// Bytes freed since `before` was measured, none if the usage grew meanwhile.
+    pub fn freed_since(&self, before: &DiskUsage) -> u64 {
+        before.used().saturating_sub(self.used())
+    }
+}
+
+/// The rules deciding which packages garbage collection evicts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+    pub low_watermark: f64,
+    pub evict_first: Vec<String>,
+    pub never_evict: Vec<String>,
+    pub keep_channel_versions: usize,
+}
+
+impl Policy {
+    /// The rules of `store.gc`, or those of one of its `policies`. A policy takes the
+    /// rules it leaves out from `store.gc`.
+    pub fn new(settings: &settings::Gc, name: Option<&str>) -> Result<Self> {
+        let defaults = Self {
+            low_watermark: settings.low_watermark,
+            evict_first: settings.evict_first.clone(),
+            never_evict: settings.never_evict.clone(),
+            keep_channel_versions: settings.keep_channel_versions,
+        };
+        let Some(name) = name else {
+            return Ok(defaults);
+        };
+        let Some(policy) = settings.policies.get(name) else {
+            bail!("Unknown garbage collection policy {}", name);
+        };
+        Ok(Self {
+            low_watermark: policy.low_watermark.unwrap_or(defaults.low_watermark),
+            evict_first: policy.evict_first.clone().unwrap_or(defaults.evict_first),
+            never_evict: policy.never_evict.clone().unwrap_or(defaults.never_evict),
+            keep_channel_versions: policy
+                .keep_channel_versions
+                .unwrap_or(defaults.keep_channel_versions),
+        })
+    }
+}
+
+/// A stored package, with what garbage collection needs to know about it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredPackage {
+    pub hash: String,
+    /// Not known if the narinfo has no store path
+    pub name: Option<String>,
+    pub nar_size: u64,
+    pub system: Option<String>,
+    /// Hashes of the packages it references, not including itself
+    pub references: Vec<String>,
+    /// Whether its NAR is being downloaded right now
+    pub served: bool,
+    /// The tags pinning it, see `gachix tag`
+    pub tags: Vec<String>,
+    /// The channel versions kept by the policy which list it, e.g. `team-tools-v42`
+    pub channels: Vec<String>,
+}
+
+impl Display for StoredPackage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{}-{}", self.hash, name),
+            None => f.write_str(&self.hash),
+        }
+    }
+}
+
+/// The packages which no other package references, leaving out those whose NAR is
+/// being served and tagged ones or those of kept channel versions.
+pub fn candidates(packages: &[StoredPackage]) -> Vec<Candidate> {
+    let referenced: HashSet<&str> = packages
+        .iter()
+        .flat_map(|package| package.references.iter().map(String::as_str))
+        .collect();
+    packages
+        .iter()
+        .filter(|package| {
+            !package.served
+                && package.tags.is_empty()
+                && package.channels.is_empty()
+                && !referenced.contains(package.hash.as_str())
+        })
+        .map(|package| Candidate {
+            hash: package.hash.clone(),
+            nar_size: package.nar_size,
+            system: package.system.clone(),
+        })
+        .collect()
+}
+
+/// A package which no other stored package references.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub hash: String,
+    pub nar_size: u64,
+    /// Not known for packages whose narinfo does not record it
+    pub system: Option<String>,
+}
+
+/// Picks the packages to evict from `candidates` until their sizes add up to
+/// `bytes_to_free`. Packages of the systems in `evict_first` go first, in that order,
+/// then the others, largest first. Packages of the systems in `never_evict` are kept.
+pub fn select_evictions(
+    mut candidates: Vec<Candidate>,
+    bytes_to_free: u64,
+    evict_first: &[String],
+    never_evict: &[String],
+) -> Vec<String> {
+    candidates.retain(|c| {
+        c.system
+            .as_ref()
+            .is_none_or(|system| !never_evict.contains(system))
+    });
+    let rank = |candidate: &Candidate| {
+        candidate
+            .system
+            .as_ref()
+            .and_then(|system| evict_first.iter().position(|s| s == system))
+            .unwrap_or(evict_first.len())
+    };
+    candidates.sort_by(|x, y| {
+        rank(x)
+            .cmp(&rank(y))
+            .then(y.nar_size.cmp(&x.nar_size))
+            .then(x.hash.cmp(&y.hash))
+    });
+    let mut freed = 0;
+    let mut evictions = Vec::new();
+    for candidate in candidates {
+        if freed >= bytes_to_free {
+            break;
+        }
+        freed += candidate.nar_size;
+        evictions.push(candidate.hash);
+    }
+    evictions
+}
+
+/// Why a package which no other package references is never evicted.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Protection {
+    /// Its system is one of `never_evict`
+    NeverEvict(String),
+    /// Its NAR is being downloaded
+    Served,
+    /// It has a tag
+    Tagged(String),
+    /// It is listed by a channel version the policy keeps
+    Channel(String),
+}
+
+impl Display for Protection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Protection::NeverEvict(system) => write!(f, "never evicted on {system}"),
+            Protection::Served => f.write_str("being served"),
+            Protection::Tagged(tag) => write!(f, "tagged {tag}"),
+            Protection::Channel(version) => write!(f, "in channel {version}"),
+        }
+    }
+}
+
+/// A package garbage collection leaves alone, which keeps its dependencies too.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProtectedRoot {
+    pub package: StoredPackage,
+    pub protection: Protection,
+    /// Number of packages in its closure, not including itself
+    pub dependencies: usize,
+    pub dependencies_size: u64,
+}
+
+/// What garbage collection would do under a policy, as reported by `gachix gc
+/// --dry-run`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GcSimulation {
+    pub usage: f64,
+    pub low_watermark: f64,
+    pub bytes_above: u64,
+    pub expiry: Expiry,
+    /// Bytes pruning removed packages would free before anything is evicted
+    pub pruned: u64,
+    /// Bytes left to free by evicting, no more than the stored packages take
+    pub bytes_to_free: u64,
+    /// The packages which would be evicted, in order
+    pub evicted: Vec<StoredPackage>,
+    pub protected: Vec<ProtectedRoot>,
+}
+
+impl GcSimulation {
+    /// The NAR size of the evicted packages. Their objects take up about as much,
+    /// less what git compresses or shares with other packages.
+    pub fn reclaimed(&self) -> u64 {
+        self.evicted.iter().map(|package| package.nar_size).sum()
+    }
+}
+
+impl Display for GcSimulation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let expiry = &self.expiry;
+        if expiry.audit + expiry.revisions + expiry.tombstones > 0 {
+            writeln!(
+                f,
+                "Would expire {} audit entries, {} replaced narinfos and {} tombstones",
+                expiry.audit, expiry.revisions, expiry.tombstones
+            )?;
+        }
+        if self.bytes_above == 0 {
+            writeln!(
+                f,
+                "Disk usage of {:.1}% is below the low watermark of {}%, nothing would be evicted",
+                self.usage, self.low_watermark
+            )?;
+        } else {
+            writeln!(
+                f,
+                "Disk usage of {:.1}% is {} above the low watermark of {}%",
+                self.usage,
+                format_size(self.bytes_above),
+                self.low_watermark
+            )?;
+            if self.pruned > 0 {
+                writeln!(
+                    f,
+                    "Pruning removed packages would free about {}",
+                    format_size(self.pruned)
+                )?;
+            }
+        }
+        if self.bytes_to_free > 0 {
+            writeln!(
+                f,
+                "Would evict {} packages, reclaiming about {}",
+                self.evicted.len(),
+                format_size(self.reclaimed())
+            )?;
+            for package in &self.evicted {
+                writeln!(f, "  {} {}", package, format_size(package.nar_size))?;
+            }
+            if self.reclaimed() < self.bytes_to_free {
+                writeln!(
+                    f,
+                    "No other packages can be evicted, {} would remain above the low watermark",
+                    format_size(self.bytes_to_free - self.reclaimed())
+                )?;
+            }
+        } else if self.bytes_above > 0 {
+            writeln!(f, "Nothing would be evicted")?;
+        }
+        if !self.protected.is_empty() {
+            writeln!(f, "Protected roots:")?;
+        }
+        for root in &self.protected {
+            writeln!(
+                f,
+                "  {} {}, keeps {} dependencies of {}",
+                root.package,
+                root.protection,
+                root.dependencies,
+                format_size(root.dependencies_size)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// What expires on every garbage collection, before anything is evicted.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Expiry {
+    pub audit: usize,
+    pub revisions: usize,
+    pub tombstones: usize,
+    /// The NAR sizes of the removed packages which would be pruned above the low
+    /// watermark. Objects they share with stored packages are kept, so pruning
+    /// may free less.
+    pub prunable: u64,
+}
+
+/// Repeats the rounds of garbage collection on `packages` without evicting anything.
+/// Like `Store::collect_garbage`, removed packages are pruned first if the usage is
+/// above the low watermark, and no more than the stored packages take is evicted.
+/// Every round evicts packages which nothing references until enough space is
+/// freed, which may leave their dependencies unreferenced for the next round. The
+/// NAR sizes stand in for the space the objects of a package take.
+pub fn simulate(
+    mut packages: Vec<StoredPackage>,
+    usage: &DiskUsage,
+    policy: &Policy,
+    expiry: Expiry,
+) -> GcSimulation {
+    let bytes_above = usage.bytes_above(policy.low_watermark);
+    let pruned = if usage.used_percent() > policy.low_watermark {
+        expiry.prunable.min(bytes_above)
+    } else {
+        0
+    };
+    let stored: u64 = packages.iter().map(|package| package.nar_size).sum();
+    let bytes_to_free = (bytes_above - pruned).min(stored);
+    let mut evicted = Vec::new();
+    let mut freed = 0;
+    while freed < bytes_to_free {
+        let evictions = select_evictions(
+            candidates(&packages),
+            bytes_to_free - freed,
+            &policy.evict_first,
+            &policy.never_evict,
+        );
+        if evictions.is_empty() {
+            break;
+        }
+        let selected: HashSet<&String> = evictions.iter().collect();
+        let (gone, kept): (Vec<_>, Vec<_>) = packages
+            .into_iter()
+            .partition(|package| selected.contains(&package.hash));
+        packages = kept;
+        let mut gone: HashMap<String, StoredPackage> = gone
+            .into_iter()
+            .map(|package| (package.hash.clone(), package))
+            .collect();
+        for hash in &evictions {
+            if let Some(package) = gone.remove(hash) {
+                freed += package.nar_size;
+                evicted.push(package);
+            }
+        }
+    }
+    GcSimulation {
+        usage: usage.used_percent(),
+        low_watermark: policy.low_watermark,
+        bytes_above,
+        expiry,
+        pruned,
+        bytes_to_free,
+        evicted,
+        protected: protected_roots(&packages, policy),
+    }
+}
+
+/// The packages nothing references which are kept however full the disk is, with
+/// the closures they keep.
+fn protected_roots(packages: &[StoredPackage], policy: &Policy) -> Vec<ProtectedRoot> {
+    let by_hash: HashMap<&str, &StoredPackage> = packages
+        .iter()
+        .map(|package| (package.hash.as_str(), package))
+        .collect();
+    let referenced: HashSet<&str> = packages
+        .iter()
+        .flat_map(|package| package.references.iter().map(String::as_str))
+        .collect();
+    let mut roots = Vec::new();
+    for package in packages {
+        if referenced.contains(package.hash.as_str()) {
+            continue;
+        }
+        let never_evict = package
+            .system
+            .as_ref()
+            .filter(|system| policy.never_evict.contains(system));
+        let protection = match never_evict {
+            _ if package.served => Protection::Served,
+            _ if !package.tags.is_empty() => Protection::Tagged(package.tags.join(", ")),
+            _ if !package.channels.is_empty() => Protection::Channel(package.channels.join(", ")),
+            Some(system) => Protection::NeverEvict(system.clone()),
+            None => continue,
+        };
+        let mut closure = HashSet::new();
+        let mut queue: Vec<&str> = package.references.iter().map(String::as_str).collect();
+        while let Some(hash) = queue.pop() {
+            let Some(dependency) = by_hash.get(hash) else {
+                continue;
+            };
+            if closure.insert(hash) {
+                queue.extend(dependency.references.iter().map(String::as_str));
+            }
+        }
+        roots.push(ProtectedRoot {
+            package: package.clone(),
+            protection,
+            dependencies: closure.len(),
+            dependencies_size: closure.iter().map(|hash| by_hash[hash].nar_size).sum(),
+        });
+    }
+    roots
+}
+
+/// Formats a number of bytes for people, e.g. `1.5 GiB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct GcSummary {
+    pub evicted: usize,
+    /// Replaced narinfos which were old enough to be pruned
+    pub revisions_expired: usize,
+    /// Removed packages whose tombstone was old enough to be pruned
+    pub tombstones_expired: usize,
+    /// Entries of the audit trail older than its retention period
+    pub audit_expired: usize,
+    pub usage_before: f64,
+    pub usage_after: f64,
+}
+
+impl Display for GcSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Evicted {} packages, disk usage went from {:.1}% to {:.1}%",
+            self.evicted, self.usage_before, self.usage_after
+        )?;
+        if self.revisions_expired > 0 {
+            writeln!(f, "Pruned {} replaced narinfos", self.revisions_expired)?;
+        }
+        if self.tombstones_expired > 0 {
+            writeln!(f, "Pruned {} removed packages", self.tombstones_expired)?;
+        }
+        if self.audit_expired > 0 {
+            writeln!(f, "Dropped {} audit entries", self.audit_expired)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(hash: &str, nar_size: u64, system: Option<&str>) -> Candidate {
+        Candidate {
+            hash: hash.to_string(),
+            nar_size,
+            system: system.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_select_evictions() {
+        let candidates = vec![
+            candidate("a", 10, None),
+            candidate("b", 300, None),
+            candidate("c", 200, None),
+        ];
+        let select = |bytes| select_evictions(candidates.clone(), bytes, &[], &[]);
+        assert_eq!(select(0), Vec::<String>::new());
+        assert_eq!(select(250), vec!["b"]);
+        assert_eq!(select(301), vec!["b", "c"]);
+        assert_eq!(select(10_000), vec!["b", "c", "a"]);
+    }
+
+    #[test]
+    fn test_select_evictions_by_system() {
+        let candidates = vec![
+            candidate("a", 10, Some("aarch64-linux")),
+            candidate("b", 300, Some("x86_64-linux")),
+            candidate("c", 200, None),
+            candidate("d", 50, Some("x86_64-darwin")),
+            candidate("e", 20, Some("aarch64-linux")),
+        ];
+        let evict_first = ["x86_64-darwin".to_string(), "aarch64-linux".to_string()];
+        let never_evict = ["x86_64-linux".to_string()];
+        assert_eq!(
+            select_evictions(candidates.clone(), 70, &evict_first, &never_evict),
+            vec!["d", "e"]
+        );
+        assert_eq!(
+            select_evictions(candidates, 10_000, &evict_first, &never_evict),
+            vec!["d", "e", "a", "c"]
+        );
+    }
+
+    fn package(hash: &str, nar_size: u64, system: &str, references: &[&str]) -> StoredPackage {
+        StoredPackage {
+            hash: hash.to_string(),
+            name: None,
+            nar_size,
+            system: Some(system.to_string()),
+            references: references.iter().map(|r| r.to_string()).collect(),
+            served: false,
+            tags: vec![],
+            channels: vec![],
+        }
+    }
+
+    #[test]
+    fn test_simulate() {
+        // app -> lib -> libc, tool -> libc, and a darwin root keeping its own dependency
+        let mut packages = vec![
+            package("app", 100, "x86_64-linux", &["lib"]),
+            package("lib", 300, "x86_64-linux", &["libc"]),
+            package("libc", 200, "x86_64-linux", &[]),
+            package("tool", 50, "x86_64-linux", &["libc"]),
+            package("mac", 10, "x86_64-darwin", &["macdep"]),
+            package("macdep", 40, "x86_64-darwin", &[]),
+        ];
+        let usage = DiskUsage {
+            total: 10_000,
+            available: 1_100,
+        };
+        let policy = Policy {
+            low_watermark: 85.0,
+            evict_first: vec![],
+            never_evict: vec!["x86_64-darwin".to_string()],
+            keep_channel_versions: 1,
+        };
+
+        // 400 bytes to free: app and tool, then lib once nothing references it
+        let simulation = simulate(packages.clone(), &usage, &policy, Expiry::default());
+        assert_eq!(simulation.bytes_to_free, 400);
+        let evicted: Vec<&str> = simulation.evicted.iter().map(|p| p.hash.as_str()).collect();
+        assert_eq!(evicted, ["app", "tool", "lib"]);
+        assert_eq!(simulation.reclaimed(), 450);
+        assert_eq!(simulation.protected.len(), 1);
+        assert_eq!(simulation.protected[0].package.hash, "mac");
+        assert_eq!(
+            simulation.protected[0].protection,
+            Protection::NeverEvict("x86_64-darwin".to_string())
+        );
+        assert_eq!(simulation.protected[0].dependencies, 1);
+        assert_eq!(simulation.protected[0].dependencies_size, 40);
+
+        // A tagged package is kept along with everything it references
+        packages[3].tags = vec!["release-1".to_string()];
+        let simulation = simulate(packages.clone(), &usage, &policy, Expiry::default());
+        let evicted: Vec<&str> = simulation.evicted.iter().map(|p| p.hash.as_str()).collect();
+        assert_eq!(evicted, ["app", "lib"]);
+        assert!(
+            simulation
+                .protected
+                .iter()
+                .any(|root| root.protection == Protection::Tagged("release-1".to_string()))
+        );
+        packages[3].tags.clear();
+
+        // So is a package of a kept channel version
+        packages[0].channels = vec!["team-tools-v2".to_string()];
+        let simulation = simulate(packages.clone(), &usage, &policy, Expiry::default());
+        let evicted: Vec<&str> = simulation.evicted.iter().map(|p| p.hash.as_str()).collect();
+        assert_eq!(evicted, ["tool"]);
+        assert!(
+            simulation
+                .to_string()
+                .contains("app in channel team-tools-v2, keeps 2 dependencies")
+        );
+        packages[0].channels.clear();
+
+        // A served package is kept along with everything it references
+        packages[0].served = true;
+        let simulation = simulate(packages.clone(), &usage, &policy, Expiry::default());
+        let evicted: Vec<&str> = simulation.evicted.iter().map(|p| p.hash.as_str()).collect();
+        assert_eq!(evicted, ["tool"]);
+        assert_eq!(simulation.protected[0].protection, Protection::Served);
+        assert_eq!(simulation.protected[0].dependencies, 2);
+        assert!(
+            simulation
+                .to_string()
+                .contains("Protected roots:\n  app being served")
+        );
+        packages[0].served = false;
+
+        // Pruning removed packages comes first and leaves less to evict
+        let expiry = Expiry {
+            tombstones: 2,
+            prunable: 300,
+            ..Default::default()
+        };
+        let simulation = simulate(packages.clone(), &usage, &policy, expiry);
+        assert_eq!(simulation.pruned, 300);
+        assert_eq!(simulation.bytes_to_free, 100);
+        assert!(simulation.reclaimed() >= 100 && simulation.evicted.len() < 3);
+        let report = simulation.to_string();
+        assert!(report.contains("0 replaced narinfos and 2 tombstones"));
+        assert!(report.contains("Pruning removed packages would free about 300 B"));
+
+        // No more than the stored packages take is evicted
+        let full = DiskUsage {
+            total: 10_000,
+            available: 0,
+        };
+        let simulation = simulate(packages, &full, &policy, Expiry::default());
+        assert_eq!(simulation.bytes_above, 1_500);
+        assert_eq!(simulation.bytes_to_free, 700);
+    }
+
+    #[test]
+    fn test_policy() -> Result<()> {
+        let mut settings = settings::Gc {
+            high_watermark: None,
+            low_watermark: 80.0,
+            check_interval: 300,
+            retention: 0,
+            tombstone_grace: 0,
+            audit_retention: 0,
+            evict_first: vec!["x86_64-darwin".to_string()],
+            never_evict: vec![],
+            keep_channel_versions: 1,
+            policies: HashMap::new(),
+        };
+        settings.policies.insert(
+            "aggressive".to_string(),
+            settings::GcPolicy {
+                low_watermark: Some(50.0),
+                keep_channel_versions: Some(0),
+                ..Default::default()
+            },
+        );
+        assert_eq!(Policy::new(&settings, None)?.low_watermark, 80.0);
+        let aggressive = Policy::new(&settings, Some("aggressive"))?;
+        assert_eq!(aggressive.low_watermark, 50.0);
+        assert_eq!(aggressive.evict_first, ["x86_64-darwin"]);
+        assert_eq!(aggressive.keep_channel_versions, 0);
+        assert!(Policy::new(&settings, Some("other")).is_err());
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536 * 1024 * 1024), "1.5 GiB");
+        Ok(())
+    }
+
+    #[test]
+    fn test_bytes_above() {
+        let usage = DiskUsage {
+            total: 1000,
+            available: 100,
+        };
+        assert_eq!(usage.used_percent(), 90.0);
+        assert_eq!(usage.bytes_above(80.0), 100);
+        assert_eq!(usage.bytes_above(95.0), 0);
+
+        let after = DiskUsage {
+            total: 1000,
+            available: 300,
+        };
+        assert_eq!(after.freed_since(&usage), 200);
+        assert_eq!(usage.freed_since(&after), 0);
+    }
+}
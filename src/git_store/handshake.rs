@@ -0,0 +1,128 @@
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::fmt::Display;
+
+/// Every writable store keeps a JSON blob of its `Handshake` at this reference, so
+/// that git peers can look up what an instance supports before relying on it.
+/// Peers which lack it run a gachix from before the handshake was added.
+pub const HANDSHAKE_REF: &str = "refs/gachix/handshake";
+
+/// Version of the handshake format, raised on incompatible changes.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Features a peer may or may not support:
+/// - `objects`: receives packages pushed as git objects
+/// - `artifacts`: stores artifacts by their hash
+/// - `tombstones`: records the packages it removed
+/// - `revocations`: records the packages it revoked
+/// - `tags`: names packages with tags
+/// - `channels`: publishes versioned sets of packages
+pub const FEATURES: &[&str] = &[
+    "objects",
+    "artifacts",
+    "tombstones",
+    "revocations",
+    "tags",
+    "channels",
+];
+
+/// Where the handshake of a git peer is fetched to while reading it.
+pub fn peer_handshake_ref(url: &str) -> String {
+    let digest = hex::encode(Sha256::digest(url.as_bytes()));
+    format!("refs/gachix/peers/{}", &digest[..16])
+}
+
+/// The version of a gachix instance and the features it supports, advertised at
+/// `HANDSHAKE_REF` and `/api/handshake`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Handshake {
+    pub protocol: u32,
+    /// Version of gachix
+    pub version: String,
+    pub features: BTreeSet<String>,
+}
+
+impl Handshake {
+    /// The handshake of this build of gachix.
+    pub fn current() -> Self {
+        Self {
+            protocol: PROTOCOL_VERSION,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            features: FEATURES.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.contains(feature)
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    /// Parses the handshake of a peer. Features unknown to this build are kept,
+    /// but a newer protocol can't be understood.
+    pub fn from_json(json: &[u8]) -> Result<Self> {
+        let handshake: Self = serde_json::from_slice(json)?;
+        if handshake.protocol > PROTOCOL_VERSION {
+            bail!(
+                "Peer speaks protocol {} of gachix {}, this gachix only understands up to {}",
+                handshake.protocol,
+                handshake.version,
+                PROTOCOL_VERSION
+            );
+        }
+        Ok(handshake)
+    }
+}
+
+impl Display for Handshake {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "gachix {} (protocol {})", self.version, self.protocol)?;
+        if !self.features.is_empty() {
+            let features: Vec<&str> = self.features.iter().map(String::as_str).collect();
+            write!(f, ": {}", features.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handshake() -> Result<()> {
+        let current = Handshake::current();
+        assert!(current.supports("tombstones"));
+        assert!(!current.supports("namespaces"));
+        assert_eq!(
+            Handshake::from_json(current.to_json()?.as_bytes())?,
+            current
+        );
+
+        let older = Handshake::from_json(
+            br#"{"protocol":1,"version":"0.1.0","features":["objects","teleport"]}"#,
+        )?;
+        assert!(older.supports("teleport"));
+        assert!(!older.supports("channels"));
+        assert_eq!(
+            older.to_string(),
+            "gachix 0.1.0 (protocol 1): objects, teleport"
+        );
+
+        let newer = format!(
+            r#"{{"protocol":{},"version":"9.0.0","features":[]}}"#,
+            PROTOCOL_VERSION + 1
+        );
+        assert!(Handshake::from_json(newer.as_bytes()).is_err());
+        assert!(Handshake::from_json(b"not json").is_err());
+
+        let reference = peer_handshake_ref("ssh://builder/srv/gachix");
+        assert!(reference.starts_with("refs/gachix/peers/"));
+        assert_ne!(reference, peer_handshake_ref("ssh://other/srv/gachix"));
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+use serde::Serialize;
+use std::fmt::Display;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PeerKind {
+    NixDaemon,
+    Git,
+}
+
+/// Whether a peer accepted the credentials. Failures which happen before
+/// authenticating, like an unreachable host, leave it unknown.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthStatus {
+    Ok,
+    Failed,
+    Unknown,
+}
+
+/// The outcome of connecting to a Nix daemon or git peer.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PeerHealth {
+    pub kind: PeerKind,
+    /// Address of the peer, without any password
+    pub address: String,
+    /// How the peer is reached, e.g. `ssh`, `https` or `unix`
+    pub protocol: String,
+    pub reachable: bool,
+    /// Milliseconds it took to connect and, for git peers, list the references
+    pub latency_ms: Option<u64>,
+    pub auth: AuthStatus,
+    /// Packages a git peer advertises, not known for Nix daemons
+    pub packages: Option<usize>,
+    /// Version of gachix a git peer runs, if it advertises its handshake
+    pub version: Option<String>,
+    pub error: Option<String>,
+}
+
+impl Display for PeerHealth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.kind {
+            PeerKind::NixDaemon => "nix-daemon",
+            PeerKind::Git => "git",
+        };
+        write!(f, "{} {} ({})", kind, self.address, self.protocol)?;
+        match (&self.error, self.latency_ms) {
+            (Some(error), _) => write!(f, " unreachable: {error}")?,
+            (None, Some(latency)) => write!(f, " reachable in {latency}ms")?,
+            (None, None) => f.write_str(" reachable")?,
+        }
+        if self.auth == AuthStatus::Failed {
+            f.write_str(", authentication failed")?;
+        }
+        if let Some(packages) = self.packages {
+            write!(f, ", {packages} packages")?;
+        }
+        if let Some(version) = &self.version {
+            write!(f, ", gachix {version}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The health of every peer, see `gachix peers status`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct HealthReport {
+    pub peers: Vec<PeerHealth>,
+    /// Why peers could not be checked at all, e.g. an invalid builder URL
+    pub errors: Vec<String>,
+}
+
+impl HealthReport {
+    pub fn healthy(&self) -> bool {
+        self.errors.is_empty() && self.peers.iter().all(|peer| peer.reachable)
+    }
+}
+
+impl Display for HealthReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.peers.is_empty() && self.errors.is_empty() {
+            return writeln!(f, "No peers configured");
+        }
+        for peer in &self.peers {
+            writeln!(f, "{peer}")?;
+        }
+        for error in &self.errors {
+            writeln!(f, "error: {error}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_health_report() {
+        let mut report = HealthReport {
+            peers: vec![PeerHealth {
+                kind: PeerKind::Git,
+                address: "https://cache.example.org/gachix".to_string(),
+                protocol: "https".to_string(),
+                reachable: true,
+                latency_ms: Some(42),
+                auth: AuthStatus::Ok,
+                packages: Some(1200),
+                version: Some("0.4.0".to_string()),
+                error: None,
+            }],
+            errors: Vec::new(),
+        };
+        assert!(report.healthy());
+        report.peers.push(PeerHealth {
+            kind: PeerKind::NixDaemon,
+            address: "builder".to_string(),
+            protocol: "ssh".to_string(),
+            reachable: false,
+            latency_ms: None,
+            auth: AuthStatus::Failed,
+            packages: None,
+            version: None,
+            error: Some("Could not authenticate".to_string()),
+        });
+        assert!(!report.healthy());
+        assert_eq!(
+            report.to_string(),
+            "git https://cache.example.org/gachix (https) reachable in 42ms, 1200 packages, gachix 0.4.0\n\
+             nix-daemon builder (ssh) unreachable: Could not authenticate, authentication failed\n"
+        );
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["peers"][1]["kind"], "nix-daemon");
+        assert_eq!(json["peers"][1]["auth"], "failed");
+        assert_eq!(HealthReport::default().to_string(), "No peers configured\n");
+
+        let broken = HealthReport {
+            peers: Vec::new(),
+            errors: vec!["Invalid builder URL".to_string()],
+        };
+        assert!(!broken.healthy());
+        assert_eq!(broken.to_string(), "error: Invalid builder URL\n");
+    }
+}
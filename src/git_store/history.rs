@@ -0,0 +1,334 @@
+use anyhow::{Result, anyhow, bail};
+use git2::Oid;
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// Every addition, removal and repair of a package is recorded as a commit on this
+/// reference. The package commits themselves carry no date, so that the same
+/// package results in the same commit on every peer.
+pub const HISTORY_REF: &str = "refs/gachix/history";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change {
+    Added,
+    Removed,
+    /// Replaced by a valid copy, with a narinfo signed again
+    Repaired,
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Change::Added => f.write_str("add"),
+            Change::Removed => f.write_str("remove"),
+            Change::Repaired => f.write_str("repair"),
+        }
+    }
+}
+
+/// A change of a single package, stored as one line of a history commit message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub change: Change,
+    pub hash: String,
+    pub commit: Oid,
+    pub narinfo: Oid,
+}
+
+impl Display for Record {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}",
+            self.change, self.hash, self.commit, self.narinfo
+        )
+    }
+}
+
+impl FromStr for Record {
+    type Err = anyhow::Error;
+
+    fn from_str(line: &str) -> Result<Self> {
+        let [change, hash, commit, narinfo] = line.split(' ').collect::<Vec<_>>()[..] else {
+            bail!("Invalid history record: {}", line);
+        };
+        let change = match change {
+            "add" => Change::Added,
+            "remove" => Change::Removed,
+            "repair" => Change::Repaired,
+            _ => bail!("Unknown change in history record: {}", line),
+        };
+        Ok(Self {
+            change,
+            hash: hash.to_string(),
+            commit: Oid::from_str(commit)?,
+            narinfo: Oid::from_str(narinfo)?,
+        })
+    }
+}
+
+/// Parses the records of a history commit message, skipping the summary line.
+pub fn parse_records(message: &str) -> Result<Vec<Record>> {
+    message
+        .lines()
+        .skip(1)
+        .filter(|line| !line.is_empty())
+        .map(Record::from_str)
+        .collect()
+}
+
+/// Renders the message of a history commit.
+pub fn format_message(records: &[Record]) -> String {
+    let summary = match records {
+        [record] => format!("{} {}", record.change, record.hash),
+        _ => format!("{} changes", records.len()),
+    };
+    let lines: Vec<String> = records.iter().map(Record::to_string).collect();
+    format!("{summary}\n\n{}\n", lines.join("\n"))
+}
+
+/// The user and host on whose behalf changes are recorded.
+pub fn identity() -> (String, String) {
+    let user = user_name();
+    let mut buffer = [0u8; 256];
+    // SAFETY: the buffer is valid for its whole length
+    let host = if unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) } == 0 {
+        let end = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+        String::from_utf8_lossy(&buffer[..end]).to_string()
+    } else {
+        "localhost".to_string()
+    };
+    (user.clone(), format!("{user}@{host}"))
+}
+
+/// The name of the user running this process, looked up by its user id rather than
+/// taken from `$USER`, which the caller may set to anything. Users without an
+/// entry in the password database are named by their id.
+fn user_name() -> String {
+    // SAFETY: getuid always succeeds
+    let uid = unsafe { libc::getuid() };
+    // SAFETY: an all-zero passwd is valid, it is only written to
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = [0 as libc::c_char; 1024];
+    let mut result = std::ptr::null_mut();
+    // SAFETY: passwd, the buffer and result are valid for the whole call
+    let status = unsafe {
+        libc::getpwuid_r(
+            uid,
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if status != 0 || result.is_null() || passwd.pw_name.is_null() {
+        return uid.to_string();
+    }
+    // SAFETY: on success pw_name points to a nul-terminated string in the buffer
+    unsafe { std::ffi::CStr::from_ptr(passwd.pw_name) }
+        .to_string_lossy()
+        .to_string()
+}
+
+/// A recorded change of a package, as shown in the audit log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    /// The history commit which recorded the change
+    pub id: Oid,
+    /// Seconds since the Unix epoch
+    pub time: i64,
+    /// Email of the user and host which made the change
+    pub identity: String,
+    pub record: Record,
+    /// Not known anymore once the narinfo of a removed package has been pruned
+    pub name: Option<String>,
+}
+
+impl Display for HistoryEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let id = self.id.to_string();
+        write!(
+            f,
+            "{} {} {} {} {}",
+            format_time(self.time),
+            &id[..10],
+            self.identity,
+            self.record.change,
+            self.record.hash
+        )?;
+        if let Some(name) = &self.name {
+            write!(f, "-{name}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Selects the changes shown in the audit log.
+#[derive(Debug, Clone, Default)]
+pub struct HistoryFilter {
+    /// Only changes of packages whose hash starts with this prefix
+    pub hash: Option<String>,
+    /// Only changes at or after this time, in seconds since the Unix epoch
+    pub since: Option<i64>,
+    /// Only changes at or before this time, in seconds since the Unix epoch
+    pub until: Option<i64>,
+}
+
+impl HistoryFilter {
+    pub fn matches(&self, time: i64, record: &Record) -> bool {
+        self.since.is_none_or(|since| time >= since)
+            && self.until.is_none_or(|until| time <= until)
+            && self
+                .hash
+                .as_ref()
+                .is_none_or(|prefix| record.hash.starts_with(prefix.as_str()))
+    }
+}
+
+/// A past state of the store, either the state right after a history commit or at a
+/// point in time.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PointInTime {
+    /// A possibly abbreviated history commit
+    Commit(String),
+    /// Seconds since the Unix epoch
+    Time(i64),
+}
+
+impl FromStr for PointInTime {
+    type Err = anyhow::Error;
+
+    /// Accepts a history commit, `@<seconds since the epoch>`, or a UTC date as
+    /// `YYYY-MM-DD` or `YYYY-MM-DDTHH:MM:SS`.
+    fn from_str(s: &str) -> Result<Self> {
+        if s.len() >= 4 && s.len() <= 40 && s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Ok(PointInTime::Commit(s.to_lowercase()));
+        }
+        parse_time(s)
+            .map(PointInTime::Time)
+            .map_err(|_| anyhow!("Expected a commit, @<seconds> or a date, got {}", s))
+    }
+}
+
+/// Parses `@<seconds since the epoch>` or a UTC date as `YYYY-MM-DD` or
+/// `YYYY-MM-DDTHH:MM:SS` into seconds since the epoch.
+pub fn parse_time(s: &str) -> Result<i64> {
+    if let Some(seconds) = s.strip_prefix('@') {
+        return Ok(seconds.parse()?);
+    }
+    let (date, time) = s.split_once(['T', ' ']).unwrap_or((s, "00:00:00"));
+    let invalid = || anyhow!("Expected @<seconds> or a date, got {}", s);
+    let date: Vec<i64> = date
+        .split('-')
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .map_err(|_| invalid())?;
+    let time: Vec<i64> = time
+        .trim_end_matches('Z')
+        .split(':')
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .map_err(|_| invalid())?;
+    let (&[year, month, day], &[hour, minute, second]) = (date.as_slice(), time.as_slice()) else {
+        return Err(invalid());
+    };
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || !(0..=23).contains(&hour)
+        || !(0..=59).contains(&minute)
+        || !(0..=59).contains(&second)
+    {
+        return Err(invalid());
+    }
+    let days = days_from_civil(year, month, day);
+    Ok(days * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+/// Formats seconds since the epoch as a UTC date, the inverse of `parse_time`.
+pub fn format_time(time: i64) -> String {
+    let (days, seconds) = (time.div_euclid(86400), time.rem_euclid(86400));
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Days since the Unix epoch of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records() -> Result<()> {
+        let records = vec![
+            Record {
+                change: Change::Added,
+                hash: "a".repeat(32),
+                commit: Oid::from_bytes(&[1; 20])?,
+                narinfo: Oid::from_bytes(&[2; 20])?,
+            },
+            Record {
+                change: Change::Removed,
+                hash: "b".repeat(32),
+                commit: Oid::from_bytes(&[3; 20])?,
+                narinfo: Oid::from_bytes(&[4; 20])?,
+            },
+        ];
+        let message = format_message(&records);
+        assert!(message.starts_with("2 changes\n"));
+        assert_eq!(parse_records(&message)?, records);
+        assert!(format_message(&records[..1]).starts_with(&format!("add {}\n", "a".repeat(32))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_point_in_time() -> Result<()> {
+        assert_eq!(
+            "@1700000000".parse::<PointInTime>()?,
+            PointInTime::Time(1700000000)
+        );
+        assert_eq!("1970-01-01".parse::<PointInTime>()?, PointInTime::Time(0));
+        assert_eq!(
+            "2024-02-29T12:30:15".parse::<PointInTime>()?,
+            PointInTime::Time(1709209815)
+        );
+        assert_eq!(
+            "ABCDEF12".parse::<PointInTime>()?,
+            PointInTime::Commit("abcdef12".to_string())
+        );
+        assert_eq!(format_time(1709209815), "2024-02-29T12:30:15Z");
+        assert_eq!(parse_time(&format_time(-86399))?, -86399);
+        assert!("2024-13-01".parse::<PointInTime>().is_err());
+        assert!("2024-05-01T24:00:00".parse::<PointInTime>().is_err());
+        assert!("2024-05-01T12:60:00".parse::<PointInTime>().is_err());
+        assert!("2024-05-01T12:00:-1".parse::<PointInTime>().is_err());
+        assert!("yesterday".parse::<PointInTime>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+use std::fmt::Display;
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+
+use anyhow::{Context, Result, bail};
+use liblzma::write::XzEncoder;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Compression level of published and exported NARs, the default of `xz`
+pub const XZ_LEVEL: u32 = 6;
+
+/// A compressed NAR which was published to IPFS.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IpfsExport {
+    /// Content ID of the compressed NAR
+    pub cid: String,
+    pub compression: String,
+    /// Size of the compressed NAR
+    pub file_size: u64,
+}
+
+impl IpfsExport {
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn from_json(json: &[u8]) -> Result<Self> {
+        Ok(serde_json::from_slice(json)?)
+    }
+}
+
+impl Display for IpfsExport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "IpfsCid: {}", self.cid)?;
+        writeln!(f, "IpfsCompression: {}", self.compression)?;
+        writeln!(f, "IpfsFileSize: {}", self.file_size)
+    }
+}
+
+/// Hashes and counts what is written through it, e.g. a compressed NAR, whose
+/// size and hash its narinfo lists.
+pub struct HashingWriter<W> {
+    pub inner: W,
+    pub hasher: Sha256,
+    pub size: u64,
+}
+
+impl<W> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            size: 0,
+        }
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Compresses the NAR written by `write_nar` with xz and adds it to IPFS with the
+/// `ipfs` command line client, pinning it on the node. `api` is the multiaddr of
+/// the node's API, the client's default node is used if it is not set.
+pub fn publish(
+    api: Option<&str>,
+    write_nar: impl FnOnce(&mut dyn Write) -> Result<()>,
+) -> Result<IpfsExport> {
+    let mut command = Command::new("ipfs");
+    if let Some(api) = api {
+        command.args(["--api", api]);
+    }
+    let mut child = command
+        .args(["add", "--quiet", "--pin"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Could not run the ipfs command")?;
+
+    let stdin = child.stdin.take().expect("stdin is piped");
+    let mut counter = HashingWriter::new(stdin);
+    let mut encoder = XzEncoder::new(&mut counter, XZ_LEVEL);
+    let written = write_nar(&mut encoder).and_then(|()| Ok(encoder.finish().map(|_| ())?));
+    // Closing stdin lets ipfs finish adding the content
+    let HashingWriter { inner, size, .. } = counter;
+    drop(inner);
+    if let Err(e) = written {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(e.context("Could not write the NAR to ipfs"));
+    }
+
+    let mut stderr = String::new();
+    if let Some(mut pipe) = child.stderr.take() {
+        pipe.read_to_string(&mut stderr)?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!("ipfs add failed: {}", stderr.trim());
+    }
+    let cid = String::from_utf8(output.stdout)?.trim().to_string();
+    if cid.is_empty() {
+        bail!("ipfs add did not report a content ID");
+    }
+    Ok(IpfsExport {
+        cid,
+        compression: "xz".to_string(),
+        file_size: size,
+    })
+}
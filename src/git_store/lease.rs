@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Pins the NARs which are being served, by the key they are served under, so that
+/// garbage collection neither evicts their packages nor prunes their objects while
+/// they are downloaded. Leases only cover garbage collection within the serving
+/// process, e.g. in the background or through the control socket.
+#[derive(Debug, Default)]
+pub struct Leases {
+    pinned: Mutex<Pinned>,
+    released: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Pinned {
+    counts: HashMap<String, usize>,
+    /// How many NARs were unpinned so far
+    releases: u64,
+}
+
+/// Pins a NAR until it is dropped.
+#[derive(Debug)]
+pub struct Lease {
+    leases: Arc<Leases>,
+    key: String,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let mut pinned = self.leases.pinned.lock().unwrap();
+        if let Some(count) = pinned.counts.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                pinned.counts.remove(&self.key);
+                pinned.releases += 1;
+                self.leases.released.notify_all();
+            }
+        }
+    }
+}
+
+impl Leases {
+    pub fn lease(self: &Arc<Self>, key: &str) -> Lease {
+        *self
+            .pinned
+            .lock()
+            .unwrap()
+            .counts
+            .entry(key.to_string())
+            .or_default() += 1;
+        Lease {
+            leases: Arc::clone(self),
+            key: key.to_string(),
+        }
+    }
+
+    pub fn is_pinned(&self, key: &str) -> bool {
+        self.pinned.lock().unwrap().counts.contains_key(key)
+    }
+
+    /// The keys of all NARs which are being served, along with a count of the NARs
+    /// unpinned so far to pass to `wait_for_release`.
+    pub fn pinned(&self) -> (Vec<String>, u64) {
+        let pinned = self.pinned.lock().unwrap();
+        (pinned.counts.keys().cloned().collect(), pinned.releases)
+    }
+
+    /// Blocks until a NAR is unpinned after `pinned` returned `releases`, or until
+    /// `timeout` elapsed.
+    pub fn wait_for_release(&self, releases: u64, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let mut pinned = self.pinned.lock().unwrap();
+        while pinned.releases == releases {
+            let Some(remaining) = deadline.checked_duration_since(Instant::now()) else {
+                return;
+            };
+            pinned = self.released.wait_timeout(pinned, remaining).unwrap().0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_leases() {
+        let leases = Arc::new(Leases::default());
+        let first = leases.lease("a");
+        let second = leases.lease("a");
+        let other = leases.lease("b");
+        let (mut pinned, releases) = leases.pinned();
+        pinned.sort();
+        assert_eq!(pinned, ["a", "b"]);
+        drop(first);
+        assert!(leases.is_pinned("a"));
+        drop(second);
+        assert!(!leases.is_pinned("a"));
+        drop(other);
+        assert!(leases.pinned().0.is_empty());
+        // Returns right away, as NARs were unpinned meanwhile
+        leases.wait_for_release(releases, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_wait_for_release() {
+        let leases = Arc::new(Leases::default());
+        let lease = leases.lease("a");
+        let (_, releases) = leases.pinned();
+        let start = Instant::now();
+        leases.wait_for_release(releases, Duration::from_millis(50));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let releasing = std::thread::spawn(move || drop(lease));
+        leases.wait_for_release(releases, Duration::from_secs(60));
+        releasing.join().unwrap();
+        assert!(!leases.is_pinned("a"));
+    }
+}
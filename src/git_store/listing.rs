@@ -0,0 +1,221 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SortBy {
+    #[default]
+    Hash,
+    Name,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ListOptions {
+    pub offset: usize,
+    pub limit: Option<usize>,
+    /// Only list entries whose hash starts with this prefix
+    pub hash: Option<String>,
+    /// Only list entries whose name contains this string
+    pub name: Option<String>,
+    /// Only list entries built for this system
+    pub system: Option<String>,
+    pub sort: SortBy,
+    pub reverse: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Entry {
+    pub hash: String,
+    pub name: String,
+}
+
+impl Display for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.hash, self.name)
+    }
+}
+
+/// An entry along with what people browsing the cache want to know about it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EntrySummary {
+    pub hash: String,
+    pub name: String,
+    pub version: Option<String>,
+    pub nar_size: Option<u64>,
+    /// Seconds since the Unix epoch, if the provenance of the entry was recorded
+    pub added_at: Option<u64>,
+    /// The file name of the derivation which built the entry, if its build log is
+    /// stored
+    pub log: Option<String>,
+}
+
+/// Keeps the hashes of the entries built for the system `options` asks for, if any.
+/// Entries whose system is not known are left out then.
+pub fn filter_system<F>(
+    hashes: Vec<String>,
+    options: &ListOptions,
+    resolve_system: F,
+) -> Result<Vec<String>>
+where
+    F: Fn(&str) -> Result<Option<String>>,
+{
+    let Some(wanted) = &options.system else {
+        return Ok(hashes);
+    };
+    let mut kept = Vec::new();
+    for hash in hashes {
+        if resolve_system(&hash)?.as_ref() == Some(wanted) {
+            kept.push(hash);
+        }
+    }
+    Ok(kept)
+}
+
+/// Applies the filters, ordering and pagination of `options` to a set of package hashes.
+///
+/// Resolving a name means reading the narinfo of the entry, so names are only resolved
+/// for every entry when filtering or sorting by name requires it. Otherwise only the
+/// entries on the requested page are resolved.
+pub fn select<F>(hashes: Vec<String>, options: &ListOptions, resolve_name: F) -> Result<Vec<Entry>>
+where
+    F: Fn(&str) -> Result<String>,
+{
+    let hashes = hashes.into_iter().filter(|hash| {
+        options
+            .hash
+            .as_ref()
+            .is_none_or(|prefix| hash.starts_with(prefix.as_str()))
+    });
+
+    let needs_all_names = options.name.is_some() || options.sort == SortBy::Name;
+    let mut entries: Vec<(String, Option<String>)> = if needs_all_names {
+        let mut entries = Vec::new();
+        for hash in hashes {
+            let name = resolve_name(&hash)?;
+            if options
+                .name
+                .as_ref()
+                .is_none_or(|filter| name.contains(filter.as_str()))
+            {
+                entries.push((hash, Some(name)));
+            }
+        }
+        entries
+    } else {
+        hashes.map(|hash| (hash, None)).collect()
+    };
+
+    match options.sort {
+        SortBy::Hash => entries.sort_by(|(x, _), (y, _)| x.cmp(y)),
+        SortBy::Name => entries.sort_by(|(xh, xn), (yh, yn)| xn.cmp(yn).then(xh.cmp(yh))),
+    }
+    if options.reverse {
+        entries.reverse();
+    }
+
+    entries
+        .into_iter()
+        .skip(options.offset)
+        .take(options.limit.unwrap_or(usize::MAX))
+        .map(|(hash, name)| {
+            let name = match name {
+                Some(name) => name,
+                None => resolve_name(&hash)?,
+            };
+            Ok(Entry { hash, name })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    fn resolve(hash: &str) -> Result<String> {
+        match hash {
+            "aaa" => Ok("zlib".to_string()),
+            "bbb" => Ok("hello".to_string()),
+            "ccc" => Ok("glibc".to_string()),
+            _ => Err(anyhow!("unknown hash {hash}")),
+        }
+    }
+
+    fn hashes() -> Vec<String> {
+        vec!["ccc".to_string(), "aaa".to_string(), "bbb".to_string()]
+    }
+
+    #[test]
+    fn test_paginate_by_hash() -> Result<()> {
+        let options = ListOptions {
+            offset: 1,
+            limit: Some(1),
+            ..Default::default()
+        };
+        let entries = select(hashes(), &options, resolve)?;
+        assert_eq!(
+            entries,
+            vec![Entry {
+                hash: "bbb".to_string(),
+                name: "hello".to_string()
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_and_sort_by_name() -> Result<()> {
+        let options = ListOptions {
+            name: Some("l".to_string()),
+            sort: SortBy::Name,
+            reverse: true,
+            ..Default::default()
+        };
+        let names: Vec<String> = select(hashes(), &options, resolve)?
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(names, vec!["zlib", "hello", "glibc"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_only_page_names_are_resolved() -> Result<()> {
+        let options = ListOptions {
+            hash: Some("a".to_string()),
+            ..Default::default()
+        };
+        let entries = select(hashes(), &options, |hash| {
+            assert_eq!(hash, "aaa");
+            resolve(hash)
+        })?;
+        assert_eq!(entries.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_system() -> Result<()> {
+        let system = |hash: &str| {
+            Ok(match hash {
+                "aaa" | "ccc" => Some("aarch64-linux".to_string()),
+                "bbb" => Some("x86_64-linux".to_string()),
+                _ => None,
+            })
+        };
+        let mut all = hashes();
+        all.push("ddd".to_string());
+        assert_eq!(
+            filter_system(all.clone(), &ListOptions::default(), system)?,
+            all
+        );
+        let options = ListOptions {
+            system: Some("aarch64-linux".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(filter_system(all, &options, system)?, vec!["ccc", "aaa"]);
+        Ok(())
+    }
+}
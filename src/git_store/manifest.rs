@@ -0,0 +1,179 @@
+use anyhow::{Result, bail};
+use git2::Oid;
+use serde::{Deserialize, Serialize};
+
+use crate::nix_interface::nar_info::NarInfo;
+use crate::nix_interface::path::NixPath;
+
+/// Version of the manifest format written by `gachix manifest`.
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Describes the exact set of packages in a closure, so that another instance can
+/// fetch the same set with `gachix prefetch --manifest` and check that it got
+/// identical packages.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    /// Store path of the package the closure belongs to
+    pub root: String,
+    /// Every package in the closure, starting with the root
+    pub paths: Vec<ManifestPath>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestPath {
+    pub store_path: String,
+    pub nar_hash: String,
+    pub nar_size: u64,
+    /// The git tree holding the contents of the package
+    pub tree: String,
+    /// The commit of the package in the repository which wrote the manifest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+}
+
+impl Manifest {
+    /// Describes a closure given the narinfos of its packages, starting with the
+    /// root, and a lookup of their commits.
+    pub fn new<F>(closure: &[NarInfo], commit_of: F) -> Result<Self>
+    where
+        F: Fn(&str) -> Option<Oid>,
+    {
+        let Some(root) = closure.first() else {
+            bail!("A manifest needs at least one package");
+        };
+        Ok(Self {
+            version: MANIFEST_VERSION,
+            root: root.store_path.get_path().to_string(),
+            paths: closure
+                .iter()
+                .map(|narinfo| ManifestPath {
+                    store_path: narinfo.store_path.get_path().to_string(),
+                    nar_hash: narinfo.nar_hash.clone(),
+                    nar_size: narinfo.nar_size,
+                    tree: narinfo.key.clone(),
+                    commit: commit_of(narinfo.store_path.get_base_32_hash())
+                        .map(|oid| oid.to_string()),
+                })
+                .collect(),
+        })
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        let manifest: Self = serde_json::from_str(json)?;
+        if manifest.version != MANIFEST_VERSION {
+            bail!(
+                "Unsupported manifest version {}, expected {}",
+                manifest.version,
+                MANIFEST_VERSION
+            );
+        }
+        Ok(manifest)
+    }
+
+    pub fn store_paths(&self) -> Result<Vec<NixPath>> {
+        self.paths
+            .iter()
+            .map(|p| NixPath::new(&p.store_path))
+            .collect()
+    }
+
+    /// Compares the packages of the manifest with the stored ones, given a lookup
+    /// of the narinfo of a stored package by its hash. Returns what is missing or
+    /// differs, one line per package.
+    pub fn differences<F>(&self, stored: F) -> Result<Vec<String>>
+    where
+        F: Fn(&str) -> Result<Option<NarInfo>>,
+    {
+        let mut differences = Vec::new();
+        for path in &self.paths {
+            let hash = NixPath::new(&path.store_path)?
+                .get_base_32_hash()
+                .to_string();
+            let Some(narinfo) = stored(&hash)? else {
+                differences.push(format!("{} is missing", path.store_path));
+                continue;
+            };
+            if narinfo.nar_hash != path.nar_hash || narinfo.nar_size != path.nar_size {
+                differences.push(format!(
+                    "{} has NAR hash {} ({} bytes) instead of {} ({} bytes)",
+                    path.store_path,
+                    narinfo.nar_hash,
+                    narinfo.nar_size,
+                    path.nar_hash,
+                    path.nar_size
+                ));
+            } else if narinfo.key != path.tree {
+                differences.push(format!(
+                    "{} is stored as tree {} instead of {}",
+                    path.store_path, narinfo.key, path.tree
+                ));
+            }
+        }
+        Ok(differences)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn narinfo(hash: &str, nar_hash: &str, references: &[&str]) -> Result<NarInfo> {
+        Ok(NarInfo::new(
+            NixPath::new(&format!("/nix/store/{hash}-pkg"))?,
+            "4b825dc642cb6eb9a060e54bf8d69288fbee4904".to_string(),
+            nar_hash.to_string(),
+            120,
+            None,
+            nar_hash.to_string(),
+            120,
+            None,
+            references
+                .iter()
+                .map(|r| NixPath::new(&format!("/nix/store/{r}-pkg")))
+                .collect::<Result<_>>()?,
+            None,
+        ))
+    }
+
+    #[test]
+    fn test_manifest() -> Result<()> {
+        let (root, dep) = ("r".repeat(32), "d".repeat(32));
+        let closure = [
+            narinfo(&root, "sha256:aaa", &[&dep])?,
+            narinfo(&dep, "sha256:bbb", &[])?,
+        ];
+        let commit = Oid::from_bytes(&[0xab; 20])?;
+        let manifest = Manifest::new(&closure, |hash| (hash == root).then_some(commit))?;
+        assert_eq!(manifest.root, format!("/nix/store/{root}-pkg"));
+        assert_eq!(manifest.paths.len(), 2);
+        assert_eq!(manifest.paths[0].commit, Some(commit.to_string()));
+        assert_eq!(manifest.paths[1].commit, None);
+
+        let json = manifest.to_json()?;
+        assert!(!json.contains("\"commit\": null"));
+        assert_eq!(Manifest::from_json(&json)?, manifest);
+        assert!(Manifest::from_json(&json.replace("\"version\": 1", "\"version\": 2")).is_err());
+        assert!(Manifest::new(&[], |_| None).is_err());
+
+        assert!(
+            manifest
+                .differences(|hash| Ok(closure
+                    .iter()
+                    .find(|n| n.store_path.get_base_32_hash() == hash)
+                    .cloned()))?
+                .is_empty()
+        );
+        let rebuilt = narinfo(&root, "sha256:ccc", &[&dep])?;
+        let differences =
+            manifest.differences(|hash| Ok((hash == root).then(|| rebuilt.clone())))?;
+        assert_eq!(differences.len(), 2);
+        assert!(differences[0].contains("sha256:ccc"), "{}", differences[0]);
+        assert_eq!(differences[1], format!("/nix/store/{dep}-pkg is missing"));
+        Ok(())
+    }
+}
@@ -1,3 +1,35 @@
+pub mod advertisement;
+pub mod audit;
+pub mod channels;
+pub mod dedup;
+pub mod derivers;
+pub mod events;
+pub mod extract;
+pub mod filter;
+pub mod fsck;
+pub mod gc;
+pub mod handshake;
+pub mod health;
+pub mod history;
+pub mod ipfs;
+pub mod lease;
+pub mod listing;
+pub mod manifest;
+pub mod plan;
+pub mod priority;
+pub mod provenance;
+pub mod quarantine;
 pub mod repository;
 pub use repository::GitRepo;
+pub mod revisions;
+pub mod revocations;
+pub mod rollback;
+pub mod sbom;
+pub mod scan;
+pub mod snapshot;
+pub mod static_site;
 pub mod store;
+pub mod tags;
+pub mod tombstones;
+pub mod upstream;
+pub mod verify;
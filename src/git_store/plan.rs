@@ -0,0 +1,117 @@
+use crate::nix_interface::path::NixPath;
+use std::fmt::Display;
+
+/// Where a store path can be substituted from, in the order `add` tries them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    /// Already in the repository
+    Stored,
+    /// A git peer which replicated the package, only objects which are not stored
+    /// yet are transferred
+    Peer(String),
+    /// A Nix daemon which holds the path, its whole NAR is transferred
+    Daemon(String),
+}
+
+/// The cheapest source of a wanted store path.
+#[derive(Debug, Clone)]
+pub struct PlannedPath {
+    pub path: NixPath,
+    /// `None` if no source has the path
+    pub source: Option<Source>,
+    /// Size of the NAR if it has to be transferred and a daemon reported it
+    pub estimated_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SubstitutionPlan {
+    pub paths: Vec<PlannedPath>,
+}
+
+impl SubstitutionPlan {
+    /// Bytes expected to be transferred, not counting paths of unknown size.
+    pub fn estimated_bytes(&self) -> u64 {
+        self.paths.iter().filter_map(|p| p.estimated_bytes).sum()
+    }
+
+    pub fn unavailable(&self) -> impl Iterator<Item = &NixPath> {
+        self.paths
+            .iter()
+            .filter(|p| p.source.is_none())
+            .map(|p| &p.path)
+    }
+}
+
+impl Display for SubstitutionPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for planned in &self.paths {
+            write!(f, "{}: ", planned.path)?;
+            match &planned.source {
+                Some(Source::Stored) => write!(f, "stored")?,
+                Some(Source::Peer(url)) => write!(f, "git peer {url}")?,
+                Some(Source::Daemon(address)) => write!(f, "Nix daemon at {address}")?,
+                None => write!(f, "unavailable")?,
+            }
+            match planned.estimated_bytes {
+                Some(bytes) if planned.source != Some(Source::Stored) => {
+                    writeln!(f, " ({bytes} bytes)")?
+                }
+                _ => writeln!(f)?,
+            }
+        }
+        writeln!(
+            f,
+            "Total: {} bytes to transfer, {} paths unavailable",
+            self.estimated_bytes(),
+            self.unavailable().count()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn test_plan() -> Result<()> {
+        let path = |name: &str| NixPath::new(&format!("/nix/store/{}-{name}", "a".repeat(32)));
+        let plan = SubstitutionPlan {
+            paths: vec![
+                PlannedPath {
+                    path: path("glibc")?,
+                    source: Some(Source::Stored),
+                    estimated_bytes: Some(0),
+                },
+                PlannedPath {
+                    path: path("hello")?,
+                    source: Some(Source::Daemon("builder".to_string())),
+                    estimated_bytes: Some(300),
+                },
+                PlannedPath {
+                    path: path("missing")?,
+                    source: None,
+                    estimated_bytes: None,
+                },
+            ],
+        };
+        assert_eq!(plan.estimated_bytes(), 300);
+        assert_eq!(
+            plan.unavailable()
+                .map(NixPath::get_name)
+                .collect::<Vec<_>>(),
+            ["missing"]
+        );
+        let a = "a".repeat(32);
+        assert_eq!(
+            plan.to_string(),
+            format!(
+                "/nix/store/{a}-glibc: stored\n\
+                 /nix/store/{a}-hello: Nix daemon at builder (300 bytes)\n\
+                 /nix/store/{a}-missing: unavailable\n\
+                 Total: 300 bytes to transfer, 1 paths unavailable\n"
+            )
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often yielding background work checks whether clients are still served.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Lets requests of clients preempt background work, such as closures added over
+/// the control socket and garbage collection. Background work yields before every
+/// step while requests are being served, but never longer than `max_wait` at a
+/// time. Once it waited that long, background work gets a turn of the same length
+/// in which it does not yield, so that it keeps a fair share under constant load,
+/// e.g. while a large NAR is being downloaded.
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    interactive: AtomicUsize,
+    /// The end of the current turn of background work
+    turn_until: Mutex<Option<Instant>>,
+}
+
+/// Marks a client request as being served until it is dropped.
+#[derive(Debug)]
+pub struct Interactive {
+    scheduler: Arc<Scheduler>,
+}
+
+impl Drop for Interactive {
+    fn drop(&mut self) {
+        self.scheduler.interactive.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Scheduler {
+    pub fn interactive(self: &Arc<Self>) -> Interactive {
+        self.interactive.fetch_add(1, Ordering::Relaxed);
+        Interactive {
+            scheduler: Arc::clone(self),
+        }
+    }
+
+    /// Number of client requests which are being served.
+    pub fn serving(&self) -> usize {
+        self.interactive.load(Ordering::Relaxed)
+    }
+
+    /// Blocks until no client requests are being served or `max_wait` has passed,
+    /// and returns how long it waited. Returns right away during a turn of
+    /// background work.
+    pub fn yield_blocking(&self, max_wait: Duration) -> Duration {
+        if self.has_turn() {
+            return Duration::ZERO;
+        }
+        let start = Instant::now();
+        while self.serving() > 0 && start.elapsed() < max_wait {
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        self.finish_wait(start, max_wait)
+    }
+
+    /// Waits until no client requests are being served or `max_wait` has passed,
+    /// and returns how long it waited. Returns right away during a turn of
+    /// background work.
+    pub async fn yield_now(&self, max_wait: Duration) -> Duration {
+        if self.has_turn() {
+            return Duration::ZERO;
+        }
+        let start = Instant::now();
+        while self.serving() > 0 && start.elapsed() < max_wait {
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        self.finish_wait(start, max_wait)
+    }
+
+    fn has_turn(&self) -> bool {
+        self.turn_until
+            .lock()
+            .unwrap()
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    /// Starts a turn of background work if a wait begun at `start` gave up on the
+    /// clients, and returns how long it waited.
+    fn finish_wait(&self, start: Instant, max_wait: Duration) -> Duration {
+        let waited = start.elapsed();
+        if self.serving() > 0 && waited >= max_wait {
+            *self.turn_until.lock().unwrap() = Some(Instant::now() + max_wait);
+        }
+        waited
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_background_yields_to_interactive() {
+        let scheduler = Arc::new(Scheduler::default());
+        assert!(scheduler.yield_blocking(Duration::from_secs(10)) < POLL_INTERVAL);
+
+        let request = scheduler.interactive();
+        assert_eq!(scheduler.serving(), 1);
+        // Never waits longer than allowed while requests keep coming in
+        let waited = scheduler.yield_now(Duration::from_millis(30)).await;
+        assert!(waited >= Duration::from_millis(30));
+
+        // Having waited that long, background work gets a turn
+        assert!(scheduler.yield_now(Duration::from_millis(30)).await < POLL_INTERVAL);
+        assert!(scheduler.yield_blocking(Duration::from_millis(30)) < POLL_INTERVAL);
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        let finish = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(request);
+        });
+        let waited = scheduler.yield_now(Duration::from_secs(10)).await;
+        assert!(waited >= Duration::from_millis(50));
+        assert!(waited < Duration::from_secs(10));
+        assert_eq!(scheduler.serving(), 0);
+        finish.await.unwrap();
+    }
+}
@@ -0,0 +1,73 @@
+use std::fmt::Display;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::nix_interface::path::NixPath;
+
+/// Where and when a package entered the cache.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// Address of the Nix daemon the package was fetched from
+    pub source: String,
+    /// Seconds since the Unix epoch
+    pub added_at: u64,
+    pub deriver: Option<String>,
+    /// Set if the package was built on request of gachix
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build: Option<BuildInfo>,
+    /// What the scanners which flagged the package reported
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub findings: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BuildInfo {
+    /// Address of the Nix daemon which built the package
+    pub builder: String,
+    /// Seconds since the Unix epoch
+    pub started_at: u64,
+    pub duration_secs: u64,
+}
+
+impl Provenance {
+    pub fn new(source: String, deriver: Option<&NixPath>) -> Self {
+        Self {
+            source,
+            added_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            deriver: deriver.map(|d| d.to_string()),
+            build: None,
+            findings: Vec::new(),
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn from_json(json: &[u8]) -> Result<Self> {
+        Ok(serde_json::from_slice(json)?)
+    }
+}
+
+impl Display for Provenance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Source: {}", self.source)?;
+        writeln!(f, "AddedAt: {}", self.added_at)?;
+        if let Some(deriver) = &self.deriver {
+            writeln!(f, "Deriver: {deriver}")?;
+        }
+        if let Some(build) = &self.build {
+            writeln!(f, "BuiltOn: {}", build.builder)?;
+            writeln!(f, "BuildStartedAt: {}", build.started_at)?;
+            writeln!(f, "BuildDuration: {}s", build.duration_secs)?;
+        }
+        for finding in &self.findings {
+            writeln!(f, "Flagged: {finding}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,58 @@
+use super::verify::Verification;
+use std::fmt::Display;
+
+/// Packages fetched from git peers are staged below this namespace until they are
+/// verified. Those which fail verification are held for review if
+/// `store.quarantine_rejected` is set, and those not signed by a trusted key if
+/// `store.review_unsigned` is set. References are named `<hash>.<kind>`, see
+/// `snapshot::package_ref`.
+pub const QUARANTINE_NAMESPACE: &str = "refs/quarantine";
+
+pub fn quarantine_ref(hash: &str, kind: &str) -> String {
+    format!("{QUARANTINE_NAMESPACE}/{hash}.{kind}")
+}
+
+/// Splits a quarantine reference into the hash and the kind of the reference.
+pub fn parse_quarantine_ref(reference: &str) -> Option<(&str, &str)> {
+    reference
+        .strip_prefix(QUARANTINE_NAMESPACE)?
+        .strip_prefix('/')?
+        .split_once('.')
+}
+
+/// A package held in quarantine and why it was not exposed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuarantinedPackage {
+    pub hash: String,
+    pub store_path: Option<String>,
+    pub verification: Verification,
+}
+
+impl Display for QuarantinedPackage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {}: {}",
+            self.hash,
+            self.store_path.as_deref().unwrap_or("-"),
+            self.verification
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quarantine_refs() {
+        let hash = "a".repeat(32);
+        let reference = quarantine_ref(&hash, "narinfo");
+        assert_eq!(reference, format!("refs/quarantine/{hash}.narinfo"));
+        assert_eq!(
+            parse_quarantine_ref(&reference),
+            Some((hash.as_str(), "narinfo"))
+        );
+        assert_eq!(parse_quarantine_ref(&format!("refs/{hash}/narinfo")), None);
+    }
+}
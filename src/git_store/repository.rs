@@ -1,27 +1,170 @@
+use crate::git_store::extract;
 use crate::nar::NarGitStream;
-use crate::nar::decode::NarGitDecoder;
+use crate::nar::budget::MemoryBudget;
+use crate::nar::decode::{Dedup, NarGitDecoder};
+use crate::nar::encode::NarGitEncoder;
+use crate::nar::index::NarIndex;
 use anyhow::{Context, Result, anyhow, bail};
 use git2::Cred;
 use git2::Direction;
 use git2::FetchOptions;
+use git2::PushOptions;
 use git2::RemoteCallbacks;
 use git2::Signature;
 use git2::Time;
-use git2::{ErrorCode, FileMode, Oid, Repository};
+use git2::{ErrorCode, FileMode, ObjectType, Oid, Repository};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 use std::env;
+use std::ffi::CString;
 use std::fs;
-use std::io::Read;
+use std::io::{Read, Write};
+use std::ops::Deref;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
-use std::sync::{Arc, RwLock};
-use tracing::{Level, info, instrument, span, trace};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{Level, debug, info, span, trace, warn};
 
+/// A pool of `Repository` handles opened on the same repository.
+///
+/// libgit2 handles are not `Sync`, so instead of serialising every operation
+/// behind one shared handle, each operation checks out its own handle and
+/// returns it to the pool when it is dropped.
+pub struct RepoPool {
+    path: PathBuf,
+    idle: Mutex<Vec<Repository>>,
+}
+
+impl RepoPool {
+    pub fn new(repo: Repository) -> Self {
+        Self {
+            path: repo.path().to_path_buf(),
+            idle: Mutex::new(vec![repo]),
+        }
+    }
+
+    pub fn get(self: &Arc<Self>) -> Result<RepoHandle, git2::Error> {
+        let idle = self
+            .idle
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .pop();
+        let repo = match idle {
+            Some(repo) => repo,
+            None => {
+                trace!("Opening additional repository handle");
+                Repository::open(&self.path)?
+            }
+        };
+        Ok(RepoHandle {
+            repo: Some(repo),
+            pool: Arc::clone(self),
+        })
+    }
+
+    /// Closes the idle handles, so that their caches of packs and objects are
+    /// dropped. Handles which are in use are returned to the pool as usual.
+    pub fn clear(&self) {
+        self.idle
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clear();
+    }
+}
+
+/// A repository handle checked out of a `RepoPool`.
+pub struct RepoHandle {
+    repo: Option<Repository>,
+    pool: Arc<RepoPool>,
+}
+
+impl Deref for RepoHandle {
+    type Target = Repository;
+
+    fn deref(&self) -> &Repository {
+        self.repo.as_ref().expect("Handle is only emptied on drop")
+    }
+}
+
+impl Drop for RepoHandle {
+    fn drop(&mut self) {
+        if let Some(repo) = self.repo.take() {
+            self.pool
+                .idle
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .push(repo);
+        }
+    }
+}
+
+/// Objects of a NAR decoded by `GitRepo::stage_nar`, which are kept apart from the
+/// repository until `keep` is called, and removed if it is dropped before.
+pub struct StagedNar {
+    dir: tempfile::TempDir,
+    objects: PathBuf,
+    pub oid: Oid,
+    pub dedup: Dedup,
+}
+
+impl StagedNar {
+    /// Moves the staged objects into the repository.
+    pub fn keep(self) -> Result<Oid> {
+        for fanout in fs::read_dir(self.dir.path().join("objects"))? {
+            let fanout = fanout?;
+            let name = fanout.file_name();
+            if name.len() != 2 || !fanout.file_type()?.is_dir() {
+                continue;
+            }
+            let destination = self.objects.join(&name);
+            fs::create_dir_all(&destination)?;
+            for object in fs::read_dir(fanout.path())? {
+                let object = object?;
+                fs::rename(object.path(), destination.join(object.file_name()))?;
+            }
+        }
+        Ok(self.oid)
+    }
+}
+
+#[derive(Default)]
+struct HashingWriter {
+    hasher: Sha256,
+    size: u64,
+}
+
+impl Write for HashingWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.hasher.update(buf);
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A commit of a log written by `GitRepo::append_to_log`.
+#[derive(Debug, Clone)]
+pub struct LogCommit {
+    pub id: Oid,
+    /// Seconds since the Unix epoch
+    pub time: i64,
+    pub author: String,
+    pub email: String,
+    pub message: String,
+    pub tree: Oid,
+}
+
+#[derive(Clone)]
 pub struct GitRepo {
-    repo: Arc<RwLock<Repository>>,
+    pool: Arc<RepoPool>,
+    read_only: bool,
+    budget: Arc<MemoryBudget>,
 }
-unsafe impl Sync for GitRepo {}
-unsafe impl Send for GitRepo {}
 
 impl GitRepo {
     pub fn new(path_to_repo: &Path) -> Result<Self, git2::Error> {
@@ -41,12 +184,78 @@ impl GitRepo {
         let mut config = repo.config()?;
         config.set_str("protocol.version", "2")?;
         Ok(Self {
-            repo: RwLock::new(repo).into(),
+            pool: Arc::new(RepoPool::new(repo)),
+            read_only: false,
+            budget: MemoryBudget::unlimited(),
+        })
+    }
+
+    /// Opens an existing repository without ever writing to it, e.g. a replicated
+    /// checkout which is only served.
+    pub fn open_read_only(path_to_repo: &Path) -> Result<Self> {
+        if !path_to_repo.exists() {
+            bail!("No Git repository at {}", path_to_repo.display());
+        }
+        info!(
+            "Using the Git repository at {} read-only",
+            path_to_repo.display()
+        );
+        Ok(Self {
+            pool: Arc::new(RepoPool::new(Repository::open(path_to_repo)?)),
+            read_only: true,
+            budget: MemoryBudget::unlimited(),
         })
     }
 
+    /// Limits the file contents which NARs being added and served hold in memory.
+    pub fn with_memory_budget(mut self, budget: Arc<MemoryBudget>) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    pub fn memory_budget(&self) -> &Arc<MemoryBudget> {
+        &self.budget
+    }
+
+    /// Opens an anonymous temporary file in the repository. The temporary directory
+    /// may be a tmpfs, which would defeat spilling to disk.
+    pub fn spill_file(&self) -> std::io::Result<fs::File> {
+        tempfile::tempfile_in(&self.pool.path)
+    }
+
+    /// Directory in the repository which holds the chunks of resumable uploads.
+    pub fn uploads_dir(&self) -> PathBuf {
+        self.pool.path.join("gachix-uploads")
+    }
+
+    /// known_hosts file in the repository with the keys of hosts trusted on first use.
+    pub fn known_hosts_path(&self) -> PathBuf {
+        self.pool.path.join("gachix-known-hosts")
+    }
+
+    fn repo(&self) -> Result<RepoHandle, git2::Error> {
+        self.pool.get()
+    }
+
+    /// Reopens the repository on next use, e.g. to see packs which were written by
+    /// another process.
+    pub fn close_idle_handles(&self) {
+        self.pool.clear();
+    }
+
+    fn ensure_writable(&self) -> Result<()> {
+        if self.read_only {
+            bail!(
+                "The repository at {} is read-only",
+                self.pool.path.display()
+            );
+        }
+        Ok(())
+    }
+
     pub fn add_file_content(&self, content: &[u8]) -> Result<Oid> {
-        let read_repo = self.repo.read().unwrap();
+        self.ensure_writable()?;
+        let read_repo = self.repo()?;
         let blob_oid = read_repo.blob(content)?;
         Ok(blob_oid)
     }
@@ -57,33 +266,81 @@ impl GitRepo {
         if !path.is_dir() {
             return Err(anyhow!("No such directory: {}", path.to_str().unwrap()));
         }
-        let tree_oid = self.create_tree_from_dir(&path)?;
+        self.ensure_writable()?;
+        let repo = self.repo()?;
+        let tree_oid = Self::create_tree_from_dir(&repo, &path)?;
         Ok(tree_oid)
     }
 
-    pub fn add_nar(&self, content: impl Read) -> Result<(Oid, i32)> {
-        let repo = self.repo.read().unwrap();
-        let decoder = NarGitDecoder::new(&repo);
+    /// Decodes a NAR into git objects. Also returns how much of its contents was
+    /// already stored.
+    pub fn add_nar(&self, content: impl Read) -> Result<(Oid, i32, Dedup)> {
+        self.ensure_writable()?;
+        let repo = self.repo()?;
+        let decoder = NarGitDecoder::new(&repo).with_budget(Arc::clone(&self.budget));
         let (oid, filemode) = decoder
             .parse(content)
             .with_context(|| "Error decoding NAR file")?;
-        Ok((oid, filemode))
+        Ok((oid, filemode, decoder.dedup()))
+    }
+
+    /// Decodes a NAR like `add_nar`, but writes the new objects to a staging
+    /// repository, which borrows the objects of this one, so that nothing is left
+    /// behind if the NAR is rejected afterwards.
+    pub fn stage_nar(&self, content: impl Read) -> Result<StagedNar> {
+        self.ensure_writable()?;
+        let dir = tempfile::Builder::new()
+            .prefix("gachix-incoming-")
+            .tempdir_in(&self.pool.path)?;
+        let objects = self.pool.path.join("objects");
+        Repository::init_bare(dir.path())?;
+        let info = dir.path().join("objects").join("info");
+        fs::create_dir_all(&info)?;
+        fs::write(info.join("alternates"), format!("{}\n", objects.display()))?;
+        // Opened again, so that the alternates are read
+        let staging = Repository::open_bare(dir.path())?;
+        let decoder = NarGitDecoder::new(&staging).with_budget(Arc::clone(&self.budget));
+        let (oid, _) = decoder
+            .parse(content)
+            .with_context(|| "Error decoding NAR file")?;
+        let dedup = decoder.dedup();
+        Ok(StagedNar {
+            dir,
+            objects,
+            oid,
+            dedup,
+        })
     }
 
     pub fn get_blob(&self, oid: Oid) -> Result<Vec<u8>> {
-        let repo = self.repo.read().unwrap();
+        let repo = self.repo()?;
         let blob = repo.find_blob(oid)?;
         Ok(blob.content().to_vec())
     }
 
     pub fn add_ref(&self, ref_name: &str, oid: Oid) -> Result<()> {
-        let repo = self.repo.read().unwrap();
+        self.ensure_writable()?;
+        let repo = self.repo()?;
         repo.reference(&ref_name, oid, false, "")?;
         Ok(())
     }
 
+    pub fn update_ref(&self, ref_name: &str, oid: Oid) -> Result<()> {
+        self.ensure_writable()?;
+        let repo = self.repo()?;
+        repo.reference(ref_name, oid, true, "")?;
+        Ok(())
+    }
+
+    pub fn delete_ref(&self, ref_name: &str) -> Result<()> {
+        self.ensure_writable()?;
+        let repo = self.repo()?;
+        repo.find_reference(ref_name)?.delete()?;
+        Ok(())
+    }
+
     pub fn get_entry_as_nar(&self, oid: Oid) -> Result<Option<NarGitStream>> {
-        let repo = self.repo.read().unwrap();
+        let repo = self.repo()?;
         let object = repo.find_object(oid, None)?;
         let kind = object
             .kind()
@@ -94,45 +351,120 @@ impl GitRepo {
             _ => bail!("Object must either be a tree or a blob"),
         };
 
-        let repo_owned = Arc::clone(&self.repo);
-        let stream = NarGitStream::new(repo_owned, oid, filemode);
+        let stream = NarGitStream::new(Arc::clone(&self.pool), oid, filemode)
+            .with_budget(Arc::clone(&self.budget));
         Ok(Some(stream))
     }
 
+    /// Computes the sha256 hash and the size of the NAR serialisation of an entry.
+    pub fn nar_hash(&self, oid: Oid) -> Result<(Vec<u8>, u64)> {
+        let mut writer = HashingWriter::default();
+        self.write_nar(oid, &mut writer)?;
+        Ok((writer.hasher.finalize().to_vec(), writer.size))
+    }
+
+    /// Writes the NAR serialisation of an entry.
+    pub fn write_nar(&self, oid: Oid, writer: impl Write) -> Result<()> {
+        let repo = self.repo()?;
+        let object = repo.find_object(oid, None)?;
+        let filemode = match object.kind() {
+            Some(git2::ObjectType::Blob) => FileMode::Blob.into(),
+            Some(git2::ObjectType::Tree) => FileMode::Tree.into(),
+            _ => bail!("Object must either be a tree or a blob"),
+        };
+        NarGitEncoder::new(&repo, &object, filemode).encode_into(writer)
+    }
+
+    pub fn contains(&self, oid: Oid) -> Result<bool> {
+        Ok(self.repo()?.odb()?.exists(oid))
+    }
+
+    /// Indexes where the file contents of an entry are in its NAR serialisation.
+    pub fn nar_index(&self, oid: Oid) -> Result<NarIndex> {
+        let repo = self.repo()?;
+        let filemode = match repo.find_object(oid, None)?.kind() {
+            Some(git2::ObjectType::Blob) => FileMode::Blob.into(),
+            Some(git2::ObjectType::Tree) => FileMode::Tree.into(),
+            _ => bail!("Object must either be a tree or a blob"),
+        };
+        NarIndex::new(&repo, oid, filemode)
+    }
+
+    /// Reads `len` bytes of a blob starting at `start`.
+    pub fn read_blob_range(&self, oid: Oid, start: u64, len: u64) -> Result<Vec<u8>> {
+        let repo = self.repo()?;
+        let blob = repo.find_blob(oid)?;
+        let range = usize::try_from(start)?..usize::try_from(start + len)?;
+        blob.content()
+            .get(range)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| anyhow!("Range is outside of blob {}", oid))
+    }
+
+    /// Writes an entry to disk with the metadata Nix gives store paths.
+    pub fn extract(&self, oid: Oid, dest: &Path) -> Result<()> {
+        let repo = self.repo()?;
+        extract::extract(&repo, oid, dest)
+    }
+
+    pub fn get_commit_parents(&self, oid: Oid) -> Result<Vec<Oid>> {
+        let repo = self.repo()?;
+        let commit = repo.find_commit(oid)?;
+        Ok(commit.parent_ids().collect())
+    }
+
+    pub fn get_commit_tree(&self, oid: Oid) -> Result<Oid> {
+        let repo = self.repo()?;
+        let commit = repo.find_commit(oid)?;
+        Ok(commit.tree_id())
+    }
+
     pub fn get_oid_from_reference(&self, reference: &str) -> Option<Oid> {
-        let repo = self.repo.read().unwrap();
+        let repo = self.repo().ok()?;
         let res = repo.find_reference(reference).ok().and_then(|r| r.target());
         res
     }
 
-    fn create_tree_from_dir(&self, path: &Path) -> Result<Oid> {
-        let repo = self.repo.read().unwrap();
+    /// Builds a tree which only depends on the contents of the directory: entries are
+    /// visited in byte order of their names, and only the executable bit of the owner
+    /// is kept, like in a NAR. Sockets, fifos and devices can't be stored and are
+    /// skipped.
+    fn create_tree_from_dir(repo: &Repository, path: &Path) -> Result<Oid> {
+        let mut entries = path.read_dir()?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
         let mut builder = repo.treebuilder(None)?;
-        for entry in path.read_dir()? {
-            let entry_path = entry?.path();
-            let entry_file_name = entry_path
-                .file_name()
-                .expect("Failed to get filename")
-                .to_str()
-                .unwrap();
-
-            if entry_path.is_symlink() {
+        for entry in entries {
+            let entry_path = entry.path();
+            let entry_file_name = entry.file_name();
+            let file_type = entry.file_type()?;
+
+            if file_type.is_symlink() {
                 let target = fs::read_link(&entry_path)?;
                 let blob_oid = repo.blob(target.as_os_str().as_bytes())?;
-                builder.insert(entry_file_name, blob_oid, FileMode::Link.into())?;
-            } else if entry_path.is_file() {
-                let permissions = entry_path.metadata()?.permissions();
-                let is_executable = permissions.mode() & 0o111 != 0;
+                builder.insert(entry_file_name.as_os_str(), blob_oid, FileMode::Link.into())?;
+            } else if file_type.is_file() {
+                let permissions = entry.metadata()?.permissions();
+                let is_executable = permissions.mode() & 0o100 != 0;
                 let filemode = if is_executable {
                     FileMode::BlobExecutable
                 } else {
                     FileMode::Blob
                 };
                 let blob_oid = repo.blob_path(&entry_path)?;
-                builder.insert(entry_file_name, blob_oid, filemode.into())?;
-            } else if entry_path.is_dir() {
-                let subtree_oid = self.create_tree_from_dir(&entry_path)?;
-                builder.insert(entry_file_name, subtree_oid, FileMode::Tree.into())?;
+                builder.insert(entry_file_name.as_os_str(), blob_oid, filemode.into())?;
+            } else if file_type.is_dir() {
+                let subtree_oid = Self::create_tree_from_dir(repo, &entry_path)?;
+                builder.insert(
+                    entry_file_name.as_os_str(),
+                    subtree_oid,
+                    FileMode::Tree.into(),
+                )?;
+            } else {
+                warn!(
+                    "Skipping {}, which is not a file, directory or symlink",
+                    entry_path.display()
+                );
             }
         }
         Ok(builder.write()?)
@@ -142,7 +474,8 @@ impl GitRepo {
         let span = span!(Level::TRACE, "Commiting", comment);
         let _guard = span.enter();
 
-        let repo = self.repo.write().unwrap();
+        self.ensure_writable()?;
+        let repo = self.repo()?;
         let sig = Signature::new("gachix", "gachix@gachix.com", &Time::new(0, 0))?;
 
         trace!("Retrieving main tree object {}", tree_oid);
@@ -170,7 +503,7 @@ impl GitRepo {
     }
 
     pub fn reference_exists(&self, name: &str) -> Result<bool> {
-        let repo = self.repo.read().unwrap();
+        let repo = self.repo()?;
         match repo.find_reference(name) {
             Ok(_) => Ok(true),
             Err(e) => {
@@ -183,97 +516,645 @@ impl GitRepo {
         }
     }
 
+    pub fn count_references(&self, ref_name: &str) -> Result<usize> {
+        let repo = self.repo()?;
+        let mut count = 0;
+        for name in repo.references_glob(ref_name)?.names() {
+            name?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Moves all loose references into the packed-refs file.
+    ///
+    /// With one reference per package and kind, loose references quickly turn into
+    /// millions of tiny files. git2 does not expose ref database compression, so this
+    /// goes through libgit2 directly.
+    pub fn pack_refs(&self) -> Result<()> {
+        self.ensure_writable()?;
+        let repo = self.repo()?;
+        let ref_storage = repo
+            .config()?
+            .get_string("extensions.refstorage")
+            .unwrap_or_default();
+        if ref_storage == "reftable" {
+            // Reftable compacts itself, there are no loose refs to pack
+            trace!("Repository uses reftable, skipping ref packing");
+            return Ok(());
+        }
+        let path = CString::new(repo.path().as_os_str().as_bytes())?;
+        drop(repo);
+
+        // SAFETY: every pointer handed to libgit2 is either obtained from libgit2 itself
+        // or a valid nul-terminated string, and each object is freed exactly once
+        let code = unsafe {
+            let mut raw_repo = std::ptr::null_mut();
+            let code = libgit2_sys::git_repository_open(&mut raw_repo, path.as_ptr());
+            if code < 0 {
+                code
+            } else {
+                let mut refdb = std::ptr::null_mut();
+                let mut code = libgit2_sys::git_repository_refdb(&mut refdb, raw_repo);
+                if code >= 0 {
+                    code = libgit2_sys::git_refdb_compress(refdb);
+                    libgit2_sys::git_refdb_free(refdb);
+                }
+                libgit2_sys::git_repository_free(raw_repo);
+                code
+            }
+        };
+        if code < 0 {
+            bail!(
+                "Failed to pack references: {}",
+                git2::Error::last_error(code)
+            );
+        }
+        Ok(())
+    }
+
+    /// Moves the given objects from loose files into a single pack and returns how
+    /// many were moved. Objects which are packed already are left alone.
+    ///
+    /// Every object git2 writes is a loose file of its own. Packing similar objects
+    /// like narinfos together lets them be delta compressed, and reading many of them
+    /// touches one file instead of one per object.
+    pub fn pack_loose_objects(&self, oids: &[Oid]) -> Result<usize> {
+        self.ensure_writable()?;
+        let objects = self.repo()?.path().join("objects");
+        let loose_path = |oid: &Oid| {
+            let hex = oid.to_string();
+            objects.join(&hex[..2]).join(&hex[2..])
+        };
+        let mut loose: Vec<Oid> = oids
+            .iter()
+            .copied()
+            .filter(|oid| loose_path(oid).exists())
+            .collect();
+        loose.sort();
+        loose.dedup();
+        if loose.is_empty() {
+            return Ok(0);
+        }
+        {
+            let repo = self.repo()?;
+            let odb = repo.odb()?;
+            let mut writer = odb.packwriter()?;
+            self.write_objects(&loose, &mut writer)?;
+            writer.commit()?;
+        }
+        // Only now that the pack is in place can the loose copies go
+        for oid in &loose {
+            fs::remove_file(loose_path(oid))?;
+        }
+        Ok(loose.len())
+    }
+
     pub fn list_references(&self, ref_name: &str) -> Result<Vec<String>> {
-        let repo = self.repo.read().unwrap();
-        let refs = repo.references_glob(ref_name)?;
+        let repo = self.repo()?;
+        let mut refs = repo.references_glob(ref_name)?;
         let mut refs_names = Vec::new();
-        for reference in refs {
-            let reference = reference?;
-            refs_names.push(
-                reference
-                    .name()
-                    .ok_or_else(|| anyhow!("Could not get name from reference"))?
-                    .to_string(),
-            );
+        // Only read the names, peeling every reference is wasted work for a listing
+        for name in refs.names() {
+            refs_names.push(name?.to_string());
         }
         Ok(refs_names)
     }
 
-    pub fn check_remote_health(&self, url: &str) -> Result<()> {
-        let repo = self.repo.read().unwrap();
-        let mut remote = repo.remote_anonymous(url)?;
-        let mut callbacks = RemoteCallbacks::new();
-        callbacks.credentials(|_url, _user_from_url, _allowed_types| {
-            let user = env::var("USER").unwrap();
-            if _allowed_types.contains(git2::CredentialType::USERNAME) {
-                return git2::Cred::username(&user);
+    /// Lists the names of the matching references along with their targets.
+    pub fn list_reference_targets(&self, ref_name: &str) -> Result<Vec<(String, Oid)>> {
+        let repo = self.repo()?;
+        let mut targets = Vec::new();
+        for reference in repo.references_glob(ref_name)? {
+            let reference = reference?;
+            if let (Some(name), Some(oid)) = (reference.name(), reference.target()) {
+                targets.push((name.to_string(), oid));
             }
-            Cred::ssh_key(
-                &env::var("USER").unwrap(),
-                None,
-                std::path::Path::new(&format!("{}/.ssh/id_ed25519", env::var("HOME").unwrap())),
-                None,
-            )
-        });
-        match remote.connect_auth(Direction::Fetch, Some(callbacks), None) {
-            Ok(connection) => {
-                connection.list()?;
-                Ok(())
+        }
+        Ok(targets)
+    }
+
+    /// Writes a tree with a commit entry for every name, like a superproject holding
+    /// submodules. The tree identifies the whole set by a single oid, and the same set
+    /// always results in the same tree.
+    pub fn write_commit_index(&self, entries: &[(String, Oid)]) -> Result<Oid> {
+        self.ensure_writable()?;
+        let repo = self.repo()?;
+        let mut builder = repo.treebuilder(None)?;
+        for (name, oid) in entries {
+            builder.insert(name, *oid, FileMode::Commit.into())?;
+        }
+        Ok(builder.write()?)
+    }
+
+    /// Computes the oid `write_commit_index` would return for `entries`, without
+    /// writing anything.
+    pub fn commit_index_oid(entries: &[(String, Oid)]) -> Result<Oid> {
+        let mut sorted: Vec<&(String, Oid)> = entries.iter().collect();
+        // Commit entries sort by their plain name, unlike trees which sort as `name/`
+        sorted.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+        let mut content = Vec::new();
+        for (name, oid) in sorted {
+            content.extend_from_slice(b"160000 ");
+            content.extend_from_slice(name.as_bytes());
+            content.push(0);
+            content.extend_from_slice(oid.as_bytes());
+        }
+        Ok(Oid::hash_object(ObjectType::Tree, &content)?)
+    }
+
+    /// Reads the entries of a tree written by `write_commit_index`, or `None` if there
+    /// is no such tree.
+    pub fn read_commit_index(&self, oid: Oid) -> Result<Option<BTreeMap<String, Oid>>> {
+        let repo = self.repo()?;
+        // Unknown oids, e.g. of a tree which was pruned meanwhile, are not an error
+        let Ok(tree) = repo.find_tree(oid) else {
+            return Ok(None);
+        };
+        Ok(Some(
+            tree.iter()
+                .filter_map(|entry| Some((entry.name()?.to_string(), entry.id())))
+                .collect(),
+        ))
+    }
+
+    /// Appends a commit with an empty tree to the chain at `ref_name`, which then
+    /// serves as a log. Appends racing with another writer are retried, so no entry
+    /// is lost.
+    pub fn append_to_log(
+        &self,
+        ref_name: &str,
+        message: &str,
+        author: (&str, &str),
+    ) -> Result<Oid> {
+        self.ensure_writable()?;
+        let tree = self.repo()?.treebuilder(None)?.write()?;
+        self.append_tree_to_log(ref_name, tree, author, |_| Ok(message.to_string()))
+    }
+
+    /// Like `append_to_log`, but the commit holds `tree`, and its message is made
+    /// from the message of the previous commit, if any.
+    pub fn append_tree_to_log(
+        &self,
+        ref_name: &str,
+        tree: Oid,
+        author: (&str, &str),
+        message: impl Fn(Option<&str>) -> Result<String>,
+    ) -> Result<Oid> {
+        self.ensure_writable()?;
+        let repo = self.repo()?;
+        let tree = repo.find_tree(tree)?;
+        let sig = Signature::now(author.0, author.1)?;
+        loop {
+            let head = repo.find_reference(ref_name).ok().and_then(|r| r.target());
+            let parent = head.map(|oid| repo.find_commit(oid)).transpose()?;
+            let parents: Vec<&git2::Commit<'_>> = parent.iter().collect();
+            let message = message(parent.as_ref().and_then(|commit| commit.message()))?;
+            let oid = repo.commit(None, &sig, &sig, &message, &tree, &parents)?;
+            let updated = match head {
+                Some(head) => repo.reference_matching(ref_name, oid, true, head, ""),
+                None => repo.reference(ref_name, oid, false, ""),
+            };
+            match updated {
+                Ok(_) => return Ok(oid),
+                Err(e)
+                    if matches!(
+                        e.code(),
+                        ErrorCode::Modified | ErrorCode::Exists | ErrorCode::Locked
+                    ) =>
+                {
+                    trace!("Log {} changed meanwhile, retrying", ref_name);
+                }
+                Err(e) => return Err(e.into()),
             }
+        }
+    }
+
+    /// Drops the commits of the log at `ref_name` made before `cutoff`, in seconds
+    /// since the Unix epoch. The newer commits are written again on top of each
+    /// other with their authors, times and messages. Returns how many commits were
+    /// dropped, none if the log was appended to meanwhile.
+    pub fn truncate_log(&self, ref_name: &str, cutoff: i64) -> Result<usize> {
+        self.ensure_writable()?;
+        let repo = self.repo()?;
+        let Some(head) = repo.find_reference(ref_name).ok().and_then(|r| r.target()) else {
+            return Ok(0);
+        };
+        let mut kept = Vec::new();
+        let mut dropped = 0;
+        let mut walk = repo.revwalk()?;
+        walk.push(head)?;
+        for oid in walk {
+            let commit = repo.find_commit(oid?)?;
+            if dropped == 0 && commit.time().seconds() >= cutoff {
+                kept.push(commit);
+            } else {
+                dropped += 1;
+            }
+        }
+        if dropped == 0 {
+            return Ok(0);
+        }
+
+        let mut rewritten: Option<Oid> = None;
+        for commit in kept.iter().rev() {
+            let parent = rewritten.map(|oid| repo.find_commit(oid)).transpose()?;
+            let parents: Vec<&git2::Commit<'_>> = parent.iter().collect();
+            rewritten = Some(repo.commit(
+                None,
+                &commit.author(),
+                &commit.committer(),
+                commit.message().unwrap_or_default(),
+                &commit.tree()?,
+                &parents,
+            )?);
+        }
+        let updated = match rewritten {
+            Some(oid) => repo
+                .reference_matching(ref_name, oid, true, head, "")
+                .map(|_| ()),
+            None => repo.find_reference(ref_name).and_then(|mut reference| {
+                if reference.target() == Some(head) {
+                    reference.delete()
+                } else {
+                    Err(git2::Error::from_str("modified"))
+                }
+            }),
+        };
+        match updated {
+            Ok(()) => Ok(dropped),
             Err(e) => {
-                bail!("Connection failed: {}", e);
+                debug!(
+                    "Not truncating log {}, it changed meanwhile: {}",
+                    ref_name, e
+                );
+                Ok(0)
+            }
+        }
+    }
+
+    /// Visits the commits of the log at `ref_name` from the newest to the oldest,
+    /// until `visit` returns false.
+    pub fn walk_log(
+        &self,
+        ref_name: &str,
+        mut visit: impl FnMut(&LogCommit) -> Result<bool>,
+    ) -> Result<()> {
+        let repo = self.repo()?;
+        let Some(head) = repo.find_reference(ref_name).ok().and_then(|r| r.target()) else {
+            return Ok(());
+        };
+        let mut walk = repo.revwalk()?;
+        walk.push(head)?;
+        for oid in walk {
+            let commit = repo.find_commit(oid?)?;
+            let author = commit.author();
+            let entry = LogCommit {
+                id: commit.id(),
+                time: commit.time().seconds(),
+                author: author.name().unwrap_or_default().to_string(),
+                email: author.email().unwrap_or_default().to_string(),
+                message: commit.message().unwrap_or_default().to_string(),
+                tree: commit.tree_id(),
+            };
+            if !visit(&entry)? {
+                break;
             }
         }
+        Ok(())
+    }
+
+    /// Writes the objects reachable from `oids` into another local repository as a
+    /// single pack. Objects reachable from the `known` commits are assumed to exist
+    /// there already and are left out.
+    pub fn transfer_to(&self, destination: &GitRepo, oids: &[Oid], known: &[Oid]) -> Result<()> {
+        destination.ensure_writable()?;
+        let destination = destination.repo()?;
+        let odb = destination.odb()?;
+        let mut writer = odb.packwriter()?;
+        self.write_pack(oids, known, &mut writer)?;
+        writer.commit()?;
+        Ok(())
+    }
+
+    /// Writes the objects reachable from `oids`, but not from the `known` commits, as
+    /// a pack to `out`.
+    pub fn write_pack(&self, oids: &[Oid], known: &[Oid], out: &mut impl Write) -> Result<()> {
+        let repo = self.repo()?;
+        let mut builder = repo.packbuilder()?;
+        let mut walk = repo.revwalk()?;
+        for oid in oids {
+            match repo.find_object(*oid, None)?.kind() {
+                Some(git2::ObjectType::Commit) => walk.push(*oid)?,
+                _ => builder.insert_recursive(*oid, None)?,
+            }
+        }
+        for oid in known {
+            walk.hide(*oid)?;
+        }
+        builder.insert_walk(&mut walk)?;
+
+        let mut result = Ok(());
+        builder.foreach(|chunk| {
+            result = out.write_all(chunk);
+            result.is_ok()
+        })?;
+        result?;
+        Ok(())
+    }
+
+    /// Writes exactly the given objects as a pack to `out`, unlike `write_pack`
+    /// without the objects they reference.
+    pub fn write_objects(&self, oids: &[Oid], out: &mut impl Write) -> Result<()> {
+        let repo = self.repo()?;
+        let mut builder = repo.packbuilder()?;
+        for oid in oids {
+            builder.insert_object(*oid, None)?;
+        }
+        let mut result = Ok(());
+        builder.foreach(|chunk| {
+            result = out.write_all(chunk);
+            result.is_ok()
+        })?;
+        result?;
+        Ok(())
+    }
+
+    /// Returns the objects among `oids` which are not in the repository.
+    pub fn missing_objects(&self, oids: &[Oid]) -> Result<Vec<Oid>> {
+        let repo = self.repo()?;
+        let odb = repo.odb()?;
+        Ok(oids
+            .iter()
+            .copied()
+            .filter(|oid| !odb.exists(*oid))
+            .collect())
+    }
+
+    /// Returns the objects the trees among `oids` list, other objects are skipped.
+    pub fn tree_entries(&self, oids: &[Oid]) -> Result<Vec<Oid>> {
+        let repo = self.repo()?;
+        let mut entries = Vec::new();
+        for oid in oids {
+            if let Ok(tree) = repo.find_tree(*oid) {
+                entries.extend(tree.iter().map(|entry| entry.id()));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Adds the objects of a pack written by `write_pack` to the repository.
+    pub fn read_pack(&self, pack: &mut impl Read) -> Result<()> {
+        self.ensure_writable()?;
+        let repo = self.repo()?;
+        let odb = repo.odb()?;
+        let mut writer = odb.packwriter()?;
+        std::io::copy(pack, &mut writer)?;
+        writer.commit()?;
+        Ok(())
     }
 
-    #[instrument(skip(self))]
-    pub fn fetch(&self, url: &str, reference: &str) -> Result<Option<()>> {
-        let repo = self.repo.read().unwrap();
-        let mut remote = match repo.find_remote("peer") {
-            Ok(remote) => remote,
-            _ => repo.remote_with_fetch("peer", url, "")?,
+    /// Removes the objects which are no longer reachable from any reference and older
+    /// than `grace_period`. libgit2 cannot prune objects, so this runs `git gc`.
+    pub fn prune_unreachable(&self, grace_period: Duration) -> Result<()> {
+        self.ensure_writable()?;
+        let prune = match grace_period.as_secs() {
+            0 => "--prune=now".to_string(),
+            seconds => format!("--prune={seconds}.seconds.ago"),
         };
-        let refspec = format!("{}:{}", reference, reference);
+        let output = std::process::Command::new("git")
+            .arg("-C")
+            .arg(&self.pool.path)
+            .args(["gc", &prune, "--quiet"])
+            .output()
+            .context("Could not run git gc")?;
+        if !output.status.success() {
+            bail!(
+                "git gc failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+
+    /// Lists the names of the references of a remote repository.
+    pub fn list_remote_references(&self, url: &str) -> Result<Vec<String>> {
+        let repo = self.repo()?;
+        let mut remote = repo.remote_anonymous(url)?;
+        let connection = remote.connect_auth(Direction::Fetch, Some(remote_callbacks()), None)?;
+        Ok(connection
+            .list()?
+            .iter()
+            .map(|head| head.name().to_string())
+            .collect())
+    }
+
+    /// Pushes the given refspecs to a remote repository, failing if the remote rejects
+    /// any of the reference updates.
+    pub fn push(&self, url: &str, refspecs: &[String]) -> Result<()> {
+        let repo = self.repo()?;
+        let mut remote = repo.remote_anonymous(url)?;
+        let mut rejected = Vec::new();
+        {
+            let mut callbacks = remote_callbacks();
+            callbacks.push_update_reference(|reference, status| {
+                if let Some(message) = status {
+                    rejected.push(format!("{reference}: {message}"));
+                }
+                Ok(())
+            });
+            let mut push_options = PushOptions::new();
+            push_options.remote_callbacks(callbacks);
+            trace!("Pushing {} references", refspecs.len());
+            remote.push(refspecs, Some(&mut push_options))?;
+        }
+        if !rejected.is_empty() {
+            bail!("Remote rejected references: {}", rejected.join(", "));
+        }
+        Ok(())
+    }
+
+    /// Fetches the remote references matching `source` into the local references
+    /// `destination` and returns the number of received objects.
+    pub fn fetch_into(&self, url: &str, source: &str, destination: &str) -> Result<usize> {
+        self.fetch_refspecs(url, &[format!("{}:{}", source, destination)])
+    }
+
+    /// Fetches the remote references matching any of `refspecs` at once and returns
+    /// the number of received objects.
+    pub fn fetch_refspecs(&self, url: &str, refspecs: &[String]) -> Result<usize> {
+        self.ensure_writable()?;
+        if refspecs.is_empty() {
+            return Ok(0);
+        }
+        let repo = self.repo()?;
+        let mut remote = repo.remote_anonymous(url)?;
 
         trace!("Fetching from remote");
         let mut fetch_options = FetchOptions::new();
-        let mut callbacks = RemoteCallbacks::new();
+        let mut callbacks = remote_callbacks();
         callbacks.update_tips(|r, _, _| {
             trace!("Added reference {r}");
             true
         });
-        callbacks.credentials(|_url, _user_from_url, _allowed_types| {
-            let user = env::var("USER").unwrap();
-            if _allowed_types.contains(git2::CredentialType::USERNAME) {
-                return git2::Cred::username(&user);
-            }
-            Cred::ssh_key(
-                &env::var("USER").unwrap(),
-                None,
-                std::path::Path::new(&format!("{}/.ssh/id_ed25519", env::var("HOME").unwrap())),
-                None,
-            )
-        });
         fetch_options.remote_callbacks(callbacks);
         fetch_options.download_tags(git2::AutotagOption::None);
         fetch_options.update_fetchhead(false);
-        remote.fetch(&vec![refspec], Some(&mut fetch_options), None)?;
+        remote.fetch(refspecs, Some(&mut fetch_options), None)?;
 
-        if remote.stats().received_objects() == 0 {
-            trace!("Did not receive anything");
-            return Ok(None);
-        }
-        trace!("Received {} objects", remote.stats().received_objects());
-
-        Ok(Some(()))
+        Ok(remote.stats().received_objects())
     }
 }
 
-impl Clone for GitRepo {
-    fn clone(&self) -> Self {
-        Self {
-            repo: self.repo.clone(),
+fn remote_callbacks<'a>() -> RemoteCallbacks<'a> {
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(|_url, _user_from_url, _allowed_types| {
+        let user = env::var("USER").unwrap();
+        if _allowed_types.contains(git2::CredentialType::USERNAME) {
+            return git2::Cred::username(&user);
         }
+        Cred::ssh_key(
+            &env::var("USER").unwrap(),
+            None,
+            std::path::Path::new(&format!("{}/.ssh/id_ed25519", env::var("HOME").unwrap())),
+            None,
+        )
+    });
+    callbacks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_pack_refs() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo_path = temp_dir.path().join("repo");
+        let repo = GitRepo::new(&repo_path)?;
+
+        let oid = repo.add_file_content(b"narinfo")?;
+        repo.add_ref("refs/a/narinfo", oid)?;
+        repo.add_ref("refs/b/narinfo", oid)?;
+        assert!(repo_path.join(".git/refs/a/narinfo").exists());
+
+        repo.pack_refs()?;
+
+        assert!(!repo_path.join(".git/refs/a/narinfo").exists());
+        assert_eq!(repo.count_references("refs/*/narinfo")?, 2);
+        assert_eq!(repo.get_oid_from_reference("refs/b/narinfo"), Some(oid));
+        Ok(())
+    }
+
+    #[test]
+    fn test_pack_loose_objects() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo_path = temp_dir.path().join("repo");
+        let repo = GitRepo::new(&repo_path)?;
+
+        let first = repo.add_file_content(b"StorePath: /nix/store/a-hello")?;
+        let second = repo.add_file_content(b"StorePath: /nix/store/b-hello")?;
+        let hex = first.to_string();
+        let loose = repo_path
+            .join(".git/objects")
+            .join(&hex[..2])
+            .join(&hex[2..]);
+        assert!(loose.exists());
+
+        assert_eq!(repo.pack_loose_objects(&[first, second, first])?, 2);
+        assert!(!loose.exists());
+        assert_eq!(repo.get_blob(first)?, b"StorePath: /nix/store/a-hello");
+        assert_eq!(repo.get_blob(second)?, b"StorePath: /nix/store/b-hello");
+        assert_eq!(repo.pack_loose_objects(&[first])?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncate_log() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = GitRepo::new(&temp_dir.path().join("repo"))?;
+        assert_eq!(repo.truncate_log("refs/log", 0)?, 0);
+        repo.append_to_log("refs/log", "first", ("a", "a@host"))?;
+        repo.append_to_log("refs/log", "second", ("a", "a@host"))?;
+        let messages = |repo: &GitRepo| -> Result<Vec<String>> {
+            let mut messages = Vec::new();
+            repo.walk_log("refs/log", |commit| {
+                messages.push(commit.message.clone());
+                Ok(true)
+            })?;
+            Ok(messages)
+        };
+
+        assert_eq!(repo.truncate_log("refs/log", 0)?, 0);
+        assert_eq!(messages(&repo)?, ["second", "first"]);
+        assert_eq!(repo.truncate_log("refs/log", i64::MAX)?, 2);
+        assert!(!repo.reference_exists("refs/log")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_only() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo_path = temp_dir.path().join("repo");
+        assert!(GitRepo::open_read_only(&repo_path).is_err());
+
+        let oid = GitRepo::new(&repo_path)?.add_file_content(b"narinfo")?;
+        let repo = GitRepo::open_read_only(&repo_path)?;
+        assert_eq!(repo.get_blob(oid)?, b"narinfo");
+        assert!(repo.add_file_content(b"other").is_err());
+        assert!(repo.add_ref("refs/a/narinfo", oid).is_err());
+        assert!(!repo.reference_exists("refs/a/narinfo")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_commit_index_oid() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = GitRepo::new(&temp_dir.path().join("repo"))?;
+        let entries = vec![
+            ("b".repeat(32), Oid::from_bytes(&[2; 20])?),
+            ("a".repeat(32), Oid::from_bytes(&[1; 20])?),
+        ];
+        let oid = GitRepo::commit_index_oid(&entries)?;
+        assert!(!repo.contains(oid)?);
+        assert_eq!(repo.write_commit_index(&entries)?, oid);
+        assert_eq!(
+            GitRepo::commit_index_oid(&[])?,
+            repo.write_commit_index(&[])?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_dir_is_canonical() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = GitRepo::new(&temp_dir.path().join("repo"))?;
+
+        let first = temp_dir.path().join("first");
+        fs::create_dir_all(first.join("lib"))?;
+        fs::write(first.join("b"), "b")?;
+        fs::write(first.join("a"), "a")?;
+        fs::write(first.join("lib/run"), "#!/bin/sh")?;
+        fs::set_permissions(first.join("lib/run"), fs::Permissions::from_mode(0o755))?;
+        std::os::unix::fs::symlink("lib/run", first.join("run"))?;
+
+        let second = temp_dir.path().join("second");
+        fs::create_dir_all(second.join("lib"))?;
+        std::os::unix::fs::symlink("lib/run", second.join("run"))?;
+        fs::write(second.join("lib/run"), "#!/bin/sh")?;
+        // Only the executable bit of the owner is significant
+        fs::set_permissions(second.join("lib/run"), fs::Permissions::from_mode(0o700))?;
+        fs::write(second.join("a"), "a")?;
+        fs::set_permissions(second.join("a"), fs::Permissions::from_mode(0o664))?;
+        fs::write(second.join("b"), "b")?;
+        let fifo = CString::new(second.join("fifo").as_os_str().as_bytes())?;
+        // SAFETY: the path is a valid C string
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+
+        assert_eq!(repo.add_dir(&first)?, repo.add_dir(&second)?);
+        Ok(())
     }
 }
 
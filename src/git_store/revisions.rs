@@ -0,0 +1,135 @@
+use super::history::format_time;
+use git2::Oid;
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// Narinfos which were replaced by a newer one are kept below this namespace as
+/// `<hash>/<seconds>-<reason>-<oid>` until the retention period of the garbage
+/// collection passed, so that the revisions of an entry can be reviewed before
+/// their blobs are pruned. The names don't end in a kind, see
+/// `snapshot::package_ref`.
+pub const REVISIONS_NAMESPACE: &str = "refs/revisions";
+
+/// Why a narinfo was replaced.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reason {
+    /// Signed again with our own key, e.g. after being fetched from a peer
+    Resigned,
+    /// Replaced along with the package by a valid copy
+    Repaired,
+}
+
+impl Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reason::Resigned => f.write_str("resign"),
+            Reason::Repaired => f.write_str("repair"),
+        }
+    }
+}
+
+impl FromStr for Reason {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "resign" => Ok(Reason::Resigned),
+            "repair" => Ok(Reason::Repaired),
+            _ => Err(()),
+        }
+    }
+}
+
+pub fn revision_ref(hash: &str, time: i64, reason: Reason, narinfo: Oid) -> String {
+    format!("{REVISIONS_NAMESPACE}/{hash}/{time}-{reason}-{narinfo}")
+}
+
+/// Splits a revision reference into the hash of the package, the time the narinfo
+/// was replaced and why.
+pub fn parse_revision_ref(reference: &str) -> Option<(&str, i64, Reason)> {
+    let (hash, name) = reference
+        .strip_prefix(REVISIONS_NAMESPACE)?
+        .strip_prefix('/')?
+        .split_once('/')?;
+    let mut parts = name.split('-');
+    let time = parts.next()?.parse().ok()?;
+    let reason = parts.next()?.parse().ok()?;
+    Some((hash, time, reason))
+}
+
+/// A narinfo an entry was served with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Revision {
+    /// When and why the narinfo was replaced, `None` for the one served now
+    pub superseded: Option<(i64, Reason)>,
+    pub narinfo: Oid,
+    /// Names of the keys which signed the narinfo
+    pub keys: Vec<String>,
+}
+
+impl Display for Revision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let narinfo = self.narinfo.to_string();
+        match self.superseded {
+            Some((time, reason)) => write!(f, "{} {}", format_time(time), reason)?,
+            None => write!(f, "{:<20} current", "-")?,
+        }
+        write!(f, " {} ", &narinfo[..10])?;
+        if self.keys.is_empty() {
+            f.write_str("unsigned")
+        } else {
+            f.write_str(&self.keys.join(","))
+        }
+    }
+}
+
+/// The names of the keys of the space separated `name:base64` signatures of a
+/// narinfo.
+pub fn key_names(signatures: &str) -> Vec<String> {
+    signatures
+        .split_whitespace()
+        .filter_map(|signature| signature.split_once(':'))
+        .map(|(name, _)| name.to_string())
+        .collect()
+}
+
+/// Whether a narinfo replaced at `time` is older than the retention period, in
+/// seconds since the Unix epoch.
+pub fn is_expired(time: i64, now: i64, retention: u64) -> bool {
+    now.saturating_sub(time) > i64::try_from(retention).unwrap_or(i64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_revision_refs() {
+        let hash = "a".repeat(32);
+        let oid = Oid::from_bytes(&[1; 20]).unwrap();
+        let reference = revision_ref(&hash, 1700000000, Reason::Resigned, oid);
+        assert_eq!(
+            reference,
+            format!("refs/revisions/{hash}/1700000000-resign-{oid}")
+        );
+        assert_eq!(
+            parse_revision_ref(&reference),
+            Some((hash.as_str(), 1700000000, Reason::Resigned))
+        );
+        assert_eq!(parse_revision_ref(&format!("refs/{hash}/narinfo")), None);
+        assert_eq!(
+            parse_revision_ref(&format!("refs/revisions/{hash}/1-rename-{oid}")),
+            None
+        );
+
+        assert_eq!(
+            key_names("cache.nixos.org-1:abc= gachix-1:def="),
+            ["cache.nixos.org-1", "gachix-1"]
+        );
+        assert!(key_names("").is_empty());
+
+        assert!(!is_expired(1000, 1500, 600));
+        assert!(is_expired(1000, 1700, 600));
+        assert!(!is_expired(0, 1700, u64::MAX));
+    }
+}
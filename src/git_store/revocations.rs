@@ -0,0 +1,110 @@
+use std::fmt::Display;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::history::format_time;
+
+/// Revoked packages are recorded as references `<hash>.revocation` below this
+/// namespace, pointing at a JSON blob of their `Revocation`. Removing a package
+/// only leaves a tombstone, while a revocation is what git peers following the
+/// deletions of this instance act on, see `Store::follow_deletions`. Revocations
+/// are kept, so that a revoked package which is added again is not removed again.
+/// For the name, see `snapshot::package_ref`.
+pub const REVOCATIONS_NAMESPACE: &str = "refs/revocations";
+
+pub fn revocation_ref(hash: &str) -> String {
+    format!("{REVOCATIONS_NAMESPACE}/{hash}.revocation")
+}
+
+/// Returns the hash of the package a revocation reference names.
+pub fn parse_revocation_ref(reference: &str) -> Option<&str> {
+    reference
+        .strip_prefix(REVOCATIONS_NAMESPACE)?
+        .strip_prefix('/')?
+        .strip_suffix(".revocation")
+        .filter(|hash| !hash.contains('/'))
+}
+
+/// Why and by whom a package was revoked.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Revocation {
+    pub hash: String,
+    pub store_path: Option<String>,
+    /// The key the NAR of the package was served under, which is no longer served
+    pub nar_key: Option<String>,
+    pub reason: String,
+    /// The user and host which revoked the package, or the URL of the git peer it
+    /// was followed from
+    pub revoked_by: String,
+    /// Seconds since the Unix epoch, on the clock of this instance
+    pub revoked_at: i64,
+}
+
+impl Revocation {
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn from_json(json: &[u8]) -> Result<Self> {
+        Ok(serde_json::from_slice(json)?)
+    }
+}
+
+impl Display for Revocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} revoked by {}: {}",
+            format_time(self.revoked_at),
+            self.hash,
+            self.store_path.as_deref().unwrap_or("-"),
+            self.revoked_by,
+            self.reason
+        )
+    }
+}
+
+/// A package removed because a git peer revoked it or one of its dependencies.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerDeletion {
+    pub hash: String,
+    pub store_path: Option<String>,
+    pub peer: String,
+    /// The hash of the revoked package, which is `hash` unless a dependency was
+    /// revoked
+    pub revoked: String,
+}
+
+impl Display for PeerDeletion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} ",
+            self.hash,
+            self.store_path.as_deref().unwrap_or("-")
+        )?;
+        if self.revoked != self.hash {
+            write!(f, "depends on {}, which was ", self.revoked)?;
+        }
+        write!(f, "revoked by {}", self.peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_revocation_refs() {
+        let hash = "a".repeat(32);
+        let reference = revocation_ref(&hash);
+        assert_eq!(reference, format!("refs/revocations/{hash}.revocation"));
+        assert_eq!(parse_revocation_ref(&reference), Some(hash.as_str()));
+        assert_eq!(parse_revocation_ref(&format!("refs/{hash}/narinfo")), None);
+        assert_eq!(
+            parse_revocation_ref("refs/revocations/a/b.revocation"),
+            None
+        );
+    }
+}
@@ -0,0 +1,119 @@
+use crate::git_store::GitRepo;
+use anyhow::Result;
+use git2::Oid;
+use tracing::warn;
+
+/// The references written while adding or repairing a package. If that fails
+/// partway through, `roll_back` restores them, so that retries and `fsck` do not
+/// find a half-written entry. The objects written for the package stay behind unreferenced until the
+/// next garbage collection prunes them.
+pub struct StagedRefs<'a> {
+    repo: &'a GitRepo,
+    package: String,
+    // Every written reference with its previous target
+    written: Vec<(String, Option<Oid>)>,
+}
+
+impl<'a> StagedRefs<'a> {
+    pub fn new(repo: &'a GitRepo, package: &str) -> Self {
+        Self {
+            repo,
+            package: package.to_string(),
+            written: Vec::new(),
+        }
+    }
+
+    /// Creates a reference, failing if it exists already. A concurrent add of the
+    /// same package writes the same objects, so a reference another writer created
+    /// with the same target is left as it is and kept on roll back.
+    pub fn add(&mut self, name: &str, oid: Oid) -> Result<()> {
+        if let Err(e) = self.repo.add_ref(name, oid) {
+            let written_here = self.written.iter().any(|(written, _)| written == name);
+            if !written_here && self.repo.get_oid_from_reference(name) == Some(oid) {
+                return Ok(());
+            }
+            return Err(e);
+        }
+        self.written.push((name.to_string(), None));
+        Ok(())
+    }
+
+    /// Creates or overwrites a reference.
+    pub fn update(&mut self, name: &str, oid: Oid) -> Result<()> {
+        let previous = self.repo.get_oid_from_reference(name);
+        self.repo.update_ref(name, oid)?;
+        self.written.push((name.to_string(), previous));
+        Ok(())
+    }
+
+    /// Deletes a reference if it exists.
+    pub fn delete(&mut self, name: &str) -> Result<()> {
+        let Some(previous) = self.repo.get_oid_from_reference(name) else {
+            return Ok(());
+        };
+        self.repo.delete_ref(name)?;
+        self.written.push((name.to_string(), Some(previous)));
+        Ok(())
+    }
+
+    /// Restores the written references in reverse order and logs what was rolled
+    /// back.
+    pub fn roll_back(self) {
+        for (name, previous) in self.written.iter().rev() {
+            let result = match previous {
+                Some(oid) => self.repo.update_ref(name, *oid),
+                None => self.repo.delete_ref(name),
+            };
+            match result {
+                Ok(()) => warn!("Rolled back {} of {}", name, self.package),
+                Err(e) => warn!("Could not roll back {} of {}: {}", name, self.package, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_roll_back() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = GitRepo::new(&temp_dir.path().join("repo"))?;
+        let old = repo.add_file_content(b"old")?;
+        let new = repo.add_file_content(b"new")?;
+        repo.add_ref("refs/shared", old)?;
+
+        let mut staged = StagedRefs::new(&repo, "pkg");
+        staged.add("refs/a/result", new)?;
+        staged.update("refs/shared", new)?;
+        assert!(staged.add("refs/a/result", new).is_err());
+        staged.delete("refs/shared")?;
+        staged.delete("refs/missing")?;
+        assert!(!repo.reference_exists("refs/shared")?);
+        staged.roll_back();
+
+        assert!(!repo.reference_exists("refs/a/result")?);
+        assert_eq!(repo.get_oid_from_reference("refs/shared"), Some(old));
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_add() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = GitRepo::new(&temp_dir.path().join("repo"))?;
+        let oid = repo.add_file_content(b"content")?;
+        // Written by another add of the same package meanwhile
+        repo.add_ref("refs/a/result", oid)?;
+
+        let mut staged = StagedRefs::new(&repo, "pkg");
+        staged.add("refs/a/result", oid)?;
+        // Only the target of the other add is tolerated
+        let other = repo.add_file_content(b"other")?;
+        assert!(staged.add("refs/a/result", other).is_err());
+        staged.roll_back();
+        assert_eq!(repo.get_oid_from_reference("refs/a/result"), Some(oid));
+        Ok(())
+    }
+}
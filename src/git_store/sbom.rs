@@ -0,0 +1,216 @@
+use anyhow::{Result, anyhow};
+use clap::ValueEnum;
+use serde_json::{Value, json};
+
+use crate::nix_interface::nar_info::NarInfo;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum SbomFormat {
+    #[default]
+    Cyclonedx,
+    Spdx,
+}
+
+/// Renders the closure of `root` as a software bill of materials. `closure` must
+/// contain the narinfo of every package in the closure, including `root`.
+pub fn render(format: SbomFormat, root: &NarInfo, closure: &[NarInfo]) -> Result<Value> {
+    match format {
+        SbomFormat::Cyclonedx => cyclonedx(root, closure),
+        SbomFormat::Spdx => spdx(root, closure),
+    }
+}
+
+/// Converts a `sha256:<nix-base32>` hash into its hex representation.
+fn hex_sha256(nar_hash: &str) -> Result<String> {
+    let encoded = nar_hash
+        .strip_prefix("sha256:")
+        .ok_or_else(|| anyhow!("Unsupported NAR hash {}", nar_hash))?;
+    let bytes = nix_base32::from_nix_base32(encoded)
+        .ok_or_else(|| anyhow!("Invalid NAR hash {}", nar_hash))?;
+    Ok(hex::encode(bytes))
+}
+
+/// Hashes of the references of a package, without the package itself.
+fn dependency_hashes(narinfo: &NarInfo) -> impl Iterator<Item = &str> {
+    narinfo
+        .references
+        .iter()
+        .map(|r| r.get_base_32_hash())
+        .filter(|hash| *hash != narinfo.store_path.get_base_32_hash())
+}
+
+fn cyclonedx_component(narinfo: &NarInfo) -> Result<Value> {
+    let (name, version) = narinfo.store_path.get_pname_and_version();
+    let mut properties = vec![json!({
+        "name": "nix:store_path",
+        "value": narinfo.store_path.get_path(),
+    })];
+    if let Some(deriver) = &narinfo.deriver {
+        properties.push(json!({ "name": "nix:deriver", "value": deriver.get_path() }));
+    }
+    Ok(json!({
+        "type": "library",
+        "bom-ref": narinfo.store_path.get_path(),
+        "name": name,
+        "version": version.unwrap_or(""),
+        "hashes": [{ "alg": "SHA-256", "content": hex_sha256(&narinfo.nar_hash)? }],
+        "properties": properties,
+    }))
+}
+
+fn cyclonedx(root: &NarInfo, closure: &[NarInfo]) -> Result<Value> {
+    let components = closure
+        .iter()
+        .filter(|n| n.store_path != root.store_path)
+        .map(cyclonedx_component)
+        .collect::<Result<Vec<_>>>()?;
+    let dependencies: Vec<Value> = closure
+        .iter()
+        .map(|n| {
+            let depends_on: Vec<&str> = dependency_hashes(n)
+                .filter_map(|hash| {
+                    closure
+                        .iter()
+                        .find(|c| c.store_path.get_base_32_hash() == hash)
+                        .map(|c| c.store_path.get_path())
+                })
+                .collect();
+            json!({ "ref": n.store_path.get_path(), "dependsOn": depends_on })
+        })
+        .collect();
+    Ok(json!({
+        "bomFormat": "CycloneDX",
+        "specVersion": "1.5",
+        "version": 1,
+        "metadata": {
+            "tools": [{ "name": "gachix", "version": env!("CARGO_PKG_VERSION") }],
+            "component": cyclonedx_component(root)?,
+        },
+        "components": components,
+        "dependencies": dependencies,
+    }))
+}
+
+fn spdx_id(narinfo: &NarInfo) -> String {
+    format!("SPDXRef-{}", narinfo.store_path.get_base_32_hash())
+}
+
+fn spdx(root: &NarInfo, closure: &[NarInfo]) -> Result<Value> {
+    let mut packages = Vec::new();
+    let mut relationships = vec![json!({
+        "spdxElementId": "SPDXRef-DOCUMENT",
+        "relationshipType": "DESCRIBES",
+        "relatedSpdxElement": spdx_id(root),
+    })];
+    for narinfo in closure {
+        let (name, version) = narinfo.store_path.get_pname_and_version();
+        let mut package = json!({
+            "SPDXID": spdx_id(narinfo),
+            "name": name,
+            "downloadLocation": "NOASSERTION",
+            "checksums": [{
+                "algorithm": "SHA256",
+                "checksumValue": hex_sha256(&narinfo.nar_hash)?,
+            }],
+            "comment": format!("Nix store path {}", narinfo.store_path),
+        });
+        if let Some(version) = version {
+            package["versionInfo"] = json!(version);
+        }
+        if let Some(deriver) = &narinfo.deriver {
+            package["sourceInfo"] = json!(format!("built from {deriver}"));
+        }
+        packages.push(package);
+        for hash in dependency_hashes(narinfo) {
+            relationships.push(json!({
+                "spdxElementId": spdx_id(narinfo),
+                "relationshipType": "DEPENDS_ON",
+                "relatedSpdxElement": format!("SPDXRef-{hash}"),
+            }));
+        }
+    }
+    Ok(json!({
+        "spdxVersion": "SPDX-2.3",
+        "dataLicense": "CC0-1.0",
+        "SPDXID": "SPDXRef-DOCUMENT",
+        "name": root.store_path.get_name(),
+        "documentNamespace": format!("https://gachix/spdx/{}", root.store_path.get_base_32_hash()),
+        // Only content-addressed data goes into the document, so it is kept reproducible
+        "creationInfo": {
+            "created": "1970-01-01T00:00:00Z",
+            "creators": [format!("Tool: gachix-{}", env!("CARGO_PKG_VERSION"))],
+        },
+        "packages": packages,
+        "relationships": relationships,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nix_interface::path::NixPath;
+
+    fn narinfo(hash: &str, name: &str, references: &[&str]) -> Result<NarInfo> {
+        let nar_hash = format!("sha256:{}", "0".repeat(52));
+        let references = references
+            .iter()
+            .map(NixPath::new)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(NarInfo::new(
+            NixPath::new(&format!("/nix/store/{hash}-{name}"))?,
+            "key".to_string(),
+            nar_hash.clone(),
+            1,
+            None,
+            nar_hash,
+            1,
+            Some(NixPath::new(&format!("/nix/store/{hash}-{name}.drv"))?),
+            references,
+            None,
+        ))
+    }
+
+    fn closure() -> Result<Vec<NarInfo>> {
+        let root = "r".repeat(32);
+        let dep = "d".repeat(32);
+        Ok(vec![
+            narinfo(&root, "hello-2.12.1", &[&format!("{dep}-glibc-2.40")])?,
+            narinfo(&dep, "glibc-2.40", &[&format!("{dep}-glibc-2.40")])?,
+        ])
+    }
+
+    #[test]
+    fn test_cyclonedx() -> Result<()> {
+        let closure = closure()?;
+        let bom = render(SbomFormat::Cyclonedx, &closure[0], &closure)?;
+
+        assert_eq!(bom["metadata"]["component"]["name"], "hello");
+        assert_eq!(bom["metadata"]["component"]["version"], "2.12.1");
+        assert_eq!(bom["components"].as_array().unwrap().len(), 1);
+        assert_eq!(bom["components"][0]["name"], "glibc");
+        assert_eq!(bom["components"][0]["hashes"][0]["content"], "0".repeat(64));
+        assert_eq!(
+            bom["dependencies"][0]["dependsOn"],
+            json!([closure[1].store_path.get_path()])
+        );
+        assert_eq!(bom["dependencies"][1]["dependsOn"], json!([]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_spdx() -> Result<()> {
+        let closure = closure()?;
+        let bom = render(SbomFormat::Spdx, &closure[0], &closure)?;
+
+        assert_eq!(bom["packages"].as_array().unwrap().len(), 2);
+        assert_eq!(bom["packages"][1]["versionInfo"], "2.40");
+        let relationships = bom["relationships"].as_array().unwrap();
+        assert_eq!(relationships.len(), 2);
+        assert_eq!(relationships[1]["relationshipType"], "DEPENDS_ON");
+        assert_eq!(
+            relationships[1]["relatedSpdxElement"],
+            format!("SPDXRef-{}", "d".repeat(32))
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,213 @@
+use anyhow::{Context, Result, bail};
+use regex::Regex;
+use std::fmt::Display;
+use std::io::{ErrorKind, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+
+use crate::git_store::filter::glob_to_regex;
+use crate::settings::{self, ScanAction};
+
+/// An external command the NAR of a package is written to, see `store.scanners`.
+#[derive(Debug, Clone)]
+struct Scanner {
+    command: Vec<String>,
+    packages: Vec<Regex>,
+    on_failure: ScanAction,
+}
+
+impl Scanner {
+    fn name(&self) -> &str {
+        &self.command[0]
+    }
+
+    fn applies_to(&self, name: &str) -> bool {
+        self.packages.is_empty() || self.packages.iter().any(|r| r.is_match(name))
+    }
+
+    /// Runs the command with the NAR on its standard input. Returns what it
+    /// reported if it exited with a failure.
+    fn run(
+        &self,
+        store_path: &str,
+        write_nar: &(dyn Fn(&mut dyn Write) -> Result<()> + Sync),
+    ) -> Result<Option<String>> {
+        let mut child = Command::new(&self.command[0])
+            .args(&self.command[1..])
+            .env("GACHIX_STORE_PATH", store_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Could not run scanner {}", self.name()))?;
+        let mut stdin = child
+            .stdin
+            .take()
+            .context("Scanner has no standard input")?;
+        // The NAR is written from another thread, so that a scanner reporting while
+        // it reads can't block on a full pipe
+        let (written, output) = thread::scope(|scope| {
+            let writer = scope.spawn(move || write_nar(&mut stdin));
+            let output = child.wait_with_output();
+            (writer.join(), output)
+        });
+        let output = output?;
+        match written {
+            Ok(Ok(())) => {}
+            // The scanner may decide before reading the whole NAR
+            Ok(Err(e))
+                if e.downcast_ref::<std::io::Error>()
+                    .is_some_and(|e| e.kind() == ErrorKind::BrokenPipe) => {}
+            Ok(Err(e)) => return Err(e.context(format!("Could not scan {store_path}"))),
+            Err(_) => bail!("Writing the NAR of {} to a scanner panicked", store_path),
+        }
+        if output.status.success() {
+            return Ok(None);
+        }
+        let report = [&output.stdout, &output.stderr]
+            .into_iter()
+            .find_map(|out| {
+                String::from_utf8_lossy(out)
+                    .lines()
+                    .map(str::trim)
+                    .find(|line| !line.is_empty())
+                    .map(str::to_string)
+            })
+            .unwrap_or_else(|| output.status.to_string());
+        Ok(Some(report))
+    }
+}
+
+/// What a scanner reported about a package it failed on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub scanner: String,
+    pub action: ScanAction,
+    pub report: String,
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.scanner, self.report)
+    }
+}
+
+/// The scanners of a store.
+#[derive(Debug, Clone, Default)]
+pub struct Scanners(Vec<Scanner>);
+
+impl Scanners {
+    pub fn new(scanners: &[settings::Scanner]) -> Result<Self> {
+        scanners
+            .iter()
+            .map(|scanner| {
+                if scanner.command.is_empty() {
+                    bail!("A scanner has no command");
+                }
+                Ok(Scanner {
+                    command: scanner.command.clone(),
+                    packages: scanner
+                        .packages
+                        .iter()
+                        .map(|g| glob_to_regex(g))
+                        .collect::<Result<_>>()?,
+                    on_failure: scanner.on_failure,
+                })
+            })
+            .collect::<Result<_>>()
+            .map(Self)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Runs every scanner which applies to a package over its NAR, as written by
+    /// `write_nar`, and returns the findings of the ones which failed. Stops at the
+    /// first scanner rejecting the package.
+    pub fn scan(
+        &self,
+        store_path: &str,
+        name: &str,
+        write_nar: &(dyn Fn(&mut dyn Write) -> Result<()> + Sync),
+    ) -> Result<Vec<Finding>> {
+        let mut findings = Vec::new();
+        for scanner in self.0.iter().filter(|s| s.applies_to(name)) {
+            let Some(report) = scanner.run(store_path, write_nar)? else {
+                continue;
+            };
+            findings.push(Finding {
+                scanner: scanner.name().to_string(),
+                action: scanner.on_failure,
+                report,
+            });
+            if scanner.on_failure == ScanAction::Reject {
+                break;
+            }
+        }
+        Ok(findings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scanner(script: &str, packages: &[&str], on_failure: ScanAction) -> settings::Scanner {
+        settings::Scanner {
+            command: vec!["sh".to_string(), "-c".to_string(), script.to_string()],
+            packages: packages.iter().map(|p| p.to_string()).collect(),
+            on_failure,
+        }
+    }
+
+    #[test]
+    fn test_scan() -> Result<()> {
+        let nar = |writer: &mut dyn Write| -> Result<()> {
+            writer.write_all(b"nix-archive-1 AKIA secret")?;
+            Ok(())
+        };
+        let scanners = Scanners::new(&[
+            scanner("cat > /dev/null", &[], ScanAction::Reject),
+            scanner(
+                "grep -q AKIA && echo \"key in $GACHIX_STORE_PATH\" && exit 1; exit 0",
+                &["*-source"],
+                ScanAction::Flag,
+            ),
+            // Fails without reading its input
+            scanner("exit 3", &["*-source"], ScanAction::Reject),
+            scanner("exit 4", &["*-source"], ScanAction::Reject),
+        ])?;
+
+        assert!(
+            scanners
+                .scan("/nix/store/x-hello", "hello", &nar)?
+                .is_empty()
+        );
+        let findings = scanners.scan("/nix/store/x-hello-source", "hello-source", &nar)?;
+        assert_eq!(findings.len(), 2);
+        assert_eq!(findings[0].action, ScanAction::Flag);
+        assert_eq!(
+            findings[0].to_string(),
+            "sh: key in /nix/store/x-hello-source"
+        );
+        assert_eq!(findings[1].action, ScanAction::Reject);
+        assert!(findings[1].report.contains('3'), "{}", findings[1].report);
+
+        assert!(
+            Scanners::new(&[settings::Scanner {
+                command: vec![],
+                packages: vec![],
+                on_failure: ScanAction::Flag,
+            }])
+            .is_err()
+        );
+        let missing = Scanners::new(&[settings::Scanner {
+            command: vec!["/nonexistent/scanner".to_string()],
+            packages: vec![],
+            on_failure: ScanAction::Flag,
+        }])?;
+        assert!(missing.scan("/nix/store/x-hello", "hello", &nar).is_err());
+        Ok(())
+    }
+}
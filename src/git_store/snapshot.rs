@@ -0,0 +1,147 @@
+use super::history::HISTORY_REF;
+use git2::Oid;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+
+/// Holds the objects of a snapshot as a single pack.
+pub const PACK_FILE: &str = "objects.pack";
+/// Describes a snapshot, written last so that only complete snapshots have one.
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+const VERSION: u32 = 1;
+
+/// The references of a store at one point in time, stored next to a pack with all
+/// objects they reach. Differential bundles only hold the objects which were added
+/// since the `base` state of the history.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotManifest {
+    pub version: u32,
+    /// Seconds since the epoch
+    pub created: u64,
+    /// Changes if settings change which affect what the store serves, e.g. the
+    /// signing key, see `Store::config_fingerprint`
+    pub config_fingerprint: String,
+    /// The history commit a differential bundle was made against, `None` for a
+    /// full snapshot
+    pub base: Option<String>,
+    /// The head of the history when the snapshot was taken, the `since` of the next
+    /// differential bundle
+    pub history: Option<String>,
+    pub packages: usize,
+    pub pack_size: u64,
+    pub references: BTreeMap<String, String>,
+}
+
+impl SnapshotManifest {
+    pub fn new(created: u64, config_fingerprint: String, references: &[(String, Oid)]) -> Self {
+        Self {
+            version: VERSION,
+            created,
+            config_fingerprint,
+            base: None,
+            history: references
+                .iter()
+                .find(|(name, _)| name == HISTORY_REF)
+                .map(|(_, oid)| oid.to_string()),
+            packages: references
+                .iter()
+                .filter(|(name, _)| package_ref(name).is_some_and(|(_, kind)| kind == "narinfo"))
+                .count(),
+            pack_size: 0,
+            references: references
+                .iter()
+                .map(|(name, oid)| (name.clone(), oid.to_string()))
+                .collect(),
+        }
+    }
+
+    pub fn is_supported(&self) -> bool {
+        self.version == VERSION
+    }
+}
+
+impl Display for SnapshotManifest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(base) = &self.base {
+            writeln!(f, "Since: {base}")?;
+        }
+        if let Some(history) = &self.history {
+            writeln!(f, "History: {history}")?;
+        }
+        writeln!(f, "Packages: {}", self.packages)?;
+        writeln!(f, "References: {}", self.references.len())?;
+        writeln!(f, "Pack size: {} bytes", self.pack_size)?;
+        writeln!(f, "Config fingerprint: {}", self.config_fingerprint)
+    }
+}
+
+/// Splits `refs/<hash>/<kind>` into the hash and kind of a package reference.
+///
+/// Globs over the package references like `refs/*/narinfo` also match below
+/// other namespaces, as `*` matches `/`. References kept anywhere else therefore
+/// must not end in `/<kind>`, and are named like `<hash>.<kind>` instead.
+pub fn package_ref(name: &str) -> Option<(&str, &str)> {
+    let (hash, kind) = name.strip_prefix("refs/")?.split_once('/')?;
+    (hash.len() == 32 && !kind.contains('/')).then_some((hash, kind))
+}
+
+/// Leaves out the references of packages which are being written while the
+/// snapshot is taken, i.e. which have only one of a result and a narinfo, so that
+/// a restored store holds no half-written entries. Other references are kept.
+pub fn complete_references(references: Vec<(String, Oid)>) -> Vec<(String, Oid)> {
+    let kinds_of = |kind: &str| -> BTreeSet<String> {
+        references
+            .iter()
+            .filter_map(|(name, _)| package_ref(name))
+            .filter(|(_, k)| *k == kind)
+            .map(|(hash, _)| hash.to_string())
+            .collect()
+    };
+    let complete: BTreeSet<String> = kinds_of("result")
+        .intersection(&kinds_of("narinfo"))
+        .cloned()
+        .collect();
+    references
+        .into_iter()
+        .filter(|(name, _)| match package_ref(name) {
+            Some((hash, _)) => complete.contains(hash),
+            None => true,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_complete_references() {
+        let oid = Oid::from_bytes(&[1; 20]).unwrap();
+        let complete = "c".repeat(32);
+        let partial = "p".repeat(32);
+        let references = complete_references(vec![
+            (format!("refs/{complete}/result"), oid),
+            (format!("refs/{complete}/narinfo"), oid),
+            (format!("refs/{complete}/provenance"), oid),
+            (format!("refs/{partial}/result"), oid),
+            (HISTORY_REF.to_string(), oid),
+        ]);
+        let names: Vec<&str> = references.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                format!("refs/{complete}/result"),
+                format!("refs/{complete}/narinfo"),
+                format!("refs/{complete}/provenance"),
+                "refs/gachix/history".to_string(),
+            ]
+        );
+
+        let manifest = SnapshotManifest::new(0, "fingerprint".to_string(), &references);
+        assert_eq!(manifest.packages, 1);
+        assert_eq!(manifest.references.len(), 4);
+        assert_eq!(manifest.history, Some(oid.to_string()));
+        assert!(manifest.is_supported());
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use liblzma::write::XzEncoder;
+use sha2::Digest;
+
+use crate::git_store::ipfs::{HashingWriter, XZ_LEVEL};
+use crate::git_store::listing::Entry;
+use crate::nix_interface::cache_info::CacheInfo;
+use crate::nix_interface::nar_info::NarInfo;
+
+/// Limits of the static host the cache is exported for.
+#[derive(Debug, Clone)]
+pub struct StaticExportOptions {
+    /// Packages whose compressed NAR is larger than this many bytes are skipped
+    pub max_file_size: u64,
+    /// Start another site once a site would grow beyond this many bytes
+    pub max_site_size: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+pub struct StaticExportSummary {
+    pub exported: usize,
+    /// Store paths of the packages which exceeded the file size limit
+    pub skipped: Vec<String>,
+    /// Store paths of the packages left out as one of their references was not
+    /// exported, which would leave them without a complete closure
+    pub incomplete: Vec<String>,
+    /// Directories of the written sites
+    pub sites: Vec<PathBuf>,
+    pub bytes: u64,
+}
+
+impl Display for StaticExportSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Exported {} packages ({} bytes) to {} sites",
+            self.exported,
+            self.bytes,
+            self.sites.len()
+        )?;
+        for site in &self.sites {
+            writeln!(f, "  {}", site.display())?;
+        }
+        if !self.skipped.is_empty() {
+            writeln!(
+                f,
+                "Skipped {} packages over the size limit:",
+                self.skipped.len()
+            )?;
+            for path in &self.skipped {
+                writeln!(f, "  {path}")?;
+            }
+        }
+        if !self.incomplete.is_empty() {
+            writeln!(
+                f,
+                "Skipped {} packages whose dependencies were not exported:",
+                self.incomplete.len()
+            )?;
+            for path in &self.incomplete {
+                writeln!(f, "  {path}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A NAR which was compressed into the staging directory of an export.
+pub struct CompressedNar {
+    path: PathBuf,
+    /// Nix base32 encoded sha256 hash of the compressed file
+    file_hash: String,
+    pub file_size: u64,
+}
+
+impl CompressedNar {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Name of the compressed NAR below `nar/`, by the hash of the file.
+    pub fn file_name(&self) -> String {
+        format!("{}.nar.xz", self.file_hash)
+    }
+
+    /// Points a narinfo at the compressed NAR. Only the file fields change, so the
+    /// signatures of the narinfo stay valid.
+    pub fn describe(&self, narinfo: &mut NarInfo) {
+        narinfo.url = Some(format!("nar/{}", self.file_name()));
+        narinfo.compression_type = Some("xz".to_string());
+        narinfo.file_hash = format!("sha256:{}", self.file_hash);
+        narinfo.file_size = self.file_size;
+    }
+}
+
+/// Compresses the NAR written by `write_nar` with xz into `path`.
+pub fn compress_nar(
+    path: &Path,
+    write_nar: impl FnOnce(&mut dyn Write) -> Result<()>,
+) -> Result<CompressedNar> {
+    let mut writer = HashingWriter::new(BufWriter::new(File::create(path)?));
+    let mut encoder = XzEncoder::new(&mut writer, XZ_LEVEL);
+    write_nar(&mut encoder)?;
+    encoder.finish()?;
+    writer.flush()?;
+    Ok(CompressedNar {
+        path: path.to_path_buf(),
+        file_hash: nix_base32::to_nix_base32(&writer.hasher.finalize()),
+        file_size: writer.size,
+    })
+}
+
+/// Orders packages by hash so that every package comes after those it references,
+/// which references outside of `narinfos` don't affect.
+pub fn references_first(narinfos: &BTreeMap<String, NarInfo>) -> Vec<&NarInfo> {
+    fn visit<'a>(
+        hash: &str,
+        narinfos: &'a BTreeMap<String, NarInfo>,
+        visited: &mut HashSet<String>,
+        order: &mut Vec<&'a NarInfo>,
+    ) {
+        if !visited.insert(hash.to_string()) {
+            return;
+        }
+        if let Some(narinfo) = narinfos.get(hash) {
+            for reference in &narinfo.references {
+                visit(reference.get_base_32_hash(), narinfos, visited, order);
+            }
+            order.push(narinfo);
+        }
+    }
+
+    let mut visited = HashSet::new();
+    let mut order = Vec::new();
+    for hash in narinfos.keys() {
+        visit(hash, narinfos, &mut visited, &mut order);
+    }
+    order
+}
+
+/// A directory holding a complete binary cache, which can be served as is.
+pub struct Site {
+    dir: PathBuf,
+    entries: Vec<Entry>,
+    pub size: u64,
+}
+
+impl Site {
+    pub fn create(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir.join("nar"))?;
+        let cache_info = CacheInfo::default().to_string();
+        fs::write(dir.join("nix-cache-info"), &cache_info)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            entries: Vec::new(),
+            size: cache_info.len() as u64,
+        })
+    }
+
+    /// Moves a compressed NAR into the site and writes a narinfo pointing at it.
+    pub fn add(&mut self, mut narinfo: NarInfo, nar: CompressedNar) -> Result<()> {
+        fs::rename(&nar.path, self.dir.join("nar").join(nar.file_name()))?;
+        nar.describe(&mut narinfo);
+
+        let hash = narinfo.store_path.get_base_32_hash().to_string();
+        let narinfo_content = narinfo.to_string();
+        fs::write(
+            self.dir.join(format!("{hash}.narinfo")),
+            narinfo_content.as_bytes(),
+        )?;
+        self.size += nar.file_size + narinfo_content.len() as u64;
+        self.entries.push(Entry {
+            hash,
+            name: narinfo.store_path.get_name().to_string(),
+        });
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Writes the listing of the packages of the site, as JSON and as a web page.
+    pub fn finish(mut self) -> Result<PathBuf> {
+        self.entries
+            .sort_by(|a, b| a.name.cmp(&b.name).then(a.hash.cmp(&b.hash)));
+        fs::write(
+            self.dir.join("entries.json"),
+            serde_json::to_string(&self.entries)?,
+        )?;
+        fs::write(self.dir.join("index.html"), render_index(&self.entries))?;
+        Ok(self.dir)
+    }
+}
+
+fn render_index(entries: &[Entry]) -> String {
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Nix binary cache</title>\n</head>\n<body>\n",
+    );
+    html.push_str(&format!(
+        "<h1>Nix binary cache</h1>\n<p>{} packages. Use this site as a substituter \
+         for <code>/nix/store</code>.</p>\n<ul>\n",
+        entries.len()
+    ));
+    for entry in entries {
+        html.push_str(&format!(
+            "<li><a href=\"{hash}.narinfo\">{name}</a> <code>{hash}</code></li>\n",
+            hash = entry.hash,
+            name = entry.name
+        ));
+    }
+    html.push_str("</ul>\n</body>\n</html>\n");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_references_first() -> Result<()> {
+        let narinfo = |hash: &str, references: &str| {
+            NarInfo::parse(&format!(
+                "StorePath: /nix/store/{hash}-pkg
+URL: nar/abc.nar
+Compression: none
+FileHash: sha256:0lfjpl49j2na01l1zdmyszxz5wr957kl5qxn278alyv0fvxh2lab
+FileSize: 1
+NarHash: sha256:0lfjpl49j2na01l1zdmyszxz5wr957kl5qxn278alyv0fvxh2lab
+NarSize: 1
+References: {references}
+Deriver:
+Sig:
+"
+            ))
+        };
+        let (a, b, c) = ("a".repeat(32), "b".repeat(32), "c".repeat(32));
+        let narinfos = BTreeMap::from([
+            (a.clone(), narinfo(&a, &format!("{a}-pkg {c}-pkg"))?),
+            (b.clone(), narinfo(&b, "")?),
+            (
+                c.clone(),
+                narinfo(&c, &format!("{b}-pkg {}-missing", "m".repeat(32)))?,
+            ),
+        ]);
+        let order: Vec<&str> = references_first(&narinfos)
+            .iter()
+            .map(|narinfo| narinfo.store_path.get_base_32_hash())
+            .collect();
+        assert_eq!(order, [b.as_str(), c.as_str(), a.as_str()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_site() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let narinfo = NarInfo::parse(
+            "StorePath: /nix/store/iylhaki6573cpsvspivjfsim700n46r3-kitty-0.43.1
+URL: nar/abc.nar
+Compression: none
+FileHash: sha256:0lfjpl49j2na01l1zdmyszxz5wr957kl5qxn278alyv0fvxh2lab
+FileSize: 18391180
+NarHash: sha256:0lfjpl49j2na01l1zdmyszxz5wr957kl5qxn278alyv0fvxh2lab
+NarSize: 18391180
+References:
+Deriver:
+Sig:
+",
+        )?;
+        let nar = compress_nar(&temp_dir.path().join("staged"), |writer| {
+            Ok(writer.write_all(b"nar contents")?)
+        })?;
+        let file_size = nar.file_size;
+
+        let dir = temp_dir.path().join("site");
+        let mut site = Site::create(&dir)?;
+        site.add(narinfo, nar)?;
+        assert!(!site.is_empty());
+        site.finish()?;
+
+        let exported = NarInfo::parse(&fs::read_to_string(
+            dir.join("iylhaki6573cpsvspivjfsim700n46r3.narinfo"),
+        )?)?;
+        assert_eq!(exported.compression_type.as_deref(), Some("xz"));
+        assert_eq!(exported.file_size, file_size);
+        let url = exported.url.unwrap();
+        assert_eq!(fs::metadata(dir.join(&url))?.len(), file_size);
+        assert!(dir.join("nix-cache-info").exists());
+        assert!(fs::read_to_string(dir.join("index.html"))?.contains("kitty-0.43.1"));
+        Ok(())
+    }
+}
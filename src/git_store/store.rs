@@ -1,23 +1,86 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::collections::VecDeque;
+use std::fmt::Display;
 use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use crate::git_store::GitRepo;
+use crate::git_store::advertisement::RefAdvertisement;
+use crate::git_store::audit::{AUDIT_REF, AuditEntry, AuditFilter, AuditRecord};
+use crate::git_store::channels::{self, CHANNELS_NAMESPACE, ChannelVersion};
+use crate::git_store::dedup::DedupReport;
+use crate::git_store::derivers::{self, DERIVERS_NAMESPACE};
+use crate::git_store::events::Event;
+use crate::git_store::filter::IngestFilter;
+use crate::git_store::fsck::{DanglingEntry, FsckReport, Inconsistency, RefKind};
+use crate::git_store::gc::{
+    self, Candidate, DiskUsage, Expiry, GcSimulation, GcSummary, Policy, StoredPackage,
+};
+use crate::git_store::handshake::{self, HANDSHAKE_REF, Handshake};
+use crate::git_store::health::{AuthStatus, HealthReport, PeerHealth, PeerKind};
+use crate::git_store::history::{
+    self, Change, HISTORY_REF, HistoryEntry, HistoryFilter, PointInTime, Record,
+};
+use crate::git_store::ipfs::{self, IpfsExport};
+use crate::git_store::lease::{Lease, Leases};
+use crate::git_store::listing::{self, Entry, EntrySummary, ListOptions};
+use crate::git_store::manifest::Manifest;
+use crate::git_store::plan::{PlannedPath, Source, SubstitutionPlan};
+use crate::git_store::priority::{Interactive, Scheduler};
+use crate::git_store::provenance::{BuildInfo, Provenance};
+use crate::git_store::quarantine::{
+    QUARANTINE_NAMESPACE, QuarantinedPackage, parse_quarantine_ref, quarantine_ref,
+};
+use crate::git_store::revisions::{self, REVISIONS_NAMESPACE, Reason, Revision};
+use crate::git_store::revocations::{self, PeerDeletion, REVOCATIONS_NAMESPACE, Revocation};
+use crate::git_store::rollback::StagedRefs;
+use crate::git_store::sbom::{self, SbomFormat};
+use crate::git_store::scan::{Finding, Scanners};
+use crate::git_store::snapshot::{self, SnapshotManifest};
+use crate::git_store::static_site::{
+    self, CompressedNar, Site, StaticExportOptions, StaticExportSummary,
+};
+use crate::git_store::tags::{self, TAGS_NAMESPACE, Tag};
+use crate::git_store::tombstones::{self, TOMBSTONES_NAMESPACE, Tombstone};
+use crate::git_store::upstream::{self, FetchedNar, MetadataImport, UPSTREAM_NAMESPACE};
+use crate::git_store::verify::{ReproducibilityReport, Verification, verify_ref};
 use crate::nar::NarGitStream;
-use crate::nix_interface::daemon::DynNixDaemon;
+use crate::nar::budget::MemoryBudget;
+use crate::nar::decode::Dedup;
+use crate::nar::index::NarIndex;
+use crate::nar::regular_file_contents;
+use crate::nix_interface::capabilities::Capabilities;
 use crate::nix_interface::daemon::NixDaemon;
+use crate::nix_interface::daemon::{Closure, DynNixDaemon, Timeouts, derived_path};
+use crate::nix_interface::derivation;
 use crate::nix_interface::nar_info::NarInfo;
-use crate::nix_interface::path::NixPath;
-use crate::nix_interface::signature::PrivateKey;
+use crate::nix_interface::path::{NixPath, is_store_hash};
 use crate::nix_interface::signature::fingerprint_store_object;
+use crate::nix_interface::signature::{PrivateKey, PublicKey};
+use crate::nix_interface::ssh::{
+    self, AuthMethod, AuthenticationFailed, HostKeyCheck, SshOptions, parse_public_host_key,
+};
 use crate::settings;
-use anyhow::{anyhow, bail};
-use async_recursion::async_recursion;
+use anyhow::{Context, anyhow, bail};
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
+use futures::future::try_join_all;
 use git2::Oid;
+use nix_daemon::{BuildResult, BuildResultStatus};
+use sha2::{Digest, Sha256};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, info, warn};
+use url::Url;
 
 use anyhow::Result;
 
@@ -26,11 +89,353 @@ pub struct Store {
     settings: settings::Store,
     repo: GitRepo,
     private_key: Option<PrivateKey>,
+    trusted_keys: Vec<PublicKey>,
+    filter: IngestFilter,
+    peer_filters: Vec<(Url, IngestFilter)>,
+    scanners: Scanners,
+    /// Fields added to the narinfo of every package, see `narinfo_fields.add`
+    narinfo_fields: Vec<(String, String)>,
+    // Counting refs is slow on large repositories, so the count is computed on
+    // first use and kept up to date as packages are added
+    package_count: Arc<Mutex<Option<usize>>>,
+    // The NAR size of the artifacts, summed up on first use like the package count
+    artifact_bytes: Arc<Mutex<Option<u64>>>,
+    packages_added: Arc<AtomicUsize>,
+    packages_since_pack: Arc<AtomicUsize>,
+    next_daemon: Arc<AtomicUsize>,
+    maintenance: Arc<AtomicBool>,
+    events: broadcast::Sender<Event>,
+    scheduler: Arc<Scheduler>,
+    leases: Arc<Leases>,
+    /// Who changes are recorded for in the history, see `acting_as`
+    principal: Option<String>,
+}
+
+/// How often an operation on a Nix daemon is retried on a new connection.
+const RECONNECT_ATTEMPTS: u32 = 3;
+
+/// Longest time background work yields to client requests before each of its steps.
+const MAX_YIELD: Duration = Duration::from_secs(5);
+
+/// Longest time garbage collection waits for NARs of removed packages to finish
+/// downloading before it prunes objects.
+const MAX_LEASE_WAIT: Duration = Duration::from_secs(60);
+
+/// While evicted packages are pruned, the packages removed within the retention
+/// period are kept by references `<hash>.<kind>` below this namespace, see
+/// `snapshot::package_ref`.
+const RETAINED_NAMESPACE: &str = "refs/gachix/retained";
+
+/// Number of events buffered for each subscriber.
+pub const EVENT_CAPACITY: usize = 1024;
+
+pub struct StoreStats {
+    pub packages: usize,
+    /// Files stored below `/cas`
+    pub artifacts: usize,
+    /// NAR size of the files stored below `/cas`
+    pub artifact_bytes: u64,
+    /// Bytes of file contents held in memory by transfers
+    pub transfer_memory: u64,
+    /// Bytes of file contents spilled to temporary files since the store was opened
+    pub spilled: u64,
+}
+
+impl Display for StoreStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Packages: {}", self.packages)?;
+        writeln!(
+            f,
+            "Artifacts: {} ({} bytes)",
+            self.artifacts, self.artifact_bytes
+        )?;
+        writeln!(f, "Transfer memory: {} bytes", self.transfer_memory)?;
+        writeln!(f, "Spilled to disk: {} bytes", self.spilled)
+    }
+}
+
+/// Whether an error broke the connection to a daemon, so that reconnecting may help,
+/// rather than the daemon refusing the operation.
+fn is_session_error(e: &anyhow::Error) -> bool {
+    e.chain()
+        .any(|cause| cause.is::<std::io::Error>() || cause.is::<async_ssh2_lite::Error>())
+}
+
+/// Connects to a daemon and runs an operation on it. If the session broke or timed
+/// out, the daemon is reconnected and the operation is run again, so that a closure
+/// add resumes from the package which failed. Other errors, e.g. a path the daemon
+/// doesn't know, are returned right away.
+async fn with_reconnects<T>(
+    daemon: &mut DynNixDaemon,
+    mut operation: impl AsyncFnMut(&mut DynNixDaemon) -> Result<T>,
+) -> Result<T> {
+    let mut attempt = 1;
+    loop {
+        let result = match daemon.connect().await {
+            Ok(()) => operation(daemon).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Err(e) if attempt < RECONNECT_ATTEMPTS && is_session_error(&e) => {
+                debug!(
+                    "Reconnecting to the Nix daemon at {} (attempt {}): {}",
+                    daemon.get_address(),
+                    attempt,
+                    e
+                );
+                tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn check_build_result(drv_path: &NixPath, result: &BuildResult) -> Result<()> {
+    match result.status {
+        BuildResultStatus::Built
+        | BuildResultStatus::Substituted
+        | BuildResultStatus::AlreadyValid
+        | BuildResultStatus::ResolvesToAlreadyValid => Ok(()),
+        _ => bail!(
+            "Building {} failed ({:?}): {}",
+            drv_path,
+            result.status,
+            result.error_msg
+        ),
+    }
+}
+
+/// Reads the NAR of a derivation, if the daemon still has the derivation.
+async fn read_derivation(daemon: &mut DynNixDaemon, drv_path: &NixPath) -> Result<Option<String>> {
+    if !daemon.path_exists(drv_path).await? {
+        return Ok(None);
+    }
+    let nar = daemon
+        .fetch(drv_path, |r| {
+            let mut nar = Vec::new();
+            r.read_to_end(&mut nar)?;
+            Ok(nar)
+        })
+        .await?;
+    Ok(Some(String::from_utf8_lossy(&nar).into_owned()))
+}
+
+/// Adds a content-addressed path which `source` holds to `destination`.
+async fn copy_path(
+    source: &mut DynNixDaemon,
+    destination: &mut DynNixDaemon,
+    path: &NixPath,
+) -> Result<()> {
+    let path_info = source
+        .get_pathinfo(path)
+        .await?
+        .ok_or_else(|| anyhow!("Could not find path info for {}", path))?;
+    let Some(ca) = path_info.ca.as_deref() else {
+        bail!("Cannot copy {}, which is not content-addressed", path);
+    };
+    // The hash comes last, e.g. in `fixed:r:sha256:<hash>`
+    let (method, _) = ca
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow!("Invalid content address {} of {}", ca, path))?;
+    let nar = source
+        .fetch(path, |r| {
+            let mut nar = Vec::new();
+            r.read_to_end(&mut nar)?;
+            Ok(nar)
+        })
+        .await?;
+    // Recursive addresses hash the NAR, flat and text ones the file itself
+    let contents = if method.starts_with("fixed:r:") {
+        nar
+    } else {
+        regular_file_contents(&nar)
+            .ok_or_else(|| anyhow!("{} is not a single file", path))?
+            .to_vec()
+    };
+    let references: Vec<String> = path_info
+        .references
+        .into_iter()
+        .filter(|reference| reference != path.get_path())
+        .collect();
+    destination
+        .add_to_store(path, method, &references, contents)
+        .await
+}
+
+/// Orders the paths of a closure so that every path comes after its references.
+fn references_first(
+    hash: &str,
+    closure: &Closure,
+    visited: &mut HashSet<String>,
+    order: &mut Vec<NixPath>,
+) {
+    if !visited.insert(hash.to_string()) {
+        return;
+    }
+    if let Some(entry) = closure.get(hash) {
+        for reference in &entry.references {
+            references_first(reference.get_base_32_hash(), closure, visited, order);
+        }
+        order.push(entry.path.clone());
+    }
+}
+
+/// Reads the system a derivation is built for, if the daemon still has the derivation.
+async fn derivation_system(
+    daemon: &mut DynNixDaemon,
+    drv_path: &NixPath,
+) -> Result<Option<String>> {
+    let drv = read_derivation(daemon, drv_path).await?;
+    Ok(drv.and_then(|drv| derivation::parse_system(&drv).map(str::to_string)))
+}
+
+/// Formats the hex encoded NAR hash reported by a daemon the way narinfos list it.
+fn format_nar_hash(hex_hash: &str) -> Result<String> {
+    // TODO: formatting should be handled by the NarInfo struct
+    Ok(format!(
+        "sha256:{}",
+        nix_base32::to_nix_base32(&hex::decode(hex_hash)?)
+    ))
+}
+
+/// Hashes and counts what is read through it, so that a NAR is hashed while it is
+/// decoded.
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.size += read as u64;
+        Ok(read)
+    }
+}
+
+/// Rejects anything but the hash of a store path. Hashes name the references of a
+/// package and are put into reference globs, in which `*` also matches `/`.
+fn check_hash(hash: &str) -> Result<()> {
+    if !is_store_hash(hash) {
+        bail!("{:?} is not the hash of a store path", hash);
+    }
+    Ok(())
+}
+
+/// A package fetched from a daemon which is committed after its dependencies.
+struct FetchedPackage {
+    narinfo_blob_oid: Oid,
+    package_oid: Oid,
+    dependencies: Vec<NixPath>,
+    provenance: Provenance,
+    nar_hash: String,
+    dedup: Dedup,
+}
+
+/// A package which was fetched from a Nix daemon but is not referenced yet.
+pub struct DaemonPackage {
+    pub narinfo: NarInfo,
+    pub narinfo_blob_oid: Oid,
+    pub package_oid: Oid,
+    /// Address of the daemon the package was fetched from
+    pub daemon: String,
+    /// Size of the NAR which was transferred, zero if an identical tree was already
+    /// stored
+    pub transferred: u64,
+    pub dedup: Dedup,
+}
+
+enum Fetched {
+    Committed(Oid),
+    Pending(FetchedPackage),
+}
+
+pub struct CopySummary {
+    pub copied: usize,
+    pub present: usize,
+    /// Requested packages the filters of the destination peer left out
+    pub skipped: usize,
+}
+
+impl Display for CopySummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Copied {} packages, {} already present",
+            self.copied, self.present
+        )?;
+        if self.skipped > 0 {
+            write!(f, ", {} skipped by the filters of the peer", self.skipped)?;
+        }
+        writeln!(f)
+    }
+}
+
+/// What `split` moved to another repository.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SplitSummary {
+    pub copy: CopySummary,
+    /// Packages removed from this repository
+    pub removed: usize,
+    /// Dependencies which were copied but are kept, as packages staying need them
+    pub kept: usize,
+}
+
+impl Display for SplitSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.copy)?;
+        writeln!(
+            f,
+            "Removed {} packages, kept {} dependencies still needed here",
+            self.removed, self.kept
+        )
+    }
+}
+
+/// What `clone_from` fetched from a git peer.
+pub struct CloneSummary {
+    /// Packages the peer holds
+    pub available: usize,
+    /// Packages fetched along with their dependencies
+    pub cloned: usize,
+    /// Packages held here already
+    pub present: usize,
+    /// Packages the filters left out, unless another package depends on them
+    pub skipped: usize,
+    /// Packages which could not be fetched, e.g. as they failed verification
+    pub failed: Vec<String>,
+}
+
+impl Display for CloneSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Cloned {} of {} packages, {} already present, {} skipped by the filters",
+            self.cloned, self.available, self.present, self.skipped
+        )?;
+        if !self.failed.is_empty() {
+            writeln!(
+                f,
+                "Could not fetch {} packages: {}",
+                self.failed.len(),
+                self.failed.join(", ")
+            )?;
+        }
+        Ok(())
+    }
 }
 
 impl Store {
     pub fn new(settings: settings::Store) -> Result<Self> {
-        let repo = GitRepo::new(&settings.path)?;
+        let repo = if settings.read_only {
+            GitRepo::open_read_only(&settings.path)?
+        } else {
+            GitRepo::new(&settings.path)?
+        }
+        .with_memory_budget(MemoryBudget::new(settings.memory_budget));
 
         let private_key = if let Some(key_path) = &settings.sign_private_key_path {
             let key = PrivateKey::from_str(&fs::read_to_string(key_path)?)?;
@@ -43,73 +448,422 @@ impl Store {
             None
         };
 
-        let store = Self {
+        let trusted_keys = settings
+            .trusted_public_keys
+            .iter()
+            .map(|key| PublicKey::from_str(key))
+            .collect::<Result<Vec<_>>>()?;
+
+        let filter = IngestFilter::new(&settings.filters)?;
+        let peer_filters = settings
+            .peer_filters
+            .iter()
+            .map(|peer| Ok((peer.remote.clone(), IngestFilter::new(&peer.filters)?)))
+            .collect::<Result<_>>()?;
+        let scanners = Scanners::new(&settings.scanners)?;
+        let narinfo_fields = settings
+            .narinfo_fields
+            .add
+            .iter()
+            .map(|line| {
+                let (key, value) = line
+                    .split_once(':')
+                    .map(|(k, v)| (k.trim(), v.trim()))
+                    .filter(|(k, _)| !k.is_empty())
+                    .ok_or_else(|| {
+                        anyhow!("Invalid narinfo field '{line}', expected 'Key: value'")
+                    })?;
+                if NarInfo::is_known_field(key) {
+                    bail!("Narinfo field {key} can't be overridden");
+                }
+                Ok((key.to_string(), value.to_string()))
+            })
+            .collect::<Result<_>>()?;
+        // The disk usage monitor would check without pause
+        if settings.gc.check_interval == 0 {
+            bail!("store.gc.check_interval has to be at least one second");
+        }
+
+        Ok(Self {
             settings,
             repo,
             private_key,
+            trusted_keys,
+            filter,
+            peer_filters,
+            scanners,
+            narinfo_fields,
+            package_count: Arc::new(Mutex::new(None)),
+            artifact_bytes: Arc::new(Mutex::new(None)),
+            packages_added: Arc::new(AtomicUsize::new(0)),
+            packages_since_pack: Arc::new(AtomicUsize::new(0)),
+            next_daemon: Arc::new(AtomicUsize::new(0)),
+            maintenance: Arc::new(AtomicBool::new(false)),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            scheduler: Arc::new(Scheduler::default()),
+            leases: Arc::new(Leases::default()),
+            principal: None,
+        })
+    }
+
+    /// Returns a handle to this store which records changes in the history as made
+    /// by `principal`, such as the authenticated client of a server request, rather
+    /// than by the user running this process.
+    pub fn acting_as(&self, principal: &str) -> Store {
+        Store {
+            principal: Some(principal.to_string()),
+            ..self.clone()
+        }
+    }
+
+    /// The name and email changes are recorded under.
+    fn identity(&self) -> (String, String) {
+        match &self.principal {
+            Some(principal) => (principal.clone(), principal.clone()),
+            None => history::identity(),
+        }
+    }
+
+    /// Opens another repository with the same settings as this store.
+    pub fn with_path(&self, path: &Path) -> Result<Self> {
+        Store::new(settings::Store {
+            path: path.to_path_buf(),
+            ..self.settings.clone()
+        })
+    }
+
+    /// Subscribes to the progress events of all operations on this store. Events
+    /// are dropped for subscribers which fall behind by more than `EVENT_CAPACITY`.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Puts the store into maintenance mode, e.g. for a repack or a migration.
+    /// Background jobs are paused until maintenance mode is left again.
+    pub fn set_maintenance(&self, maintenance: bool) {
+        if self.maintenance.swap(maintenance, Ordering::Relaxed) != maintenance {
+            if maintenance {
+                info!("Entering maintenance mode");
+            } else {
+                info!("Leaving maintenance mode");
+            }
+        }
+    }
+
+    /// Whether the repository was opened without write access, see
+    /// `settings::Store::read_only`.
+    pub fn is_read_only(&self) -> bool {
+        self.settings.read_only
+    }
+
+    pub fn in_maintenance(&self) -> bool {
+        self.maintenance.load(Ordering::Relaxed)
+    }
+
+    /// Shared by all transfers of this store, see `settings::Store::memory_budget`.
+    pub fn memory_budget(&self) -> &Arc<MemoryBudget> {
+        self.repo.memory_budget()
+    }
+
+    /// Opens a temporary file for data which does not fit into the memory budget.
+    pub fn spill_file(&self) -> std::io::Result<fs::File> {
+        self.repo.spill_file()
+    }
+
+    /// Directory for the chunks of resumable uploads to `/cas`.
+    pub fn uploads_dir(&self) -> PathBuf {
+        self.repo.uploads_dir()
+    }
+
+    /// Marks a client request as being served, so that background work yields to it
+    /// until the returned guard is dropped.
+    pub fn interactive(&self) -> Interactive {
+        self.scheduler.interactive()
+    }
+
+    /// Pins the NAR served under `key` against garbage collection until the returned
+    /// lease is dropped, see `get_as_nar_stream`. Returns `None` if there is no such
+    /// NAR, so that requests for unknown keys cannot hold up pruning.
+    pub fn lease(&self, key: &str) -> Result<Option<Lease>> {
+        let Ok(oid) = Oid::from_str(key) else {
+            return Ok(None);
         };
-        info!(
-            "Repository contains {} packages",
-            store.num_available_packages()?
-        );
-        Ok(store)
+        if !self.repo.contains(oid)? {
+            return Ok(None);
+        }
+        Ok(Some(self.leases.lease(key)))
+    }
+
+    fn emit(&self, event: Event) {
+        // Sending only fails if nobody is subscribed
+        let _ = self.events.send(event);
     }
 
     pub fn available_daemons(&self) -> Result<Vec<DynNixDaemon>> {
+        Ok(self
+            .daemons_with_capabilities()?
+            .into_iter()
+            .map(|(daemon, _)| daemon)
+            .collect())
+    }
+
+    /// The local daemon is not restricted, builders declare their capabilities in
+    /// their URL.
+    fn daemons_with_capabilities(&self) -> Result<Vec<(DynNixDaemon, Capabilities)>> {
+        let timeouts = self.daemon_timeouts();
         let mut daemons = Vec::new();
         if self.settings.use_local_nix_daemon {
-            daemons.push(DynNixDaemon::Local(NixDaemon::local()));
-        }
-        if self.settings.builders.is_empty() {
-            return Ok(daemons);
+            daemons.push((
+                DynNixDaemon::Local(NixDaemon::local().with_timeouts(timeouts)),
+                Capabilities::default(),
+            ));
         }
-        let key_file = self.settings.ssh_private_key_path.as_ref().ok_or_else(|| {
-            anyhow!("Path to private ssh key must be specified when using remote Nix daemons")
-        })?;
-
         for url in &self.settings.builders {
-            daemons.push(DynNixDaemon::Remote(NixDaemon::remote(
-                &url.host_str().unwrap(),
-                key_file.clone(),
-            )));
+            daemons.push((
+                DynNixDaemon::Remote(
+                    NixDaemon::remote(&url.host_str().unwrap(), self.ssh_options(url)?)
+                        .with_timeouts(timeouts),
+                ),
+                Capabilities::from_url(url),
+            ));
         }
         Ok(daemons)
     }
 
-    pub async fn peer_health_check(&self) -> bool {
-        let mut success = true;
+    /// Whether any builder declares the systems it holds paths for, which is only
+    /// useful if the systems of the paths are known.
+    fn builders_declare_systems(&self) -> bool {
+        self.settings
+            .builders
+            .iter()
+            .any(|url| Capabilities::from_url(url).systems.is_some())
+    }
+
+    /// The user and the `auth-methods` parameter of a builder URL override the
+    /// configured defaults.
+    fn ssh_options(&self, url: &Url) -> Result<SshOptions> {
+        let auth_methods = match url.query_pairs().find(|(name, _)| name == "auth-methods") {
+            Some((_, methods)) => methods
+                .split(',')
+                .map(str::parse)
+                .collect::<Result<Vec<AuthMethod>>>()?,
+            None => self.settings.ssh_auth_methods.clone(),
+        };
+        if self.settings.ssh_private_key_path.is_none()
+            && auth_methods.iter().all(|m| *m == AuthMethod::Publickey)
+        {
+            bail!("Path to private ssh key must be specified when using remote Nix daemons");
+        }
+        let user = match url.username() {
+            "" => ssh::DEFAULT_USER.to_string(),
+            user => user.to_string(),
+        };
+        // As in Nix, ssh-ng stores run the daemon explicitly, by default `nix-daemon`
+        let remote_program = url
+            .query_pairs()
+            .find(|(name, _)| name == "remote-program")
+            .map(|(_, program)| program.into_owned());
+        let remote_program = match url.scheme() {
+            "ssh-ng" => Some(remote_program.unwrap_or_else(|| "nix-daemon".to_string())),
+            "ssh" => remote_program,
+            scheme => bail!("Unsupported builder URL scheme {scheme}, use ssh or ssh-ng"),
+        };
+        Ok(SshOptions {
+            user,
+            port: url.port().unwrap_or(22),
+            remote_command: remote_program.map(|program| format!("{program} --stdio")),
+            private_key_path: self.settings.ssh_private_key_path.clone(),
+            auth_methods,
+            host_key_check: self.host_key_check(url)?,
+        })
+    }
+
+    /// A key pinned with the `base64-ssh-public-host-key` parameter of a builder URL
+    /// takes precedence over the known_hosts file.
+    fn host_key_check(&self, url: &Url) -> Result<HostKeyCheck> {
+        if let Some((_, key)) = url
+            .query_pairs()
+            .find(|(name, _)| name == "base64-ssh-public-host-key")
+        {
+            return Ok(HostKeyCheck::Pinned(parse_public_host_key(&key)?));
+        }
+        let path = match &self.settings.ssh_known_hosts_path {
+            Some(path) => path.clone(),
+            None => HostKeyCheck::default_known_hosts_path()
+                .ok_or_else(|| anyhow!("Could not determine the path of known_hosts"))?,
+        };
+        Ok(HostKeyCheck::KnownHosts {
+            path,
+            learned: self.repo.known_hosts_path(),
+            trust_on_first_use: self.settings.ssh_trust_on_first_use,
+        })
+    }
+
+    fn daemon_timeouts(&self) -> Timeouts {
+        let timeouts = &self.settings.timeouts;
+        Timeouts {
+            connect: Duration::from_secs(timeouts.connect),
+            query: Duration::from_secs(timeouts.query),
+            transfer: Duration::from_secs(timeouts.transfer),
+            keepalive: Duration::from_secs(timeouts.keepalive),
+        }
+    }
 
-        for mut daemon in self.available_daemons().unwrap() {
-            match daemon.connect().await {
+    /// Connects to every Nix daemon and git peer and reports how each of them is
+    /// doing. The outcome is logged as well, so that problems show up before they
+    /// fail an operation. Git peers are checked on a blocking thread, as libgit2
+    /// blocks while it connects.
+    pub async fn peer_health_check(&self) -> HealthReport {
+        let mut report = HealthReport::default();
+
+        let daemons = match self.available_daemons() {
+            Ok(daemons) => daemons,
+            Err(e) => {
+                warn!("Could not set up the Nix daemons: {}", e);
+                report
+                    .errors
+                    .push(format!("Could not set up the Nix daemons: {e}"));
+                Vec::new()
+            }
+        };
+        for mut daemon in daemons {
+            let protocol = match daemon {
+                DynNixDaemon::Local(_) => "unix",
+                DynNixDaemon::Remote(_) => "ssh",
+            };
+            let start = Instant::now();
+            let result = daemon.connect().await;
+            let latency = start.elapsed();
+            match &result {
                 Ok(_) => info!(
                     "Succesfully connected to Nix daemon at {}",
                     daemon.get_address()
                 ),
-                Err(e) => {
-                    success = false;
-                    warn!(
-                        "Failed to connect to remote Nix daemon at {} : {}",
-                        daemon.get_address(),
-                        e
-                    )
-                }
+                Err(e) => warn!(
+                    "Failed to connect to remote Nix daemon at {} : {}",
+                    daemon.get_address(),
+                    e
+                ),
             };
+            let auth = match &result {
+                Ok(_) => AuthStatus::Ok,
+                Err(e) if e.downcast_ref::<AuthenticationFailed>().is_some() => AuthStatus::Failed,
+                Err(_) => AuthStatus::Unknown,
+            };
+            report.peers.push(PeerHealth {
+                kind: PeerKind::NixDaemon,
+                address: daemon.get_address(),
+                protocol: protocol.to_string(),
+                reachable: result.is_ok(),
+                latency_ms: result.is_ok().then(|| latency.as_millis() as u64),
+                auth,
+                packages: None,
+                version: None,
+                error: result.err().map(|e| e.to_string()),
+            });
             daemon.disconnect();
         }
 
+        let store = self.clone();
+        match tokio::task::spawn_blocking(move || store.git_peer_health()).await {
+            Ok(peers) => report.peers.extend(peers),
+            Err(e) => report
+                .errors
+                .push(format!("Could not check the git peers: {e}")),
+        }
+        report
+    }
+
+    /// Lists the references of every git peer and reports how each of them is doing,
+    /// see `peer_health_check`.
+    fn git_peer_health(&self) -> Vec<PeerHealth> {
+        let mut peers = Vec::new();
         for url in &self.settings.remotes {
-            let url_str = url.as_str();
-            let host = url.host().unwrap();
-            match self.repo.check_remote_health(&url_str) {
+            let host = url.host_str().unwrap_or_default();
+            let start = Instant::now();
+            let result = self.repo.list_remote_references(url.as_str());
+            let latency = start.elapsed();
+            match &result {
                 Ok(_) => info!("Succesfully connected to Git repository at {}", host),
-                Err(e) => {
-                    success = false;
-                    warn!("Failed to connect to Git repository {}: {}", host, e)
-                }
+                Err(e) => warn!("Failed to connect to Git repository {}: {}", host, e),
             }
+            let auth = match &result {
+                Ok(_) => AuthStatus::Ok,
+                Err(e)
+                    if e.downcast_ref::<git2::Error>()
+                        .is_some_and(|e| e.code() == git2::ErrorCode::Auth) =>
+                {
+                    AuthStatus::Failed
+                }
+                Err(_) => AuthStatus::Unknown,
+            };
+            let version = match &result {
+                Ok(_) => self
+                    .peer_handshake(url.as_str())
+                    .unwrap_or_else(|e| {
+                        warn!("Could not read the handshake of {}: {}", host, e);
+                        None
+                    })
+                    .map(|handshake| handshake.version),
+                Err(_) => None,
+            };
+            let mut address = url.clone();
+            let _ = address.set_password(None);
+            peers.push(PeerHealth {
+                kind: PeerKind::Git,
+                address: address.to_string(),
+                protocol: url.scheme().to_string(),
+                reachable: result.is_ok(),
+                latency_ms: result.is_ok().then(|| latency.as_millis() as u64),
+                auth,
+                packages: result.as_ref().ok().map(|references| {
+                    references
+                        .iter()
+                        .filter(|name| {
+                            snapshot::package_ref(name).is_some_and(|(_, kind)| kind == "narinfo")
+                        })
+                        .count()
+                }),
+                version,
+                error: result.err().map(|e| e.to_string()),
+            });
+        }
+        peers
+    }
+
+    /// Advertises the version of this gachix and the features it supports at
+    /// `HANDSHAKE_REF`, so that its git peers can tell what they may rely on.
+    /// Read-only stores are left as they are.
+    pub fn advertise_handshake(&self) -> Result<()> {
+        if self.settings.read_only {
+            return Ok(());
+        }
+        let json = Handshake::current().to_json()?;
+        let oid = self.repo.add_file_content(json.as_bytes())?;
+        if self.repo.get_oid_from_reference(HANDSHAKE_REF) != Some(oid) {
+            self.repo.update_ref(HANDSHAKE_REF, oid)?;
         }
+        Ok(())
+    }
 
-        success
+    /// Fetches the handshake of a git peer. Returns `None` for peers which run a
+    /// gachix from before the handshake, and for read-only stores, which can't fetch
+    /// it.
+    pub fn peer_handshake(&self, url: &str) -> Result<Option<Handshake>> {
+        if self.settings.read_only {
+            return Ok(None);
+        }
+        let scratch = handshake::peer_handshake_ref(url);
+        if self.repo.reference_exists(&scratch)? {
+            self.repo.delete_ref(&scratch)?;
+        }
+        self.repo.fetch_into(url, HANDSHAKE_REF, &scratch)?;
+        let Some(oid) = self.repo.get_oid_from_reference(&scratch) else {
+            return Ok(None);
+        };
+        let blob = self.repo.get_blob(oid);
+        self.repo.delete_ref(&scratch)?;
+        Ok(Some(Handshake::from_json(&blob?)?))
     }
 
     pub async fn add_single(&self, package_path: &NixPath) -> Result<()> {
@@ -120,11 +874,16 @@ impl Store {
 
         if self.repo.reference_exists(&narinfo_ref)? {
             debug!("Package already exists");
+            self.emit(Event::Skipped {
+                path: package_path.to_string(),
+                reason: "already stored".to_string(),
+            });
             return Ok(());
         }
 
-        let Ok(Some((_, narinfo_blob_oid, _))) =
-            self.get_package_from_nix_daemons(package_path).await
+        let Ok(Some(DaemonPackage {
+            narinfo_blob_oid, ..
+        })) = self.get_package_from_nix_daemons(package_path, None).await
         else {
             bail!(
                 "There doesn't exist a Nix daemon which has {}",
@@ -132,353 +891,7272 @@ impl Store {
             );
         };
         self.repo.add_ref(&narinfo_ref, narinfo_blob_oid)?;
+        self.record_added_package();
+        self.pack_refs_if_needed()?;
         Ok(())
     }
 
-    pub async fn add_closure(&self, package_path: &NixPath) -> Result<()> {
-        info!("Adding closure for {}", package_path.get_name());
-        let entries_before = self.num_available_packages()?;
-        match self._add_closure(package_path).await? {
-            Some(_) => {
-                let entries_after = self.num_available_packages()?;
-                let num_packages_added = entries_after - entries_before;
-                info!("Added {num_packages_added} packages")
+    /// Makes sure a daemon holds the given outputs of a derivation, keyed by their
+    /// name, building or substituting them on the local Nix daemon if none of the
+    /// daemons has all of them yet.
+    pub async fn realise(
+        &self,
+        drv_path: &NixPath,
+        outputs: &BTreeMap<String, NixPath>,
+    ) -> Result<()> {
+        for mut daemon in self.available_daemons()? {
+            if daemon.connect().await.is_err() {
+                continue;
+            }
+            let mut provided = Ok(true);
+            for path in outputs.values() {
+                provided = daemon.path_exists(path).await;
+                if !matches!(provided, Ok(true)) {
+                    break;
+                }
+            }
+            daemon.disconnect();
+            if provided? {
+                return Ok(());
             }
-            None => bail!(
-                "Could not add closure of package {}",
-                package_path.get_name()
-            ),
         }
-        Ok(())
+        if !self.settings.use_local_nix_daemon {
+            bail!(
+                "No Nix daemon provides the outputs of {} and building them requires the local Nix daemon",
+                drv_path
+            );
+        }
+        info!("Building {}", drv_path.get_name());
+        let names: Vec<&str> = outputs.keys().map(String::as_str).collect();
+        let mut daemon = NixDaemon::local().with_timeouts(self.daemon_timeouts());
+        daemon.connect().await?;
+        let results = daemon.build(&[drv_path], &names, true).await;
+        daemon.disconnect();
+        let result = results?
+            .remove(&derived_path(drv_path, &names))
+            .ok_or_else(|| anyhow!("Did not find build result for {}", drv_path))?;
+        check_build_result(drv_path, &result)
     }
 
-    #[async_recursion]
-    pub async fn _add_closure(&self, package_path: &NixPath) -> Result<Option<Oid>> {
-        let package_id = package_path.get_base_32_hash();
-
-        // Check if commit already exists locally
-        if let Some(commit_oid) = self.get_commit(package_id) {
-            debug!("Package already exists: {}", package_path.get_name());
-            return Ok(Some(commit_oid));
+    /// Builds all outputs of a derivation on a daemon, the first available one unless
+    /// `builder` names the host of a configured builder, and adds their closures.
+    /// Returns the output paths.
+    pub async fn build(
+        &self,
+        drv_path: &NixPath,
+        builder: Option<&str>,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<NixPath>> {
+        let mut daemons = self.daemons_with_capabilities()?;
+        if let Some(builder) = builder {
+            daemons.retain(|(d, _)| {
+                matches!(d, DynNixDaemon::Remote(_)) && d.get_address() == builder
+            });
         }
-
-        // Ask Git peers if they have replicated the package
-        if let Some(commit_oid) = self.get_package_commit_from_git_remotes(package_path)? {
-            return Ok(Some(commit_oid));
+        if daemons
+            .iter()
+            .any(|(_, capabilities)| capabilities.is_restricted())
+        {
+            let (system, features) = self.derivation_requirements(drv_path).await?;
+            daemons.retain(|(daemon, capabilities)| {
+                let can_build = capabilities.can_build(system.as_deref(), &features);
+                if !can_build {
+                    debug!(
+                        "Not building {} on {}, which does not support {} with features {:?}",
+                        drv_path.get_name(),
+                        daemon.get_address(),
+                        system.as_deref().unwrap_or("its system"),
+                        features
+                    );
+                }
+                can_build
+            });
         }
-
-        // Ask known Nix daemons if they can build the package
-        let Ok(Some((narinfo, narinfo_blob_oid, package_oid))) =
-            self.get_package_from_nix_daemons(package_path).await
-        else {
-            return Ok(None);
+        let Some((mut daemon, _)) = daemons.into_iter().next() else {
+            bail!("No Nix daemon is available to build {}", drv_path);
         };
+        daemon.connect().await?;
+        self.copy_derivation_closure(drv_path, &mut daemon).await?;
+        let daemon_address = daemon.get_address();
+        info!("Building {} on {}", drv_path.get_name(), daemon_address);
+        let (result, outputs) = cancel
+            .run_until_cancelled(daemon.build_derivation(drv_path, true, |line| {
+                self.emit(Event::BuildOutput {
+                    path: drv_path.to_string(),
+                    line,
+                })
+            }))
+            .await
+            .ok_or_else(|| anyhow!("Cancelled building {}", drv_path.get_name()))??;
+        check_build_result(drv_path, &result)?;
+        daemon.disconnect();
 
-        // Recurse into package dependecies and collect their commit oids
-        let deps = narinfo.get_dependencies();
-        let mut parent_commits = Vec::new();
-        for dependency in &deps {
-            let Some(dep_coid) = self._add_closure(&dependency).await? else {
-                return Ok(None);
-            };
-            parent_commits.push(dep_coid);
+        let mut paths: Vec<NixPath> = outputs.into_values().collect();
+        paths.sort_by(|a, b| a.get_path().cmp(b.get_path()));
+        for path in &paths {
+            self.add_closure(path, cancel).await?;
+            if result.status == BuildResultStatus::Built {
+                let hash = path.get_base_32_hash();
+                let mut provenance = self
+                    .provenance(hash)?
+                    .unwrap_or_else(|| Provenance::new(daemon_address.clone(), Some(drv_path)));
+                let started_at = result.start_time.timestamp().max(0) as u64;
+                provenance.build = Some(BuildInfo {
+                    builder: daemon_address.clone(),
+                    started_at,
+                    duration_secs: (result.stop_time.timestamp().max(0) as u64)
+                        .saturating_sub(started_at),
+                });
+                self.record_provenance(hash, &provenance)?;
+            }
         }
+        Ok(paths)
+    }
 
-        // Commit the package tree and specify dependency commits as parents
-        let commit_oid =
-            self.repo
-                .commit(package_oid, &parent_commits, Some(package_path.get_name()))?;
-
-        // Add references: nix-hash -> package-commit-oid, nix-hash -> narinfo-blob-oid
-        self.repo
-            .add_ref(&self.get_result_ref(package_id), commit_oid)?;
-        self.repo
-            .add_ref(&self.get_narinfo_ref(package_id), narinfo_blob_oid)?;
-        Ok(Some(commit_oid))
+    /// Copies a derivation, with the derivations and sources it depends on, to
+    /// `builder` from the first other daemon which holds it, as a builder cannot
+    /// build a derivation it lacks. These are all content-addressed, which is what
+    /// a daemon accepts from clients.
+    async fn copy_derivation_closure(
+        &self,
+        drv_path: &NixPath,
+        builder: &mut DynNixDaemon,
+    ) -> Result<()> {
+        if builder.path_exists(drv_path).await? {
+            return Ok(());
+        }
+        let address = builder.get_address();
+        for mut source in self.available_daemons()? {
+            if source.get_address() == address {
+                continue;
+            }
+            let closure = with_reconnects(&mut source, async |source| {
+                if !source.path_exists(drv_path).await? {
+                    return Ok(None);
+                }
+                Ok(Some(source.query_closure(drv_path).await?))
+            })
+            .await;
+            let closure = match closure {
+                Ok(Some(closure)) => closure,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Skipping Nix daemon at {}: {}", source.get_address(), e);
+                    continue;
+                }
+            };
+            let mut order = Vec::new();
+            references_first(
+                drv_path.get_base_32_hash(),
+                &closure,
+                &mut HashSet::new(),
+                &mut order,
+            );
+            info!(
+                "Copying {} to {} from {}",
+                drv_path.get_name(),
+                address,
+                source.get_address()
+            );
+            for path in &order {
+                if !builder.path_exists(path).await? {
+                    copy_path(&mut source, builder, path).await?;
+                }
+            }
+            source.disconnect();
+            return Ok(());
+        }
+        bail!("No Nix daemon holds {} to copy it to {}", drv_path, address)
     }
 
-    pub async fn get_package_from_nix_daemons(
+    /// Reads the system and the required features of a derivation from the first
+    /// daemon which holds it.
+    async fn derivation_requirements(
         &self,
-        package_path: &NixPath,
-    ) -> Result<Option<(NarInfo, Oid, Oid)>> {
+        drv_path: &NixPath,
+    ) -> Result<(Option<String>, Vec<String>)> {
         for mut daemon in self.available_daemons()? {
-            daemon.connect().await?;
-            // Ask if daemon has the package
-            // TODO: ask it to build the package if it does not have it
-            if !daemon.path_exists(package_path).await? {
-                continue;
-            };
-            // Add the package contents to the Git database
-            let clone = self.repo.clone();
-            let package_oid = daemon
-                .fetch(package_path, move |r| {
-                    let (oid, _) = clone.add_nar(r)?;
-                    Ok(oid)
-                })
-                .await?;
-
-            // Get metadata info about the package and add it to the Git database
-            let narinfo = self
-                .build_narinfo(&mut daemon, package_oid.to_string().as_str(), package_path)
-                .await?;
-            let narinfo_blob_oid = self.repo.add_file_content(narinfo.to_string().as_bytes())?;
-
-            match &daemon {
-                DynNixDaemon::Local(_) => {
-                    debug!("Using local daemon, fetched {} ", package_path.get_name())
+            let drv = with_reconnects(&mut daemon, async |daemon| {
+                read_derivation(daemon, drv_path).await
+            })
+            .await;
+            match drv {
+                Ok(Some(drv)) => {
+                    return Ok((
+                        derivation::parse_system(&drv).map(str::to_string),
+                        derivation::parse_required_features(&drv),
+                    ));
                 }
-                DynNixDaemon::Remote(daemon) => debug!(
-                    "Using daemon at {}, fetched package {}",
-                    daemon.get_address(),
-                    package_path.get_name()
-                ),
+                Ok(None) => {}
+                Err(e) => warn!("Skipping Nix daemon at {}: {}", daemon.get_address(), e),
             }
-            daemon.disconnect();
-            return Ok(Some((narinfo, narinfo_blob_oid, package_oid)));
         }
-        Ok(None)
+        Ok((None, Vec::new()))
     }
 
-    fn get_package_commit_from_git_remotes(&self, store_path: &NixPath) -> Result<Option<Oid>> {
-        let package_id = store_path.get_base_32_hash();
-        let mut commit_oid = None;
-        let mut success_remote = "";
-        for remote_url in &self.settings.remotes {
-            let url = remote_url.as_str();
-            if let Some(oid) = self.fetch_from_remote(package_id, url)? {
-                debug!(
-                    "Using git peer at {}, fetched package {}",
-                    remote_url,
-                    store_path.get_name()
+    /// Adds the closure of a package. When `cancel` is triggered, running fetches are
+    /// aborted. Packages are only referenced once they and all of their dependencies
+    /// are committed, so whatever was fetched for the remaining ones is left
+    /// unreachable and removed by the next garbage collection.
+    pub async fn add_closure(
+        &self,
+        package_path: &NixPath,
+        cancel: &CancellationToken,
+    ) -> Result<DedupReport> {
+        self.add_closure_after(package_path, None, cancel).await
+    }
+
+    /// Adds the closure of a package like `add_closure`, but only looks at the paths
+    /// which are new since `previous`, e.g. the system generation before it. Stored
+    /// paths of the previous closure are taken as they are, so that capturing a
+    /// generation after a routine `nixos-rebuild` doesn't resolve and filter the
+    /// whole unchanged closure again.
+    pub async fn add_closure_since(
+        &self,
+        package_path: &NixPath,
+        previous: &NixPath,
+        cancel: &CancellationToken,
+    ) -> Result<DedupReport> {
+        self.add_closure_after(package_path, Some(previous), cancel)
+            .await
+    }
+
+    async fn add_closure_after(
+        &self,
+        package_path: &NixPath,
+        previous: Option<&NixPath>,
+        cancel: &CancellationToken,
+    ) -> Result<DedupReport> {
+        info!("Adding closure for {}", package_path.get_name());
+        let added_before = self.packages_added.load(Ordering::Relaxed);
+        let mut closure = match self.query_closure(package_path).await {
+            Ok(closure) => closure,
+            // Without the closure, the ingest filters could not be applied
+            Err(e) if !self.filter.is_empty() => bail!(
+                "Could not query closure of {}, which the ingest filters need: {}",
+                package_path,
+                e
+            ),
+            Err(e) => {
+                warn!("Could not query closure of {}: {}", package_path, e);
+                None
+            }
+        };
+        if let Some(closure) = &mut closure
+            && let Some(previous) = previous
+        {
+            self.drop_unchanged(package_path, closure, previous)?;
+        }
+        if let Some(closure) = &mut closure
+            && (self.filter.filters_systems() || self.builders_declare_systems())
+        {
+            self.resolve_systems(closure).await?;
+        }
+        if let Some(closure) = &mut closure
+            && !self.filter.is_empty()
+        {
+            self.apply_filters(package_path, closure).await?;
+        }
+        if let Some(closure) = &closure {
+            let missing = closure
+                .keys()
+                .filter(|hash| self.get_commit(hash).is_none())
+                .count();
+            info!(
+                "Closure has {} packages, {} of them are missing",
+                closure.len(),
+                missing
+            );
+        }
+        let mut report = DedupReport::default();
+        match self
+            ._add_closure(package_path, closure.as_ref(), &mut report, cancel)
+            .await?
+        {
+            Some(_) => {
+                let num_packages_added = self.packages_added.load(Ordering::Relaxed) - added_before;
+                info!(
+                    "Added {num_packages_added} packages, {} bytes were new and {} bytes ({:.1}%) shared with stored objects",
+                    report.total.new_bytes,
+                    report.total.shared_bytes,
+                    report.total.shared_percent()
+                )
+            }
+            None => bail!(
+                "Could not add closure of package {}",
+                package_path.get_name()
+            ),
+        }
+        if !self.filter.is_empty() {
+            report.incomplete = self.incomplete_packages(package_path.get_base_32_hash())?;
+            if !report.incomplete.is_empty() {
+                warn!(
+                    "{} packages were stored without dependencies the ingest filters skipped",
+                    report.incomplete.len()
                 );
-                commit_oid = Some(oid);
-                success_remote = url;
-                break;
             }
         }
-        if commit_oid == None {
-            return Ok(None);
+        self.pack_refs_if_needed()?;
+        Ok(report)
+    }
+
+    /// Picks the cheapest source of every wanted path without fetching anything:
+    /// the repository itself, a git peer which advertises the package, or a Nix
+    /// daemon which holds it. Daemons are asked for the NAR sizes of all missing
+    /// paths, so the plan also estimates how much has to be transferred.
+    pub async fn substitution_plan(&self, paths: &[NixPath]) -> Result<SubstitutionPlan> {
+        let missing: Vec<&NixPath> = paths
+            .iter()
+            .filter(|p| self.get_commit(p.get_base_32_hash()).is_none())
+            .collect();
+
+        let mut peers = Vec::new();
+        let mut daemon_paths: HashMap<String, (String, u64)> = HashMap::new();
+        if !missing.is_empty() {
+            peers = self.peer_references();
+            for mut daemon in self.available_daemons()? {
+                let sizes = with_reconnects(&mut daemon, async |daemon| {
+                    let mut sizes = Vec::new();
+                    for path in &missing {
+                        if let Some(path_info) = daemon.get_pathinfo(path).await? {
+                            sizes.push((path.get_base_32_hash().to_string(), path_info.nar_size));
+                        }
+                    }
+                    Ok(sizes)
+                })
+                .await;
+                match sizes {
+                    Ok(sizes) => {
+                        for (hash, size) in sizes {
+                            daemon_paths
+                                .entry(hash)
+                                .or_insert_with(|| (daemon.get_address(), size));
+                        }
+                    }
+                    Err(e) => warn!(
+                        "Skipping Nix daemon at {} in the plan: {}",
+                        daemon.get_address(),
+                        e
+                    ),
+                }
+                daemon.disconnect();
+            }
         }
 
-        let mut open = VecDeque::new();
-        let mut visited = HashSet::new();
-        open.push_back(package_id.to_string());
-        visited.insert(package_id.to_string());
-        while let Some(id) = open.pop_front() {
-            for dep in self.get_dep_ids(&id)? {
-                let dep_hash = dep.get_base_32_hash();
-                if !visited.contains(dep_hash) {
-                    if !(self.repo.reference_exists(&self.get_result_ref(dep_hash))?
+        let paths = paths
+            .iter()
+            .map(|path| {
+                let hash = path.get_base_32_hash();
+                let daemon = daemon_paths.get(hash);
+                let (source, estimated_bytes) = if self.get_commit(hash).is_some() {
+                    (Some(Source::Stored), Some(0))
+                } else if let Some((url, _)) = peers.iter().find(|(url, references)| {
+                    references.contains(&self.get_result_ref(hash))
                         && self
-                            .repo
-                            .reference_exists(&self.get_narinfo_ref(dep_hash))?)
-                    {
-                        self.fetch_from_remote(dep_hash, success_remote)?;
-                        debug!(
-                            "Using git peer at {}, fetched package {}",
-                            success_remote,
-                            dep.get_name()
-                        );
+                            .peer_filter(url)
+                            .is_none_or(|f| f.rejects(path.get_name(), 0, None).is_none())
+                }) {
+                    (
+                        Some(Source::Peer(url.clone())),
+                        daemon.map(|(_, size)| *size),
+                    )
+                } else if let Some((address, size)) = daemon {
+                    (Some(Source::Daemon(address.clone())), Some(*size))
+                } else {
+                    (None, None)
+                };
+                PlannedPath {
+                    path: path.clone(),
+                    source,
+                    estimated_bytes,
+                }
+            })
+            .collect();
+        Ok(SubstitutionPlan { paths })
+    }
+
+    /// The filters configured for a git peer, see `store.peer_filters`.
+    fn peer_filter(&self, remote: &str) -> Option<&IngestFilter> {
+        let remote = Url::parse(remote).ok()?;
+        self.peer_filters
+            .iter()
+            .find(|(url, _)| *url == remote)
+            .map(|(_, filter)| filter)
+    }
+
+    /// Lists the references of every reachable git peer, a single listing per peer
+    /// instead of a fetch per path.
+    fn peer_references(&self) -> Vec<(String, HashSet<String>)> {
+        let mut peers = Vec::new();
+        for url in &self.settings.remotes {
+            match self.repo.list_remote_references(url.as_str()) {
+                Ok(references) => peers.push((url.to_string(), references.into_iter().collect())),
+                Err(e) => warn!("Skipping git peer {} in the plan: {}", url, e),
+            }
+        }
+        peers
+    }
+
+    /// Tells whether every output of a derivation can be substituted from the
+    /// repository or one of its git peers, so that CI can skip building it. Nix
+    /// daemons are not asked, as holding an output does not make it substitutable.
+    pub async fn check_build(&self, drv_path: &NixPath) -> Result<SubstitutionPlan> {
+        let drv = self.derivation_content(drv_path).await?;
+        let outputs = derivation::parse_outputs(&drv)
+            .ok_or_else(|| anyhow!("Could not read the outputs of {}", drv_path))?;
+        let paths = outputs
+            .into_iter()
+            .map(|(name, path)| {
+                if path.is_empty() {
+                    bail!(
+                        "Output {} of {} is content-addressed, its path is unknown until it is built",
+                        name,
+                        drv_path
+                    );
+                }
+                NixPath::new(path)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let peers = if paths
+            .iter()
+            .all(|p| self.get_commit(p.get_base_32_hash()).is_some())
+        {
+            Vec::new()
+        } else {
+            self.peer_references()
+        };
+        let paths = paths
+            .into_iter()
+            .map(|path| {
+                let hash = path.get_base_32_hash();
+                let source = if self.get_commit(hash).is_some() {
+                    Some(Source::Stored)
+                } else {
+                    peers
+                        .iter()
+                        .find(|(_, references)| references.contains(&self.get_result_ref(hash)))
+                        .map(|(url, _)| Source::Peer(url.clone()))
+                };
+                PlannedPath {
+                    path,
+                    source,
+                    estimated_bytes: None,
+                }
+            })
+            .collect();
+        Ok(SubstitutionPlan { paths })
+    }
+
+    /// Reads a derivation from the local Nix store, or else from the first daemon
+    /// which has it.
+    async fn derivation_content(&self, drv_path: &NixPath) -> Result<String> {
+        if let Ok(drv) = fs::read_to_string(drv_path.get_path()) {
+            return Ok(drv);
+        }
+        for mut daemon in self.available_daemons()? {
+            let drv = with_reconnects(&mut daemon, async |daemon| {
+                read_derivation(daemon, drv_path).await
+            })
+            .await;
+            daemon.disconnect();
+            match drv {
+                Ok(Some(drv)) => return Ok(drv),
+                Ok(None) => {}
+                Err(e) => warn!("Could not read {} from a Nix daemon: {}", drv_path, e),
+            }
+        }
+        bail!("No Nix daemon has the derivation {}", drv_path)
+    }
+
+    /// Asks the daemons which hold a package for the references of every path in its
+    /// closure, so that the whole dependency set is known before fetching.
+    async fn query_closure(&self, package_path: &NixPath) -> Result<Option<Closure>> {
+        for mut daemon in self.available_daemons()? {
+            let closure = with_reconnects(&mut daemon, async |daemon| {
+                if daemon.path_exists(package_path).await? {
+                    Ok(Some(daemon.query_closure(package_path).await?))
+                } else {
+                    Ok(None)
+                }
+            })
+            .await;
+            match closure {
+                Ok(Some(closure)) => return Ok(Some(closure)),
+                Ok(None) => {}
+                Err(e) => warn!("Skipping Nix daemon at {}: {}", daemon.get_address(), e),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Removes the paths which were in the stored closure of `previous` already from
+    /// a closure. The stored closure is followed through the parents of the commit of
+    /// `previous`, so that no daemon has to know it anymore. Their dependencies are
+    /// stored as well, so they are neither fetched nor followed when the closure is
+    /// added. Unchanged paths which are not stored, e.g. because the ingest filters
+    /// rejected them, are kept and filtered again.
+    fn drop_unchanged(
+        &self,
+        package_path: &NixPath,
+        closure: &mut Closure,
+        previous: &NixPath,
+    ) -> Result<()> {
+        let Some(previous_commit) = self.get_commit(previous.get_base_32_hash()) else {
+            warn!(
+                "{} is not stored, adding the whole closure of {}",
+                previous,
+                package_path.get_name()
+            );
+            return Ok(());
+        };
+        let mut previous_closure = HashSet::from([previous_commit]);
+        let mut open = VecDeque::from([previous_commit]);
+        while let Some(commit_oid) = open.pop_front() {
+            for parent in self.repo.get_commit_parents(commit_oid)? {
+                if previous_closure.insert(parent) {
+                    open.push_back(parent);
+                }
+            }
+        }
+        let size = closure.len();
+        closure.retain(|hash, _| {
+            hash == package_path.get_base_32_hash()
+                || self
+                    .get_commit(hash)
+                    .is_none_or(|commit_oid| !previous_closure.contains(&commit_oid))
+        });
+        info!(
+            "{} of {} packages are new since {}",
+            closure.len(),
+            size,
+            previous.get_name()
+        );
+        Ok(())
+    }
+
+    /// Returns the stored packages in the closure of a package whose references are
+    /// not all stored, along with the names of the missing references.
+    fn incomplete_packages(&self, hash: &str) -> Result<Vec<(String, Vec<String>)>> {
+        let mut incomplete = Vec::new();
+        let mut open = VecDeque::from([hash.to_string()]);
+        let mut visited = HashSet::from([hash.to_string()]);
+        while let Some(id) = open.pop_front() {
+            let Some(narinfo_blob) = self.get_narinfo(&id)? else {
+                continue;
+            };
+            let narinfo = NarInfo::parse(&String::from_utf8_lossy(&narinfo_blob))?;
+            let mut missing = Vec::new();
+            for reference in narinfo.get_dependencies() {
+                let reference_hash = reference.get_base_32_hash();
+                if !self.entry_exists(reference_hash)? {
+                    missing.push(reference.get_name().to_string());
+                } else if visited.insert(reference_hash.to_string()) {
+                    open.push_back(reference_hash.to_string());
+                }
+            }
+            if !missing.is_empty() {
+                incomplete.push((narinfo.store_path.get_name().to_string(), missing));
+            }
+        }
+        Ok(incomplete)
+    }
+
+    /// Reads the systems of the paths in a closure from their derivations on the first
+    /// daemon which can be reached. Systems of paths whose derivation is gone stay
+    /// unknown. If the ingest filters limit the systems, failing to read them is an
+    /// error rather than leaving systems unknown.
+    async fn resolve_systems(&self, closure: &mut Closure) -> Result<()> {
+        let candidates = match self.available_daemons() {
+            Ok(candidates) => candidates,
+            Err(e) if !self.filter.filters_systems() => {
+                warn!("Could not read the systems of a closure: {}", e);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let mut daemon = None;
+        for mut candidate in candidates {
+            if candidate.connect().await.is_ok() {
+                daemon = Some(candidate);
+                break;
+            }
+        }
+        let Some(mut daemon) = daemon else {
+            if self.filter.filters_systems() {
+                bail!("No Nix daemon can be reached to read the systems the ingest filters need");
+            }
+            return Ok(());
+        };
+        for entry in closure.values_mut() {
+            let Some(deriver) = &entry.deriver else {
+                continue;
+            };
+            match derivation_system(&mut daemon, deriver).await {
+                Ok(system) => entry.system = system,
+                Err(e) if self.filter.filters_systems() => {
+                    daemon.disconnect();
+                    return Err(e);
+                }
+                // Only the daemons to ask depend on the systems, and unknown systems
+                // are looked for on every daemon
+                Err(e) => {
+                    warn!(
+                        "Could not read the system of {}, asking every Nix daemon for the rest of the closure: {}",
+                        entry.path, e
+                    );
+                    break;
+                }
+            }
+        }
+        daemon.disconnect();
+        Ok(())
+    }
+
+    /// Removes the dependencies rejected by the ingest filters from a closure, so that
+    /// they are neither fetched nor recorded as commit parents.
+    async fn apply_filters(&self, package_path: &NixPath, closure: &mut Closure) -> Result<()> {
+        let mut rejected = HashSet::new();
+        for (hash, entry) in closure.iter() {
+            if hash == package_path.get_base_32_hash() {
+                continue;
+            }
+            if let Some(reason) = self.filter.rejects(
+                entry.path.get_name(),
+                entry.nar_size,
+                entry.system.as_deref(),
+            ) {
+                debug!("Skipping {}: {}", entry.path.get_name(), reason);
+                self.emit(Event::Skipped {
+                    path: entry.path.to_string(),
+                    reason,
+                });
+                rejected.insert(hash.clone());
+            }
+        }
+        if !rejected.is_empty() {
+            info!(
+                "Skipping {} packages rejected by the ingest filters",
+                rejected.len()
+            );
+        }
+        closure.retain(|hash, _| !rejected.contains(hash));
+        for entry in closure.values_mut() {
+            entry
+                .references
+                .retain(|r| !rejected.contains(r.get_base_32_hash()));
+        }
+        Ok(())
+    }
+
+    /// Adds the closure of a package bottom-up. The closure is traversed depth-first
+    /// with an explicit stack, so deep closures cannot overflow the call stack and
+    /// reference cycles are reported instead of being followed forever.
+    pub async fn _add_closure(
+        &self,
+        package_path: &NixPath,
+        closure: Option<&Closure>,
+        report: &mut DedupReport,
+        cancel: &CancellationToken,
+    ) -> Result<Option<Oid>> {
+        let mut commits: HashMap<String, Oid> = HashMap::new();
+        let mut fetched: HashMap<String, FetchedPackage> = HashMap::new();
+        // Packages whose dependencies are being added, i.e. the current path from the root
+        let mut in_progress: HashSet<String> = HashSet::new();
+        let mut stack = vec![package_path.clone()];
+
+        while let Some(path) = stack.last().cloned() {
+            if cancel.is_cancelled() {
+                bail!(
+                    "Cancelled adding the closure of {}",
+                    package_path.get_name()
+                );
+            }
+            let package_id = path.get_base_32_hash().to_string();
+            if commits.contains_key(&package_id) {
+                stack.pop();
+                continue;
+            }
+
+            if in_progress.contains(&package_id) {
+                // Second visit, all dependencies have been committed
+                let package = fetched
+                    .remove(&package_id)
+                    .ok_or_else(|| anyhow!("Package {} was not fetched", package_id))?;
+                let parent_commits: Vec<Oid> = package
+                    .dependencies
+                    .iter()
+                    .map(|d| commits[d.get_base_32_hash()])
+                    .collect();
+                let commit_oid = self.commit_package(&path, &package, &parent_commits)?;
+                report.add(path.get_name(), package.dedup);
+                in_progress.remove(&package_id);
+                commits.insert(package_id, commit_oid);
+                stack.pop();
+                continue;
+            }
+
+            let package = match fetched.remove(&package_id) {
+                Some(package) => package,
+                None => match cancel
+                    .run_until_cancelled(self.fetch_package(&path, closure))
+                    .await
+                    .ok_or_else(|| anyhow!("Cancelled fetching {}", path.get_name()))??
+                {
+                    None => return Ok(None),
+                    Some(Fetched::Committed(commit_oid)) => {
+                        commits.insert(package_id, commit_oid);
+                        stack.pop();
+                        continue;
+                    }
+                    Some(Fetched::Pending(package)) => package,
+                },
+            };
+
+            // Fetch the dependencies which are not stored yet concurrently
+            let mut missing = Vec::new();
+            for dependency in &package.dependencies {
+                let dependency_id = dependency.get_base_32_hash();
+                if in_progress.contains(dependency_id) {
+                    bail!(
+                        "Reference cycle between {} and {}",
+                        path.get_name(),
+                        dependency.get_name()
+                    );
+                }
+                if !commits.contains_key(dependency_id)
+                    && !fetched.contains_key(dependency_id)
+                    && !missing.contains(&dependency)
+                {
+                    missing.push(dependency);
+                }
+            }
+            let results = cancel
+                .run_until_cancelled(try_join_all(
+                    missing.iter().map(|d| self.fetch_package(d, closure)),
+                ))
+                .await
+                .ok_or_else(|| {
+                    anyhow!("Cancelled fetching the dependencies of {}", path.get_name())
+                })??;
+            for (dependency, result) in missing.iter().zip(results) {
+                let dependency_id = dependency.get_base_32_hash().to_string();
+                match result {
+                    None => return Ok(None),
+                    Some(Fetched::Committed(commit_oid)) => {
+                        commits.insert(dependency_id, commit_oid);
+                    }
+                    Some(Fetched::Pending(dependency_package)) => {
+                        fetched.insert(dependency_id, dependency_package);
                     }
-                    // TODO: do I need to add to open queue if references already exist?
-                    open.push_back(dep_hash.to_string());
-                    visited.insert(dep_hash.to_string());
                 }
             }
+
+            stack.extend(package.dependencies.iter().cloned());
+            in_progress.insert(package_id.clone());
+            fetched.insert(package_id, package);
+        }
+
+        Ok(commits.get(package_path.get_base_32_hash()).copied())
+    }
+
+    /// Looks up a single package locally, at the git peers and at the daemons. Packages
+    /// fetched from a daemon still have to be committed once their dependencies are.
+    async fn fetch_package(
+        &self,
+        package_path: &NixPath,
+        closure: Option<&Closure>,
+    ) -> Result<Option<Fetched>> {
+        let package_id = package_path.get_base_32_hash();
+        let closure_entry = closure.and_then(|c| c.get(package_id));
+        let system = closure_entry.and_then(|e| e.system.as_deref());
+
+        // Transfers compete with clients for the repository and the daemons
+        let waited = self.scheduler.yield_now(MAX_YIELD).await;
+        if !waited.is_zero() {
+            debug!(
+                "Waited {:?} for clients before fetching {}",
+                waited,
+                package_path.get_name()
+            );
+        }
+
+        // Check if commit already exists locally
+        if let Some(commit_oid) = self.get_commit(package_id) {
+            debug!("Package already exists: {}", package_path.get_name());
+            self.emit(Event::Skipped {
+                path: package_path.to_string(),
+                reason: "already stored".to_string(),
+            });
+            return Ok(Some(Fetched::Committed(commit_oid)));
+        }
+
+        // Ask Git peers if they have replicated the package
+        if let Some(commit_oid) = self.get_package_commit_from_git_remotes(package_path)? {
+            self.emit(Event::Committed {
+                path: package_path.to_string(),
+                commit: commit_oid,
+            });
+            return Ok(Some(Fetched::Committed(commit_oid)));
+        }
+
+        // Ask known Nix daemons if they can build the package
+        self.emit(Event::PackageStarted {
+            path: package_path.to_string(),
+        });
+        let DaemonPackage {
+            narinfo,
+            narinfo_blob_oid,
+            package_oid,
+            daemon,
+            transferred,
+            dedup,
+        } = match self
+            .get_package_from_nix_daemons(package_path, system)
+            .await
+        {
+            Ok(Some(package)) => package,
+            Ok(None) => {
+                self.emit(Event::Failed {
+                    path: package_path.to_string(),
+                    error: "no Nix daemon has the package".to_string(),
+                });
+                return Ok(None);
+            }
+            Err(e) => {
+                self.emit(Event::Failed {
+                    path: package_path.to_string(),
+                    error: e.to_string(),
+                });
+                return Ok(None);
+            }
+        };
+        self.emit(Event::BytesTransferred {
+            path: package_path.to_string(),
+            bytes: transferred,
+        });
+        let mut dependencies = match closure_entry {
+            Some(entry) => entry.references.clone(),
+            None => narinfo.get_dependencies().into_iter().cloned().collect(),
+        };
+        dependencies.retain(|d| d.get_base_32_hash() != package_id);
+        Ok(Some(Fetched::Pending(FetchedPackage {
+            narinfo_blob_oid,
+            package_oid,
+            dependencies,
+            provenance: Provenance::new(daemon, narinfo.deriver.as_ref()),
+            nar_hash: narinfo.nar_hash,
+            dedup,
+        })))
+    }
+
+    /// Describes a change of the given packages for the history, reading their
+    /// references. Removals have to be described before the references are deleted.
+    fn history_records(&self, change: Change, hashes: &[String]) -> Vec<Record> {
+        hashes
+            .iter()
+            .filter_map(|hash| {
+                Some(Record {
+                    change,
+                    hash: hash.clone(),
+                    commit: self.get_commit(hash)?,
+                    narinfo: self
+                        .repo
+                        .get_oid_from_reference(&self.get_narinfo_ref(hash))?,
+                })
+            })
+            .collect()
+    }
+
+    /// Appends records to the history. The change already took place, so a failure
+    /// is only logged.
+    fn record_history(&self, records: &[Record]) {
+        if records.is_empty() {
+            return;
+        }
+        let (name, email) = self.identity();
+        let message = history::format_message(records);
+        if let Err(e) = self
+            .repo
+            .append_to_log(HISTORY_REF, &message, (&name, &email))
+        {
+            warn!("Could not record {} in the history: {}", records[0], e);
+        }
+    }
+
+    fn commit_package(
+        &self,
+        package_path: &NixPath,
+        package: &FetchedPackage,
+        parent_commits: &[Oid],
+    ) -> Result<Oid> {
+        let package_id = package_path.get_base_32_hash();
+        let mut provenance = package.provenance.clone();
+        provenance
+            .findings
+            .extend(self.scan(package_path, package.package_oid)?);
+
+        // Commit the package tree and specify dependency commits as parents
+        let commit_oid = self.repo.commit(
+            package.package_oid,
+            parent_commits,
+            Some(package_path.get_name()),
+        )?;
+
+        let mut staged = StagedRefs::new(&self.repo, package_path.get_name());
+        if let Err(e) =
+            self.write_package_refs(&mut staged, package_id, package, &provenance, commit_oid)
+        {
+            staged.roll_back();
+            return Err(e);
+        }
+        self.record_added_package();
+        self.record_history(&self.history_records(Change::Added, &[package_id.to_string()]));
+        self.emit(Event::Committed {
+            path: package_path.to_string(),
+            commit: commit_oid,
+        });
+        Ok(commit_oid)
+    }
+
+    fn write_package_refs(
+        &self,
+        staged: &mut StagedRefs,
+        package_id: &str,
+        package: &FetchedPackage,
+        provenance: &Provenance,
+        commit_oid: Oid,
+    ) -> Result<()> {
+        // Add references: nix-hash -> package-commit-oid, nix-hash -> narinfo-blob-oid
+        staged.add(&self.get_result_ref(package_id), commit_oid)?;
+        staged.add(&self.get_narinfo_ref(package_id), package.narinfo_blob_oid)?;
+        staged.update(&self.get_nar_hash_ref(&package.nar_hash), commit_oid)?;
+        // Indexed by the narinfo like everywhere else, not by the provenance
+        let narinfo = self.repo.get_blob(package.narinfo_blob_oid)?;
+        if let Some(deriver) = Self::deriver_from_narinfo(&narinfo) {
+            staged.update(&derivers::deriver_ref(&deriver, package_id), commit_oid)?;
+        }
+        let provenance_oid = self
+            .repo
+            .add_file_content(provenance.to_json()?.as_bytes())?;
+        staged.update(&self.get_provenance_ref(package_id), provenance_oid)
+    }
+
+    /// Runs the scanners which apply to a package over the NAR of its tree. Fails
+    /// if one of them rejects the package, and returns what the ones flagging it
+    /// reported otherwise.
+    fn scan(&self, package_path: &NixPath, tree_oid: Oid) -> Result<Vec<String>> {
+        if self.scanners.is_empty() {
+            return Ok(Vec::new());
+        }
+        let repo = &self.repo;
+        let findings = self.scanners.scan(
+            package_path.get_path(),
+            package_path.get_name(),
+            &|writer| repo.write_nar(tree_oid, writer),
+        )?;
+        if let Some(rejection) = findings
+            .iter()
+            .find(|f| f.action == settings::ScanAction::Reject)
+        {
+            bail!(
+                "Package {} was rejected by scanner {}",
+                package_path.get_name(),
+                rejection
+            );
+        }
+        for finding in &findings {
+            warn!(
+                "Package {} was flagged by scanner {}",
+                package_path.get_name(),
+                finding
+            );
+        }
+        Ok(findings.iter().map(Finding::to_string).collect())
+    }
+
+    /// Scans a package fetched into quarantine, recording what flagging scanners
+    /// reported in its fetched provenance.
+    fn scan_fetched(&self, hash: &str) -> Result<()> {
+        if self.scanners.is_empty() {
+            return Ok(());
+        }
+        let narinfo = self.quarantined_narinfo(hash)?;
+        let commit_oid = self
+            .repo
+            .get_oid_from_reference(&quarantine_ref(hash, "result"))
+            .ok_or_else(|| anyhow!("Could not find the commit of {}", hash))?;
+        let findings = self.scan(&narinfo.store_path, self.repo.get_commit_tree(commit_oid)?)?;
+        if findings.is_empty() {
+            return Ok(());
         }
+        let provenance_ref = quarantine_ref(hash, "provenance");
+        let Some(oid) = self.repo.get_oid_from_reference(&provenance_ref) else {
+            return Ok(());
+        };
+        let mut provenance = Provenance::from_json(&self.repo.get_blob(oid)?)?;
+        provenance.findings.extend(findings);
+        let oid = self
+            .repo
+            .add_file_content(provenance.to_json()?.as_bytes())?;
+        self.repo.update_ref(&provenance_ref, oid)
+    }
+
+    /// Fetches a package from the first daemon which has it, skipping the daemons
+    /// which do not hold paths of its system if that is known.
+    pub async fn get_package_from_nix_daemons(
+        &self,
+        package_path: &NixPath,
+        system: Option<&str>,
+    ) -> Result<Option<DaemonPackage>> {
+        let mut daemons: Vec<DynNixDaemon> = self
+            .daemons_with_capabilities()?
+            .into_iter()
+            .filter(|(daemon, capabilities)| {
+                let holds = capabilities.holds(system);
+                if !holds {
+                    debug!(
+                        "Not asking {} for {}, which is built for {}",
+                        daemon.get_address(),
+                        package_path.get_name(),
+                        system.unwrap_or_default()
+                    );
+                }
+                holds
+            })
+            .map(|(daemon, _)| daemon)
+            .collect();
+        // Start with a different daemon for every package, so that fetching a closure
+        // is spread over all daemons which hold parts of it
+        if !daemons.is_empty() {
+            let start = self.next_daemon.fetch_add(1, Ordering::Relaxed) % daemons.len();
+            daemons.rotate_left(start);
+        }
+        for mut daemon in daemons {
+            // A daemon which keeps failing, e.g. because its sessions die, is skipped
+            // in favour of the next one. Objects it already wrote stay unreferenced
+            let package = with_reconnects(&mut daemon, async |daemon| {
+                self.fetch_from_daemon(daemon, package_path, system).await
+            })
+            .await;
+            match package {
+                Ok(Some(package)) => {
+                    daemon.disconnect();
+                    return Ok(Some(package));
+                }
+                Ok(None) => {}
+                Err(e) => warn!(
+                    "Fetching {} from the Nix daemon at {} failed: {}",
+                    package_path.get_name(),
+                    daemon.get_address(),
+                    e
+                ),
+            }
+        }
+        Ok(None)
+    }
+
+    async fn fetch_from_daemon(
+        &self,
+        daemon: &mut DynNixDaemon,
+        package_path: &NixPath,
+        system: Option<&str>,
+    ) -> Result<Option<DaemonPackage>> {
+        // Ask if daemon has the package
+        // TODO: ask it to build the package if it does not have it
+        let Some(path_info) = daemon.get_pathinfo(package_path).await? else {
+            return Ok(None);
+        };
+        // Another store path, e.g. the same output after a rebuild, may have the same
+        // contents. Its tree is reused instead of transferring the NAR again
+        let nar_hash = format_nar_hash(&path_info.nar_hash)?;
+        let (package_oid, transferred, dedup) = match self.find_tree_by_nar_hash(&nar_hash)? {
+            Some(tree_oid) => {
+                debug!(
+                    "Reusing stored tree {} for {}",
+                    tree_oid,
+                    package_path.get_name()
+                );
+                // Nothing was decoded, the size of the NAR stands in for its contents
+                let dedup = Dedup {
+                    new_bytes: 0,
+                    shared_bytes: path_info.nar_size,
+                };
+                (tree_oid, 0, dedup)
+            }
+            None => {
+                // Add the package contents to the Git database
+                let clone = self.repo.clone();
+                let (package_oid, dedup) = daemon
+                    .fetch(package_path, move |r| {
+                        let (oid, _, dedup) = clone.add_nar(r)?;
+                        Ok((oid, dedup))
+                    })
+                    .await?;
+                (package_oid, path_info.nar_size, dedup)
+            }
+        };
+
+        // Get metadata info about the package and add it to the Git database
+        let narinfo = self
+            .build_narinfo(
+                daemon,
+                package_oid.to_string().as_str(),
+                package_path,
+                system,
+            )
+            .await?;
+        let narinfo_blob_oid = self.repo.add_file_content(narinfo.to_string().as_bytes())?;
+
+        match daemon {
+            DynNixDaemon::Local(_) => {
+                debug!("Using local daemon, fetched {} ", package_path.get_name())
+            }
+            DynNixDaemon::Remote(daemon) => debug!(
+                "Using daemon at {}, fetched package {}",
+                daemon.get_address(),
+                package_path.get_name()
+            ),
+        }
+        Ok(Some(DaemonPackage {
+            narinfo,
+            narinfo_blob_oid,
+            package_oid,
+            daemon: daemon.get_address(),
+            transferred,
+            dedup,
+        }))
+    }
+
+    fn get_package_commit_from_git_remotes(&self, store_path: &NixPath) -> Result<Option<Oid>> {
+        let package_id = store_path.get_base_32_hash();
+        let mut commit_oid = None;
+        let mut success_remote = "";
+        for remote_url in &self.settings.remotes {
+            let url = remote_url.as_str();
+            let filter = self.peer_filter(url);
+            // Sizes and systems are only known once the narinfo was fetched
+            if let Some(reason) = filter.and_then(|f| f.rejects(store_path.get_name(), 0, None)) {
+                debug!(
+                    "Not fetching {} from git peer {}: {}",
+                    store_path.get_name(),
+                    url,
+                    reason
+                );
+                continue;
+            }
+            if let Some(oid) = self.fetch_from_remote(package_id, url, filter)? {
+                debug!(
+                    "Using git peer at {}, fetched package {}",
+                    remote_url,
+                    store_path.get_name()
+                );
+                commit_oid = Some(oid);
+                success_remote = url;
+                break;
+            }
+        }
+        if commit_oid == None {
+            return Ok(None);
+        }
+
+        let mut fetched = vec![package_id.to_string()];
+        let filter = (!self.filter.is_empty()).then_some(&self.filter);
+        if let Err(e) =
+            self.fetch_dependencies_from_remote(package_id, success_remote, filter, &mut fetched)
+        {
+            self.roll_back_fetched(&fetched);
+            return Err(e);
+        }
+        self.record_history(&self.history_records(Change::Added, &fetched));
+
+        Ok(commit_oid)
+    }
+
+    /// Fetches a package and everything it references from a git peer.
+    pub fn fetch_closure(&self, package_id: &str, remote: &str) -> Result<()> {
+        let mut fetched = Vec::new();
+        if self.fetch_from_remote(package_id, remote, None)?.is_some() {
+            fetched.push(package_id.to_string());
+        } else if !self.entry_exists(package_id)? {
+            bail!("Package {} is not available at {}", package_id, remote);
+        }
+        if let Err(e) = self.fetch_dependencies_from_remote(package_id, remote, None, &mut fetched)
+        {
+            self.roll_back_fetched(&fetched);
+            return Err(e);
+        }
+        self.record_history(&self.history_records(Change::Added, &fetched));
+        Ok(())
+    }
+
+    /// Fetches the packages a git peer holds, along with their dependencies, to set
+    /// up a new mirror of it. Only packages `filter` lets through are picked, for
+    /// which the narinfos are fetched and looked at first. The picked packages and
+    /// their dependencies are then fetched at once. Packages are verified like any
+    /// package fetched from a peer, and those which fail, or whose dependencies
+    /// fail, are reported rather than failing the whole clone.
+    pub fn clone_from(&self, remote: &str, filter: &IngestFilter) -> Result<CloneSummary> {
+        let references: HashSet<String> = self
+            .repo
+            .list_remote_references(remote)?
+            .into_iter()
+            .collect();
+        let mut hashes: Vec<String> = references
+            .iter()
+            .filter_map(|r| r.strip_prefix("refs/")?.strip_suffix("/narinfo"))
+            .filter(|hash| is_store_hash(hash))
+            .filter(|hash| references.contains(&format!("refs/{hash}/result")))
+            .map(str::to_string)
+            .collect();
+        hashes.sort();
+        let mut summary = CloneSummary {
+            available: hashes.len(),
+            cloned: 0,
+            present: 0,
+            skipped: 0,
+            failed: Vec::new(),
+        };
+        let narinfos = self.fetch_remote_narinfos(remote)?;
+        if !filter.is_empty() {
+            hashes.retain(|hash| {
+                let Some(narinfo) = narinfos.get(hash) else {
+                    return false;
+                };
+                let name = narinfo.store_path.get_name();
+                match filter.rejects(name, narinfo.nar_size, narinfo.system.as_deref()) {
+                    Some(reason) => {
+                        debug!("Not cloning {}: {}", name, reason);
+                        false
+                    }
+                    None => true,
+                }
+            });
+            summary.skipped = summary.available - hashes.len();
+        }
+
+        // The picked packages and their dependencies, the latter first
+        let mut wanted = Vec::new();
+        let mut visited = HashSet::new();
+        for hash in &hashes {
+            if self.entry_exists(hash)? {
+                summary.present += 1;
+                continue;
+            }
+            Self::dependencies_first(hash, &narinfos, &mut visited, &mut wanted);
+        }
+        let mut held = HashSet::new();
+        let mut refspecs = Vec::new();
+        for hash in &wanted {
+            if self.entry_exists(hash)? {
+                continue;
+            }
+            if !self.quarantined_refs(hash)?.is_empty() {
+                debug!("Package {} is held in quarantine for review", hash);
+                held.insert(hash.clone());
+                continue;
+            }
+            refspecs.push(format!(
+                "{}/*:{}",
+                self.get_package_ref(hash),
+                quarantine_ref(hash, "*")
+            ));
+        }
+        info!(
+            "Cloning {} packages from {}, fetching {} with their dependencies",
+            hashes.len() - summary.present,
+            remote,
+            refspecs.len()
+        );
+        self.repo.fetch_refspecs(remote, &refspecs)?;
+
+        let mut added = Vec::new();
+        for hash in &wanted {
+            if self.entry_exists(hash)? || held.contains(hash) {
+                continue;
+            }
+            let dependencies_stored = match narinfos.get(hash) {
+                Some(narinfo) => narinfo
+                    .get_dependencies()
+                    .iter()
+                    .map(|d| self.entry_exists(d.get_base_32_hash()))
+                    .collect::<Result<Vec<_>>>()?
+                    .into_iter()
+                    .all(|stored| stored),
+                None => false,
+            };
+            let admitted = dependencies_stored
+                && self.check_quarantined(hash, remote).unwrap_or_else(|e| {
+                    warn!("Could not clone {} from {}: {}", hash, remote, e);
+                    false
+                });
+            if admitted {
+                self.release_from_quarantine(hash)?;
+                added.push(hash.clone());
+            } else if !dependencies_stored {
+                debug!("Not cloning {}, a dependency could not be fetched", hash);
+                self.drop_quarantined_refs(hash)?;
+            }
+        }
+        self.record_history(&self.history_records(Change::Added, &added));
+        for hash in &hashes {
+            if added.contains(hash) {
+                summary.cloned += 1;
+            } else if !self.entry_exists(hash)? {
+                warn!("Could not clone {} from {}", hash, remote);
+                summary.failed.push(hash.clone());
+            }
+        }
+        Ok(summary)
+    }
+
+    /// Appends `hash` to `order` after those of its dependencies which are not
+    /// visited yet, going by the narinfos of a peer.
+    fn dependencies_first(
+        hash: &str,
+        narinfos: &BTreeMap<String, NarInfo>,
+        visited: &mut HashSet<String>,
+        order: &mut Vec<String>,
+    ) {
+        if !visited.insert(hash.to_string()) {
+            return;
+        }
+        if let Some(narinfo) = narinfos.get(hash) {
+            for dependency in narinfo.get_dependencies() {
+                Self::dependencies_first(dependency.get_base_32_hash(), narinfos, visited, order);
+            }
+        }
+        order.push(hash.to_string());
+    }
+
+    /// Fetches the narinfos of the packages a git peer holds, by their hashes. They
+    /// are fetched below `refs/clone` as `<hash>.narinfo` (see
+    /// `snapshot::package_ref`), which is cleaned up afterwards and before, in case
+    /// an earlier clone was interrupted.
+    fn fetch_remote_narinfos(&self, remote: &str) -> Result<BTreeMap<String, NarInfo>> {
+        let drop_clone_refs = || -> Result<()> {
+            for reference in self.repo.list_references("refs/clone/*")? {
+                self.repo.delete_ref(&reference)?;
+            }
+            Ok(())
+        };
+        drop_clone_refs()?;
+        let result = self
+            .repo
+            .fetch_into(remote, "refs/*/narinfo", "refs/clone/*.narinfo")
+            .and_then(|_| {
+                let mut narinfos = BTreeMap::new();
+                for (reference, oid) in self.repo.list_reference_targets("refs/clone/*")? {
+                    let Some(hash) = reference
+                        .strip_prefix("refs/clone/")
+                        .and_then(|name| name.strip_suffix(".narinfo"))
+                        .filter(|hash| is_store_hash(hash))
+                    else {
+                        continue;
+                    };
+                    let narinfo =
+                        NarInfo::parse(&String::from_utf8_lossy(&self.repo.get_blob(oid)?))?;
+                    narinfos.insert(hash.to_string(), narinfo);
+                }
+                Ok(narinfos)
+            });
+        drop_clone_refs()?;
+        result
+    }
+
+    /// Removes the references of packages fetched from a git peer whose closure could
+    /// not be completed. Their objects are pruned by the next garbage collection.
+    fn roll_back_fetched(&self, hashes: &[String]) {
+        for hash in hashes {
+            if let Err(e) = self.delete_deriver_ref(hash) {
+                warn!(
+                    "Could not remove {} from the index of derivers: {}",
+                    hash, e
+                );
+            }
+            let references = match self
+                .repo
+                .list_references(&format!("{}/*", self.get_package_ref(hash)))
+            {
+                Ok(references) => references,
+                Err(e) => {
+                    warn!("Could not roll back the fetched package {}: {}", hash, e);
+                    continue;
+                }
+            };
+            for reference in references {
+                match self.repo.delete_ref(&reference) {
+                    Ok(()) => warn!("Rolled back {} fetched from a git peer", reference),
+                    Err(e) => warn!("Could not roll back {}: {}", reference, e),
+                }
+            }
+        }
+    }
+
+    /// Fetches the missing dependencies of a package, recording the fetched ones in
+    /// `fetched`. Dependencies `filter` rejects are skipped along with their own
+    /// dependencies, see `incomplete_packages`.
+    fn fetch_dependencies_from_remote(
+        &self,
+        package_id: &str,
+        remote: &str,
+        filter: Option<&IngestFilter>,
+        fetched: &mut Vec<String>,
+    ) -> Result<()> {
+        let mut open = VecDeque::new();
+        let mut visited = HashSet::new();
+        open.push_back(package_id.to_string());
+        visited.insert(package_id.to_string());
+        while let Some(id) = open.pop_front() {
+            for dep in self.get_dep_ids(&id)? {
+                let dep_hash = dep.get_base_32_hash();
+                if !visited.contains(dep_hash) {
+                    if !(self.repo.reference_exists(&self.get_result_ref(dep_hash))?
+                        && self
+                            .repo
+                            .reference_exists(&self.get_narinfo_ref(dep_hash))?)
+                    {
+                        if let Some(filter) = filter
+                            && let Some(reason) = self.peer_rejection(dep_hash, remote, filter)?
+                        {
+                            debug!("Skipping {}: {}", dep.get_name(), reason);
+                            self.emit(Event::Skipped {
+                                path: dep.to_string(),
+                                reason,
+                            });
+                            visited.insert(dep_hash.to_string());
+                            continue;
+                        }
+                        if self.fetch_from_remote(dep_hash, remote, None)?.is_none() {
+                            bail!("Dependency {} is not available at {}", dep, remote);
+                        }
+                        fetched.push(dep_hash.to_string());
+                        debug!(
+                            "Using git peer at {}, fetched package {}",
+                            remote,
+                            dep.get_name()
+                        );
+                    }
+                    // TODO: do I need to add to open queue if references already exist?
+                    open.push_back(dep_hash.to_string());
+                    visited.insert(dep_hash.to_string());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fetches only the narinfo of a package from a git peer and returns why `filter`
+    /// rejects the package, if it does. The narinfo is not kept. Packages the peer
+    /// doesn't have are not rejected, that is up to the fetch of the package.
+    fn peer_rejection(
+        &self,
+        package_id: &str,
+        remote: &str,
+        filter: &IngestFilter,
+    ) -> Result<Option<String>> {
+        if !self.quarantined_refs(package_id)?.is_empty() {
+            return Ok(None);
+        }
+        self.repo.fetch_into(
+            remote,
+            &self.get_narinfo_ref(package_id),
+            &quarantine_ref(package_id, "narinfo"),
+        )?;
+        if !self
+            .repo
+            .reference_exists(&quarantine_ref(package_id, "narinfo"))?
+        {
+            return Ok(None);
+        }
+        let narinfo = self.quarantined_narinfo(package_id);
+        self.drop_quarantined_refs(package_id)?;
+        let narinfo = narinfo?;
+        Ok(filter.rejects(
+            narinfo.store_path.get_name(),
+            narinfo.nar_size,
+            narinfo.system.as_deref(),
+        ))
+    }
+
+    /// Fetches a package from a git peer into quarantine and only exposes it once
+    /// its contents match the NAR hash and size of its narinfo, and, if trusted keys
+    /// are configured, its narinfo is signed by one of them. Rejected packages are
+    /// reported and either held for review or dropped. Packages `filter` rejects are
+    /// dropped without further ado.
+    fn fetch_from_remote(
+        &self,
+        package_id: &str,
+        remote: &str,
+        filter: Option<&IngestFilter>,
+    ) -> Result<Option<Oid>> {
+        if !self.fetch_into_quarantine(package_id, remote, filter)? {
+            return Ok(None);
+        }
+        self.release_from_quarantine(package_id)?;
+
+        let oid = self
+            .get_commit(package_id)
+            .ok_or_else(|| anyhow!("Could not get commit id for {}", package_id))?;
+        Ok(Some(oid))
+    }
+
+    /// Fetches a package from a git peer into quarantine and checks it like
+    /// `fetch_from_remote`. Returns whether the package in quarantine may be
+    /// released.
+    fn fetch_into_quarantine(
+        &self,
+        package_id: &str,
+        remote: &str,
+        filter: Option<&IngestFilter>,
+    ) -> Result<bool> {
+        let held = self.quarantined_refs(package_id)?;
+        if !held.is_empty() {
+            debug!("Package {} is held in quarantine for review", package_id);
+            return Ok(false);
+        }
+        // Only the narinfo tells the size and system, so the contents are fetched once
+        // it passed the filter
+        if let Some(filter) = filter
+            && let Some(reason) = self.peer_rejection(package_id, remote, filter)?
+        {
+            debug!(
+                "Not fetching {} from git peer {}: {}",
+                package_id, remote, reason
+            );
+            return Ok(false);
+        }
+        self.repo.fetch_into(
+            remote,
+            &format!("{}/*", self.get_package_ref(package_id)),
+            &quarantine_ref(package_id, "*"),
+        )?;
+        self.check_quarantined(package_id, remote)
+    }
+
+    /// Checks a package fetched from a git peer into quarantine, see
+    /// `fetch_from_remote`. Returns whether it may be released.
+    fn check_quarantined(&self, package_id: &str, remote: &str) -> Result<bool> {
+        if !self
+            .repo
+            .reference_exists(&quarantine_ref(package_id, "result"))?
+        {
+            self.drop_quarantined_refs(package_id)?;
+            return Ok(false);
+        }
+
+        let verification = match self.verify_fetched(package_id) {
+            Ok(verification) => verification,
+            Err(e) => {
+                self.drop_quarantined_refs(package_id)?;
+                return Err(e);
+            }
+        };
+        if !verification.is_valid() {
+            let path = self
+                .quarantined_store_path(package_id)
+                .unwrap_or_else(|| package_id.to_string());
+            let hold = self.settings.quarantine_rejected
+                || (self.settings.review_unsigned
+                    && verification == Verification::UntrustedSignature);
+            let error = if hold {
+                warn!(
+                    "Holding package {} fetched from {} in quarantine: {}",
+                    package_id, remote, verification
+                );
+                format!("held in quarantine, fetched from {remote}: {verification}")
+            } else {
+                warn!(
+                    "Rejected package {} fetched from {}: {}",
+                    package_id, remote, verification
+                );
+                self.drop_quarantined_refs(package_id)?;
+                format!("rejected from {remote}: {verification}")
+            };
+            self.emit(Event::Failed { path, error });
+            return Ok(false);
+        }
+        if let Err(e) = self.scan_fetched(package_id) {
+            warn!(
+                "Rejected package {} fetched from {}: {}",
+                package_id, remote, e
+            );
+            let path = self
+                .quarantined_store_path(package_id)
+                .unwrap_or_else(|| package_id.to_string());
+            self.drop_quarantined_refs(package_id)?;
+            self.emit(Event::Failed {
+                path,
+                error: format!("rejected from {remote}: {e}"),
+            });
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Checks a package in quarantine like `verify`, and, if trusted keys are
+    /// configured or `store.review_unsigned` is set, whether its narinfo is signed
+    /// by one of them.
+    fn verify_fetched(&self, hash: &str) -> Result<Verification> {
+        let verification = self.verify_entry(&quarantine_ref(hash, "narinfo"))?;
+        if !verification.is_valid()
+            || (self.trusted_keys.is_empty() && !self.settings.review_unsigned)
+        {
+            return Ok(verification);
+        }
+        Ok(if self.is_trusted(&self.quarantined_narinfo(hash)?)? {
+            verification
+        } else {
+            Verification::UntrustedSignature
+        })
+    }
+
+    fn quarantined_refs(&self, hash: &str) -> Result<Vec<String>> {
+        self.repo.list_references(&quarantine_ref(hash, "*"))
+    }
+
+    fn quarantined_narinfo(&self, hash: &str) -> Result<NarInfo> {
+        let narinfo_oid = self
+            .repo
+            .get_oid_from_reference(&quarantine_ref(hash, "narinfo"))
+            .ok_or_else(|| anyhow!("Could not find narinfo for {}", hash))?;
+        NarInfo::parse(&String::from_utf8_lossy(&self.repo.get_blob(narinfo_oid)?))
+    }
+
+    fn quarantined_store_path(&self, hash: &str) -> Option<String> {
+        let narinfo_oid = self
+            .repo
+            .get_oid_from_reference(&quarantine_ref(hash, "narinfo"))?;
+        let narinfo = self.repo.get_blob(narinfo_oid).ok()?;
+        NarInfo::field(&String::from_utf8_lossy(&narinfo), "StorePath").map(str::to_string)
+    }
+
+    fn drop_quarantined_refs(&self, hash: &str) -> Result<()> {
+        for reference in self.quarantined_refs(hash)? {
+            self.repo.delete_ref(&reference)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the references of a package in quarantine to where it is served from.
+    fn release_from_quarantine(&self, hash: &str) -> Result<()> {
+        for reference in self.quarantined_refs(hash)? {
+            let Some((_, kind)) = parse_quarantine_ref(&reference) else {
+                continue;
+            };
+            let mut oid = self
+                .repo
+                .get_oid_from_reference(&reference)
+                .ok_or_else(|| anyhow!("Could not resolve {}", reference))?;
+            if kind == "narinfo" {
+                oid = self.apply_narinfo_fields_to_blob(oid)?;
+            }
+            self.repo
+                .update_ref(&format!("{}/{kind}", self.get_package_ref(hash)), oid)?;
+            self.repo.delete_ref(&reference)?;
+        }
+        self.index_deriver(hash)
+    }
+
+    /// Adds a stored package to the index of derivers, for packages whose references
+    /// were not written by `write_package_refs`.
+    fn index_deriver(&self, hash: &str) -> Result<()> {
+        let (Some(commit_oid), Some(narinfo)) = (self.get_commit(hash), self.get_narinfo(hash)?)
+        else {
+            return Ok(());
+        };
+        match Self::deriver_from_narinfo(&narinfo) {
+            Some(deriver) => self
+                .repo
+                .update_ref(&derivers::deriver_ref(&deriver, hash), commit_oid),
+            None => Ok(()),
+        }
+    }
+
+    /// Removes a package from the index of derivers.
+    fn delete_deriver_ref(&self, hash: &str) -> Result<()> {
+        let Some(deriver) = self
+            .get_narinfo(hash)?
+            .and_then(|narinfo| Self::deriver_from_narinfo(&narinfo))
+        else {
+            return Ok(());
+        };
+        let deriver_ref = derivers::deriver_ref(&deriver, hash);
+        if self.repo.reference_exists(&deriver_ref)? {
+            self.repo.delete_ref(&deriver_ref)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the store paths of the stored outputs of a derivation, given as a
+    /// store path, file name or hash.
+    pub fn derivation_outputs(&self, deriver: &str) -> Result<Vec<String>> {
+        let deriver_hash = derivers::deriver_hash(deriver)?;
+        let mut outputs = Vec::new();
+        for reference in self
+            .repo
+            .list_references(&format!("{DERIVERS_NAMESPACE}/{deriver_hash}/*"))?
+        {
+            let Some((_, hash)) = derivers::parse_deriver_ref(&reference) else {
+                continue;
+            };
+            // The index may lag behind packages whose references were deleted directly
+            let Some(narinfo) = self.get_narinfo(hash)? else {
+                continue;
+            };
+            if let Some(store_path) =
+                NarInfo::field(&String::from_utf8_lossy(&narinfo), "StorePath")
+            {
+                outputs.push(store_path.to_string());
+            }
+        }
+        outputs.sort();
+        Ok(outputs)
+    }
+
+    /// Returns the store paths of all outputs of a derivation, given as a store path,
+    /// file name or hash, and fails naming the outputs which are not stored. As the
+    /// index only knows the stored outputs, the derivation is read, from disk or
+    /// from a daemon.
+    pub async fn all_derivation_outputs(&self, deriver: &str) -> Result<Vec<String>> {
+        let stored = self.derivation_outputs(deriver)?;
+        let drv_path = if derivers::deriver_hash(deriver)? == deriver {
+            // Only a hash was given, the narinfos of its outputs name the derivation
+            let Some(output) = stored.first() else {
+                bail!("No outputs of {} are in the cache", deriver);
+            };
+            let narinfo = self
+                .get_narinfo(NixPath::new(output)?.get_base_32_hash())?
+                .unwrap_or_default();
+            let narinfo = String::from_utf8_lossy(&narinfo);
+            NixPath::new(
+                NarInfo::field(&narinfo, "Deriver")
+                    .ok_or_else(|| anyhow!("{} names no deriver", output))?,
+            )?
+        } else {
+            NixPath::new(deriver)?
+        };
+        let drv = self.derivation_content(&drv_path).await?;
+        let outputs = derivation::parse_outputs(&drv)
+            .ok_or_else(|| anyhow!("Could not read the outputs of {}", drv_path))?;
+        let drv_name = drv_path.get_name().trim_end_matches(".drv");
+        let mut paths = Vec::new();
+        let mut missing = Vec::new();
+        for (name, path) in outputs {
+            let found = if path.is_empty() {
+                // Content-addressed outputs are only known by their name until built
+                let output_name = match name {
+                    "out" => drv_name.to_string(),
+                    name => format!("{drv_name}-{name}"),
+                };
+                stored
+                    .iter()
+                    .find(|p| NixPath::new(p).is_ok_and(|p| p.get_name() == output_name))
+                    .cloned()
+            } else {
+                let path = NixPath::new(path)?;
+                self.entry_exists(path.get_base_32_hash())?
+                    .then(|| path.get_path().to_string())
+            };
+            match found {
+                Some(path) => paths.push(path),
+                None => missing.push(name),
+            }
+        }
+        if !missing.is_empty() {
+            bail!(
+                "Outputs {} of {} are not in the cache",
+                missing.join(", "),
+                drv_path
+            );
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// Lists the packages held in quarantine along with why they were held.
+    pub fn quarantined(&self) -> Result<Vec<QuarantinedPackage>> {
+        let hashes: BTreeSet<String> = self
+            .repo
+            .list_references(&format!("{QUARANTINE_NAMESPACE}/*"))?
+            .iter()
+            .filter_map(|r| parse_quarantine_ref(r))
+            .map(|(hash, _)| hash.to_string())
+            .collect();
+        hashes
+            .into_iter()
+            .map(|hash| {
+                Ok(QuarantinedPackage {
+                    store_path: self.quarantined_store_path(&hash),
+                    verification: self.verify_fetched(&hash)?,
+                    hash,
+                })
+            })
+            .collect()
+    }
+
+    /// Serves a package held in quarantine after an operator reviewed it. Its
+    /// contents still have to match its narinfo and its dependencies have to be
+    /// stored, but it does not need to be signed by a trusted key. The narinfo is
+    /// signed again with our own key.
+    pub fn promote(&self, hash: &str) -> Result<()> {
+        if self.quarantined_refs(hash)?.is_empty() {
+            bail!("Package {} is not in quarantine", hash);
+        }
+        if self.entry_exists(hash)? {
+            bail!("Package {} is already in the cache, drop it instead", hash);
+        }
+        let verification = self.verify_entry(&quarantine_ref(hash, "narinfo"))?;
+        if !verification.is_valid() {
+            bail!("Package {} can't be promoted: {}", hash, verification);
+        }
+        let narinfo = self.quarantined_narinfo(hash)?;
+        for dependency in narinfo.get_dependencies() {
+            if !self.entry_exists(dependency.get_base_32_hash())? {
+                bail!(
+                    "Dependency {} of {} is not in the cache, add or promote it first",
+                    dependency,
+                    hash
+                );
+            }
+        }
+        self.release_from_quarantine(hash)?;
+        self.sign_again(hash)?;
+        self.record_history(&self.history_records(Change::Added, &[hash.to_string()]));
+        info!(
+            "Promoted package {} from quarantine",
+            narinfo.store_path.get_name()
+        );
+        Ok(())
+    }
+
+    /// Removes a package from quarantine. Its objects are pruned by the next garbage
+    /// collection.
+    pub fn drop_quarantined(&self, hash: &str) -> Result<()> {
+        if self.quarantined_refs(hash)?.is_empty() {
+            bail!("Package {} is not in quarantine", hash);
+        }
+        self.drop_quarantined_refs(hash)?;
+        info!("Dropped package {} from quarantine", hash);
+        Ok(())
+    }
+
+    fn get_dep_ids(&self, package_id: &str) -> Result<Vec<NixPath>> {
+        let narinfo_blob = self
+            .get_narinfo(package_id)?
+            .ok_or_else(|| anyhow!("Could not find narinfo for {}", package_id))?;
+        let narinfo = NarInfo::parse(&String::from_utf8_lossy(&narinfo_blob).to_string())?;
+        let dependencies = narinfo.get_dependencies();
+        Ok(dependencies.into_iter().cloned().collect())
+    }
+
+    /// Returns the narinfos of a stored package and of everything it references,
+    /// starting with the package itself.
+    pub fn closure(&self, hash: &str) -> Result<Vec<NarInfo>> {
+        let mut closure = Vec::new();
+        let mut open = VecDeque::from([hash.to_string()]);
+        let mut visited = HashSet::from([hash.to_string()]);
+        while let Some(id) = open.pop_front() {
+            let narinfo_blob = self
+                .get_narinfo(&id)?
+                .ok_or_else(|| anyhow!("Could not find narinfo for {}", id))?;
+            let narinfo = NarInfo::parse(&String::from_utf8_lossy(&narinfo_blob))?;
+            for reference in &narinfo.references {
+                let reference_hash = reference.get_base_32_hash();
+                if visited.insert(reference_hash.to_string()) {
+                    open.push_back(reference_hash.to_string());
+                }
+            }
+            closure.push(narinfo);
+        }
+        Ok(closure)
+    }
+
+    /// Copies the closures of `hashes` to the gachix repository at `destination`.
+    /// Packages the destination already holds are not transferred again, nor are
+    /// those which the filters of the destination reject, see `store.peer_filters`.
+    pub fn copy_to(&self, hashes: &[String], destination: &str) -> Result<CopySummary> {
+        // Local pushes into non-bare repositories are not supported by libgit2, so
+        // objects are handed over directly when the destination is a local repository
+        let local_destination = if Path::new(destination).is_dir() {
+            Some(GitRepo::new(Path::new(destination))?)
+        } else {
+            None
+        };
+        let destination_refs = match &local_destination {
+            Some(repo) => repo.list_references("refs/*/narinfo")?,
+            None => self.repo.list_remote_references(destination)?,
+        };
+        let present: HashSet<String> = destination_refs
+            .iter()
+            .filter(|r| r.ends_with("/narinfo"))
+            .filter_map(|r| r.split('/').nth(1))
+            .map(str::to_string)
+            .collect();
+
+        let mut visited = HashSet::new();
+        let mut missing = Vec::new();
+        let mut summary = CopySummary {
+            copied: 0,
+            present: 0,
+            skipped: 0,
+        };
+        let filter = self.peer_filter(destination);
+        for hash in hashes {
+            let closure = self.closure(hash)?;
+            if let Some(filter) = filter
+                && let Some(reason) = filter.rejects(
+                    closure[0].store_path.get_name(),
+                    closure[0].nar_size,
+                    closure[0].system.as_deref(),
+                )
+            {
+                debug!("Not copying {} to {}: {}", hash, destination, reason);
+                summary.skipped += 1;
+                continue;
+            }
+            for narinfo in closure {
+                let package_hash = narinfo.store_path.get_base_32_hash().to_string();
+                if !visited.insert(package_hash.clone()) {
+                    continue;
+                }
+                if present.contains(&package_hash) {
+                    summary.present += 1;
+                } else {
+                    missing.push(package_hash);
+                }
+            }
+        }
+        summary.copied = missing.len();
+        if missing.is_empty() {
+            return Ok(summary);
+        }
+        info!("Copying {} packages to {}", missing.len(), destination);
+
+        let mut references = Vec::new();
+        for hash in &missing {
+            for reference in [self.get_result_ref(hash), self.get_narinfo_ref(hash)] {
+                let oid = self
+                    .repo
+                    .get_oid_from_reference(&reference)
+                    .ok_or_else(|| anyhow!("Could not find reference {}", reference))?;
+                references.push((reference, oid));
+            }
+            for reference in [self.get_provenance_ref(hash), self.get_ipfs_ref(hash)] {
+                if let Some(oid) = self.repo.get_oid_from_reference(&reference) {
+                    references.push((reference, oid));
+                }
+            }
+            references.extend(self.index_refs(hash)?);
+        }
+        match local_destination {
+            Some(destination_repo) => {
+                let known: Vec<Oid> = present.iter().filter_map(|h| self.get_commit(h)).collect();
+                let oids: Vec<Oid> = references.iter().map(|(_, oid)| *oid).collect();
+                self.repo.transfer_to(&destination_repo, &oids, &known)?;
+                for (reference, oid) in &references {
+                    destination_repo.update_ref(reference, *oid)?;
+                }
+            }
+            None => {
+                let refspecs: Vec<String> = references
+                    .iter()
+                    .map(|(reference, _)| format!("+{reference}:{reference}"))
+                    .collect();
+                self.repo.push(destination, &refspecs)?;
+            }
+        }
+        Ok(summary)
+    }
+
+    /// The references looking up a package by its NAR hash and by its deriver,
+    /// which point at its commit.
+    fn index_refs(&self, hash: &str) -> Result<Vec<(String, Oid)>> {
+        let (Some(commit_oid), Some(narinfo)) = (self.get_commit(hash), self.get_narinfo(hash)?)
+        else {
+            return Ok(Vec::new());
+        };
+        let mut references = Vec::new();
+        if let Some(nar_hash) = NarInfo::field(&String::from_utf8_lossy(&narinfo), "NarHash") {
+            references.push(self.get_nar_hash_ref(nar_hash));
+        }
+        if let Some(deriver) = Self::deriver_from_narinfo(&narinfo) {
+            references.push(derivers::deriver_ref(&deriver, hash));
+        }
+        Ok(references
+            .into_iter()
+            .filter(|reference| self.repo.get_oid_from_reference(reference) == Some(commit_oid))
+            .map(|reference| (reference, commit_oid))
+            .collect())
+    }
+
+    /// Moves the packages `options` selects to the gachix repository at
+    /// `destination`, which is created if it does not exist, along with their
+    /// dependencies. Dependencies which packages staying here still need are copied
+    /// but kept. Objects the destination holds already are not copied again.
+    pub fn split(&self, options: &ListOptions, destination: &Path) -> Result<SplitSummary> {
+        let selected: Vec<String> = self
+            .list_entries(options)?
+            .into_iter()
+            .map(|entry| entry.hash)
+            .collect();
+        if selected.is_empty() {
+            bail!("No packages match");
+        }
+        let target = self.with_path(destination)?;
+        let destination = destination
+            .to_str()
+            .ok_or_else(|| anyhow!("Invalid path {}", destination.display()))?;
+        let copy = self.copy_to(&selected, destination)?;
+
+        // Packages the filters of the destination skipped stay here
+        let mut moved = HashSet::new();
+        for hash in &selected {
+            for narinfo in self.closure(hash)? {
+                let hash = narinfo.store_path.get_base_32_hash();
+                if target.entry_exists(hash)? {
+                    moved.insert(hash.to_string());
+                }
+            }
+        }
+        // Moved packages which packages staying here reference have to stay as well
+        let mut open: VecDeque<String> = self
+            .list_entries(&ListOptions::default())?
+            .into_iter()
+            .map(|entry| entry.hash)
+            .filter(|hash| !moved.contains(hash))
+            .collect();
+        let mut needed = HashSet::new();
+        while let Some(hash) = open.pop_front() {
+            for dep in self.get_dep_ids(&hash)? {
+                let dep = dep.get_base_32_hash();
+                if moved.contains(dep) && needed.insert(dep.to_string()) {
+                    open.push_back(dep.to_string());
+                }
+            }
+        }
+
+        let mut summary = SplitSummary {
+            copy,
+            removed: 0,
+            kept: 0,
+        };
+        for hash in &moved {
+            if needed.contains(hash) {
+                summary.kept += 1;
+            } else {
+                // The packages live on in the destination, so peers following
+                // deletions are not told about them
+                self.purge(hash, true)?;
+                summary.removed += 1;
+            }
+        }
+        Ok(summary)
+    }
+
+    /// Copies every package of the gachix repository at `other` into this one,
+    /// along with the references describing them. Objects both repositories hold
+    /// are not copied again.
+    pub fn merge(&self, other: &Path) -> Result<CopySummary> {
+        if !other.is_dir() {
+            bail!("{} is not a gachix repository", other.display());
+        }
+        let other = self.with_path(other)?;
+        let hashes: Vec<String> = other
+            .list_entries(&ListOptions::default())?
+            .into_iter()
+            .map(|entry| entry.hash)
+            .collect();
+        let destination = self
+            .settings
+            .path
+            .to_str()
+            .ok_or_else(|| anyhow!("Invalid path {}", self.settings.path.display()))?;
+        let mut present = HashSet::new();
+        for hash in &hashes {
+            if self.entry_exists(hash)? {
+                present.insert(hash.clone());
+            }
+        }
+        let summary = other.copy_to(&hashes, destination)?;
+        // The packages were added behind the back of the count
+        *self.package_count.lock().unwrap() = None;
+        let mut added = Vec::new();
+        for hash in hashes {
+            if !present.contains(&hash) && self.entry_exists(&hash)? {
+                added.push(hash);
+            }
+        }
+        self.record_history(&self.history_records(Change::Added, &added));
+        Ok(summary)
+    }
+
+    /// Describes the closure of a stored package, see `manifest::Manifest`.
+    pub fn manifest(&self, hash: &str) -> Result<Manifest> {
+        Manifest::new(&self.closure(hash)?, |hash| self.get_commit(hash))
+    }
+
+    /// Lists the packages of a manifest which are not stored, or are stored with
+    /// other contents.
+    pub fn check_manifest(&self, manifest: &Manifest) -> Result<Vec<String>> {
+        manifest.differences(|hash| {
+            self.get_narinfo(hash)?
+                .map(|narinfo| NarInfo::parse(&String::from_utf8_lossy(&narinfo)))
+                .transpose()
+        })
+    }
+
+    pub fn sbom(&self, hash: &str, format: SbomFormat) -> Result<serde_json::Value> {
+        let closure = self.closure(hash)?;
+        sbom::render(format, &closure[0], &closure)
+    }
+
+    async fn build_narinfo(
+        &self,
+        nix_daemon: &mut DynNixDaemon,
+        key: &str,
+        store_path: &NixPath,
+        system: Option<&str>,
+    ) -> Result<NarInfo> {
+        let Some(path_info) = nix_daemon.get_pathinfo(&store_path).await? else {
+            return Err(anyhow!(
+                "Could not find narinfo for {}",
+                store_path.get_path()
+            ));
+        };
+        let references: Vec<NixPath> = path_info
+            .references
+            .iter()
+            .map(|p| NixPath::new(p))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let nar_size = path_info.nar_size;
+        // TODO: compute hash instead of copying it and verify it against the received hash
+        let nar_hash_32_base = format_nar_hash(&path_info.nar_hash)?;
+
+        let signature = self.sign(store_path, &nar_hash_32_base, nar_size, &references);
+
+        let deriver = path_info.deriver.map(|d| NixPath::new(&d)).transpose()?;
+        let system = match (system, &deriver) {
+            (Some(system), _) => Some(system.to_string()),
+            // The system is only recorded for convenience, a derivation which can't
+            // be read does not keep the package from being added
+            (None, Some(deriver)) => derivation_system(nix_daemon, deriver).await.ok().flatten(),
+            (None, None) => None,
+        };
+        let mut narinfo = NarInfo::new(
+            store_path.clone(),
+            key.to_string(),
+            nar_hash_32_base.clone(),
+            path_info.nar_size,
+            None,
+            nar_hash_32_base,
+            path_info.nar_size,
+            deriver,
+            references,
+            signature,
+        );
+        narinfo.system = system;
+        self.apply_narinfo_fields(&mut narinfo)?;
+        Ok(narinfo)
+    }
+
+    /// Strips and adds the fields of `narinfo_fields`, for every package which is
+    /// fetched from a daemon or a git peer, or pushed.
+    fn apply_narinfo_fields(&self, narinfo: &mut NarInfo) -> Result<()> {
+        narinfo.strip_extra(&self.settings.narinfo_fields.strip);
+        for (key, value) in &self.narinfo_fields {
+            narinfo.set_extra(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Applies `narinfo_fields` to a narinfo fetched from a git peer, and returns
+    /// the blob to store, which is `oid` unless a field changed.
+    fn apply_narinfo_fields_to_blob(&self, oid: Oid) -> Result<Oid> {
+        if self.narinfo_fields.is_empty() && self.settings.narinfo_fields.strip.is_empty() {
+            return Ok(oid);
+        }
+        let mut narinfo = NarInfo::parse(&String::from_utf8_lossy(&self.repo.get_blob(oid)?))?;
+        let extra = narinfo.extra.clone();
+        self.apply_narinfo_fields(&mut narinfo)?;
+        if narinfo.extra == extra {
+            return Ok(oid);
+        }
+        self.repo.add_file_content(narinfo.to_string().as_bytes())
+    }
+
+    fn sign(
+        &self,
+        store_path: &NixPath,
+        nar_hash: &str,
+        nar_size: u64,
+        references: &[NixPath],
+    ) -> Option<String> {
+        self.private_key.as_ref().map(|private_key| {
+            let fingerprint = fingerprint_store_object(store_path, nar_hash, nar_size, references);
+            let signature_bytes = private_key.sign(fingerprint.as_bytes());
+            format!(
+                "{}:{}",
+                private_key.name,
+                BASE64_STANDARD.encode(signature_bytes)
+            )
+        })
+    }
+
+    /// Checks that the stored objects of an entry still serialise to the NAR described
+    /// by its narinfo.
+    pub fn verify(&self, hash: &str) -> Result<Verification> {
+        self.verify_entry(&self.get_narinfo_ref(hash))
+    }
+
+    /// Verifies the entry whose narinfo is at `narinfo_ref`, which need not be the
+    /// place the package is served from, e.g. a package fetched into quarantine.
+    fn verify_entry(&self, narinfo_ref: &str) -> Result<Verification> {
+        let Some(narinfo_oid) = self.repo.get_oid_from_reference(narinfo_ref) else {
+            return Ok(Verification::MissingNarinfo);
+        };
+        let narinfo = self.repo.get_blob(narinfo_oid)?;
+        let narinfo = match NarInfo::parse(&String::from_utf8_lossy(&narinfo)) {
+            Ok(narinfo) => narinfo,
+            Err(e) => return Ok(Verification::InvalidNarinfo(e.to_string())),
+        };
+        let oid = match Oid::from_str(&narinfo.key) {
+            Ok(oid) => oid,
+            Err(e) => return Ok(Verification::InvalidNarinfo(e.to_string())),
+        };
+        let (nar_hash, nar_size) = match self.repo.nar_hash(oid) {
+            Ok(result) => result,
+            Err(e) => return Ok(Verification::MissingObjects(e.to_string())),
+        };
+        let actual = format!("sha256:{}", nix_base32::to_nix_base32(&nar_hash));
+        if actual != narinfo.nar_hash {
+            return Ok(Verification::HashMismatch {
+                expected: narinfo.nar_hash,
+                actual,
+            });
+        }
+        if nar_size != narinfo.nar_size {
+            return Ok(Verification::SizeMismatch {
+                expected: narinfo.nar_size,
+                actual: nar_size,
+            });
+        }
+        Ok(Verification::Valid)
+    }
+
+    /// Whether the narinfo is signed by one of the trusted keys. Signatures cover
+    /// the full paths of references, which narinfos list without the store
+    /// directory.
+    fn is_trusted(&self, narinfo: &NarInfo) -> Result<bool> {
+        let Some(signatures) = &narinfo.signature else {
+            return Ok(false);
+        };
+        let fingerprint = fingerprint_store_object(
+            &narinfo.store_path,
+            &narinfo.nar_hash,
+            narinfo.nar_size,
+            &Self::full_references(narinfo)?,
+        );
+        Ok(self
+            .trusted_keys
+            .iter()
+            .any(|key| key.verifies(&fingerprint, signatures)))
+    }
+
+    /// The references of a parsed narinfo as full store paths, as signatures cover
+    /// them.
+    fn full_references(narinfo: &NarInfo) -> Result<Vec<NixPath>> {
+        let store_dir = Path::new(narinfo.store_path.get_path())
+            .parent()
+            .ok_or_else(|| anyhow!("Store path {} has no store directory", narinfo.store_path))?;
+        narinfo
+            .references
+            .iter()
+            .map(|r| NixPath::new(&store_dir.join(r.get_path())))
+            .collect()
+    }
+
+    /// Verifies an entry and, if verification fails, replaces it with a fresh copy from
+    /// a Nix daemon or a Git peer. Returns whether the entry had to be repaired.
+    pub async fn repair(&self, hash: &str) -> Result<bool> {
+        let verification = self.verify(hash)?;
+        if verification.is_valid() {
+            debug!("Package {} is valid", hash);
+            return Ok(false);
+        }
+        warn!("Package {} is corrupted: {}", hash, verification);
+
+        let narinfo = self
+            .get_narinfo(hash)?
+            .ok_or_else(|| anyhow!("Cannot repair {} without its narinfo", hash))?;
+        let narinfo = String::from_utf8_lossy(&narinfo).to_string();
+        let store_path = NarInfo::field(&narinfo, "StorePath")
+            .ok_or_else(|| anyhow!("Narinfo of {} does not contain a store path", hash))?;
+        let store_path = NixPath::new(store_path)?;
+        let previous_narinfo = self
+            .repo
+            .get_oid_from_reference(&self.get_narinfo_ref(hash));
+
+        if let Ok(Some(DaemonPackage {
+            narinfo,
+            narinfo_blob_oid,
+            package_oid,
+            ..
+        })) = self.get_package_from_nix_daemons(&store_path, None).await
+        {
+            let parents = match self.get_commit(hash) {
+                Some(commit_oid) => self.repo.get_commit_parents(commit_oid)?,
+                None => narinfo
+                    .get_dependencies()
+                    .iter()
+                    .map(|dep| {
+                        self.get_commit(dep.get_base_32_hash()).ok_or_else(|| {
+                            anyhow!("Dependency {} is not in the cache", dep.get_name())
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()?,
+            };
+            let commit_oid =
+                self.repo
+                    .commit(package_oid, &parents, Some(store_path.get_name()))?;
+            self.repo
+                .update_ref(&self.get_result_ref(hash), commit_oid)?;
+            self.repo
+                .update_ref(&self.get_narinfo_ref(hash), narinfo_blob_oid)?;
+            self.index_deriver(hash)?;
+        } else if !self.repair_from_git_remotes(&store_path)? {
+            bail!(
+                "Neither a Nix daemon nor a Git peer could provide {}",
+                store_path
+            );
+        }
+
+        let verification = self.verify(hash)?;
+        if !verification.is_valid() {
+            bail!("Package {} is still corrupted: {}", hash, verification);
+        }
+        if let (Some(previous), Some(current)) = (
+            previous_narinfo,
+            self.repo
+                .get_oid_from_reference(&self.get_narinfo_ref(hash)),
+        ) {
+            self.keep_revision(hash, previous, current, Reason::Repaired)?;
+        }
+        self.record_history(&self.history_records(Change::Repaired, &[hash.to_string()]));
+        info!("Repaired package {}", store_path.get_name());
+        Ok(true)
+    }
+
+    /// Fetches a fresh copy of a package from the git peers. The stored references
+    /// are only replaced once a copy passed the checks in quarantine, so a package
+    /// no peer can provide stays as it is. The copy is only signed with our own key
+    /// if trusted public keys are configured, which it was checked against.
+    fn repair_from_git_remotes(&self, store_path: &NixPath) -> Result<bool> {
+        let hash = store_path.get_base_32_hash();
+        for remote_url in &self.settings.remotes {
+            if !self.fetch_into_quarantine(hash, remote_url.as_str(), None)? {
+                continue;
+            }
+            let mut staged = StagedRefs::new(&self.repo, hash);
+            if let Err(e) = self.swap_in_quarantined(&mut staged, hash) {
+                staged.roll_back();
+                self.drop_quarantined_refs(hash)?;
+                return Err(e);
+            }
+            self.drop_quarantined_refs(hash)?;
+            // Without trusted keys, nothing vouches for the copy of the peer
+            if !self.trusted_keys.is_empty() {
+                self.sign_again(hash)?;
+            }
+            debug!(
+                "Using git peer at {}, repaired package {}",
+                remote_url,
+                store_path.get_name()
+            );
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Replaces the references of a stored package with those of its copy in
+    /// quarantine, dropping the ones the copy does not have.
+    fn swap_in_quarantined(&self, staged: &mut StagedRefs, hash: &str) -> Result<()> {
+        let mut fetched = Vec::new();
+        for reference in self.quarantined_refs(hash)? {
+            let Some((_, kind)) = parse_quarantine_ref(&reference) else {
+                continue;
+            };
+            let oid = self
+                .repo
+                .get_oid_from_reference(&reference)
+                .ok_or_else(|| anyhow!("Could not resolve {}", reference))?;
+            fetched.push((format!("{}/{kind}", self.get_package_ref(hash)), oid));
+        }
+
+        if let Some(deriver) = self
+            .get_narinfo(hash)?
+            .and_then(|narinfo| Self::deriver_from_narinfo(&narinfo))
+        {
+            staged.delete(&derivers::deriver_ref(&deriver, hash))?;
+        }
+        for reference in self
+            .repo
+            .list_references(&format!("{}/*", self.get_package_ref(hash)))?
+        {
+            if !fetched.iter().any(|(name, _)| *name == reference) {
+                staged.delete(&reference)?;
+            }
+        }
+        for (name, oid) in &fetched {
+            staged.update(name, *oid)?;
+        }
+        self.index_deriver(hash)
+    }
+
+    /// The narinfo of a package fetched from a peer carries the signature of the
+    /// peer, signs it with our own key if one is configured.
+    fn sign_again(&self, hash: &str) -> Result<()> {
+        if self.private_key.is_none() {
+            return Ok(());
+        }
+        let narinfo_blob = self
+            .get_narinfo(hash)?
+            .ok_or_else(|| anyhow!("Could not find narinfo for {}", hash))?;
+        let mut narinfo = NarInfo::parse(&String::from_utf8_lossy(&narinfo_blob))?;
+        narinfo.signature = self.sign(
+            &narinfo.store_path,
+            &narinfo.nar_hash,
+            narinfo.nar_size,
+            &narinfo.references,
+        );
+        let narinfo_blob_oid = self.repo.add_file_content(narinfo.to_string().as_bytes())?;
+        self.replace_narinfo(hash, narinfo_blob_oid, Reason::Resigned)
+    }
+
+    /// Points the narinfo reference of a package at a new narinfo and keeps the one
+    /// it replaces as a revision, see `revisions::REVISIONS_NAMESPACE`.
+    fn replace_narinfo(&self, hash: &str, narinfo: Oid, reason: Reason) -> Result<()> {
+        let narinfo_ref = self.get_narinfo_ref(hash);
+        if let Some(previous) = self.repo.get_oid_from_reference(&narinfo_ref) {
+            self.keep_revision(hash, previous, narinfo, reason)?;
+        }
+        self.repo.update_ref(&narinfo_ref, narinfo)
+    }
+
+    fn keep_revision(&self, hash: &str, previous: Oid, current: Oid, reason: Reason) -> Result<()> {
+        if previous == current {
+            return Ok(());
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        self.repo.update_ref(
+            &revisions::revision_ref(hash, now, reason, previous),
+            previous,
+        )
+    }
+
+    /// Lists the narinfos a package was served with, the current one first and then
+    /// the replaced ones which were not pruned yet, newest first.
+    pub fn narinfo_revisions(&self, hash: &str) -> Result<Vec<Revision>> {
+        let describe = |superseded, narinfo| -> Result<Revision> {
+            let content = self.repo.get_blob(narinfo)?;
+            let content = String::from_utf8_lossy(&content);
+            Ok(Revision {
+                superseded,
+                narinfo,
+                keys: revisions::key_names(NarInfo::field(&content, "Sig").unwrap_or("")),
+            })
+        };
+        let mut replaced = Vec::new();
+        for (reference, narinfo) in self
+            .repo
+            .list_reference_targets(&format!("{REVISIONS_NAMESPACE}/{hash}/*"))?
+        {
+            if let Some((_, time, reason)) = revisions::parse_revision_ref(&reference) {
+                replaced.push(describe(Some((time, reason)), narinfo)?);
+            }
+        }
+        replaced.sort_by_key(|revision| std::cmp::Reverse(revision.superseded.map(|(t, _)| t)));
+
+        let mut result = Vec::new();
+        if let Some(narinfo) = self
+            .repo
+            .get_oid_from_reference(&self.get_narinfo_ref(hash))
+        {
+            result.push(describe(None, narinfo)?);
+        }
+        if result.is_empty() && replaced.is_empty() {
+            bail!("Package {} has no narinfo revisions", hash);
+        }
+        result.extend(replaced);
+        Ok(result)
+    }
+
+    /// Drops the replaced narinfos of a package which is removed.
+    fn drop_revisions(&self, hash: &str) -> Result<()> {
+        for reference in self
+            .repo
+            .list_references(&format!("{REVISIONS_NAMESPACE}/{hash}/*"))?
+        {
+            self.repo.delete_ref(&reference)?;
+        }
+        Ok(())
+    }
+
+    /// Forgets the narinfos which were replaced longer than `retention` seconds ago,
+    /// so that the next prune removes their blobs. Returns how many were forgotten,
+    /// or would be with `dry_run`.
+    fn expire_revisions(&self, retention: u64, dry_run: bool) -> Result<usize> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let mut expired = 0;
+        for reference in self
+            .repo
+            .list_references(&format!("{REVISIONS_NAMESPACE}/*"))?
+        {
+            let Some((_, time, _)) = revisions::parse_revision_ref(&reference) else {
+                continue;
+            };
+            if revisions::is_expired(time, now, retention) {
+                if !dry_run {
+                    self.repo.delete_ref(&reference)?;
+                }
+                expired += 1;
+            }
+        }
+        Ok(expired)
+    }
+
+    /// Fetches the entry a peer produced for `hash` and compares it with the local one.
+    pub fn verify_reproducible(&self, hash: &str, peer: &str) -> Result<ReproducibilityReport> {
+        let local = self
+            .describe_entry(&self.get_result_ref(hash), &self.get_narinfo_ref(hash))?
+            .ok_or_else(|| anyhow!("Package {} is not in the cache", hash))?;
+
+        self.repo.fetch_into(
+            peer,
+            &format!("{}/*", self.get_package_ref(hash)),
+            &verify_ref(hash, "*"),
+        )?;
+        let peer_entry =
+            self.describe_entry(&verify_ref(hash, "result"), &verify_ref(hash, "narinfo"));
+        for reference in self.repo.list_references(&verify_ref(hash, "*"))? {
+            self.repo.delete_ref(&reference)?;
+        }
+        let peer_entry =
+            peer_entry?.ok_or_else(|| anyhow!("Package {} is not available at {}", hash, peer))?;
+
+        Ok(ReproducibilityReport::new(hash, local, peer_entry))
+    }
+
+    /// Collects the properties of the entry at `result_ref` and `narinfo_ref` which
+    /// two instances ingesting the same store path are expected to agree on.
+    fn describe_entry(
+        &self,
+        result_ref: &str,
+        narinfo_ref: &str,
+    ) -> Result<Option<Vec<(&'static str, String)>>> {
+        let (Some(commit_oid), Some(narinfo_oid)) = (
+            self.repo.get_oid_from_reference(result_ref),
+            self.repo.get_oid_from_reference(narinfo_ref),
+        ) else {
+            return Ok(None);
+        };
+        let narinfo = NarInfo::parse(&String::from_utf8_lossy(&self.repo.get_blob(narinfo_oid)?))?;
+        let tree_oid = self.repo.get_commit_tree(commit_oid)?;
+        let (nar_hash, nar_size) = self.repo.nar_hash(tree_oid)?;
+        Ok(Some(vec![
+            ("commit", commit_oid.to_string()),
+            ("tree", tree_oid.to_string()),
+            (
+                "NAR hash",
+                format!("sha256:{}", nix_base32::to_nix_base32(&nar_hash)),
+            ),
+            ("NAR size", nar_size.to_string()),
+            ("narinfo NarHash", narinfo.nar_hash),
+            ("narinfo NarSize", narinfo.nar_size.to_string()),
+        ]))
+    }
+
+    /// Returns where and when a package was added, if that was recorded.
+    pub fn provenance(&self, hash: &str) -> Result<Option<Provenance>> {
+        match self
+            .repo
+            .get_oid_from_reference(&self.get_provenance_ref(hash))
+        {
+            Some(oid) => Ok(Some(Provenance::from_json(&self.repo.get_blob(oid)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn record_provenance(&self, hash: &str, provenance: &Provenance) -> Result<()> {
+        let oid = self
+            .repo
+            .add_file_content(provenance.to_json()?.as_bytes())?;
+        self.repo.update_ref(&self.get_provenance_ref(hash), oid)
+    }
+
+    /// Publishes the NAR of a package to IPFS and records its content ID. Packages
+    /// which were published before are not published again.
+    pub fn export_to_ipfs(&self, hash: &str, api: Option<&str>) -> Result<IpfsExport> {
+        if let Some(export) = self.ipfs_export(hash)? {
+            return Ok(export);
+        }
+        let narinfo_blob = self
+            .get_narinfo(hash)?
+            .ok_or_else(|| anyhow!("Package {} is not in the cache", hash))?;
+        let narinfo = NarInfo::parse(&String::from_utf8_lossy(&narinfo_blob))?;
+        let oid = Oid::from_str(&narinfo.key)?;
+        let export = ipfs::publish(api, |writer| self.repo.write_nar(oid, writer))?;
+        let blob_oid = self.repo.add_file_content(export.to_json()?.as_bytes())?;
+        self.repo.update_ref(&self.get_ipfs_ref(hash), blob_oid)?;
+        info!(
+            "Published {} to IPFS as {}",
+            narinfo.store_path.get_name(),
+            export.cid
+        );
+        Ok(export)
+    }
+
+    /// Writes the closures of `hashes`, or all packages if none are given, as a static
+    /// binary cache with xz compressed NARs to `destination`. If the export exceeds
+    /// the site size limit, it is split into several caches `site-1`, `site-2`, ...
+    pub fn export_static(
+        &self,
+        hashes: &[String],
+        destination: &Path,
+        options: &StaticExportOptions,
+    ) -> Result<StaticExportSummary> {
+        let packages = if hashes.is_empty() {
+            self.list_entries(&ListOptions::default())?
+                .into_iter()
+                .map(|entry| entry.hash)
+                .collect()
+        } else {
+            let mut visited = HashSet::new();
+            let mut packages = Vec::new();
+            for hash in hashes {
+                for narinfo in self.closure(hash)? {
+                    let package_hash = narinfo.store_path.get_base_32_hash().to_string();
+                    if visited.insert(package_hash.clone()) {
+                        packages.push(package_hash);
+                    }
+                }
+            }
+            packages
+        };
+
+        let site_dir = |index: usize| match options.max_site_size {
+            Some(_) => destination.join(format!("site-{index}")),
+            None => destination.to_path_buf(),
+        };
+        let mut narinfos = BTreeMap::new();
+        for hash in packages {
+            let narinfo_blob = self
+                .get_narinfo(&hash)?
+                .ok_or_else(|| anyhow!("Could not find narinfo for {}", hash))?;
+            narinfos.insert(
+                hash,
+                NarInfo::parse(&String::from_utf8_lossy(&narinfo_blob))?,
+            );
+        }
+
+        let staging = destination.join(".staging");
+        fs::create_dir_all(&staging)?;
+        let mut summary = StaticExportSummary::default();
+        let mut exported = HashSet::new();
+        let mut site = Site::create(&site_dir(1))?;
+        for narinfo in static_site::references_first(&narinfos) {
+            let hash = narinfo.store_path.get_base_32_hash();
+            // Nix would substitute the package without the rest of its closure
+            if let Some(missing) = narinfo
+                .get_dependencies()
+                .into_iter()
+                .map(NixPath::get_base_32_hash)
+                .find(|dependency| !exported.contains(*dependency))
+            {
+                warn!(
+                    "Skipping {}, its dependency {} was not exported",
+                    narinfo.store_path.get_name(),
+                    missing
+                );
+                summary.incomplete.push(narinfo.store_path.to_string());
+                continue;
+            }
+            let oid = Oid::from_str(&narinfo.key)?;
+            let nar = static_site::compress_nar(&staging.join(hash), |writer| {
+                self.repo.write_nar(oid, writer)
+            })?;
+            if nar.file_size > options.max_file_size {
+                warn!(
+                    "Skipping {}, its compressed NAR has {} bytes",
+                    narinfo.store_path.get_name(),
+                    nar.file_size
+                );
+                summary.skipped.push(narinfo.store_path.to_string());
+                fs::remove_file(staging.join(hash))?;
+                continue;
+            }
+            if let Some(max_site_size) = options.max_site_size
+                && !site.is_empty()
+                && site.size + nar.file_size > max_site_size
+            {
+                summary.sites.push(site.finish()?);
+                site = Site::create(&site_dir(summary.sites.len() + 1))?;
+            }
+            summary.exported += 1;
+            summary.bytes += nar.file_size;
+            exported.insert(hash.to_string());
+            site.add(narinfo.clone(), nar)?;
+        }
+        summary.sites.push(site.finish()?);
+        fs::remove_dir_all(&staging)?;
+        Ok(summary)
+    }
+
+    /// Returns the IPFS content ID of a package, if it was published.
+    pub fn ipfs_export(&self, hash: &str) -> Result<Option<IpfsExport>> {
+        match self.repo.get_oid_from_reference(&self.get_ipfs_ref(hash)) {
+            Some(oid) => Ok(Some(IpfsExport::from_json(&self.repo.get_blob(oid)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the tree of a stored package whose NAR has the hash `nar_hash`.
+    fn find_tree_by_nar_hash(&self, nar_hash: &str) -> Result<Option<Oid>> {
+        let Some(commit_oid) = self
+            .repo
+            .get_oid_from_reference(&self.get_nar_hash_ref(nar_hash))
+        else {
+            return Ok(None);
+        };
+        Ok(Some(self.repo.get_commit_tree(commit_oid)?))
+    }
+
+    pub fn get_narinfo(&self, base32_hash: &str) -> Result<Option<Vec<u8>>> {
+        let result = self
+            .repo
+            .get_oid_from_reference(&self.get_narinfo_ref(base32_hash));
+        match result {
+            Some(oid) => Ok(Some(self.repo.get_blob(oid)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn entry_exists(&self, base32_hash: &str) -> Result<bool> {
+        self.repo
+            .reference_exists(&self.get_result_ref(base32_hash))
+    }
+
+    pub fn get_as_nar_stream(&self, key: &str) -> Result<Option<NarGitStream>> {
+        if self.is_withdrawn(key)? {
+            return Ok(None);
+        }
+        self.repo.get_entry_as_nar(Oid::from_str(key)?)
+    }
+
+    /// Indexes the NAR served under `key`, see `get_as_nar_stream`.
+    pub fn nar_index(&self, key: &str) -> Result<Option<NarIndex>> {
+        let oid = Oid::from_str(key)?;
+        if !self.repo.contains(oid)? || self.is_withdrawn(key)? {
+            return Ok(None);
+        }
+        Ok(Some(self.repo.nar_index(oid)?))
+    }
+
+    /// Lists the files of a package with their offsets in its NAR.
+    pub fn nar_listing(&self, hash: &str) -> Result<Option<NarIndex>> {
+        let Some(narinfo) = self.get_narinfo(hash)? else {
+            return Ok(None);
+        };
+        let narinfo = NarInfo::parse(&String::from_utf8_lossy(&narinfo))?;
+        self.nar_index(&narinfo.key)
+    }
+
+    /// Reads the inclusive byte range `start..=end` of an indexed NAR, if the range
+    /// lies within the contents of a single file.
+    pub fn read_nar_range(
+        &self,
+        index: &NarIndex,
+        start: u64,
+        end: u64,
+    ) -> Result<Option<Vec<u8>>> {
+        let Some((blob, offset)) = index.locate(start, end) else {
+            return Ok(None);
+        };
+        Ok(Some(self.repo.read_blob_range(
+            blob,
+            offset,
+            end - start + 1,
+        )?))
+    }
+
+    /// Writes a package to `dest`, which must not exist yet, the way Nix would have
+    /// written it to the store.
+    pub fn extract(&self, hash: &str, dest: &Path) -> Result<()> {
+        let narinfo = self
+            .get_narinfo(hash)?
+            .ok_or_else(|| anyhow!("Package {} is not in the cache", hash))?;
+        let narinfo = NarInfo::parse(&String::from_utf8_lossy(&narinfo))?;
+        self.repo.extract(Oid::from_str(&narinfo.key)?, dest)?;
+        info!("Extracted {} to {}", hash, dest.display());
+        Ok(())
+    }
+
+    /// Stores an arbitrary file or directory, serialised as a NAR, under the
+    /// nix-base32 sha256 of the NAR. The NAR is hashed while it is decoded, and
+    /// nothing is stored if it does not match `hash`, in which case false is
+    /// returned. Data after the end of the NAR is refused.
+    pub fn add_artifact(&self, hash: &str, nar: impl Read) -> Result<bool> {
+        let mut reader = HashingReader {
+            inner: nar,
+            hasher: Sha256::new(),
+            size: 0,
+        };
+        let staged = self.repo.stage_nar(&mut reader)?;
+        if reader.read(&mut [0])? != 0 {
+            bail!("Unexpected data after the NAR of artifact {}", hash);
+        }
+        if nix_base32::to_nix_base32(&reader.hasher.finalize()) != hash {
+            debug!("Rejected artifact which does not match {}", hash);
+            return Ok(false);
+        }
+        let oid = staged.keep()?;
+        let artifact_ref = self.get_artifact_ref(hash);
+        let stored = self.repo.reference_exists(&artifact_ref)?;
+        self.repo.update_ref(&artifact_ref, oid)?;
+        if !stored && let Some(bytes) = self.artifact_bytes.lock().unwrap().as_mut() {
+            *bytes += self.repo.nar_index(oid)?.nar_size;
+        }
+        debug!("Stored artifact {}", hash);
+        Ok(true)
+    }
+
+    /// Returns the hashes of the packages which are not stored, for clients which
+    /// push their packages, see `gachix push`.
+    pub fn missing(&self, hashes: &[String]) -> Result<Vec<String>> {
+        let mut missing = Vec::new();
+        for hash in hashes {
+            if !self.entry_exists(hash)? {
+                missing.push(hash.clone());
+            }
+        }
+        Ok(missing)
+    }
+
+    /// Adds a package pushed by another gachix over HTTP from its narinfo and its
+    /// uncompressed NAR. The dependencies of the package have to be stored already.
+    /// The NAR is hashed while it is decoded, and nothing is stored if it does not
+    /// match the narinfo, in which case false is returned. Data after the end of the
+    /// NAR is refused. The narinfo is signed again if a signing key is configured.
+    pub fn add_pushed(&self, narinfo: &NarInfo, nar: impl Read, source: &str) -> Result<bool> {
+        if self.entry_exists(narinfo.store_path.get_base_32_hash())? {
+            return Ok(true);
+        }
+        let parent_commits = self.pushed_parent_commits(narinfo)?;
+        let mut reader = HashingReader {
+            inner: nar,
+            hasher: Sha256::new(),
+            size: 0,
+        };
+        let staged = self.repo.stage_nar(&mut reader)?;
+        if reader.read(&mut [0])? != 0 {
+            bail!("Unexpected data after the NAR of {}", narinfo.store_path);
+        }
+        let nar_hash = reader.hasher.finalize();
+        if !Self::matches_narinfo(narinfo, &nar_hash, reader.size) {
+            return Ok(false);
+        }
+        let package_oid = staged.keep()?;
+        self.commit_pushed(narinfo, package_oid, &parent_commits, source)?;
+        Ok(true)
+    }
+
+    /// Adds a package pushed by another gachix whose tree, named by the key of its
+    /// narinfo, was sent as git objects beforehand, see `missing_objects`. Like
+    /// `add_pushed`, the tree has to serialise to the NAR described by the narinfo.
+    pub fn add_pushed_tree(&self, narinfo: &NarInfo, source: &str) -> Result<bool> {
+        let hash = narinfo.store_path.get_base_32_hash();
+        if self.entry_exists(hash)? {
+            return Ok(true);
+        }
+        let parent_commits = self.pushed_parent_commits(narinfo)?;
+        let tree_oid = Oid::from_str(&narinfo.key)?;
+        let (nar_hash, nar_size) = self
+            .repo
+            .nar_hash(tree_oid)
+            .with_context(|| format!("Objects of {hash} are missing"))?;
+        if !Self::matches_narinfo(narinfo, &nar_hash, nar_size) {
+            return Ok(false);
+        }
+        self.commit_pushed(narinfo, tree_oid, &parent_commits, source)?;
+        Ok(true)
+    }
+
+    /// The commits of the dependencies of a pushed package, which become the parents
+    /// of its commit.
+    fn pushed_parent_commits(&self, narinfo: &NarInfo) -> Result<Vec<Oid>> {
+        narinfo
+            .get_dependencies()
+            .into_iter()
+            .map(|d| {
+                self.get_commit(d.get_base_32_hash()).ok_or_else(|| {
+                    anyhow!(
+                        "Dependency {} of {} is not stored",
+                        d,
+                        narinfo.store_path.get_base_32_hash()
+                    )
+                })
+            })
+            .collect()
+    }
+
+    fn matches_narinfo(narinfo: &NarInfo, nar_hash: &[u8], nar_size: u64) -> bool {
+        let nar_hash = format!("sha256:{}", nix_base32::to_nix_base32(nar_hash));
+        let matches = nar_hash == narinfo.nar_hash && nar_size == narinfo.nar_size;
+        if !matches {
+            debug!(
+                "Rejected pushed package {} which does not match its narinfo",
+                narinfo.store_path.get_base_32_hash()
+            );
+        }
+        matches
+    }
+
+    /// Commits a pushed package whose contents were checked against its narinfo.
+    fn commit_pushed(
+        &self,
+        narinfo: &NarInfo,
+        package_oid: Oid,
+        parent_commits: &[Oid],
+        source: &str,
+    ) -> Result<()> {
+        // The NAR is served uncompressed from the stored tree
+        let mut stored = narinfo.clone();
+        stored.key = package_oid.to_string();
+        stored.url = None;
+        stored.compression_type = None;
+        stored.file_hash = narinfo.nar_hash.clone();
+        stored.file_size = narinfo.nar_size;
+        self.apply_narinfo_fields(&mut stored)?;
+        let references = Self::full_references(narinfo)?;
+        if let Some(signature) = self.sign(
+            &stored.store_path,
+            &narinfo.nar_hash,
+            narinfo.nar_size,
+            &references,
+        ) {
+            stored.signature = Some(signature);
+        }
+        let package = FetchedPackage {
+            narinfo_blob_oid: self.repo.add_file_content(stored.to_string().as_bytes())?,
+            package_oid,
+            dependencies: narinfo.get_dependencies().into_iter().cloned().collect(),
+            provenance: Provenance::new(source.to_string(), narinfo.deriver.as_ref()),
+            nar_hash: narinfo.nar_hash.clone(),
+            dedup: Dedup::default(),
+        };
+        self.commit_package(&narinfo.store_path, &package, parent_commits)?;
+        Ok(())
+    }
+
+    /// Imports the narinfos of the closures of `store_paths` from the binary cache at
+    /// `upstream` without fetching their NARs. The packages are then served as if
+    /// they were stored, and `fetch_upstream` fetches a NAR once a client asks for
+    /// it. Narinfos need a signature by one of the trusted public keys, and packages
+    /// are left out unless their whole closure can be imported.
+    pub fn import_metadata(
+        &self,
+        upstream: &Url,
+        store_paths: &[NixPath],
+    ) -> Result<MetadataImport> {
+        if self.trusted_keys.is_empty() {
+            bail!(
+                "Set store.trusted_public_keys to the keys of {} before importing from it",
+                upstream
+            );
+        }
+        let client = reqwest::blocking::Client::new();
+        let mut summary = MetadataImport::default();
+        let mut visited = HashSet::new();
+        let mut present = HashSet::new();
+        let mut fetched = BTreeMap::new();
+        let mut pending = store_paths.to_vec();
+        while let Some(path) = pending.pop() {
+            let hash = path.get_base_32_hash().to_string();
+            if !visited.insert(hash.clone()) {
+                continue;
+            }
+            if self.entry_exists(&hash)? {
+                summary.present += 1;
+                present.insert(hash);
+                continue;
+            }
+            let narinfo = match self.upstream_narinfo(&hash)? {
+                Some(narinfo) => {
+                    summary.present += 1;
+                    present.insert(hash);
+                    narinfo
+                }
+                None => {
+                    let Some(mut narinfo) = upstream::fetch_narinfo(&client, upstream, &hash)?
+                    else {
+                        summary.missing.push(path.to_string());
+                        continue;
+                    };
+                    if !self.is_trusted(&narinfo)? {
+                        summary.untrusted.push(path.to_string());
+                        continue;
+                    }
+                    narinfo.set_extra(upstream::UPSTREAM_FIELD, upstream.as_str())?;
+                    fetched.insert(hash, narinfo.clone());
+                    narinfo
+                }
+            };
+            pending.extend(narinfo.get_dependencies().into_iter().cloned());
+        }
+        let complete = upstream::complete_closures(&fetched, &present);
+        for (hash, narinfo) in fetched {
+            if !complete.contains(&hash) {
+                summary.incomplete.push(narinfo.store_path.to_string());
+                continue;
+            }
+            let oid = self.repo.add_file_content(narinfo.to_string().as_bytes())?;
+            self.repo.update_ref(&upstream::upstream_ref(&hash), oid)?;
+            summary.imported += 1;
+        }
+        info!(
+            "Imported {} narinfos from {}",
+            summary.imported,
+            upstream.as_str()
+        );
+        Ok(summary)
+    }
+
+    /// The narinfo imported from an upstream cache for a package whose NAR was not
+    /// fetched yet.
+    pub fn upstream_narinfo(&self, hash: &str) -> Result<Option<NarInfo>> {
+        match self
+            .repo
+            .get_oid_from_reference(&upstream::upstream_ref(hash))
+        {
+            Some(oid) => Ok(Some(NarInfo::parse(&String::from_utf8_lossy(
+                &self.repo.get_blob(oid)?,
+            ))?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The imported narinfos of the packages whose NARs were not fetched yet.
+    pub fn upstream_narinfos(&self) -> Result<Vec<NarInfo>> {
+        let mut narinfos = Vec::new();
+        for (reference, oid) in self
+            .repo
+            .list_reference_targets(&format!("{UPSTREAM_NAMESPACE}/*"))?
+        {
+            let Some(hash) = upstream::parse_upstream_ref(&reference) else {
+                continue;
+            };
+            if self.entry_exists(hash)? {
+                continue;
+            }
+            narinfos.push(NarInfo::parse(&String::from_utf8_lossy(
+                &self.repo.get_blob(oid)?,
+            ))?);
+        }
+        Ok(narinfos)
+    }
+
+    /// Fetches the NAR of a package whose narinfo was imported from an upstream
+    /// cache, and then adds it along with those of its dependencies which are not
+    /// stored yet, see `fetch_upstream_nar` and `ingest_upstream`. Returns the
+    /// narinfo of the stored package, or `None` if the package is neither stored nor
+    /// imported.
+    pub fn fetch_upstream(&self, hash: &str) -> Result<Option<NarInfo>> {
+        if let Some(fetched) = self.fetch_upstream_nar(hash)? {
+            self.ingest_upstream(&fetched)?;
+        }
+        // Stored by now, by this call or a concurrent one
+        match self.get_narinfo(hash)? {
+            Some(narinfo) => Ok(Some(NarInfo::parse(&String::from_utf8_lossy(&narinfo))?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Fetches the NAR of a package whose narinfo was imported from an upstream
+    /// cache into the repository and checks it against the narinfo, so that it can
+    /// be served before the package and its dependencies are added. Returns `None`
+    /// if the package is stored already or not imported.
+    pub fn fetch_upstream_nar(&self, hash: &str) -> Result<Option<FetchedNar>> {
+        if self.entry_exists(hash)? {
+            return Ok(None);
+        }
+        let Some(imported) = self.upstream_narinfo(hash)? else {
+            return Ok(None);
+        };
+        let upstream = upstream::upstream_of(&imported)?;
+        let mut narinfo = upstream::without_upstream(&imported);
+        // The objects of the NAR are unreferenced until the package is added
+        let _fetching = self.leases.lease(hash);
+        let mut reader = HashingReader {
+            inner: upstream::fetch_nar(&reqwest::blocking::Client::new(), &upstream, &narinfo)?,
+            hasher: Sha256::new(),
+            size: 0,
+        };
+        let (tree_oid, _, _) = self.repo.add_nar(&mut reader)?;
+        std::io::copy(&mut reader, &mut std::io::sink())?;
+        if !Self::matches_narinfo(&narinfo, &reader.hasher.finalize(), reader.size) {
+            bail!(
+                "The NAR of {} at {} does not match its narinfo",
+                narinfo.store_path,
+                upstream
+            );
+        }
+        narinfo.key = tree_oid.to_string();
+        let lease = self.leases.lease(&narinfo.key);
+        Ok(Some(FetchedNar {
+            narinfo,
+            upstream,
+            lease,
+        }))
+    }
+
+    /// Adds a package whose NAR `fetch_upstream_nar` fetched, after fetching and
+    /// adding its dependencies which are not stored yet.
+    pub fn ingest_upstream(&self, fetched: &FetchedNar) -> Result<()> {
+        let narinfo = &fetched.narinfo;
+        let hash = narinfo.store_path.get_base_32_hash();
+        for dependency in narinfo.get_dependencies() {
+            let dependency = dependency.get_base_32_hash();
+            if self.fetch_upstream(dependency)?.is_none() {
+                bail!(
+                    "Dependency {} of {} is neither stored nor imported",
+                    dependency,
+                    hash
+                );
+            }
+        }
+        if !self.entry_exists(hash)? {
+            let parent_commits = self.pushed_parent_commits(narinfo)?;
+            let tree_oid = Oid::from_str(&narinfo.key)?;
+            self.commit_pushed(
+                narinfo,
+                tree_oid,
+                &parent_commits,
+                fetched.upstream.as_str(),
+            )?;
+            info!("Fetched {} from {}", narinfo.store_path, fetched.upstream);
+        }
+        // A concurrent request may have stored the package and dropped it already
+        let upstream_ref = upstream::upstream_ref(hash);
+        if let Err(e) = self.repo.delete_ref(&upstream_ref)
+            && self.repo.reference_exists(&upstream_ref)?
+        {
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Returns the objects which are not in the repository, for clients which push
+    /// packages as git objects.
+    pub fn missing_objects(&self, oids: &[Oid]) -> Result<Vec<Oid>> {
+        self.repo.missing_objects(oids)
+    }
+
+    /// Returns the entries of the trees among `oids`, to continue negotiating which
+    /// objects of a package another gachix is missing below the trees it lacks.
+    pub fn tree_entries(&self, oids: &[Oid]) -> Result<Vec<Oid>> {
+        self.repo.tree_entries(oids)
+    }
+
+    /// Writes the given objects, without what they reference, as a pack to `out`.
+    pub fn write_objects(&self, oids: &[Oid], out: &mut impl Write) -> Result<()> {
+        self.repo.write_objects(oids, out)
+    }
+
+    /// Adds the objects of a pack sent by another gachix to the repository. They
+    /// stay unreferenced until the packages they belong to are added.
+    pub fn receive_objects(&self, pack: &mut impl Read) -> Result<()> {
+        self.repo.read_pack(pack)
+    }
+
+    /// Compresses the NAR of a stored package with xz into `dir`, and returns its
+    /// narinfo pointing at the compressed NAR, for pushing it to another gachix.
+    pub fn compress_nar(&self, hash: &str, dir: &Path) -> Result<(NarInfo, CompressedNar)> {
+        let narinfo_blob = self
+            .get_narinfo(hash)?
+            .ok_or_else(|| anyhow!("Could not find narinfo for {}", hash))?;
+        let mut narinfo = NarInfo::parse(&String::from_utf8_lossy(&narinfo_blob))?;
+        let oid = Oid::from_str(&narinfo.key)?;
+        let nar =
+            static_site::compress_nar(&dir.join(hash), |writer| self.repo.write_nar(oid, writer))?;
+        nar.describe(&mut narinfo);
+        Ok((narinfo, nar))
+    }
+
+    /// Streams an artifact stored with `add_artifact` as a NAR.
+    pub fn get_artifact(&self, hash: &str) -> Result<Option<NarGitStream>> {
+        match self
+            .repo
+            .get_oid_from_reference(&self.get_artifact_ref(hash))
+        {
+            Some(oid) => self.repo.get_entry_as_nar(oid),
+            None => Ok(None),
+        }
+    }
+
+    /// Stores the build log of a derivation uploaded to the server, replacing an
+    /// earlier one. `deriver` is named like in `derivers::deriver_hash`.
+    pub fn add_build_log(&self, deriver: &str, log: &[u8]) -> Result<()> {
+        let deriver_hash = derivers::deriver_hash(deriver)?;
+        let oid = self.repo.add_file_content(log)?;
+        self.repo
+            .update_ref(&derivers::log_ref(&deriver_hash), oid)?;
+        debug!("Stored the build log of {}", deriver);
+        Ok(())
+    }
+
+    /// Returns the build log of a derivation stored with `add_build_log`.
+    pub fn get_build_log(&self, deriver: &str) -> Result<Option<Vec<u8>>> {
+        let deriver_hash = derivers::deriver_hash(deriver)?;
+        self.repo
+            .get_oid_from_reference(&derivers::log_ref(&deriver_hash))
+            .map(|oid| self.repo.get_blob(oid))
+            .transpose()
+    }
+
+    pub fn list_entries(&self, options: &ListOptions) -> Result<Vec<Entry>> {
+        let hashes = self
+            .repo
+            .list_references("refs/*/narinfo")?
+            .iter()
+            .filter_map(|r| r.split('/').nth(1))
+            .map(str::to_string)
+            .collect();
+        let hashes = listing::filter_system(hashes, options, |hash| {
+            Ok(self
+                .get_narinfo(hash)?
+                .and_then(|narinfo| Self::system_from_narinfo(&narinfo)))
+        })?;
+        listing::select(hashes, options, |hash| self.get_entry_name(hash))
+    }
+
+    /// Lists entries like `list_entries`, along with their version, size and when
+    /// they were added, for the pages browsing the cache.
+    pub fn summarize_entries(&self, options: &ListOptions) -> Result<Vec<EntrySummary>> {
+        let mut summaries = Vec::new();
+        for entry in self.list_entries(options)? {
+            let narinfo = self
+                .get_narinfo(&entry.hash)?
+                .map(|narinfo| String::from_utf8_lossy(&narinfo).to_string());
+            let nar_size = narinfo
+                .as_deref()
+                .and_then(|narinfo| NarInfo::field(narinfo, "NarSize")?.parse().ok());
+            let mut log = None;
+            if let Some(deriver) = narinfo
+                .as_deref()
+                .and_then(|narinfo| NarInfo::field(narinfo, "Deriver"))
+                && let Ok(deriver_hash) = derivers::deriver_hash(deriver)
+                && self
+                    .repo
+                    .reference_exists(&derivers::log_ref(&deriver_hash))?
+            {
+                log = Some(deriver.to_string());
+            }
+            let added_at = self.provenance(&entry.hash)?.map(|p| p.added_at);
+            let version = NixPath::new(&format!("/nix/store/{entry}"))
+                .ok()
+                .and_then(|path| path.get_pname_and_version().1.map(str::to_string));
+            summaries.push(EntrySummary {
+                hash: entry.hash,
+                name: entry.name,
+                version,
+                nar_size,
+                added_at,
+                log,
+            });
+        }
+        Ok(summaries)
+    }
+
+    /// Advertises the stored packages with their result commits, only listing the
+    /// changes if `since` is the head of an earlier advertisement.
+    pub fn advertise_refs(&self, since: Option<Oid>) -> Result<RefAdvertisement> {
+        let current: BTreeMap<String, Oid> = self
+            .repo
+            .list_reference_targets("refs/*/result")?
+            .into_iter()
+            .filter_map(|(name, oid)| Some((name.split('/').nth(1)?.to_string(), oid)))
+            .collect();
+        if self.settings.read_only {
+            return Ok(RefAdvertisement::full(None, &current));
+        }
+        let entries: Vec<(String, Oid)> = current.iter().map(|(h, o)| (h.clone(), *o)).collect();
+        // Hashed in memory, so that only a changed set of packages writes a tree
+        let head = GitRepo::commit_index_oid(&entries)?;
+        if !self.repo.contains(head)? {
+            self.repo.write_commit_index(&entries)?;
+        }
+        let previous = match since {
+            Some(since) if since == head => Some(current.clone()),
+            Some(since) => self.repo.read_commit_index(since)?,
+            None => None,
+        };
+        Ok(match previous {
+            Some(previous) => RefAdvertisement::since(head, &previous, &current),
+            None => RefAdvertisement::full(Some(head), &current),
+        })
+    }
+
+    /// Lists the packages the store held right after a commit of its history or at a
+    /// point in time. Packages added before the history was recorded count as
+    /// present all along.
+    pub fn entries_at(&self, at: &PointInTime, options: &ListOptions) -> Result<Vec<Entry>> {
+        // Packages which are still stored map to `None`, removed ones to the narinfo
+        // they had
+        let mut narinfos: HashMap<String, Option<Oid>> = self
+            .repo
+            .list_references("refs/*/narinfo")?
+            .iter()
+            .filter_map(|r| r.split('/').nth(1))
+            .map(|hash| (hash.to_string(), None))
+            .collect();
+        let mut reached = false;
+        // Walks back from the newest commit, undoing every change made after `at`
+        self.repo.walk_log(HISTORY_REF, |commit| {
+            reached = match at {
+                PointInTime::Commit(prefix) => commit.id.to_string().starts_with(prefix.as_str()),
+                PointInTime::Time(time) => commit.time <= *time,
+            };
+            if reached {
+                return Ok(false);
+            }
+            for record in history::parse_records(&commit.message)?.iter().rev() {
+                match record.change {
+                    Change::Added => narinfos.remove(&record.hash),
+                    Change::Removed => narinfos.insert(record.hash.clone(), Some(record.narinfo)),
+                    Change::Repaired => None,
+                };
+            }
+            Ok(true)
+        })?;
+        if let PointInTime::Commit(prefix) = at
+            && !reached
+        {
+            bail!("{} is not a commit of the history", prefix);
+        }
+
+        let hashes = narinfos.keys().cloned().collect();
+        let hashes = listing::filter_system(hashes, options, |hash| {
+            let narinfo = match narinfos[hash] {
+                None => self.get_narinfo(hash)?,
+                Some(oid) => self.repo.get_blob(oid).ok(),
+            };
+            Ok(narinfo.and_then(|narinfo| Self::system_from_narinfo(&narinfo)))
+        })?;
+        listing::select(hashes, options, |hash| match narinfos[hash] {
+            None => self.get_entry_name(hash),
+            // The objects of removed packages are gone after the next garbage collection
+            Some(oid) => match self.repo.get_blob(oid) {
+                Ok(narinfo) => Self::name_from_narinfo(hash, &narinfo),
+                Err(_) => Ok("(pruned)".to_string()),
+            },
+        })
+    }
+
+    /// Returns the recorded changes matching `filter`, newest first.
+    pub fn history(&self, filter: &HistoryFilter) -> Result<Vec<HistoryEntry>> {
+        let mut entries = Vec::new();
+        self.repo.walk_log(HISTORY_REF, |commit| {
+            if filter.since.is_some_and(|since| commit.time < since) {
+                return Ok(false);
+            }
+            for record in history::parse_records(&commit.message)? {
+                if !filter.matches(commit.time, &record) {
+                    continue;
+                }
+                let name = self
+                    .repo
+                    .get_blob(record.narinfo)
+                    .ok()
+                    .and_then(|narinfo| Self::name_from_narinfo(&record.hash, &narinfo).ok());
+                entries.push(HistoryEntry {
+                    id: commit.id,
+                    time: commit.time,
+                    identity: commit.email.clone(),
+                    record,
+                    name,
+                });
+            }
+            Ok(true)
+        })?;
+        Ok(entries)
+    }
+
+    /// Appends an operation to the audit trail. The operation already took place,
+    /// so a failure is only logged. Read-only stores have nothing to audit.
+    pub fn audit(&self, record: &AuditRecord) {
+        if self.settings.read_only {
+            return;
+        }
+        let author = (record.principal.as_str(), record.principal.as_str());
+        if let Err(e) = self
+            .repo
+            .append_to_log(AUDIT_REF, &record.to_message(), author)
+        {
+            warn!(
+                "Could not record {} {} in the audit trail: {}",
+                record.action, record.target, e
+            );
+        }
+    }
+
+    /// Drops the entries of the audit trail recorded more than `retention` seconds
+    /// ago. Returns how many were dropped, or would be with `dry_run`.
+    fn expire_audit(&self, retention: u64, dry_run: bool) -> Result<usize> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let cutoff = now.saturating_sub(retention as i64);
+        if !dry_run {
+            return self.repo.truncate_log(AUDIT_REF, cutoff);
+        }
+        // Like `truncate_log`, everything from the first older entry on is dropped
+        let mut expired = 0;
+        self.repo.walk_log(AUDIT_REF, |commit| {
+            if expired > 0 || commit.time < cutoff {
+                expired += 1;
+            }
+            Ok(true)
+        })?;
+        Ok(expired)
+    }
+
+    /// Returns the audited operations matching `filter`, newest first.
+    pub fn audit_trail(&self, filter: &AuditFilter) -> Result<Vec<AuditEntry>> {
+        let mut entries = Vec::new();
+        self.repo.walk_log(AUDIT_REF, |commit| {
+            if filter.since.is_some_and(|since| commit.time < since) {
+                return Ok(false);
+            }
+            let record = AuditRecord::parse(&commit.message)?;
+            if filter.matches(commit.time, &record) {
+                entries.push(AuditEntry {
+                    id: commit.id,
+                    time: commit.time,
+                    record,
+                });
+            }
+            Ok(true)
+        })?;
+        Ok(entries)
+    }
+
+    fn get_entry_name(&self, hash: &str) -> Result<String> {
+        let narinfo = self
+            .get_narinfo(hash)?
+            .ok_or_else(|| anyhow!("Could not find narinfo for {}", hash))?;
+        Self::name_from_narinfo(hash, &narinfo)
+    }
+
+    fn name_from_narinfo(hash: &str, narinfo: &[u8]) -> Result<String> {
+        let narinfo = String::from_utf8_lossy(narinfo);
+        let store_path = NarInfo::field(&narinfo, "StorePath")
+            .ok_or_else(|| anyhow!("Narinfo of {} does not contain a store path", hash))?;
+        Ok(NixPath::new(store_path)?.get_name().to_string())
+    }
+
+    fn system_from_narinfo(narinfo: &[u8]) -> Option<String> {
+        NarInfo::field(&String::from_utf8_lossy(narinfo), "System").map(str::to_string)
+    }
+
+    /// The hash of the derivation a package was built from, if its narinfo names one.
+    fn deriver_from_narinfo(narinfo: &[u8]) -> Option<String> {
+        let narinfo = String::from_utf8_lossy(narinfo);
+        let deriver = NixPath::new(NarInfo::field(&narinfo, "Deriver")?).ok()?;
+        Some(deriver.get_base_32_hash().to_string())
+    }
+
+    /// Removes a single package, leaving a tombstone which keeps its objects until
+    /// the tombstone grace period passed. Unless `force` is set, the package is only
+    /// removed if no other stored package references it and it is not tagged. With
+    /// `force` its tags are removed along with it.
+    pub fn delete(&self, hash: &str, force: bool) -> Result<()> {
+        check_hash(hash)?;
+        self.remove(hash, force, true)
+    }
+
+    /// Removes all references of a single package without leaving a tombstone, so
+    /// that its objects are pruned once the retention period passed.
+    pub fn purge(&self, hash: &str, force: bool) -> Result<()> {
+        check_hash(hash)?;
+        self.remove(hash, force, false)
+    }
+
+    fn remove(&self, hash: &str, force: bool, tombstone: bool) -> Result<()> {
+        let refs = self
+            .repo
+            .list_references(&format!("{}/*", self.get_package_ref(hash)))?;
+        if refs.is_empty() {
+            bail!("Package {} is not in the cache", hash);
+        }
+        let tag_names = self.tags_by_hash()?.remove(hash).unwrap_or_default();
+        if !force {
+            let referrers = self.referrers(hash)?;
+            if !referrers.is_empty() {
+                bail!(
+                    "Package {} is still referenced by: {}",
+                    hash,
+                    referrers.join(", ")
+                );
+            }
+            if !tag_names.is_empty() {
+                bail!(
+                    "Package {} is tagged as {}, pass --force to remove it along with its tags",
+                    hash,
+                    tag_names.join(", ")
+                );
+            }
+        }
+        for name in &tag_names {
+            self.repo.delete_ref(&tags::tag_ref(name))?;
+            info!("Removed tag {} of {}", name, hash);
+        }
+        let had_narinfo = refs.contains(&self.get_narinfo_ref(hash));
+        let records = self.history_records(Change::Removed, &[hash.to_string()]);
+        self.delete_nar_hash_ref(hash)?;
+        self.delete_deriver_ref(hash)?;
+        self.drop_tombstones(hash)?;
+        self.drop_revisions(hash)?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        for reference in &refs {
+            if tombstone
+                && let Some(oid) = self.repo.get_oid_from_reference(reference)
+                && let Some(kind) = reference.rsplit('/').next()
+            {
+                self.repo
+                    .update_ref(&tombstones::tombstone_ref(hash, now, kind), oid)?;
+            }
+            debug!("Deleting reference {}", reference);
+            self.repo.delete_ref(reference)?;
+        }
+        self.record_history(&records);
+        if had_narinfo && let Some(count) = self.package_count.lock().unwrap().as_mut() {
+            *count -= 1;
+        }
+        info!("Deleted package {}", hash);
+        Ok(())
+    }
+
+    /// Restores a removed package from its history record, along with the removed
+    /// dependencies it needs. Returns the hashes of the restored packages. Fails
+    /// once garbage collection has pruned the objects of one of them.
+    pub fn undelete(&self, hash: &str) -> Result<Vec<String>> {
+        check_hash(hash)?;
+        if self.entry_exists(hash)? {
+            bail!("Package {} is stored already", hash);
+        }
+        // The newest change of every package which is currently removed
+        let mut newest = HashMap::new();
+        self.repo.walk_log(HISTORY_REF, |commit| {
+            for record in history::parse_records(&commit.message)? {
+                newest.entry(record.hash.clone()).or_insert(record);
+            }
+            Ok(true)
+        })?;
+
+        let mut staged = StagedRefs::new(&self.repo, hash);
+        let mut restored = Vec::new();
+        let mut open = VecDeque::from([hash.to_string()]);
+        while let Some(hash) = open.pop_front() {
+            if restored.contains(&hash) || self.entry_exists(&hash)? {
+                continue;
+            }
+            let dependencies = match newest.get(&hash) {
+                Some(record) if record.change == Change::Removed => {
+                    self.restore_refs(&mut staged, record)
+                }
+                _ => Err(anyhow!(
+                    "Package {} was not removed according to the history",
+                    hash
+                )),
+            };
+            match dependencies {
+                Ok(dependencies) => open.extend(dependencies),
+                Err(e) => {
+                    staged.roll_back();
+                    return Err(e);
+                }
+            }
+            restored.push(hash);
+        }
+        for hash in &restored {
+            self.restore_tombstone(hash)?;
+        }
+        self.record_history(&self.history_records(Change::Added, &restored));
+        if let Some(count) = self.package_count.lock().unwrap().as_mut() {
+            *count += restored.len();
+        }
+        info!("Restored {} packages", restored.len());
+        Ok(restored)
+    }
+
+    /// Points the references of a removed package at its recorded objects again and
+    /// returns the hashes of its dependencies.
+    fn restore_refs(&self, staged: &mut StagedRefs, record: &Record) -> Result<Vec<String>> {
+        let pruned = || format!("The objects of {} have been pruned", record.hash);
+        self.repo
+            .get_commit_tree(record.commit)
+            .with_context(pruned)?;
+        let narinfo_blob = self.repo.get_blob(record.narinfo).with_context(pruned)?;
+        let narinfo = NarInfo::parse(&String::from_utf8_lossy(&narinfo_blob))?;
+
+        staged.add(&self.get_result_ref(&record.hash), record.commit)?;
+        staged.add(&self.get_narinfo_ref(&record.hash), record.narinfo)?;
+        let nar_hash_ref = self.get_nar_hash_ref(&narinfo.nar_hash);
+        if self.repo.get_oid_from_reference(&nar_hash_ref).is_none() {
+            staged.update(&nar_hash_ref, record.commit)?;
+        }
+        if let Some(deriver) = Self::deriver_from_narinfo(&narinfo_blob) {
+            staged.update(
+                &derivers::deriver_ref(&deriver, &record.hash),
+                record.commit,
+            )?;
+        }
+        Ok(narinfo
+            .get_dependencies()
+            .iter()
+            .map(|dep| dep.get_base_32_hash().to_string())
+            .collect())
+    }
+
+    /// Points the references of a restored package which `restore_refs` does not
+    /// recover, like its provenance, at the ones of its tombstone, and drops the
+    /// tombstone.
+    fn restore_tombstone(&self, hash: &str) -> Result<()> {
+        for reference in self.tombstone_refs(hash)? {
+            let Some((_, _, kind)) = tombstones::parse_tombstone_ref(&reference) else {
+                continue;
+            };
+            let restored = format!("{}/{kind}", self.get_package_ref(hash));
+            if !self.repo.reference_exists(&restored)?
+                && let Some(oid) = self.repo.get_oid_from_reference(&reference)
+            {
+                self.repo.update_ref(&restored, oid)?;
+            }
+        }
+        self.drop_tombstones(hash)
+    }
+
+    fn tombstone_refs(&self, hash: &str) -> Result<Vec<String>> {
+        self.repo
+            .list_references(&format!("{TOMBSTONES_NAMESPACE}/{hash}/*"))
+    }
+
+    fn drop_tombstones(&self, hash: &str) -> Result<()> {
+        for reference in self.tombstone_refs(hash)? {
+            self.repo.delete_ref(&reference)?;
+        }
+        Ok(())
+    }
+
+    /// Lists the removed packages whose objects are kept by a tombstone, most
+    /// recently removed first.
+    pub fn tombstones(&self) -> Result<Vec<Tombstone>> {
+        let mut removed: BTreeMap<String, Tombstone> = BTreeMap::new();
+        for reference in self
+            .repo
+            .list_references(&format!("{TOMBSTONES_NAMESPACE}/*"))?
+        {
+            let Some((hash, time, kind)) = tombstones::parse_tombstone_ref(&reference) else {
+                continue;
+            };
+            // Packages added again since are stored, whatever their tombstone says
+            if self.entry_exists(hash)? {
+                continue;
+            }
+            let tombstone = removed.entry(hash.to_string()).or_insert(Tombstone {
+                hash: hash.to_string(),
+                removed_at: time,
+                store_path: None,
+            });
+            tombstone.removed_at = tombstone.removed_at.max(time);
+            if kind == "narinfo"
+                && let Some(oid) = self.repo.get_oid_from_reference(&reference)
+            {
+                let narinfo = self.repo.get_blob(oid)?;
+                tombstone.store_path =
+                    NarInfo::field(&String::from_utf8_lossy(&narinfo), "StorePath")
+                        .map(str::to_string);
+            }
+        }
+        let mut tombstones: Vec<Tombstone> = removed.into_values().collect();
+        tombstones.sort_by(|a, b| b.removed_at.cmp(&a.removed_at).then(a.hash.cmp(&b.hash)));
+        Ok(tombstones)
+    }
+
+    /// Gives a stored package a human readable name, which garbage collection
+    /// keeps along with its closure. An existing tag is only moved to another
+    /// package with `force`.
+    pub fn tag(&self, name: &str, hash: &str, force: bool) -> Result<()> {
+        tags::validate_tag_name(name)?;
+        check_hash(hash)?;
+        let Some(narinfo) = self
+            .repo
+            .get_oid_from_reference(&self.get_narinfo_ref(hash))
+        else {
+            bail!("Package {} is not in the cache", hash);
+        };
+        let reference = tags::tag_ref(name);
+        if !force
+            && let Some(tag) = self.read_tag(name)?
+            && tag.hash != hash
+        {
+            bail!("Tag {} exists already, pass --force to move it", name);
+        }
+        self.repo.update_ref(&reference, narinfo)?;
+        info!("Tagged {} as {}", hash, name);
+        Ok(())
+    }
+
+    /// Removes a tag. Returns false if there was no such tag.
+    pub fn untag(&self, name: &str) -> Result<bool> {
+        tags::validate_tag_name(name)?;
+        let reference = tags::tag_ref(name);
+        if !self.repo.reference_exists(&reference)? {
+            return Ok(false);
+        }
+        self.repo.delete_ref(&reference)?;
+        info!("Removed tag {}", name);
+        Ok(true)
+    }
+
+    /// Lists the tags, sorted by name.
+    pub fn tags(&self) -> Result<Vec<Tag>> {
+        let mut tags = Vec::new();
+        for reference in self.repo.list_references(&format!("{TAGS_NAMESPACE}/*"))? {
+            if let Some(name) = tags::parse_tag_ref(&reference)
+                && let Some(tag) = self.read_tag(name)?
+            {
+                tags.push(tag);
+            }
+        }
+        tags.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(tags)
+    }
+
+    /// Looks up the stored package a tag names.
+    pub fn resolve_tag(&self, name: &str) -> Result<Tag> {
+        tags::validate_tag_name(name)?;
+        match self.read_tag(name)? {
+            Some(tag) if tag.stored => Ok(tag),
+            Some(tag) => bail!("The package tagged {} is no longer stored", tag.name),
+            None => bail!("No tag {}", name),
+        }
+    }
+
+    fn read_tag(&self, name: &str) -> Result<Option<Tag>> {
+        let Some(oid) = self.repo.get_oid_from_reference(&tags::tag_ref(name)) else {
+            return Ok(None);
+        };
+        let narinfo = self.repo.get_blob(oid)?;
+        let Some(store_path) = NarInfo::field(&String::from_utf8_lossy(&narinfo), "StorePath")
+        else {
+            bail!("Tag {} does not point at a narinfo", name);
+        };
+        let hash = NixPath::new(store_path)?.get_base_32_hash().to_string();
+        Ok(Some(Tag {
+            name: name.to_string(),
+            stored: self.entry_exists(&hash)?,
+            hash,
+            store_path: store_path.to_string(),
+        }))
+    }
+
+    /// Publishes the next version of a channel, which lists the given packages.
+    pub fn publish_channel(&self, name: &str, hashes: &[String]) -> Result<ChannelVersion> {
+        channels::validate_channel_name(name)?;
+        if hashes.is_empty() {
+            bail!("A channel needs at least one package");
+        }
+        let mut entries = Vec::new();
+        for hash in hashes {
+            let (Some(commit), Some(narinfo)) = (self.get_commit(hash), self.get_narinfo(hash)?)
+            else {
+                bail!("Package {} is not in the cache", hash);
+            };
+            let name = Self::name_from_narinfo(hash, &narinfo)?;
+            entries.push((format!("{hash}-{name}"), commit));
+        }
+        let tree = self.repo.write_commit_index(&entries)?;
+        let (user, email) = self.identity();
+        self.repo.append_tree_to_log(
+            &channels::channel_ref(name),
+            tree,
+            (&user, &email),
+            |previous| {
+                let version = previous
+                    .and_then(|message| channels::parse_version(message.trim()))
+                    .map_or(1, |(_, version)| version + 1);
+                Ok(channels::version_name(name, version))
+            },
+        )?;
+        let published = self.channel_versions(name)?.swap_remove(0);
+        info!(
+            "Published {}",
+            channels::version_name(name, published.version)
+        );
+        Ok(published)
+    }
+
+    /// Lists the newest version of every channel, sorted by name.
+    pub fn channels(&self) -> Result<Vec<ChannelVersion>> {
+        let mut newest = Vec::new();
+        for reference in self
+            .repo
+            .list_references(&format!("{CHANNELS_NAMESPACE}/*"))?
+        {
+            let Some(name) = channels::parse_channel_ref(&reference) else {
+                continue;
+            };
+            if let Some(version) = self.channel_versions(name)?.into_iter().next() {
+                newest.push(version);
+            }
+        }
+        newest.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(newest)
+    }
+
+    /// Lists the versions of a channel, newest first.
+    pub fn channel_versions(&self, name: &str) -> Result<Vec<ChannelVersion>> {
+        let mut versions = Vec::new();
+        self.repo.walk_log(&channels::channel_ref(name), |commit| {
+            let Some((_, version)) = channels::parse_version(commit.message.trim()) else {
+                bail!(
+                    "Channel {} has a commit {} without a version",
+                    name,
+                    commit.id
+                );
+            };
+            let index = self
+                .repo
+                .read_commit_index(commit.tree)?
+                .unwrap_or_default();
+            versions.push(ChannelVersion {
+                name: name.to_string(),
+                version,
+                published_at: commit.time,
+                publisher: commit.email.clone(),
+                store_paths: index
+                    .into_keys()
+                    .map(|entry| format!("/nix/store/{entry}"))
+                    .collect(),
+            });
+            Ok(true)
+        })?;
+        Ok(versions)
+    }
+
+    /// Looks up a channel version like `team-tools-v42`, or the newest version of
+    /// a channel given just its name.
+    pub fn channel(&self, spec: &str) -> Result<ChannelVersion> {
+        let (name, version) = match channels::parse_version(spec) {
+            Some((name, version)) => (name, Some(version)),
+            None => (spec, None),
+        };
+        channels::validate_channel_name(name)?;
+        let versions = self.channel_versions(name)?;
+        let found = match version {
+            Some(version) => versions.into_iter().find(|v| v.version == version),
+            None => versions.into_iter().next(),
+        };
+        found.ok_or_else(|| anyhow!("No channel {}", spec))
+    }
+
+    /// Fetches the versions of a channel from the first git peer which published the
+    /// requested one, then looks it up like `channel`. Returns the URL of that peer
+    /// along with the version. The packages are not fetched, see
+    /// `fetch_channel_packages`.
+    pub fn fetch_channel(&self, spec: &str) -> Result<(ChannelVersion, String)> {
+        let name = channels::parse_version(spec).map_or(spec, |(name, _)| name);
+        channels::validate_channel_name(name)?;
+        let reference = channels::channel_ref(name);
+        for url in &self.settings.remotes {
+            match self.peer_handshake(url.as_str()) {
+                Ok(Some(handshake)) if !handshake.supports("channels") => {
+                    warn!(
+                        "Not fetching channel {} from {}, gachix {} does not publish channels",
+                        name, url, handshake.version
+                    );
+                    continue;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Could not fetch channel {} from {}: {}", name, url, e);
+                    continue;
+                }
+            }
+            if let Err(e) = self.repo.fetch_into(url.as_str(), &reference, &reference) {
+                warn!("Could not fetch channel {} from {}: {}", name, url, e);
+                continue;
+            }
+            if let Ok(version) = self.channel(spec) {
+                return Ok((version, url.to_string()));
+            }
+        }
+        bail!("No git peer published channel {}", spec)
+    }
+
+    /// Fetches the missing packages of a channel version, along with their closures,
+    /// from the git peer which published it, see `fetch_channel`. Every package has
+    /// to be the one the channel lists: either the recorded commit or, for packages
+    /// which were stored before, a commit with the same contents. Returns how many
+    /// packages were fetched.
+    pub fn fetch_channel_packages(&self, version: &ChannelVersion, peer: &str) -> Result<usize> {
+        let index = self.channel_index(version)?;
+        let mut fetched = 0;
+        let mut mismatched = Vec::new();
+        for (name, recorded) in &index {
+            let path = NixPath::new(&format!("/nix/store/{name}"))?;
+            let hash = path.get_base_32_hash();
+            if !self.entry_exists(hash)? {
+                self.fetch_closure(hash, peer)?;
+                fetched += 1;
+            }
+            let Some(stored) = self.get_commit(hash) else {
+                bail!("Package {} of {} is not stored", name, version.name);
+            };
+            if stored != *recorded && !self.same_contents(hash, stored, *recorded, peer)? {
+                mismatched.push(name.clone());
+            }
+        }
+        if !mismatched.is_empty() {
+            bail!(
+                "Packages of {} differ from the ones it lists: {}",
+                channels::version_name(&version.name, version.version),
+                mismatched.join(", ")
+            );
+        }
+        Ok(fetched)
+    }
+
+    /// The commits a channel version lists, by the file names of their store paths.
+    fn channel_index(&self, version: &ChannelVersion) -> Result<BTreeMap<String, Oid>> {
+        let name = channels::version_name(&version.name, version.version);
+        let mut index = None;
+        self.repo
+            .walk_log(&channels::channel_ref(&version.name), |commit| {
+                if commit.message.trim() != name {
+                    return Ok(true);
+                }
+                index = self.repo.read_commit_index(commit.tree)?;
+                Ok(false)
+            })?;
+        index.ok_or_else(|| anyhow!("No channel {}", name))
+    }
+
+    /// Whether the stored commit of a package has the same tree as the commit a
+    /// channel recorded for it. The recorded commit is fetched from the peer if it is
+    /// not stored, as long as the peer still has it.
+    fn same_contents(&self, hash: &str, stored: Oid, recorded: Oid, peer: &str) -> Result<bool> {
+        if self.repo.get_commit_tree(recorded).is_err() {
+            let scratch = format!("refs/clone/{hash}.result");
+            let fetch = self
+                .repo
+                .fetch_into(peer, &self.get_result_ref(hash), &scratch);
+            if self.repo.reference_exists(&scratch)? {
+                self.repo.delete_ref(&scratch)?;
+            }
+            fetch?;
+        }
+        match self.repo.get_commit_tree(recorded) {
+            Ok(tree) => Ok(tree == self.repo.get_commit_tree(stored)?),
+            Err(_) => Ok(false),
+        }
+    }
+
+    /// The newest `keep` versions of every channel listing a package, by its hash.
+    fn channels_by_hash(&self, keep: usize) -> Result<HashMap<String, Vec<String>>> {
+        let mut by_hash: HashMap<String, Vec<String>> = HashMap::new();
+        if keep == 0 {
+            return Ok(by_hash);
+        }
+        for channel in self.channels()? {
+            for version in self.channel_versions(&channel.name)?.into_iter().take(keep) {
+                let name = channels::version_name(&version.name, version.version);
+                for store_path in &version.store_paths {
+                    let hash = NixPath::new(store_path)?.get_base_32_hash().to_string();
+                    by_hash.entry(hash).or_default().push(name.clone());
+                }
+            }
+        }
+        Ok(by_hash)
+    }
+
+    /// The names of the tags of every tagged package, by its hash.
+    fn tags_by_hash(&self) -> Result<HashMap<String, Vec<String>>> {
+        let mut by_hash: HashMap<String, Vec<String>> = HashMap::new();
+        for tag in self.tags()? {
+            if tag.stored {
+                by_hash.entry(tag.hash).or_default().push(tag.name);
+            }
+        }
+        Ok(by_hash)
+    }
+
+    /// Revokes a package: removes it along with every stored package depending on
+    /// it, without leaving tombstones, and records the revocation, which git peers
+    /// following the deletions of this instance act on. Its NAR is no longer served,
+    /// even before garbage collection prunes its objects. Returns the hashes of the
+    /// removed packages.
+    pub fn revoke(&self, hash: &str, reason: &str) -> Result<Vec<String>> {
+        let (_, identity) = self.identity();
+        self.revoke_as(hash, reason, &identity)
+    }
+
+    fn revoke_as(&self, hash: &str, reason: &str, revoked_by: &str) -> Result<Vec<String>> {
+        check_hash(hash)?;
+        if self
+            .repo
+            .reference_exists(&revocations::revocation_ref(hash))?
+        {
+            bail!("Package {} is revoked already", hash);
+        }
+        let narinfo = self
+            .get_narinfo(hash)?
+            .map(|narinfo| String::from_utf8_lossy(&narinfo).to_string());
+        let revocation = Revocation {
+            hash: hash.to_string(),
+            store_path: narinfo
+                .as_deref()
+                .and_then(|narinfo| NarInfo::field(narinfo, "StorePath"))
+                .map(str::to_string),
+            nar_key: narinfo
+                .as_deref()
+                .and_then(Self::nar_key)
+                .map(str::to_string),
+            reason: reason.to_string(),
+            revoked_by: revoked_by.to_string(),
+            revoked_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64,
+        };
+        let oid = self
+            .repo
+            .add_file_content(revocation.to_json()?.as_bytes())?;
+        self.repo
+            .update_ref(&revocations::revocation_ref(hash), oid)?;
+
+        let mut removed = Vec::new();
+        if self.entry_exists(hash)? {
+            removed.push(hash.to_string());
+        }
+        removed.extend(self.dependents(hash)?);
+        for hash in &removed {
+            self.remove(hash, true, false)?;
+        }
+        info!(
+            "Revoked package {}, removing {} packages",
+            hash,
+            removed.len()
+        );
+        Ok(removed)
+    }
+
+    /// The stored packages which depend on a package, directly or through others.
+    fn dependents(&self, hash: &str) -> Result<Vec<String>> {
+        let mut referrers: HashMap<String, Vec<String>> = HashMap::new();
+        for reference in self.repo.list_references("refs/*/narinfo")? {
+            let Some(candidate) = reference.split('/').nth(1) else {
+                continue;
+            };
+            let Some(narinfo) = self.get_narinfo(candidate)? else {
+                continue;
+            };
+            let narinfo = String::from_utf8_lossy(&narinfo);
+            for dependency in NarInfo::field(&narinfo, "References")
+                .unwrap_or("")
+                .split(' ')
+                .filter_map(|r| r.split('-').next())
+                .filter(|dependency| *dependency != candidate)
+            {
+                referrers
+                    .entry(dependency.to_string())
+                    .or_default()
+                    .push(candidate.to_string());
+            }
+        }
+        let mut dependents = Vec::new();
+        let mut open = VecDeque::from([hash.to_string()]);
+        while let Some(hash) = open.pop_front() {
+            for referrer in referrers.remove(&hash).unwrap_or_default() {
+                if !dependents.contains(&referrer) {
+                    dependents.push(referrer.clone());
+                    open.push_back(referrer);
+                }
+            }
+        }
+        Ok(dependents)
+    }
+
+    /// Lists the revoked packages, most recently revoked first.
+    pub fn revocations(&self) -> Result<Vec<Revocation>> {
+        let mut revoked = Vec::new();
+        for reference in self
+            .repo
+            .list_references(&format!("{REVOCATIONS_NAMESPACE}/*"))?
+        {
+            if revocations::parse_revocation_ref(&reference).is_none() {
+                continue;
+            }
+            let Some(oid) = self.repo.get_oid_from_reference(&reference) else {
+                continue;
+            };
+            revoked.push(Revocation::from_json(&self.repo.get_blob(oid)?)?);
+        }
+        revoked.sort_by(|a, b| b.revoked_at.cmp(&a.revoked_at).then(a.hash.cmp(&b.hash)));
+        Ok(revoked)
+    }
+
+    /// Whether the NAR served under `key` belongs to a removed or revoked package
+    /// and to no stored one, so that it is not served until its objects are pruned.
+    fn is_withdrawn(&self, key: &str) -> Result<bool> {
+        let revoked = self
+            .revocations()?
+            .iter()
+            .any(|revocation| revocation.nar_key.as_deref() == Some(key));
+        let removed = || -> Result<bool> {
+            for reference in self
+                .repo
+                .list_references(&format!("{TOMBSTONES_NAMESPACE}/*"))?
+            {
+                if let Some((_, _, "narinfo")) = tombstones::parse_tombstone_ref(&reference)
+                    && let Some(oid) = self.repo.get_oid_from_reference(&reference)
+                    && Self::nar_key(&String::from_utf8_lossy(&self.repo.get_blob(oid)?))
+                        == Some(key)
+                {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        };
+        Ok((revoked || removed()?) && !self.served_nar_keys()?.contains(key))
+    }
+
+    /// Revokes the packages which a git peer revoked, along with the stored packages
+    /// depending on them, see `revoke`. Plain removals on the peer are not followed.
+    /// Every revocation is followed once: a revoked package which is added here
+    /// again afterwards is kept. With `dry_run`, the packages are only listed.
+    pub fn follow_deletions(&self, dry_run: bool) -> Result<Vec<PeerDeletion>> {
+        let mut followed: Vec<PeerDeletion> = Vec::new();
+        let mut revoked = HashSet::new();
+        for url in &self.settings.remotes {
+            match self.peer_handshake(url.as_str()) {
+                Ok(Some(handshake)) if handshake.supports("revocations") => {}
+                Ok(Some(handshake)) => {
+                    info!(
+                        "Not following the deletions of git peer {}, gachix {} does not record revocations",
+                        url, handshake.version
+                    );
+                    continue;
+                }
+                Ok(None) => {
+                    info!(
+                        "Not following the deletions of git peer {}, it does not record revocations",
+                        url
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Not following the deletions of git peer {}: {}", url, e);
+                    continue;
+                }
+            }
+            let references = match self.repo.list_remote_references(url.as_str()) {
+                Ok(references) => references,
+                Err(e) => {
+                    warn!("Not following the deletions of git peer {}: {}", url, e);
+                    continue;
+                }
+            };
+            for hash in references
+                .iter()
+                .filter_map(|reference| revocations::parse_revocation_ref(reference))
+            {
+                if !is_store_hash(hash) {
+                    warn!(
+                        "Ignoring the revocation of {:?} from git peer {}",
+                        hash, url
+                    );
+                    continue;
+                }
+                if !revoked.insert(hash.to_string())
+                    || self
+                        .repo
+                        .reference_exists(&revocations::revocation_ref(hash))?
+                {
+                    continue;
+                }
+                let mut removed = Vec::new();
+                if self.entry_exists(hash)? {
+                    removed.push(hash.to_string());
+                }
+                removed.extend(self.dependents(hash)?);
+                for removed in removed {
+                    let store_path = self.get_narinfo(&removed)?.and_then(|narinfo| {
+                        NarInfo::field(&String::from_utf8_lossy(&narinfo), "StorePath")
+                            .map(str::to_string)
+                    });
+                    followed.push(PeerDeletion {
+                        hash: removed,
+                        store_path,
+                        peer: url.to_string(),
+                        revoked: hash.to_string(),
+                    });
+                }
+                if !dry_run {
+                    self.revoke_as(hash, &format!("Revoked by git peer {url}"), url.as_str())?;
+                }
+            }
+        }
+        Ok(followed)
+    }
+
+    /// Periodically follows the deletions of the git peers in the background, if
+    /// `store.follow_deletions` is set. Skipped in maintenance mode. The follower
+    /// stops once `cancel` is triggered.
+    pub fn spawn_deletion_follower(
+        &self,
+        cancel: CancellationToken,
+    ) -> Result<Option<thread::JoinHandle<()>>> {
+        let Some(interval) = self.settings.follow_deletions else {
+            return Ok(None);
+        };
+        if self.settings.read_only || self.settings.remotes.is_empty() {
+            return Ok(None);
+        }
+        let store = self.clone();
+        let interval = Duration::from_secs(interval);
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()?;
+        Ok(Some(thread::spawn(move || {
+            while !cancel.is_cancelled() {
+                if store.in_maintenance() {
+                    debug!("Not following deletions in maintenance mode");
+                } else if let Err(e) = store.follow_deletions(false) {
+                    warn!("Following the deletions of git peers failed: {}", e);
+                }
+                let _ = rt.block_on(tokio::time::timeout(interval, cancel.cancelled()));
+            }
+        })))
+    }
+
+    /// Drops the tombstones of packages removed longer than `grace_period` seconds
+    /// ago, and those of packages which were added again, so that the next prune
+    /// removes their objects. Returns how many packages lost their tombstone, or
+    /// would with `dry_run`.
+    fn expire_tombstones(&self, grace_period: u64, dry_run: bool) -> Result<usize> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let mut expired = BTreeSet::new();
+        for reference in self
+            .repo
+            .list_references(&format!("{TOMBSTONES_NAMESPACE}/*"))?
+        {
+            let Some((hash, time, _)) = tombstones::parse_tombstone_ref(&reference) else {
+                continue;
+            };
+            let stored = self.entry_exists(hash)?;
+            if stored || revisions::is_expired(time, now, grace_period) {
+                if !dry_run {
+                    self.repo.delete_ref(&reference)?;
+                }
+                if !stored {
+                    expired.insert(hash.to_string());
+                }
+            }
+        }
+        Ok(expired.len())
+    }
+
+    /// Removes the NAR hash index entry of a package, unless it belongs to another
+    /// package with the same contents.
+    fn delete_nar_hash_ref(&self, hash: &str) -> Result<()> {
+        let (Some(commit_oid), Some(narinfo)) = (self.get_commit(hash), self.get_narinfo(hash)?)
+        else {
+            return Ok(());
+        };
+        let narinfo = String::from_utf8_lossy(&narinfo);
+        let Some(nar_hash) = NarInfo::field(&narinfo, "NarHash") else {
+            return Ok(());
+        };
+        let nar_hash_ref = self.get_nar_hash_ref(nar_hash);
+        if self.repo.get_oid_from_reference(&nar_hash_ref) == Some(commit_oid) {
+            self.repo.delete_ref(&nar_hash_ref)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the hashes of all stored packages whose narinfo references `hash`.
+    pub fn referrers(&self, hash: &str) -> Result<Vec<String>> {
+        let mut referrers = Vec::new();
+        for reference in self.repo.list_references("refs/*/narinfo")? {
+            let Some(candidate) = reference.split('/').nth(1) else {
+                continue;
+            };
+            if candidate == hash {
+                continue;
+            }
+            let Some(narinfo) = self.get_narinfo(candidate)? else {
+                continue;
+            };
+            let narinfo = String::from_utf8_lossy(&narinfo);
+            let references = NarInfo::field(&narinfo, "References").unwrap_or("");
+            if references.split(' ').any(|r| r.starts_with(hash)) {
+                referrers.push(candidate.to_string());
+            }
+        }
+        Ok(referrers)
+    }
+
+    /// Checks the repository for entries that are only partially written.
+    pub fn fsck(&self) -> Result<FsckReport> {
+        let hashes_of = |kind: &str| -> Result<BTreeSet<String>> {
+            Ok(self
+                .repo
+                .list_references(&format!("refs/*/{kind}"))?
+                .iter()
+                .filter_map(|r| r.split('/').nth(1))
+                .map(str::to_string)
+                .collect())
+        };
+        let results = hashes_of("result")?;
+        let narinfos = hashes_of("narinfo")?;
+
+        let mut report = FsckReport {
+            checked: results.union(&narinfos).count(),
+            ..Default::default()
+        };
+        for hash in results.difference(&narinfos) {
+            report.dangling.push(DanglingEntry {
+                hash: hash.clone(),
+                missing: RefKind::Narinfo,
+            });
+        }
+        for hash in narinfos.difference(&results) {
+            report.dangling.push(DanglingEntry {
+                hash: hash.clone(),
+                missing: RefKind::Result,
+            });
+        }
+        for hash in &narinfos {
+            if let Some(inconsistency) = self.check_closure(hash, &narinfos)? {
+                report.inconsistent.push(inconsistency);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Checks that every reference of an entry is stored and that the parents of its
+    /// result commit are exactly the result commits of those references.
+    fn check_closure(
+        &self,
+        hash: &str,
+        narinfos: &BTreeSet<String>,
+    ) -> Result<Option<Inconsistency>> {
+        let narinfo_blob = self
+            .get_narinfo(hash)?
+            .ok_or_else(|| anyhow!("Could not find narinfo for {}", hash))?;
+        let narinfo = match NarInfo::parse(&String::from_utf8_lossy(&narinfo_blob)) {
+            Ok(narinfo) => narinfo,
+            Err(e) => {
+                return Ok(Some(Inconsistency::InvalidNarinfo {
+                    hash: hash.to_string(),
+                    error: e.to_string(),
+                }));
+            }
+        };
+        let dependencies = narinfo.get_dependencies();
+        if let Some(missing) = dependencies
+            .iter()
+            .find(|dep| !narinfos.contains(dep.get_base_32_hash()))
+        {
+            return Ok(Some(Inconsistency::MissingReference {
+                hash: hash.to_string(),
+                reference: missing.to_string(),
+            }));
+        }
+
+        let Some(commit_oid) = self.get_commit(hash) else {
+            return Ok(None);
+        };
+        let actual: HashSet<Oid> = self
+            .repo
+            .get_commit_parents(commit_oid)?
+            .into_iter()
+            .collect();
+        let mut missing = Vec::new();
+        let mut expected = HashSet::new();
+        for dep in &dependencies {
+            match self.get_commit(dep.get_base_32_hash()) {
+                Some(oid) => {
+                    expected.insert(oid);
+                    if !actual.contains(&oid) {
+                        missing.push(dep.to_string());
+                    }
+                }
+                None => missing.push(dep.to_string()),
+            }
+        }
+        let unexpected: Vec<String> = actual
+            .difference(&expected)
+            .map(|oid| oid.to_string())
+            .collect();
+        if missing.is_empty() && unexpected.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Inconsistency::ParentMismatch {
+            hash: hash.to_string(),
+            missing,
+            unexpected,
+        }))
+    }
+
+    /// Removes the remaining reference of every dangling entry in `report`.
+    pub fn fix_dangling(&self, report: &FsckReport) -> Result<()> {
+        for entry in &report.dangling {
+            let present = match entry.missing {
+                RefKind::Result => self.get_narinfo_ref(&entry.hash),
+                RefKind::Narinfo => self.get_result_ref(&entry.hash),
+            };
+            info!("Removing dangling reference {}", present);
+            self.repo.delete_ref(&present)?;
+        }
+        // The cached package count no longer matches once narinfo refs are removed
+        *self.package_count.lock().unwrap() = None;
+        Ok(())
+    }
+
+    /// Evicts packages if the disk usage crossed the high watermark.
+    pub fn collect_garbage_if_needed(
+        &self,
+        cancel: &CancellationToken,
+    ) -> Result<Option<GcSummary>> {
+        let Some(high_watermark) = self.settings.gc.high_watermark else {
+            return Ok(None);
+        };
+        let usage = DiskUsage::of(&self.settings.path)?.used_percent();
+        if usage < high_watermark {
+            return Ok(None);
+        }
+        info!("Disk usage of {usage:.1}% crossed the high watermark of {high_watermark}%");
+        self.collect_garbage(&self.gc_policy(None)?, cancel)
+            .map(Some)
+    }
+
+    /// The eviction rules of `store.gc`, or of the named policy of it.
+    pub fn gc_policy(&self, name: Option<&str>) -> Result<Policy> {
+        Policy::new(&self.settings.gc, name)
+    }
+
+    /// Evicts packages which no other package references, largest first, until the
+    /// disk usage is below the low watermark. As the filesystem may be shared, at
+    /// most the size of the stored packages is freed, and eviction stops once a
+    /// round frees no space. When `cancel` is triggered, no further packages are
+    /// evicted; their objects are pruned by the next run.
+    ///
+    /// Tombstones older than their grace period and revisions older than the
+    /// retention period are dropped on every run. Above the low watermark, the
+    /// objects of packages removed, and narinfos replaced, longer than the retention
+    /// period ago are pruned first. Evicted packages leave no tombstone, they are pruned right
+    /// away and cannot be restored.
+    pub fn collect_garbage(
+        &self,
+        policy: &Policy,
+        cancel: &CancellationToken,
+    ) -> Result<GcSummary> {
+        let low_watermark = policy.low_watermark;
+        let mut usage = DiskUsage::of(&self.settings.path)?;
+        let mut summary = GcSummary {
+            usage_before: usage.used_percent(),
+            audit_expired: self.expire_audit(self.settings.gc.audit_retention, false)?,
+            revisions_expired: self.expire_revisions(self.settings.gc.retention, false)?,
+            tombstones_expired: self.expire_tombstones(self.settings.gc.tombstone_grace, false)?,
+            ..Default::default()
+        };
+        if usage.used_percent() > low_watermark {
+            let retention = Duration::from_secs(self.settings.gc.retention);
+            self.prune_unleased(retention, MAX_LEASE_WAIT)?;
+            usage = DiskUsage::of(&self.settings.path)?;
+        }
+        let mut bytes_to_free = usage.bytes_above(low_watermark);
+        if bytes_to_free > 0 {
+            let stored: u64 = self
+                .stored_packages(policy)?
+                .iter()
+                .map(|package| package.nar_size)
+                .sum();
+            bytes_to_free = bytes_to_free.min(stored);
+        }
+        let mut evicted = HashSet::new();
+        while bytes_to_free > 0 {
+            let evictions = gc::select_evictions(
+                self.eviction_candidates(policy)?,
+                bytes_to_free,
+                &policy.evict_first,
+                &policy.never_evict,
+            );
+            if evictions.is_empty() {
+                warn!("No packages left to evict");
+                break;
+            }
+            for hash in &evictions {
+                if cancel.is_cancelled() {
+                    info!("Garbage collection cancelled");
+                    summary.usage_after = usage.used_percent();
+                    return Ok(summary);
+                }
+                self.scheduler.yield_blocking(MAX_YIELD);
+                // The package may have started being served since it was selected
+                if self.is_leased(hash)? {
+                    continue;
+                }
+                self.purge(hash, true)?;
+                evicted.insert(hash.clone());
+                summary.evicted += 1;
+            }
+            self.scheduler.yield_blocking(MAX_YIELD);
+            // Only the evicted packages are pruned right away
+            let retained = self.retain_removed(&evicted)?;
+            let pruned = self.prune_unleased(Duration::ZERO, MAX_LEASE_WAIT);
+            for reference in &retained {
+                self.repo.delete_ref(reference)?;
+            }
+            if !pruned? {
+                break;
+            }
+            let before = usage;
+            usage = DiskUsage::of(&self.settings.path)?;
+            let freed = usage.freed_since(&before);
+            if freed == 0 {
+                warn!("Evicting packages freed no space, the disk is filled by other files");
+                break;
+            }
+            bytes_to_free = bytes_to_free
+                .saturating_sub(freed)
+                .min(usage.bytes_above(low_watermark));
+        }
+        summary.usage_after = usage.used_percent();
+        Ok(summary)
+    }
+
+    /// The key the NAR of a package is served under, taken from the URL of its
+    /// narinfo.
+    fn nar_key(narinfo: &str) -> Option<&str> {
+        NarInfo::field(narinfo, "URL")?
+            .strip_prefix("nar/")?
+            .split('.')
+            .next()
+    }
+
+    fn is_leased(&self, hash: &str) -> Result<bool> {
+        let Some(narinfo) = self.get_narinfo(hash)? else {
+            return Ok(false);
+        };
+        Ok(Self::nar_key(&String::from_utf8_lossy(&narinfo))
+            .is_some_and(|key| self.leases.is_pinned(key)))
+    }
+
+    /// Points references at the objects of the packages removed within the retention
+    /// period, except for those in `evicted`, so that pruning without a grace period
+    /// leaves them restorable. Returns the references, to be deleted after pruning.
+    fn retain_removed(&self, evicted: &HashSet<String>) -> Result<Vec<String>> {
+        // Left behind if a previous collection was interrupted
+        for reference in self
+            .repo
+            .list_references(&format!("{RETAINED_NAMESPACE}/*"))?
+        {
+            self.repo.delete_ref(&reference)?;
+        }
+        let cutoff = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64
+            - self.settings.gc.retention as i64;
+        let mut newest = HashSet::new();
+        let mut removed = Vec::new();
+        self.repo.walk_log(HISTORY_REF, |commit| {
+            if commit.time < cutoff {
+                return Ok(false);
+            }
+            for record in history::parse_records(&commit.message)? {
+                if newest.insert(record.hash.clone())
+                    && record.change == Change::Removed
+                    && !evicted.contains(&record.hash)
+                {
+                    removed.push(record);
+                }
+            }
+            Ok(true)
+        })?;
+        let mut retained = Vec::new();
+        for record in removed {
+            if self.entry_exists(&record.hash)? {
+                continue;
+            }
+            for (kind, oid) in [("result", record.commit), ("narinfo", record.narinfo)] {
+                if self.repo.contains(oid)? {
+                    let reference = format!("{RETAINED_NAMESPACE}/{}.{kind}", record.hash);
+                    self.repo.update_ref(&reference, oid)?;
+                    retained.push(reference);
+                }
+            }
+        }
+        Ok(retained)
+    }
+
+    /// Prunes unreachable objects once no NAR which is no longer served by any
+    /// package is being downloaded, as its objects would disappear mid-transfer.
+    /// Gives up after `max_wait` and returns whether it pruned.
+    fn prune_unleased(&self, grace_period: Duration, max_wait: Duration) -> Result<bool> {
+        let start = Instant::now();
+        loop {
+            let (pinned, releases) = self.leases.pinned();
+            let orphaned = if pinned.is_empty() {
+                0
+            } else {
+                let served = self.served_nar_keys()?;
+                pinned.iter().filter(|key| !served.contains(*key)).count()
+            };
+            if orphaned == 0 {
+                self.repo.prune_unreachable(grace_period)?;
+                return Ok(true);
+            }
+            let Some(remaining) = max_wait.checked_sub(start.elapsed()) else {
+                warn!("Not pruning, {orphaned} NARs of removed packages are still being served");
+                return Ok(false);
+            };
+            self.leases.wait_for_release(releases, remaining);
+        }
+    }
+
+    fn served_nar_keys(&self) -> Result<HashSet<String>> {
+        let mut keys = HashSet::new();
+        for reference in self.repo.list_references("refs/*/narinfo")? {
+            let Some(oid) = self.repo.get_oid_from_reference(&reference) else {
+                continue;
+            };
+            let narinfo = self.repo.get_blob(oid)?;
+            if let Some(key) = Self::nar_key(&String::from_utf8_lossy(&narinfo)) {
+                keys.insert(key.to_string());
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Returns the packages which no other stored package references.
+    fn eviction_candidates(&self, policy: &Policy) -> Result<Vec<Candidate>> {
+        Ok(gc::candidates(&self.stored_packages(policy)?))
+    }
+
+    /// Describes every stored package for garbage collection under `policy`.
+    fn stored_packages(&self, policy: &Policy) -> Result<Vec<StoredPackage>> {
+        let mut tags = self.tags_by_hash()?;
+        let mut channels = self.channels_by_hash(policy.keep_channel_versions)?;
+        let mut packages = Vec::new();
+        for reference in self.repo.list_references("refs/*/narinfo")? {
+            let Some(hash) = reference.split('/').nth(1) else {
+                continue;
+            };
+            let Some(narinfo) = self.get_narinfo(hash)? else {
+                continue;
+            };
+            let name = Self::name_from_narinfo(hash, &narinfo).ok();
+            let narinfo = String::from_utf8_lossy(&narinfo);
+            let references = NarInfo::field(&narinfo, "References")
+                .unwrap_or("")
+                .split(' ')
+                .filter_map(|path| Some(path.split_once('-')?.0))
+                .filter(|reference_hash| *reference_hash != hash)
+                .map(str::to_string)
+                .collect();
+            packages.push(StoredPackage {
+                hash: hash.to_string(),
+                name,
+                nar_size: NarInfo::field(&narinfo, "NarSize")
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or(0),
+                system: NarInfo::field(&narinfo, "System").map(str::to_string),
+                references,
+                // Packages whose NAR is being served are not evicted
+                served: Self::nar_key(&narinfo).is_some_and(|key| self.leases.is_pinned(key)),
+                tags: tags.remove(hash).unwrap_or_default(),
+                channels: channels.remove(hash).unwrap_or_default(),
+            });
+        }
+        Ok(packages)
+    }
+
+    /// Works out what `collect_garbage` would expire, prune and evict under `policy`,
+    /// without changing anything.
+    pub fn simulate_garbage_collection(&self, policy: &Policy) -> Result<GcSimulation> {
+        let gc = &self.settings.gc;
+        let expiry = Expiry {
+            audit: self.expire_audit(gc.audit_retention, true)?,
+            revisions: self.expire_revisions(gc.retention, true)?,
+            tombstones: self.expire_tombstones(gc.tombstone_grace, true)?,
+            prunable: self.prunable_size()?,
+        };
+        let usage = DiskUsage::of(&self.settings.path)?;
+        Ok(gc::simulate(
+            self.stored_packages(policy)?,
+            &usage,
+            policy,
+            expiry,
+        ))
+    }
+
+    /// The NAR sizes of the removed packages whose objects the prune of
+    /// `collect_garbage` removes: their tombstone expires, and they were removed
+    /// longer than the retention period ago.
+    fn prunable_size(&self) -> Result<u64> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let gc = &self.settings.gc;
+        let mut size = 0;
+        for reference in self
+            .repo
+            .list_references(&format!("{TOMBSTONES_NAMESPACE}/*"))?
+        {
+            let Some((hash, time, "narinfo")) = tombstones::parse_tombstone_ref(&reference) else {
+                continue;
+            };
+            if !revisions::is_expired(time, now, gc.tombstone_grace)
+                || !revisions::is_expired(time, now, gc.retention)
+                || self.entry_exists(hash)?
+            {
+                continue;
+            }
+            let Some(oid) = self.repo.get_oid_from_reference(&reference) else {
+                continue;
+            };
+            let narinfo = self.repo.get_blob(oid)?;
+            size += NarInfo::field(&String::from_utf8_lossy(&narinfo), "NarSize")
+                .and_then(|size| size.parse::<u64>().ok())
+                .unwrap_or(0);
+        }
+        Ok(size)
+    }
+
+    /// Periodically checks the disk usage in the background, if a high watermark is set.
+    /// Checks are skipped in maintenance mode. The monitor stops once `cancel` is
+    /// triggered.
+    pub fn spawn_gc_monitor(
+        &self,
+        cancel: CancellationToken,
+    ) -> Result<Option<thread::JoinHandle<()>>> {
+        if self.settings.gc.high_watermark.is_none() || self.settings.read_only {
+            return Ok(None);
+        }
+        let store = self.clone();
+        let interval = Duration::from_secs(self.settings.gc.check_interval);
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()?;
+        Ok(Some(thread::spawn(move || {
+            while !cancel.is_cancelled() {
+                if store.in_maintenance() {
+                    debug!("Skipping the disk usage check in maintenance mode");
+                } else {
+                    match store.collect_garbage_if_needed(&cancel) {
+                        Ok(Some(summary)) => info!("{}", summary.to_string().trim_end()),
+                        Ok(None) => {}
+                        Err(e) => warn!("Garbage collection failed: {}", e),
+                    }
+                }
+                let _ = rt.block_on(tokio::time::timeout(interval, cancel.cancelled()));
+            }
+        })))
+    }
+
+    /// Number of packages added since the store was opened.
+    pub fn packages_added(&self) -> usize {
+        self.packages_added.load(Ordering::Relaxed)
+    }
+
+    /// Fingerprint of the settings which affect what the store serves, so that a
+    /// snapshot restored into a differently configured store is noticed.
+    pub fn config_fingerprint(&self) -> String {
+        let signing_key = self.private_key.as_ref().map(|key| &key.name);
+        let settings = format!(
+            "{:?}\n{:?}\n{:?}",
+            signing_key, self.settings.trusted_public_keys, self.settings.filters
+        );
+        hex::encode(Sha256::digest(settings.as_bytes()))
+    }
+
+    /// Writes a snapshot of all references and the objects they reach to `dir`,
+    /// which must not exist yet. The references are read at once, so the snapshot is
+    /// consistent even while packages are added, and packages which are only
+    /// partially written are left out.
+    pub fn snapshot(&self, dir: &Path) -> Result<SnapshotManifest> {
+        let references = snapshot::complete_references(self.repo.list_reference_targets("refs/*")?);
+        let oids: Vec<Oid> = references.iter().map(|(_, oid)| *oid).collect();
+        self.write_snapshot(dir, &references, &oids, &[], None)
+    }
+
+    /// Writes a bundle like `snapshot`, but with only the objects added since the
+    /// history commit `since`, e.g. the history of an earlier snapshot. Restoring it
+    /// requires a store which is at that state.
+    pub fn bundle_diff(&self, since: &str, dir: &Path) -> Result<SnapshotManifest> {
+        let since = Oid::from_str(since)?;
+        let mut changed = HashSet::new();
+        let mut reached = false;
+        self.repo.walk_log(HISTORY_REF, |commit| {
+            if commit.id == since {
+                reached = true;
+                return Ok(false);
+            }
+            for record in history::parse_records(&commit.message)? {
+                changed.insert(record.hash);
+            }
+            Ok(true)
+        })?;
+        if !reached {
+            bail!("{} is not a commit of the history, see `gachix log`", since);
+        }
+
+        let references = snapshot::complete_references(self.repo.list_reference_targets("refs/*")?);
+        let mut oids = Vec::new();
+        // Objects reachable from the packages which did not change are at the base
+        let mut known = vec![since];
+        for (name, oid) in &references {
+            match snapshot::package_ref(name) {
+                Some((hash, kind)) if !changed.contains(hash) => {
+                    if kind == "result" {
+                        known.push(*oid);
+                    }
+                }
+                _ => oids.push(*oid),
+            }
+        }
+        self.write_snapshot(dir, &references, &oids, &known, Some(since))
+    }
+
+    fn write_snapshot(
+        &self,
+        dir: &Path,
+        references: &[(String, Oid)],
+        oids: &[Oid],
+        known: &[Oid],
+        base: Option<Oid>,
+    ) -> Result<SnapshotManifest> {
+        fs::create_dir(dir)
+            .with_context(|| format!("Could not create snapshot directory {}", dir.display()))?;
+        let pack_path = dir.join(snapshot::PACK_FILE);
+        let mut pack = std::io::BufWriter::new(fs::File::create(&pack_path)?);
+        self.repo.write_pack(oids, known, &mut pack)?;
+        pack.flush()?;
+
+        let created = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut manifest = SnapshotManifest::new(created, self.config_fingerprint(), references);
+        manifest.base = base.map(|oid| oid.to_string());
+        manifest.pack_size = fs::metadata(&pack_path)?.len();
+        fs::write(
+            dir.join(snapshot::MANIFEST_FILE),
+            serde_json::to_vec_pretty(&manifest)?,
+        )?;
+        info!(
+            "Wrote a snapshot of {} packages to {}",
+            manifest.packages,
+            dir.display()
+        );
+        Ok(manifest)
+    }
+
+    /// Restores a snapshot written by `snapshot` into this store, which must not hold
+    /// any packages yet. A differential bundle is applied to a store which is at the
+    /// state it was made against, and removes the packages removed since.
+    pub fn restore_snapshot(&self, dir: &Path) -> Result<SnapshotManifest> {
+        let manifest_path = dir.join(snapshot::MANIFEST_FILE);
+        let manifest: SnapshotManifest = serde_json::from_slice(
+            &fs::read(&manifest_path)
+                .with_context(|| format!("{} is not a complete snapshot", dir.display()))?,
+        )?;
+        if !manifest.is_supported() {
+            bail!("Unsupported snapshot version {}", manifest.version);
+        }
+        match &manifest.base {
+            Some(base) => {
+                let head = self.repo.get_oid_from_reference(HISTORY_REF);
+                if head.map(|oid| oid.to_string()).as_ref() != Some(base) {
+                    bail!(
+                        "The store is not at {}, which the bundle was made against",
+                        base
+                    );
+                }
+            }
+            None => {
+                if !self.repo.list_references("refs/*/narinfo")?.is_empty() {
+                    bail!("The store already holds packages, restore into an empty store");
+                }
+            }
+        }
+        if manifest.config_fingerprint != self.config_fingerprint() {
+            warn!("The snapshot was taken with different signing or filter settings");
+        }
+
+        let mut pack = std::io::BufReader::new(fs::File::open(dir.join(snapshot::PACK_FILE))?);
+        self.repo.read_pack(&mut pack)?;
+        let mut staged = StagedRefs::new(&self.repo, &dir.display().to_string());
+        if let Err(e) = self.restore_snapshot_refs(&mut staged, &manifest) {
+            staged.roll_back();
+            return Err(e);
+        }
+        self.flush_caches();
+        info!(
+            "Restored a snapshot of {} packages from {}",
+            manifest.packages,
+            dir.display()
+        );
+        Ok(manifest)
+    }
+
+    /// Points the references at the targets recorded in a snapshot, and for a
+    /// differential bundle drops the package references it does not list.
+    fn restore_snapshot_refs(
+        &self,
+        staged: &mut StagedRefs,
+        manifest: &SnapshotManifest,
+    ) -> Result<()> {
+        for (name, oid) in &manifest.references {
+            staged.update(name, Oid::from_str(oid)?)?;
+        }
+        if manifest.base.is_some() {
+            for reference in self.repo.list_references("refs/*")? {
+                if snapshot::package_ref(&reference).is_some()
+                    && !manifest.references.contains_key(&reference)
+                {
+                    staged.delete(&reference)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the cached counts and the idle repository handles, e.g. after
+    /// another process changed the repository.
+    pub fn flush_caches(&self) {
+        *self.package_count.lock().unwrap() = None;
+        *self.artifact_bytes.lock().unwrap() = None;
+        self.repo.close_idle_handles();
+    }
+
+    pub fn stats(&self) -> Result<StoreStats> {
+        let budget = self.memory_budget();
+        Ok(StoreStats {
+            packages: self.num_available_packages()?,
+            artifacts: self.repo.count_references(&self.get_artifact_ref("*"))?,
+            artifact_bytes: self.artifact_bytes()?,
+            transfer_memory: budget.in_use(),
+            spilled: budget.spilled(),
+        })
+    }
+
+    fn artifact_bytes(&self) -> Result<u64> {
+        let mut bytes = self.artifact_bytes.lock().unwrap();
+        if let Some(bytes) = *bytes {
+            return Ok(bytes);
+        }
+        let mut summed = 0;
+        for (_, oid) in self
+            .repo
+            .list_reference_targets(&self.get_artifact_ref("*"))?
+        {
+            summed += self.repo.nar_index(oid)?.nar_size;
+        }
+        *bytes = Some(summed);
+        Ok(summed)
+    }
+
+    fn num_available_packages(&self) -> Result<usize> {
+        let mut count = self.package_count.lock().unwrap();
+        if let Some(count) = *count {
+            return Ok(count);
+        }
+        let counted = self.repo.count_references("refs/*/narinfo")?;
+        *count = Some(counted);
+        Ok(counted)
+    }
+
+    pub fn pack_refs(&self) -> Result<()> {
+        self.repo.pack_refs()?;
+        self.packages_since_pack.store(0, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Moves the narinfos which are still loose objects into a single pack, see
+    /// `gachix pack-metadata`. Returns how many were packed.
+    pub fn pack_metadata(&self) -> Result<usize> {
+        let narinfos: Vec<Oid> = self
+            .repo
+            .list_reference_targets("refs/*/narinfo")?
+            .into_iter()
+            .map(|(_, oid)| oid)
+            .collect();
+        let packed = self.repo.pack_loose_objects(&narinfos)?;
+        info!("Packed {} narinfos", packed);
+        Ok(packed)
+    }
+
+    fn pack_refs_if_needed(&self) -> Result<()> {
+        let threshold = self.settings.pack_refs_threshold;
+        if threshold > 0 && self.packages_since_pack.load(Ordering::Relaxed) >= threshold {
+            debug!("Packing references");
+            self.pack_refs()?;
+        }
+        Ok(())
+    }
+
+    fn record_added_package(&self) {
+        self.packages_added.fetch_add(1, Ordering::Relaxed);
+        self.packages_since_pack.fetch_add(1, Ordering::Relaxed);
+        if let Some(count) = self.package_count.lock().unwrap().as_mut() {
+            *count += 1;
+        }
+    }
+
+    pub fn get_commit(&self, hash: &str) -> Option<Oid> {
+        self.repo.get_oid_from_reference(&self.get_result_ref(hash))
+    }
+
+    fn get_package_ref(&self, hash: &str) -> String {
+        format!("refs/{hash}")
+    }
+
+    fn get_result_ref(&self, hash: &str) -> String {
+        format!("{}/result", self.get_package_ref(hash))
+    }
+
+    fn get_narinfo_ref(&self, hash: &str) -> String {
+        format!("{}/narinfo", self.get_package_ref(hash))
+    }
+
+    fn get_provenance_ref(&self, hash: &str) -> String {
+        format!("{}/provenance", self.get_package_ref(hash))
+    }
+
+    fn get_ipfs_ref(&self, hash: &str) -> String {
+        format!("{}/ipfs", self.get_package_ref(hash))
+    }
+
+    fn get_artifact_ref(&self, hash: &str) -> String {
+        format!("refs/cas/{hash}")
+    }
+
+    /// Index from the NAR hash of a package to its result commit
+    fn get_nar_hash_ref(&self, nar_hash: &str) -> String {
+        format!("refs/narhash/{}", nar_hash.trim_start_matches("sha256:"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        git_store::audit::{AuditFilter, AuditRecord, Outcome},
+        git_store::dedup::DedupReport,
+        git_store::events::Event,
+        git_store::filter::IngestFilter,
+        git_store::fsck::{DanglingEntry, Inconsistency, RefKind},
+        git_store::history::{Change, HISTORY_REF, HistoryFilter, PointInTime},
+        git_store::listing::{Entry, ListOptions},
+        git_store::plan::Source,
+        git_store::provenance::Provenance,
+        git_store::store::{FetchedPackage, Store},
+        nar::decode::Dedup,
+        nix_interface::ssh::AuthMethod,
+        nix_interface::{
+            daemon::{DynNixDaemon, NixDaemon},
+            path::NixPath,
+        },
+        settings,
+    };
+    use anyhow::{Result, anyhow};
+    use git2::Oid;
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+    use std::time::Duration;
+    use tempfile::TempDir;
+    use tokio_util::sync::CancellationToken;
+    use url::Url;
+
+    fn build_nix_package(package_name: &str) -> Result<NixPath> {
+        let output = Command::new("nix")
+            .arg("build")
+            .arg(format!("nixpkgs#{}", package_name))
+            .arg("--print-out-paths")
+            .output()?;
+
+        let path = NixPath::new(&String::from_utf8_lossy(&output.stdout).to_string())?;
+        Ok(path)
+    }
+
+    pub fn set_repo_path(path: &PathBuf) -> settings::Store {
+        settings::Store {
+            path: path.clone(),
+            builders: vec![],
+            remotes: vec![],
+            discovery_domain: None,
+            follow_deletions: None,
+            read_only: false,
+            use_local_nix_daemon: true,
+            sign_private_key_path: None,
+            trusted_public_keys: Vec::new(),
+            quarantine_rejected: false,
+            review_unsigned: false,
+            ssh_private_key_path: None,
+            ssh_known_hosts_path: None,
+            ssh_trust_on_first_use: false,
+            ssh_auth_methods: vec![AuthMethod::Publickey],
+            pack_refs_threshold: 1000,
+            memory_budget: 1 << 30,
+            filters: Default::default(),
+            peer_filters: Vec::new(),
+            scanners: Vec::new(),
+            narinfo_fields: Default::default(),
+            gc: settings::Gc {
+                high_watermark: None,
+                low_watermark: 80.0,
+                check_interval: 300,
+                retention: 0,
+                tombstone_grace: 0,
+                audit_retention: 0,
+                evict_first: Vec::new(),
+                never_evict: Vec::new(),
+                keep_channel_versions: 1,
+                policies: HashMap::new(),
+            },
+            timeouts: settings::Timeouts {
+                connect: 30,
+                query: 60,
+                transfer: 120,
+                keepalive: 15,
+            },
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_add_package() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo_path = temp_dir.path().join("gachix");
+        let store = Store::new(set_repo_path(&repo_path))?;
+
+        let path = build_nix_package("hello")?;
+        store.get_package_from_nix_daemons(&path, None).await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_add_closure() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo_path = temp_dir.path().join("gachix");
+        let store = Store::new(set_repo_path(&repo_path))?;
+
+        let path = build_nix_package("sl")?;
+        store.add_closure(&path, &CancellationToken::new()).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_add_narinfo() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo_path = temp_dir.path().join("gachix");
+        let store = Store::new(set_repo_path(&repo_path))?;
+
+        let path = build_nix_package("kitty")?;
+        let mut nix = DynNixDaemon::Local(NixDaemon::local());
+        nix.connect().await?;
+        store
+            .build_narinfo(&mut nix, "somekey", &path, None)
+            .await?;
+        Ok(())
+    }
+
+    /// Stores an entry with an empty tree, a narinfo with the given references and,
+    /// if `parents` is given, a result commit with those parents.
+    fn add_fake_entry(
+        store: &Store,
+        hash: &str,
+        references: &[&str],
+        parents: Option<&[git2::Oid]>,
+    ) -> Result<()> {
+        use crate::nix_interface::nar_info::NarInfo;
+
+        let tree_oid = store.repo.add_nar(
+            [
+                &13u64.to_le_bytes()[..],
+                b"nix-archive-1\0\0\0",
+                &1u64.to_le_bytes(),
+                b"(\0\0\0\0\0\0\0",
+                &4u64.to_le_bytes(),
+                b"type\0\0\0\0",
+                &9u64.to_le_bytes(),
+                b"directory\0\0\0\0\0\0\0",
+                &1u64.to_le_bytes(),
+                b")\0\0\0\0\0\0\0",
+            ]
+            .concat()
+            .as_slice(),
+        )?;
+        let references = references
+            .iter()
+            .map(|r| NixPath::new(&format!("/nix/store/{r}-dep")))
+            .collect::<Result<Vec<_>>>()?;
+        // Peers verify fetched packages, so the narinfo has to match the contents
+        let (nar_hash, nar_size) = store.repo.nar_hash(tree_oid.0)?;
+        let nar_hash = format!("sha256:{}", nix_base32::to_nix_base32(&nar_hash));
+        let narinfo = NarInfo::new(
+            NixPath::new(&format!("/nix/store/{hash}-pkg"))?,
+            tree_oid.0.to_string(),
+            nar_hash.clone(),
+            nar_size,
+            None,
+            nar_hash,
+            nar_size,
+            None,
+            references,
+            None,
+        );
+        let narinfo_oid = store
+            .repo
+            .add_file_content(narinfo.to_string().as_bytes())?;
+        store
+            .repo
+            .add_ref(&store.get_narinfo_ref(hash), narinfo_oid)?;
+        if let Some(parents) = parents {
+            let commit_oid = store.repo.commit(tree_oid.0, parents, None)?;
+            store
+                .repo
+                .add_ref(&store.get_result_ref(hash), commit_oid)?;
+        }
+        Ok(())
+    }
+
+    /// A second repository standing in for a git peer. It is reachable over
+    /// `file://`, so replication is covered without network peers.
+    struct FakeRemote {
+        _dir: TempDir,
+        store: Store,
+        url: Url,
+    }
+
+    impl FakeRemote {
+        fn new() -> Result<Self> {
+            Self::open(false)
+        }
+
+        /// libgit2 only pushes into bare repositories.
+        fn bare() -> Result<Self> {
+            Self::open(true)
+        }
+
+        fn open(bare: bool) -> Result<Self> {
+            let dir = TempDir::new()?;
+            let path = dir.path().join("remote");
+            if bare {
+                git2::Repository::init_bare(&path)?;
+            }
+            let store = Store::new(set_repo_path(&path))?;
+            let url = Url::from_file_path(&path)
+                .map_err(|_| anyhow!("Invalid remote path {}", path.display()))?;
+            Ok(Self {
+                _dir: dir,
+                store,
+                url,
+            })
+        }
+
+        /// Opens a store at `path` which uses this remote as its only git peer.
+        fn peer_of(&self, path: &Path) -> Result<Store> {
+            let mut settings = set_repo_path(&path.to_path_buf());
+            settings.remotes = vec![self.url.clone()];
+            Store::new(settings)
+        }
+
+        /// Adds a package referencing `dependencies` to the remote.
+        fn add(&self, hash: &str, dependencies: &[&str]) -> Result<()> {
+            add_fake_entry(&self.store, hash, dependencies, Some(&[]))
+        }
+    }
+
+    #[test]
+    fn test_clone_from() -> Result<()> {
+        let remote = FakeRemote::new()?;
+        let (dep, root, other) = ("d".repeat(32), "r".repeat(32), "o".repeat(32));
+        remote.add(&dep, &[])?;
+        remote.add(&root, &[&dep])?;
+        remote.add(&other, &[])?;
+        let temp_dir = TempDir::new()?;
+        let store = remote.peer_of(&temp_dir.path().join("gachix"))?;
+
+        let everything = IngestFilter::new(&settings::IngestFilters {
+            exclude: vec!["*".to_string()],
+            ..Default::default()
+        })?;
+        let summary = store.clone_from(remote.url.as_str(), &everything)?;
+        assert_eq!((summary.available, summary.skipped), (3, 3));
+        assert!(store.list_entries(&ListOptions::default())?.is_empty());
+        assert!(store.repo.list_references("refs/clone/*")?.is_empty());
+
+        store.fetch_closure(&other, remote.url.as_str())?;
+        let summary = store.clone_from(remote.url.as_str(), &IngestFilter::default())?;
+        assert_eq!((summary.cloned, summary.present), (2, 1));
+        assert!(summary.failed.is_empty());
+        assert_eq!(store.list_entries(&ListOptions::default())?.len(), 3);
+        assert!(store.verify(&root)?.is_valid());
+        Ok(())
+    }
+
+    #[test]
+    fn test_channels() -> Result<()> {
+        let remote = FakeRemote::new()?;
+        let (tool, lib) = ("t".repeat(32), "l".repeat(32));
+        remote.add(&lib, &[])?;
+        remote.add(&tool, &[&lib])?;
+        assert!(remote.store.publish_channel("team-tools", &[]).is_err());
+        assert!(
+            remote
+                .store
+                .publish_channel("team-tools-v1", &[tool.clone()])
+                .is_err()
+        );
+        assert!(
+            remote
+                .store
+                .publish_channel("team-tools", &["m".repeat(32)])
+                .is_err()
+        );
+        let first = remote.store.publish_channel("team-tools", &[lib.clone()])?;
+        assert_eq!(first.version, 1);
+        assert_eq!(first.store_paths, [format!("/nix/store/{lib}-pkg")]);
+        let second = remote
+            .store
+            .publish_channel("team-tools", &[tool.clone(), lib.clone()])?;
+        assert_eq!(second.version, 2);
+        assert_eq!(second.store_paths.len(), 2);
+
+        let temp_dir = TempDir::new()?;
+        let store = remote.peer_of(&temp_dir.path().join("gachix"))?;
+        assert!(store.channel("team-tools").is_err());
+        let (fetched, peer) = store.fetch_channel("team-tools-v1")?;
+        assert_eq!(fetched, first);
+        assert_eq!(peer, remote.url.as_str());
+        assert_eq!(store.channel("team-tools")?, second);
+        assert!(store.channel("team-tools-v3").is_err());
+        assert_eq!(store.channels()?, [second]);
+        // Only the channel is fetched, not its packages
+        assert!(!store.entry_exists(&lib)?);
+        assert_eq!(store.fetch_channel_packages(&second, &peer)?, 2);
+        assert_eq!(store.get_commit(&tool), remote.store.get_commit(&tool));
+        assert_eq!(store.fetch_channel_packages(&second, &peer)?, 0);
+
+        // The packages of the newest version are kept by garbage collection
+        let mut policy = remote.store.gc_policy(None)?;
+        assert!(remote.store.eviction_candidates(&policy)?.is_empty());
+        policy.keep_channel_versions = 0;
+        assert_eq!(remote.store.eviction_candidates(&policy)?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_handshake() -> Result<()> {
+        let remote = FakeRemote::new()?;
+        let temp_dir = TempDir::new()?;
+        let store = remote.peer_of(&temp_dir.path().join("gachix"))?;
+        let url = remote.url.as_str();
+        assert_eq!(store.peer_handshake(url)?, None);
+
+        remote.store.advertise_handshake()?;
+        remote.store.advertise_handshake()?;
+        assert_eq!(store.peer_handshake(url)?, Some(Handshake::current()));
+        assert!(
+            store
+                .repo
+                .list_references("refs/gachix/peers/*")?
+                .is_empty()
+        );
+
+        // Channels aren't fetched from peers which don't publish them
+        let lib = "l".repeat(32);
+        remote.add(&lib, &[])?;
+        remote.store.publish_channel("team-tools", &[lib])?;
+        let older = br#"{"protocol":1,"version":"0.1.0","features":["objects"]}"#;
+        let oid = remote.store.repo.add_file_content(older)?;
+        remote.store.repo.update_ref(HANDSHAKE_REF, oid)?;
+        assert_eq!(store.peer_handshake(url)?.unwrap().version, "0.1.0");
+        assert!(store.fetch_channel("team-tools").is_err());
+
+        remote.store.advertise_handshake()?;
+        assert_eq!(store.fetch_channel("team-tools")?.0.version, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_upstream_narinfos() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+        let (stored, imported) = ("s".repeat(32), "i".repeat(32));
+        add_fake_entry(&store, &stored, &[], Some(&[]))?;
+        assert!(store.upstream_narinfo(&imported)?.is_none());
+        assert!(store.fetch_upstream(&imported)?.is_none());
+
+        // Imported narinfos of packages which were stored since are not listed
+        for hash in [&stored, &imported] {
+            let mut narinfo = NarInfo::parse(&format!(
+                "StorePath: /nix/store/{hash}-pkg\nURL: nar/1abc.nar.xz\nCompression: xz\n\
+                 FileHash: sha256:1abc\nFileSize: 40\nNarHash: sha256:0def\nNarSize: 120\n\
+                 References: \nDeriver: \nSig: \n"
+            ))?;
+            narinfo.set_extra(upstream::UPSTREAM_FIELD, "https://cache.example.org")?;
+            let oid = store
+                .repo
+                .add_file_content(narinfo.to_string().as_bytes())?;
+            store.repo.update_ref(&upstream::upstream_ref(hash), oid)?;
+        }
+        let narinfos = store.upstream_narinfos()?;
+        assert_eq!(narinfos.len(), 1);
+        assert_eq!(narinfos[0].store_path.get_base_32_hash(), imported);
+        assert_eq!(
+            store.upstream_narinfo(&imported)?.unwrap().to_string(),
+            narinfos[0].to_string()
+        );
+        // Globs over the package references don't see the imported narinfos
+        assert_eq!(store.repo.list_references("refs/*/narinfo")?.len(), 1);
+
+        let fetched = store.fetch_upstream(&stored)?.unwrap();
+        assert_eq!(fetched.store_path.get_base_32_hash(), stored);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fsck_fix_dangling() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo_path = temp_dir.path().join("gachix");
+        let store = Store::new(set_repo_path(&repo_path))?;
+
+        let hash_a = "a".repeat(32);
+        let hash_b = "b".repeat(32);
+        let hash_c = "c".repeat(32);
+        let oid = store.repo.add_file_content(b"content")?;
+        store.repo.add_ref(&store.get_result_ref(&hash_a), oid)?;
+        add_fake_entry(&store, &hash_b, &[], None)?;
+        add_fake_entry(&store, &hash_c, &[], Some(&[]))?;
+
+        let report = store.fsck()?;
+        assert_eq!(report.checked, 3);
+        assert_eq!(
+            report.dangling,
+            vec![
+                DanglingEntry {
+                    hash: hash_a.clone(),
+                    missing: RefKind::Narinfo
+                },
+                DanglingEntry {
+                    hash: hash_b.clone(),
+                    missing: RefKind::Result
+                },
+            ]
+        );
+
+        store.fix_dangling(&report)?;
+        assert!(store.fsck()?.is_clean());
+        assert!(store.entry_exists(&hash_c)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fsck_closure_consistency() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+
+        let dep = "d".repeat(32);
+        let missing = "m".repeat(32);
+        let consistent = "c".repeat(32);
+        let orphaned = "o".repeat(32);
+        let diverging = "x".repeat(32);
+        add_fake_entry(&store, &dep, &[], Some(&[]))?;
+        let dep_commit = store.get_commit(&dep).unwrap();
+        add_fake_entry(&store, &consistent, &[&dep], Some(&[dep_commit]))?;
+        add_fake_entry(&store, &orphaned, &[&missing], Some(&[]))?;
+        add_fake_entry(&store, &diverging, &[&dep], Some(&[]))?;
+
+        let report = store.fsck()?;
+        assert!(report.dangling.is_empty());
+        assert_eq!(
+            report.inconsistent,
+            vec![
+                Inconsistency::MissingReference {
+                    hash: orphaned,
+                    reference: format!("{missing}-dep"),
+                },
+                Inconsistency::ParentMismatch {
+                    hash: diverging,
+                    missing: vec![format!("{dep}-dep")],
+                    unexpected: vec![],
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_session_error() {
+        use super::is_session_error;
+
+        let broken = anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::BrokenPipe));
+        assert!(is_session_error(&broken.context("Querying path info")));
+        assert!(!is_session_error(&anyhow!("path is not valid")));
+    }
+
+    #[test]
+    fn test_closure() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+
+        let leaf = "l".repeat(32);
+        let dep = "d".repeat(32);
+        let root = "r".repeat(32);
+        add_fake_entry(&store, &leaf, &[], Some(&[]))?;
+        add_fake_entry(&store, &dep, &[&leaf], Some(&[]))?;
+        add_fake_entry(&store, &root, &[&dep, &leaf], Some(&[]))?;
+
+        let hashes: Vec<String> = store
+            .closure(&root)?
+            .iter()
+            .map(|n| n.store_path.get_base_32_hash().to_string())
+            .collect();
+        assert_eq!(hashes, vec![root, dep.clone(), leaf.clone()]);
+
+        store.delete(&leaf, true)?;
+        assert!(store.closure(&dep).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_incomplete_packages() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+        let skipped = "s".repeat(32);
+        let dep = "d".repeat(32);
+        let root = "r".repeat(32);
+        add_fake_entry(&store, &dep, &[&skipped], Some(&[]))?;
+        add_fake_entry(&store, &root, &[&dep], Some(&[]))?;
+        assert_eq!(
+            store.incomplete_packages(&root)?,
+            vec![("pkg".to_string(), vec!["dep".to_string()])]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_drop_unchanged() -> Result<()> {
+        use crate::nix_interface::daemon::{Closure, ClosureEntry};
+
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+        let leaf = "l".repeat(32);
+        let previous = "p".repeat(32);
+        let stored = "s".repeat(32);
+        let new = "n".repeat(32);
+        let root = "r".repeat(32);
+        add_fake_entry(&store, &leaf, &[], Some(&[]))?;
+        let leaf_commit = store.get_commit(&leaf).unwrap();
+        add_fake_entry(&store, &previous, &[&leaf], Some(&[leaf_commit]))?;
+        let previous_commit = store.get_commit(&previous).unwrap();
+        add_fake_entry(
+            &store,
+            &stored,
+            &[&leaf],
+            Some(&[leaf_commit, previous_commit]),
+        )?;
+
+        let entry = |hash: &str| -> Result<(String, ClosureEntry)> {
+            Ok((
+                hash.to_string(),
+                ClosureEntry {
+                    path: NixPath::new(&format!("/nix/store/{hash}-pkg"))?,
+                    references: Vec::new(),
+                    nar_size: 0,
+                    deriver: None,
+                    system: None,
+                },
+            ))
+        };
+        let mut closure = [&root, &leaf, &stored, &new]
+            .into_iter()
+            .map(|hash| entry(hash))
+            .collect::<Result<Closure>>()?;
+        let root_path = NixPath::new(&format!("/nix/store/{root}-pkg"))?;
+        let previous_path = NixPath::new(&format!("/nix/store/{previous}-pkg"))?;
+
+        // The previous closure is read from the stored commits, not from a daemon
+        store.drop_unchanged(&root_path, &mut closure, &previous_path)?;
+        let mut kept: Vec<&String> = closure.keys().collect();
+        kept.sort();
+        assert_eq!(kept, vec![&new, &root, &stored]);
+
+        // Nothing is dropped if the previous generation is not stored
+        let mut closure = [&root, &leaf]
+            .into_iter()
+            .map(|hash| entry(hash))
+            .collect::<Result<Closure>>()?;
+        let missing = NixPath::new(&format!("/nix/store/{}-pkg", "m".repeat(32)))?;
+        store.drop_unchanged(&root_path, &mut closure, &missing)?;
+        assert_eq!(closure.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_advertise_refs() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+        let first = "a".repeat(32);
+        let second = "b".repeat(32);
+        add_fake_entry(&store, &first, &[], Some(&[]))?;
+
+        let full = store.advertise_refs(None)?;
+        assert!(full.full);
+        assert_eq!(full.packages.len(), 1);
+        assert_eq!(
+            full.packages[0].commit,
+            store.get_commit(&first).unwrap().to_string()
+        );
+        let head = Oid::from_str(full.head.as_deref().unwrap())?;
+        assert_eq!(store.advertise_refs(None)?.head, full.head);
+
+        add_fake_entry(&store, &second, &[], Some(&[]))?;
+        store.delete(&first, true)?;
+        let changes = store.advertise_refs(Some(head))?;
+        assert!(!changes.full);
+        assert_eq!(changes.packages.len(), 1);
+        assert_eq!(changes.packages[0].hash, second);
+        assert_eq!(changes.removed, vec![first]);
+
+        // An unknown head falls back to the full set
+        let unknown = store.advertise_refs(Some(Oid::from_bytes(&[1; 20])?))?;
+        assert!(unknown.full);
+        Ok(())
+    }
+
+    #[test]
+    fn test_entries_at() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+        let hashes = |entries: Vec<Entry>| entries.into_iter().map(|e| e.hash).collect::<Vec<_>>();
+        let options = ListOptions::default();
+
+        // Added before the history was recorded
+        let old = "a".repeat(32);
+        add_fake_entry(&store, &old, &[], Some(&[]))?;
+        let new = "b".repeat(32);
+        add_fake_entry(&store, &new, &[], Some(&[]))?;
+        store.record_history(&store.history_records(Change::Added, &[new.clone()]));
+        let added = store.repo.get_oid_from_reference(HISTORY_REF).unwrap();
+        store.delete(&old, true)?;
+
+        let at_added = PointInTime::Commit(added.to_string()[..8].to_string());
+        assert_eq!(
+            hashes(store.entries_at(&at_added, &options)?),
+            [old.clone(), new.clone()]
+        );
+        let before = store.entries_at(&PointInTime::Time(0), &options)?;
+        assert_eq!(before[0].name, "pkg");
+        assert_eq!(hashes(before), [old]);
+        assert_eq!(hashes(store.list_entries(&options)?), [new]);
+        assert!(
+            store
+                .entries_at(&PointInTime::Commit("0000000".to_string()), &options)
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_history() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+        let hash = "b".repeat(32);
+        add_fake_entry(&store, &hash, &[], Some(&[]))?;
+        let added = store.history_records(Change::Added, &[hash.clone()]);
+        store.record_history(&added);
+        store.delete(&hash, true)?;
+
+        let entries = store.history(&HistoryFilter::default())?;
+        let changes: Vec<Change> = entries.iter().map(|e| e.record.change).collect();
+        assert_eq!(changes, [Change::Removed, Change::Added]);
+        assert_eq!(entries[0].record.hash, hash);
+        assert_eq!(entries[0].name.as_deref(), Some("pkg"));
+        assert!(entries[0].identity.contains('@'));
+
+        let other = HistoryFilter {
+            hash: Some("c".to_string()),
+            ..Default::default()
+        };
+        assert!(store.history(&other)?.is_empty());
+        let later = HistoryFilter {
+            since: Some(entries[0].time + 1),
+            ..Default::default()
+        };
+        assert!(store.history(&later)?.is_empty());
+
+        store.acting_as("admin@10.0.0.7").record_history(&added);
+        let entries = store.history(&HistoryFilter::default())?;
+        assert_eq!(entries[0].identity, "admin@10.0.0.7");
+        assert_ne!(entries[1].identity, "admin@10.0.0.7");
+        Ok(())
+    }
+
+    #[test]
+    fn test_audit_trail() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+        let refused = AuditRecord {
+            principal: "anonymous@10.0.0.7".to_string(),
+            action: "put".to_string(),
+            target: "/cas/abc".to_string(),
+            outcome: Outcome::Failed("401 Unauthorized".to_string()),
+        };
+        store.audit(&refused);
+        store.audit(&AuditRecord {
+            principal: "alice@builder".to_string(),
+            action: "gc".to_string(),
+            target: String::new(),
+            outcome: Outcome::Succeeded,
+        });
+
+        let entries = store.audit_trail(&AuditFilter::default())?;
+        let actions: Vec<&str> = entries.iter().map(|e| e.record.action.as_str()).collect();
+        assert_eq!(actions, ["gc", "put"]);
+        assert_eq!(entries[1].record, refused);
+
+        let failed = AuditFilter {
+            failed: true,
+            ..Default::default()
+        };
+        assert_eq!(store.audit_trail(&failed)?.len(), 1);
+        let later = AuditFilter {
+            since: Some(entries[0].time + 1),
+            ..Default::default()
+        };
+        assert!(store.audit_trail(&later)?.is_empty());
+        // The audit trail is not mistaken for the history of packages
+        assert!(store.history(&HistoryFilter::default())?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_undelete() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+        let dep = "d".repeat(32);
+        let root = "r".repeat(32);
+        add_fake_entry(&store, &dep, &[], Some(&[]))?;
+        let dep_commit = store.get_commit(&dep).unwrap();
+        add_fake_entry(&store, &root, &[&dep], Some(&[dep_commit]))?;
+        store.record_history(&store.history_records(Change::Added, &[dep.clone(), root.clone()]));
+        store.delete(&root, true)?;
+        store.delete(&dep, true)?;
+
+        assert!(store.undelete(&"a".repeat(32)).is_err());
+        assert_eq!(store.undelete(&root)?, [root.clone(), dep.clone()]);
+        assert_eq!(store.closure(&root)?.len(), 2);
+        assert!(store.undelete(&root).is_err());
+
+        // The tombstone keeps the objects until it expired
+        store.delete(&root, true)?;
+        store.repo.prune_unreachable(Duration::ZERO)?;
+        assert_eq!(store.undelete(&root)?, [root.clone()]);
+
+        store.purge(&root, true)?;
+        store.repo.prune_unreachable(Duration::ZERO)?;
+        assert!(store.undelete(&root).is_err());
+        assert!(!store.entry_exists(&root)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_retain_removed() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let mut settings = set_repo_path(&temp_dir.path().join("gachix"));
+        settings.gc.retention = 3600;
+        let store = Store::new(settings)?;
+        let (removed, evicted) = ("r".repeat(32), "v".repeat(32));
+        for hash in [&removed, &evicted] {
+            add_fake_entry(&store, hash, &[], Some(&[]))?;
+        }
+        let narinfo_of = |hash: &str| {
+            store
+                .repo
+                .get_oid_from_reference(&store.get_narinfo_ref(hash))
+        };
+        let (removed_narinfo, evicted_narinfo) =
+            (narinfo_of(&removed).unwrap(), narinfo_of(&evicted).unwrap());
+        store.purge(&removed, true)?;
+        store.purge(&evicted, true)?;
+
+        let retained = store.retain_removed(&HashSet::from([evicted.clone()]))?;
+        assert_eq!(retained.len(), 2);
+        store.repo.prune_unreachable(Duration::ZERO)?;
+        for reference in &retained {
+            store.repo.delete_ref(reference)?;
+        }
+        assert!(store.repo.contains(removed_narinfo)?);
+        assert!(!store.repo.contains(evicted_narinfo)?);
+        assert_eq!(store.undelete(&removed)?, vec![removed.clone()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_revoke() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+        let (revoked, dependent) = ("r".repeat(32), "d".repeat(32));
+        add_fake_entry(&store, &revoked, &[], Some(&[]))?;
+        add_fake_entry(&store, &dependent, &[&revoked], Some(&[]))?;
+        let narinfo = store.get_narinfo(&revoked)?.unwrap();
+        let key = Store::nar_key(&String::from_utf8_lossy(&narinfo))
+            .unwrap()
+            .to_string();
+        assert!(store.get_as_nar_stream(&key)?.is_some());
+
+        assert_eq!(
+            store.revoke(&revoked, "poisoned")?,
+            vec![revoked.clone(), dependent.clone()]
+        );
+        assert!(!store.entry_exists(&revoked)?);
+        assert!(!store.entry_exists(&dependent)?);
+        assert!(store.tombstones()?.is_empty());
+        // The objects are still there until they are pruned, but no longer served
+        assert!(store.get_as_nar_stream(&key)?.is_none());
+        let revocations = store.revocations()?;
+        assert_eq!(revocations.len(), 1);
+        assert_eq!(revocations[0].nar_key.as_deref(), Some(key.as_str()));
+        assert!(store.revoke(&revoked, "poisoned").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_follow_deletions() -> Result<()> {
+        let remote = FakeRemote::new()?;
+        let temp_dir = TempDir::new()?;
+        let store = remote.peer_of(&temp_dir.path().join("gachix"))?;
+        let (revoked, dependent, removed, kept) = (
+            "r".repeat(32),
+            "d".repeat(32),
+            "a".repeat(32),
+            "k".repeat(32),
+        );
+        for hash in [&revoked, &removed, &kept] {
+            remote.add(hash, &[])?;
+            add_fake_entry(&store, hash, &[], Some(&[]))?;
+        }
+        add_fake_entry(&store, &dependent, &[&revoked], Some(&[]))?;
+        remote.store.revoke(&revoked, "poisoned")?;
+        // Only removed, not revoked
+        remote.store.delete(&removed, true)?;
+
+        let planned = store.follow_deletions(true)?;
+        assert_eq!(planned.len(), 2);
+        assert_eq!(planned[0].hash, revoked);
+        assert_eq!(planned[1].hash, dependent);
+        assert_eq!(planned[1].revoked, revoked);
+        assert_eq!(planned[0].peer, remote.url.to_string());
+        assert!(store.entry_exists(&revoked)?);
+
+        assert_eq!(store.follow_deletions(false)?, planned);
+        assert!(!store.entry_exists(&revoked)?);
+        assert!(!store.entry_exists(&dependent)?);
+        assert!(store.entry_exists(&removed)?);
+        assert!(store.entry_exists(&kept)?);
+        assert!(store.tombstones()?.is_empty());
+        assert_eq!(store.revocations()?[0].revoked_by, remote.url.to_string());
+
+        // Every revocation is followed once
+        add_fake_entry(&store, &revoked, &[], Some(&[]))?;
+        assert!(store.follow_deletions(false)?.is_empty());
+        assert!(store.entry_exists(&revoked)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reject_invalid_hashes() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+        let hash = "c".repeat(32);
+        add_fake_entry(&store, &hash, &[], Some(&[]))?;
+        store.record_history(&store.history_records(Change::Added, &[hash.clone()]));
+        store.tag("release-1", &hash, false)?;
+
+        for invalid in ["*", "gachix", "tags", "channels", "c*"] {
+            assert!(store.delete(invalid, true).is_err());
+            assert!(store.purge(invalid, true).is_err());
+            assert!(store.undelete(invalid).is_err());
+            assert!(store.tag("release-2", invalid, true).is_err());
+        }
+        assert!(store.entry_exists(&hash)?);
+        assert!(store.repo.reference_exists(HISTORY_REF)?);
+        assert!(store.read_tag("release-1")?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tombstones() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+        let hash = "c".repeat(32);
+        add_fake_entry(&store, &hash, &[], Some(&[]))?;
+        store.record_provenance(&hash, &Provenance::new("local".to_string(), None))?;
+        store.record_history(&store.history_records(Change::Added, &[hash.clone()]));
+        let commit = store.get_commit(&hash).unwrap();
+        let narinfo = store.get_narinfo(&hash)?.unwrap();
+        let key = Store::nar_key(&String::from_utf8_lossy(&narinfo))
+            .unwrap()
+            .to_string();
+
+        store.delete(&hash, false)?;
+        assert!(!store.entry_exists(&hash)?);
+        assert!(store.get_narinfo(&hash)?.is_none());
+        assert!(store.get_as_nar_stream(&key)?.is_none());
+        assert!(store.list_entries(&ListOptions::default())?.is_empty());
+        let tombstones = store.tombstones()?;
+        assert_eq!(tombstones.len(), 1);
+        assert_eq!(tombstones[0].hash, hash);
+        assert_eq!(
+            tombstones[0].store_path.as_deref(),
+            Some(format!("/nix/store/{hash}-pkg").as_str())
+        );
+        store.repo.prune_unreachable(Duration::ZERO)?;
+        assert!(store.repo.contains(commit)?);
+
+        // The provenance, which the history does not record, comes back too
+        store.undelete(&hash)?;
+        assert!(store.provenance(&hash)?.is_some());
+        assert!(store.tombstones()?.is_empty());
+        assert!(store.repo.list_references("refs/tombstones/*")?.is_empty());
+        assert!(store.get_as_nar_stream(&key)?.is_some());
+
+        store.delete(&hash, false)?;
+        assert_eq!(store.expire_tombstones(u64::MAX, false)?, 0);
+        // Tombstones are only past a grace period of 0 once a second passed
+        std::thread::sleep(Duration::from_millis(1100));
+        assert_eq!(store.expire_tombstones(0, true)?, 1);
+        assert_eq!(store.tombstones()?.len(), 1);
+        assert_eq!(store.expire_tombstones(0, false)?, 1);
+        assert!(store.tombstones()?.is_empty());
+        store.repo.prune_unreachable(Duration::ZERO)?;
+        assert!(!store.repo.contains(commit)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tags() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+        let (first, second) = ("f".repeat(32), "s".repeat(32));
+        add_fake_entry(&store, &first, &[], Some(&[]))?;
+        add_fake_entry(&store, &second, &[], Some(&[]))?;
+
+        assert!(store.tag("release-1", &"m".repeat(32), false).is_err());
+        assert!(store.tag("no/slash", &first, false).is_err());
+        store.tag("release-1", &first, false)?;
+        // A tag called like a package reference is no package
+        store.tag("result", &first, false)?;
+        assert_eq!(store.num_available_packages()?, 2);
+        assert_eq!(store.eviction_candidates(&store.gc_policy(None)?)?.len(), 1);
+
+        assert!(store.tag("release-1", &second, false).is_err());
+        store.tag("release-1", &second, true)?;
+        let tag = store.resolve_tag("release-1")?;
+        assert_eq!(tag.hash, second);
+        assert_eq!(tag.store_path, format!("/nix/store/{second}-pkg"));
+        let names: Vec<String> = store.tags()?.into_iter().map(|tag| tag.name).collect();
+        assert_eq!(names, ["release-1", "result"]);
+
+        // Tagged packages are only removed with force
+        assert!(store.delete(&second, false).is_err());
+        assert!(store.entry_exists(&second)?);
+        // A tag outlives a package removed by other means, e.g. restoring a snapshot
+        let package_refs = format!("{}/*", store.get_package_ref(&second));
+        for reference in store.repo.list_references(&package_refs)? {
+            store.repo.delete_ref(&reference)?;
+        }
+        assert!(store.resolve_tag("release-1").is_err());
+        assert_eq!(
+            store.tags()?[0].to_string(),
+            format!("release-1 /nix/store/{second}-pkg (removed)")
+        );
+        assert!(store.untag("release-1")?);
+        assert!(!store.untag("release-1")?);
+        assert!(store.resolve_tag("release-1").is_err());
+
+        store.delete(&first, true)?;
+        assert!(store.tags()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_leased_nar_survives_gc() -> Result<()> {
+        use crate::nix_interface::nar_info::NarInfo;
+
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+        let hash = "l".repeat(32);
+        add_fake_entry(&store, &hash, &[], Some(&[]))?;
+        // Fake entries all have the empty tree, the commit stands in for the objects
+        let commit = store.get_commit(&hash).unwrap();
+        let narinfo = store.get_narinfo(&hash)?.unwrap();
+        let key = NarInfo::parse(&String::from_utf8_lossy(&narinfo))?.key;
+
+        let lease = store.lease(&key)?.unwrap();
+        assert!(store.is_leased(&hash)?);
+        // Unknown keys are not pinned
+        assert!(store.lease(&"0".repeat(40))?.is_none());
+        assert!(store.lease("not-a-key")?.is_none());
+        assert!(
+            store
+                .eviction_candidates(&store.gc_policy(None)?)?
+                .is_empty()
+        );
+        store.purge(&hash, true)?;
+        assert!(!store.prune_unleased(Duration::ZERO, Duration::from_millis(50))?);
+        assert!(store.repo.contains(commit)?);
+
+        drop(lease);
+        assert!(store.prune_unleased(Duration::ZERO, Duration::from_millis(50))?);
+        assert!(!store.repo.contains(commit)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+        let dep = "d".repeat(32);
+        let root = "r".repeat(32);
+        let partial = "p".repeat(32);
+        add_fake_entry(&store, &dep, &[], Some(&[]))?;
+        let dep_commit = store.get_commit(&dep).unwrap();
+        add_fake_entry(&store, &root, &[&dep], Some(&[dep_commit]))?;
+        store.record_history(&store.history_records(Change::Added, &[dep.clone(), root.clone()]));
+        // Still being written while the snapshot is taken
+        add_fake_entry(&store, &partial, &[], None)?;
+
+        let snapshot = temp_dir.path().join("snapshot");
+        let manifest = store.snapshot(&snapshot)?;
+        assert_eq!(manifest.packages, 2);
+        assert!(store.snapshot(&snapshot).is_err());
+
+        let restored = Store::new(set_repo_path(&temp_dir.path().join("restored")))?;
+        assert_eq!(restored.restore_snapshot(&snapshot)?, manifest);
+        assert_eq!(restored.closure(&root)?.len(), 2);
+        assert_eq!(restored.get_commit(&root), store.get_commit(&root));
+        assert!(!restored.entry_exists(&partial)?);
+        assert_eq!(restored.history(&HistoryFilter::default())?.len(), 2);
+        assert_eq!(restored.num_available_packages()?, 2);
+        // Restoring twice would mix two stores
+        assert!(restored.restore_snapshot(&snapshot).is_err());
+
+        // A snapshot which can't be restored completely leaves no references behind
+        let broken = temp_dir.path().join("broken");
+        fs::create_dir(&broken)?;
+        fs::copy(
+            snapshot.join(snapshot::PACK_FILE),
+            broken.join(snapshot::PACK_FILE),
+        )?;
+        let mut broken_manifest = manifest.clone();
+        broken_manifest.references.insert(
+            format!("refs/{}/result", "z".repeat(32)),
+            "invalid".to_string(),
+        );
+        fs::write(
+            broken.join(snapshot::MANIFEST_FILE),
+            serde_json::to_vec(&broken_manifest)?,
+        )?;
+        let empty = Store::new(set_repo_path(&temp_dir.path().join("empty")))?;
+        assert!(empty.restore_snapshot(&broken).is_err());
+        assert!(empty.repo.list_references("refs/*/narinfo")?.is_empty());
+        assert!(!empty.repo.reference_exists(HISTORY_REF)?);
+
+        let new = "n".repeat(32);
+        add_fake_entry(&store, &new, &[&dep], Some(&[dep_commit]))?;
+        store.record_history(&store.history_records(Change::Added, &[new.clone()]));
+        store.delete(&root, true)?;
+        let since = manifest.history.unwrap();
+        assert!(
+            store
+                .bundle_diff(&"0".repeat(40), &temp_dir.path().join("bad"))
+                .is_err()
+        );
+        let diff = temp_dir.path().join("diff");
+        let diff_manifest = store.bundle_diff(&since, &diff)?;
+        assert_eq!(diff_manifest.base.as_ref(), Some(&since));
+        assert_eq!(diff_manifest.packages, 2);
+
+        restored.restore_snapshot(&diff)?;
+        assert!(restored.entry_exists(&new)?);
+        assert!(!restored.entry_exists(&root)?);
+        assert_eq!(restored.closure(&new)?.len(), 2);
+        // The store has moved on from the state the bundle was made against
+        assert!(restored.restore_snapshot(&diff).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_artifacts() -> Result<()> {
+        use futures::{StreamExt, executor::block_on};
+        use sha2::{Digest, Sha256};
+
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+        let dir = temp_dir.path().join("artifact");
+        std::fs::create_dir(&dir)?;
+        std::fs::write(dir.join("report.txt"), b"all green")?;
+        let mut nar = Vec::new();
+        nix_nar::Encoder::new(&dir)?.read_to_end(&mut nar)?;
+
+        let hash = nix_base32::to_nix_base32(&Sha256::digest(&nar));
+        assert!(!store.add_artifact(&"0".repeat(52), nar.as_slice())?);
+        assert!(store.get_artifact(&"0".repeat(52))?.is_none());
+        // Nothing of a rejected upload is left behind
+        let contents = Oid::hash_object(git2::ObjectType::Blob, b"all green")?;
+        assert!(!store.repo.contains(contents)?);
+        let trailing = [nar.as_slice(), b"trailing"].concat();
+        assert!(store.add_artifact(&hash, trailing.as_slice()).is_err());
+        assert!(!store.repo.contains(contents)?);
+        assert!(store.add_artifact(&hash, nar.as_slice())?);
+        assert!(store.repo.contains(contents)?);
+        let stream = store.get_artifact(&hash)?.unwrap();
+        let mut streamed = Vec::new();
+        for chunk in block_on(stream.collect::<Vec<_>>()) {
+            streamed.extend_from_slice(&chunk?);
+        }
+        assert_eq!(streamed, nar);
+        let stats = store.stats()?;
+        assert_eq!(
+            (stats.artifacts, stats.artifact_bytes),
+            (1, nar.len() as u64)
+        );
+        // Storing an artifact again does not count it twice
+        assert!(store.add_artifact(&hash, nar.as_slice())?);
+        assert_eq!(store.stats()?.artifact_bytes, nar.len() as u64);
+        store.flush_caches();
+        assert_eq!(store.stats()?.artifact_bytes, nar.len() as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_static_complete_closures() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+        let (dep, root) = ("d".repeat(32), "r".repeat(32));
+        add_fake_entry(&store, &dep, &[], Some(&[]))?;
+        add_fake_entry(&store, &root, &[&dep], Some(&[]))?;
+
+        let options = StaticExportOptions {
+            max_file_size: u64::MAX,
+            max_site_size: None,
+        };
+        let all = temp_dir.path().join("all");
+        let summary = store.export_static(&[root.clone()], &all, &options)?;
+        assert_eq!(summary.exported, 2);
+        assert!(all.join(format!("{root}.narinfo")).exists());
+
+        // The root is left out along with its dependency over the size limit
+        let options = StaticExportOptions {
+            max_file_size: 0,
+            ..options
+        };
+        let none = temp_dir.path().join("none");
+        let summary = store.export_static(&[root.clone()], &none, &options)?;
+        assert_eq!(summary.exported, 0);
+        assert_eq!(summary.skipped, [format!("/nix/store/{dep}-pkg")]);
+        assert_eq!(summary.incomplete, [format!("/nix/store/{root}-pkg")]);
+        assert!(!none.join(format!("{root}.narinfo")).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_logs() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+        let drv = format!("{}-hello-2.12.drv", "d".repeat(32));
+        assert!(store.get_build_log(&drv)?.is_none());
+        store.add_build_log(&drv, b"building hello\n")?;
+        assert_eq!(
+            store
+                .get_build_log(&format!("/nix/store/{drv}"))?
+                .as_deref(),
+            Some(&b"building hello\n"[..])
+        );
+        store.add_build_log(&drv, b"rebuilt")?;
+        assert_eq!(store.get_build_log(&drv)?.as_deref(), Some(&b"rebuilt"[..]));
+        assert!(store.add_build_log("hello", b"").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ssh_options() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let mut settings = set_repo_path(&temp_dir.path().join("gachix"));
+        settings.ssh_private_key_path = Some(temp_dir.path().join("id_ed25519"));
+        let store = Store::new(settings)?;
+
+        let options = store.ssh_options(&"ssh-ng://builder:2222".parse()?)?;
+        assert_eq!(options.port, 2222);
+        assert_eq!(options.user, "nix-ssh");
+        assert_eq!(
+            options.remote_command.as_deref(),
+            Some("nix-daemon --stdio")
+        );
+
+        let options =
+            store.ssh_options(&"ssh-ng://root@builder?remote-program=/bin/nix-daemon".parse()?)?;
+        assert_eq!(options.user, "root");
+        assert_eq!(
+            options.remote_command.as_deref(),
+            Some("/bin/nix-daemon --stdio")
+        );
+
+        let options = store.ssh_options(&"ssh://builder".parse()?)?;
+        assert_eq!(options.port, 22);
+        assert_eq!(options.remote_command, None);
+
+        assert!(store.ssh_options(&"http://builder".parse()?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_scanners() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let mut settings = set_repo_path(&temp_dir.path().join("gachix"));
+        let scanner = |script: &str, packages: &str, on_failure| settings::Scanner {
+            command: vec!["sh".to_string(), "-c".to_string(), script.to_string()],
+            packages: vec![packages.to_string()],
+            on_failure,
+        };
+        settings.scanners = vec![
+            scanner(
+                "grep -q secret && echo leaked && exit 1; exit 0",
+                "*-flagged",
+                settings::ScanAction::Flag,
+            ),
+            scanner(
+                "grep -q secret && exit 1; exit 0",
+                "*-rejected",
+                settings::ScanAction::Reject,
+            ),
+        ];
+        let store = Store::new(settings)?;
+        let package_dir = temp_dir.path().join("pkg");
+        std::fs::create_dir(&package_dir)?;
+        std::fs::write(package_dir.join("file"), b"a secret")?;
+        let package = FetchedPackage {
+            narinfo_blob_oid: store.repo.add_file_content(b"narinfo")?,
+            package_oid: store.repo.add_dir(&package_dir)?,
+            dependencies: vec![],
+            provenance: Provenance::new("local".to_string(), None),
+            nar_hash: format!("sha256:{}", "0".repeat(52)),
+            dedup: Dedup::default(),
+        };
+
+        let flagged = NixPath::new(&format!("/nix/store/{}-flagged", "f".repeat(32)))?;
+        store.commit_package(&flagged, &package, &[])?;
+        let provenance = store.provenance(flagged.get_base_32_hash())?.unwrap();
+        assert_eq!(provenance.findings, ["sh: leaked"]);
+
+        let rejected = NixPath::new(&format!("/nix/store/{}-rejected", "r".repeat(32)))?;
+        let error = store.commit_package(&rejected, &package, &[]).unwrap_err();
+        assert!(error.to_string().contains("rejected by scanner"), "{error}");
+        assert!(!store.entry_exists(rejected.get_base_32_hash())?);
+
+        let unscanned = NixPath::new(&format!("/nix/store/{}-other", "o".repeat(32)))?;
+        store.commit_package(&unscanned, &package, &[])?;
+        assert!(
+            store
+                .provenance(unscanned.get_base_32_hash())?
+                .unwrap()
+                .findings
+                .is_empty()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_commit_emits_event() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+        let mut events = store.subscribe();
+
+        let path = NixPath::new(&format!("/nix/store/{}-pkg", "e".repeat(32)))?;
+        let package_dir = temp_dir.path().join("pkg");
+        std::fs::create_dir(&package_dir)?;
+        std::fs::write(package_dir.join("file"), b"content")?;
+        let package = FetchedPackage {
+            narinfo_blob_oid: store.repo.add_file_content(b"narinfo")?,
+            package_oid: store.repo.add_dir(&package_dir)?,
+            dependencies: vec![],
+            provenance: Provenance::new("local".to_string(), None),
+            nar_hash: format!("sha256:{}", "0".repeat(52)),
+            dedup: Dedup::default(),
+        };
+        let commit = store.commit_package(&path, &package, &[])?;
+        assert_eq!(
+            store.provenance(path.get_base_32_hash())?,
+            Some(package.provenance.clone())
+        );
+
+        assert_eq!(
+            events.try_recv()?,
+            Event::Committed {
+                path: path.to_string(),
+                commit
+            }
+        );
+        assert!(events.try_recv().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_nar_hash_index() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+
+        let path = NixPath::new(&format!("/nix/store/{}-pkg", "f".repeat(32)))?;
+        let package_dir = temp_dir.path().join("pkg");
+        std::fs::create_dir(&package_dir)?;
+        std::fs::write(package_dir.join("file"), b"content")?;
+        let nar_hash = format!("sha256:{}", "1".repeat(52));
+        let narinfo = format!("StorePath: {path}\nNarHash: {nar_hash}\nReferences: \n");
+        let package = FetchedPackage {
+            narinfo_blob_oid: store.repo.add_file_content(narinfo.as_bytes())?,
+            package_oid: store.repo.add_dir(&package_dir)?,
+            dependencies: vec![],
+            provenance: Provenance::new("local".to_string(), None),
+            nar_hash: nar_hash.clone(),
+            dedup: Dedup::default(),
+        };
+        assert_eq!(store.find_tree_by_nar_hash(&nar_hash)?, None);
+        store.commit_package(&path, &package, &[])?;
+        assert_eq!(
+            store.find_tree_by_nar_hash(&nar_hash)?,
+            Some(package.package_oid)
+        );
+
+        store.delete(path.get_base_32_hash(), true)?;
+        assert_eq!(store.find_tree_by_nar_hash(&nar_hash)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_deriver_index() -> Result<()> {
+        use crate::nix_interface::nar_info::NarInfo;
+
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+
+        let deriver = NixPath::new(&format!("/nix/store/{}-pkg.drv", "v".repeat(32)))?;
+        let path = NixPath::new(&format!("/nix/store/{}-pkg", "f".repeat(32)))?;
+        let package_dir = temp_dir.path().join("pkg");
+        std::fs::create_dir(&package_dir)?;
+        std::fs::write(package_dir.join("file"), b"content")?;
+        let nar_hash = format!("sha256:{}", "1".repeat(52));
+        let narinfo = NarInfo::new(
+            path.clone(),
+            "key".to_string(),
+            nar_hash.clone(),
+            7,
+            None,
+            nar_hash.clone(),
+            7,
+            Some(deriver.clone()),
+            vec![],
+            None,
+        )
+        .to_string();
+        let package = FetchedPackage {
+            narinfo_blob_oid: store.repo.add_file_content(narinfo.as_bytes())?,
+            package_oid: store.repo.add_dir(&package_dir)?,
+            dependencies: vec![],
+            provenance: Provenance::new("local".to_string(), Some(&deriver)),
+            nar_hash,
+            dedup: Dedup::default(),
+        };
+        assert!(store.derivation_outputs(deriver.get_path())?.is_empty());
+        store.commit_package(&path, &package, &[])?;
+        assert_eq!(
+            store.derivation_outputs(deriver.get_path())?,
+            [path.to_string()]
+        );
+        assert_eq!(
+            store.derivation_outputs(deriver.get_base_32_hash())?,
+            [path.to_string()]
+        );
+
+        store.delete(path.get_base_32_hash(), true)?;
+        assert!(store.derivation_outputs(deriver.get_path())?.is_empty());
+        assert!(store.repo.list_references("refs/deriver/*")?.is_empty());
+        store.undelete(path.get_base_32_hash())?;
+        assert_eq!(
+            store.derivation_outputs(deriver.get_path())?,
+            [path.to_string()]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_to() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("source")))?;
+        let destination_path = temp_dir.path().join("destination");
+        let destination = store.with_path(&destination_path)?;
+        let destination_url = destination_path.to_str().unwrap();
+
+        let leaf = "l".repeat(32);
+        let dep = "d".repeat(32);
+        let root = "r".repeat(32);
+        add_fake_entry(&store, &leaf, &[], Some(&[]))?;
+        add_fake_entry(&store, &dep, &[&leaf], Some(&[]))?;
+        add_fake_entry(&store, &root, &[&dep], Some(&[]))?;
+
+        let summary = store.copy_to(std::slice::from_ref(&dep), destination_url)?;
+        assert_eq!((summary.copied, summary.present), (2, 0));
+
+        let summary = store.copy_to(std::slice::from_ref(&root), destination_url)?;
+        assert_eq!((summary.copied, summary.present), (1, 2));
+        assert_eq!(destination.closure(&root)?.len(), 3);
+        assert_eq!(destination.get_commit(&root), store.get_commit(&root));
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("source")))?;
+        let dep = "d".repeat(32);
+        let root = "r".repeat(32);
+        add_fake_entry(&store, &dep, &[], Some(&[]))?;
+        add_fake_entry(&store, &root, &[&dep], Some(&[]))?;
+
+        let manifest = store.manifest(&root)?;
+        assert_eq!(manifest.paths.len(), 2);
+        assert_eq!(
+            manifest.paths[0].commit,
+            store.get_commit(&root).map(|oid| oid.to_string())
+        );
+        assert!(store.check_manifest(&manifest)?.is_empty());
 
-        Ok(commit_oid)
+        let other = store.with_path(&temp_dir.path().join("other"))?;
+        add_fake_entry(&other, &dep, &[], Some(&[]))?;
+        assert_eq!(
+            other.check_manifest(&manifest)?,
+            [format!("/nix/store/{root}-pkg is missing")]
+        );
+        Ok(())
     }
 
-    fn fetch_from_remote(&self, package_id: &str, remote: &str) -> Result<Option<Oid>> {
-        if let Some(()) = self
-            .repo
-            .fetch(&remote, &format!("{}/*", self.get_package_ref(package_id)))?
-        {
-            let oid = self
-                .get_commit(package_id)
-                .ok_or_else(|| anyhow!("Could not get commit id for {}", package_id))?;
-            return Ok(Some(oid));
-        }
-        Ok(None)
+    #[test]
+    fn test_split_and_merge() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("source")))?;
+        let destination_path = temp_dir.path().join("destination");
+
+        let leaf = "l".repeat(32);
+        let shared = "s".repeat(32);
+        let root = "r".repeat(32);
+        let other = "o".repeat(32);
+        add_fake_entry(&store, &leaf, &[], Some(&[]))?;
+        add_fake_entry(&store, &shared, &[], Some(&[]))?;
+        add_fake_entry(&store, &root, &[&leaf, &shared], Some(&[]))?;
+        add_fake_entry(&store, &other, &[&shared], Some(&[]))?;
+        let root_commit = store.get_commit(&root);
+
+        let by_hash = |prefix: &str| ListOptions {
+            hash: Some(prefix.to_string()),
+            ..Default::default()
+        };
+        assert!(store.split(&by_hash("x"), &destination_path).is_err());
+        let summary = store.split(&by_hash("r"), &destination_path)?;
+        assert_eq!((summary.copy.copied, summary.copy.present), (3, 0));
+        assert_eq!((summary.removed, summary.kept), (2, 1));
+        let stored = |store: &Store| -> Result<Vec<String>> {
+            Ok(store
+                .list_entries(&ListOptions::default())?
+                .into_iter()
+                .map(|entry| entry.hash)
+                .collect())
+        };
+        assert_eq!(stored(&store)?, [other.clone(), shared.clone()]);
+        let destination = store.with_path(&destination_path)?;
+        assert_eq!(destination.closure(&root)?.len(), 3);
+        assert_eq!(destination.get_commit(&root), root_commit);
+
+        let summary = store.merge(&destination_path)?;
+        assert_eq!((summary.copied, summary.present), (2, 1));
+        assert_eq!(stored(&store)?.len(), 4);
+        let merged: Vec<(Change, String)> = store
+            .history(&HistoryFilter::default())?
+            .into_iter()
+            .take_while(|entry| entry.record.change == Change::Added)
+            .map(|entry| (entry.record.change, entry.record.hash))
+            .collect();
+        assert_eq!(merged.len(), 2);
+        assert!(
+            merged
+                .iter()
+                .all(|(_, hash)| *hash == root || *hash == leaf)
+        );
+        assert_eq!(store.get_commit(&root), root_commit);
+        assert!(store.verify(&root)?.is_valid());
+        assert!(store.merge(&temp_dir.path().join("missing")).is_err());
+        Ok(())
     }
 
-    fn get_dep_ids(&self, package_id: &str) -> Result<Vec<NixPath>> {
-        let narinfo_blob = self
-            .get_narinfo(package_id)?
-            .ok_or_else(|| anyhow!("Could not find narinfo for {}", package_id))?;
-        let narinfo = NarInfo::parse(&String::from_utf8_lossy(&narinfo_blob).to_string())?;
-        let dependencies = narinfo.get_dependencies();
-        Ok(dependencies.into_iter().cloned().collect())
+    #[test]
+    fn test_add_pushed() -> Result<()> {
+        use liblzma::read::XzDecoder;
+
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("source")))?;
+        let destination = store.with_path(&temp_dir.path().join("destination"))?;
+        let dep = "d".repeat(32);
+        let root = "r".repeat(32);
+        add_fake_entry(&store, &dep, &[], Some(&[]))?;
+        add_fake_entry(&store, &root, &[&dep], Some(&[]))?;
+        let hashes = [dep.clone(), root.clone()];
+        assert_eq!(destination.missing(&hashes)?, hashes);
+
+        let open = |path: &Path| -> Result<_> { Ok(XzDecoder::new(std::fs::File::open(path)?)) };
+        let (root_narinfo, root_nar) = store.compress_nar(&root, temp_dir.path())?;
+        // Dependencies have to be pushed first
+        assert!(
+            destination
+                .add_pushed(&root_narinfo, open(root_nar.path())?, "test")
+                .is_err()
+        );
+        let (dep_narinfo, dep_nar) = store.compress_nar(&dep, temp_dir.path())?;
+        let mut tampered = dep_narinfo.clone();
+        tampered.nar_size += 1;
+        assert!(!destination.add_pushed(&tampered, open(dep_nar.path())?, "test")?);
+        assert!(destination.add_pushed(&dep_narinfo, open(dep_nar.path())?, "test")?);
+        assert!(destination.add_pushed(&root_narinfo, open(root_nar.path())?, "test")?);
+
+        assert!(destination.missing(&hashes)?.is_empty());
+        assert!(destination.verify(&root)?.is_valid());
+        assert_eq!(destination.closure(&root)?.len(), 2);
+
+        // Pushed as git objects, only those the other side is missing are sent
+        let other = store.with_path(&temp_dir.path().join("other"))?;
+        assert!(other.add_pushed(&dep_narinfo, open(dep_nar.path())?, "test")?);
+        let tree = Oid::from_str(&root_narinfo.key)?;
+        let missing = other.missing_objects(&[tree])?;
+        assert!(missing.is_empty(), "the tree of the dependency is the same");
+        let mut pack = Vec::new();
+        store.write_objects(&missing, &mut pack)?;
+        other.receive_objects(&mut &pack[..])?;
+        assert!(other.add_pushed_tree(&root_narinfo, "test")?);
+        assert!(other.verify(&root)?.is_valid());
+        Ok(())
     }
 
-    async fn build_narinfo(
-        &self,
-        nix_daemon: &mut DynNixDaemon,
-        key: &str,
-        store_path: &NixPath,
-    ) -> Result<NarInfo> {
-        let Some(path_info) = nix_daemon.get_pathinfo(&store_path).await? else {
-            return Err(anyhow!(
-                "Could not find narinfo for {}",
-                store_path.get_path()
-            ));
+    #[test]
+    fn test_narinfo_fields() -> Result<()> {
+        use crate::nix_interface::nar_info::NarInfo;
+        use liblzma::read::XzDecoder;
+
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("source")))?;
+        let mut settings = set_repo_path(&temp_dir.path().join("destination"));
+        settings.narinfo_fields.add = vec!["Priority: 30".to_string()];
+        settings.narinfo_fields.strip = vec!["CI-Build".to_string()];
+        let destination = Store::new(settings.clone())?;
+
+        let hash = "d".repeat(32);
+        add_fake_entry(&store, &hash, &[], Some(&[]))?;
+        let (mut narinfo, nar) = store.compress_nar(&hash, temp_dir.path())?;
+        narinfo.set_extra("CI-Build", "42")?;
+        narinfo.set_extra("Priority", "10")?;
+        narinfo.set_extra("CA", "fixed:r:sha256:abc")?;
+        let expected = [
+            ("Priority".to_string(), "30".to_string()),
+            ("CA".to_string(), "fixed:r:sha256:abc".to_string()),
+        ];
+        let stored_extra = |store: &Store| -> Result<Vec<(String, String)>> {
+            let narinfo = store.get_narinfo(&hash)?.unwrap();
+            Ok(NarInfo::parse(&String::from_utf8_lossy(&narinfo))?.extra)
         };
-        let references: Vec<NixPath> = path_info
-            .references
+        let reader = XzDecoder::new(std::fs::File::open(nar.path())?);
+        assert!(destination.add_pushed(&narinfo, reader, "test")?);
+        assert_eq!(stored_extra(&destination)?, expected);
+        assert!(destination.verify(&hash)?.is_valid());
+
+        // Narinfos fetched from git peers are treated the same
+        let peer = FakeRemote::new()?;
+        let reader = XzDecoder::new(std::fs::File::open(nar.path())?);
+        assert!(peer.store.add_pushed(&narinfo, reader, "test")?);
+        assert!(stored_extra(&peer.store)?.contains(&("CI-Build".to_string(), "42".to_string())));
+        let mut fetching = settings.clone();
+        fetching.path = temp_dir.path().join("fetching");
+        fetching.remotes = vec![peer.url.clone()];
+        let fetching = Store::new(fetching)?;
+        fetching.fetch_closure(&hash, peer.url.as_str())?;
+        assert_eq!(stored_extra(&fetching)?, expected);
+        assert!(fetching.verify(&hash)?.is_valid());
+
+        for invalid in ["NarSize: 1", "Priority"] {
+            settings.narinfo_fields.add = vec![invalid.to_string()];
+            assert!(Store::new(settings.clone()).is_err(), "{invalid}");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_substitution_plan() -> Result<()> {
+        let remote = FakeRemote::new()?;
+        let (stored, replicated, missing) = ("a".repeat(32), "b".repeat(32), "c".repeat(32));
+        remote.add(&replicated, &[])?;
+        let temp_dir = TempDir::new()?;
+        let mut settings = set_repo_path(&temp_dir.path().join("gachix"));
+        settings.remotes = vec![remote.url.clone()];
+        settings.use_local_nix_daemon = false;
+        let store = Store::new(settings)?;
+        add_fake_entry(&store, &stored, &[], Some(&[]))?;
+
+        let paths = [&stored, &replicated, &missing]
             .iter()
-            .map(|p| NixPath::new(p))
-            .collect::<Result<Vec<_>, _>>()?;
+            .map(|hash| NixPath::new(&format!("/nix/store/{hash}-pkg")))
+            .collect::<Result<Vec<_>>>()?;
+        let plan = store.substitution_plan(&paths).await?;
+        let sources: Vec<_> = plan.paths.iter().map(|p| p.source.clone()).collect();
+        assert_eq!(
+            sources,
+            [
+                Some(Source::Stored),
+                Some(Source::Peer(remote.url.to_string())),
+                None
+            ]
+        );
+        assert_eq!(plan.unavailable().count(), 1);
+        // Planning does not fetch
+        assert!(!store.entry_exists(&replicated)?);
+        Ok(())
+    }
 
-        let nar_size = path_info.nar_size;
-        let nar_hash = hex::decode(path_info.nar_hash)?;
+    #[tokio::test]
+    async fn test_check_build() -> Result<()> {
+        let remote = FakeRemote::new()?;
+        let (stored, replicated, missing) = ("a".repeat(32), "b".repeat(32), "c".repeat(32));
+        remote.add(&replicated, &[])?;
+        let temp_dir = TempDir::new()?;
+        let mut settings = set_repo_path(&temp_dir.path().join("gachix"));
+        settings.remotes = vec![remote.url.clone()];
+        settings.use_local_nix_daemon = false;
+        let store = Store::new(settings)?;
+        add_fake_entry(&store, &stored, &[], Some(&[]))?;
 
-        // TODO: compute hash instead of copying it and verify it against the received hash
-        let mut nar_hash_32_base = nix_base32::to_nix_base32(&nar_hash);
-        // TODO: formatting should be handled by the NarInfo struct
-        nar_hash_32_base = format!("sha256:{}", nar_hash_32_base);
+        let write_drv = |name: &str, outputs: &[(&str, &String)]| -> Result<NixPath> {
+            let outputs: Vec<String> = outputs
+                .iter()
+                .map(|(output, hash)| format!(r#"("{output}","/nix/store/{hash}-pkg","","")"#))
+                .collect();
+            let drv_path = temp_dir
+                .path()
+                .join(format!("{}-{name}.drv", "v".repeat(32)));
+            std::fs::write(
+                &drv_path,
+                format!(
+                    r#"Derive([{}],[],[],"x86_64-linux","/bin/sh",[],[])"#,
+                    outputs.join(",")
+                ),
+            )?;
+            NixPath::new(&drv_path)
+        };
 
-        let signature = self.private_key.as_ref().map(|private_key| {
-            let fingerprint =
-                fingerprint_store_object(store_path, &nar_hash_32_base, nar_size, &references);
-            let signature_bytes = private_key.sign(fingerprint.as_bytes());
-            format!(
-                "{}:{}",
-                private_key.name,
-                BASE64_STANDARD.encode(signature_bytes)
-            )
-        });
+        let available = write_drv("available", &[("dev", &stored), ("out", &replicated)])?;
+        let plan = store.check_build(&available).await?;
+        let sources: Vec<_> = plan.paths.iter().map(|p| p.source.clone()).collect();
+        assert_eq!(
+            sources,
+            [
+                Some(Source::Stored),
+                Some(Source::Peer(remote.url.to_string()))
+            ]
+        );
+        assert_eq!(plan.unavailable().count(), 0);
 
-        let deriver = path_info.deriver.map(|d| NixPath::new(&d)).transpose()?;
-        let narinfo = NarInfo::new(
-            store_path.clone(),
-            key.to_string(),
-            nar_hash_32_base.clone(),
-            path_info.nar_size,
-            None,
-            nar_hash_32_base,
-            path_info.nar_size,
-            deriver,
-            references,
-            signature,
+        let partial = write_drv("partial", &[("dev", &stored), ("out", &missing)])?;
+        let plan = store.check_build(&partial).await?;
+        assert_eq!(plan.unavailable().count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_from_git_remote() -> Result<()> {
+        let remote = FakeRemote::new()?;
+        let leaf = "l".repeat(32);
+        let dep = "d".repeat(32);
+        let root = "r".repeat(32);
+        remote.add(&leaf, &[])?;
+        remote.add(&dep, &[&leaf])?;
+        remote.add(&root, &[&dep])?;
+
+        let temp_dir = TempDir::new()?;
+        let store = remote.peer_of(&temp_dir.path().join("gachix"))?;
+        let root_path = NixPath::new(&format!("/nix/store/{root}-pkg"))?;
+        let commit = store.get_package_commit_from_git_remotes(&root_path)?;
+        assert_eq!(commit, remote.store.get_commit(&root));
+        assert_eq!(store.closure(&root)?.len(), 3);
+        assert_eq!(store.get_commit(&leaf), remote.store.get_commit(&leaf));
+
+        let missing = "m".repeat(32);
+        let missing_path = NixPath::new(&format!("/nix/store/{missing}-pkg"))?;
+        assert_eq!(
+            store.get_package_commit_from_git_remotes(&missing_path)?,
+            None
         );
-        Ok(narinfo)
+        assert!(store.fetch_closure(&missing, remote.url.as_str()).is_err());
+        Ok(())
     }
 
-    pub fn get_narinfo(&self, base32_hash: &str) -> Result<Option<Vec<u8>>> {
-        let result = self
+    #[test]
+    fn test_corrupted_fetch_is_rejected() -> Result<()> {
+        use crate::nix_interface::nar_info::NarInfo;
+
+        let remote = FakeRemote::new()?;
+        let hash = "c".repeat(32);
+        remote.add(&hash, &[])?;
+        // The peer serves contents which do not match its narinfo
+        let narinfo = remote.store.get_narinfo(&hash)?.unwrap();
+        let narinfo = NarInfo::replace_field(&String::from_utf8_lossy(&narinfo), "NarSize", "1");
+        let narinfo_oid = remote.store.repo.add_file_content(narinfo.as_bytes())?;
+        remote
+            .store
             .repo
-            .get_oid_from_reference(&self.get_narinfo_ref(base32_hash));
-        match result {
-            Some(oid) => Ok(Some(self.repo.get_blob(oid)?)),
-            None => Ok(None),
-        }
+            .update_ref(&remote.store.get_narinfo_ref(&hash), narinfo_oid)?;
+
+        let temp_dir = TempDir::new()?;
+        let store = remote.peer_of(&temp_dir.path().join("gachix"))?;
+        let mut events = store.subscribe();
+        let path = NixPath::new(&format!("/nix/store/{hash}-pkg"))?;
+        assert_eq!(store.get_package_commit_from_git_remotes(&path)?, None);
+        assert!(store.repo.list_references("refs/*")?.is_empty());
+        assert!(matches!(
+            events.try_recv()?,
+            Event::Failed { path: failed, .. } if failed == path.to_string()
+        ));
+
+        // Packages not signed by a trusted key are rejected as well
+        let unsigned = "u".repeat(32);
+        remote.add(&unsigned, &[])?;
+        let path = NixPath::new(&format!("/nix/store/{unsigned}-pkg"))?;
+        let mut settings = set_repo_path(&temp_dir.path().join("trusting"));
+        settings.remotes = vec![remote.url.clone()];
+        settings.trusted_public_keys =
+            vec!["cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=".to_string()];
+        let store = Store::new(settings)?;
+        assert_eq!(store.get_package_commit_from_git_remotes(&path)?, None);
+        assert!(store.repo.list_references("refs/*")?.is_empty());
+        Ok(())
     }
 
-    pub fn entry_exists(&self, base32_hash: &str) -> Result<bool> {
-        self.repo
-            .reference_exists(&self.get_result_ref(base32_hash))
+    #[test]
+    fn test_repair_from_git_remotes() -> Result<()> {
+        use crate::nix_interface::nar_info::NarInfo;
+
+        let remote = FakeRemote::new()?;
+        let (hash, missing) = ("h".repeat(32), "m".repeat(32));
+        remote.add(&hash, &[])?;
+        let temp_dir = TempDir::new()?;
+        let store = remote.peer_of(&temp_dir.path().join("gachix"))?;
+        let stale = store.repo.add_file_content(b"stale")?;
+        for hash in [&hash, &missing] {
+            add_fake_entry(&store, hash, &[], Some(&[]))?;
+            let narinfo = store.get_narinfo(hash)?.unwrap();
+            let narinfo =
+                NarInfo::replace_field(&String::from_utf8_lossy(&narinfo), "NarSize", "1");
+            let narinfo_oid = store.repo.add_file_content(narinfo.as_bytes())?;
+            store
+                .repo
+                .update_ref(&store.get_narinfo_ref(hash), narinfo_oid)?;
+            store
+                .repo
+                .add_ref(&format!("{}/ipfs", store.get_package_ref(hash)), stale)?;
+        }
+
+        // A package no peer has is kept as it is
+        let path = NixPath::new(&format!("/nix/store/{missing}-pkg"))?;
+        let refs = store
+            .repo
+            .list_references(&format!("{}/*", store.get_package_ref(&missing)))?;
+        assert!(!store.repair_from_git_remotes(&path)?);
+        assert_eq!(
+            store
+                .repo
+                .list_references(&format!("{}/*", store.get_package_ref(&missing)))?,
+            refs
+        );
+
+        let path = NixPath::new(&format!("/nix/store/{hash}-pkg"))?;
+        assert!(store.repair_from_git_remotes(&path)?);
+        assert!(store.verify(&hash)?.is_valid());
+        assert_eq!(store.get_commit(&hash), remote.store.get_commit(&hash));
+        assert!(
+            !store
+                .repo
+                .reference_exists(&format!("{}/ipfs", store.get_package_ref(&hash)))?
+        );
+        assert!(store.quarantined_refs(&hash)?.is_empty());
+        Ok(())
     }
 
-    pub fn get_as_nar_stream(&self, key: &str) -> Result<Option<NarGitStream>> {
-        self.repo.get_entry_as_nar(Oid::from_str(key)?)
+    #[tokio::test]
+    async fn test_peer_filters() -> Result<()> {
+        let remote = FakeRemote::new()?;
+        let hash = "p".repeat(32);
+        remote.add(&hash, &[])?;
+        let path = NixPath::new(&format!("/nix/store/{hash}-pkg"))?;
+        let temp_dir = TempDir::new()?;
+        let peer_filter = |filters: settings::IngestFilters| settings::PeerFilter {
+            remote: remote.url.clone(),
+            filters,
+        };
+
+        let mut settings = set_repo_path(&temp_dir.path().join("excluding"));
+        settings.remotes = vec![remote.url.clone()];
+        settings.use_local_nix_daemon = false;
+        settings.peer_filters = vec![peer_filter(settings::IngestFilters {
+            exclude: vec!["pk?".to_string()],
+            ..Default::default()
+        })];
+        let store = Store::new(settings.clone())?;
+        assert_eq!(store.get_package_commit_from_git_remotes(&path)?, None);
+        let plan = store.substitution_plan(std::slice::from_ref(&path)).await?;
+        assert_eq!(plan.paths[0].source, None);
+
+        // The size is only known from the fetched narinfo
+        settings.path = temp_dir.path().join("small");
+        settings.peer_filters = vec![peer_filter(settings::IngestFilters {
+            max_nar_size: Some(1),
+            ..Default::default()
+        })];
+        let store = Store::new(settings.clone())?;
+        assert_eq!(store.get_package_commit_from_git_remotes(&path)?, None);
+        assert!(store.repo.list_references("refs/*")?.is_empty());
+
+        settings.path = temp_dir.path().join("unfiltered");
+        settings.peer_filters = Vec::new();
+        let store = Store::new(settings)?;
+        assert!(store.get_package_commit_from_git_remotes(&path)?.is_some());
+
+        // Packages copied to a peer are filtered as well
+        let bare = FakeRemote::bare()?;
+        let mut settings = set_repo_path(&temp_dir.path().join("copying"));
+        settings.peer_filters = vec![settings::PeerFilter {
+            remote: bare.url.clone(),
+            filters: settings::IngestFilters {
+                include: vec!["other-*".to_string()],
+                ..Default::default()
+            },
+        }];
+        let copying = Store::new(settings)?;
+        add_fake_entry(&copying, &hash, &[], Some(&[]))?;
+        let summary = copying.copy_to(std::slice::from_ref(&hash), bare.url.as_str())?;
+        assert_eq!((summary.copied, summary.skipped), (0, 1));
+        assert!(!bare.store.entry_exists(&hash)?);
+        Ok(())
     }
 
-    pub fn list_entries(&self) -> Result<Vec<String>> {
-        let entries = self.repo.list_references("refs/*")?;
-        Ok(entries)
+    #[test]
+    fn test_quarantine_review() -> Result<()> {
+        use crate::git_store::verify::Verification;
+        use crate::nix_interface::nar_info::NarInfo;
+
+        let remote = FakeRemote::new()?;
+        let unsigned = "u".repeat(32);
+        let corrupted = "c".repeat(32);
+        remote.add(&unsigned, &[])?;
+        remote.add(&corrupted, &[])?;
+        let narinfo = remote.store.get_narinfo(&corrupted)?.unwrap();
+        let narinfo = NarInfo::replace_field(&String::from_utf8_lossy(&narinfo), "NarSize", "1");
+        let narinfo_oid = remote.store.repo.add_file_content(narinfo.as_bytes())?;
+        remote
+            .store
+            .repo
+            .update_ref(&remote.store.get_narinfo_ref(&corrupted), narinfo_oid)?;
+
+        let temp_dir = TempDir::new()?;
+        let mut settings = set_repo_path(&temp_dir.path().join("gachix"));
+        settings.remotes = vec![remote.url.clone()];
+        settings.trusted_public_keys =
+            vec!["cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=".to_string()];
+        settings.quarantine_rejected = true;
+        let store = Store::new(settings)?;
+        for hash in [&unsigned, &corrupted] {
+            let path = NixPath::new(&format!("/nix/store/{hash}-pkg"))?;
+            assert_eq!(store.get_package_commit_from_git_remotes(&path)?, None);
+            // Held packages are not fetched again until they are reviewed
+            assert_eq!(store.get_package_commit_from_git_remotes(&path)?, None);
+        }
+        assert!(store.list_entries(&ListOptions::default())?.is_empty());
+        let held = store.quarantined()?;
+        assert_eq!(held.len(), 2);
+        assert_eq!(held[0].hash, corrupted);
+        assert!(matches!(
+            held[0].verification,
+            Verification::SizeMismatch { expected: 1, .. }
+        ));
+        assert_eq!(held[1].hash, unsigned);
+        assert_eq!(held[1].verification, Verification::UntrustedSignature);
+        assert_eq!(
+            held[1].store_path.as_deref(),
+            Some(format!("/nix/store/{unsigned}-pkg").as_str())
+        );
+
+        // Only intact packages can be promoted
+        assert!(store.promote(&corrupted).is_err());
+        store.promote(&unsigned)?;
+        assert_eq!(
+            store.get_commit(&unsigned),
+            remote.store.get_commit(&unsigned)
+        );
+        assert!(store.promote(&unsigned).is_err());
+
+        store.drop_quarantined(&corrupted)?;
+        assert!(store.quarantined()?.is_empty());
+        assert!(store.drop_quarantined(&corrupted).is_err());
+        assert_eq!(store.list_entries(&ListOptions::default())?.len(), 1);
+        Ok(())
     }
 
-    fn num_available_packages(&self) -> Result<usize> {
-        Ok(self.repo.list_references("refs/*/narinfo")?.len())
+    #[test]
+    fn test_review_unsigned() -> Result<()> {
+        use crate::git_store::verify::Verification;
+
+        let remote = FakeRemote::new()?;
+        let hash = "s".repeat(32);
+        remote.add(&hash, &[])?;
+
+        // Without trusted keys, every package from a peer waits for a review
+        let temp_dir = TempDir::new()?;
+        let mut settings = set_repo_path(&temp_dir.path().join("gachix"));
+        settings.remotes = vec![remote.url.clone()];
+        settings.review_unsigned = true;
+        let store = Store::new(settings)?;
+        let path = NixPath::new(&format!("/nix/store/{hash}-pkg"))?;
+        assert_eq!(store.get_package_commit_from_git_remotes(&path)?, None);
+        assert!(!store.entry_exists(&hash)?);
+        let held = store.quarantined()?;
+        assert_eq!(held.len(), 1);
+        assert_eq!(held[0].verification, Verification::UntrustedSignature);
+
+        store.promote(&hash)?;
+        assert_eq!(store.get_commit(&hash), remote.store.get_commit(&hash));
+        Ok(())
     }
 
-    pub fn get_commit(&self, hash: &str) -> Option<Oid> {
-        self.repo.get_oid_from_reference(&self.get_result_ref(hash))
+    #[test]
+    fn test_push_to_git_remote() -> Result<()> {
+        let remote = FakeRemote::bare()?;
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+        let leaf = "l".repeat(32);
+        let root = "r".repeat(32);
+        add_fake_entry(&store, &leaf, &[], Some(&[]))?;
+        add_fake_entry(&store, &root, &[&leaf], Some(&[]))?;
+
+        let summary = store.copy_to(std::slice::from_ref(&root), remote.url.as_str())?;
+        assert_eq!((summary.copied, summary.present), (2, 0));
+        assert_eq!(remote.store.closure(&root)?.len(), 2);
+        assert_eq!(remote.store.get_commit(&root), store.get_commit(&root));
+
+        let summary = store.copy_to(std::slice::from_ref(&root), remote.url.as_str())?;
+        assert_eq!((summary.copied, summary.present), (0, 2));
+        Ok(())
     }
 
-    fn get_package_ref(&self, hash: &str) -> String {
-        format!("refs/{hash}")
+    #[test]
+    fn test_incomplete_fetch_is_rolled_back() -> Result<()> {
+        let remote = FakeRemote::new()?;
+        let dep = "d".repeat(32);
+        let root = "r".repeat(32);
+        remote.add(&dep, &[])?;
+        remote.add(&root, &[&dep])?;
+        // The dependency is half-written at the peer
+        remote
+            .store
+            .repo
+            .delete_ref(&remote.store.get_narinfo_ref(&dep))?;
+
+        let temp_dir = TempDir::new()?;
+        let store = remote.peer_of(&temp_dir.path().join("gachix"))?;
+        let root_path = NixPath::new(&format!("/nix/store/{root}-pkg"))?;
+        assert!(
+            store
+                .get_package_commit_from_git_remotes(&root_path)
+                .is_err()
+        );
+        assert!(store.repo.list_references("refs/*")?.is_empty());
+        Ok(())
     }
 
-    fn get_result_ref(&self, hash: &str) -> String {
-        format!("{}/result", self.get_package_ref(hash))
+    #[test]
+    fn test_failed_commit_is_rolled_back() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+
+        let path = NixPath::new(&format!("/nix/store/{}-pkg", "e".repeat(32)))?;
+        let hash = path.get_base_32_hash();
+        let package_dir = temp_dir.path().join("pkg");
+        std::fs::create_dir(&package_dir)?;
+        std::fs::write(package_dir.join("file"), b"content")?;
+        let package = FetchedPackage {
+            narinfo_blob_oid: store.repo.add_file_content(b"narinfo")?,
+            package_oid: store.repo.add_dir(&package_dir)?,
+            dependencies: vec![],
+            provenance: Provenance::new("local".to_string(), None),
+            nar_hash: format!("sha256:{}", "0".repeat(52)),
+            dedup: Dedup::default(),
+        };
+        // A leftover narinfo reference makes the commit fail partway through
+        let leftover = store.repo.add_file_content(b"leftover")?;
+        store.repo.add_ref(&store.get_narinfo_ref(hash), leftover)?;
+
+        assert!(store.commit_package(&path, &package, &[]).is_err());
+        assert_eq!(store.get_commit(hash), None);
+        assert_eq!(
+            store
+                .repo
+                .get_oid_from_reference(&store.get_narinfo_ref(hash)),
+            Some(leftover)
+        );
+
+        store.repo.delete_ref(&store.get_narinfo_ref(hash))?;
+        store.commit_package(&path, &package, &[])?;
+        assert!(store.entry_exists(hash)?);
+        Ok(())
     }
 
-    fn get_narinfo_ref(&self, hash: &str) -> String {
-        format!("{}/narinfo", self.get_package_ref(hash))
+    #[test]
+    fn test_eviction_candidates() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+
+        let leaf = "l".repeat(32);
+        let dep = "d".repeat(32);
+        let root = "r".repeat(32);
+        let other = "o".repeat(32);
+        add_fake_entry(&store, &leaf, &[], Some(&[]))?;
+        add_fake_entry(&store, &dep, &[&leaf], Some(&[]))?;
+        add_fake_entry(&store, &root, &[&dep, &leaf], Some(&[]))?;
+        add_fake_entry(&store, &other, &[&leaf], Some(&[]))?;
+
+        let mut candidates = store.eviction_candidates(&store.gc_policy(None)?)?;
+        candidates.sort();
+        assert_eq!(candidates, vec![(other, 0), (root.clone(), 0)]);
+
+        store.delete(&root, false)?;
+        let candidates = store.eviction_candidates(&store.gc_policy(None)?)?;
+        assert!(candidates.iter().any(|(hash, _)| *hash == dep));
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::{
-        git_store::store::Store,
-        nix_interface::{
-            daemon::{DynNixDaemon, NixDaemon},
-            path::NixPath,
-        },
-        settings,
-    };
-    use anyhow::Result;
-    use std::path::PathBuf;
-    use std::process::Command;
-    use tempfile::TempDir;
+    #[test]
+    fn test_cancelled_gc_keeps_packages() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let mut settings = set_repo_path(&temp_dir.path().join("gachix"));
+        settings.gc.low_watermark = 0.0;
+        let store = Store::new(settings)?;
 
-    fn build_nix_package(package_name: &str) -> Result<NixPath> {
-        let output = Command::new("nix")
-            .arg("build")
-            .arg(format!("nixpkgs#{}", package_name))
-            .arg("--print-out-paths")
-            .output()?;
+        let hash = "a".repeat(32);
+        add_fake_entry(&store, &hash, &[], Some(&[]))?;
 
-        let path = NixPath::new(&String::from_utf8_lossy(&output.stdout).to_string())?;
-        Ok(path)
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let summary = store.collect_garbage(&store.gc_policy(None)?, &cancel)?;
+        assert_eq!(summary.evicted, 0);
+        assert!(store.entry_exists(&hash)?);
+        Ok(())
     }
 
-    pub fn set_repo_path(path: &PathBuf) -> settings::Store {
-        settings::Store {
-            path: path.clone(),
-            builders: vec![],
-            remotes: vec![],
-            use_local_nix_daemon: true,
-            sign_private_key_path: None,
-            ssh_private_key_path: None,
-        }
+    #[test]
+    fn test_gc_check_interval() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let mut settings = set_repo_path(&temp_dir.path().join("gachix"));
+        settings.gc.check_interval = 0;
+        assert!(Store::new(settings).is_err());
+        Ok(())
     }
 
-    #[tokio::test(flavor = "multi_thread")]
-    async fn test_add_package() -> Result<()> {
+    #[tokio::test]
+    async fn test_cancelled_add_closure() -> Result<()> {
         let temp_dir = TempDir::new()?;
-        let repo_path = temp_dir.path().join("gachix");
-        let store = Store::new(set_repo_path(&repo_path))?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+        let path = NixPath::new(&format!("/nix/store/{}-pkg", "a".repeat(32)))?;
 
-        let path = build_nix_package("hello")?;
-        store.get_package_from_nix_daemons(&path).await?;
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        assert!(
+            store
+                ._add_closure(&path, None, &mut DedupReport::default(), &cancel)
+                .await
+                .is_err()
+        );
         Ok(())
     }
 
-    #[tokio::test(flavor = "multi_thread")]
-    async fn test_add_closure() -> Result<()> {
+    #[test]
+    fn test_reproducibility_report() -> Result<()> {
+        let remote = FakeRemote::new()?;
+        let (dep, hash) = ("d".repeat(32), "h".repeat(32));
+        remote.add(&hash, &[])?;
         let temp_dir = TempDir::new()?;
-        let repo_path = temp_dir.path().join("gachix");
-        let store = Store::new(set_repo_path(&repo_path))?;
+        let store = remote.peer_of(&temp_dir.path().join("gachix"))?;
+        store.fetch_closure(&hash, remote.url.as_str())?;
 
-        let path = build_nix_package("sl")?;
-        store.add_closure(&path).await?;
+        let report = store.verify_reproducible(&hash, remote.url.as_str())?;
+        assert!(report.is_reproducible(), "{report}");
+        // The fetched entry is not left behind as a package of its own
+        assert!(store.repo.list_references("refs/verify/*")?.is_empty());
+        assert_eq!(store.list_entries(&ListOptions::default())?.len(), 1);
+
+        remote.add(&dep, &[])?;
+        let dep_commit = remote.store.get_commit(&dep).unwrap();
+        remote.store.delete(&hash, true)?;
+        add_fake_entry(&remote.store, &hash, &[], Some(&[dep_commit]))?;
+        let report = store.verify_reproducible(&hash, remote.url.as_str())?;
+        let differing: Vec<&str> = report
+            .comparisons
+            .iter()
+            .filter(|c| c.local != c.peer)
+            .map(|c| c.property)
+            .collect();
+        assert_eq!(differing, vec!["commit"]);
+
+        let missing = "m".repeat(32);
+        add_fake_entry(&store, &missing, &[], Some(&[]))?;
+        assert!(
+            store
+                .verify_reproducible(&missing, remote.url.as_str())
+                .is_err()
+        );
+        assert!(store.repo.list_references("refs/verify/*")?.is_empty());
         Ok(())
     }
 
-    #[tokio::test]
-    async fn test_add_narinfo() -> Result<()> {
+    #[test]
+    fn test_verify() -> Result<()> {
+        use crate::git_store::verify::Verification;
+        use crate::nix_interface::nar_info::NarInfo;
+        use sha2::{Digest, Sha256};
+        use std::io::Read;
+
         let temp_dir = TempDir::new()?;
-        let repo_path = temp_dir.path().join("gachix");
-        let store = Store::new(set_repo_path(&repo_path))?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
 
-        let path = build_nix_package("kitty")?;
-        let mut nix = DynNixDaemon::Local(NixDaemon::local());
-        nix.connect().await?;
-        store.build_narinfo(&mut nix, "somekey", &path).await?;
+        let file_path = temp_dir.path().join("file");
+        std::fs::write(&file_path, b"hello")?;
+        let mut nar = Vec::new();
+        nix_nar::Encoder::new(&file_path)?.read_to_end(&mut nar)?;
+        let nar_hash = format!(
+            "sha256:{}",
+            nix_base32::to_nix_base32(&Sha256::digest(&nar))
+        );
+        let (oid, _, _) = store.repo.add_nar(nar.as_slice())?;
+
+        let hash = "iylhaki6573cpsvspivjfsim700n46r3";
+        let store_path = NixPath::new(&format!("/nix/store/{hash}-file"))?;
+        let mut narinfo = NarInfo::new(
+            store_path,
+            oid.to_string(),
+            nar_hash.clone(),
+            nar.len() as u64,
+            None,
+            nar_hash,
+            nar.len() as u64,
+            None,
+            vec![],
+            None,
+        );
+        let narinfo_oid = store
+            .repo
+            .add_file_content(narinfo.to_string().as_bytes())?;
+        store
+            .repo
+            .add_ref(&store.get_narinfo_ref(hash), narinfo_oid)?;
+        assert_eq!(store.verify(hash)?, Verification::Valid);
+
+        narinfo.nar_hash = "sha256:0000000000000000000000000000000000000000000000000000".into();
+        let narinfo_oid = store
+            .repo
+            .add_file_content(narinfo.to_string().as_bytes())?;
+        store
+            .repo
+            .update_ref(&store.get_narinfo_ref(hash), narinfo_oid)?;
+        assert!(matches!(
+            store.verify(hash)?,
+            Verification::HashMismatch { .. }
+        ));
+        assert_eq!(store.verify("missing")?, Verification::MissingNarinfo);
+        Ok(())
+    }
+
+    #[test]
+    fn test_narinfo_revisions() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let store = Store::new(set_repo_path(&temp_dir.path().join("gachix")))?;
+        let hash = "iylhaki6573cpsvspivjfsim700n46r3";
+        assert!(store.narinfo_revisions(hash).is_err());
+
+        let narinfo = |signature: &str| {
+            store.repo.add_file_content(
+                format!("StorePath: /nix/store/{hash}-file\nSig: {signature}\n").as_bytes(),
+            )
+        };
+        let peer = narinfo("peer-1:abc=")?;
+        let ours = narinfo("gachix-1:def=")?;
+        store.repo.add_ref(&store.get_narinfo_ref(hash), peer)?;
+        store.replace_narinfo(hash, ours, Reason::Resigned)?;
+        // Replacing a narinfo with itself keeps no revision
+        store.replace_narinfo(hash, ours, Reason::Resigned)?;
+        let old = narinfo("")?;
+        store.repo.add_ref(
+            &revisions::revision_ref(hash, 0, Reason::Repaired, old),
+            old,
+        )?;
+
+        let revisions = store.narinfo_revisions(hash)?;
+        let described: Vec<_> = revisions
+            .iter()
+            .map(|r| {
+                (
+                    r.superseded.map(|(_, reason)| reason),
+                    r.narinfo,
+                    r.keys.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            described,
+            [
+                (None, ours, vec!["gachix-1".to_string()]),
+                (Some(Reason::Resigned), peer, vec!["peer-1".to_string()]),
+                (Some(Reason::Repaired), old, vec![]),
+            ]
+        );
+
+        assert_eq!(store.expire_revisions(3600, true)?, 1);
+        assert_eq!(store.expire_revisions(3600, false)?, 1);
+        assert_eq!(store.narinfo_revisions(hash)?.len(), 2);
+
+        store.purge(hash, true)?;
+        assert!(
+            store
+                .repo
+                .list_references(&format!("{REVISIONS_NAMESPACE}/*"))?
+                .is_empty()
+        );
         Ok(())
     }
 }
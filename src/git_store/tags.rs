@@ -0,0 +1,90 @@
+use anyhow::{Result, bail};
+use std::fmt::Display;
+
+/// Tags are references `<name>.tag` below this namespace pointing at the narinfo
+/// of a package, which names it even if another package has the same contents.
+/// The names end in `.tag`, so that a tag called `result` is no package reference,
+/// see `snapshot::package_ref`.
+pub const TAGS_NAMESPACE: &str = "refs/tags";
+
+pub fn tag_ref(name: &str) -> String {
+    format!("{TAGS_NAMESPACE}/{name}.tag")
+}
+
+/// Returns the name of the tag a reference holds.
+pub fn parse_tag_ref(reference: &str) -> Option<&str> {
+    reference
+        .strip_prefix(TAGS_NAMESPACE)?
+        .strip_prefix('/')?
+        .strip_suffix(".tag")
+        .filter(|name| !name.contains('/'))
+}
+
+/// Tag names are made of letters, digits, `.`, `_` and `-`, and start with a
+/// letter or digit, e.g. `release-2024.11`.
+pub fn validate_tag_name(name: &str) -> Result<()> {
+    validate_name("tag", name)
+}
+
+/// Checks a name which becomes part of a reference, see `validate_tag_name`.
+pub fn validate_name(kind: &str, name: &str) -> Result<()> {
+    let valid = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+        && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && !name.ends_with(".lock")
+        && !name.contains("..");
+    if !valid {
+        bail!(
+            "Invalid {} name {:?}, use letters, digits, '.', '_' and '-'",
+            kind,
+            name
+        );
+    }
+    Ok(())
+}
+
+/// A human readable name for a stored package, see `gachix tag`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tag {
+    pub name: String,
+    pub hash: String,
+    pub store_path: String,
+    /// Whether the package is still stored
+    pub stored: bool,
+}
+
+impl Display for Tag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.name, self.store_path)?;
+        if !self.stored {
+            f.write_str(" (removed)")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tag_refs() {
+        let reference = tag_ref("release-2024.11");
+        assert_eq!(reference, "refs/tags/release-2024.11.tag");
+        assert_eq!(parse_tag_ref(&reference), Some("release-2024.11"));
+        assert_eq!(parse_tag_ref("refs/tags/release-2024.11"), None);
+        assert_eq!(
+            parse_tag_ref(&format!("refs/{}/result", "a".repeat(32))),
+            None
+        );
+
+        assert!(validate_tag_name("prod-frontend").is_ok());
+        assert!(validate_tag_name("result").is_ok());
+        for name in [
+            "", "-rc", ".hidden", "a/b", "a..b", "x.lock", "a b", "tag~1",
+        ] {
+            assert!(validate_tag_name(name).is_err(), "{name}");
+        }
+    }
+}
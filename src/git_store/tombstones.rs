@@ -0,0 +1,79 @@
+use super::history::format_time;
+use std::fmt::Display;
+
+/// Removed packages keep their references below this namespace as
+/// `<hash>/<seconds>.<kind>` until the tombstone grace period of the garbage
+/// collection passed. The package is neither served nor listed, not even its NAR,
+/// but its objects stay reachable so that it can be restored with `undelete`. The
+/// kind is a suffix, see `snapshot::package_ref`.
+pub const TOMBSTONES_NAMESPACE: &str = "refs/tombstones";
+
+pub fn tombstone_ref(hash: &str, time: i64, kind: &str) -> String {
+    format!("{TOMBSTONES_NAMESPACE}/{hash}/{time}.{kind}")
+}
+
+/// Splits a tombstone reference into the hash of the package, the time it was
+/// removed and the kind of the reference.
+pub fn parse_tombstone_ref(reference: &str) -> Option<(&str, i64, &str)> {
+    let (hash, name) = reference
+        .strip_prefix(TOMBSTONES_NAMESPACE)?
+        .strip_prefix('/')?
+        .split_once('/')?;
+    let (time, kind) = name.split_once('.')?;
+    Some((hash, time.parse().ok()?, kind))
+}
+
+/// A removed package whose objects are still kept.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tombstone {
+    pub hash: String,
+    /// Seconds since the Unix epoch
+    pub removed_at: i64,
+    pub store_path: Option<String>,
+}
+
+impl Display for Tombstone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {}",
+            format_time(self.removed_at),
+            self.hash,
+            self.store_path.as_deref().unwrap_or("-")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tombstone_refs() {
+        let hash = "a".repeat(32);
+        let reference = tombstone_ref(&hash, 1700000000, "narinfo");
+        assert_eq!(
+            reference,
+            format!("refs/tombstones/{hash}/1700000000.narinfo")
+        );
+        assert_eq!(
+            parse_tombstone_ref(&reference),
+            Some((hash.as_str(), 1700000000, "narinfo"))
+        );
+        assert_eq!(parse_tombstone_ref(&format!("refs/{hash}/narinfo")), None);
+        assert_eq!(
+            parse_tombstone_ref(&format!("refs/tombstones/{hash}/later.narinfo")),
+            None
+        );
+
+        let tombstone = Tombstone {
+            hash: hash.clone(),
+            removed_at: 1714564800,
+            store_path: None,
+        };
+        assert_eq!(
+            tombstone.to_string(),
+            format!("2024-05-01T12:00:00Z {hash} -")
+        );
+    }
+}
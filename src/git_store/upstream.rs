@@ -0,0 +1,288 @@
+use anyhow::{Result, anyhow, bail};
+use liblzma::read::XzDecoder;
+use reqwest::StatusCode;
+use reqwest::blocking::Client;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Display;
+use std::io::Read;
+use url::Url;
+
+use super::lease::Lease;
+use crate::nix_interface::nar_info::NarInfo;
+
+/// Narinfos imported from an upstream binary cache without their NARs are kept at
+/// `<hash>.narinfo` below this namespace until a client asks for the NAR, see
+/// `gachix upstream import` and, for the name, `snapshot::package_ref`.
+pub const UPSTREAM_NAMESPACE: &str = "refs/upstream";
+
+/// Field of an imported narinfo naming the cache it was imported from. It is
+/// dropped before the narinfo is served or the package is stored.
+pub const UPSTREAM_FIELD: &str = "Upstream";
+
+pub fn upstream_ref(hash: &str) -> String {
+    format!("{UPSTREAM_NAMESPACE}/{hash}.narinfo")
+}
+
+/// Returns the hash of the package whose imported narinfo a reference holds.
+pub fn parse_upstream_ref(reference: &str) -> Option<&str> {
+    reference
+        .strip_prefix(UPSTREAM_NAMESPACE)?
+        .strip_prefix('/')?
+        .strip_suffix(".narinfo")
+        .filter(|hash| !hash.contains('/'))
+}
+
+/// Where the NAR of an imported narinfo is served, which fetches it from upstream
+/// on the first request.
+pub fn lazy_nar_url(hash: &str) -> String {
+    format!("nar/upstream/{hash}.nar")
+}
+
+/// The cache an imported narinfo came from.
+pub fn upstream_of(narinfo: &NarInfo) -> Result<Url> {
+    let upstream = narinfo
+        .extra
+        .iter()
+        .find(|(key, _)| key == UPSTREAM_FIELD)
+        .map(|(_, value)| value)
+        .ok_or_else(|| anyhow!("Narinfo of {} has no upstream", narinfo.store_path))?;
+    Ok(Url::parse(upstream)?)
+}
+
+/// An imported narinfo as upstream wrote it.
+pub fn without_upstream(narinfo: &NarInfo) -> NarInfo {
+    let mut narinfo = narinfo.clone();
+    narinfo.extra.retain(|(key, _)| key != UPSTREAM_FIELD);
+    narinfo
+}
+
+/// The narinfo handed to clients for a package whose NAR is not fetched yet. The
+/// NAR is served uncompressed at `lazy_nar_url`. The signatures of upstream stay
+/// valid, as they don't cover the URL or the compression.
+pub fn served_narinfo(imported: &NarInfo) -> NarInfo {
+    let mut served = without_upstream(imported);
+    served.url = Some(lazy_nar_url(imported.store_path.get_base_32_hash()));
+    served.compression_type = None;
+    served.file_hash = imported.nar_hash.clone();
+    served.file_size = imported.nar_size;
+    served
+}
+
+/// Resolves a path relative to the root of a binary cache, which `Url::join`
+/// resolves relative to the last segment otherwise.
+fn join(upstream: &Url, path: &str) -> Result<Url> {
+    let mut base = upstream.clone();
+    if !base.path().ends_with('/') {
+        base.set_path(&format!("{}/", base.path()));
+    }
+    Ok(base.join(path)?)
+}
+
+/// Fetches the narinfo of a package from an upstream binary cache, `None` if the
+/// cache does not have it.
+pub fn fetch_narinfo(client: &Client, upstream: &Url, hash: &str) -> Result<Option<NarInfo>> {
+    let response = client
+        .get(join(upstream, &format!("{hash}.narinfo"))?)
+        .send()?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let narinfo = NarInfo::parse(&response.error_for_status()?.text()?)?;
+    if narinfo.store_path.get_base_32_hash() != hash {
+        bail!(
+            "{} answered the narinfo of {} with {}",
+            upstream,
+            hash,
+            narinfo.store_path
+        );
+    }
+    Ok(Some(narinfo))
+}
+
+/// Opens the uncompressed NAR of a narinfo fetched from `upstream`.
+pub fn fetch_nar(
+    client: &Client,
+    upstream: &Url,
+    narinfo: &NarInfo,
+) -> Result<Box<dyn Read + Send>> {
+    let url = narinfo
+        .url
+        .as_deref()
+        .ok_or_else(|| anyhow!("Narinfo of {} has no URL", narinfo.store_path))?;
+    let response = client
+        .get(join(upstream, url)?)
+        .send()?
+        .error_for_status()?;
+    match narinfo.compression_type.as_deref() {
+        None | Some("none") => Ok(Box::new(response)),
+        Some("xz") => Ok(Box::new(XzDecoder::new(response))),
+        Some(compression) => bail!(
+            "Can't fetch {}, its NAR is compressed with unsupported {}",
+            narinfo.store_path,
+            compression
+        ),
+    }
+}
+
+/// The outcome of `gachix upstream import`.
+#[derive(Debug, Default)]
+pub struct MetadataImport {
+    pub imported: usize,
+    /// Packages which were stored or imported before
+    pub present: usize,
+    /// Store paths upstream does not have
+    pub missing: Vec<String>,
+    /// Store paths whose narinfo is not signed by a trusted key
+    pub untrusted: Vec<String>,
+    /// Store paths left out because a dependency is missing or untrusted
+    pub incomplete: Vec<String>,
+}
+
+impl Display for MetadataImport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Imported {} narinfos, {} were already present",
+            self.imported, self.present
+        )?;
+        for path in &self.missing {
+            writeln!(f, "Not available upstream: {path}")?;
+        }
+        for path in &self.untrusted {
+            writeln!(f, "Not signed by a trusted key: {path}")?;
+        }
+        for path in &self.incomplete {
+            writeln!(f, "Left out, a dependency is missing or untrusted: {path}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The hashes of the fetched narinfos whose closure is complete: every dependency
+/// is `present` already or fetched with a complete closure itself.
+pub fn complete_closures(
+    fetched: &BTreeMap<String, NarInfo>,
+    present: &HashSet<String>,
+) -> HashSet<String> {
+    let mut complete: HashSet<String> = fetched.keys().cloned().collect();
+    loop {
+        let incomplete: Vec<String> = complete
+            .iter()
+            .filter(|hash| {
+                fetched[*hash].get_dependencies().iter().any(|dependency| {
+                    let dependency = dependency.get_base_32_hash();
+                    !present.contains(dependency) && !complete.contains(dependency)
+                })
+            })
+            .cloned()
+            .collect();
+        if incomplete.is_empty() {
+            return complete;
+        }
+        for hash in incomplete {
+            complete.remove(&hash);
+        }
+    }
+}
+
+/// The NAR of an imported package, fetched into the repository before the package
+/// is added, see `Store::fetch_upstream_nar`.
+pub struct FetchedNar {
+    /// The imported narinfo, without the upstream field and with the tree of the
+    /// NAR as its key
+    pub narinfo: NarInfo,
+    pub upstream: Url,
+    /// Keeps garbage collection from pruning the NAR until the package is added
+    pub lease: Lease,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_served_narinfo() -> Result<()> {
+        let hash = "h".repeat(32);
+        let reference = upstream_ref(&hash);
+        assert_eq!(reference, format!("refs/upstream/{hash}.narinfo"));
+        assert_eq!(parse_upstream_ref(&reference), Some(hash.as_str()));
+        assert_eq!(parse_upstream_ref(&format!("refs/{hash}/narinfo")), None);
+
+        let mut imported = NarInfo::parse(&format!(
+            "StorePath: /nix/store/{hash}-hello\n\
+             URL: nar/1abc.nar.xz\n\
+             Compression: xz\n\
+             FileHash: sha256:1abc\n\
+             FileSize: 40\n\
+             NarHash: sha256:0def\n\
+             NarSize: 120\n\
+             References: {hash}-hello\n\
+             Deriver: \n\
+             Sig: cache.nixos.org-1:c2ln\n"
+        ))?;
+        assert!(upstream_of(&imported).is_err());
+        imported.set_extra(UPSTREAM_FIELD, "https://cache.nixos.org")?;
+        assert_eq!(upstream_of(&imported)?.as_str(), "https://cache.nixos.org/");
+
+        let served = served_narinfo(&imported).to_string();
+        assert_eq!(
+            NarInfo::field(&served, "URL"),
+            Some(format!("nar/upstream/{hash}.nar").as_str())
+        );
+        assert_eq!(NarInfo::field(&served, "Compression"), Some("none"));
+        assert_eq!(NarInfo::field(&served, "FileHash"), Some("sha256:0def"));
+        assert_eq!(NarInfo::field(&served, "FileSize"), Some("120"));
+        assert_eq!(
+            NarInfo::field(&served, "Sig"),
+            Some("cache.nixos.org-1:c2ln")
+        );
+        assert_eq!(NarInfo::field(&served, UPSTREAM_FIELD), None);
+
+        let upstream = Url::parse("https://mirror.example.org/nix")?;
+        assert_eq!(
+            join(&upstream, "nar/1abc.nar.xz")?.as_str(),
+            "https://mirror.example.org/nix/nar/1abc.nar.xz"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_complete_closures() -> Result<()> {
+        let narinfo = |hash: &str, references: &[&str]| -> Result<(String, NarInfo)> {
+            let references: Vec<String> = references.iter().map(|r| format!("{r}-dep")).collect();
+            let narinfo = NarInfo::parse(&format!(
+                "StorePath: /nix/store/{hash}-dep
+URL: nar/1abc.nar
+Compression: none
+                 FileHash: sha256:1abc
+FileSize: 40
+NarHash: sha256:1abc
+NarSize: 40
+                 References: {}
+Deriver: 
+Sig: 
+",
+                references.join(" ")
+            ))?;
+            Ok((hash.to_string(), narinfo))
+        };
+        let (app, lib, stored, broken, missing) = (
+            "a".repeat(32),
+            "l".repeat(32),
+            "s".repeat(32),
+            "b".repeat(32),
+            "m".repeat(32),
+        );
+        let fetched = BTreeMap::from([
+            narinfo(&app, &[&app, &lib, &stored])?,
+            narinfo(&lib, &[&stored])?,
+            // Its dependency was missing upstream, and so is its referrer left out
+            narinfo(&broken, &[&missing])?,
+            narinfo(&"r".repeat(32), &[&broken])?,
+        ]);
+        let present = HashSet::from([stored]);
+        let complete = complete_closures(&fetched, &present);
+        assert_eq!(complete, HashSet::from([app, lib]));
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+use std::fmt::Display;
+
+/// The entry a peer produced is fetched to `<hash>.<kind>` below this namespace by
+/// `gachix verify-reproducible` while it is compared, see `snapshot::package_ref`.
+pub const VERIFY_NAMESPACE: &str = "refs/verify";
+
+pub fn verify_ref(hash: &str, kind: &str) -> String {
+    format!("{VERIFY_NAMESPACE}/{hash}.{kind}")
+}
+
+/// The outcome of checking a stored entry against its narinfo.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verification {
+    Valid,
+    MissingNarinfo,
+    InvalidNarinfo(String),
+    MissingObjects(String),
+    HashMismatch { expected: String, actual: String },
+    SizeMismatch { expected: u64, actual: u64 },
+    UntrustedSignature,
+}
+
+impl Verification {
+    pub fn is_valid(&self) -> bool {
+        *self == Verification::Valid
+    }
+}
+
+impl Display for Verification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Verification::Valid => f.write_str("valid"),
+            Verification::MissingNarinfo => f.write_str("narinfo is missing"),
+            Verification::InvalidNarinfo(e) => write!(f, "narinfo is invalid: {e}"),
+            Verification::MissingObjects(e) => write!(f, "objects are missing: {e}"),
+            Verification::HashMismatch { expected, actual } => {
+                write!(f, "NAR hash mismatch: expected {expected}, got {actual}")
+            }
+            Verification::SizeMismatch { expected, actual } => {
+                write!(f, "NAR size mismatch: expected {expected}, got {actual}")
+            }
+            Verification::UntrustedSignature => f.write_str("not signed by a trusted key"),
+        }
+    }
+}
+
+/// A property of an entry as computed by this instance and by a peer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comparison {
+    pub property: &'static str,
+    pub local: String,
+    pub peer: String,
+}
+
+/// The outcome of comparing an entry with the one a peer produced for the same store path.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReproducibilityReport {
+    pub hash: String,
+    pub comparisons: Vec<Comparison>,
+}
+
+impl ReproducibilityReport {
+    pub fn new(
+        hash: &str,
+        local: Vec<(&'static str, String)>,
+        peer: Vec<(&'static str, String)>,
+    ) -> Self {
+        let comparisons = local
+            .into_iter()
+            .zip(peer)
+            .map(|((property, local), (_, peer))| Comparison {
+                property,
+                local,
+                peer,
+            })
+            .collect();
+        Self {
+            hash: hash.to_string(),
+            comparisons,
+        }
+    }
+
+    pub fn is_reproducible(&self) -> bool {
+        self.comparisons.iter().all(|c| c.local == c.peer)
+    }
+}
+
+impl Display for ReproducibilityReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for comparison in &self.comparisons {
+            if comparison.local == comparison.peer {
+                writeln!(
+                    f,
+                    "{}: identical ({})",
+                    comparison.property, comparison.local
+                )?;
+            } else {
+                writeln!(
+                    f,
+                    "{}: differs (local {}, peer {})",
+                    comparison.property, comparison.local, comparison.peer
+                )?;
+            }
+        }
+        if self.is_reproducible() {
+            writeln!(f, "Entry {} is reproducible", self.hash)
+        } else {
+            writeln!(f, "Entry {} is not reproducible", self.hash)
+        }
+    }
+}
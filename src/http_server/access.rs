@@ -0,0 +1,111 @@
+use crate::settings::ReadAccess;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use sha2::{Digest, Sha256};
+
+/// Picks the rule guarding a request path, the one with the longest matching
+/// prefix, or `None` if the path can be read by anyone.
+pub fn guarding<'a>(rules: &'a [ReadAccess], path: &str) -> Option<&'a ReadAccess> {
+    rules
+        .iter()
+        .filter(|rule| path.starts_with(rule.prefix.as_str()))
+        .max_by_key(|rule| rule.prefix.len())
+}
+
+/// Whether the `Authorization` header of a request carries credentials of a rule,
+/// either a bearer token or the `user:password` of basic auth, which is what Nix
+/// sends for the machines of its `netrc-file`.
+pub fn grants(rule: &ReadAccess, authorization: &str) -> bool {
+    if let Some(token) = authorization.strip_prefix("Bearer ") {
+        return rule.tokens.iter().any(|t| secrets_match(token, t));
+    }
+    let Some(encoded) = authorization.strip_prefix("Basic ") else {
+        return false;
+    };
+    let Ok(decoded) = BASE64_STANDARD.decode(encoded.trim()) else {
+        return false;
+    };
+    let Ok(credentials) = String::from_utf8(decoded) else {
+        return false;
+    };
+    rule.users
+        .iter()
+        .any(|user| secrets_match(&credentials, user))
+}
+
+/// Compares a secret a client sent to the expected one in constant time, so that
+/// the time a check takes does not tell how much of a guess was right. Both are
+/// hashed first, which hides the length of the expected secret as well.
+pub fn secrets_match(given: &str, expected: &str) -> bool {
+    let (given, expected) = (Sha256::digest(given), Sha256::digest(expected));
+    given
+        .iter()
+        .zip(expected.iter())
+        .fold(0, |difference, (a, b)| difference | (a ^ b))
+        == 0
+}
+
+/// The `WWW-Authenticate` challenges for the kinds of credentials a rule accepts.
+/// The admin token is accepted as a bearer token on every path.
+pub fn challenges(rule: &ReadAccess) -> Vec<&'static str> {
+    let mut challenges = Vec::new();
+    if !rule.users.is_empty() {
+        challenges.push(r#"Basic realm="gachix""#);
+    }
+    if !rule.tokens.is_empty() || challenges.is_empty() {
+        challenges.push(r#"Bearer realm="gachix""#);
+    }
+    challenges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(prefix: &str, users: &[&str], tokens: &[&str]) -> ReadAccess {
+        ReadAccess {
+            prefix: prefix.to_string(),
+            users: users.iter().map(|u| u.to_string()).collect(),
+            tokens: tokens.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_guarding() {
+        let rules = [
+            rule("/", &["ci:secret"], &[]),
+            rule("/cas/", &[], &["artifacts"]),
+        ];
+        assert_eq!(guarding(&rules, "/cas/abc").unwrap().prefix, "/cas/");
+        assert_eq!(guarding(&rules, "/abc.narinfo").unwrap().prefix, "/");
+        assert!(guarding(&rules[1..], "/abc.narinfo").is_none());
+    }
+
+    #[test]
+    fn test_secrets_match() {
+        assert!(secrets_match("secret", "secret"));
+        assert!(!secrets_match("secreT", "secret"));
+        assert!(!secrets_match("secret-but-longer", "secret"));
+        assert!(!secrets_match("", "secret"));
+    }
+
+    #[test]
+    fn test_grants() {
+        let access = rule("/", &["ci:secret"], &["token"]);
+        let basic = |credentials: &str| format!("Basic {}", BASE64_STANDARD.encode(credentials));
+        assert!(grants(&access, &basic("ci:secret")));
+        assert!(!grants(&access, &basic("ci:wrong")));
+        assert!(grants(&access, "Bearer token"));
+        assert!(!grants(&access, "Bearer other"));
+        assert!(!grants(&access, "Basic not-base64!"));
+        assert!(!grants(&access, "Digest ci"));
+        assert_eq!(
+            challenges(&access),
+            [r#"Basic realm="gachix""#, r#"Bearer realm="gachix""#]
+        );
+        assert_eq!(
+            challenges(&rule("/", &[], &[])),
+            [r#"Bearer realm="gachix""#]
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use crate::git_store::gc::format_size;
+use crate::git_store::history::format_time;
+use crate::git_store::listing::{EntrySummary, ListOptions};
+
+/// Entries shown on a page of `/packages` unless the query asks for another limit.
+pub const PAGE_SIZE: usize = 100;
+
+/// Escapes text for use in HTML, both as content and within quoted attributes.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n\
+         <style>table {{ border-collapse: collapse }} td, th {{ padding: 0.2em 0.8em; text-align: left }}</style>\n\
+         </head>\n<body>\n{body}</body>\n</html>\n",
+        title = escape(title)
+    )
+}
+
+/// The search form of both pages, filled in with the current name filter.
+fn search_form(options: &ListOptions) -> String {
+    format!(
+        "<form action=\"/packages\" method=\"get\">\n\
+         <input type=\"search\" name=\"name\" value=\"{}\" placeholder=\"Package name\">\n\
+         <input type=\"submit\" value=\"Search\">\n</form>\n",
+        escape(options.name.as_deref().unwrap_or_default())
+    )
+}
+
+/// The landing page at `/`, telling people how to use the cache.
+pub fn render_index(packages: usize) -> String {
+    let body = format!(
+        "<h1>Nix binary cache</h1>\n\
+         <p>This cache holds {packages} packages for <code>/nix/store</code>. \
+         Add its URL to the <code>substituters</code> of Nix to use it.</p>\n\
+         {form}<p><a href=\"/packages\">Browse all packages</a></p>\n",
+        form = search_form(&ListOptions::default())
+    );
+    page("Nix binary cache", &body)
+}
+
+/// Builds the query of a link to another page of `/packages`, keeping the filters.
+fn page_query(options: &ListOptions, offset: usize) -> String {
+    let mut query = vec![format!("offset={offset}")];
+    if let Some(limit) = options.limit {
+        query.push(format!("limit={limit}"));
+    }
+    if let Some(name) = &options.name {
+        query.push(format!(
+            "name={}",
+            url::form_urlencoded::byte_serialize(name.as_bytes()).collect::<String>()
+        ));
+    }
+    if let Some(system) = &options.system {
+        query.push(format!(
+            "system={}",
+            url::form_urlencoded::byte_serialize(system.as_bytes()).collect::<String>()
+        ));
+    }
+    escape(&query.join("&"))
+}
+
+/// The page at `/packages` listing one page of entries. `options` are the ones
+/// the entries were listed with, its limit already defaulted to a page.
+pub fn render_packages(entries: &[EntrySummary], options: &ListOptions) -> String {
+    let mut body = String::from("<h1>Packages</h1>\n<p><a href=\"/\">Back</a></p>\n");
+    body.push_str(&search_form(options));
+    if entries.is_empty() {
+        body.push_str("<p>No packages found.</p>\n");
+        return page("Packages", &body);
+    }
+    body.push_str(
+        "<table>\n<tr><th>Name</th><th>Version</th><th>Size</th><th>Added</th><th>Hash</th><th></th></tr>\n",
+    );
+    for entry in entries {
+        // Names come from pushed narinfos, so cutting off the version must not panic
+        let pname = entry
+            .name
+            .len()
+            .checked_sub(entry.version.as_ref().map_or(0, |v| v.len() + 1))
+            .and_then(|end| entry.name.get(..end))
+            .unwrap_or(&entry.name);
+        let log = entry
+            .log
+            .as_ref()
+            .map(|drv| format!(" <a href=\"/log/{}\">log</a>", escape(drv)))
+            .unwrap_or_default();
+        body.push_str(&format!(
+            "<tr><td>{pname}</td><td>{version}</td><td>{size}</td><td>{added}</td>\
+             <td><code>{hash}</code></td>\
+             <td><a href=\"/{hash}.narinfo\">narinfo</a> <a href=\"/{hash}.ls\">files</a>{log}</td></tr>\n",
+            pname = escape(pname),
+            version = escape(entry.version.as_deref().unwrap_or_default()),
+            size = entry.nar_size.map(format_size).unwrap_or_default(),
+            added = entry
+                .added_at
+                .map(|time| format_time(time as i64))
+                .unwrap_or_default(),
+            hash = escape(&entry.hash),
+        ));
+    }
+    body.push_str("</table>\n<p>\n");
+    let limit = options.limit.unwrap_or(PAGE_SIZE);
+    if options.offset > 0 {
+        body.push_str(&format!(
+            "<a href=\"/packages?{}\">Previous</a>\n",
+            page_query(options, options.offset.saturating_sub(limit))
+        ));
+    }
+    // A full page hints at more entries, listing one more just to know is not worth it
+    if entries.len() >= limit {
+        body.push_str(&format!(
+            "<a href=\"/packages?{}\">Next</a>\n",
+            page_query(options, options.offset + limit)
+        ));
+    }
+    body.push_str("</p>\n");
+    page("Packages", &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(hash: &str, name: &str, version: Option<&str>) -> EntrySummary {
+        EntrySummary {
+            hash: hash.to_string(),
+            name: name.to_string(),
+            version: version.map(str::to_string),
+            nar_size: Some(1536),
+            added_at: Some(1714564800),
+            log: None,
+        }
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(
+            escape(r#"<a href="x">'&'</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;"
+        );
+        assert_eq!(escape("hello-2.12"), "hello-2.12");
+    }
+
+    #[test]
+    fn test_render_packages() {
+        let options = ListOptions {
+            limit: Some(2),
+            name: Some("a&b".to_string()),
+            ..Default::default()
+        };
+        let entries = [
+            summary("aaa", "hello-2.12", Some("2.12")),
+            summary("bbb", "<script>", None),
+        ];
+        let html = render_packages(&entries, &options);
+        assert!(
+            html.contains(
+                "<td>hello</td><td>2.12</td><td>1.5 KiB</td><td>2024-05-01T12:00:00Z</td>"
+            )
+        );
+        assert!(html.contains("<a href=\"/aaa.narinfo\">narinfo</a>"));
+        assert!(!html.contains("/log/"));
+        assert!(html.contains("&lt;script&gt;"));
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("value=\"a&amp;b\""));
+        assert!(html.contains("/packages?offset=2&amp;limit=2&amp;name=a%26b"));
+        assert!(!html.contains("Previous"));
+
+        let last = ListOptions {
+            offset: 2,
+            ..options
+        };
+        let html = render_packages(&entries[..1], &last);
+        assert!(html.contains("/packages?offset=0&amp;limit=2"));
+        assert!(!html.contains("Next"));
+
+        let logged = EntrySummary {
+            log: Some("ddd-hello-2.12.drv".to_string()),
+            ..summary("aaa", "hello-2.12", Some("2.12"))
+        };
+        let html = render_packages(&[logged], &ListOptions::default());
+        assert!(html.contains("<a href=\"/log/ddd-hello-2.12.drv\">log</a>"));
+
+        // Versions which are not a suffix of the name must not split a character
+        let html = render_packages(
+            &[
+                summary("ccc", "éx", Some("")),
+                summary("ddd", "a", Some("1.0")),
+            ],
+            &ListOptions::default(),
+        );
+        assert!(html.contains("<td>éx</td>"));
+        assert!(html.contains("<td>a</td><td>1.0</td>"));
+
+        assert!(render_packages(&[], &ListOptions::default()).contains("No packages found"));
+        assert!(render_index(3).contains("holds 3 packages"));
+    }
+}
@@ -1,2 +1,6 @@
+pub mod access;
+pub mod browse;
+pub mod network;
+pub mod resumable;
 pub mod server;
 pub use server::start_server;
@@ -0,0 +1,134 @@
+use crate::settings::NetworkAccess;
+use anyhow::{anyhow, bail};
+use serde::Deserialize;
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A network like `10.0.0.0/8` or `2001:db8::/32`. A single address stands for a
+/// network of just that address.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a server listening on IPv6 show up as `::ffff:a.b.c.d`
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(ip) & mask == u32::from(network)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(ip) & mask == u128::from(network)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let address: IpAddr = address
+            .parse()
+            .map_err(|_| anyhow!("Invalid address in network {}", s))?;
+        let bits = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .map_err(|_| anyhow!("Invalid prefix length in network {}", s))?,
+            None => bits,
+        };
+        if prefix > bits {
+            bail!("Prefix length of network {} is longer than the address", s);
+        }
+        // Host bits are ignored, `10.1.2.3/8` is `10.0.0.0/8`
+        let network = match address {
+            IpAddr::V4(address) => {
+                let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+                IpAddr::V4((u32::from(address) & mask).into())
+            }
+            IpAddr::V6(address) => {
+                let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+                IpAddr::V6((u128::from(address) & mask).into())
+            }
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<Self> {
+        s.parse()
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Whether a client may use endpoints guarded by `access`: it must not be in a
+/// denied network, and it has to be in an allowed network unless none are listed.
+pub fn admits(access: &NetworkAccess, ip: IpAddr) -> bool {
+    !access.deny.iter().any(|network| network.contains(ip))
+        && (access.allow.is_empty() || access.allow.iter().any(|network| network.contains(ip)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cidr() -> anyhow::Result<()> {
+        let office: Cidr = "10.1.2.3/16".parse()?;
+        assert_eq!(office.to_string(), "10.1.0.0/16");
+        assert!(office.contains("10.1.200.7".parse()?));
+        assert!(office.contains("::ffff:10.1.0.1".parse()?));
+        assert!(!office.contains("10.2.0.1".parse()?));
+        assert!(!office.contains("2001:db8::1".parse()?));
+
+        let host: Cidr = "192.168.0.5".parse()?;
+        assert!(host.contains("192.168.0.5".parse()?));
+        assert!(!host.contains("192.168.0.6".parse()?));
+
+        let everything: Cidr = "::/0".parse()?;
+        assert!(everything.contains("2001:db8::1".parse()?));
+        let v6: Cidr = "2001:db8::/32".parse()?;
+        assert!(v6.contains("2001:db8:1::1".parse()?));
+        assert!(!v6.contains("2001:db9::1".parse()?));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_admits() -> anyhow::Result<()> {
+        let open = NetworkAccess::default();
+        assert!(admits(&open, "203.0.113.1".parse()?));
+
+        let ci = NetworkAccess {
+            allow: vec!["10.0.0.0/8".parse()?],
+            deny: vec!["10.9.0.0/16".parse()?],
+        };
+        assert!(admits(&ci, "10.1.0.1".parse()?));
+        assert!(!admits(&ci, "10.9.0.1".parse()?));
+        assert!(!admits(&ci, "203.0.113.1".parse()?));
+        Ok(())
+    }
+}
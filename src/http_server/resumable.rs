@@ -0,0 +1,214 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::nix_interface::path::NIX_BASE32_CHARS;
+
+/// Collects the chunks of uploads to `/cas` which are sent with a `Content-Range`,
+/// one file per artifact, so that an interrupted upload continues where it stopped
+/// instead of starting from zero. The files live in the repository, survive
+/// restarts of the server and are removed once they are older than the expiry.
+/// NARs pushed by `gachix push` are kept in the same directory until their narinfo
+/// arrives.
+#[derive(Debug)]
+pub struct PartialUploads {
+    dir: PathBuf,
+    expiry: Duration,
+    active: Mutex<HashSet<String>>,
+}
+
+/// Marks the upload of an artifact as being received until it is dropped, so that
+/// two clients never append to the same file.
+#[derive(Debug)]
+pub struct Claim {
+    uploads: Arc<PartialUploads>,
+    hash: String,
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        self.uploads.active.lock().unwrap().remove(&self.hash);
+    }
+}
+
+impl Claim {
+    fn path(&self) -> PathBuf {
+        self.uploads.dir.join(format!("{}.part", self.hash))
+    }
+
+    /// Bytes of the artifact which were received so far.
+    pub fn received(&self) -> io::Result<u64> {
+        match fs::metadata(self.path()) {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Opens the file for appending the next chunk.
+    pub fn append(&self) -> io::Result<File> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path())
+    }
+
+    /// Opens the complete upload for reading.
+    pub fn open(&self) -> io::Result<File> {
+        File::open(self.path())
+    }
+
+    /// Forgets the upload, after it was stored or rejected.
+    pub fn remove(&self) -> io::Result<()> {
+        match fs::remove_file(self.path()) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl PartialUploads {
+    pub fn new(dir: PathBuf, expiry: Duration) -> io::Result<Arc<Self>> {
+        fs::create_dir_all(&dir)?;
+        Ok(Arc::new(Self {
+            dir,
+            expiry,
+            active: Mutex::new(HashSet::new()),
+        }))
+    }
+
+    /// Claims the upload of the artifact `hash`, or `None` if another request is
+    /// receiving it or the hash is not a nix-base32 sha256.
+    pub fn claim(self: &Arc<Self>, hash: &str) -> Option<Claim> {
+        if !is_artifact_hash(hash) || !self.active.lock().unwrap().insert(hash.to_string()) {
+            return None;
+        }
+        Some(Claim {
+            uploads: Arc::clone(self),
+            hash: hash.to_string(),
+        })
+    }
+
+    /// Where a NAR pushed before its narinfo is kept, by the file name the narinfo
+    /// points at, or `None` if the name is not `<hash>.nar` or `<hash>.nar.xz`.
+    pub fn staged_nar(&self, file_name: &str) -> Option<PathBuf> {
+        let (hash, extension) = file_name.split_once('.')?;
+        (is_artifact_hash(hash) && matches!(extension, "nar" | "nar.xz"))
+            .then(|| self.dir.join(file_name))
+    }
+
+    /// Removes the files of uploads which were not continued within the expiry and
+    /// returns how many were removed.
+    pub fn remove_expired(&self) -> io::Result<usize> {
+        let now = SystemTime::now();
+        let active = self.active.lock().unwrap();
+        let mut removed = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            // Chunks are named `<hash>.part`, pushed NARs `<hash>.nar.xz`
+            let Some(hash) = name.to_str().and_then(|name| name.split('.').next()) else {
+                continue;
+            };
+            let modified = entry.metadata()?.modified()?;
+            let age = now.duration_since(modified).unwrap_or_default();
+            if age > self.expiry && !active.contains(hash) {
+                fs::remove_file(entry.path())?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Whether `hash` can name an artifact, which also keeps it from escaping the
+/// directory of the uploads.
+pub fn is_artifact_hash(hash: &str) -> bool {
+    hash.len() == 52 && hash.bytes().all(|c| NIX_BASE32_CHARS.contains(&c))
+}
+
+/// Where a chunk has to start, and whether it completes the upload.
+#[derive(Debug, PartialEq)]
+pub enum ChunkCheck {
+    /// The chunk continues the upload
+    Append { complete: bool },
+    /// The chunk does not start at the bytes received so far
+    Misplaced,
+    /// The range does not lie within the artifact
+    Invalid,
+    /// The artifact is larger than allowed
+    TooLarge,
+}
+
+/// Checks the chunk `start..=end` of an artifact of `total` bytes, of which
+/// `received` have arrived.
+pub fn check_chunk(start: u64, end: u64, total: u64, received: u64, limit: u64) -> ChunkCheck {
+    if total > limit {
+        ChunkCheck::TooLarge
+    } else if start > end || end >= total {
+        ChunkCheck::Invalid
+    } else if start != received {
+        ChunkCheck::Misplaced
+    } else {
+        ChunkCheck::Append {
+            complete: end + 1 == total,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_check_chunk() {
+        assert_eq!(
+            check_chunk(0, 9, 20, 0, 100),
+            ChunkCheck::Append { complete: false }
+        );
+        assert_eq!(
+            check_chunk(10, 19, 20, 10, 100),
+            ChunkCheck::Append { complete: true }
+        );
+        assert_eq!(check_chunk(5, 19, 20, 10, 100), ChunkCheck::Misplaced);
+        assert_eq!(check_chunk(10, 20, 20, 10, 100), ChunkCheck::Invalid);
+        assert_eq!(check_chunk(10, 9, 20, 10, 100), ChunkCheck::Invalid);
+        assert_eq!(check_chunk(0, 9, 200, 0, 100), ChunkCheck::TooLarge);
+    }
+
+    #[test]
+    fn test_claims() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        let uploads = PartialUploads::new(dir.path().join("uploads"), Duration::ZERO)?;
+        let hash = "0".repeat(52);
+        assert!(uploads.claim("../../etc/passwd").is_none());
+        assert_eq!(
+            uploads.staged_nar(&format!("{hash}.nar.xz")),
+            Some(dir.path().join("uploads").join(format!("{hash}.nar.xz")))
+        );
+        assert_eq!(uploads.staged_nar(&format!("{hash}.tar")), None);
+        assert_eq!(uploads.staged_nar("...nar"), None);
+
+        let claim = uploads.claim(&hash).unwrap();
+        assert!(uploads.claim(&hash).is_none());
+        assert_eq!(claim.received()?, 0);
+        claim.append()?.write_all(b"first")?;
+        claim.append()?.write_all(b"second")?;
+        assert_eq!(claim.received()?, 11);
+        // Uploads which are being received never expire
+        assert_eq!(uploads.remove_expired()?, 0);
+        drop(claim);
+
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(uploads.remove_expired()?, 1);
+        let claim = uploads.claim(&hash).unwrap();
+        assert_eq!(claim.received()?, 0);
+        claim.remove()?;
+        Ok(())
+    }
+}
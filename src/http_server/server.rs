@@ -1,26 +1,123 @@
+use crate::git_store::audit::{AuditRecord, Outcome};
+use crate::git_store::derivers;
+use crate::git_store::handshake::Handshake;
+use crate::git_store::listing::ListOptions;
 use crate::git_store::store::Store;
+use crate::git_store::upstream;
+use crate::http_server::resumable::{ChunkCheck, PartialUploads, check_chunk, is_artifact_hash};
+use crate::http_server::{access, browse, network};
+use crate::nar::budget::Reservation;
 use crate::nix_interface::cache_info;
+use crate::nix_interface::nar_info::NarInfo;
+use crate::settings;
 use actix_web::{
-    App, HttpResponse, HttpServer, Responder, get, head,
-    web::{Data, Path},
+    App, HttpRequest, HttpResponse, HttpResponseBuilder, HttpServer, Responder,
+    body::{EitherBody, MessageBody},
+    delete,
+    dev::{ServiceRequest, ServiceResponse},
+    get, head,
+    http::Method,
+    http::header::{
+        AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE, CacheControl, CacheDirective,
+        ContentRange, ContentRangeSpec, Header, HeaderValue, RANGE, RETRY_AFTER, Range,
+        WWW_AUTHENTICATE,
+    },
+    middleware::{Condition, Next, from_fn},
+    post, put,
+    web::{self, Data, Path, Payload, Query, ServiceConfig},
 };
-use tracing::error;
+use futures::StreamExt;
+use git2::Oid;
+use liblzma::read::XzDecoder;
+use serde::Deserialize;
+use serde_json::json;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, Write};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info};
 use tracing_actix_web::TracingLogger;
+use url::Url;
+
+/// Picks the mirror serving the NAR of a package, spreading the packages evenly
+/// over the mirrors.
+fn mirror_for<'a>(mirrors: &'a [Url], hash: &str) -> Option<&'a Url> {
+    if mirrors.is_empty() {
+        return None;
+    }
+    let index = hash.bytes().map(usize::from).sum::<usize>() % mirrors.len();
+    mirrors.get(index)
+}
+
+/// Points the `URL` of a narinfo at a mirror. The URL is not part of the signed
+/// fingerprint, so signatures stay valid.
+fn point_at_mirror(narinfo: &str, mirror: &Url) -> String {
+    let Some(url) = NarInfo::field(narinfo, "URL") else {
+        return narinfo.to_string();
+    };
+    let url = format!("{}/{}", mirror.as_str().trim_end_matches('/'), url);
+    NarInfo::replace_field(narinfo, "URL", &url)
+}
+
+/// Lets caches keep a response for `max_age` seconds.
+fn cache_for(max_age: u32) -> CacheControl {
+    CacheControl(vec![
+        CacheDirective::Public,
+        CacheDirective::MaxAge(max_age),
+    ])
+}
+
+/// NARs are addressed by their contents and never change, so caches can keep them
+/// without revalidating.
+fn cache_immutable(max_age: u32) -> CacheControl {
+    let CacheControl(mut directives) = cache_for(max_age);
+    directives.push(CacheDirective::Extension("immutable".to_string(), None));
+    CacheControl(directives)
+}
 
 #[get("/nix-cache-info")]
-async fn nix_cache_info() -> impl Responder {
+async fn nix_cache_info(settings: Data<settings::Server>) -> impl Responder {
     let default_cache_info = cache_info::CacheInfo::default();
-    HttpResponse::Ok().body(default_cache_info.to_string())
+    HttpResponse::Ok()
+        .insert_header(cache_for(settings.cache_control.narinfo_max_age))
+        .body(default_cache_info.to_string())
 }
 
 #[get("/{nix_hash}.narinfo")]
-async fn get_narinfo(cache: Data<Store>, path: Path<String>) -> impl Responder {
+async fn get_narinfo(
+    cache: Data<Store>,
+    settings: Data<settings::Server>,
+    path: Path<String>,
+) -> impl Responder {
     let cache = cache.into_inner();
+    let _interactive = cache.interactive();
     let hash = path.into_inner();
     let res = cache.get_narinfo(&hash);
+    let max_age = &settings.cache_control;
     match res {
-        Ok(Some(nar_info)) => HttpResponse::Ok().body(nar_info),
-        Ok(None) => HttpResponse::NotFound().body("Entry is not in the Cache"),
+        Ok(Some(nar_info)) => {
+            let mut response = HttpResponse::Ok();
+            response.insert_header(cache_for(max_age.narinfo_max_age));
+            match mirror_for(&settings.mirrors, &hash) {
+                Some(mirror) => {
+                    response.body(point_at_mirror(&String::from_utf8_lossy(&nar_info), mirror))
+                }
+                None => response.body(nar_info),
+            }
+        }
+        Ok(None) => match cache.upstream_narinfo(&hash) {
+            Ok(Some(imported)) => HttpResponse::Ok()
+                .insert_header(cache_for(max_age.narinfo_max_age))
+                .body(upstream::served_narinfo(&imported).to_string()),
+            Ok(None) => HttpResponse::NotFound()
+                .insert_header(cache_for(max_age.not_found_max_age))
+                .body("Entry is not in the Cache"),
+            Err(e) => {
+                error!("Error while reading imported NarInfo: {e}");
+                HttpResponse::InternalServerError()
+                    .body("Server error while fetching narinfo entry")
+            }
+        },
         Err(e) => {
             error!("Error while fetching NarInfo: {e}");
             HttpResponse::InternalServerError().body("Server error while fetching narinfo entry")
@@ -28,19 +125,169 @@ async fn get_narinfo(cache: Data<Store>, path: Path<String>) -> impl Responder {
     }
 }
 
-#[get("/nar/{nix_hash}.ls")]
-async fn get_listing(path: Path<String>) -> impl Responder {
+/// Lists the files of a package with their offsets in the NAR, which Nix reads to
+/// access single files of a binary cache.
+#[get("/{nix_hash}.ls")]
+async fn get_listing(
+    cache: Data<Store>,
+    settings: Data<settings::Server>,
+    path: Path<String>,
+) -> impl Responder {
+    let _interactive = cache.interactive();
     let hash = path.into_inner();
-    HttpResponse::Ok().body(hash)
+    match cache.nar_listing(&hash) {
+        Ok(Some(index)) => HttpResponse::Ok()
+            .insert_header(cache_for(settings.cache_control.narinfo_max_age))
+            .json(index.listing()),
+        Ok(None) => HttpResponse::NotFound().body("Entry is not in the Cache"),
+        Err(e) => {
+            error!("Error while listing NAR: {e}");
+            HttpResponse::InternalServerError().body("Server error while listing entry")
+        }
+    }
+}
+
+/// Serves a single range within the contents of one file from the NAR index, or
+/// `None` if the whole NAR has to be served.
+fn get_nar_range(
+    cache: &Store,
+    key: &str,
+    range: &Range,
+    cache_control: CacheControl,
+) -> anyhow::Result<Option<HttpResponse>> {
+    let Range::Bytes(specs) = range else {
+        return Ok(None);
+    };
+    let [spec] = specs.as_slice() else {
+        return Ok(None);
+    };
+    let Some(index) = cache.nar_index(key)? else {
+        return Ok(None);
+    };
+    let Some((start, end)) = spec.to_satisfiable_range(index.nar_size) else {
+        return Ok(None);
+    };
+    let Some(bytes) = cache.read_nar_range(&index, start, end)? else {
+        return Ok(None);
+    };
+    Ok(Some(
+        HttpResponse::PartialContent()
+            .insert_header(cache_control)
+            .insert_header(ContentRange(ContentRangeSpec::Bytes {
+                range: Some((start, end)),
+                instance_length: Some(index.nar_size),
+            }))
+            .body(bytes),
+    ))
 }
 
 #[get("/nar/{file_hash}.nar")]
-async fn get_nar(cache: Data<Store>, path: Path<String>) -> impl Responder {
+async fn get_nar(
+    req: HttpRequest,
+    cache: Data<Store>,
+    settings: Data<settings::Server>,
+    path: Path<String>,
+) -> impl Responder {
     let cache = cache.into_inner();
+    let interactive = cache.interactive();
     let hash = path.into_inner();
+    // Garbage collection keeps the objects of the NAR until it is sent
+    let lease = match cache.lease(&hash) {
+        Ok(Some(lease)) => lease,
+        Ok(None) => return HttpResponse::NotFound().body("Entry is not in the Cache"),
+        Err(e) => {
+            error!("Error while fetching Nar: {e}");
+            return HttpResponse::InternalServerError().body("Server error while fetching entry");
+        }
+    };
+
+    if let Ok(range) = Range::parse(&req) {
+        let cache_control = cache_immutable(settings.cache_control.nar_max_age);
+        match get_nar_range(&cache, &hash, &range, cache_control) {
+            Ok(Some(response)) => return response,
+            Ok(None) => {}
+            Err(e) => error!("Error while reading range of Nar: {e}"),
+        }
+    }
 
     match cache.get_as_nar_stream(&hash) {
-        Ok(Some(nar_stream)) => HttpResponse::Ok().streaming(nar_stream),
+        Ok(Some(nar_stream)) => HttpResponse::Ok()
+            .insert_header(cache_immutable(settings.cache_control.nar_max_age))
+            // Background work keeps yielding until the whole NAR is sent
+            .streaming(nar_stream.inspect(move |_| {
+                let _ = (&interactive, &lease);
+            })),
+        Ok(None) => HttpResponse::NotFound().body("Entry is not in the Cache"),
+        Err(e) => {
+            error!("Error while fetching Nar: {e}");
+            HttpResponse::InternalServerError().body("Server error while fetching entry")
+        }
+    }
+}
+
+/// Serves the NAR of a package whose narinfo was imported from an upstream cache.
+/// The first request fetches it, serves it, and adds the package along with its
+/// dependencies in the background, see `Store::fetch_upstream_nar`.
+#[get("/nar/upstream/{nix_hash}.nar")]
+async fn get_upstream_nar(
+    cache: Data<Store>,
+    settings: Data<settings::Server>,
+    path: Path<String>,
+) -> impl Responder {
+    let cache = cache.into_inner();
+    let interactive = cache.interactive();
+    let hash = path.into_inner();
+    let fetching = cache.clone();
+    let fetched_hash = hash.clone();
+    let fetched = web::block(move || fetching.fetch_upstream_nar(&fetched_hash)).await;
+    let key = match fetched.map_err(anyhow::Error::from).flatten() {
+        Ok(Some(fetched)) => {
+            let key = fetched.narinfo.key.clone();
+            let ingesting = cache.clone();
+            actix_web::rt::spawn(async move {
+                let ingested = web::block(move || ingesting.ingest_upstream(&fetched)).await;
+                if let Err(e) = ingested.map_err(anyhow::Error::from).flatten() {
+                    error!("Error while adding package fetched from upstream: {e}");
+                }
+            });
+            key
+        }
+        // Stored already, possibly by a concurrent request
+        Ok(None) => match cache.get_narinfo(&hash) {
+            Ok(Some(narinfo)) => match NarInfo::parse(&String::from_utf8_lossy(&narinfo)) {
+                Ok(narinfo) => narinfo.key,
+                Err(e) => {
+                    error!("Error while reading NarInfo: {e}");
+                    return HttpResponse::InternalServerError()
+                        .body("Server error while fetching entry");
+                }
+            },
+            Ok(None) => return HttpResponse::NotFound().body("Entry is not in the Cache"),
+            Err(e) => {
+                error!("Error while fetching NarInfo: {e}");
+                return HttpResponse::InternalServerError()
+                    .body("Server error while fetching entry");
+            }
+        },
+        Err(e) => {
+            error!("Error while fetching Nar from upstream: {e}");
+            return HttpResponse::BadGateway().body("Could not fetch the entry from upstream");
+        }
+    };
+    let lease = match cache.lease(&key) {
+        Ok(Some(lease)) => lease,
+        Ok(None) => return HttpResponse::NotFound().body("Entry is not in the Cache"),
+        Err(e) => {
+            error!("Error while fetching Nar: {e}");
+            return HttpResponse::InternalServerError().body("Server error while fetching entry");
+        }
+    };
+    match cache.get_as_nar_stream(&key) {
+        Ok(Some(nar_stream)) => HttpResponse::Ok()
+            .insert_header(cache_immutable(settings.cache_control.nar_max_age))
+            .streaming(nar_stream.inspect(move |_| {
+                let _ = (&interactive, &lease);
+            })),
         Ok(None) => HttpResponse::NotFound().body("Entry is not in the Cache"),
         Err(e) => {
             error!("Error while fetching Nar: {e}");
@@ -49,30 +296,1049 @@ async fn get_nar(cache: Data<Store>, path: Path<String>) -> impl Responder {
     }
 }
 
+#[get("/api/entries")]
+async fn list_entries(cache: Data<Store>, options: Query<ListOptions>) -> impl Responder {
+    let options = options.into_inner();
+    let entries = web::block(move || cache.list_entries(&options)).await;
+    match entries.map_err(anyhow::Error::from).flatten() {
+        Ok(entries) => HttpResponse::Ok().json(entries),
+        Err(e) => {
+            error!("Error while listing entries: {e}");
+            HttpResponse::InternalServerError().body("Server error while listing entries")
+        }
+    }
+}
+
+/// Landing page for people opening the cache in a browser.
+#[get("/")]
+async fn browse_index(cache: Data<Store>) -> impl Responder {
+    let stats = web::block(move || cache.stats()).await;
+    match stats.map_err(anyhow::Error::from).flatten() {
+        Ok(stats) => HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .body(browse::render_index(stats.packages)),
+        Err(e) => {
+            error!("Error while counting entries: {e}");
+            HttpResponse::InternalServerError().body("Server error while counting entries")
+        }
+    }
+}
+
+/// Lists the entries in a browser, a page at a time. Takes the same query as
+/// `/api/entries`.
+#[get("/packages")]
+async fn browse_packages(cache: Data<Store>, options: Query<ListOptions>) -> impl Responder {
+    let mut options = options.into_inner();
+    options.limit = Some(options.limit.unwrap_or(browse::PAGE_SIZE));
+    let listed = options.clone();
+    let entries = web::block(move || cache.summarize_entries(&listed)).await;
+    match entries.map_err(anyhow::Error::from).flatten() {
+        Ok(entries) => HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .body(browse::render_packages(&entries, &options)),
+        Err(e) => {
+            error!("Error while listing entries: {e}");
+            HttpResponse::InternalServerError().body("Server error while listing entries")
+        }
+    }
+}
+
+#[get("/cas/{hash}")]
+async fn get_artifact(
+    cache: Data<Store>,
+    settings: Data<settings::Server>,
+    path: Path<String>,
+) -> impl Responder {
+    match cache.get_artifact(&path.into_inner()) {
+        Ok(Some(nar_stream)) => HttpResponse::Ok()
+            .insert_header(cache_immutable(settings.cache_control.nar_max_age))
+            .streaming(nar_stream),
+        Ok(None) => HttpResponse::NotFound().body("Artifact is not in the Cache"),
+        Err(e) => {
+            error!("Error while fetching artifact: {e}");
+            HttpResponse::InternalServerError().body("Server error while fetching artifact")
+        }
+    }
+}
+
+/// Serves the build log of a derivation, linked from the package pages, as `nix
+/// log` looks it up below `log/` of a binary cache.
+#[get("/log/{drv}")]
+async fn get_build_log(cache: Data<Store>, path: Path<String>) -> impl Responder {
+    if derivers::deriver_hash(&path).is_err() {
+        return HttpResponse::BadRequest().body("Invalid derivation name");
+    }
+    match cache.get_build_log(&path) {
+        Ok(Some(log)) => HttpResponse::Ok()
+            .content_type("text/plain; charset=utf-8")
+            .body(log),
+        Ok(None) => HttpResponse::NotFound().body("Build log is not in the Cache"),
+        Err(e) => {
+            error!("Error while fetching build log: {e}");
+            HttpResponse::InternalServerError().body("Server error while fetching build log")
+        }
+    }
+}
+
+/// An upload received in memory if it fit into the memory budget, and in a
+/// temporary file otherwise.
+enum Upload {
+    InMemory(Vec<u8>, Reservation),
+    Spilled(File),
+}
+
+/// Receives the body of an upload of at most `limit` bytes, or `None` if it is
+/// larger.
+async fn receive_upload(
+    req: &HttpRequest,
+    cache: &Store,
+    limit: usize,
+    mut payload: Payload,
+) -> anyhow::Result<Option<Upload>> {
+    let length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if length.is_some_and(|length| length > limit as u64) {
+        return Ok(None);
+    }
+    // Bodies of unknown length go to a file right away
+    let reservation = length.and_then(|length| cache.memory_budget().try_reserve(length));
+    let mut upload = match reservation {
+        Some(reservation) => Upload::InMemory(Vec::new(), reservation),
+        None => Upload::Spilled(cache.spill_file()?),
+    };
+    let mut received = 0;
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        received += chunk.len();
+        if received > limit {
+            return Ok(None);
+        }
+        match &mut upload {
+            Upload::InMemory(body, _) => body.extend_from_slice(&chunk),
+            Upload::Spilled(file) => file.write_all(&chunk)?,
+        }
+    }
+    if let Upload::Spilled(_) = upload {
+        cache.memory_budget().record_spill(received as u64);
+    }
+    Ok(Some(upload))
+}
+
+/// Answers a request for an artifact which was stored, or rejected because its NAR
+/// does not match the hash.
+fn artifact_stored(stored: anyhow::Result<bool>) -> HttpResponse {
+    match stored {
+        Ok(true) => HttpResponse::Created().finish(),
+        Ok(false) => HttpResponse::BadRequest().body("The NAR does not match the hash"),
+        Err(e) => {
+            error!("Error while storing artifact: {e}");
+            HttpResponse::InternalServerError().body("Server error while storing artifact")
+        }
+    }
+}
+
+/// Tells the client of a resumable upload how many bytes were received, with a
+/// `Range` header like other resumable upload protocols do. The header is left out
+/// while nothing was received.
+fn upload_progress(mut response: HttpResponseBuilder, received: u64) -> HttpResponse {
+    if received > 0 {
+        response.insert_header((RANGE, format!("bytes=0-{}", received - 1)));
+    }
+    response.finish()
+}
+
+/// Receives one chunk of a resumable upload, sent with `Content-Range: bytes
+/// <start>-<end>/<total>`. Chunks have to continue at the bytes received so far, a
+/// `Content-Range: bytes */<total>` without a body asks how many that are. The
+/// artifact is stored once its last byte has arrived.
+async fn put_artifact_chunk(
+    req: &HttpRequest,
+    cache: Data<Store>,
+    uploads: Data<PartialUploads>,
+    limit: usize,
+    hash: String,
+    mut payload: Payload,
+) -> HttpResponse {
+    let Ok(ContentRange(ContentRangeSpec::Bytes {
+        range,
+        instance_length: Some(total),
+    })) = ContentRange::parse(req)
+    else {
+        return HttpResponse::BadRequest().body("Invalid Content-Range");
+    };
+    if !is_artifact_hash(&hash) {
+        return HttpResponse::BadRequest().body("Invalid artifact hash");
+    }
+    if let Err(e) = uploads.remove_expired() {
+        error!("Error while removing expired uploads: {e}");
+    }
+    let Some(claim) = uploads.claim(&hash) else {
+        return HttpResponse::Conflict().body("The artifact is being uploaded by another request");
+    };
+    let received = match claim.received() {
+        Ok(received) => received,
+        Err(e) => {
+            error!("Error while resuming upload: {e}");
+            return HttpResponse::InternalServerError().body("Server error while resuming upload");
+        }
+    };
+    let Some((start, end)) = range else {
+        return upload_progress(HttpResponse::Ok(), received);
+    };
+    let complete = match check_chunk(start, end, total, received, limit as u64) {
+        ChunkCheck::Append { complete } => complete,
+        ChunkCheck::Misplaced => return upload_progress(HttpResponse::Conflict(), received),
+        ChunkCheck::Invalid => {
+            return HttpResponse::RangeNotSatisfiable().body("The range is not within the NAR");
+        }
+        ChunkCheck::TooLarge => {
+            return HttpResponse::PayloadTooLarge().body("The NAR is too large");
+        }
+    };
+
+    let length = end - start + 1;
+    let written = async {
+        let mut file = claim.append()?;
+        let mut written = 0;
+        // Whatever arrived before the connection broke is kept for the next attempt
+        while let Some(Ok(chunk)) = payload.next().await {
+            if written + chunk.len() as u64 > length {
+                file.set_len(start)?;
+                return Ok(None);
+            }
+            file.write_all(&chunk)?;
+            written += chunk.len() as u64;
+        }
+        anyhow::Ok(Some(written))
+    };
+    let written = match written.await {
+        Ok(Some(written)) => written,
+        Ok(None) => return HttpResponse::BadRequest().body("The body is longer than the range"),
+        Err(e) => {
+            error!("Error while receiving chunk: {e}");
+            return HttpResponse::InternalServerError().body("Server error while receiving chunk");
+        }
+    };
+    if !complete || written < length {
+        return upload_progress(HttpResponse::Accepted(), start + written);
+    }
+
+    let stored = web::block(move || {
+        let stored = cache.add_artifact(&hash, BufReader::new(claim.open()?));
+        claim.remove()?;
+        stored
+    })
+    .await;
+    artifact_stored(stored.map_err(anyhow::Error::from).flatten())
+}
+
+/// Stores a file or directory serialised as a NAR under the nix-base32 sha256 of the
+/// NAR, which has to match the hash in the path. Uploads with a `Content-Range` are
+/// received in chunks, see `put_artifact_chunk`.
+#[put("/cas/{hash}")]
+async fn put_artifact(
+    req: HttpRequest,
+    cache: Data<Store>,
+    uploads: Data<PartialUploads>,
+    settings: Data<settings::Server>,
+    path: Path<String>,
+    payload: Payload,
+) -> impl Responder {
+    if !is_admin(&req, settings.admin_token.as_deref()) {
+        return HttpResponse::Unauthorized().body("Missing or invalid admin token");
+    }
+    let hash = path.into_inner();
+    if req.headers().contains_key(CONTENT_RANGE) {
+        let limit = settings.max_upload_size;
+        return put_artifact_chunk(&req, cache, uploads, limit, hash, payload).await;
+    }
+    let upload = match receive_upload(&req, &cache, settings.max_upload_size, payload).await {
+        Ok(Some(upload)) => upload,
+        Ok(None) => return HttpResponse::PayloadTooLarge().body("The NAR is too large"),
+        Err(e) => {
+            error!("Error while receiving artifact: {e}");
+            return HttpResponse::BadRequest().body("Could not receive the NAR");
+        }
+    };
+    let stored = web::block(move || match upload {
+        Upload::InMemory(body, _reservation) => cache.add_artifact(&hash, &body[..]),
+        Upload::Spilled(mut file) => {
+            file.rewind()?;
+            cache.add_artifact(&hash, BufReader::new(file))
+        }
+    })
+    .await;
+    artifact_stored(stored.map_err(anyhow::Error::from).flatten())
+}
+
+/// Stores the build log of a derivation, uploaded like an artifact.
+#[put("/log/{drv}")]
+async fn put_build_log(
+    req: HttpRequest,
+    cache: Data<Store>,
+    settings: Data<settings::Server>,
+    path: Path<String>,
+    payload: Payload,
+) -> impl Responder {
+    if !is_admin(&req, settings.admin_token.as_deref()) {
+        return HttpResponse::Unauthorized().body("Missing or invalid admin token");
+    }
+    if derivers::deriver_hash(&path).is_err() {
+        return HttpResponse::BadRequest().body("Invalid derivation name");
+    }
+    let upload = match receive_upload(&req, &cache, settings.max_upload_size, payload).await {
+        Ok(Some(upload)) => upload,
+        Ok(None) => return HttpResponse::PayloadTooLarge().body("The build log is too large"),
+        Err(e) => {
+            error!("Error while receiving build log: {e}");
+            return HttpResponse::BadRequest().body("Could not receive the build log");
+        }
+    };
+    let stored = web::block(move || match upload {
+        Upload::InMemory(body, _reservation) => cache.add_build_log(&path, &body),
+        Upload::Spilled(mut file) => {
+            file.rewind()?;
+            let mut log = Vec::new();
+            file.read_to_end(&mut log)?;
+            cache.add_build_log(&path, &log)
+        }
+    })
+    .await;
+    match stored.map_err(anyhow::Error::from).flatten() {
+        Ok(()) => HttpResponse::Created().finish(),
+        Err(e) => {
+            error!("Error while storing build log: {e}");
+            HttpResponse::InternalServerError().body("Server error while storing build log")
+        }
+    }
+}
+
+/// Answers which of the posted hashes are not stored, so that `gachix push` only
+/// uploads what is missing.
+#[post("/api/missing")]
+async fn missing_entries(cache: Data<Store>, hashes: web::Json<Vec<String>>) -> impl Responder {
+    let hashes = hashes.into_inner();
+    let missing = web::block(move || cache.missing(&hashes)).await;
+    match missing.map_err(anyhow::Error::from).flatten() {
+        Ok(missing) => HttpResponse::Ok().json(missing),
+        Err(e) => {
+            error!("Error while looking up entries: {e}");
+            HttpResponse::InternalServerError().body("Server error while looking up entries")
+        }
+    }
+}
+
+/// Receives a NAR pushed by `gachix push`, which is kept until the narinfo pointing
+/// at it arrives.
+#[put("/nar/{file_name}")]
+async fn put_nar(
+    req: HttpRequest,
+    uploads: Data<PartialUploads>,
+    settings: Data<settings::Server>,
+    path: Path<String>,
+    mut payload: Payload,
+) -> impl Responder {
+    if !is_admin(&req, settings.admin_token.as_deref()) {
+        return HttpResponse::Unauthorized().body("Missing or invalid admin token");
+    }
+    let Some(staged) = uploads.staged_nar(&path) else {
+        return HttpResponse::BadRequest().body("Invalid NAR file name");
+    };
+    let received = async {
+        let dir = staged.parent().unwrap_or(&staged);
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        let mut received = 0;
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk?;
+            received += chunk.len();
+            if received > settings.max_upload_size {
+                return Ok(false);
+            }
+            file.write_all(&chunk)?;
+        }
+        file.persist(&staged)?;
+        anyhow::Ok(true)
+    };
+    match received.await {
+        Ok(true) => HttpResponse::Created().finish(),
+        Ok(false) => HttpResponse::PayloadTooLarge().body("The NAR is too large"),
+        Err(e) => {
+            error!("Error while receiving NAR: {e}");
+            HttpResponse::BadRequest().body("Could not receive the NAR")
+        }
+    }
+}
+
+/// Answers a push of a package whose dependencies are not all stored with 409, as
+/// they have to be pushed first.
+fn reject_missing_dependencies(cache: &Store, narinfo: &NarInfo) -> Option<HttpResponse> {
+    let dependencies: Vec<String> = narinfo
+        .get_dependencies()
+        .iter()
+        .map(|d| d.get_base_32_hash().to_string())
+        .collect();
+    match cache.missing(&dependencies) {
+        Ok(missing) if missing.is_empty() => None,
+        Ok(missing) => Some(
+            HttpResponse::Conflict()
+                .body(format!("Dependencies are missing: {}", missing.join(" "))),
+        ),
+        Err(e) => {
+            error!("Error while looking up dependencies: {e}");
+            Some(
+                HttpResponse::InternalServerError()
+                    .body("Server error while looking up dependencies"),
+            )
+        }
+    }
+}
+
+/// Where a pushed package came from, recorded in its provenance.
+fn push_source(req: &HttpRequest) -> String {
+    format!(
+        "push from {}",
+        req.connection_info()
+            .realip_remote_addr()
+            .unwrap_or("unknown")
+    )
+}
+
+/// Who an admin request is recorded for in the audit trail and the history: the
+/// holder of the admin token, at the address of the client.
+fn admin_principal(req: &HttpRequest) -> String {
+    let address = req
+        .peer_addr()
+        .map_or_else(|| "unknown".to_string(), |peer| peer.ip().to_string());
+    format!("admin@{address}")
+}
+
+/// Adds a package pushed by `gachix push` from its narinfo and the NAR it points
+/// at, which has to be pushed first, as do the dependencies of the package.
+#[put("/{nix_hash}.narinfo")]
+async fn put_narinfo(
+    req: HttpRequest,
+    cache: Data<Store>,
+    uploads: Data<PartialUploads>,
+    settings: Data<settings::Server>,
+    path: Path<String>,
+    body: String,
+) -> impl Responder {
+    if !is_admin(&req, settings.admin_token.as_deref()) {
+        return HttpResponse::Unauthorized().body("Missing or invalid admin token");
+    }
+    let narinfo = match NarInfo::parse(&body) {
+        Ok(narinfo) if narinfo.store_path.get_base_32_hash() == path.as_str() => narinfo,
+        _ => return HttpResponse::BadRequest().body("Invalid narinfo"),
+    };
+    let xz = match narinfo.compression_type.as_deref() {
+        None | Some("none") => false,
+        Some("xz") => true,
+        Some(_) => return HttpResponse::BadRequest().body("Unsupported compression"),
+    };
+    let staged = narinfo
+        .url
+        .as_deref()
+        .and_then(|url| url.strip_prefix("nar/"))
+        .and_then(|file_name| uploads.staged_nar(file_name));
+    let Some(staged) = staged.filter(|staged| staged.exists()) else {
+        return HttpResponse::Conflict().body("The NAR of the narinfo was not pushed");
+    };
+    if let Some(response) = reject_missing_dependencies(&cache, &narinfo) {
+        return response;
+    }
+
+    let source = push_source(&req);
+    let cache = cache.acting_as(&admin_principal(&req));
+    let stored = web::block(move || {
+        let nar = BufReader::new(File::open(&staged)?);
+        let stored = if xz {
+            cache.add_pushed(&narinfo, XzDecoder::new(nar), &source)
+        } else {
+            cache.add_pushed(&narinfo, nar, &source)
+        };
+        std::fs::remove_file(&staged)?;
+        stored
+    })
+    .await;
+    artifact_stored(stored.map_err(anyhow::Error::from).flatten())
+}
+
+/// Answers which of the posted git objects are not stored, so that `gachix push`
+/// only sends the objects of a package which are new.
+#[post("/api/objects/missing")]
+async fn missing_objects(
+    req: HttpRequest,
+    cache: Data<Store>,
+    settings: Data<settings::Server>,
+    oids: web::Json<Vec<String>>,
+) -> impl Responder {
+    if !is_admin(&req, settings.admin_token.as_deref()) {
+        return HttpResponse::Unauthorized().body("Missing or invalid admin token");
+    }
+    let oids = match oids
+        .iter()
+        .map(|oid| Oid::from_str(oid))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(oids) => oids,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid oid"),
+    };
+    let missing = web::block(move || cache.missing_objects(&oids)).await;
+    match missing.map_err(anyhow::Error::from).flatten() {
+        Ok(missing) => {
+            HttpResponse::Ok().json(missing.iter().map(Oid::to_string).collect::<Vec<_>>())
+        }
+        Err(e) => {
+            error!("Error while looking up objects: {e}");
+            HttpResponse::InternalServerError().body("Server error while looking up objects")
+        }
+    }
+}
+
+/// Receives a pack of git objects pushed by `gachix push`. The objects stay
+/// unreferenced until the packages they belong to are added.
+#[put("/api/objects")]
+async fn put_objects(
+    req: HttpRequest,
+    cache: Data<Store>,
+    settings: Data<settings::Server>,
+    payload: Payload,
+) -> impl Responder {
+    if !is_admin(&req, settings.admin_token.as_deref()) {
+        return HttpResponse::Unauthorized().body("Missing or invalid admin token");
+    }
+    let upload = match receive_upload(&req, &cache, settings.max_upload_size, payload).await {
+        Ok(Some(upload)) => upload,
+        Ok(None) => return HttpResponse::PayloadTooLarge().body("The pack is too large"),
+        Err(e) => {
+            error!("Error while receiving pack: {e}");
+            return HttpResponse::BadRequest().body("Could not receive the pack");
+        }
+    };
+    let received = web::block(move || match upload {
+        Upload::InMemory(body, _reservation) => cache.receive_objects(&mut &body[..]),
+        Upload::Spilled(mut file) => {
+            file.rewind()?;
+            cache.receive_objects(&mut BufReader::new(file))
+        }
+    })
+    .await;
+    match received.map_err(anyhow::Error::from).flatten() {
+        Ok(()) => HttpResponse::Created().finish(),
+        Err(e) => {
+            error!("Error while storing pack: {e}");
+            HttpResponse::BadRequest().body("Could not store the pack")
+        }
+    }
+}
+
+/// Adds a package pushed by `gachix push` from its narinfo, whose tree was sent as
+/// git objects to `/api/objects` before.
+#[put("/api/packages/{nix_hash}")]
+async fn put_package(
+    req: HttpRequest,
+    cache: Data<Store>,
+    settings: Data<settings::Server>,
+    path: Path<String>,
+    body: String,
+) -> impl Responder {
+    if !is_admin(&req, settings.admin_token.as_deref()) {
+        return HttpResponse::Unauthorized().body("Missing or invalid admin token");
+    }
+    let narinfo = match NarInfo::parse(&body) {
+        Ok(narinfo) if narinfo.store_path.get_base_32_hash() == path.as_str() => narinfo,
+        _ => return HttpResponse::BadRequest().body("Invalid narinfo"),
+    };
+    if let Some(response) = reject_missing_dependencies(&cache, &narinfo) {
+        return response;
+    }
+    let source = push_source(&req);
+    let cache = cache.acting_as(&admin_principal(&req));
+    let stored = web::block(move || cache.add_pushed_tree(&narinfo, &source)).await;
+    artifact_stored(stored.map_err(anyhow::Error::from).flatten())
+}
+
+#[derive(Deserialize)]
+struct RefsQuery {
+    since: Option<String>,
+}
+
+#[get("/api/refs")]
+async fn advertise_refs(cache: Data<Store>, query: Query<RefsQuery>) -> impl Responder {
+    let since = match query.since.as_deref().map(Oid::from_str).transpose() {
+        Ok(since) => since,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid oid in since"),
+    };
+    match cache.advertise_refs(since) {
+        Ok(advertisement) => HttpResponse::Ok().json(advertisement),
+        Err(e) => {
+            error!("Error while advertising references: {e}");
+            HttpResponse::InternalServerError().body("Server error while advertising references")
+        }
+    }
+}
+
+/// Tells clients like `gachix push` which version of gachix serves the cache and
+/// what it supports.
+#[get("/api/handshake")]
+async fn handshake() -> impl Responder {
+    HttpResponse::Ok().json(Handshake::current())
+}
+
 #[head("/{nix_hash}.narinfo")]
-async fn nar_exists(cache: Data<Store>, path: Path<String>) -> impl Responder {
+async fn nar_exists(
+    cache: Data<Store>,
+    settings: Data<settings::Server>,
+    path: Path<String>,
+) -> impl Responder {
     let cache = cache.into_inner();
+    let _interactive = cache.interactive();
     let hash = path.into_inner();
+    let max_age = &settings.cache_control;
+
+    // Packages whose narinfo was imported from upstream are served too
+    let exists = cache
+        .entry_exists(&hash)
+        .and_then(|stored| Ok(stored || cache.upstream_narinfo(&hash)?.is_some()));
+    match exists {
+        Ok(true) => HttpResponse::Ok()
+            .insert_header(cache_for(max_age.narinfo_max_age))
+            .finish(),
+        _ => HttpResponse::NotFound()
+            .insert_header(cache_for(max_age.not_found_max_age))
+            .finish(),
+    }
+}
+
+/// Whether the `Authorization` header of a request carries the admin token. Admin
+/// requests are always refused if no token is configured.
+fn is_admin(req: &HttpRequest, admin_token: Option<&str>) -> bool {
+    let Some(admin_token) = admin_token else {
+        return false;
+    };
+    req.headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| access::secrets_match(token, admin_token))
+}
+
+/// Reports the health of every Nix daemon and git peer, as rendered by `gachix
+/// peers status`.
+#[get("/api/admin/peers")]
+async fn get_peers(
+    req: HttpRequest,
+    cache: Data<Store>,
+    settings: Data<settings::Server>,
+) -> impl Responder {
+    if !is_admin(&req, settings.admin_token.as_deref()) {
+        return HttpResponse::Unauthorized().body("Missing or invalid admin token");
+    }
+    HttpResponse::Ok().json(cache.peer_health_check().await)
+}
+
+fn maintenance_status(cache: &Store) -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "maintenance": cache.in_maintenance() }))
+}
+
+#[get("/api/admin/maintenance")]
+async fn get_maintenance(
+    req: HttpRequest,
+    cache: Data<Store>,
+    settings: Data<settings::Server>,
+) -> impl Responder {
+    if !is_admin(&req, settings.admin_token.as_deref()) {
+        return HttpResponse::Unauthorized().body("Missing or invalid admin token");
+    }
+    maintenance_status(&cache)
+}
+
+#[put("/api/admin/maintenance")]
+async fn enter_maintenance(
+    req: HttpRequest,
+    cache: Data<Store>,
+    settings: Data<settings::Server>,
+) -> impl Responder {
+    if !is_admin(&req, settings.admin_token.as_deref()) {
+        return HttpResponse::Unauthorized().body("Missing or invalid admin token");
+    }
+    cache.set_maintenance(true);
+    maintenance_status(&cache)
+}
+
+#[delete("/api/admin/maintenance")]
+async fn leave_maintenance(
+    req: HttpRequest,
+    cache: Data<Store>,
+    settings: Data<settings::Server>,
+) -> impl Responder {
+    if !is_admin(&req, settings.admin_token.as_deref()) {
+        return HttpResponse::Unauthorized().body("Missing or invalid admin token");
+    }
+    cache.set_maintenance(false);
+    maintenance_status(&cache)
+}
+
+/// Answers every request except the admin ones with 503 while in maintenance mode.
+/// Transfers which started before keep streaming until they are done.
+async fn reject_in_maintenance(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let in_maintenance = req
+        .app_data::<Data<Store>>()
+        .is_some_and(|cache| cache.in_maintenance());
+    if in_maintenance && !req.path().starts_with("/api/admin/") {
+        let retry_after = req
+            .app_data::<Data<settings::Server>>()
+            .map_or(0, |settings| settings.maintenance_retry_after);
+        let response = HttpResponse::ServiceUnavailable()
+            .insert_header((RETRY_AFTER, retry_after.to_string()))
+            .body("The cache is in maintenance, retry later");
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+/// Records the requests which may change the store in the audit trail, once the
+/// networks and read access admitted them. Requests other than `GET`, `HEAD` and
+/// the lookups posted to the API are audited if they carry the admin token. Those
+/// without are refused by the handlers and only logged, as every anonymous
+/// request would otherwise add a commit to the trail.
+async fn audit_writes(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let audited = !matches!(*req.method(), Method::GET | Method::HEAD | Method::POST);
+    let Some(cache) = req.app_data::<Data<Store>>().cloned().filter(|_| audited) else {
+        return next.call(req).await;
+    };
+    let admin = req
+        .app_data::<Data<settings::Server>>()
+        .is_some_and(|settings| is_admin(req.request(), settings.admin_token.as_deref()));
+    if !admin {
+        info!(
+            "Not auditing {} {} from {} without the admin token",
+            req.method(),
+            req.path(),
+            req.peer_addr()
+                .map_or_else(|| "unknown".to_string(), |peer| peer.ip().to_string())
+        );
+        return next.call(req).await;
+    }
+    let principal = admin_principal(req.request());
+    let action = req.method().as_str().to_lowercase();
+    let target = req.path().to_string();
+
+    let res = next.call(req).await;
+    let outcome = match &res {
+        Ok(res) if res.status().is_success() => Outcome::Succeeded,
+        Ok(res) => Outcome::Failed(res.status().to_string()),
+        Err(e) => Outcome::Failed(e.to_string()),
+    };
+    let record = AuditRecord {
+        principal,
+        action,
+        target,
+        outcome,
+    };
+    if let Err(e) = web::block(move || cache.audit(&record)).await {
+        error!("Error while auditing a request: {e}");
+    }
+    res
+}
 
-    match cache.entry_exists(&hash) {
-        Ok(true) => HttpResponse::Ok(),
-        _ => HttpResponse::NotFound(),
+/// Refuses clients outside of the networks which may read, or for requests other
+/// than `GET` and `HEAD`, write. Behind a reverse proxy the networks apply to the
+/// proxy.
+async fn restrict_networks(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let refused = req
+        .app_data::<Data<settings::Server>>()
+        .zip(req.peer_addr())
+        .and_then(|(settings, peer)| {
+            let (access, kind) = if matches!(*req.method(), Method::GET | Method::HEAD) {
+                (&settings.networks.read, "read")
+            } else {
+                (&settings.networks.write, "write")
+            };
+            (!network::admits(access, peer.ip())).then_some((peer.ip(), kind))
+        });
+    if let Some((ip, kind)) = refused {
+        let response =
+            HttpResponse::Forbidden().body(format!("{ip} may not {kind} from this cache"));
+        return Ok(req.into_response(response).map_into_right_body());
     }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+/// Asks for credentials on the paths guarded by `server.read_access`. Responses to
+/// such requests are marked as private, so that shared caches in front of the
+/// server don't hand them out to others.
+async fn require_read_access(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let Some(settings) = req.app_data::<Data<settings::Server>>().cloned() else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    };
+    // Routing decodes the path, so `/c%61s/` has to be guarded like `/cas/`
+    let Some(rule) = access::guarding(&settings.read_access, req.match_info().as_str()) else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    };
+    let authorization = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    let granted = is_admin(req.request(), settings.admin_token.as_deref())
+        || authorization.is_some_and(|authorization| access::grants(rule, authorization));
+    if !granted {
+        let mut response = HttpResponse::Unauthorized();
+        for challenge in access::challenges(rule) {
+            response.append_header((WWW_AUTHENTICATE, challenge));
+        }
+        let response = response.body("Missing or invalid credentials");
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+    let mut res = next.call(req).await?;
+    let private = res
+        .headers()
+        .get(CACHE_CONTROL)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.replace("public", "private"));
+    if let Some(private) = private.and_then(|value| HeaderValue::from_str(&value).ok()) {
+        res.headers_mut().insert(CACHE_CONTROL, private);
+    }
+    Ok(res.map_into_left_body())
+}
+
+/// The routes which write to the repository, left out for a read-only instance.
+fn write_routes(cfg: &mut ServiceConfig, uploads: &Arc<PartialUploads>) {
+    cfg.app_data(Data::from(Arc::clone(uploads)))
+        .service(get_upstream_nar)
+        .service(put_artifact)
+        .service(put_build_log)
+        .service(put_nar)
+        .service(put_narinfo)
+        .service(put_objects)
+        .service(put_package);
 }
 
 #[actix_web::main]
-pub async fn start_server(host: &str, port: u16, store: Store) -> std::io::Result<()> {
+pub async fn start_server(settings: settings::Server, store: Store) -> std::io::Result<()> {
+    let address = (settings.host.clone(), settings.port);
+    // A read-only instance neither receives uploads nor writes an audit trail
+    let writable = !store.is_read_only();
+    let uploads = if writable {
+        Some(PartialUploads::new(
+            store.uploads_dir(),
+            Duration::from_secs(settings.upload_expiry),
+        )?)
+    } else {
+        None
+    };
     HttpServer::new(move || {
         App::new()
+            .wrap(Condition::new(writable, from_fn(audit_writes)))
+            .wrap(from_fn(require_read_access))
+            .wrap(from_fn(restrict_networks))
+            .wrap(from_fn(reject_in_maintenance))
             .wrap(TracingLogger::default())
             .app_data(Data::new(store.clone()))
+            .app_data(Data::new(settings.clone()))
             .service(get_narinfo)
             .service(nix_cache_info)
             .service(nar_exists)
             .service(get_nar)
             .service(get_listing)
+            .service(list_entries)
+            .service(browse_index)
+            .service(browse_packages)
+            .service(advertise_refs)
+            .service(handshake)
+            .service(get_artifact)
+            .service(get_build_log)
+            .service(missing_entries)
+            .service(missing_objects)
+            .configure(|cfg| {
+                if let Some(uploads) = &uploads {
+                    write_routes(cfg, uploads);
+                }
+            })
+            .service(get_maintenance)
+            .service(enter_maintenance)
+            .service(leave_maintenance)
+            .service(get_peers)
     })
-    .bind((host, port))?
+    .bind(address)?
     .run()
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_point_at_mirror() -> anyhow::Result<()> {
+        let mirrors: Vec<Url> = vec![
+            "https://cdn.example.org/cache/".parse()?,
+            "https://mirror.example.org".parse()?,
+        ];
+        let hash = "iylhaki6573cpsvspivjfsim700n46r3";
+        let mirror = mirror_for(&mirrors, hash).unwrap();
+        assert_eq!(mirror_for(&mirrors, hash), Some(mirror));
+        assert_eq!(mirror_for(&[], hash), None);
+
+        let narinfo =
+            "StorePath: /nix/store/iylhaki6573cpsvspivjfsim700n46r3-kitty\nURL: nar/abc.nar\n";
+        assert_eq!(
+            point_at_mirror(narinfo, &mirrors[0]),
+            "StorePath: /nix/store/iylhaki6573cpsvspivjfsim700n46r3-kitty\nURL: https://cdn.example.org/cache/nar/abc.nar\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_admin() {
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((AUTHORIZATION, "Bearer secret"))
+            .to_http_request();
+        assert!(is_admin(&req, Some("secret")));
+        assert!(!is_admin(&req, Some("other")));
+        assert!(!is_admin(&req, None));
+
+        let req = actix_web::test::TestRequest::default().to_http_request();
+        assert!(!is_admin(&req, Some("secret")));
+    }
+
+    fn server_settings() -> settings::Server {
+        settings::Server {
+            port: 8080,
+            host: "localhost".to_string(),
+            mirrors: vec![],
+            cache_control: settings::CacheControl {
+                nar_max_age: 31536000,
+                narinfo_max_age: 300,
+                not_found_max_age: 60,
+            },
+            admin_token: Some("admin".to_string()),
+            maintenance_retry_after: 120,
+            control_socket: None,
+            pid_file: None,
+            max_upload_size: 1024,
+            upload_expiry: 60,
+            read_access: vec![],
+            networks: settings::Networks::default(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_require_read_access() {
+        use actix_web::test;
+
+        let mut settings = server_settings();
+        settings.read_access = vec![settings::ReadAccess {
+            prefix: "/nix-cache-info".to_string(),
+            users: vec!["ci:secret".to_string()],
+            tokens: vec![],
+        }];
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(require_read_access))
+                .app_data(Data::new(settings))
+                .service(nix_cache_info),
+        )
+        .await;
+        let request = |authorization: Option<&str>| {
+            let request = test::TestRequest::get().uri("/nix-cache-info");
+            match authorization {
+                Some(authorization) => request.insert_header((AUTHORIZATION, authorization)),
+                None => request,
+            }
+            .to_request()
+        };
+
+        let response = test::call_service(&app, request(None)).await;
+        assert_eq!(response.status(), 401);
+        assert_eq!(
+            response.headers().get(WWW_AUTHENTICATE).unwrap(),
+            r#"Basic realm="gachix""#
+        );
+        // `ci:secret`, as Nix sends it for a machine of its netrc file
+        let response = test::call_service(&app, request(Some("Basic Y2k6c2VjcmV0"))).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers().get(CACHE_CONTROL).unwrap(),
+            "private, max-age=300"
+        );
+        let response = test::call_service(&app, request(Some("Bearer admin"))).await;
+        assert_eq!(response.status(), 200);
+        let response = test::call_service(&app, request(Some("Bearer other"))).await;
+        assert_eq!(response.status(), 401);
+        // Percent-encoding the path does not get around the rule
+        let encoded = test::TestRequest::get()
+            .uri("/nix-cache-%69nfo")
+            .to_request();
+        let response = test::call_service(&app, encoded).await;
+        assert_eq!(response.status(), 401);
+    }
+
+    #[actix_web::test]
+    async fn test_restrict_networks() -> anyhow::Result<()> {
+        use actix_web::test;
+
+        let mut settings = server_settings();
+        settings.networks.write.allow = vec!["10.0.0.0/8".parse()?];
+        settings.networks.read.deny = vec!["192.0.2.0/24".parse()?];
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(restrict_networks))
+                .app_data(Data::new(settings))
+                .service(nix_cache_info),
+        )
+        .await;
+        let request = |method: Method, peer: &str| {
+            test::TestRequest::default()
+                .method(method)
+                .uri("/nix-cache-info")
+                .peer_addr(format!("{peer}:1234").parse().unwrap())
+                .to_request()
+        };
+
+        let response = test::call_service(&app, request(Method::GET, "203.0.113.1")).await;
+        assert_eq!(response.status(), 200);
+        let response = test::call_service(&app, request(Method::GET, "192.0.2.1")).await;
+        assert_eq!(response.status(), 403);
+        // Writes are only refused by the networks, the route itself doesn't exist
+        let response = test::call_service(&app, request(Method::PUT, "203.0.113.1")).await;
+        assert_eq!(response.status(), 403);
+        let response = test::call_service(&app, request(Method::PUT, "10.1.2.3")).await;
+        assert_eq!(response.status(), 404);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_control() {
+        assert_eq!(cache_for(300).to_string(), "public, max-age=300");
+        assert_eq!(
+            cache_immutable(31536000).to_string(),
+            "public, max-age=31536000, immutable"
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use anyhow::Result;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+
+const LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+
+type ReloadFilter = Box<dyn Fn(EnvFilter) -> Result<()> + Send + Sync>;
+
+/// Replaces the filter of the global subscriber, set by `init`.
+static RELOAD_FILTER: OnceLock<ReloadFilter> = OnceLock::new();
+
+/// Sets up the global subscriber.
+///
+/// `verbosity` is the number of `-v` flags minus the number of `-q` flags. If it is
+/// non-zero it takes precedence over `RUST_LOG`, which in turn takes precedence over
+/// the filter from the configuration file.
+pub fn init(log_level: &str, log_file: Option<&Path>, verbosity: i8) -> Result<()> {
+    let filter = if verbosity != 0 {
+        let default = LEVELS.iter().position(|l| *l == log_level).unwrap_or(2) as i8;
+        let index = (default + verbosity).clamp(0, LEVELS.len() as i8 - 1);
+        EnvFilter::new(LEVELS[index as usize])
+    } else {
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(log_level))
+    };
+
+    let writer = match log_file {
+        Some(path) => BoxMakeWriter::new(Arc::new(LogFile::open(path)?)),
+        None => BoxMakeWriter::new(io::stdout),
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_filter_reloading();
+    let builder = if log_file.is_some() {
+        builder.with_ansi(false)
+    } else {
+        builder
+    };
+    let handle = builder.reload_handle();
+    builder.init();
+    let _ = RELOAD_FILTER.set(Box::new(move |filter| Ok(handle.reload(filter)?)));
+    Ok(())
+}
+
+/// Changes the log level of the running process, e.g. after the configuration was
+/// reloaded. `RUST_LOG` and the `-v` and `-q` flags no longer apply afterwards.
+pub fn set_level(log_level: &str) -> Result<()> {
+    let filter = EnvFilter::try_new(log_level)?;
+    match RELOAD_FILTER.get() {
+        Some(reload) => reload(filter),
+        None => anyhow::bail!("Logging is not initialised"),
+    }
+}
+
+/// A log file which is reopened when it has been moved or deleted, so that it plays
+/// well with external log rotation.
+pub struct LogFile {
+    path: PathBuf,
+    file: Mutex<(File, u64)>,
+}
+
+impl LogFile {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(Self::open_file(path)?),
+        })
+    }
+
+    fn open_file(path: &Path) -> io::Result<(File, u64)> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let inode = file.metadata()?.ino();
+        Ok((file, inode))
+    }
+}
+
+impl Write for &LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        let rotated = fs::metadata(&self.path).map_or(true, |m| m.ino() != file.1);
+        if rotated {
+            *file = LogFile::open_file(&self.path)?;
+        }
+        file.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .0
+            .flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_log_file_reopens_after_rotation() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("gachix.log");
+        let log_file = LogFile::open(&path)?;
+
+        (&log_file).write_all(b"first\n")?;
+        fs::rename(&path, temp_dir.path().join("gachix.log.1"))?;
+        (&log_file).write_all(b"second\n")?;
+
+        assert_eq!(
+            fs::read_to_string(temp_dir.path().join("gachix.log.1"))?,
+            "first\n"
+        );
+        assert_eq!(fs::read_to_string(&path)?, "second\n");
+        Ok(())
+    }
+}
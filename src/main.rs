@@ -1,43 +1,199 @@
 use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use futures::StreamExt;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+mod bench;
+mod control;
+mod daemonize;
+mod discovery;
 mod git_store;
 mod http_server;
+mod logging;
 mod nar;
 mod nix_interface;
+mod push;
 
+use crate::bench::{BenchOptions, Workload};
+use crate::control::ControlSocket;
+use crate::daemonize::PidFile;
 use crate::http_server::start_server;
+use crate::nix_interface::flake;
 use crate::nix_interface::path::NixPath;
-use anyhow::Result;
-use git_store::store::Store;
+use crate::push::PushOptions;
+use anyhow::{Result, bail};
+use git_store::audit::{AuditFilter, AuditRecord, Outcome};
+use git_store::channels;
+use git_store::filter::IngestFilter;
+use git_store::history::{self, HistoryFilter, PointInTime};
+use git_store::listing::{ListOptions, SortBy};
+use git_store::manifest::Manifest;
+use git_store::sbom::SbomFormat;
+use git_store::static_site::StaticExportOptions;
+use git_store::store::{CopySummary, Store};
+use serde_json::json;
+use std::time::Duration;
 use tokio::runtime::Runtime;
-use tracing_subscriber::EnvFilter;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, warn};
+use url::Url;
 mod settings;
 
 fn main() -> Result<()> {
     let args = Args::parse();
 
-    let settings = settings::load_config(&args.config.unwrap_or("".to_string()))?;
+    let config_file = args.config.as_deref().unwrap_or("");
+    let mut settings = settings::load_config(config_file)?;
 
-    let filter =
-        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&settings.log_level));
+    let verbosity = args.verbose as i8 - args.quiet as i8;
+    logging::init(&settings.log_level, settings.log_file.as_deref(), verbosity)?;
 
-    tracing_subscriber::fmt().with_env_filter(filter).init();
+    // Talk to a running server, which holds the repository
+    match &args.cmd {
+        Command::Ctl(x) => return x.run(&settings.server),
+        Command::Status(x) => return x.run(&settings.server),
+        Command::Bench(x) => return x.run(&settings.server),
+        _ => {}
+    }
+    // Sets up a repository of its own instead of opening the configured one
+    if let Command::Clone(x) = &args.cmd {
+        return x.run(settings);
+    }
+
+    if args.discover
+        && let Err(e) = discovery::discover(&mut settings.store)
+    {
+        warn!("Could not discover peers in DNS: {e}");
+    }
+    if let Command::Serve(Serve {
+        read_only: true, ..
+    }) = args.cmd
+    {
+        settings.store.read_only = true;
+    }
 
-    let args = Args::parse();
     let cache = Store::new(settings.store)?;
+    if let Err(e) = cache.advertise_handshake() {
+        warn!("Could not advertise the handshake to peers: {e}");
+    }
 
-    match args.cmd {
-        Command::Add(x) => x.run(&cache)?,
-        Command::List(x) => x.run(&cache)?,
-        Command::Serve(x) => x.run(cache, settings.server)?,
+    let audited = args.cmd.audited();
+    let result = match args.cmd {
+        Command::Add(x) => x.run(&cache),
+        Command::Plan(x) => x.run(&cache),
+        Command::List(x) => x.run(&cache),
+        Command::Log(x) => x.run(&cache),
+        Command::Audit(x) => x.run(&cache),
+        Command::NarinfoLog(x) => x.run(&cache),
+        Command::Query(x) => x.run(&cache),
+        Command::Rm(x) => x.run(&cache),
+        Command::Undelete(x) => x.run(&cache),
+        Command::Tombstones(x) => x.run(&cache),
+        Command::Revoke(x) => x.run(&cache),
+        Command::Revocations(x) => x.run(&cache),
+        Command::Tag(x) => x.run(&cache),
+        Command::ListTags(x) => x.run(&cache),
+        Command::Resolve(x) => x.run(&cache),
+        Command::FollowDeletions(x) => x.run(&cache),
+        Command::Fsck(x) => x.run(&cache),
+        Command::Repair(x) => x.run(&cache),
+        Command::Quarantine(x) => x.run(&cache),
+        Command::Channel(x) => x.run(&cache),
+        Command::Peers(x) => x.run(&cache),
+        Command::Upstream(x) => x.run(&cache),
+        Command::VerifyReproducible(x) => x.run(&cache),
+        Command::Info(x) => x.run(&cache),
+        Command::Extract(x) => x.run(&cache),
+        Command::Sbom(x) => x.run(&cache),
+        Command::Manifest(x) => x.run(&cache),
+        Command::Copy(x) => x.run(&cache),
+        Command::Split(x) => x.run(&cache),
+        Command::Merge(x) => x.run(&cache),
+        Command::ExportIpfs(x) => x.run(&cache),
+        Command::ExportStatic(x) => x.run(&cache),
+        Command::Mirror(x) => x.run(&cache),
+        Command::Prefetch(x) => x.run(&cache),
+        Command::Build(x) => x.run(&cache),
+        Command::CheckBuild(x) => x.run(&cache),
+        Command::CiPush(x) => x.run(&cache),
+        Command::Push(x) => x.run(&cache),
+        Command::Stats(x) => x.run(&cache),
+        Command::PackRefs(x) => x.run(&cache),
+        Command::PackMetadata(x) => x.run(&cache),
+        Command::Snapshot(x) => x.run(&cache),
+        Command::RestoreSnapshot(x) => x.run(&cache),
+        Command::Bundle(x) => x.run(&cache),
+        Command::Gc(x) => x.run(&cache),
+        Command::Serve(x) => return x.run(cache, settings.server, config_file),
+        Command::Ctl(_) | Command::Status(_) | Command::Bench(_) | Command::Clone(_) => {
+            unreachable!("Handled before the store is opened")
+        }
     };
-    Ok(())
+    if let Some((action, target)) = audited {
+        cache.audit(&AuditRecord {
+            principal: history::identity().1,
+            action: action.to_string(),
+            target,
+            outcome: Outcome::of(&result),
+        });
+    }
+    result
+}
+
+/// Returns a token which is cancelled on the first Ctrl-C, so that the running
+/// operation can stop cleanly. A second Ctrl-C exits immediately. Must be called
+/// within a Tokio runtime.
+fn cancel_on_ctrl_c() -> CancellationToken {
+    let cancel = CancellationToken::new();
+    let token = cancel.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            warn!("Interrupted, stopping (press Ctrl-C again to exit immediately)");
+            token.cancel();
+        }
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+    cancel
+}
+
+/// Takes a store path, or a link to one like a profile generation or `./result`.
+fn resolve_store_path(path: &Path) -> Result<NixPath> {
+    NixPath::new(path).or_else(|e| match path.canonicalize() {
+        Ok(target) => NixPath::new(&target),
+        Err(_) => Err(e),
+    })
+}
+
+/// Prints the progress events of the store to stderr in the background. Must be
+/// called within a Tokio runtime.
+fn print_events(cache: &Store) {
+    let mut events = cache.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => eprintln!("{event}"),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
 }
 
 #[derive(Parser)]
 struct Args {
     #[clap(short, long)]
     config: Option<String>,
+    /// Increase the log verbosity, can be repeated
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Decrease the log verbosity, can be repeated
+    #[arg(short, long, action = clap::ArgAction::Count, global = true, conflicts_with = "verbose")]
+    quiet: u8,
+    /// Also use the peers and builders announced in DNS for `store.discovery_domain`
+    #[arg(long, action, global = true)]
+    discover: bool,
     #[command(subcommand)]
     cmd: Command,
 }
@@ -45,8 +201,105 @@ struct Args {
 #[derive(Subcommand)]
 enum Command {
     Add(Add),
+    Plan(Plan),
     List(List),
+    Log(Log),
+    Audit(Audit),
+    NarinfoLog(NarinfoLog),
+    Query(Query),
+    Rm(Rm),
+    Undelete(Undelete),
+    Tombstones(Tombstones),
+    Revoke(Revoke),
+    Revocations(Revocations),
+    Tag(Tag),
+    ListTags(ListTags),
+    Resolve(Resolve),
+    FollowDeletions(FollowDeletions),
+    Fsck(Fsck),
+    Repair(Repair),
+    Quarantine(Quarantine),
+    Channel(Channel),
+    Peers(Peers),
+    Upstream(Upstream),
+    VerifyReproducible(VerifyReproducible),
+    Info(Info),
+    Extract(Extract),
+    Sbom(Sbom),
+    Manifest(WriteManifest),
+    Copy(CopyPackages),
+    Split(Split),
+    Merge(Merge),
+    Clone(CloneRepo),
+    ExportIpfs(ExportIpfs),
+    ExportStatic(ExportStatic),
+    Mirror(Mirror),
+    Prefetch(Prefetch),
+    Build(Build),
+    CheckBuild(CheckBuild),
+    CiPush(CiPush),
+    Push(Push),
+    Stats(Stats),
+    PackRefs(PackRefs),
+    PackMetadata(PackMetadata),
+    Snapshot(Snapshot),
+    RestoreSnapshot(RestoreSnapshot),
+    Bundle(Bundle),
+    Gc(Gc),
     Serve(Serve),
+    Ctl(Ctl),
+    Status(Status),
+    Bench(Bench),
+}
+
+impl Command {
+    /// The action and target recorded in the audit trail, for the commands which
+    /// change the store.
+    fn audited(&self) -> Option<(&'static str, String)> {
+        match self {
+            Command::Add(x) => Some(("add", x.file_path.display().to_string())),
+            Command::Rm(x) if x.purge => Some(("purge", x.hash.clone())),
+            Command::Rm(x) => Some(("rm", x.hash.clone())),
+            Command::Undelete(x) => Some(("undelete", x.hash.clone())),
+            Command::Revoke(x) => Some(("revoke", x.hash.clone())),
+            Command::Tag(x) if x.delete => Some(("untag", x.name.clone())),
+            Command::Tag(x) => Some(("tag", x.name.clone())),
+            Command::FollowDeletions(x) if !x.dry_run => Some(("follow-deletions", String::new())),
+            Command::Repair(x) => Some(("repair", x.hash.clone().unwrap_or_default())),
+            Command::Quarantine(x) => match &x.action {
+                QuarantineAction::List => None,
+                QuarantineAction::Promote { hash } => Some(("promote", hash.clone())),
+                QuarantineAction::Drop { hash } => Some(("drop", hash.clone())),
+            },
+            Command::Channel(x) => match &x.action {
+                ChannelAction::Publish { name, .. } => Some(("publish-channel", name.clone())),
+                ChannelAction::Fetch { version } => Some(("fetch-channel", version.clone())),
+                ChannelAction::List { .. } => None,
+            },
+            Command::Upstream(x) => match &x.action {
+                UpstreamAction::Import { upstream, .. } => {
+                    Some(("import-metadata", upstream.to_string()))
+                }
+                UpstreamAction::Fetch { hash } => Some(("fetch-upstream", hash.clone())),
+                UpstreamAction::List => None,
+            },
+            Command::Split(x) => Some(("split", x.to.display().to_string())),
+            Command::Merge(x) => Some(("merge", x.other.display().to_string())),
+            Command::Mirror(x) => Some(("mirror", x.flakeref.clone())),
+            Command::Prefetch(x) => Some((
+                "prefetch",
+                x.manifest
+                    .as_ref()
+                    .or(x.from_file.as_ref())
+                    .map(|file| file.display().to_string())
+                    .unwrap_or_default(),
+            )),
+            Command::Build(x) => Some(("build", x.installable.clone())),
+            Command::RestoreSnapshot(x) => Some(("restore-snapshot", x.dir.display().to_string())),
+            Command::Gc(x) if !x.dry_run => Some(("gc", x.policy.clone().unwrap_or_default())),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Parser)]
@@ -54,15 +307,33 @@ struct Add {
     file_path: PathBuf,
     #[arg(short, long, action)]
     single: bool,
+    /// Only look at the paths which are new since this store path or profile
+    /// generation, e.g. `/nix/var/nix/profiles/system-41-link`
+    #[arg(long, conflicts_with = "single")]
+    since: Option<PathBuf>,
+    /// Print the progress of every package to stderr
+    #[arg(long, action)]
+    progress: bool,
 }
 impl Add {
     async fn run_async(&self, cache: &Store) -> Result<()> {
-        let path = NixPath::new(&self.file_path)?;
+        let path = resolve_store_path(&self.file_path)?;
+        if self.progress {
+            print_events(cache);
+        }
         cache.peer_health_check().await;
         if self.single {
             cache.add_single(&path).await?;
         } else {
-            cache.add_closure(&path).await?;
+            let cancel = cancel_on_ctrl_c();
+            let report = match &self.since {
+                Some(previous) => {
+                    let previous = resolve_store_path(previous)?;
+                    cache.add_closure_since(&path, &previous, &cancel).await?
+                }
+                None => cache.add_closure(&path, &cancel).await?,
+            };
+            print!("{report}");
         }
         Ok(())
     }
@@ -74,20 +345,1579 @@ impl Add {
 }
 
 #[derive(Parser)]
-struct List {}
+struct Plan {
+    /// Store paths to look up, without fetching them
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+}
+impl Plan {
+    fn run(&self, cache: &Store) -> Result<()> {
+        let paths = self
+            .paths
+            .iter()
+            .map(NixPath::new)
+            .collect::<Result<Vec<_>>>()?;
+        let rt = Runtime::new()?;
+        let plan = rt.block_on(cache.substitution_plan(&paths))?;
+        print!("{plan}");
+        Ok(())
+    }
+}
+
+#[derive(Parser)]
+struct List {
+    /// Number of entries to skip
+    #[arg(long, default_value_t = 0)]
+    offset: usize,
+    /// Maximum number of entries to list
+    #[arg(long)]
+    limit: Option<usize>,
+    /// Only list entries whose hash starts with this prefix
+    #[arg(long)]
+    hash: Option<String>,
+    /// Only list entries whose name contains this string
+    #[arg(long)]
+    name: Option<String>,
+    /// Only list entries built for this system, e.g. `aarch64-linux`
+    #[arg(long)]
+    system: Option<String>,
+    #[arg(long, value_enum, default_value_t = SortBy::Hash)]
+    sort: SortBy,
+    #[arg(long, action)]
+    reverse: bool,
+    /// List the entries the cache held at a commit of its history (see `git log
+    /// refs/gachix/history`), at `@<seconds since the epoch>` or at a UTC date like
+    /// `2024-05-01` or `2024-05-01T12:00:00`
+    #[arg(long)]
+    at: Option<PointInTime>,
+}
 impl List {
     fn run(&self, cache: &Store) -> Result<()> {
-        let result = cache.list_entries()?;
+        let options = ListOptions {
+            offset: self.offset,
+            limit: self.limit,
+            hash: self.hash.clone(),
+            name: self.name.clone(),
+            system: self.system.clone(),
+            sort: self.sort,
+            reverse: self.reverse,
+        };
+        let result = match &self.at {
+            Some(at) => cache.entries_at(at, &options)?,
+            None => cache.list_entries(&options)?,
+        };
         result.iter().for_each(|e| println!("{e}"));
         Ok(())
     }
 }
 
 #[derive(Parser)]
-struct Serve {}
+struct Log {
+    /// Only show changes of packages whose hash starts with this prefix
+    hash: Option<String>,
+    /// Only show changes at or after a UTC date like `2024-05-01T12:00:00` or
+    /// `@<seconds since the epoch>`
+    #[arg(long, value_parser = history::parse_time)]
+    since: Option<i64>,
+    /// Only show changes at or before a UTC date or `@<seconds since the epoch>`
+    #[arg(long, value_parser = history::parse_time)]
+    until: Option<i64>,
+    /// Maximum number of changes to show
+    #[arg(long)]
+    limit: Option<usize>,
+}
+impl Log {
+    fn run(&self, cache: &Store) -> Result<()> {
+        let filter = HistoryFilter {
+            hash: self.hash.clone(),
+            since: self.since,
+            until: self.until,
+        };
+        let entries = cache.history(&filter)?;
+        entries
+            .iter()
+            .take(self.limit.unwrap_or(usize::MAX))
+            .for_each(|e| println!("{e}"));
+        Ok(())
+    }
+}
+
+/// Shows who changed the store through the command line, the control socket or the
+/// API, and whether it succeeded
+#[derive(Parser)]
+struct Audit {
+    /// Only show operations of principals containing this string, e.g. a user or an
+    /// address
+    #[arg(long)]
+    principal: Option<String>,
+    /// Only show operations with this action, e.g. `rm` or `put`
+    #[arg(long)]
+    action: Option<String>,
+    /// Only show operations at or after a UTC date like `2024-05-01T12:00:00` or
+    /// `@<seconds since the epoch>`
+    #[arg(long, value_parser = history::parse_time)]
+    since: Option<i64>,
+    /// Only show operations at or before a UTC date or `@<seconds since the epoch>`
+    #[arg(long, value_parser = history::parse_time)]
+    until: Option<i64>,
+    /// Only show operations which failed or were refused
+    #[arg(long, action)]
+    failed: bool,
+    /// Maximum number of operations to show
+    #[arg(long)]
+    limit: Option<usize>,
+}
+impl Audit {
+    fn run(&self, cache: &Store) -> Result<()> {
+        let filter = AuditFilter {
+            principal: self.principal.clone(),
+            action: self.action.clone(),
+            since: self.since,
+            until: self.until,
+            failed: self.failed,
+        };
+        let entries = cache.audit_trail(&filter)?;
+        entries
+            .iter()
+            .take(self.limit.unwrap_or(usize::MAX))
+            .for_each(|e| println!("{e}"));
+        Ok(())
+    }
+}
+
+/// Shows the narinfos a package was served with, including the replaced ones which
+/// were not pruned yet
+#[derive(Parser)]
+struct NarinfoLog {
+    /// The nix hash of the package
+    hash: String,
+}
+impl NarinfoLog {
+    fn run(&self, cache: &Store) -> Result<()> {
+        cache
+            .narinfo_revisions(&self.hash)?
+            .iter()
+            .for_each(|r| println!("{r}"));
+        Ok(())
+    }
+}
+
+/// Tells whether the outputs of a derivation are stored, e.g. before CI rebuilds it
+#[derive(Parser)]
+struct Query {
+    /// Print the outputs of a derivation, given as a store path, file name or hash,
+    /// and fail unless all of them are stored
+    #[arg(long)]
+    deriver: String,
+}
+impl Query {
+    fn run(&self, cache: &Store) -> Result<()> {
+        let rt = Runtime::new()?;
+        let outputs = rt.block_on(cache.all_derivation_outputs(&self.deriver))?;
+        outputs.iter().for_each(|o| println!("{o}"));
+        Ok(())
+    }
+}
+
+#[derive(Parser)]
+struct Rm {
+    /// The nix hash of the package to remove
+    hash: String,
+    /// Remove the package even if other packages still reference it or it is
+    /// tagged, removing its tags as well
+    #[arg(short, long, action)]
+    force: bool,
+    /// Don't leave a tombstone, so that the objects of the package are pruned by
+    /// the next garbage collection past the retention period
+    #[arg(long, action)]
+    purge: bool,
+}
+impl Rm {
+    fn run(&self, cache: &Store) -> Result<()> {
+        if self.purge {
+            cache.purge(&self.hash, self.force)
+        } else {
+            cache.delete(&self.hash, self.force)
+        }
+    }
+}
+
+#[derive(Parser)]
+struct Undelete {
+    /// The nix hash of a removed package, its removed dependencies are restored too
+    hash: String,
+}
+impl Undelete {
+    fn run(&self, cache: &Store) -> Result<()> {
+        for hash in cache.undelete(&self.hash)? {
+            println!("Restored {hash}");
+        }
+        Ok(())
+    }
+}
+
+/// Lists the removed packages which can still be restored with `undelete`
+#[derive(Parser)]
+struct Tombstones {}
+impl Tombstones {
+    fn run(&self, cache: &Store) -> Result<()> {
+        for tombstone in cache.tombstones()? {
+            println!("{tombstone}");
+        }
+        Ok(())
+    }
+}
+
+/// Removes a package along with every package depending on it and records the
+/// revocation, which git peers following the deletions of this instance act on
+#[derive(Parser)]
+struct Revoke {
+    /// The nix hash of the package
+    hash: String,
+    /// Why the package is revoked, recorded with the revocation
+    #[arg(long)]
+    reason: String,
+}
+impl Revoke {
+    fn run(&self, cache: &Store) -> Result<()> {
+        for hash in cache.revoke(&self.hash, &self.reason)? {
+            println!("Removed {hash}");
+        }
+        Ok(())
+    }
+}
+
+/// Lists the revoked packages
+#[derive(Parser)]
+struct Revocations {}
+impl Revocations {
+    fn run(&self, cache: &Store) -> Result<()> {
+        for revocation in cache.revocations()? {
+            println!("{revocation}");
+        }
+        Ok(())
+    }
+}
+
+/// Gives a stored package a name like `release-2024.11`, which keeps it and its
+/// closure from being garbage collected
+#[derive(Parser)]
+struct Tag {
+    name: String,
+    /// The nix hash of the package
+    #[arg(required_unless_present = "delete")]
+    hash: Option<String>,
+    /// Move the tag if it names another package
+    #[arg(long, action)]
+    force: bool,
+    /// Remove the tag instead
+    #[arg(long, action, conflicts_with_all = ["hash", "force"])]
+    delete: bool,
+}
+impl Tag {
+    fn run(&self, cache: &Store) -> Result<()> {
+        match &self.hash {
+            Some(hash) => cache.tag(&self.name, hash, self.force),
+            None if cache.untag(&self.name)? => Ok(()),
+            None => bail!("No tag {}", self.name),
+        }
+    }
+}
+
+/// Lists the tags with the store paths they name
+#[derive(Parser)]
+struct ListTags {}
+impl ListTags {
+    fn run(&self, cache: &Store) -> Result<()> {
+        for tag in cache.tags()? {
+            println!("{tag}");
+        }
+        Ok(())
+    }
+}
+
+/// Prints the store path a tag names
+#[derive(Parser)]
+struct Resolve {
+    name: String,
+}
+impl Resolve {
+    fn run(&self, cache: &Store) -> Result<()> {
+        println!("{}", cache.resolve_tag(&self.name)?.store_path);
+        Ok(())
+    }
+}
+
+/// Revokes the stored packages which a git peer revoked, along with the packages
+/// depending on them. Packages the peer merely removed are kept
+#[derive(Parser)]
+struct FollowDeletions {
+    /// Only list the packages which would be removed
+    #[arg(long, action)]
+    dry_run: bool,
+}
+impl FollowDeletions {
+    fn run(&self, cache: &Store) -> Result<()> {
+        for deletion in cache.follow_deletions(self.dry_run)? {
+            println!("{deletion}");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Parser)]
+struct Fsck {
+    /// Remove entries of which only the result or only the narinfo reference exists
+    #[arg(long, action)]
+    fix_dangling: bool,
+}
+impl Fsck {
+    fn run(&self, cache: &Store) -> Result<()> {
+        let report = cache.fsck()?;
+        print!("{report}");
+        if self.fix_dangling {
+            cache.fix_dangling(&report)?;
+        } else if !report.is_clean() {
+            bail!("Repository contains inconsistent entries");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Parser)]
+struct Repair {
+    /// The nix hash of the package to repair
+    #[arg(required_unless_present = "all")]
+    hash: Option<String>,
+    /// Verify and repair every package in the cache
+    #[arg(long, action, conflicts_with = "hash")]
+    all: bool,
+}
+impl Repair {
+    async fn run_async(&self, cache: &Store) -> Result<()> {
+        let hashes = match &self.hash {
+            Some(hash) => vec![hash.clone()],
+            None => cache
+                .list_entries(&ListOptions::default())?
+                .into_iter()
+                .map(|e| e.hash)
+                .collect(),
+        };
+        let mut repaired = 0;
+        let mut failed = 0;
+        for hash in &hashes {
+            match cache.repair(hash).await {
+                Ok(true) => repaired += 1,
+                Ok(false) => {}
+                Err(e) => {
+                    failed += 1;
+                    error!("Failed to repair {hash}: {e}");
+                }
+            }
+        }
+        println!(
+            "Checked {} packages, repaired {repaired}, failed {failed}",
+            hashes.len()
+        );
+        if failed > 0 {
+            bail!("Could not repair {failed} packages");
+        }
+        Ok(())
+    }
+
+    fn run(&self, cache: &Store) -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(self.run_async(cache))
+    }
+}
+
+/// Reviews packages fetched from git peers which are held in quarantine, see
+/// store.quarantine_rejected and store.review_unsigned
+#[derive(Parser)]
+struct Quarantine {
+    #[command(subcommand)]
+    action: QuarantineAction,
+}
+impl Quarantine {
+    fn run(&self, cache: &Store) -> Result<()> {
+        match &self.action {
+            QuarantineAction::List => {
+                for package in cache.quarantined()? {
+                    println!("{package}");
+                }
+                Ok(())
+            }
+            QuarantineAction::Promote { hash } => cache.promote(hash),
+            QuarantineAction::Drop { hash } => cache.drop_quarantined(hash),
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum QuarantineAction {
+    /// Lists the held packages and why they were held
+    List,
+    /// Serves a held package whose contents match its narinfo, signed with our own
+    /// key
+    Promote {
+        /// The nix hash of the package
+        hash: String,
+    },
+    /// Discards a held package
+    Drop {
+        /// The nix hash of the package
+        hash: String,
+    },
+}
+
+/// Publishes named, versioned sets of packages, so that others can fetch a whole
+/// set like `team-tools-v42` at once
+#[derive(Parser)]
+struct Channel {
+    #[command(subcommand)]
+    action: ChannelAction,
+}
+impl Channel {
+    fn run(&self, cache: &Store) -> Result<()> {
+        match &self.action {
+            ChannelAction::Publish { name, hashes } => {
+                println!("Published {}", cache.publish_channel(name, hashes)?);
+                Ok(())
+            }
+            ChannelAction::List { name: Some(name) } => {
+                for version in cache.channel_versions(name)? {
+                    println!("{version}");
+                }
+                Ok(())
+            }
+            ChannelAction::List { name: None } => {
+                for channel in cache.channels()? {
+                    println!("{channel}");
+                }
+                Ok(())
+            }
+            ChannelAction::Fetch { version } => {
+                let (version, peer) = cache.fetch_channel(version)?;
+                let fetched = cache.fetch_channel_packages(&version, &peer)?;
+                println!(
+                    "Fetched {} of {} packages of {} from {}",
+                    fetched,
+                    version.store_paths.len(),
+                    channels::version_name(&version.name, version.version),
+                    peer
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum ChannelAction {
+    /// Publishes the next version of a channel with the given packages and their
+    /// closures
+    Publish {
+        name: String,
+        /// The nix hashes of the packages
+        #[arg(required = true)]
+        hashes: Vec<String>,
+    },
+    /// Lists the newest version of every channel, or every version of one channel
+    List { name: Option<String> },
+    /// Fetches a channel version like `team-tools-v42`, or the newest version of a
+    /// channel, along with its packages from the git peer which published it
+    Fetch { version: String },
+}
+
+/// Checks the Nix daemons and git peers
+#[derive(Parser)]
+struct Peers {
+    #[command(subcommand)]
+    action: PeersAction,
+}
+impl Peers {
+    fn run(&self, cache: &Store) -> Result<()> {
+        match &self.action {
+            PeersAction::Status { json } => {
+                let report = Runtime::new()?.block_on(cache.peer_health_check());
+                if *json {
+                    println!("{}", serde_json::to_string_pretty(&report)?);
+                } else {
+                    print!("{report}");
+                }
+                if !report.healthy() {
+                    bail!("Not every peer is reachable");
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum PeersAction {
+    /// Connects to every peer and reports whether it is reachable, how long that
+    /// took, whether it accepted the credentials and, for git peers, how many
+    /// packages they advertise
+    Status {
+        /// Print the report as JSON
+        #[arg(long, action)]
+        json: bool,
+    },
+}
+
+/// Serves packages of an upstream binary cache, whose NARs are only fetched once a
+/// client asks for them
+#[derive(Parser)]
+struct Upstream {
+    #[command(subcommand)]
+    action: UpstreamAction,
+}
+impl Upstream {
+    fn run(&self, cache: &Store) -> Result<()> {
+        match &self.action {
+            UpstreamAction::Import {
+                upstream,
+                store_paths,
+            } => {
+                let paths = store_paths
+                    .iter()
+                    .map(NixPath::new)
+                    .collect::<Result<Vec<_>>>()?;
+                print!("{}", cache.import_metadata(upstream, &paths)?);
+                Ok(())
+            }
+            UpstreamAction::List => {
+                for narinfo in cache.upstream_narinfos()? {
+                    println!("{} {}", narinfo.store_path, narinfo.nar_size);
+                }
+                Ok(())
+            }
+            UpstreamAction::Fetch { hash } => match cache.fetch_upstream(hash)? {
+                Some(narinfo) => {
+                    println!("Stored {}", narinfo.store_path);
+                    Ok(())
+                }
+                None => bail!("No narinfo of {} was imported", hash),
+            },
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum UpstreamAction {
+    /// Imports the narinfos of the closures of store paths from a binary cache,
+    /// e.g. `https://cache.nixos.org`, without their NARs
+    Import {
+        upstream: Url,
+        #[arg(required = true)]
+        store_paths: Vec<PathBuf>,
+    },
+    /// Lists the imported packages whose NARs were not fetched yet, with their NAR
+    /// sizes
+    List,
+    /// Fetches the NAR of an imported package and those of its dependencies now,
+    /// rather than on the first request
+    Fetch {
+        /// The nix hash of the package
+        hash: String,
+    },
+}
+
+#[derive(Parser)]
+struct VerifyReproducible {
+    /// The nix hash of the package to compare
+    hash: String,
+    /// URL of the gachix repository of the other instance
+    #[arg(long)]
+    against: String,
+}
+impl VerifyReproducible {
+    fn run(&self, cache: &Store) -> Result<()> {
+        let report = cache.verify_reproducible(&self.hash, &self.against)?;
+        print!("{report}");
+        if !report.is_reproducible() {
+            bail!("Package {} differs from {}", self.hash, self.against);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Parser)]
+struct Build {
+    /// A derivation path or a flake installable, e.g. `nixpkgs#hello`
+    installable: String,
+    /// Host of the configured builder to build on, defaults to the first available daemon
+    #[arg(long)]
+    builder: Option<String>,
+}
+impl Build {
+    async fn run_async(&self, cache: &Store) -> Result<()> {
+        let drv_path = if self.installable.ends_with(".drv") {
+            NixPath::new(&self.installable)?
+        } else {
+            flake::derivation_path(&self.installable)?
+        };
+        print_events(cache);
+        let outputs = cache
+            .build(&drv_path, self.builder.as_deref(), &cancel_on_ctrl_c())
+            .await?;
+        for output in outputs {
+            println!("{output}");
+        }
+        Ok(())
+    }
+
+    fn run(&self, cache: &Store) -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(self.run_async(cache))
+    }
+}
+
+/// Exits successfully only if every output of a derivation can be substituted from
+/// the cache or its git peers, so that CI can skip building it
+#[derive(Parser)]
+struct CheckBuild {
+    /// A derivation path or a flake installable, e.g. `nixpkgs#hello`
+    installable: String,
+}
+impl CheckBuild {
+    fn run(&self, cache: &Store) -> Result<()> {
+        let drv_path = if self.installable.ends_with(".drv") {
+            NixPath::new(&self.installable)?
+        } else {
+            flake::derivation_path(&self.installable)?
+        };
+        let rt = Runtime::new()?;
+        let plan = rt.block_on(cache.check_build(&drv_path))?;
+        print!("{plan}");
+        let unavailable = plan.unavailable().count();
+        if unavailable > 0 {
+            bail!(
+                "{unavailable} outputs of {} are not substitutable",
+                self.installable
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Parser)]
+struct Mirror {
+    /// The flake whose outputs are mirrored, e.g. `github:owner/repo`
+    flakeref: String,
+    /// Systems to mirror, defaults to the current system
+    #[arg(long = "system")]
+    systems: Vec<String>,
+    /// Output categories to mirror
+    #[arg(long = "category", default_values_t = flake::DEFAULT_CATEGORIES.map(String::from))]
+    categories: Vec<String>,
+}
+impl Mirror {
+    async fn run_async(&self, cache: &Store) -> Result<()> {
+        let systems = if self.systems.is_empty() {
+            vec![flake::current_system()?]
+        } else {
+            self.systems.clone()
+        };
+        let outputs = flake::enumerate(&self.flakeref, &self.categories, &systems)?;
+        if outputs.is_empty() {
+            bail!("Flake {} has no outputs to mirror", self.flakeref);
+        }
+        cache.peer_health_check().await;
+        let cancel = cancel_on_ctrl_c();
+        let mut failed = 0;
+        for output in &outputs {
+            if cancel.is_cancelled() {
+                bail!("Cancelled mirroring {}", self.flakeref);
+            }
+            let mut missing = BTreeMap::new();
+            for (name, path) in &output.outputs {
+                if !cache.entry_exists(path.get_base_32_hash())? {
+                    missing.insert(name.clone(), path.clone());
+                }
+            }
+            if missing.is_empty() {
+                continue;
+            }
+            let result = async {
+                cache.realise(&output.drv_path, &missing).await?;
+                for path in missing.values() {
+                    cache.add_closure(path, &cancel).await?;
+                }
+                anyhow::Ok(())
+            }
+            .await;
+            if let Err(e) = result {
+                failed += 1;
+                error!("Failed to mirror {}: {e}", output.attr);
+            }
+        }
+        println!(
+            "Mirrored {} of {} outputs",
+            outputs.len() - failed,
+            outputs.len()
+        );
+        if failed > 0 {
+            bail!("Could not mirror {failed} outputs");
+        }
+        Ok(())
+    }
+
+    fn run(&self, cache: &Store) -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(self.run_async(cache))
+    }
+}
+
+/// Makes sure that every store path listed in a file is in the cache, e.g. the
+/// closures of an upcoming deployment, fetching the missing closures from peers and
+/// Nix daemons in parallel
+#[derive(Parser)]
+struct Prefetch {
+    /// File with one store path per line, like the output of `nix-store -qR`, or `-`
+    /// for stdin
+    #[arg(long, required_unless_present = "manifest")]
+    from_file: Option<PathBuf>,
+    /// Manifest written by `gachix manifest`. Fails unless every package of the
+    /// manifest is stored with the same contents afterwards
+    #[arg(long, conflicts_with = "from_file")]
+    manifest: Option<PathBuf>,
+    /// Number of closures fetched at the same time
+    #[arg(long, default_value_t = 4)]
+    jobs: usize,
+}
+impl Prefetch {
+    async fn run_async(&self, cache: &Store) -> Result<()> {
+        let read = |file: &Path| -> Result<String> {
+            Ok(if file == Path::new("-") {
+                std::io::read_to_string(std::io::stdin())?
+            } else {
+                std::fs::read_to_string(file)?
+            })
+        };
+        let manifest = self
+            .manifest
+            .as_deref()
+            .map(|file| Manifest::from_json(&read(file)?))
+            .transpose()?;
+        let paths = match (&manifest, &self.from_file) {
+            (Some(manifest), _) => manifest.store_paths()?,
+            (None, Some(file)) => NixPath::parse_list(&read(file)?)?,
+            (None, None) => bail!("Either --from-file or --manifest is required"),
+        };
+        let mut missing = Vec::new();
+        for path in &paths {
+            if !cache.entry_exists(path.get_base_32_hash())? {
+                missing.push(path);
+            }
+        }
+        println!(
+            "{} of {} paths are stored, prefetching {}",
+            paths.len() - missing.len(),
+            paths.len(),
+            missing.len()
+        );
+        if !missing.is_empty() {
+            prefetch_paths(cache, missing, self.jobs).await?;
+        }
+        if let Some(manifest) = manifest {
+            let differences = cache.check_manifest(&manifest)?;
+            if !differences.is_empty() {
+                bail!(
+                    "The stored packages do not match the manifest:\n{}",
+                    differences.join("\n")
+                );
+            }
+            println!("All {} paths of the manifest are stored", paths.len());
+        }
+        Ok(())
+    }
+
+    fn run(&self, cache: &Store) -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(self.run_async(cache))
+    }
+}
+
+/// Fetches the closures of the `missing` paths from peers and Nix daemons, `jobs`
+/// of them at the same time.
+async fn prefetch_paths(cache: &Store, missing: Vec<&NixPath>, jobs: usize) -> Result<()> {
+    cache.peer_health_check().await;
+    let cancel = cancel_on_ctrl_c();
+    let mut results = futures::stream::iter(missing)
+        .map(|path| {
+            let cancel = &cancel;
+            async move { (path, cache.add_closure(path, cancel).await) }
+        })
+        .buffer_unordered(jobs.max(1));
+    let mut failed = 0;
+    while let Some((path, result)) = results.next().await {
+        match result {
+            Ok(_) => eprintln!("Prefetched {path}"),
+            Err(e) => {
+                failed += 1;
+                error!("Failed to prefetch {path}: {e}");
+            }
+        }
+    }
+    if cancel.is_cancelled() {
+        bail!("Cancelled prefetching");
+    }
+    if failed > 0 {
+        bail!("Could not prefetch {failed} paths");
+    }
+    Ok(())
+}
+
+#[derive(Parser)]
+struct CiPush {
+    /// Store paths to push, read from stdin (one per line) if none are given
+    paths: Vec<PathBuf>,
+    /// Git URL of the gachix repository to push to, or the http(s) URL of a gachix
+    /// server, which is pushed to like with `gachix push`
+    #[arg(long)]
+    to: String,
+    /// Admin token of the remote gachix, when pushing to a server
+    #[arg(long)]
+    token: Option<String>,
+    /// How often a failing path is retried
+    #[arg(long, default_value_t = 3)]
+    retries: u32,
+}
+impl CiPush {
+    /// The URL of the server to push to, if `to` is not a git URL.
+    fn server_url(&self) -> Result<Option<Url>> {
+        if !self.to.starts_with("http://") && !self.to.starts_with("https://") {
+            return Ok(None);
+        }
+        Ok(Some(Url::parse(&self.to)?))
+    }
+
+    async fn with_retries<T>(
+        &self,
+        what: &str,
+        cancel: &CancellationToken,
+        mut f: impl AsyncFnMut() -> Result<T>,
+    ) -> Result<T> {
+        let mut attempt = 0;
+        loop {
+            match f().await {
+                Ok(value) => return Ok(value),
+                Err(e) if attempt < self.retries && !cancel.is_cancelled() => {
+                    attempt += 1;
+                    warn!("{what} failed (attempt {attempt}): {e}");
+                    tokio::time::sleep(Duration::from_secs(1 << attempt.min(5))).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn push(
+        &self,
+        cache: &Store,
+        path: &Path,
+        cancel: &CancellationToken,
+    ) -> Result<CopySummary> {
+        let path = NixPath::new(path)?;
+        let hash = path.get_base_32_hash().to_string();
+        self.with_retries(&format!("Adding {path}"), cancel, async || {
+            cache.add_closure(&path, cancel).await
+        })
+        .await?;
+        let server = self.server_url()?;
+        self.with_retries(&format!("Pushing {path}"), cancel, async || match &server {
+            Some(url) => {
+                let options = PushOptions {
+                    url: url.clone(),
+                    token: self.token.clone(),
+                    jobs: 4,
+                    // Retried as a whole
+                    retries: 0,
+                };
+                let summary = tokio::task::block_in_place(|| {
+                    push::push(cache, std::slice::from_ref(&hash), &options)
+                })?;
+                Ok(CopySummary {
+                    copied: summary.pushed,
+                    present: summary.present,
+                    skipped: 0,
+                })
+            }
+            None => cache.copy_to(std::slice::from_ref(&hash), &self.to),
+        })
+        .await
+    }
+
+    async fn run_async(&self, cache: &Store) -> Result<()> {
+        let paths = if self.paths.is_empty() {
+            std::io::stdin()
+                .lines()
+                .map(|line| Ok(PathBuf::from(line?.trim())))
+                .filter(|path| !matches!(path, Ok(p) if p.as_os_str().is_empty()))
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            self.paths.clone()
+        };
+        cache.peer_health_check().await;
+        let cancel = cancel_on_ctrl_c();
+
+        let mut pushed = Vec::new();
+        let mut failed = Vec::new();
+        let (mut copied, mut present) = (0, 0);
+        for path in &paths {
+            if cancel.is_cancelled() {
+                failed.push(json!({ "path": path.display().to_string(), "error": "cancelled" }));
+                continue;
+            }
+            match self.push(cache, path, &cancel).await {
+                Ok(summary) => {
+                    copied += summary.copied;
+                    present += summary.present;
+                    pushed.push(path.display().to_string());
+                }
+                Err(e) => {
+                    error!("Failed to push {}: {e}", path.display());
+                    failed.push(
+                        json!({ "path": path.display().to_string(), "error": e.to_string() }),
+                    );
+                }
+            }
+        }
+        println!(
+            "{}",
+            json!({ "pushed": pushed, "failed": failed, "copied": copied, "present": present })
+        );
+        if !failed.is_empty() {
+            bail!("Could not push {} of {} paths", failed.len(), paths.len());
+        }
+        Ok(())
+    }
+
+    fn run(&self, cache: &Store) -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(self.run_async(cache))
+    }
+}
+
+#[derive(Parser)]
+struct Extract {
+    /// The nix hash of the package
+    hash: String,
+    /// Where to write the package, with read-only permissions and the timestamps of
+    /// the Nix store
+    dest: PathBuf,
+}
+impl Extract {
+    fn run(&self, cache: &Store) -> Result<()> {
+        cache.extract(&self.hash, &self.dest)
+    }
+}
+
+#[derive(Parser)]
+struct Info {
+    /// The nix hash of the package
+    hash: String,
+    /// Print the provenance as JSON
+    #[arg(long, action)]
+    json: bool,
+}
+impl Info {
+    fn run(&self, cache: &Store) -> Result<()> {
+        let Some(narinfo) = cache.get_narinfo(&self.hash)? else {
+            bail!("Package {} is not in the cache", self.hash);
+        };
+        let provenance = cache.provenance(&self.hash)?;
+        let ipfs = cache.ipfs_export(&self.hash)?;
+        if self.json {
+            println!(
+                "{}",
+                json!({ "hash": self.hash, "provenance": provenance, "ipfs": ipfs })
+            );
+            return Ok(());
+        }
+        print!("{}", String::from_utf8_lossy(&narinfo));
+        match provenance {
+            Some(provenance) => print!("{provenance}"),
+            None => println!("Provenance: unknown"),
+        }
+        if let Some(ipfs) = ipfs {
+            print!("{ipfs}");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Parser)]
+struct Sbom {
+    /// The nix hash of the package whose closure is described
+    hash: String,
+    #[arg(long, value_enum, default_value_t = SbomFormat::Cyclonedx)]
+    format: SbomFormat,
+}
+impl Sbom {
+    fn run(&self, cache: &Store) -> Result<()> {
+        let document = cache.sbom(&self.hash, self.format)?;
+        println!("{}", serde_json::to_string_pretty(&document)?);
+        Ok(())
+    }
+}
+
+/// Writes a JSON manifest of the exact packages in the closure of a package, which
+/// `gachix prefetch --manifest` fetches elsewhere
+#[derive(Parser)]
+struct WriteManifest {
+    /// The nix hash of the package whose closure is described
+    hash: String,
+}
+impl WriteManifest {
+    fn run(&self, cache: &Store) -> Result<()> {
+        println!("{}", cache.manifest(&self.hash)?.to_json()?);
+        Ok(())
+    }
+}
+
+#[derive(Parser)]
+struct CopyPackages {
+    /// The nix hashes of the packages whose closures are copied
+    #[arg(required = true)]
+    hashes: Vec<String>,
+    /// Path or URL of the source repository, defaults to the configured store
+    #[arg(long)]
+    from: Option<String>,
+    /// Path or URL of the destination repository
+    #[arg(long)]
+    to: String,
+}
+impl CopyPackages {
+    fn run(&self, cache: &Store) -> Result<()> {
+        let staging = tempfile::tempdir()?;
+        let source = match &self.from {
+            None => cache.clone(),
+            Some(from) if Path::new(from).is_dir() => cache.with_path(Path::new(from))?,
+            Some(from) => {
+                // Remote sources are fetched into a scratch repository first
+                let source = cache.with_path(staging.path())?;
+                for hash in &self.hashes {
+                    source.fetch_closure(hash, from)?;
+                }
+                source
+            }
+        };
+        print!("{}", source.copy_to(&self.hashes, &self.to)?);
+        Ok(())
+    }
+}
+
+/// Moves packages to another gachix repository, e.g. to keep the packages of one
+/// system apart. Dependencies which packages staying behind need are copied
+#[derive(Parser)]
+struct Split {
+    /// Path of the repository the packages are moved to, created if it does not exist
+    #[arg(long)]
+    to: PathBuf,
+    /// Only move packages whose hash starts with this prefix
+    #[arg(long)]
+    hash: Option<String>,
+    /// Only move packages whose name contains this string
+    #[arg(long)]
+    name: Option<String>,
+    /// Only move packages built for this system, e.g. `aarch64-linux`
+    #[arg(long)]
+    system: Option<String>,
+}
+impl Split {
+    fn run(&self, cache: &Store) -> Result<()> {
+        if self.hash.is_none() && self.name.is_none() && self.system.is_none() {
+            bail!("Select the packages to move with --hash, --name or --system");
+        }
+        let options = ListOptions {
+            hash: self.hash.clone(),
+            name: self.name.clone(),
+            system: self.system.clone(),
+            ..Default::default()
+        };
+        print!("{}", cache.split(&options, &self.to)?);
+        Ok(())
+    }
+}
+
+/// Sets up a new mirror of a gachix peer: initializes a repository, fetches the
+/// packages of the peer into it and writes a config serving it
+#[derive(Parser)]
+struct CloneRepo {
+    /// Git URL of the peer
+    url: Url,
+    /// Where the repository is created, must not exist or be empty
+    path: PathBuf,
+    /// Only clone packages whose name matches one of these globs, along with their
+    /// dependencies
+    #[arg(long)]
+    include: Vec<String>,
+    /// Skip packages whose name matches one of these globs, unless a cloned package
+    /// depends on them
+    #[arg(long)]
+    exclude: Vec<String>,
+    /// Only clone packages built for these systems, e.g. `x86_64-linux`
+    #[arg(long)]
+    system: Vec<String>,
+    /// Key in the `name:base64` format of Nix, one of which has to sign every cloned
+    /// package. Can be repeated, defaults to `store.trusted_public_keys`
+    #[arg(long = "trusted-public-key")]
+    trusted_public_keys: Vec<String>,
+}
+impl CloneRepo {
+    fn run(&self, settings: settings::Settings) -> Result<()> {
+        if self.path.exists() && self.path.read_dir()?.next().is_some() {
+            bail!("{} exists and is not empty", self.path.display());
+        }
+        let mut store_settings = settings.store;
+        store_settings.path = self.path.clone();
+        store_settings.remotes = vec![self.url.clone()];
+        if !self.trusted_public_keys.is_empty() {
+            store_settings.trusted_public_keys = self.trusted_public_keys.clone();
+        }
+        if store_settings.trusted_public_keys.is_empty() {
+            warn!(
+                "No trusted public keys are set, the signatures of cloned packages are not checked"
+            );
+        }
+        let cache = Store::new(store_settings.clone())?;
+        if let Err(e) = cache.advertise_handshake() {
+            warn!("Could not advertise the handshake to peers: {e}");
+        }
+        let filter = IngestFilter::new(&settings::IngestFilters {
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+            systems: self.system.clone(),
+            ..Default::default()
+        })?;
+        let result = cache.clone_from(self.url.as_str(), &filter);
+        cache.audit(&AuditRecord {
+            principal: history::identity().1,
+            action: "clone".to_string(),
+            target: self.url.to_string(),
+            outcome: Outcome::of(&result),
+        });
+        print!("{}", result?);
+
+        store_settings.path = self.path.canonicalize()?;
+        let config = self.path.join("gachix.yaml");
+        std::fs::write(&config, mirror_config(&store_settings, &settings.server))?;
+        println!(
+            "Wrote {}, serve the mirror with `gachix --config {} serve`",
+            config.display(),
+            config.display()
+        );
+        Ok(())
+    }
+}
+
+/// The config `gachix clone` writes for a new mirror. Values are written as JSON,
+/// which YAML reads as well.
+fn mirror_config(store: &settings::Store, server: &settings::Server) -> String {
+    let remotes: Vec<&str> = store.remotes.iter().map(Url::as_str).collect();
+    format!(
+        "# Written by gachix clone, see the README for the other settings\n\
+         store:\n  path: {}\n  remotes: {}\n  trusted_public_keys: {}\n\
+         server:\n  host: {}\n  port: {}\n",
+        json!(store.path),
+        json!(remotes),
+        json!(store.trusted_public_keys),
+        json!(server.host),
+        server.port
+    )
+}
+
+/// Copies every package of another gachix repository into this one
+#[derive(Parser)]
+struct Merge {
+    /// Path of the other repository, which is left as it is
+    other: PathBuf,
+}
+impl Merge {
+    fn run(&self, cache: &Store) -> Result<()> {
+        print!("{}", cache.merge(&self.other)?);
+        Ok(())
+    }
+}
+
+#[derive(Parser)]
+struct ExportIpfs {
+    /// The nix hashes of the packages to publish
+    #[arg(required = true)]
+    hashes: Vec<String>,
+    /// Also publish everything the packages reference
+    #[arg(long, action)]
+    closure: bool,
+    /// Multiaddr of the API of the IPFS node, defaults to the one of the ipfs client
+    #[arg(long)]
+    api: Option<String>,
+}
+impl ExportIpfs {
+    fn run(&self, cache: &Store) -> Result<()> {
+        let mut hashes = Vec::new();
+        for hash in &self.hashes {
+            if self.closure {
+                for narinfo in cache.closure(hash)? {
+                    hashes.push(narinfo.store_path.get_base_32_hash().to_string());
+                }
+            } else {
+                hashes.push(hash.clone());
+            }
+        }
+        let mut seen = HashSet::new();
+        hashes.retain(|hash| seen.insert(hash.clone()));
+        for hash in &hashes {
+            let export = cache.export_to_ipfs(hash, self.api.as_deref())?;
+            println!("{hash} {}", export.cid);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Parser)]
+struct ExportStatic {
+    /// Directory to write the static cache to
+    destination: PathBuf,
+    /// The nix hashes of the packages whose closures are exported, all packages if
+    /// none are given
+    hashes: Vec<String>,
+    /// Skip packages whose compressed NAR is larger than this many bytes
+    #[arg(long, default_value_t = 100 * 1024 * 1024)]
+    max_file_size: u64,
+    /// Split the export into several caches of at most this many bytes
+    #[arg(long)]
+    max_site_size: Option<u64>,
+}
+impl ExportStatic {
+    fn run(&self, cache: &Store) -> Result<()> {
+        let options = StaticExportOptions {
+            max_file_size: self.max_file_size,
+            max_site_size: self.max_site_size,
+        };
+        print!(
+            "{}",
+            cache.export_static(&self.hashes, &self.destination, &options)?
+        );
+        Ok(())
+    }
+}
+
+/// Pushes the closures of store paths to a remote gachix over HTTP, uploading
+/// only the packages it is missing. Paths which are not in the cache are added
+/// first
+#[derive(Parser)]
+struct Push {
+    /// Store paths to push
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+    /// URL of the remote gachix
+    #[arg(long)]
+    to: Url,
+    /// Admin token of the remote gachix
+    #[arg(long)]
+    token: Option<String>,
+    /// Number of packages uploaded in parallel
+    #[arg(long, default_value_t = 4)]
+    jobs: usize,
+    /// How often a failing upload is retried
+    #[arg(long, default_value_t = 3)]
+    retries: u32,
+}
+impl Push {
+    fn run(&self, cache: &Store) -> Result<()> {
+        let rt = Runtime::new()?;
+        let mut hashes = Vec::new();
+        for path in &self.paths {
+            let path = NixPath::new(path)?;
+            let hash = path.get_base_32_hash().to_string();
+            if !cache.entry_exists(&hash)? {
+                rt.block_on(async { cache.add_closure(&path, &cancel_on_ctrl_c()).await })?;
+            }
+            hashes.push(hash);
+        }
+        let summary = push::push(
+            cache,
+            &hashes,
+            &PushOptions {
+                url: self.to.clone(),
+                token: self.token.clone(),
+                jobs: self.jobs,
+                retries: self.retries,
+            },
+        )?;
+        print!("{summary}");
+        Ok(())
+    }
+}
+
+#[derive(Parser)]
+struct Stats {}
+impl Stats {
+    fn run(&self, cache: &Store) -> Result<()> {
+        print!("{}", cache.stats()?);
+        Ok(())
+    }
+}
+
+#[derive(Parser)]
+struct PackRefs {}
+impl PackRefs {
+    fn run(&self, cache: &Store) -> Result<()> {
+        cache.pack_refs()
+    }
+}
+
+/// Moves the narinfos into a single pack, which saves space and speeds up
+/// operations reading all of them, like re-signing
+#[derive(Parser)]
+struct PackMetadata {}
+impl PackMetadata {
+    fn run(&self, cache: &Store) -> Result<()> {
+        println!("Packed {} narinfos", cache.pack_metadata()?);
+        Ok(())
+    }
+}
+
+/// Writes a consistent backup of the cache, which can be taken while the server
+/// runs
+#[derive(Parser)]
+struct Snapshot {
+    /// Directory to write the snapshot to, must not exist yet
+    dir: PathBuf,
+}
+impl Snapshot {
+    fn run(&self, cache: &Store) -> Result<()> {
+        print!("{}", cache.snapshot(&self.dir)?);
+        Ok(())
+    }
+}
+
+/// Restores a snapshot written by `gachix snapshot` into an empty cache, or a bundle
+/// written by `gachix bundle diff` into a cache at the state it was made against
+#[derive(Parser)]
+struct RestoreSnapshot {
+    /// Directory of the snapshot
+    dir: PathBuf,
+}
+impl RestoreSnapshot {
+    fn run(&self, cache: &Store) -> Result<()> {
+        print!("{}", cache.restore_snapshot(&self.dir)?);
+        Ok(())
+    }
+}
+
+#[derive(Parser)]
+struct Bundle {
+    #[command(subcommand)]
+    action: BundleAction,
+}
+impl Bundle {
+    fn run(&self, cache: &Store) -> Result<()> {
+        match &self.action {
+            BundleAction::Diff { since, dir } => print!("{}", cache.bundle_diff(since, dir)?),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Subcommand)]
+enum BundleAction {
+    /// Writes the objects and references added since a commit of the history, e.g.
+    /// the one an earlier snapshot or bundle reported, for incremental backups
+    Diff {
+        /// Commit of `refs/gachix/history` the bundle is made against
+        #[arg(long)]
+        since: String,
+        /// Directory to write the bundle to, must not exist yet
+        dir: PathBuf,
+    },
+}
+
+#[derive(Parser)]
+struct Gc {
+    /// Only report which packages would be evicted, how much space that reclaims and
+    /// which roots are protected
+    #[arg(long, action)]
+    dry_run: bool,
+    /// Evict by the rules of one of `store.gc.policies` instead of `store.gc`
+    #[arg(long)]
+    policy: Option<String>,
+}
+impl Gc {
+    fn run(&self, cache: &Store) -> Result<()> {
+        let policy = cache.gc_policy(self.policy.as_deref())?;
+        if self.dry_run {
+            print!("{}", cache.simulate_garbage_collection(&policy)?);
+            return Ok(());
+        }
+        let rt = Runtime::new()?;
+        let _guard = rt.enter();
+        print!("{}", cache.collect_garbage(&policy, &cancel_on_ctrl_c())?);
+        Ok(())
+    }
+}
+
+#[derive(Parser)]
+struct Serve {
+    /// Serve the repository without ever writing to it, e.g. a replicated checkout
+    #[arg(long, action)]
+    read_only: bool,
+    /// Detach from the terminal and keep running in the background
+    #[arg(long, action)]
+    daemonize: bool,
+    /// Write the pid of the server to this file, overrides server.pid_file
+    #[arg(long)]
+    pid_file: Option<PathBuf>,
+}
 impl Serve {
-    fn run(&self, cache: Store, server_settings: settings::Server) -> Result<()> {
-        start_server(&server_settings.host, server_settings.port, cache)?;
+    fn run(
+        &self,
+        cache: Store,
+        server_settings: settings::Server,
+        config_file: &str,
+    ) -> Result<()> {
+        let pid_file = self.pid_file.as_ref().or(server_settings.pid_file.as_ref());
+        if let Some(path) = pid_file
+            && let Some(pid) = daemonize::running_pid(path)?
+        {
+            bail!("A server is already running with pid {pid}");
+        }
+        if self.daemonize {
+            daemonize::daemonize()?;
+        }
+        let _pid_file = pid_file.map(|path| PidFile::create(path)).transpose()?;
+
+        let cancel = CancellationToken::new();
+        let gc_monitor = cache.spawn_gc_monitor(cancel.clone())?;
+        let deletion_follower = cache.spawn_deletion_follower(cancel.clone())?;
+        let control_socket = match &server_settings.control_socket {
+            Some(path) => Some(ControlSocket::spawn(
+                path,
+                cache.clone(),
+                &server_settings,
+                config_file,
+                cancel.clone(),
+            )?),
+            None => None,
+        };
+        start_server(server_settings, cache)?;
+        // The server stopped, e.g. on SIGINT, so stop the background jobs as well
+        cancel.cancel();
+        if let Some(control_socket) = control_socket {
+            control_socket.stop();
+        }
+        if let Some(gc_monitor) = gc_monitor {
+            let _ = gc_monitor.join();
+        }
+        if let Some(deletion_follower) = deletion_follower {
+            let _ = deletion_follower.join();
+        }
+        Ok(())
+    }
+}
+
+/// Sends a command to a running server over its control socket: `stats`, `gc`,
+/// `pack-refs`, `add <store-path>`, `flush`, `reload` or `maintenance on|off`
+#[derive(Parser)]
+struct Ctl {
+    #[arg(required = true, num_args = 1..)]
+    command: Vec<String>,
+}
+impl Ctl {
+    fn run(&self, server_settings: &settings::Server) -> Result<()> {
+        let Some(path) = &server_settings.control_socket else {
+            bail!("No control socket is configured, see server.control_socket");
+        };
+        print!("{}", control::send(path, &self.command.join(" "))?);
+        Ok(())
+    }
+}
+
+/// Reports whether a server is running, with its uptime and listening address. The
+/// queue of running commands is only known with a control socket
+#[derive(Parser)]
+struct Status {
+    /// Pid file of the server, overrides server.pid_file
+    #[arg(long)]
+    pid_file: Option<PathBuf>,
+}
+impl Status {
+    fn run(&self, server_settings: &settings::Server) -> Result<()> {
+        let Some(path) = self.pid_file.as_ref().or(server_settings.pid_file.as_ref()) else {
+            bail!("No pid file is configured, see server.pid_file or --pid-file");
+        };
+        let Some(pid) = daemonize::running_pid(path)? else {
+            bail!("No server is running");
+        };
+        println!("Running with pid {pid}");
+        if let Some(socket) = &server_settings.control_socket {
+            match control::send(socket, "stats") {
+                Ok(stats) => {
+                    print!("{stats}");
+                    return Ok(());
+                }
+                Err(e) => warn!("Could not query the control socket: {e}"),
+            }
+        }
+        println!("Uptime: {}s", daemonize::uptime(path)?.as_secs());
+        println!(
+            "Listening: {}:{}",
+            server_settings.host, server_settings.port
+        );
+        Ok(())
+    }
+}
+
+#[derive(Parser)]
+struct Bench {
+    #[command(subcommand)]
+    target: BenchTarget,
+}
+impl Bench {
+    fn run(&self, server_settings: &settings::Server) -> Result<()> {
+        match &self.target {
+            BenchTarget::Serve(x) => x.run(server_settings),
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum BenchTarget {
+    /// Replays a synthetic workload against a running server and reports its
+    /// throughput and latency percentiles
+    Serve(BenchServe),
+}
+
+#[derive(Parser)]
+struct BenchServe {
+    /// URL of the server, defaults to the configured host and port
+    #[arg(long)]
+    url: Option<Url>,
+    #[arg(long, value_enum, default_value_t = Workload::Mixed)]
+    workload: Workload,
+    /// Number of operations to run
+    #[arg(long, default_value_t = 1000)]
+    requests: usize,
+    /// Number of operations running in parallel
+    #[arg(long, default_value_t = 8)]
+    concurrency: usize,
+}
+impl BenchServe {
+    fn run(&self, server_settings: &settings::Server) -> Result<()> {
+        let url = match &self.url {
+            Some(url) => url.clone(),
+            None => format!("http://{}:{}", server_settings.host, server_settings.port).parse()?,
+        };
+        let report = bench::run(&BenchOptions {
+            url,
+            workload: self.workload,
+            requests: self.requests,
+            concurrency: self.concurrency,
+        })?;
+        print!("{report}");
         Ok(())
     }
 }
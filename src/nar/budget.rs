@@ -0,0 +1,90 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Limits the bytes of file contents which all NAR transfers together, ingested
+/// and served, hold in memory at a time. Contents which do not fit into the budget
+/// are spilled to temporary files instead, so that a burst of large transfers
+/// slows down rather than running out of memory.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: u64,
+    used: AtomicU64,
+    spilled: AtomicU64,
+}
+
+/// Bytes of the budget held by one buffer until it is dropped.
+#[derive(Debug)]
+pub struct Reservation {
+    budget: Arc<MemoryBudget>,
+    bytes: u64,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+impl MemoryBudget {
+    pub fn new(limit: u64) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            used: AtomicU64::new(0),
+            spilled: AtomicU64::new(0),
+        })
+    }
+
+    pub fn unlimited() -> Arc<Self> {
+        Self::new(u64::MAX)
+    }
+
+    /// Reserves `bytes` if they fit into what is left of the budget.
+    pub fn try_reserve(self: &Arc<Self>, bytes: u64) -> Option<Reservation> {
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(bytes).filter(|&total| total <= self.limit)
+            })
+            .ok()?;
+        Some(Reservation {
+            budget: Arc::clone(self),
+            bytes,
+        })
+    }
+
+    /// Records that `bytes` were written to a temporary file instead of being held
+    /// in memory.
+    pub fn record_spill(&self, bytes: u64) {
+        self.spilled.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Bytes currently held in memory by transfers.
+    pub fn in_use(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Bytes spilled to temporary files since the budget was created.
+    pub fn spilled(&self) -> u64 {
+        self.spilled.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reservations() {
+        let budget = MemoryBudget::new(100);
+        let first = budget.try_reserve(60).unwrap();
+        assert!(budget.try_reserve(50).is_none());
+        let second = budget.try_reserve(40).unwrap();
+        assert_eq!(budget.in_use(), 100);
+        drop(first);
+        assert_eq!(budget.in_use(), 40);
+        assert!(budget.try_reserve(60).is_some());
+        drop(second);
+        assert_eq!(budget.in_use(), 0);
+        assert!(budget.try_reserve(101).is_none());
+        assert!(MemoryBudget::unlimited().try_reserve(u64::MAX).is_some());
+    }
+}
@@ -1,16 +1,93 @@
+use super::budget::MemoryBudget;
 use super::{NIX_VERSION_MAGIC, PAD_LEN};
 use anyhow::Result;
-use anyhow::anyhow;
-use git2::{FileMode, Oid, Repository};
-use std::io::Read;
+use anyhow::{anyhow, bail};
+use git2::{FileMode, ObjectType, Oid, Repository};
+use std::cell::Cell;
+use std::io::{self, Read};
+use std::sync::Arc;
+
+/// Bytes of decoded file contents which were new to the repository, and those
+/// which were stored already, e.g. as part of another package.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Dedup {
+    pub new_bytes: u64,
+    pub shared_bytes: u64,
+}
+
+impl Dedup {
+    pub fn add(&mut self, other: Dedup) {
+        self.new_bytes += other.new_bytes;
+        self.shared_bytes += other.shared_bytes;
+    }
+
+    pub fn shared_percent(&self) -> f64 {
+        let total = self.new_bytes + self.shared_bytes;
+        if total == 0 {
+            return 0.0;
+        }
+        self.shared_bytes as f64 * 100.0 / total as f64
+    }
+}
 
 pub struct NarGitDecoder<'a> {
     repo: &'a Repository,
+    dedup: Cell<Dedup>,
+    budget: Arc<MemoryBudget>,
 }
 
 impl<'a> NarGitDecoder<'a> {
     pub fn new(repo: &'a Repository) -> Self {
-        Self { repo }
+        Self {
+            repo,
+            dedup: Cell::new(Dedup::default()),
+            budget: MemoryBudget::unlimited(),
+        }
+    }
+
+    /// Spills file contents to temporary files in the repository instead of reading
+    /// them into memory if they do not fit into `budget`.
+    pub fn with_budget(mut self, budget: Arc<MemoryBudget>) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// How much of the contents decoded so far was already stored.
+    pub fn dedup(&self) -> Dedup {
+        self.dedup.get()
+    }
+
+    fn write_blob(&self, data: &[u8]) -> Result<Oid> {
+        let oid = Oid::hash_object(ObjectType::Blob, data)?;
+        let mut dedup = self.dedup.get();
+        if self.repo.odb()?.exists(oid) {
+            dedup.shared_bytes += data.len() as u64;
+        } else {
+            self.repo.blob(data)?;
+            dedup.new_bytes += data.len() as u64;
+        }
+        self.dedup.set(dedup);
+        Ok(oid)
+    }
+
+    /// Writes file contents of `len` bytes through a temporary file, without holding
+    /// them in memory.
+    fn spill_blob(&self, len: u64, reader: &mut impl Read) -> Result<Oid> {
+        let mut file = tempfile::NamedTempFile::new_in(self.repo.path())?;
+        if io::copy(&mut reader.by_ref().take(len), &mut file)? != len {
+            bail!("Unexpected end of file contents");
+        }
+        self.budget.record_spill(len);
+        let oid = Oid::hash_file(ObjectType::Blob, file.path())?;
+        let mut dedup = self.dedup.get();
+        if self.repo.odb()?.exists(oid) {
+            dedup.shared_bytes += len;
+        } else {
+            self.repo.blob_path(file.path())?;
+            dedup.new_bytes += len;
+        }
+        self.dedup.set(dedup);
+        Ok(oid)
     }
 
     pub fn parse(&self, mut reader: impl Read) -> Result<(Oid, i32)> {
@@ -43,14 +120,21 @@ impl<'a> NarGitDecoder<'a> {
                         ));
                     }
                 }
-                let data = self.read_bytes_padded(reader)?;
-                oid = self.repo.blob(&data)?;
+                let len = self.read_len(reader)?;
+                oid = match self.budget.try_reserve(len) {
+                    Some(_reservation) => {
+                        let data = self.read_contents(len, reader)?;
+                        self.write_blob(&data)?
+                    }
+                    None => self.spill_blob(len, reader)?,
+                };
+                self.read_padding(len, reader)?;
                 self.read_expect(b")", reader)?;
             }
             "symlink" => {
                 self.read_expect(b"target", reader)?;
                 let target = self.read_bytes_padded(reader)?;
-                oid = self.repo.blob(&target)?;
+                oid = self.write_blob(&target)?;
                 filemode = FileMode::Link;
                 self.read_expect(b")", reader)?;
             }
@@ -135,14 +219,26 @@ impl<'a> NarGitDecoder<'a> {
     }
 
     fn read_bytes_padded(&self, reader: &mut impl Read) -> Result<Vec<u8>> {
+        let len = self.read_len(reader)?;
+        let data_buffer = self.read_contents(len, reader)?;
+        self.read_padding(len, reader)?;
+        Ok(data_buffer)
+    }
+
+    fn read_len(&self, reader: &mut impl Read) -> Result<u64> {
         let mut len_buffer = [0u8; PAD_LEN];
         reader.read_exact(&mut len_buffer[..])?;
-        let len = u64::from_le_bytes(len_buffer);
+        Ok(u64::from_le_bytes(len_buffer))
+    }
 
+    fn read_contents(&self, len: u64, reader: &mut impl Read) -> Result<Vec<u8>> {
         let mut data_buffer = vec![0u8; len as usize];
         reader.read_exact(&mut data_buffer)?;
+        Ok(data_buffer)
+    }
 
-        let remainder = data_buffer.len() % PAD_LEN;
+    fn read_padding(&self, len: u64, reader: &mut impl Read) -> Result<()> {
+        let remainder = len as usize % PAD_LEN;
         if remainder > 0 {
             let mut buffer = [0u8; PAD_LEN];
             let padding = &mut buffer[0..PAD_LEN - remainder];
@@ -151,8 +247,7 @@ impl<'a> NarGitDecoder<'a> {
                 return Err(anyhow!("Bad archive padding"));
             }
         }
-
-        Ok(data_buffer)
+        Ok(())
     }
 }
 
@@ -205,6 +300,67 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_decode_counts_shared_bytes() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let base_path = temp_dir.path();
+        let dir_path = base_path.join("dir");
+        fs::create_dir(&dir_path)?;
+        fs::write(dir_path.join("a"), b"same")?;
+        fs::write(dir_path.join("b"), b"same")?;
+        fs::write(dir_path.join("c"), b"other")?;
+
+        let mut buf = Vec::new();
+        Encoder::new(&dir_path)?.read_to_end(&mut buf)?;
+        let repo = Repository::init(base_path.join("repo"))?;
+
+        let decoder = NarGitDecoder::new(&repo);
+        decoder.parse(Cursor::new(&buf))?;
+        let expected = Dedup {
+            new_bytes: 9,
+            shared_bytes: 4,
+        };
+        assert_eq!(decoder.dedup(), expected);
+
+        let decoder = NarGitDecoder::new(&repo);
+        decoder.parse(Cursor::new(&buf))?;
+        assert_eq!(decoder.dedup().new_bytes, 0);
+        assert_eq!(decoder.dedup().shared_percent(), 100.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_spills_over_budget() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let base_path = temp_dir.path();
+        let dir_path = base_path.join("dir");
+        fs::create_dir(&dir_path)?;
+        fs::write(dir_path.join("small"), b"tiny")?;
+        fs::write(dir_path.join("large"), vec![7u8; 1001])?;
+        fs::write(dir_path.join("copy"), vec![7u8; 1001])?;
+
+        let mut buf = Vec::new();
+        Encoder::new(&dir_path)?.read_to_end(&mut buf)?;
+        let in_memory = Repository::init(base_path.join("in-memory"))?;
+        let (expected, _) = NarGitDecoder::new(&in_memory).parse(Cursor::new(&buf))?;
+
+        let repo = Repository::init(base_path.join("repo"))?;
+        let budget = MemoryBudget::new(100);
+        let decoder = NarGitDecoder::new(&repo).with_budget(budget.clone());
+        let (oid, _) = decoder.parse(Cursor::new(&buf))?;
+        assert_eq!(oid, expected);
+        assert_eq!(budget.spilled(), 2002);
+        assert_eq!(budget.in_use(), 0);
+        let expected = Dedup {
+            new_bytes: 1005,
+            shared_bytes: 1001,
+        };
+        assert_eq!(decoder.dedup(), expected);
+        let large = repo.find_tree(oid)?.get_name("large").unwrap().id();
+        assert_eq!(repo.find_blob(large)?.content(), vec![7u8; 1001]);
+        Ok(())
+    }
+
     #[test]
     fn test_decode_directory() -> Result<(), Box<dyn std::error::Error>> {
         let temp_dir = TempDir::new()?;
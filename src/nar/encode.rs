@@ -4,7 +4,6 @@ use anyhow::anyhow;
 use git2::{FileMode, Object, ObjectType, Repository};
 use std::io::{self, Write};
 
-#[allow(dead_code)]
 pub struct NarGitEncoder<'a> {
     repo: &'a Repository,
     root_obj: &'a Object<'a>,
@@ -12,7 +11,6 @@ pub struct NarGitEncoder<'a> {
 }
 
 impl<'a> NarGitEncoder<'a> {
-    #[allow(dead_code)]
     pub fn new(repo: &'a Repository, root_obj: &'a Object, root_obj_filemode: i32) -> Self {
         NarGitEncoder {
             repo,
@@ -28,7 +26,6 @@ impl<'a> NarGitEncoder<'a> {
         Ok(buffer)
     }
 
-    #[allow(dead_code)]
     pub fn encode_into<W: Write>(&self, mut writer: W) -> Result<()> {
         write_padded(&mut writer, NIX_VERSION_MAGIC)?;
         self._encode_into(&mut writer, self.root_obj, self.root_obj_filemode)?;
@@ -107,3 +104,155 @@ fn write_padded<W: Write>(writer: &mut W, bytes: &[u8]) -> io::Result<()> {
 
     Ok(())
 }
+
+// Golden tests comparing the encoders with the NARs Nix itself produces. They are
+// skipped when `nix` is not installed.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git_store::repository::RepoPool;
+    use crate::nar::NarGitStream;
+    use crate::nar::decode::NarGitDecoder;
+    use futures::{StreamExt, executor::block_on};
+    use std::fs;
+    use std::os::unix::fs::{PermissionsExt, symlink};
+    use std::path::Path;
+    use std::process::Command;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    /// Serialises `path` with `nix nar dump-path`, or returns `None` without Nix.
+    fn nix_dump(path: &Path) -> Result<Option<Vec<u8>>> {
+        let output = match Command::new("nix")
+            .args(["--extra-experimental-features", "nix-command"])
+            .args(["nar", "dump-path"])
+            .arg(path)
+            .output()
+        {
+            Ok(output) => output,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if !output.status.success() {
+            return Err(anyhow!(
+                "nix nar dump-path failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+        Ok(Some(output.stdout))
+    }
+
+    fn write_executable(path: &Path, content: &[u8]) -> Result<()> {
+        fs::write(path, content)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+        Ok(())
+    }
+
+    /// Store paths with the cases the format is easy to get wrong on.
+    fn create_corpus(base: &Path) -> Result<Vec<std::path::PathBuf>> {
+        let symlink_farm = base.join("symlink-farm");
+        fs::create_dir_all(symlink_farm.join("bin"))?;
+        fs::create_dir_all(symlink_farm.join("share/doc"))?;
+        write_executable(&symlink_farm.join("bin/tool"), b"#!/bin/sh\necho tool\n")?;
+        symlink("tool", symlink_farm.join("bin/alias"))?;
+        symlink("../bin/tool", symlink_farm.join("share/doc/relative"))?;
+        symlink(
+            "/nix/store/iylhaki6573cpsvspivjfsim700n46r3-hello",
+            symlink_farm.join("absolute"),
+        )?;
+        symlink("missing", symlink_farm.join("dangling"))?;
+        symlink("share", symlink_farm.join("to-directory"))?;
+
+        let executables = base.join("executables");
+        fs::create_dir(&executables)?;
+        write_executable(&executables.join("run"), b"\x7fELF")?;
+        write_executable(&executables.join("empty-executable"), b"")?;
+        fs::write(executables.join("data"), b"not executable")?;
+
+        let empty_dirs = base.join("empty-dirs");
+        fs::create_dir_all(empty_dirs.join("nested/deeper/deepest"))?;
+        fs::create_dir(empty_dirs.join("empty"))?;
+        fs::write(empty_dirs.join("empty-file"), b"")?;
+
+        let unicode = base.join("unicode");
+        fs::create_dir(&unicode)?;
+        for name in ["héllo", "日本語", "emoji-🦀", "Zebra", "zebra", "ä"] {
+            fs::write(unicode.join(name), name.as_bytes())?;
+        }
+
+        // Git sorts directories as if their name ended with a slash, NAR does not
+        let ordering = base.join("ordering");
+        fs::create_dir_all(ordering.join("foo"))?;
+        fs::write(ordering.join("foo/inner"), b"inner")?;
+        for name in ["foo.txt", "foo-bar", "foo0", "fo"] {
+            fs::write(ordering.join(name), name.as_bytes())?;
+        }
+
+        // Contents around the padding boundary
+        let padding = base.join("padding");
+        fs::create_dir(&padding)?;
+        for len in [0, 1, 7, 8, 9, 15, 16, 17] {
+            fs::write(padding.join(format!("len-{len}")), vec![b'x'; len])?;
+        }
+
+        let single_file = base.join("single-file");
+        fs::write(&single_file, b"just a file")?;
+        let single_executable = base.join("single-executable");
+        write_executable(&single_executable, b"#!/bin/sh\n")?;
+        let single_symlink = base.join("single-symlink");
+        symlink(
+            "/nix/store/iylhaki6573cpsvspivjfsim700n46r3-hello",
+            &single_symlink,
+        )?;
+
+        Ok(vec![
+            symlink_farm,
+            executables,
+            empty_dirs,
+            unicode,
+            ordering,
+            padding,
+            single_file,
+            single_executable,
+            single_symlink,
+        ])
+    }
+
+    #[test]
+    fn test_encode_matches_nix() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let corpus = temp_dir.path().join("corpus");
+        fs::create_dir(&corpus)?;
+        let repo_path = temp_dir.path().join("repo");
+        let repo = Repository::init(&repo_path)?;
+        let pool = Arc::new(RepoPool::new(Repository::open(&repo_path)?));
+
+        for path in create_corpus(&corpus)? {
+            let Some(expected) = nix_dump(&path)? else {
+                eprintln!("Skipping NAR golden tests, nix is not installed");
+                return Ok(());
+            };
+            let name = path.file_name().unwrap().to_string_lossy();
+
+            let (oid, filemode) = NarGitDecoder::new(&repo).parse(expected.as_slice())?;
+            let object = repo.find_object(oid, None)?;
+            let encoded = NarGitEncoder::new(&repo, &object, filemode).encode()?;
+            assert!(
+                encoded == expected,
+                "Encoded NAR of {name} differs from Nix"
+            );
+
+            let mut streamed = Vec::new();
+            for chunk in
+                block_on(NarGitStream::new(pool.clone(), oid, filemode).collect::<Vec<_>>())
+            {
+                streamed.extend_from_slice(&chunk?);
+            }
+            assert!(
+                streamed == expected,
+                "Streamed NAR of {name} differs from Nix"
+            );
+        }
+        Ok(())
+    }
+}
@@ -1,11 +1,15 @@
+use super::budget::{MemoryBudget, Reservation};
 use super::{NIX_VERSION_MAGIC, PAD_LEN};
+use crate::git_store::repository::RepoPool;
 use anyhow::{Result, anyhow};
-use bytes::Bytes;
+use bytes::{BufMut, Bytes, BytesMut};
 use futures::Stream;
-use git2::{FileMode, ObjectType, Oid, Repository};
+use git2::{FileMode, ObjectType, Oid};
 use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Read, Seek, Write};
 use std::pin::Pin;
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::vec::IntoIter;
 
@@ -16,46 +20,65 @@ struct OwnedTreeEntry {
     name: Vec<u8>,
 }
 
-fn write_padded_bytes(bytes: &[u8]) -> Bytes {
-    let len = bytes.len() as u64;
-    let len_bytes = len.to_le_bytes();
-
-    let remainder = bytes.len() % PAD_LEN;
-    let padding_len = if remainder > 0 {
-        PAD_LEN - remainder
-    } else {
-        0
-    };
-
-    let total_len = 8 + bytes.len() + padding_len;
-    let mut buf = Vec::with_capacity(total_len);
+/// The zeros padding `len` bytes to a multiple of eight.
+fn padding(len: u64) -> &'static [u8] {
+    &[0u8; PAD_LEN][..(PAD_LEN - len as usize % PAD_LEN) % PAD_LEN]
+}
 
-    buf.extend_from_slice(&len_bytes);
-    buf.extend_from_slice(bytes);
-    if padding_len > 0 {
-        buf.extend_from_slice(&[0u8; PAD_LEN][..padding_len]);
-    }
+/// Appends a length-prefixed, padded string of the NAR format.
+fn put_padded(buffer: &mut BytesMut, bytes: &[u8]) {
+    buffer.put_u64_le(bytes.len() as u64);
+    buffer.put_slice(bytes);
+    buffer.put_slice(padding(bytes.len() as u64));
+}
 
-    Bytes::from(buf)
+/// Framing and small file contents are gathered into chunks of about this size, so
+/// that a NAR of many small files is written in a few large writes instead of one
+/// tiny write per token. The buffer is reused once a sent chunk is dropped.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Contents of files up to this size are copied into the chunk being gathered.
+/// Larger ones are sent as chunks of their own, copied once out of the object
+/// libgit2 inflated from the packfile, and count against the memory budget.
+const INLINE_LIMIT: usize = 16 * 1024;
+
+/// Size of the chunks in which spilled file contents are read back.
+const SPILL_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Writes file contents to an anonymous temporary file next to the repository, from
+/// which they are streamed instead of being held in memory.
+fn spill(repo: &git2::Repository, content: &[u8]) -> std::io::Result<File> {
+    let mut file = tempfile::tempfile_in(repo.path())?;
+    file.write_all(content)?;
+    file.rewind()?;
+    Ok(file)
 }
 
 enum TraversalState {
     StartNode(Oid, i32),
     ProcessTreeEntries(IntoIter<OwnedTreeEntry>),
+    /// File contents which did not fit into the memory budget, with the number of
+    /// bytes left to read and the total length
+    SpilledContents(File, u64, u64),
     FinishTreeEntry,
     FinishNode,
 }
 
 pub struct NarGitStream {
-    repo: Arc<RwLock<Repository>>,
+    repo: Arc<RepoPool>,
     stack: Vec<TraversalState>,
+    /// The chunk being gathered
+    buffer: BytesMut,
     pending_chunks: VecDeque<Result<Bytes>>,
+    budget: Arc<MemoryBudget>,
+    /// Held until the contents of the file queued last have been sent
+    reservation: Option<Reservation>,
 }
 
 impl NarGitStream {
-    pub fn new(repo: Arc<RwLock<Repository>>, root_obj: Oid, root_obj_filemode: i32) -> Self {
-        let mut pending_chunks = VecDeque::new();
-        pending_chunks.push_back(Ok(write_padded_bytes(NIX_VERSION_MAGIC)));
+    pub fn new(repo: Arc<RepoPool>, root_obj: Oid, root_obj_filemode: i32) -> Self {
+        let mut buffer = BytesMut::with_capacity(CHUNK_SIZE);
+        put_padded(&mut buffer, NIX_VERSION_MAGIC);
 
         let stack = vec![
             TraversalState::FinishNode,
@@ -65,7 +88,24 @@ impl NarGitStream {
         NarGitStream {
             repo,
             stack,
-            pending_chunks,
+            buffer,
+            pending_chunks: VecDeque::new(),
+            budget: MemoryBudget::unlimited(),
+            reservation: None,
+        }
+    }
+
+    /// Streams file contents which do not fit into `budget` from temporary files.
+    pub fn with_budget(mut self, budget: Arc<MemoryBudget>) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Queues the gathered chunk to be sent.
+    fn flush(&mut self) {
+        if !self.buffer.is_empty() {
+            let chunk = self.buffer.split().freeze();
+            self.pending_chunks.push_back(Ok(chunk));
         }
     }
 }
@@ -78,9 +118,18 @@ impl Stream for NarGitStream {
             if let Some(chunk) = self.pending_chunks.pop_front() {
                 return Poll::Ready(Some(chunk));
             }
+            self.reservation = None;
+            if self.buffer.len() >= CHUNK_SIZE {
+                self.flush();
+                continue;
+            }
 
             let Some(current_state) = self.stack.pop() else {
-                return Poll::Ready(None);
+                if self.buffer.is_empty() {
+                    return Poll::Ready(None);
+                }
+                self.flush();
+                continue;
             };
 
             match current_state {
@@ -91,107 +140,87 @@ impl Stream for NarGitStream {
                         ObjectType::Blob
                     };
 
-                    self.pending_chunks.push_back(Ok(write_padded_bytes(b"(")));
-                    self.pending_chunks
-                        .push_back(Ok(write_padded_bytes(b"type")));
-
-                    enum OwnedData {
-                        TreeEntries(IntoIter<OwnedTreeEntry>),
-                        Blob { content: Vec<u8>, executable: bool },
-                        LinkTarget(Vec<u8>),
-                    }
+                    // Borrows the fields separately, the repository is borrowed while
+                    // the contents of a blob are written into the buffer
+                    let this = &mut *self;
+                    let repo = match this.repo.get() {
+                        Ok(repo) => repo,
+                        Err(e) => return Poll::Ready(Some(Err(e.into()))),
+                    };
+                    let Ok(obj) = repo.find_object(oid, Some(kind)) else {
+                        let err = anyhow!("Could not find object with oid {}", oid);
+                        return Poll::Ready(Some(Err(err)));
+                    };
 
-                    let (node_type_str, owned_data) = {
-                        let repo = self.repo.read().unwrap();
-                        let Ok(obj) = repo.find_object(oid, Some(kind)) else {
-                            let err = anyhow!("Could not find object with oid {}", oid);
-                            return Poll::Ready(Some(Err(err)));
-                        };
-
-                        match kind {
-                            ObjectType::Tree => {
-                                let tree = obj.as_tree().unwrap();
-                                let mut entries: Vec<_> = tree
-                                    .iter()
-                                    .map(|entry| OwnedTreeEntry {
-                                        id: entry.id(),
-                                        filemode: entry.filemode(),
-                                        name: entry.name_bytes().to_vec(),
-                                    })
-                                    .collect();
-                                entries.sort_by(|x, y| x.name.cmp(&y.name));
-                                (
-                                    b"directory".as_slice(),
-                                    Some(OwnedData::TreeEntries(entries.into_iter())),
-                                )
-                            }
-                            ObjectType::Blob => {
-                                let blob = obj.as_blob().unwrap();
-                                let content = blob.content().to_vec();
-
-                                if filemode
-                                    == <FileMode as Into<i32>>::into(FileMode::BlobExecutable)
-                                {
-                                    (
-                                        b"regular".as_slice(),
-                                        Some(OwnedData::Blob {
-                                            content,
-                                            executable: true,
-                                        }),
-                                    )
-                                } else if filemode == <FileMode as Into<i32>>::into(FileMode::Blob)
-                                {
-                                    (
-                                        b"regular".as_slice(),
-                                        Some(OwnedData::Blob {
-                                            content,
-                                            executable: false,
-                                        }),
-                                    )
-                                } else if filemode == <FileMode as Into<i32>>::into(FileMode::Link)
-                                {
-                                    (b"symlink".as_slice(), Some(OwnedData::LinkTarget(content)))
+                    put_padded(&mut this.buffer, b"(");
+                    put_padded(&mut this.buffer, b"type");
+                    match kind {
+                        ObjectType::Tree => {
+                            let tree = obj.as_tree().unwrap();
+                            let mut entries: Vec<_> = tree
+                                .iter()
+                                .map(|entry| OwnedTreeEntry {
+                                    id: entry.id(),
+                                    filemode: entry.filemode(),
+                                    name: entry.name_bytes().to_vec(),
+                                })
+                                .collect();
+                            entries.sort_by(|x, y| x.name.cmp(&y.name));
+                            put_padded(&mut this.buffer, b"directory");
+                            this.stack
+                                .push(TraversalState::ProcessTreeEntries(entries.into_iter()));
+                        }
+                        ObjectType::Blob => {
+                            let blob = obj.as_blob().unwrap();
+                            let content = blob.content();
+                            let executable =
+                                filemode == <FileMode as Into<i32>>::into(FileMode::BlobExecutable);
+                            if filemode == <FileMode as Into<i32>>::into(FileMode::Link) {
+                                put_padded(&mut this.buffer, b"symlink");
+                                put_padded(&mut this.buffer, b"target");
+                                put_padded(&mut this.buffer, content);
+                            } else if executable
+                                || filemode == <FileMode as Into<i32>>::into(FileMode::Blob)
+                            {
+                                put_padded(&mut this.buffer, b"regular");
+                                if executable {
+                                    put_padded(&mut this.buffer, b"executable");
+                                    put_padded(&mut this.buffer, b"");
+                                }
+                                put_padded(&mut this.buffer, b"contents");
+                                let len = content.len() as u64;
+                                if content.len() <= INLINE_LIMIT {
+                                    put_padded(&mut this.buffer, content);
                                 } else {
-                                    let err = anyhow!("Unsupported blob filemode: {}", filemode);
-                                    return Poll::Ready(Some(Err(err)));
+                                    this.buffer.put_u64_le(len);
+                                    match this.budget.try_reserve(len) {
+                                        Some(reservation) => {
+                                            let framing = this.buffer.split().freeze();
+                                            this.pending_chunks.push_back(Ok(framing));
+                                            this.pending_chunks
+                                                .push_back(Ok(Bytes::copy_from_slice(content)));
+                                            this.reservation = Some(reservation);
+                                            this.buffer.put_slice(padding(len));
+                                        }
+                                        None => match spill(&repo, content) {
+                                            Ok(file) => {
+                                                this.budget.record_spill(len);
+                                                this.stack.push(TraversalState::SpilledContents(
+                                                    file, len, len,
+                                                ));
+                                            }
+                                            Err(e) => return Poll::Ready(Some(Err(e.into()))),
+                                        },
+                                    }
                                 }
-                            }
-                            _ => {
-                                let err = anyhow!("Unrecognized file type");
+                            } else {
+                                let err = anyhow!("Unsupported blob filemode: {}", filemode);
                                 return Poll::Ready(Some(Err(err)));
                             }
                         }
-                    };
-
-                    self.pending_chunks
-                        .push_back(Ok(write_padded_bytes(node_type_str)));
-
-                    if let Some(data) = owned_data {
-                        match data {
-                            OwnedData::TreeEntries(entries_iter) => {
-                                self.stack
-                                    .push(TraversalState::ProcessTreeEntries(entries_iter));
-                            }
-                            OwnedData::Blob {
-                                content,
-                                executable,
-                            } => {
-                                if executable {
-                                    self.pending_chunks
-                                        .push_back(Ok(write_padded_bytes(b"executable")));
-                                    self.pending_chunks.push_back(Ok(write_padded_bytes(b"")));
-                                }
-                                self.pending_chunks
-                                    .push_back(Ok(write_padded_bytes(b"contents")));
-                                self.pending_chunks
-                                    .push_back(Ok(write_padded_bytes(&content)));
-                            }
-                            OwnedData::LinkTarget(target) => {
-                                self.pending_chunks
-                                    .push_back(Ok(write_padded_bytes(b"target")));
-                                self.pending_chunks
-                                    .push_back(Ok(write_padded_bytes(&target)));
-                            }
+                        _ => {
+                            let err = anyhow!("Unrecognized file type");
+                            return Poll::Ready(Some(Err(err)));
                         }
                     }
                 }
@@ -200,31 +229,40 @@ impl Stream for NarGitStream {
                     if let Some(entry) = entries_iter.next() {
                         self.stack
                             .push(TraversalState::ProcessTreeEntries(entries_iter));
-                        let name_bytes = &entry.name;
 
                         self.stack.push(TraversalState::FinishTreeEntry);
                         self.stack.push(TraversalState::FinishNode);
                         self.stack
                             .push(TraversalState::StartNode(entry.id, entry.filemode));
 
-                        self.pending_chunks
-                            .push_back(Ok(write_padded_bytes(b"entry")));
-                        self.pending_chunks.push_back(Ok(write_padded_bytes(b"(")));
-                        self.pending_chunks
-                            .push_back(Ok(write_padded_bytes(b"name")));
-                        self.pending_chunks
-                            .push_back(Ok(write_padded_bytes(name_bytes)));
-                        self.pending_chunks
-                            .push_back(Ok(write_padded_bytes(b"node")));
+                        put_padded(&mut self.buffer, b"entry");
+                        put_padded(&mut self.buffer, b"(");
+                        put_padded(&mut self.buffer, b"name");
+                        put_padded(&mut self.buffer, &entry.name);
+                        put_padded(&mut self.buffer, b"node");
                     }
                 }
 
-                TraversalState::FinishTreeEntry => {
-                    self.pending_chunks.push_back(Ok(write_padded_bytes(b")")));
+                TraversalState::SpilledContents(mut file, remaining, len) => {
+                    // Read straight into the buffer, which is sent right after
+                    let start = self.buffer.len();
+                    let size = remaining.min(SPILL_CHUNK_SIZE as u64) as usize;
+                    self.buffer.resize(start + size, 0);
+                    if let Err(e) = file.read_exact(&mut self.buffer[start..]) {
+                        return Poll::Ready(Some(Err(e.into())));
+                    }
+                    let remaining = remaining - size as u64;
+                    if remaining > 0 {
+                        self.stack
+                            .push(TraversalState::SpilledContents(file, remaining, len));
+                    } else {
+                        self.buffer.put_slice(padding(len));
+                    }
+                    self.flush();
                 }
 
-                TraversalState::FinishNode => {
-                    self.pending_chunks.push_back(Ok(write_padded_bytes(b")")));
+                TraversalState::FinishTreeEntry | TraversalState::FinishNode => {
+                    put_padded(&mut self.buffer, b")");
                 }
             }
         }
@@ -234,12 +272,13 @@ impl Stream for NarGitStream {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::nar::encode::NarGitEncoder;
     use futures::{StreamExt, executor::block_on};
     use git2::Repository;
     use nix_nar::Encoder;
     use std::fs::File;
     use std::io::{Read, Write};
-    use std::sync::{Arc, RwLock};
+    use std::sync::Arc;
     use tempfile::TempDir;
 
     #[test]
@@ -258,7 +297,7 @@ mod tests {
         let mut encoder = Encoder::new(&file_name)?;
         encoder.read_to_end(&mut expected_nar)?;
 
-        let repo = Arc::new(RwLock::new(repo));
+        let repo = Arc::new(RepoPool::new(repo));
         let nar_stream = NarGitStream::new(repo, oid, FileMode::Blob.into());
         let results: Vec<Result<Bytes>> = block_on(nar_stream.collect());
         let mut actual_nar = Vec::new();
@@ -273,4 +312,64 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_encode_coalesces_chunks() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = Repository::init(temp_dir.path().join("repo"))?;
+        let large: Vec<u8> = (0..INLINE_LIMIT * 4 + 5).map(|i| i as u8).collect();
+        let mut builder = repo.treebuilder(None)?;
+        for i in 0..300 {
+            let content = format!("file {i}");
+            builder.insert(format!("{i:03}"), repo.blob(content.as_bytes())?, 0o100644)?;
+        }
+        builder.insert("large", repo.blob(&large)?, FileMode::Blob.into())?;
+        let root = builder.write()?;
+        drop(builder);
+
+        let expected = {
+            let obj = repo.find_object(root, None)?;
+            NarGitEncoder::new(&repo, &obj, FileMode::Tree.into()).encode()?
+        };
+        let pool = Arc::new(RepoPool::new(repo));
+        let stream = NarGitStream::new(pool, root, FileMode::Tree.into());
+        let chunks = block_on(stream.collect::<Vec<_>>())
+            .into_iter()
+            .collect::<Result<Vec<Bytes>>>()?;
+        assert_eq!(chunks.concat(), expected);
+        // The framing of the small files is gathered, the large file is sent as is
+        assert!(chunks.len() <= 4, "{} chunks", chunks.len());
+        assert!(chunks.iter().any(|chunk| chunk[..] == large[..]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_spills_over_budget() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = Repository::init(temp_dir.path().join("repo"))?;
+        let large: Vec<u8> = (0..SPILL_CHUNK_SIZE * 2 + 3).map(|i| i as u8).collect();
+        let mut builder = repo.treebuilder(None)?;
+        builder.insert("large", repo.blob(&large)?, FileMode::BlobExecutable.into())?;
+        builder.insert("small", repo.blob(b"small")?, FileMode::Blob.into())?;
+        builder.insert("link", repo.blob(b"large")?, FileMode::Link.into())?;
+        let root = builder.write()?;
+        drop(builder);
+
+        let pool = Arc::new(RepoPool::new(repo));
+        let collect = |stream: NarGitStream| -> Result<Vec<u8>> {
+            let mut nar = Vec::new();
+            for chunk in block_on(stream.collect::<Vec<_>>()) {
+                nar.extend_from_slice(&chunk?);
+            }
+            Ok(nar)
+        };
+        let expected = collect(NarGitStream::new(pool.clone(), root, FileMode::Tree.into()))?;
+        let budget = MemoryBudget::new(100);
+        let stream =
+            NarGitStream::new(pool, root, FileMode::Tree.into()).with_budget(budget.clone());
+        assert_eq!(collect(stream)?, expected);
+        assert_eq!(budget.spilled(), large.len() as u64);
+        assert_eq!(budget.in_use(), 0);
+        Ok(())
+    }
 }
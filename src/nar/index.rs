@@ -0,0 +1,215 @@
+use super::{NIX_VERSION_MAGIC, PAD_LEN};
+use anyhow::{Result, anyhow, bail};
+use git2::{FileMode, ObjectType, Oid, Repository};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// A node of a NAR listing, in the format Nix serves as `<hash>.ls` from binary
+/// caches.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Node {
+    Directory {
+        entries: BTreeMap<String, Node>,
+    },
+    Regular {
+        size: u64,
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        executable: bool,
+        /// Where the contents start in the NAR
+        #[serde(rename = "narOffset")]
+        nar_offset: u64,
+    },
+    Symlink {
+        target: String,
+    },
+}
+
+/// The contents of a file and where they are in the NAR.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct IndexedFile {
+    nar_offset: u64,
+    size: u64,
+    blob: Oid,
+}
+
+/// Maps byte offsets of the NAR serialisation of an entry to the blobs holding the
+/// file contents, so that parts of a NAR can be read without encoding it from the
+/// start. Only the headers of blobs are read to build it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NarIndex {
+    pub root: Node,
+    pub nar_size: u64,
+    /// Ordered by offset
+    files: Vec<IndexedFile>,
+}
+
+/// Size of a string or file contents in a NAR, with its length and padding.
+fn padded(len: u64) -> u64 {
+    8 + len.div_ceil(PAD_LEN as u64) * PAD_LEN as u64
+}
+
+fn padded_str(s: &[u8]) -> u64 {
+    padded(s.len() as u64)
+}
+
+impl NarIndex {
+    pub fn new(repo: &Repository, oid: Oid, filemode: i32) -> Result<Self> {
+        let mut builder = Builder {
+            repo,
+            offset: padded_str(NIX_VERSION_MAGIC),
+            files: Vec::new(),
+        };
+        let root = builder.node(oid, filemode)?;
+        Ok(Self {
+            root,
+            nar_size: builder.offset,
+            files: builder.files,
+        })
+    }
+
+    /// The listing Nix expects from `<hash>.ls`.
+    pub fn listing(&self) -> serde_json::Value {
+        serde_json::json!({ "version": 1, "root": self.root })
+    }
+
+    /// Finds the blob holding the inclusive byte range `start..=end` of the NAR,
+    /// and where the range starts in it. Ranges which are not within the contents
+    /// of a single file are not indexed.
+    pub fn locate(&self, start: u64, end: u64) -> Option<(Oid, u64)> {
+        let index = self
+            .files
+            .partition_point(|file| file.nar_offset <= start)
+            .checked_sub(1)?;
+        let file = self.files[index];
+        (end < file.nar_offset + file.size).then_some((file.blob, start - file.nar_offset))
+    }
+}
+
+/// Walks an entry in NAR order, adding up the sizes of the serialised parts.
+struct Builder<'a> {
+    repo: &'a Repository,
+    offset: u64,
+    files: Vec<IndexedFile>,
+}
+
+impl Builder<'_> {
+    fn skip(&mut self, parts: &[&[u8]]) {
+        self.offset += parts.iter().map(|part| padded_str(part)).sum::<u64>();
+    }
+
+    fn node(&mut self, oid: Oid, filemode: i32) -> Result<Node> {
+        self.skip(&[b"(", b"type"]);
+        let node = if filemode == i32::from(FileMode::Tree) {
+            self.skip(&[b"directory"]);
+            let tree = self.repo.find_tree(oid)?;
+            let mut entries = BTreeMap::new();
+            for entry in tree.iter() {
+                let name = entry
+                    .name()
+                    .ok_or_else(|| anyhow!("Entry name in tree {} is not UTF-8", oid))?;
+                entries.insert(name.to_string(), (entry.id(), entry.filemode()));
+            }
+            // NAR requires directory entries to be sorted by name, which the map does
+            let mut nodes = BTreeMap::new();
+            for (name, (id, filemode)) in entries {
+                self.skip(&[b"entry", b"(", b"name", name.as_bytes(), b"node"]);
+                nodes.insert(name, self.node(id, filemode)?);
+                self.skip(&[b")"]);
+            }
+            Node::Directory { entries: nodes }
+        } else if filemode == i32::from(FileMode::Link) {
+            let target = self.repo.find_blob(oid)?.content().to_vec();
+            self.skip(&[b"symlink", b"target", &target]);
+            Node::Symlink {
+                target: String::from_utf8(target)?,
+            }
+        } else {
+            let executable = filemode == i32::from(FileMode::BlobExecutable);
+            if !executable && filemode != i32::from(FileMode::Blob) {
+                bail!("Unsupported blob filemode: {}", filemode);
+            }
+            let (size, kind) = self.repo.odb()?.read_header(oid)?;
+            if kind != ObjectType::Blob {
+                bail!("Object {} is not a blob", oid);
+            }
+            let size = size as u64;
+            self.skip(&[b"regular"]);
+            if executable {
+                self.skip(&[b"executable", b""]);
+            }
+            self.skip(&[b"contents"]);
+            let nar_offset = self.offset + 8;
+            self.files.push(IndexedFile {
+                nar_offset,
+                size,
+                blob: oid,
+            });
+            self.offset += padded(size);
+            Node::Regular {
+                size,
+                executable,
+                nar_offset,
+            }
+        };
+        self.skip(&[b")"]);
+        Ok(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nar::encode::NarGitEncoder;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_index_matches_encoding() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let repo = Repository::init(temp_dir.path())?;
+        let mut bin = repo.treebuilder(None)?;
+        bin.insert(
+            "run",
+            repo.blob(b"#!/bin/sh\n")?,
+            FileMode::BlobExecutable.into(),
+        )?;
+        let bin = bin.write()?;
+        let mut root = repo.treebuilder(None)?;
+        root.insert("bin", bin, FileMode::Tree.into())?;
+        root.insert("a", repo.blob(b"seventeen bytes!!")?, FileMode::Blob.into())?;
+        root.insert("empty", repo.blob(b"")?, FileMode::Blob.into())?;
+        root.insert("link", repo.blob(b"bin/run")?, FileMode::Link.into())?;
+        let root = root.write()?;
+
+        let index = NarIndex::new(&repo, root, FileMode::Tree.into())?;
+        let object = repo.find_object(root, None)?;
+        let nar = NarGitEncoder::new(&repo, &object, FileMode::Tree.into()).encode()?;
+        assert_eq!(index.nar_size, nar.len() as u64);
+
+        let Node::Directory { entries } = &index.root else {
+            panic!("Root is not a directory");
+        };
+        let Some(Node::Regular { nar_offset, .. }) = entries.get("a") else {
+            panic!("a is not a regular file");
+        };
+        let offset = *nar_offset as usize;
+        assert_eq!(&nar[offset..offset + 17], b"seventeen bytes!!");
+
+        let (blob, start) = index.locate(*nar_offset + 2, *nar_offset + 16).unwrap();
+        assert_eq!(
+            &repo.find_blob(blob)?.content()[start as usize..],
+            b"venteen bytes!!"
+        );
+        assert!(index.locate(*nar_offset, *nar_offset + 17).is_none());
+        assert!(index.locate(0, 10).is_none());
+
+        let listing = index.listing();
+        assert_eq!(
+            listing["root"]["entries"]["bin"]["entries"]["run"]["executable"],
+            true
+        );
+        assert_eq!(listing["root"]["entries"]["link"]["target"], "bin/run");
+        assert!(listing["root"]["entries"]["a"].get("executable").is_none());
+        Ok(())
+    }
+}
@@ -1,8 +1,72 @@
 use crate::nar;
+pub mod budget;
 pub mod decode;
 pub mod encode;
 pub mod encode_stream;
+pub mod index;
 pub use nar::encode_stream::NarGitStream;
 
 const NIX_VERSION_MAGIC: &[u8] = b"nix-archive-1";
 const PAD_LEN: usize = 8;
+
+/// Returns the contents of the file a NAR holds, if it holds a single regular file.
+pub fn regular_file_contents(nar: &[u8]) -> Option<&[u8]> {
+    let mut rest = nar;
+    let header: [&[u8]; 4] = [NIX_VERSION_MAGIC, b"(", b"type", b"regular"];
+    for expected in header {
+        if read_field(&mut rest)? != expected {
+            return None;
+        }
+    }
+    let mut field = read_field(&mut rest)?;
+    if field == b"executable" {
+        read_field(&mut rest)?;
+        field = read_field(&mut rest)?;
+    }
+    if field != b"contents" {
+        return None;
+    }
+    let contents = read_field(&mut rest)?;
+    (read_field(&mut rest)? == b")" && rest.is_empty()).then_some(contents)
+}
+
+/// Reads a length-prefixed, padded field from the start of `rest` and advances it.
+fn read_field<'a>(rest: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = u64::from_le_bytes(rest.get(..8)?.try_into().ok()?);
+    let len = usize::try_from(len).ok()?;
+    let body = rest.get(8..)?;
+    let field = body.get(..len)?;
+    *rest = body.get(len.checked_next_multiple_of(PAD_LEN)?..)?;
+    Some(field)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nar_of(fields: &[&[u8]]) -> Vec<u8> {
+        let mut nar = Vec::new();
+        for field in fields {
+            nar.extend_from_slice(&(field.len() as u64).to_le_bytes());
+            nar.extend_from_slice(field);
+            nar.resize(nar.len().next_multiple_of(PAD_LEN), 0);
+        }
+        nar
+    }
+
+    #[test]
+    fn test_regular_file_contents() {
+        let header: [&[u8]; 4] = [NIX_VERSION_MAGIC, b"(", b"type", b"regular"];
+        let fields: [&[u8]; 3] = [b"contents", b"Derive(...)", b")"];
+        let file = nar_of(&[&header[..], &fields[..]].concat());
+        assert_eq!(regular_file_contents(&file), Some(&b"Derive(...)"[..]));
+
+        let fields: [&[u8]; 5] = [b"executable", b"", b"contents", b"#!/bin/sh", b")"];
+        let executable = nar_of(&[&header[..], &fields[..]].concat());
+        assert_eq!(regular_file_contents(&executable), Some(&b"#!/bin/sh"[..]));
+
+        let directory = nar_of(&[NIX_VERSION_MAGIC, b"(", b"type", b"directory", b")"]);
+        assert_eq!(regular_file_contents(&directory), None);
+        assert_eq!(regular_file_contents(&file[..file.len() - 8]), None);
+    }
+}
@@ -0,0 +1,85 @@
+use url::Url;
+
+/// The systems a daemon holds and builds paths for and the features its builds
+/// support. Builders declare them with the `systems` and `supported-features`
+/// parameters of their URL, e.g.
+/// `ssh-ng://builder?systems=aarch64-linux&supported-features=kvm,big-parallel`,
+/// like in the machines file of Nix. Whatever a daemon does not declare is not
+/// restricted.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Capabilities {
+    pub systems: Option<Vec<String>>,
+    pub features: Option<Vec<String>>,
+}
+
+fn list_parameter(url: &Url, name: &str) -> Option<Vec<String>> {
+    url.query_pairs()
+        .find(|(parameter, _)| parameter == name)
+        .map(|(_, values)| {
+            values
+                .split(',')
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+}
+
+impl Capabilities {
+    pub fn from_url(url: &Url) -> Self {
+        Self {
+            systems: list_parameter(url, "systems"),
+            features: list_parameter(url, "supported-features"),
+        }
+    }
+
+    pub fn is_restricted(&self) -> bool {
+        self.systems.is_some() || self.features.is_some()
+    }
+
+    /// Whether the daemon may hold paths of `system`. Paths of an unknown system
+    /// may be anywhere, as may those of `builtin` derivations like fetchurl, which
+    /// every daemon builds.
+    pub fn holds(&self, system: Option<&str>) -> bool {
+        match (system, &self.systems) {
+            (Some("builtin"), _) => true,
+            (Some(system), Some(systems)) => systems.iter().any(|s| s == system),
+            _ => true,
+        }
+    }
+
+    /// Whether the daemon can build a derivation for `system` which requires
+    /// `features`.
+    pub fn can_build(&self, system: Option<&str>, features: &[String]) -> bool {
+        self.holds(system)
+            && self
+                .features
+                .as_ref()
+                .is_none_or(|supported| features.iter().all(|f| supported.contains(f)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn test_capabilities() -> Result<()> {
+        let builder = Capabilities::from_url(&Url::parse(
+            "ssh-ng://builder?systems=aarch64-linux,armv7l-linux&supported-features=kvm",
+        )?);
+        assert!(builder.is_restricted());
+        assert!(builder.holds(Some("aarch64-linux")));
+        assert!(builder.holds(None));
+        assert!(!builder.holds(Some("x86_64-linux")));
+        assert!(builder.holds(Some("builtin")));
+        assert!(builder.can_build(Some("armv7l-linux"), &["kvm".to_string()]));
+        assert!(!builder.can_build(Some("aarch64-linux"), &["big-parallel".to_string()]));
+
+        let unrestricted = Capabilities::from_url(&Url::parse("ssh://builder")?);
+        assert!(!unrestricted.is_restricted());
+        assert!(unrestricted.can_build(Some("x86_64-darwin"), &["kvm".to_string()]));
+        Ok(())
+    }
+}
@@ -1,18 +1,122 @@
 use std::collections::HashMap;
-use std::io::{BufReader, Read};
-use std::path::PathBuf;
+use std::io::Read;
+use std::time::Duration;
 
 use anyhow::{Result, anyhow, bail};
 use async_ssh2_lite::{AsyncChannel, AsyncSession, TokioTcpStream};
+use bytes::Bytes;
 use futures::io;
 use nix_daemon::{BuildMode, ClientSettings, Progress, Store, nix::DaemonStore};
-use nix_daemon::{BuildResult, PathInfo};
+use nix_daemon::{BuildResult, PathInfo, Stderr, StderrField, StderrResultType};
 use std::net::ToSocketAddrs;
 use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::UnixStream;
-use tokio_util::io::SyncIoBridge;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
 
 use crate::nix_interface::path::NixPath;
+use crate::nix_interface::ssh::SshOptions;
+
+const FETCH_CHUNK_SIZE: usize = 64 * 1024;
+/// Number of chunks which may be buffered between the network and git
+const FETCH_QUEUE_CHUNKS: usize = 64;
+
+/// Limits on how long a daemon may take before it is considered hung.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// Establishing the connection, including the SSH handshake
+    pub connect: Duration,
+    /// A single path info or validity query
+    pub query: Duration,
+    /// Receiving the next chunk of a NAR, so that large transfers are not cut off
+    pub transfer: Duration,
+    /// Interval of SSH keepalives on sessions to remote daemons, disabled if zero
+    pub keepalive: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(30),
+            query: Duration::from_secs(60),
+            transfer: Duration::from_secs(120),
+            keepalive: Duration::from_secs(15),
+        }
+    }
+}
+
+/// Blocking reader over the chunks sent through a channel.
+struct ChannelReader {
+    receiver: mpsc::Receiver<Bytes>,
+    chunk: Bytes,
+}
+
+impl ChannelReader {
+    fn new(receiver: mpsc::Receiver<Bytes>) -> Self {
+        Self {
+            receiver,
+            chunk: Bytes::new(),
+        }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.receiver.blocking_recv() {
+                Some(chunk) => self.chunk = chunk,
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len());
+        buf[..n].copy_from_slice(&self.chunk.split_to(n));
+        Ok(n)
+    }
+}
+
+/// Turns a message the daemon sent during a build into a line for the user, or
+/// `None` for messages which are only relevant to the protocol.
+fn describe_output(stderr: &Stderr) -> Option<String> {
+    let text = |fields: &[StderrField]| match fields.first() {
+        Some(StderrField::String(s)) => Some(s.clone()),
+        _ => None,
+    };
+    match stderr {
+        Stderr::Next(message) => Some(message.trim_end().to_string()),
+        Stderr::StartActivity(activity) if !activity.s.is_empty() => Some(activity.s.clone()),
+        Stderr::Result(result) => match result.kind {
+            StderrResultType::BuildLogLine | StderrResultType::PostBuildLogLine => {
+                text(&result.fields)
+            }
+            StderrResultType::SetPhase => {
+                text(&result.fields).map(|phase| format!("phase: {phase}"))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Names outputs of a derivation the way the daemon takes them for a build, e.g.
+/// `/nix/store/...-hello.drv!out,man`.
+pub fn derived_path(drv_path: &NixPath, outputs: &[&str]) -> String {
+    format!("{drv_path}!{}", outputs.join(","))
+}
+
+#[derive(Debug, Clone)]
+pub struct ClosureEntry {
+    pub path: NixPath,
+    /// References to other paths, without the path itself
+    pub references: Vec<NixPath>,
+    pub nar_size: u64,
+    pub deriver: Option<NixPath>,
+    /// Read from the deriver by the store when it is needed
+    pub system: Option<String>,
+}
+
+/// The paths in a closure, keyed by their hash.
+pub type Closure = HashMap<String, ClosureEntry>;
 
 pub trait AsyncStream: AsyncWriteExt + AsyncReadExt + Unpin + Unpin + Send {}
 impl<T> AsyncStream for T where T: AsyncWriteExt + AsyncReadExt + AsyncWrite + Unpin + Send {}
@@ -21,7 +125,10 @@ pub struct NixDaemon<C: AsyncStream> {
     daemon: Option<DaemonStore<C>>,
     address: String,
     // TODO: this is only used by the ssh Nix daemon. find a better place to store this
-    ssh_private_key_path: Option<PathBuf>,
+    ssh: Option<SshOptions>,
+    timeouts: Timeouts,
+    /// Cancelled when the session is known to be dead, or once it is no longer used
+    session_lost: CancellationToken,
 }
 
 impl NixDaemon<UnixStream> {
@@ -29,74 +136,147 @@ impl NixDaemon<UnixStream> {
         Self {
             daemon: None,
             address: "/nix/var/nix/daemon-socket/socket".to_string(),
-            ssh_private_key_path: None,
+            ssh: None,
+            timeouts: Timeouts::default(),
+            session_lost: CancellationToken::new(),
         }
     }
     pub async fn connect(&mut self) -> Result<()> {
-        let store = DaemonStore::builder().connect_unix(&self.address).await?;
+        let store = tokio::time::timeout(
+            self.timeouts.connect,
+            DaemonStore::builder().connect_unix(&self.address),
+        )
+        .await
+        .map_err(|_| self.timed_out("Connecting", self.timeouts.connect))??;
         self.daemon = Some(store);
         Ok(())
     }
 }
 impl NixDaemon<AsyncChannel<TokioTcpStream>> {
-    pub fn remote(address: &str, ssh_private_key_path: PathBuf) -> Self {
+    pub fn remote(address: &str, ssh: SshOptions) -> Self {
         Self {
             daemon: None,
             address: address.to_string(),
-            ssh_private_key_path: Some(ssh_private_key_path),
+            ssh: Some(ssh),
+            timeouts: Timeouts::default(),
+            session_lost: CancellationToken::new(),
         }
     }
 
     pub async fn connect(&mut self) -> Result<()> {
-        let addr = (self.address.as_str(), 22)
+        let (daemon, session) = tokio::time::timeout(self.timeouts.connect, self.open_session())
+            .await
+            .map_err(|_| self.timed_out("Connecting", self.timeouts.connect))??;
+        self.session_lost.cancel();
+        self.session_lost = CancellationToken::new();
+        if !self.timeouts.keepalive.is_zero() {
+            self.spawn_keepalive(session);
+        }
+        self.daemon = Some(daemon);
+        Ok(())
+    }
+
+    /// Sends keepalives in the background, so that idle sessions survive NAT and
+    /// firewall timeouts and dead sessions are noticed without waiting for TCP.
+    fn spawn_keepalive(&self, session: AsyncSession<TokioTcpStream>) {
+        let interval = self.timeouts.keepalive;
+        let session_lost = self.session_lost.clone();
+        let address = self.address.clone();
+        session.set_keepalive(true, interval.as_secs().max(1) as u32);
+        tokio::spawn(async move {
+            while session_lost
+                .run_until_cancelled(tokio::time::sleep(interval))
+                .await
+                .is_some()
+            {
+                if let Err(e) = session.keepalive_send().await {
+                    warn!("SSH session to {address} is dead: {e}");
+                    session_lost.cancel();
+                }
+            }
+        });
+    }
+
+    async fn open_session(
+        &self,
+    ) -> Result<(
+        DaemonStore<AsyncChannel<TokioTcpStream>>,
+        AsyncSession<TokioTcpStream>,
+    )> {
+        // we can safely unwrap because all ssh Nix daemons are provided with ssh options
+        let ssh = self.ssh.as_ref().unwrap();
+        let addr = (self.address.as_str(), ssh.port)
             .to_socket_addrs()?
             .next()
             .ok_or(anyhow!("Failed to resolve address"))?;
         let stream = TokioTcpStream::connect(addr).await?;
         let mut session = AsyncSession::new(stream, None)?;
         session.handshake().await?;
-
-        // we can safely unwrap because all ssh Nix daemons are provided with a private key
-        let key_path = self.ssh_private_key_path.as_ref().unwrap();
-        // the default user name for accessing remote ssh stores
-        // as specified in https://nix.dev/manual/nix/2.22/package-management/ssh-substituter
-        let user = "nix-ssh";
-
-        session
-            .userauth_pubkey_file(&user, None, &key_path, None)
-            .await?;
-        if !session.authenticated() {
-            return Err(anyhow!("Could not authenticate to remote",));
-        }
+        ssh.host_key_check
+            .verify(&session, &self.address, ssh.port)?;
+        ssh.authenticate(&session, &self.address).await?;
         let mut channel = session.channel_session().await?;
         // NOTE: for some reason this has to be executed, I have no idea why
-        channel.exec("").await?;
-        self.daemon = Some(DaemonStore::builder().init(channel).await?);
-        Ok(())
+        channel
+            .exec(ssh.remote_command.as_deref().unwrap_or(""))
+            .await?;
+        Ok((DaemonStore::builder().init(channel).await?, session))
     }
 }
 
 impl<C: AsyncStream> NixDaemon<C> {
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Drops the connection after an operation timed out, since the daemon protocol
+    /// cannot be resumed in the middle of a response. The error is an I/O error, so
+    /// that callers reconnect.
+    fn timed_out(&mut self, operation: &str, after: Duration) -> anyhow::Error {
+        self.daemon = None;
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!(
+                "{operation} to Nix daemon at {} timed out after {}s",
+                self.address,
+                after.as_secs()
+            ),
+        )
+        .into()
+    }
+
     pub async fn get_pathinfo(&mut self, path: &NixPath) -> Result<Option<PathInfo>> {
         let Some(daemon) = &mut self.daemon else {
             bail!("Not connected to Nix Daemon")
         };
-        let path_info = daemon.query_pathinfo(path).result().await?;
+        let path_info =
+            tokio::time::timeout(self.timeouts.query, daemon.query_pathinfo(path).result())
+                .await
+                .map_err(|_| self.timed_out("Querying path info", self.timeouts.query))??;
         Ok(path_info)
     }
 
-    #[allow(dead_code)]
-    // This function could be used to trigger builds
-    pub async fn build(&mut self, drv_paths: &[&NixPath]) -> Result<HashMap<String, BuildResult>> {
+    /// Builds the given outputs of every derivation. The results are keyed by
+    /// `derived_path`.
+    pub async fn build(
+        &mut self,
+        drv_paths: &[&NixPath],
+        outputs: &[&str],
+        use_substitutes: bool,
+    ) -> Result<HashMap<String, BuildResult>> {
         let Some(daemon) = &mut self.daemon else {
             bail!("Not connected to Nix Daemon")
         };
-        daemon.set_options(ClientSettings {
-            try_fallback: true,
-            use_substitutes: false,
-            ..ClientSettings::default()
-        });
-        let out_drv_paths = drv_paths.iter().map(|p| format!("{}!out", p));
+        daemon
+            .set_options(ClientSettings {
+                try_fallback: true,
+                use_substitutes,
+                ..ClientSettings::default()
+            })
+            .result()
+            .await?;
+        let out_drv_paths = drv_paths.iter().map(|p| derived_path(p, outputs));
         let result = daemon
             .build_paths_with_results(out_drv_paths, BuildMode::Normal)
             .result()
@@ -104,11 +284,97 @@ impl<C: AsyncStream> NixDaemon<C> {
         Ok(result)
     }
 
+    /// Builds all outputs of a derivation and returns the result together with the
+    /// paths of the outputs, keyed by their name. Log lines and activities of the
+    /// daemon are passed to `on_output` while the build runs.
+    pub async fn build_derivation(
+        &mut self,
+        drv_path: &NixPath,
+        use_substitutes: bool,
+        mut on_output: impl FnMut(String) + Send,
+    ) -> Result<(BuildResult, HashMap<String, NixPath>)> {
+        let Some(daemon) = &mut self.daemon else {
+            bail!("Not connected to Nix Daemon")
+        };
+        let outputs = daemon
+            .query_derivation_output_map(drv_path)
+            .result()
+            .await?
+            .into_iter()
+            .map(|(name, path)| Ok((name, NixPath::new(&path)?)))
+            .collect::<Result<HashMap<_, _>>>()?;
+        if outputs.is_empty() {
+            bail!("Derivation {} has no outputs", drv_path);
+        }
+        daemon
+            .set_options(ClientSettings {
+                try_fallback: true,
+                use_substitutes,
+                ..ClientSettings::default()
+            })
+            .result()
+            .await?;
+        let mut names: Vec<&str> = outputs.keys().map(String::as_str).collect();
+        names.sort();
+        let derived_path = format!("{}!{}", drv_path, names.join(","));
+        let mut progress =
+            daemon.build_paths_with_results([derived_path.as_str()], BuildMode::Normal);
+        while let Some(stderr) = progress.next().await? {
+            if let Some(line) = describe_output(&stderr) {
+                on_output(line);
+            }
+        }
+        let mut results = progress.result().await?;
+        let result = results
+            .remove(&derived_path)
+            .ok_or_else(|| anyhow!("Did not find build result for {}", drv_path))?;
+        Ok((result, outputs))
+    }
+
+    /// Queries the path info of every path in the closure of `store_path`.
+    pub async fn query_closure(&mut self, store_path: &NixPath) -> Result<Closure> {
+        let mut closure = Closure::new();
+        let mut open = vec![store_path.clone()];
+        while let Some(path) = open.pop() {
+            if closure.contains_key(path.get_base_32_hash()) {
+                continue;
+            }
+            let path_info = self
+                .get_pathinfo(&path)
+                .await?
+                .ok_or_else(|| anyhow!("Could not find path info for {}", path))?;
+            let mut references = path_info
+                .references
+                .iter()
+                .map(NixPath::new)
+                .collect::<Result<Vec<_>>>()?;
+            references.retain(|r| *r != path);
+            open.extend(references.iter().cloned());
+            let deriver = path_info.deriver.map(|d| NixPath::new(&d)).transpose()?;
+            closure.insert(
+                path.get_base_32_hash().to_string(),
+                ClosureEntry {
+                    path,
+                    references,
+                    nar_size: path_info.nar_size,
+                    deriver,
+                    system: None,
+                },
+            );
+        }
+        Ok(closure)
+    }
+
     pub async fn path_exists(&mut self, store_path: &NixPath) -> Result<bool> {
         let Some(daemon) = &mut self.daemon else {
             bail!("Not connected to Nix Daemon")
         };
-        let exists = daemon.is_valid_path(store_path).result().await?;
+        let exists = tokio::time::timeout(
+            self.timeouts.query,
+            daemon.is_valid_path(store_path).result(),
+        )
+        .await
+        .map_err(|_| self.timed_out("Querying path validity", self.timeouts.query))??;
         Ok(exists)
     }
 
@@ -121,22 +387,82 @@ impl<C: AsyncStream> NixDaemon<C> {
             bail!("Not connected to Nix Daemon")
         };
 
-        let progress = daemon.nar_from_path(store_path, |reader| {
+        // The NAR is read from the daemon while a blocking task decodes the chunks
+        // received so far, so that network transfer and writing to git overlap
+        let transfer_timeout = self.timeouts.transfer;
+        let session_lost = self.session_lost.clone();
+        let progress = daemon.nar_from_path(store_path, move |mut reader| {
             Box::pin(async move {
-                tokio::task::block_in_place(|| {
-                    let sync_reader = SyncIoBridge::new(reader);
-                    let mut buf_reader = BufReader::new(sync_reader);
-                    let val = parser(&mut buf_reader)
-                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-                    Ok(val)
-                })
+                let (sender, receiver) = mpsc::channel(FETCH_QUEUE_CHUNKS);
+                let consumer = tokio::task::spawn_blocking(move || {
+                    let mut reader = ChannelReader::new(receiver);
+                    parser(&mut reader).map_err(io::Error::other)
+                });
+                let mut buf = vec![0; FETCH_CHUNK_SIZE];
+                loop {
+                    let read = tokio::time::timeout(transfer_timeout, reader.read(&mut buf));
+                    let n = session_lost
+                        .run_until_cancelled(read)
+                        .await
+                        .ok_or_else(|| {
+                            io::Error::new(io::ErrorKind::ConnectionAborted, "SSH session is dead")
+                        })?
+                        .map_err(|_| {
+                            io::Error::new(
+                                io::ErrorKind::TimedOut,
+                                format!("no data received for {}s", transfer_timeout.as_secs()),
+                            )
+                        })??;
+                    // A closed channel means the consumer failed, its error is reported below
+                    if n == 0
+                        || sender
+                            .send(Bytes::copy_from_slice(&buf[..n]))
+                            .await
+                            .is_err()
+                    {
+                        break;
+                    }
+                }
+                drop(sender);
+                consumer.await.map_err(io::Error::other)?
             })
         });
 
-        let val = progress.result().await?;
-
-        Ok(val)
+        let val = progress.result().await;
+        if val.is_err() {
+            // The rest of the NAR may still be in flight
+            self.daemon = None;
+        }
+        Ok(val?)
     }
+    /// Adds a content-addressed path to the store of the daemon. `method` is the
+    /// content address without the hash, e.g. `text:sha256`, and `contents` the file
+    /// for flat and text addresses or the NAR for recursive ones.
+    pub async fn add_to_store(
+        &mut self,
+        path: &NixPath,
+        method: &str,
+        references: &[String],
+        contents: Vec<u8>,
+    ) -> Result<()> {
+        let Some(daemon) = &mut self.daemon else {
+            bail!("Not connected to Nix Daemon")
+        };
+        let added = daemon
+            .add_to_store(
+                path.get_name(),
+                method,
+                references,
+                false,
+                std::io::Cursor::new(contents),
+            )
+            .result();
+        tokio::time::timeout(self.timeouts.transfer, added)
+            .await
+            .map_err(|_| self.timed_out("Adding a path", self.timeouts.transfer))??;
+        Ok(())
+    }
+
     pub fn get_address(&self) -> String {
         self.address.clone()
     }
@@ -146,6 +472,13 @@ impl<C: AsyncStream> NixDaemon<C> {
     }
 }
 
+impl<C: AsyncStream> Drop for NixDaemon<C> {
+    fn drop(&mut self) {
+        // Stops the keepalives of the session
+        self.session_lost.cancel();
+    }
+}
+
 pub enum DynNixDaemon {
     Local(NixDaemon<UnixStream>),
     Remote(NixDaemon<AsyncChannel<TokioTcpStream>>),
@@ -166,6 +499,33 @@ impl DynNixDaemon {
         }
     }
 
+    pub async fn query_closure(&mut self, store_path: &NixPath) -> Result<Closure> {
+        match self {
+            DynNixDaemon::Local(daemon) => daemon.query_closure(store_path).await,
+            DynNixDaemon::Remote(daemon) => daemon.query_closure(store_path).await,
+        }
+    }
+
+    pub async fn build_derivation(
+        &mut self,
+        drv_path: &NixPath,
+        use_substitutes: bool,
+        on_output: impl FnMut(String) + Send,
+    ) -> Result<(BuildResult, HashMap<String, NixPath>)> {
+        match self {
+            DynNixDaemon::Local(daemon) => {
+                daemon
+                    .build_derivation(drv_path, use_substitutes, on_output)
+                    .await
+            }
+            DynNixDaemon::Remote(daemon) => {
+                daemon
+                    .build_derivation(drv_path, use_substitutes, on_output)
+                    .await
+            }
+        }
+    }
+
     pub async fn path_exists(&mut self, store_path: &NixPath) -> Result<bool> {
         match self {
             DynNixDaemon::Local(daemon) => daemon.path_exists(store_path).await,
@@ -184,6 +544,27 @@ impl DynNixDaemon {
         }
     }
 
+    pub async fn add_to_store(
+        &mut self,
+        path: &NixPath,
+        method: &str,
+        references: &[String],
+        contents: Vec<u8>,
+    ) -> Result<()> {
+        match self {
+            DynNixDaemon::Local(daemon) => {
+                daemon
+                    .add_to_store(path, method, references, contents)
+                    .await
+            }
+            DynNixDaemon::Remote(daemon) => {
+                daemon
+                    .add_to_store(path, method, references, contents)
+                    .await
+            }
+        }
+    }
+
     pub fn disconnect(self) {
         match self {
             DynNixDaemon::Local(daemon) => daemon.disconnect(),
@@ -208,6 +589,37 @@ mod tests {
     use std::io::Write;
     use std::process::Stdio;
 
+    #[test]
+    fn test_describe_output() {
+        use nix_daemon::StderrResult;
+
+        let log_line = Stderr::Result(StderrResult {
+            act_id: 1,
+            kind: StderrResultType::BuildLogLine,
+            fields: vec![StderrField::String("compiling".to_string())],
+        });
+        assert_eq!(describe_output(&log_line).as_deref(), Some("compiling"));
+        let phase = Stderr::Result(StderrResult {
+            act_id: 1,
+            kind: StderrResultType::SetPhase,
+            fields: vec![StderrField::String("buildPhase".to_string())],
+        });
+        assert_eq!(
+            describe_output(&phase).as_deref(),
+            Some("phase: buildPhase")
+        );
+        let progress = Stderr::Result(StderrResult {
+            act_id: 1,
+            kind: StderrResultType::Progress,
+            fields: vec![StderrField::Int(1), StderrField::Int(2)],
+        });
+        assert_eq!(describe_output(&progress), None);
+        assert_eq!(
+            describe_output(&Stderr::Next("warning: dirty\n".to_string())).as_deref(),
+            Some("warning: dirty")
+        );
+    }
+
     #[tokio::test]
     async fn test_local_build_package() -> Result<()> {
         let mut nix = NixDaemon::local();
@@ -215,9 +627,9 @@ mod tests {
         let drv_path = create_random_derivation().await?;
         let drv_path = NixPath::new(&drv_path)?;
 
-        let result = nix.build(&[&drv_path]).await?;
+        let result = nix.build(&[&drv_path], &["out"], false).await?;
 
-        let key = format!("{}!out", drv_path);
+        let key = derived_path(&drv_path, &["out"]);
         let build_result = result
             .get(&key)
             .ok_or_else(|| anyhow!("Did not find build result"))?;
@@ -226,6 +638,39 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_connect_timeout() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let socket = temp_dir.path().join("socket");
+        // Accepts connections but never completes the handshake
+        let _listener = tokio::net::UnixListener::bind(&socket)?;
+
+        let mut daemon = NixDaemon::local().with_timeouts(Timeouts {
+            connect: Duration::from_millis(100),
+            ..Timeouts::default()
+        });
+        daemon.address = socket.to_str().unwrap().to_string();
+        let error = daemon.connect().await.unwrap_err();
+        assert!(error.to_string().contains("timed out"), "{error}");
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_channel_reader() -> Result<()> {
+        let (sender, receiver) = mpsc::channel(2);
+        let consumer = tokio::task::spawn_blocking(move || {
+            let mut content = Vec::new();
+            ChannelReader::new(receiver).read_to_end(&mut content)?;
+            Ok::<_, std::io::Error>(content)
+        });
+        for chunk in [&b"nix-"[..], b"", b"archive-1"] {
+            sender.send(Bytes::from_static(chunk)).await?;
+        }
+        drop(sender);
+        assert_eq!(consumer.await??, b"nix-archive-1");
+        Ok(())
+    }
+
     async fn create_random_derivation() -> Result<String> {
         let cookie = {
             use rand::distributions::{Alphanumeric, DistString};
@@ -0,0 +1,111 @@
+/// Extracts the system of a derivation from its ATerm serialisation, which may be
+/// embedded in other data such as the NAR of the `.drv` file.
+pub fn parse_system(content: &str) -> Option<&str> {
+    let start = content.find("Derive(")? + "Derive(".len();
+    let content = &content[start..];
+
+    // The system is the fourth field of `Derive(outputs, inputDrvs, inputSrcs, system, ...)`
+    let mut depth = 0;
+    let mut field = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in content.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' if depth == 0 && field == 3 => {
+                let end = content[i + 1..].find('"')?;
+                return Some(&content[i + 1..i + 1 + end]);
+            }
+            '"' => in_string = true,
+            '[' | '(' => depth += 1,
+            ']' | ')' if depth == 0 => return None,
+            ']' | ')' => depth -= 1,
+            ',' if depth == 0 => field += 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Extracts the names and paths of the outputs of a derivation from the first field
+/// of its ATerm serialisation. Paths are empty for outputs which are content-addressed
+/// and only known once they are built.
+pub fn parse_outputs(content: &str) -> Option<Vec<(&str, &str)>> {
+    let start = content.find("Derive([")? + "Derive([".len();
+    let mut rest = &content[start..];
+    let mut outputs = Vec::new();
+    while let Some(output) = rest.strip_prefix("(\"") {
+        let (name, output) = output.split_once("\",\"")?;
+        let (path, output) = output.split_once('"')?;
+        let end = output.find(')')?;
+        outputs.push((name, path));
+        rest = output[end + 1..].trim_start_matches(',');
+    }
+    rest.starts_with(']').then_some(outputs)
+}
+
+/// Extracts the features a derivation requires from the `requiredSystemFeatures`
+/// variable of its environment.
+pub fn parse_required_features(content: &str) -> Vec<String> {
+    const VARIABLE: &str = r#"("requiredSystemFeatures",""#;
+    let Some(start) = content.find(VARIABLE) else {
+        return Vec::new();
+    };
+    let value = &content[start + VARIABLE.len()..];
+    let end = value.find('"').unwrap_or(value.len());
+    value[..end]
+        .split_whitespace()
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_system() {
+        let drv = r#"Derive([("out","/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello","","")],[("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-bash.drv",["out"])],["/nix/store/cccccccccccccccccccccccccccccccc-builder.sh"],"aarch64-darwin","/bin/sh",["-c","echo \"],\" > $out"],[("name","hello")])"#;
+        assert_eq!(parse_system(drv), Some("aarch64-darwin"));
+        assert_eq!(
+            parse_system(&format!("nix-archive-1\0\0\0contents{drv}")),
+            Some("aarch64-darwin")
+        );
+        assert_eq!(parse_system("Derive([],[])"), None);
+        assert_eq!(parse_system("not a derivation"), None);
+    }
+
+    #[test]
+    fn test_parse_outputs() {
+        let drv = r#"Derive([("dev","/nix/store/dddddddddddddddddddddddddddddddd-hello-dev","",""),("out","/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello","","")],[],[],"x86_64-linux","/bin/sh",[],[])"#;
+        assert_eq!(
+            parse_outputs(drv),
+            Some(vec![
+                (
+                    "dev",
+                    "/nix/store/dddddddddddddddddddddddddddddddd-hello-dev"
+                ),
+                ("out", "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello"),
+            ])
+        );
+        let floating = r#"Derive([("out","","r:sha256","")],[],[],"x86_64-linux","/bin/sh",[],[])"#;
+        assert_eq!(parse_outputs(floating), Some(vec![("out", "")]));
+        assert_eq!(parse_outputs("Derive([(\"out\""), None);
+        assert_eq!(parse_outputs("not a derivation"), None);
+    }
+
+    #[test]
+    fn test_parse_required_features() {
+        let drv = r#"Derive([],[],[],"x86_64-linux","/bin/sh",[],[("name","vm-test"),("requiredSystemFeatures","kvm nixos-test")])"#;
+        assert_eq!(parse_required_features(drv), ["kvm", "nixos-test"]);
+        assert!(parse_required_features(r#"Derive([],[],[],"x86_64-linux")"#).is_empty());
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::BTreeMap;
+use std::process::Command;
+
+use anyhow::{Result, anyhow, bail};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::nix_interface::path::NixPath;
+
+pub const DEFAULT_CATEGORIES: [&str; 3] = ["packages", "checks", "devShells"];
+
+/// Maps the derivations of an attribute set to their derivation path and the paths
+/// of all of their outputs, by output name.
+const APPLY: &str = "builtins.mapAttrs (_: d: { drvPath = d.drvPath; \
+     outputs = builtins.listToAttrs (map (o: { name = o; value = d.${o}.outPath; }) \
+     (d.outputs or [ \"out\" ])); })";
+
+#[derive(Debug, Deserialize)]
+struct Paths {
+    #[serde(rename = "drvPath")]
+    drv_path: String,
+    outputs: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FlakeOutput {
+    /// The attribute path below the flake outputs, e.g. `packages.x86_64-linux.hello`
+    pub attr: String,
+    pub drv_path: NixPath,
+    /// The paths of every output of the derivation, e.g. `out` and `dev`
+    pub outputs: BTreeMap<String, NixPath>,
+}
+
+pub fn current_system() -> Result<String> {
+    let output = Command::new("nix")
+        .args([
+            "eval",
+            "--impure",
+            "--raw",
+            "--expr",
+            "builtins.currentSystem",
+        ])
+        .output()?;
+    if !output.status.success() {
+        bail!(
+            "Could not determine the current system: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Instantiates the derivation of an installable such as `nixpkgs#hello` or `.#default`.
+pub fn derivation_path(installable: &str) -> Result<NixPath> {
+    let output = Command::new("nix")
+        .args(["path-info", "--derivation"])
+        .arg(installable)
+        .output()?;
+    if !output.status.success() {
+        bail!(
+            "Could not evaluate {}: {}",
+            installable,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let stdout = String::from_utf8(output.stdout)?;
+    let path = stdout
+        .lines()
+        .next()
+        .ok_or_else(|| anyhow!("{} has no derivation", installable))?;
+    NixPath::new(path)
+}
+
+/// Evaluates the derivations of a flake in the given output categories and systems.
+/// Categories which the flake does not provide for a system are skipped.
+pub fn enumerate(
+    flakeref: &str,
+    categories: &[String],
+    systems: &[String],
+) -> Result<Vec<FlakeOutput>> {
+    let mut outputs = Vec::new();
+    for category in categories {
+        for system in systems {
+            let prefix = format!("{category}.{system}");
+            let output = Command::new("nix")
+                .args(["eval", "--json", "--apply", APPLY])
+                .arg(format!("{flakeref}#{prefix}"))
+                .output()?;
+            if !output.status.success() {
+                warn!(
+                    "Skipping {}: {}",
+                    prefix,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+                continue;
+            }
+            outputs.extend(parse_outputs(
+                &prefix,
+                &String::from_utf8_lossy(&output.stdout),
+            )?);
+        }
+    }
+    Ok(outputs)
+}
+
+fn parse_outputs(prefix: &str, json: &str) -> Result<Vec<FlakeOutput>> {
+    let paths: BTreeMap<String, Paths> = serde_json::from_str(json)
+        .map_err(|e| anyhow!("Unexpected evaluation result for {}: {}", prefix, e))?;
+    paths
+        .into_iter()
+        .map(|(name, paths)| {
+            if paths.outputs.is_empty() {
+                bail!("{prefix}.{name} has no outputs");
+            }
+            Ok(FlakeOutput {
+                attr: format!("{prefix}.{name}"),
+                drv_path: NixPath::new(&paths.drv_path)?,
+                outputs: paths
+                    .outputs
+                    .into_iter()
+                    .map(|(output, path)| Ok((output, NixPath::new(&path)?)))
+                    .collect::<Result<_>>()?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_outputs() -> Result<()> {
+        let json = r#"{
+            "hello": {
+                "drvPath": "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello-2.12.1.drv",
+                "outputs": {
+                    "out": "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-hello-2.12.1",
+                    "man": "/nix/store/cccccccccccccccccccccccccccccccc-hello-2.12.1-man"
+                }
+            },
+            "default": {
+                "drvPath": "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello-2.12.1.drv",
+                "outputs": {
+                    "out": "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-hello-2.12.1"
+                }
+            }
+        }"#;
+        let outputs = parse_outputs("packages.x86_64-linux", json)?;
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(outputs[0].attr, "packages.x86_64-linux.default");
+        let names: Vec<&str> = outputs[1].outputs.keys().map(String::as_str).collect();
+        assert_eq!(names, ["man", "out"]);
+        assert_eq!(outputs[1].outputs["out"].get_name(), "hello-2.12.1");
+        assert!(parse_outputs("packages.x86_64-linux", "[]").is_err());
+        assert!(
+            parse_outputs(
+                "packages.x86_64-linux",
+                r#"{"empty": {"drvPath": "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-e.drv", "outputs": {}}}"#
+            )
+            .is_err()
+        );
+        Ok(())
+    }
+}
@@ -1,5 +1,9 @@
 pub mod cache_info;
+pub mod capabilities;
 pub mod daemon;
+pub mod derivation;
+pub mod flake;
 pub mod nar_info;
 pub mod path;
 pub mod signature;
+pub mod ssh;
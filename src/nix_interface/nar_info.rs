@@ -29,6 +29,13 @@ pub struct NarInfo {
     pub references: Vec<NixPath>,
     pub deriver: Option<NixPath>,
     pub signature: Option<String>,
+    /// The system the package was built for, not known for packages which were
+    /// added before it was recorded or whose derivation is not available
+    pub system: Option<String>,
+    /// Fields gachix does not interpret, e.g. `CA`, in the order they were read.
+    /// They are written after the known ones, so that they survive being parsed
+    /// and written again
+    pub extra: Vec<(String, String)>,
 }
 
 impl NarInfo {
@@ -56,16 +63,18 @@ impl NarInfo {
             references: references,
             deriver: deriver,
             signature: signature,
+            system: None,
+            extra: Vec::new(),
         }
     }
 
     pub fn parse(content: &str) -> Result<Self> {
-        let hashmap: HashMap<&str, &str> = content
+        let fields: Vec<(&str, &str)> = content
             .trim()
             .lines()
             .enumerate()
             .map(|(line_num, line)| {
-                line.split_once(": ")
+                line.split_once(':')
                     .map(|(k, v)| Ok((k.trim(), v.trim())))
                     .unwrap_or_else(|| {
                         Err(anyhow::anyhow!(
@@ -74,6 +83,7 @@ impl NarInfo {
                     })
             })
             .collect::<Result<_>>()?;
+        let hashmap: HashMap<&str, &str> = fields.iter().copied().collect();
 
         let get = |k| {
             hashmap
@@ -125,14 +135,69 @@ impl NarInfo {
             nar_size: get("NarSize")?.parse::<u64>()?,
             references,
             deriver,
-            signature: Some(get("Sig")?.to_string()),
+            signature: match get("Sig")? {
+                "" => None,
+                s => Some(s.to_string()),
+            },
+            system: hashmap.get("System").map(|s| s.to_string()),
+            extra: fields
+                .into_iter()
+                .filter(|(k, _)| !Self::is_known_field(k))
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
         })
     }
 
+    /// Whether gachix interprets the field, as opposed to keeping it in `extra`.
+    pub fn is_known_field(key: &str) -> bool {
+        KEYS.contains(&key) || key == "System"
+    }
+
+    /// Sets a field gachix does not interpret, replacing its value if it is
+    /// already set. Known fields can't be set this way.
+    pub fn set_extra(&mut self, key: &str, value: &str) -> Result<()> {
+        if Self::is_known_field(key) {
+            anyhow::bail!("Narinfo field {key} can't be overridden");
+        }
+        match self.extra.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value.to_string(),
+            None => self.extra.push((key.to_string(), value.to_string())),
+        }
+        Ok(())
+    }
+
+    /// Drops the fields gachix does not interpret with one of these names.
+    pub fn strip_extra(&mut self, keys: &[String]) {
+        self.extra.retain(|(k, _)| !keys.contains(k));
+    }
+
+    /// Looks up a single field without parsing the whole narinfo.
+    pub fn field<'a>(content: &'a str, key: &str) -> Option<&'a str> {
+        content.lines().find_map(|line| {
+            line.split_once(':')
+                .filter(|(k, _)| k.trim() == key)
+                .map(|(_, v)| v.trim())
+        })
+    }
+
+    /// Replaces the value of a single field without parsing the whole narinfo.
+    pub fn replace_field(content: &str, key: &str, value: &str) -> String {
+        content
+            .lines()
+            .map(|line| match line.split_once(':') {
+                Some((k, _)) if k.trim() == key => format!("{key}: {value}\n"),
+                _ => format!("{line}\n"),
+            })
+            .collect()
+    }
+
+    /// Returns the references without the package itself. References are compared
+    /// by hash, as parsed narinfos list them without the store directory.
     pub fn get_dependencies(&self) -> Vec<&NixPath> {
+        let hash = self.store_path.get_base_32_hash();
         self.references
             .iter()
-            .filter(|r| **r != self.store_path)
+            .filter(|r| r.get_base_32_hash() != hash)
             .collect()
     }
 }
@@ -171,6 +236,12 @@ impl Display for NarInfo {
         for (key, value) in KEYS.iter().zip(values) {
             write!(f, "{}: {}\n", key, value)?;
         }
+        if let Some(system) = &self.system {
+            write!(f, "System: {}\n", system)?;
+        }
+        for (key, value) in &self.extra {
+            write!(f, "{}: {}\n", key, value)?;
+        }
         Ok(())
     }
 }
@@ -196,6 +267,108 @@ Sig: cache.nixos.org-1:NqjenY5yhRXNsUTUHwR9Io9xoD8B2XIUJQJFt6gBl9ik55Rcnj7wdHV1L
         "#;
         let narinfo = NarInfo::parse(content)?;
         assert_eq!(content.trim(), narinfo.to_string().trim());
+
+        // kitty references itself, which must not make it its own dependency
+        assert_eq!(narinfo.references.len(), 14);
+        let dependencies = narinfo.get_dependencies();
+        assert_eq!(dependencies.len(), 13);
+        assert!(
+            dependencies
+                .iter()
+                .all(|d| d.get_base_32_hash() != "iylhaki6573cpsvspivjfsim700n46r3")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_unsigned_narinfo() -> Result<()> {
+        let content = "StorePath: /nix/store/iylhaki6573cpsvspivjfsim700n46r3-kitty-0.43.1
+URL: nar/0lfjpl49j2na01l1zdmyszxz5wr957kl5qxn278alyv0fvxh2lab.nar
+Compression: none
+FileHash: sha256:0lfjpl49j2na01l1zdmyszxz5wr957kl5qxn278alyv0fvxh2lab
+FileSize: 18391180
+NarHash: sha256:0lfjpl49j2na01l1zdmyszxz5wr957kl5qxn278alyv0fvxh2lab
+NarSize: 18391180
+References: 
+Deriver: 
+Sig: 
+";
+        let narinfo = NarInfo::parse(content)?;
+        assert!(narinfo.signature.is_none());
+        assert!(narinfo.references.is_empty());
+        assert_eq!(content, narinfo.to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn test_narinfo_system() -> Result<()> {
+        let content = "StorePath: /nix/store/iylhaki6573cpsvspivjfsim700n46r3-kitty-0.43.1
+URL: nar/0lfjpl49j2na01l1zdmyszxz5wr957kl5qxn278alyv0fvxh2lab.nar
+Compression: none
+FileHash: sha256:0lfjpl49j2na01l1zdmyszxz5wr957kl5qxn278alyv0fvxh2lab
+FileSize: 18391180
+NarHash: sha256:0lfjpl49j2na01l1zdmyszxz5wr957kl5qxn278alyv0fvxh2lab
+NarSize: 18391180
+References: 
+Deriver: 
+Sig: 
+System: aarch64-linux
+";
+        let narinfo = NarInfo::parse(content)?;
+        assert_eq!(narinfo.system.as_deref(), Some("aarch64-linux"));
+        assert_eq!(content, narinfo.to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn test_narinfo_extra_fields() -> Result<()> {
+        let content = "StorePath: /nix/store/iylhaki6573cpsvspivjfsim700n46r3-kitty-0.43.1
+URL: nar/0lfjpl49j2na01l1zdmyszxz5wr957kl5qxn278alyv0fvxh2lab.nar
+Compression: none
+FileHash: sha256:0lfjpl49j2na01l1zdmyszxz5wr957kl5qxn278alyv0fvxh2lab
+FileSize: 18391180
+NarHash: sha256:0lfjpl49j2na01l1zdmyszxz5wr957kl5qxn278alyv0fvxh2lab
+NarSize: 18391180
+References: 
+Deriver: 
+Sig: 
+CA: fixed:r:sha256:0lfjpl49j2na01l1zdmyszxz5wr957kl5qxn278alyv0fvxh2lab
+CI-Build: https://ci.example.org/builds/42
+";
+        let mut narinfo = NarInfo::parse(content)?;
+        assert_eq!(narinfo.extra.len(), 2);
+        assert_eq!(content, narinfo.to_string());
+
+        narinfo.set_extra("CI-Build", "https://ci.example.org/builds/43")?;
+        narinfo.set_extra("Priority", "10")?;
+        assert!(narinfo.set_extra("NarSize", "1").is_err());
+        narinfo.strip_extra(&["CA".to_string()]);
+        let written = narinfo.to_string();
+        assert!(
+            written.ends_with("Sig: \nCI-Build: https://ci.example.org/builds/43\nPriority: 10\n")
+        );
+        assert_eq!(NarInfo::parse(&written)?.extra, narinfo.extra);
         Ok(())
     }
+
+    #[test]
+    fn test_narinfo_field() {
+        let content =
+            "StorePath: /nix/store/iylhaki6573cpsvspivjfsim700n46r3-kitty-0.43.1\nSig: \n";
+        assert_eq!(
+            NarInfo::field(content, "StorePath"),
+            Some("/nix/store/iylhaki6573cpsvspivjfsim700n46r3-kitty-0.43.1")
+        );
+        assert_eq!(NarInfo::field(content, "Sig"), Some(""));
+        assert_eq!(NarInfo::field(content, "Deriver"), None);
+    }
+
+    #[test]
+    fn test_replace_narinfo_field() {
+        let content = "StorePath: /nix/store/iylhaki6573cpsvspivjfsim700n46r3-kitty-0.43.1\nURL: nar/abc.nar\nSig: \n";
+        assert_eq!(
+            NarInfo::replace_field(content, "URL", "https://cdn.example.org/nar/abc.nar"),
+            "StorePath: /nix/store/iylhaki6573cpsvspivjfsim700n46r3-kitty-0.43.1\nURL: https://cdn.example.org/nar/abc.nar\nSig: \n"
+        );
+    }
 }
@@ -1,6 +1,14 @@
 use anyhow::{Result, anyhow};
 use std::{fmt::Display, path::Path};
 
+/// The digits of nix-base32, which leaves out `e`, `o`, `u` and `t`.
+pub const NIX_BASE32_CHARS: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+/// Whether `hash` is the hash part of a store path: 32 nix-base32 digits.
+pub fn is_store_hash(hash: &str) -> bool {
+    hash.len() == 32 && hash.bytes().all(|c| NIX_BASE32_CHARS.contains(&c))
+}
+
 #[derive(Debug, Clone)]
 pub struct NixPath {
     path: String,
@@ -41,6 +49,24 @@ impl NixPath {
         })
     }
 
+    /// Parses a list of store paths with one path per line, like the output of
+    /// `nix-store -qR`. Blank lines and lines starting with `#` are skipped, as are
+    /// paths listed more than once.
+    pub fn parse_list(content: &str) -> Result<Vec<Self>> {
+        let mut paths: Vec<Self> = Vec::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let path = Self::new(line).map_err(|e| anyhow!("Line {}: {}", number + 1, e))?;
+            if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+
     pub fn get_base_32_hash(&self) -> &str {
         &self.hash
     }
@@ -52,6 +78,21 @@ impl NixPath {
     pub fn get_path(&self) -> &str {
         &self.path
     }
+
+    /// Splits the name into package name and version the way Nix does: the version
+    /// starts after the first dash which is not followed by a letter.
+    pub fn get_pname_and_version(&self) -> (&str, Option<&str>) {
+        let split = self.name.match_indices('-').map(|(i, _)| i).find(|&i| {
+            self.name[i + 1..]
+                .chars()
+                .next()
+                .is_some_and(|c| !c.is_alphabetic())
+        });
+        match split {
+            Some(i) => (&self.name[..i], Some(&self.name[i + 1..])),
+            None => (&self.name, None),
+        }
+    }
 }
 
 impl AsRef<str> for NixPath {
@@ -76,3 +117,54 @@ impl PartialEq for NixPath {
         self.path == other.path
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_list() -> Result<()> {
+        let hello = format!("/nix/store/{}-hello-2.12.1", "a".repeat(32));
+        let glibc = format!("/nix/store/{}-glibc-2.40", "b".repeat(32));
+        let list = format!("# deploy of tomorrow\n{hello}\n\n  {glibc}  \n{hello}\n");
+        let paths = NixPath::parse_list(&list)?;
+        let paths: Vec<&str> = paths.iter().map(NixPath::get_path).collect();
+        assert_eq!(paths, [hello.as_str(), glibc.as_str()]);
+
+        let error = NixPath::parse_list(&format!("{hello}\nhello\n")).unwrap_err();
+        assert!(error.to_string().starts_with("Line 2:"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_store_hash() {
+        assert!(is_store_hash(&"a".repeat(32)));
+        for hash in [
+            "*".to_string(),
+            "gachix".to_string(),
+            "e".repeat(32),
+            "a".repeat(33),
+        ] {
+            assert!(!is_store_hash(&hash), "{hash}");
+        }
+    }
+
+    #[test]
+    fn test_pname_and_version() -> Result<()> {
+        let cases = [
+            ("hello-2.12.1", ("hello", Some("2.12.1"))),
+            (
+                "python3.11-requests-2.31.0",
+                ("python3.11-requests", Some("2.31.0")),
+            ),
+            ("hello-2.12.1-man", ("hello", Some("2.12.1-man"))),
+            ("source", ("source", None)),
+            ("nixos-system-host", ("nixos-system-host", None)),
+        ];
+        for (name, expected) in cases {
+            let path = NixPath::new(&format!("/nix/store/{}-{name}", "a".repeat(32)))?;
+            assert_eq!(path.get_pname_and_version(), expected);
+        }
+        Ok(())
+    }
+}
@@ -1,7 +1,7 @@
 use crate::nix_interface::path::NixPath;
 use anyhow::{Result, anyhow};
 use base64::{Engine, prelude::BASE64_STANDARD};
-use ring::signature::Ed25519KeyPair;
+use ring::signature::{self, Ed25519KeyPair, UnparsedPublicKey};
 use std::str::FromStr;
 
 pub const NUM_SEED_BYTES: usize = 32;
@@ -46,6 +46,45 @@ impl FromStr for PrivateKey {
     }
 }
 
+/// A key whose signatures are trusted, in the `name:base64` format of Nix's
+/// `trusted-public-keys`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublicKey {
+    pub name: String,
+    key: [u8; NUM_PUBLIC_KEY_BYTES],
+}
+
+impl PublicKey {
+    /// Whether one of the space separated `name:base64` signatures of a narinfo is a
+    /// valid signature of `fingerprint` by this key.
+    pub fn verifies(&self, fingerprint: &str, signatures: &str) -> bool {
+        let public_key = UnparsedPublicKey::new(&signature::ED25519, &self.key);
+        signatures
+            .split_whitespace()
+            .filter_map(|sig| sig.split_once(':'))
+            .filter(|(name, _)| *name == self.name)
+            .filter_map(|(_, sig)| BASE64_STANDARD.decode(sig).ok())
+            .any(|sig| public_key.verify(fingerprint.as_bytes(), &sig).is_ok())
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, key_base64) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Public key {} is not in the name:key format", s))?;
+        let key = BASE64_STANDARD
+            .decode(key_base64)?
+            .try_into()
+            .map_err(|_| anyhow!("Public key {} does not have 32 bytes", name))?;
+        Ok(Self {
+            name: name.to_string(),
+            key,
+        })
+    }
+}
+
 pub fn fingerprint_store_object(
     store_path: &NixPath,
     nar_hash: &str,
@@ -67,7 +106,6 @@ pub fn fingerprint_store_object(
 mod tests {
 
     use super::*;
-    use ring::signature::{self, UnparsedPublicKey};
 
     #[test]
     fn test_signature() -> Result<()> {
@@ -83,6 +121,28 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_public_key_verifies() -> Result<()> {
+        let secret_key = PrivateKey::from_str(
+            "cache.example.org-1:ZJui+kG6vPCSRD4+p1P4DyUVlASmp/zsaeN84PTFW28tj2/PtQWvFWK6Mw+ay8kGif8AZkR5KosHLvuwlzDlgg==",
+        )?;
+        let public_key = PublicKey::from_str(
+            "cache.example.org-1:LY9vz7UFrxViujMPmsvJBon/AGZEeSqLBy77sJcw5YI=",
+        )?;
+        let fingerprint = "1;/nix/store/02bfycjg1607gpcnsg8l13lc45qa8qj3-libssh2-1.10.0;sha256:1l29f8r5q2739wnq4i7m2v545qx77b3wrdsw9xz2ajiy3hv1al8b;294664;";
+        let signature = format!(
+            "cache.example.org-1:{}",
+            BASE64_STANDARD.encode(secret_key.sign(fingerprint))
+        );
+        assert!(public_key.verifies(fingerprint, &signature));
+        assert!(public_key.verifies(fingerprint, &format!("other-1:AAAA {signature}")));
+        assert!(!public_key.verifies(&fingerprint.replace("294664", "294665"), &signature));
+        let renamed = signature.replace("cache.example.org-1", "other-1");
+        assert!(!public_key.verifies(fingerprint, &renamed));
+        assert!(PublicKey::from_str("cache.example.org-1:AAAA").is_err());
+        Ok(())
+    }
+
     // #[test]
     // fn test_fingerprint() {
     //     let store_path = "/nix/store/2bcv91i8fahqghn8dmyr791iaycbsjdd-hello-2.12.2";
@@ -0,0 +1,405 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{LazyLock, Mutex};
+
+use anyhow::{Result, anyhow, bail};
+use async_ssh2_lite::ssh2::{CheckResult, KeyboardInteractivePrompt, KnownHostFileKind, Prompt};
+use async_ssh2_lite::{AsyncSession, TokioTcpStream};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use serde::Deserialize;
+use tokio::runtime::RuntimeFlavor;
+use tracing::{debug, warn};
+
+/// The user name for accessing remote ssh stores, as specified in
+/// https://nix.dev/manual/nix/2.22/package-management/ssh-substituter
+pub const DEFAULT_USER: &str = "nix-ssh";
+
+/// Passwords entered for a `user@host`, so that they are only asked for once
+static PASSWORDS: LazyLock<Mutex<HashMap<String, String>>> = LazyLock::new(Default::default);
+/// Keeps concurrent connections from prompting at the same time
+static PROMPT: Mutex<()> = Mutex::new(());
+
+/// None of the authentication methods was accepted, as opposed to the connection
+/// failing.
+#[derive(Debug)]
+pub struct AuthenticationFailed {
+    login: String,
+    offered: String,
+}
+
+impl std::fmt::Display for AuthenticationFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Could not authenticate to {} with any of the methods it offers ({})",
+            self.login, self.offered
+        )
+    }
+}
+
+impl std::error::Error for AuthenticationFailed {}
+
+/// An SSH authentication method, named as in OpenSSH.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthMethod {
+    Publickey,
+    Password,
+    KeyboardInteractive,
+}
+
+impl AuthMethod {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AuthMethod::Publickey => "publickey",
+            AuthMethod::Password => "password",
+            AuthMethod::KeyboardInteractive => "keyboard-interactive",
+        }
+    }
+
+    /// Whether the method asks the user for input
+    fn is_interactive(&self) -> bool {
+        *self != AuthMethod::Publickey
+    }
+}
+
+impl FromStr for AuthMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        [
+            AuthMethod::Publickey,
+            AuthMethod::Password,
+            AuthMethod::KeyboardInteractive,
+        ]
+        .into_iter()
+        .find(|method| method.name() == s)
+        .ok_or_else(|| anyhow!("Unknown SSH authentication method: {s}"))
+    }
+}
+
+/// How to log in to a remote Nix daemon.
+#[derive(Debug, Clone)]
+pub struct SshOptions {
+    pub user: String,
+    pub port: u16,
+    /// Command which serves the daemon protocol on stdio. If not set, the command
+    /// forced for the user by the remote, e.g. in `authorized_keys`, is used
+    pub remote_command: Option<String>,
+    pub private_key_path: Option<PathBuf>,
+    /// Methods to try, in order
+    pub auth_methods: Vec<AuthMethod>,
+    pub host_key_check: HostKeyCheck,
+}
+
+impl SshOptions {
+    /// Tries the configured authentication methods which the server offers, in order.
+    /// Methods which prompt are skipped if there is no terminal to prompt on.
+    pub async fn authenticate(
+        &self,
+        session: &AsyncSession<TokioTcpStream>,
+        host: &str,
+    ) -> Result<()> {
+        let offered = session.auth_methods(&self.user).await?.to_string();
+        let offered: Vec<&str> = offered.split(',').collect();
+        let tty = tty_available();
+        for method in &self.auth_methods {
+            if !offered.contains(&method.name()) || (method.is_interactive() && !tty) {
+                continue;
+            }
+            let result = match method {
+                AuthMethod::Publickey => {
+                    let Some(key_path) = &self.private_key_path else {
+                        continue;
+                    };
+                    session
+                        .userauth_pubkey_file(&self.user, None, key_path, None)
+                        .await
+                }
+                AuthMethod::Password => {
+                    let password = self.password(host).await?;
+                    let result = session.userauth_password(&self.user, &password).await;
+                    if result.is_err() {
+                        PASSWORDS.lock().unwrap().remove(&self.login(host));
+                    }
+                    result
+                }
+                AuthMethod::KeyboardInteractive => {
+                    session
+                        .userauth_keyboard_interactive(&self.user, &mut TtyPrompter)
+                        .await
+                }
+            };
+            match result {
+                Ok(()) if session.authenticated() => return Ok(()),
+                Ok(()) => {}
+                Err(e) => debug!("{} authentication to {host} failed: {e}", method.name()),
+            }
+        }
+        Err(AuthenticationFailed {
+            login: self.login(host),
+            offered: offered.join(", "),
+        }
+        .into())
+    }
+
+    fn login(&self, host: &str) -> String {
+        format!("{}@{}", self.user, host)
+    }
+
+    async fn password(&self, host: &str) -> Result<String> {
+        let login = self.login(host);
+        if let Some(password) = PASSWORDS.lock().unwrap().get(&login) {
+            return Ok(password.clone());
+        }
+        // Waiting for the terminal would hold up the other tasks of the worker
+        let text = format!("Password for {login}: ");
+        let password = tokio::task::spawn_blocking(move || prompt(&text, false)).await??;
+        PASSWORDS.lock().unwrap().insert(login, password.clone());
+        Ok(password)
+    }
+}
+
+fn tty_available() -> bool {
+    File::open("/dev/tty").is_ok()
+}
+
+/// Asks for a line on the controlling terminal, without echoing it unless `echo` is set.
+fn prompt(text: &str, echo: bool) -> Result<String> {
+    let _prompt = PROMPT.lock().unwrap_or_else(|e| e.into_inner());
+    let mut tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+    write!(tty, "{text}")?;
+    tty.flush()?;
+
+    let fd = tty.as_raw_fd();
+    let mut original = unsafe { std::mem::zeroed::<libc::termios>() };
+    let hide = !echo && unsafe { libc::tcgetattr(fd, &mut original) } == 0;
+    if hide {
+        let mut hidden = original;
+        hidden.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &hidden) };
+    }
+    let mut line = String::new();
+    let read = BufReader::new(&tty).read_line(&mut line);
+    if hide {
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &original) };
+        writeln!(tty)?;
+    }
+    read?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Prompts from the keyboard-interactive callback, which libssh2 calls while the
+/// authentication future is polled. The worker hands its other tasks to another
+/// thread while waiting for the terminal.
+fn prompt_in_place(text: &str, echo: bool) -> Result<String> {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(|| prompt(text, echo))
+        }
+        _ => prompt(text, echo),
+    }
+}
+
+struct TtyPrompter;
+
+impl KeyboardInteractivePrompt for TtyPrompter {
+    fn prompt<'a>(
+        &mut self,
+        _username: &str,
+        instructions: &str,
+        prompts: &[Prompt<'a>],
+    ) -> Vec<String> {
+        if !instructions.is_empty()
+            && let Ok(mut tty) = OpenOptions::new().write(true).open("/dev/tty")
+        {
+            let _ = writeln!(tty, "{instructions}");
+        }
+        prompts
+            .iter()
+            .map(|p| prompt_in_place(&p.text, p.echo).unwrap_or_default())
+            .collect()
+    }
+}
+
+/// How the host key of a remote Nix daemon is verified.
+#[derive(Debug, Clone)]
+pub enum HostKeyCheck {
+    /// The host must present exactly this raw public key
+    Pinned(Vec<u8>),
+    /// The host must be listed in the known_hosts file at `path` or in `learned`.
+    /// Unknown hosts are added to `learned` if `trust_on_first_use` is set, so that
+    /// the known_hosts file of the user is never written
+    KnownHosts {
+        path: PathBuf,
+        learned: PathBuf,
+        trust_on_first_use: bool,
+    },
+}
+
+impl HostKeyCheck {
+    pub fn default_known_hosts_path() -> Option<PathBuf> {
+        dirs::home_dir().map(|home| home.join(".ssh").join("known_hosts"))
+    }
+
+    /// Verifies the key the host presented during the handshake.
+    pub fn verify(
+        &self,
+        session: &AsyncSession<TokioTcpStream>,
+        host: &str,
+        port: u16,
+    ) -> Result<()> {
+        let (key, key_type) = session
+            .host_key()
+            .ok_or_else(|| anyhow!("{host} did not present a host key"))?;
+        match self {
+            HostKeyCheck::Pinned(pinned) => {
+                if key != pinned.as_slice() {
+                    bail!("Host key of {host} does not match the pinned key");
+                }
+            }
+            HostKeyCheck::KnownHosts {
+                path,
+                learned,
+                trust_on_first_use,
+            } => {
+                let mut known_hosts = session.known_hosts()?;
+                for file in [path, learned] {
+                    read_known_hosts(&mut known_hosts, file)?;
+                }
+                match known_hosts.check_port(host, port, key) {
+                    CheckResult::Match => {}
+                    CheckResult::Mismatch => bail!(
+                        "Host key of {host} does not match the one in {}, refusing to connect",
+                        path.display()
+                    ),
+                    CheckResult::NotFound if *trust_on_first_use => {
+                        warn!("Trusting previously unknown host key of {host}");
+                        let name = if port == 22 {
+                            host.to_string()
+                        } else {
+                            format!("[{host}]:{port}")
+                        };
+                        let key_type = key_type_name(key).ok_or_else(|| {
+                            anyhow!("Could not read the type of the host key of {host}")
+                        })?;
+                        append_known_host(learned, &name, key_type, key)?;
+                    }
+                    CheckResult::NotFound => bail!(
+                        "{host} is not a known host in {}, add its key or enable ssh_trust_on_first_use",
+                        path.display()
+                    ),
+                    CheckResult::Failure => bail!("Could not check the host key of {host}"),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the entries of a known_hosts file, if it exists. Lines libssh2 can't parse,
+/// e.g. key types it doesn't support, are skipped rather than failing the check.
+fn read_known_hosts(
+    known_hosts: &mut async_ssh2_lite::ssh2::KnownHosts,
+    path: &Path,
+) -> Result<()> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let entry = line.trim();
+        if entry.is_empty() || entry.starts_with('#') {
+            continue;
+        }
+        if let Err(e) = known_hosts.read_str(entry, KnownHostFileKind::OpenSSH) {
+            debug!("Skipping line {} of {}: {}", number + 1, path.display(), e);
+        }
+    }
+    Ok(())
+}
+
+/// The key type a raw SSH public key starts with, e.g. `ssh-ed25519`.
+fn key_type_name(key: &[u8]) -> Option<&str> {
+    let length = u32::from_be_bytes(key.get(..4)?.try_into().ok()?) as usize;
+    std::str::from_utf8(key.get(4..4 + length)?).ok()
+}
+
+/// Appends a single host key to a known_hosts file, leaving the other lines as they
+/// are.
+fn append_known_host(path: &Path, name: &str, key_type: &str, key: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(
+        file,
+        "{name} {key_type} {} added by gachix",
+        BASE64_STANDARD.encode(key)
+    )?;
+    Ok(())
+}
+
+/// Parses a public host key in the format of the `base64-ssh-public-host-key` store
+/// URL parameter of Nix, i.e. a base64 encoded `<type> <base64 key>` line, into the
+/// raw key.
+pub fn parse_public_host_key(encoded: &str) -> Result<Vec<u8>> {
+    let line = String::from_utf8(BASE64_STANDARD.decode(encoded.trim())?)?;
+    let key = line
+        .split_whitespace()
+        .nth(1)
+        .ok_or_else(|| anyhow!("Public host key is missing the key: {line}"))?;
+    Ok(BASE64_STANDARD.decode(key)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_auth_method() -> Result<()> {
+        assert_eq!(
+            "keyboard-interactive".parse::<AuthMethod>()?,
+            AuthMethod::KeyboardInteractive
+        );
+        assert_eq!("publickey".parse::<AuthMethod>()?, AuthMethod::Publickey);
+        assert!("hostbased".parse::<AuthMethod>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_known_host() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let path = temp_dir.path().join("known_hosts");
+        std::fs::write(&path, "builder not-a-key\n")?;
+        let raw = b"\0\0\0\x0bssh-ed25519\0\0\0\x20key";
+        assert_eq!(key_type_name(raw), Some("ssh-ed25519"));
+        assert_eq!(key_type_name(b"\0\0\0\x0bssh"), None);
+
+        append_known_host(&path, "[builder]:2222", "ssh-ed25519", raw)?;
+        assert_eq!(
+            std::fs::read_to_string(&path)?,
+            format!(
+                "builder not-a-key\n[builder]:2222 ssh-ed25519 {} added by gachix\n",
+                BASE64_STANDARD.encode(raw)
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_public_host_key() -> Result<()> {
+        let raw = b"\0\0\0\x0bssh-ed25519\0\0\0\x20key";
+        let line = format!("ssh-ed25519 {} root@builder", BASE64_STANDARD.encode(raw));
+        let encoded = BASE64_STANDARD.encode(line);
+        assert_eq!(parse_public_host_key(&encoded)?, raw);
+        assert!(parse_public_host_key(&BASE64_STANDARD.encode("ssh-ed25519")).is_err());
+        Ok(())
+    }
+}
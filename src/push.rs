@@ -0,0 +1,375 @@
+use crate::git_store::handshake::Handshake;
+use crate::git_store::store::Store;
+use crate::nix_interface::nar_info::NarInfo;
+use anyhow::{Result, anyhow, bail};
+use git2::Oid;
+use reqwest::StatusCode;
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::CONTENT_TYPE;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::Seek;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+use url::Url;
+
+/// Objects asked about at once while negotiating what the remote is missing.
+const NEGOTIATION_BATCH: usize = 10_000;
+
+pub struct PushOptions {
+    pub url: Url,
+    /// Admin token of the remote gachix
+    pub token: Option<String>,
+    /// Number of packages uploaded in parallel
+    pub jobs: usize,
+    /// How often a failing upload is retried
+    pub retries: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct PushSummary {
+    pub pushed: usize,
+    pub present: usize,
+    /// Bytes of the compressed NARs which were uploaded
+    pub bytes: u64,
+}
+
+impl Display for PushSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Pushed {} packages ({} bytes), {} were already present",
+            self.pushed, self.bytes, self.present
+        )
+    }
+}
+
+/// Pushes the closures of `hashes` to the gachix at `options.url`. The remote is
+/// asked which packages it is missing, which are then uploaded `options.jobs` at a
+/// time. A package is only uploaded once its dependencies are, as the remote does
+/// not accept it before. Packages are sent as the git objects the remote is
+/// missing, or as xz compressed NARs to servers which cannot receive objects.
+pub fn push(cache: &Store, hashes: &[String], options: &PushOptions) -> Result<PushSummary> {
+    let mut base = options.url.clone();
+    if !base.path().ends_with('/') {
+        base.set_path(&format!("{}/", base.path()));
+    }
+    // Large NARs take longer than the default timeout to upload
+    let client = Client::builder().timeout(None).build()?;
+
+    let mut closure = BTreeMap::new();
+    for hash in hashes {
+        for narinfo in cache.closure(hash)? {
+            let package_hash = narinfo.store_path.get_base_32_hash().to_string();
+            closure.entry(package_hash).or_insert(narinfo);
+        }
+    }
+    let hashes: Vec<String> = closure.keys().cloned().collect();
+    let missing: HashSet<String> = with_retries(options.retries, "Querying the remote", || {
+        query_missing(&client, &base, &hashes)
+    })?
+    .into_iter()
+    .collect();
+    let mut pending: BTreeMap<String, NarInfo> = closure
+        .into_iter()
+        .filter(|(hash, _)| missing.contains(hash))
+        .collect();
+    let mut summary = PushSummary {
+        present: hashes.len() - pending.len(),
+        ..Default::default()
+    };
+    info!("Pushing {} packages to {}", pending.len(), base);
+    if pending.is_empty() {
+        return Ok(summary);
+    }
+    let objects = receives_objects(&client, &base, options)?;
+    if !objects {
+        debug!("{} does not receive git objects, pushing NARs", base);
+    }
+
+    let staging = tempfile::tempdir()?;
+    let transfer = Transfer {
+        cache,
+        client: &client,
+        base: &base,
+        options,
+        staging: staging.path(),
+        objects,
+    };
+    while !pending.is_empty() {
+        let ready = ready_to_push(&pending);
+        if ready.is_empty() {
+            bail!("The closure has packages which reference each other");
+        }
+        summary.bytes += transfer.upload_all(&ready)?;
+        summary.pushed += ready.len();
+        for hash in &ready {
+            pending.remove(hash);
+        }
+    }
+    Ok(summary)
+}
+
+/// The pending packages none of whose dependencies are pending.
+fn ready_to_push(pending: &BTreeMap<String, NarInfo>) -> Vec<String> {
+    pending
+        .iter()
+        .filter(|(_, narinfo)| {
+            narinfo
+                .get_dependencies()
+                .iter()
+                .all(|d| !pending.contains_key(d.get_base_32_hash()))
+        })
+        .map(|(hash, _)| hash.clone())
+        .collect()
+}
+
+fn query_missing(client: &Client, base: &Url, hashes: &[String]) -> Result<Vec<String>> {
+    let response = client
+        .post(base.join("api/missing")?)
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_string(hashes)?)
+        .send()?
+        .error_for_status()?;
+    Ok(serde_json::from_str(&response.text()?)?)
+}
+
+/// Whether the remote receives packages as git objects, which older servers don't.
+/// Servers tell it in their handshake, those from before the handshake are probed.
+fn receives_objects(client: &Client, base: &Url, options: &PushOptions) -> Result<bool> {
+    let response = authorized(client.get(base.join("api/handshake")?), options).send()?;
+    if response.status() != StatusCode::NOT_FOUND {
+        let handshake = Handshake::from_json(&response.error_for_status()?.bytes()?)?;
+        debug!("{} runs {}", base, handshake);
+        return Ok(handshake.supports("objects"));
+    }
+    let request = client
+        .post(base.join("api/objects/missing")?)
+        .header(CONTENT_TYPE, "application/json")
+        .body("[]");
+    let response = authorized(request, options).send()?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(false);
+    }
+    response.error_for_status()?;
+    Ok(true)
+}
+
+/// Everything the uploads of one push share.
+struct Transfer<'a> {
+    cache: &'a Store,
+    client: &'a Client,
+    base: &'a Url,
+    options: &'a PushOptions,
+    staging: &'a Path,
+    /// Whether packages are sent as git objects rather than as NARs
+    objects: bool,
+}
+
+impl Transfer<'_> {
+    /// Uploads the packages in parallel and returns how many bytes were uploaded.
+    fn upload_all(&self, hashes: &[String]) -> Result<u64> {
+        let next = AtomicUsize::new(0);
+        let bytes = AtomicU64::new(0);
+        let failed = Mutex::new(Vec::new());
+        thread::scope(|scope| {
+            for _ in 0..self.options.jobs.max(1) {
+                let (next, bytes, failed) = (&next, &bytes, &failed);
+                scope.spawn(move || {
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(hash) = hashes.get(i) else {
+                            break;
+                        };
+                        match self.upload(hash) {
+                            Ok(uploaded) => {
+                                bytes.fetch_add(uploaded, Ordering::Relaxed);
+                            }
+                            Err(e) => {
+                                warn!("Could not push {hash}: {e}");
+                                failed.lock().unwrap().push(hash.clone());
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        let failed = failed.into_inner().unwrap();
+        if !failed.is_empty() {
+            bail!(
+                "Could not push {} packages: {}",
+                failed.len(),
+                failed.join(" ")
+            );
+        }
+        Ok(bytes.into_inner())
+    }
+
+    fn upload(&self, hash: &str) -> Result<u64> {
+        if self.objects {
+            self.upload_objects(hash)
+        } else {
+            self.upload_nar(hash)
+        }
+    }
+
+    /// Uploads the objects of a package the remote is missing as a pack and then
+    /// the narinfo, which makes the remote add the package. For a rebuilt package
+    /// most of its files are usually stored already.
+    fn upload_objects(&self, hash: &str) -> Result<u64> {
+        let narinfo_blob = self
+            .cache
+            .get_narinfo(hash)?
+            .ok_or_else(|| anyhow!("Could not find narinfo for {}", hash))?;
+        let narinfo = String::from_utf8_lossy(&narinfo_blob).to_string();
+        let parsed = NarInfo::parse(&narinfo)?;
+        let root = Oid::from_str(&parsed.key)?;
+        let what = format!("Pushing {}", parsed.store_path);
+        with_retries(self.options.retries, &what, || {
+            let objects = self.negotiate(root)?;
+            let mut pack = tempfile::tempfile_in(self.staging)?;
+            self.cache.write_objects(&objects, &mut pack)?;
+            let size = pack.stream_position()?;
+            pack.rewind()?;
+            let objects_url = self.base.join("api/objects")?;
+            send(self.client.put(objects_url).body(pack), self.options)?;
+            let package_url = self.base.join(&format!("api/packages/{hash}"))?;
+            send(
+                self.client.put(package_url).body(narinfo.clone()),
+                self.options,
+            )?;
+            Ok(size)
+        })
+    }
+
+    /// Asks the remote which objects below `root` it is missing, one level of the
+    /// tree at a time, so that subtrees the remote holds are not descended into.
+    fn negotiate(&self, root: Oid) -> Result<Vec<Oid>> {
+        let mut missing = Vec::new();
+        let mut visited = HashSet::from([root]);
+        let mut level = vec![root];
+        while !level.is_empty() {
+            let mut missing_level = Vec::new();
+            for batch in level.chunks(NEGOTIATION_BATCH) {
+                missing_level.extend(self.query_missing_objects(batch)?);
+            }
+            level = self
+                .cache
+                .tree_entries(&missing_level)?
+                .into_iter()
+                .filter(|oid| visited.insert(*oid))
+                .collect();
+            missing.extend(missing_level);
+        }
+        Ok(missing)
+    }
+
+    fn query_missing_objects(&self, oids: &[Oid]) -> Result<Vec<Oid>> {
+        let oids: Vec<String> = oids.iter().map(Oid::to_string).collect();
+        let request = self
+            .client
+            .post(self.base.join("api/objects/missing")?)
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_string(&oids)?);
+        let response = send(request, self.options)?;
+        let missing: Vec<String> = serde_json::from_str(&response.text()?)?;
+        Ok(missing
+            .iter()
+            .map(|oid| Oid::from_str(oid))
+            .collect::<Result<_, _>>()?)
+    }
+
+    /// Uploads the NAR of a package and then its narinfo, which makes the remote
+    /// add the package.
+    fn upload_nar(&self, hash: &str) -> Result<u64> {
+        let (narinfo, nar) = self.cache.compress_nar(hash, self.staging)?;
+        let what = format!("Pushing {}", narinfo.store_path);
+        let result = with_retries(self.options.retries, &what, || {
+            let nar_url = self.base.join(&format!("nar/{}", nar.file_name()))?;
+            send(
+                self.client.put(nar_url).body(File::open(nar.path())?),
+                self.options,
+            )?;
+            let narinfo_url = self.base.join(&format!("{hash}.narinfo"))?;
+            send(
+                self.client.put(narinfo_url).body(narinfo.to_string()),
+                self.options,
+            )?;
+            Ok(())
+        });
+        fs::remove_file(nar.path())?;
+        result.map(|()| nar.file_size)
+    }
+}
+
+fn authorized(request: RequestBuilder, options: &PushOptions) -> RequestBuilder {
+    match &options.token {
+        Some(token) => request.bearer_auth(token),
+        None => request,
+    }
+}
+
+fn send(request: RequestBuilder, options: &PushOptions) -> Result<Response> {
+    Ok(authorized(request, options).send()?.error_for_status()?)
+}
+
+fn with_retries<T>(retries: u32, what: &str, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                warn!("{what} failed (attempt {attempt}): {e}");
+                thread::sleep(Duration::from_secs(1 << attempt.min(5)));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn narinfo(hash: &str, references: &[&str]) -> NarInfo {
+        let references: Vec<String> = references.iter().map(|r| format!("{r}-dep")).collect();
+        NarInfo::parse(&format!(
+            "StorePath: /nix/store/{hash}-pkg
+URL: nar/0lfjpl49j2na01l1zdmyszxz5wr957kl5qxn278alyv0fvxh2lab.nar
+Compression: none
+FileHash: sha256:0lfjpl49j2na01l1zdmyszxz5wr957kl5qxn278alyv0fvxh2lab
+FileSize: 120
+NarHash: sha256:0lfjpl49j2na01l1zdmyszxz5wr957kl5qxn278alyv0fvxh2lab
+NarSize: 120
+References: {}
+Deriver:
+Sig: ",
+            references.join(" ")
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_ready_to_push() {
+        let leaf = "l".repeat(32);
+        let dep = "d".repeat(32);
+        let root = "r".repeat(32);
+        let mut pending = BTreeMap::from([
+            (leaf.clone(), narinfo(&leaf, &[&leaf])),
+            (dep.clone(), narinfo(&dep, &[&leaf])),
+            (root.clone(), narinfo(&root, &[&dep, &leaf])),
+        ]);
+        // A package referencing itself is not held back by that
+        assert_eq!(ready_to_push(&pending), [leaf.clone()]);
+        pending.remove(&leaf);
+        assert_eq!(ready_to_push(&pending), [dep.clone()]);
+        pending.remove(&dep);
+        assert_eq!(ready_to_push(&pending), [root]);
+    }
+}
@@ -1,13 +1,84 @@
+use std::collections::HashMap;
 use std::path::PathBuf;
 
 use config::{Config, ConfigError, Environment, File};
 use serde::Deserialize;
 use url::Url;
 
+use crate::http_server::network::Cidr;
+use crate::nix_interface::ssh::AuthMethod;
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Server {
     pub port: u16,
     pub host: String,
+    /// Base URLs which serve the NARs of the cache under `nar/`, e.g. a CDN. Served
+    /// narinfos point at one of them instead of at gachix
+    #[serde(default)]
+    pub mirrors: Vec<Url>,
+    pub cache_control: CacheControl,
+    /// Bearer token for the `/api/admin` endpoints, which are disabled if not set
+    pub admin_token: Option<String>,
+    /// Seconds after which clients are told to retry while in maintenance mode
+    pub maintenance_retry_after: u32,
+    /// Unix socket through which `gachix ctl` controls the running server, disabled
+    /// if not set
+    pub control_socket: Option<PathBuf>,
+    /// File holding the pid of the running server, read by `gachix status`
+    pub pid_file: Option<PathBuf>,
+    /// Largest artifact or pushed NAR in bytes which can be uploaded
+    pub max_upload_size: usize,
+    /// Seconds after which resumable uploads to `/cas` which were not continued are
+    /// removed
+    pub upload_expiry: u64,
+    /// Credentials required to read below path prefixes, everything is public if
+    /// empty
+    #[serde(default)]
+    pub read_access: Vec<ReadAccess>,
+    #[serde(default)]
+    pub networks: Networks,
+}
+
+/// Networks of the clients which may use the server, for reads (`GET` and `HEAD`)
+/// and for all other requests, e.g. uploads, separately.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Networks {
+    pub read: NetworkAccess,
+    pub write: NetworkAccess,
+}
+
+/// Clients in a denied network are refused, as are clients outside of all allowed
+/// networks unless none are listed.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct NetworkAccess {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+/// Credentials which may read below a path prefix of the server, e.g. `/` for the
+/// whole cache or `/cas/` for the artifacts. The rule with the longest matching
+/// prefix applies, the admin token is always accepted.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ReadAccess {
+    pub prefix: String,
+    /// `user:password` pairs accepted with basic auth
+    #[serde(default)]
+    pub users: Vec<String>,
+    /// Tokens accepted with bearer auth
+    #[serde(default)]
+    pub tokens: Vec<String>,
+}
+
+/// Seconds for which caches in front of the server, e.g. a CDN, may keep responses.
+#[derive(Debug, Deserialize, Clone)]
+pub struct CacheControl {
+    /// NARs never change, they are marked as immutable as well
+    pub nar_max_age: u32,
+    pub narinfo_max_age: u32,
+    /// Packages which are not in the cache may be added at any time
+    pub not_found_max_age: u32,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -15,9 +86,171 @@ pub struct Store {
     pub path: PathBuf,
     pub builders: Vec<Url>,
     pub remotes: Vec<Url>,
+    /// Domain whose `_gachix._tcp` DNS records announce further remotes and builders,
+    /// looked up with `--discover`
+    pub discovery_domain: Option<String>,
+    /// Seconds between checks of the git peers for packages they revoked, which are
+    /// then revoked here as well. Disabled if not set
+    pub follow_deletions: Option<u64>,
+    /// Open the repository without writing to it. Everything which would modify it
+    /// fails and background garbage collection is disabled
+    pub read_only: bool,
     pub use_local_nix_daemon: bool,
     pub sign_private_key_path: Option<PathBuf>,
+    /// Keys in the `name:base64` format of Nix. If any are set, packages fetched from
+    /// git peers must carry a signature by one of them
+    #[serde(default)]
+    pub trusted_public_keys: Vec<String>,
+    /// Hold packages fetched from git peers which fail verification in quarantine
+    /// for review instead of dropping them
+    pub quarantine_rejected: bool,
+    /// Stage packages fetched from git peers which are not signed by one of
+    /// `trusted_public_keys` in quarantine until an operator reviews them, even if
+    /// no keys are set
+    pub review_unsigned: bool,
     pub ssh_private_key_path: Option<PathBuf>,
+    /// Defaults to ~/.ssh/known_hosts
+    pub ssh_known_hosts_path: Option<PathBuf>,
+    pub ssh_trust_on_first_use: bool,
+    /// Tried in order, methods which prompt are only used on a terminal
+    pub ssh_auth_methods: Vec<AuthMethod>,
+    pub pack_refs_threshold: usize,
+    /// Bytes of file contents which all NARs being added and served may hold in
+    /// memory together. Larger contents are spilled to temporary files
+    pub memory_budget: u64,
+    #[serde(default)]
+    pub filters: IngestFilters,
+    /// Filters of single git peers
+    #[serde(default)]
+    pub peer_filters: Vec<PeerFilter>,
+    /// Commands every added package is scanned with
+    #[serde(default)]
+    pub scanners: Vec<Scanner>,
+    #[serde(default)]
+    pub narinfo_fields: NarinfoFields,
+    pub gc: Gc,
+    pub timeouts: Timeouts,
+}
+
+/// Seconds after which an operation on a Nix daemon is given up.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Timeouts {
+    pub connect: u64,
+    pub query: u64,
+    /// Longest pause while receiving a NAR, not a limit on the whole transfer
+    pub transfer: u64,
+    /// Interval of SSH keepalives on sessions to remote daemons, 0 disables them
+    pub keepalive: u64,
+}
+
+/// Eviction of packages when the disk of the repository fills up. Watermarks are
+/// percentages of the filesystem in use.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Gc {
+    /// Start evicting once usage crosses this watermark, disabled if not set
+    pub high_watermark: Option<f64>,
+    /// Stop evicting once usage is below this watermark
+    pub low_watermark: f64,
+    /// Seconds between checks of the disk usage while serving, at least one
+    pub check_interval: u64,
+    /// Seconds for which the objects of removed packages are kept, so that they can
+    /// be restored with `undelete`. Packages evicted under disk pressure are pruned
+    /// right away. Replaced narinfos are kept as long
+    pub retention: u64,
+    /// Seconds for which removed packages are kept as tombstones, hidden but
+    /// restorable with `undelete`. The retention period starts once a tombstone
+    /// expired
+    pub tombstone_grace: u64,
+    /// Seconds for which the entries of the audit trail are kept
+    pub audit_retention: u64,
+    /// Systems whose packages are evicted before those of other systems, in this
+    /// order
+    #[serde(default)]
+    pub evict_first: Vec<String>,
+    /// Systems whose packages are never evicted
+    #[serde(default)]
+    pub never_evict: Vec<String>,
+    /// Number of the newest versions of every channel whose packages are never
+    /// evicted, along with their closures
+    pub keep_channel_versions: usize,
+    /// Named sets of eviction rules, applied or tried out with `gachix gc --policy`
+    #[serde(default)]
+    pub policies: HashMap<String, GcPolicy>,
+}
+
+/// Eviction rules replacing those of `store.gc`, which are used for the ones left
+/// out.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct GcPolicy {
+    pub low_watermark: Option<f64>,
+    pub evict_first: Option<Vec<String>>,
+    pub never_evict: Option<Vec<String>>,
+    pub keep_channel_versions: Option<usize>,
+}
+
+/// Rules deciding which dependencies are skipped when adding a closure.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct IngestFilters {
+    /// Only add packages whose name matches one of these globs
+    pub include: Vec<String>,
+    /// Skip packages whose name matches one of these globs
+    pub exclude: Vec<String>,
+    /// Skip packages whose NAR is larger than this many bytes
+    pub max_nar_size: Option<u64>,
+    /// Skip packages built for other systems
+    pub systems: Vec<String>,
+}
+
+/// Limits which packages are fetched from and copied to one git peer, e.g. so that
+/// an edge cache only mirrors what its site uses. The dependencies of a package
+/// which passes are transferred along with it.
+#[derive(Debug, Deserialize, Clone)]
+pub struct PeerFilter {
+    /// The git URL of the peer, as in `store.remotes`
+    pub remote: Url,
+    #[serde(default)]
+    pub filters: IngestFilters,
+}
+
+/// Fields of narinfos gachix does not interpret itself.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct NarinfoFields {
+    /// `Key: value` lines added to the narinfo of every package added, e.g.
+    /// `Priority: 30`, replacing fields of the same name in pushed or fetched
+    /// narinfos
+    pub add: Vec<String>,
+    /// Dropped from the narinfos of packages pushed to the server or fetched from
+    /// daemons and git peers
+    pub strip: Vec<String>,
+}
+
+/// An external command, e.g. a secret or malware scanner, which reads the NAR of
+/// a package on its standard input and exits with a failure if it finds something.
+/// The store path is passed in `GACHIX_STORE_PATH`.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Scanner {
+    /// The program and its arguments
+    pub command: Vec<String>,
+    /// Only scan packages whose name matches one of these globs, all if empty
+    #[serde(default)]
+    pub packages: Vec<String>,
+    #[serde(default)]
+    pub on_failure: ScanAction,
+}
+
+/// What happens to a package a scanner fails on.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanAction {
+    /// The package is not added
+    #[default]
+    Reject,
+    /// The package is added, and what the scanner reported is recorded in its
+    /// provenance
+    Flag,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -25,6 +258,7 @@ pub struct Settings {
     pub store: Store,
     pub server: Server,
     pub log_level: String,
+    pub log_file: Option<PathBuf>,
 }
 
 pub fn load_config(config_file: &str) -> Result<Settings, ConfigError> {
@@ -35,10 +269,36 @@ store:
     builders: []
     remotes: []
     use_local_nix_daemon: true
+    read_only: false
+    quarantine_rejected: false
+    review_unsigned: false
+    ssh_trust_on_first_use: false
+    ssh_auth_methods: [publickey, keyboard-interactive, password]
+    pack_refs_threshold: 1000
+    memory_budget: 1073741824
+    gc:
+        low_watermark: 80
+        check_interval: 300
+        retention: 604800
+        tombstone_grace: 604800
+        audit_retention: 7776000
+        keep_channel_versions: 1
+    timeouts:
+        connect: 30
+        query: 60
+        transfer: 120
+        keepalive: 15
 
 server:
     host: localhost
     port: 8080
+    cache_control:
+        nar_max_age: 31536000
+        narinfo_max_age: 300
+        not_found_max_age: 60
+    maintenance_retry_after: 120
+    max_upload_size: 268435456
+    upload_expiry: 86400
     "#;
     let settings = Config::builder()
         .add_source(File::from_str(defaults, config::FileFormat::Yaml).required(true))
@@ -49,6 +309,18 @@ server:
                 .list_separator(",")
                 .with_list_parse_key("store.remotes")
                 .with_list_parse_key("store.builders")
+                .with_list_parse_key("store.ssh_auth_methods")
+                .with_list_parse_key("store.trusted_public_keys")
+                .with_list_parse_key("store.filters.include")
+                .with_list_parse_key("store.filters.exclude")
+                .with_list_parse_key("store.filters.systems")
+                .with_list_parse_key("store.gc.evict_first")
+                .with_list_parse_key("store.gc.never_evict")
+                .with_list_parse_key("server.mirrors")
+                .with_list_parse_key("server.networks.read.allow")
+                .with_list_parse_key("server.networks.read.deny")
+                .with_list_parse_key("server.networks.write.allow")
+                .with_list_parse_key("server.networks.write.deny")
                 .try_parsing(true),
         )
         .build()?;
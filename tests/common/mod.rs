@@ -15,12 +15,24 @@ pub struct CacheServer {
 }
 impl CacheServer {
     pub fn start(port: u16, cache_path: &Path) -> Result<Self> {
+        Self::start_with_config(port, cache_path, None)
+    }
+
+    pub fn start_with_config(
+        port: u16,
+        cache_path: &Path,
+        config: Option<HashMap<&str, &str>>,
+    ) -> Result<Self> {
         let mut command = Command::new(assert_cmd::cargo::cargo_bin!());
-        let mut child = command
+        let process = command
             .env("GACHIX__STORE__PATH", cache_path)
             .env("GACHIX__SERVER__PORT", port.to_string())
             .arg("serve")
-            .stdout(Stdio::null())
+            .stdout(Stdio::null());
+        if let Some(config) = config {
+            process.envs(config);
+        }
+        let mut child = process
             .spawn()
             .map_err(|e| anyhow!("Failed to start server: {}", e))?;
 
@@ -111,6 +123,21 @@ pub fn add_to_cache(
     Ok(())
 }
 
+pub fn ctl(control_socket: &Path, command: &[&str]) -> Result<String> {
+    let output = Command::new(assert_cmd::cargo::cargo_bin!())
+        .env("GACHIX__SERVER__CONTROL_SOCKET", control_socket)
+        .arg("ctl")
+        .args(command)
+        .output()?;
+    if !output.status.success() {
+        bail!(
+            "Control command failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
 pub fn request(url: &str) -> Result<reqwest::blocking::Response> {
     let response = reqwest::blocking::get(url)?;
     assert!(
@@ -213,3 +213,37 @@ fn test_nix_substituter() -> Result<()> {
     // TODO: It should test whether the path was actually substituted
     Ok(())
 }
+
+#[test]
+fn test_control_socket() -> Result<()> {
+    let tempdir = TempDir::new()?;
+    let temp_path = tempdir.path();
+    let port = 9240;
+    let base_url = format!("http://localhost:{}", port);
+    let control_socket = temp_path.join("gachix.sock");
+
+    let config = HashMap::from([(
+        "GACHIX__SERVER__CONTROL_SOCKET",
+        control_socket.to_str().unwrap(),
+    )]);
+    let _server =
+        common::CacheServer::start_with_config(port, &temp_path.join("gachix"), Some(config))?;
+
+    let stats = common::ctl(&control_socket, &["stats"])?;
+    assert!(stats.contains("Packages: 0"), "Unexpected stats:\n{stats}");
+    assert!(
+        stats.contains("Maintenance: off"),
+        "Unexpected stats:\n{stats}"
+    );
+
+    common::ctl(&control_socket, &["maintenance", "on"])?;
+    let response = reqwest::blocking::get(format!("{base_url}/nix-cache-info"))?;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(response.headers().contains_key("retry-after"));
+
+    common::ctl(&control_socket, &["maintenance", "off"])?;
+    common::request(&format!("{base_url}/nix-cache-info"))?;
+
+    assert!(common::ctl(&control_socket, &["unknown"]).is_err());
+    Ok(())
+}